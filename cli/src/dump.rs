@@ -4,6 +4,7 @@ use crate::tensor::run_params_from_subcommand;
 #[allow(unused_imports)]
 use nu_ansi_term::Style;
 use tract_hir::internal::*;
+use tract_core::ops::matmul::roofline::MachinePeaks;
 use tract_libcli::annotations::*;
 use tract_libcli::display_params::*;
 use tract_libcli::model::Model;
@@ -132,6 +133,14 @@ pub fn handle(
             .context("Can only profile typed models")?;
         let inputs = retrieve_or_make_inputs(model, &run_params)?;
         tract_libcli::profile::profile(model, bench_limits, &mut annotations, &inputs[0], None, options.folded)?;
+        if options.profile_fused_specs {
+            tract_libcli::profile::profile_fused_specs(
+                model,
+                bench_limits,
+                &mut annotations,
+                &inputs[0],
+            )?;
+        }
     }
 
     if sub_matches.is_present("axes") || sub_matches.is_present("axes-names") {
@@ -150,6 +159,38 @@ pub fn handle(
         annotations.track_axes(model, &hints)?;
     }
 
+    if sub_matches.is_present("roofline") {
+        let model = params
+            .tract_model
+            .downcast_ref::<TypedModel>()
+            .context("Can only estimate the roofline of typed models")?;
+        let configured = |arg| -> TractResult<Option<f64>> {
+            Ok(sub_matches.value_of(arg).map(|v| v.parse::<f64>()).transpose()?.map(|v| v * 1e9))
+        };
+        let (bandwidth, flops) = (configured("peak-bandwidth")?, configured("peak-gflops")?);
+        let peaks = if let (Some(bandwidth), Some(flops)) = (bandwidth, flops) {
+            MachinePeaks { bandwidth, flops }
+        } else {
+            let measured = MachinePeaks::measured();
+            MachinePeaks {
+                bandwidth: bandwidth.unwrap_or(measured.bandwidth),
+                flops: flops.unwrap_or(measured.flops),
+            }
+        };
+        tract_libcli::profile::roofline(model, &mut annotations, &peaks)?;
+    }
+
+    if sub_matches.is_present("check-numerics") {
+        let model = params
+            .tract_model
+            .downcast_ref::<TypedModel>()
+            .context("Can only check numerics of typed models")?;
+        for issue in model.check_numerics_policy()? {
+            let section = vec![format!("{:?}: {}", issue.kind, issue.detail), issue.suggestion];
+            annotations.node_mut(issue.node.into()).sections.push(section);
+        }
+    }
+
     if let Some(asserts) = &params.assertions.assert_output_facts {
         let outputs_facts: Vec<InferenceFact> = model
             .output_outlets()
@@ -117,6 +117,8 @@ fn main() -> tract_core::anyhow::Result<()> {
         .arg(arg!(--"optimize-step" [STEP] "Stop optimizing process after application of patch number N"))
         .arg(arg!(--"extract-decluttered-sub" [SUB] "Zoom on a subgraph after decluttering by parent node name"))
 
+        .arg(Arg::new("float-fallback").long("float-fallback").multiple_occurrences(true).takes_value(true)
+         .long_help("Run this quantized einsum node in f32, on its dequantized operands"))
         .arg(arg!(--"half-floats" "Convert the decluttered network from f32 to f16"))
         .arg(Arg::new("set").long("set").multiple_occurrences(true).takes_value(true)
          .long_help("Set a symbol to a concrete value after decluttering"))
@@ -448,8 +450,43 @@ fn output_options(command: clap::Command) -> clap::Command {
         .arg(Arg::new("io-none").long("io-none").help("hide i/o information"))
         .arg(Arg::new("json").long("json").help("dump performance info as json"))
         .arg(Arg::new("outlet-labels").long("outlet-labels").help("display outlet labels"))
+        .arg(
+            Arg::new("patches")
+                .long("patches")
+                .help("display the optimizer patches that created or rewired each node"),
+        )
         .arg(Arg::new("cost").long("cost").help("Include const information"))
         .arg(Arg::new("profile").long("profile").help("Include results for profile run"))
+        .arg(
+            Arg::new("profile-fused-specs")
+                .long("profile-fused-specs")
+                .requires("profile")
+                .help("With --profile, time the fused steps of each matrix multiplication"),
+        )
+        .arg(
+            Arg::new("roofline")
+                .long("roofline")
+                .help("Estimate the bound of each matrix multiplication from its arithmetic intensity, compared to the measured throughput with --profile"),
+        )
+        .arg(
+            Arg::new("peak-bandwidth")
+                .long("peak-bandwidth")
+                .takes_value(true)
+                .requires("roofline")
+                .help("Memory bandwidth for --roofline, in GB/s [default: measured]"),
+        )
+        .arg(
+            Arg::new("peak-gflops")
+                .long("peak-gflops")
+                .takes_value(true)
+                .requires("roofline")
+                .help("Arithmetic throughput for --roofline, in GFLOP/s [default: measured]"),
+        )
+        .arg(
+            Arg::new("check-numerics")
+                .long("check-numerics")
+                .help("Report matmuls with lossy accumulators or saturating outputs"),
+        )
         .arg(Arg::new("folded").long("folded").help("Don't display submodel informations"))
         .arg(
             Arg::new("invariants")
@@ -583,6 +583,13 @@ impl Parameters {
         });
 
         let nnef_cycle = matches.is_present("nnef-cycle");
+        let optimizer_options = tract_core::optim::OptimizerOptions {
+            track_patches: matches
+                .subcommand()
+                .map(|(_, sm)| sm.is_present("patches"))
+                .unwrap_or(false),
+            ..Default::default()
+        };
 
         info!("Will stop at {}", stop_at);
 
@@ -664,7 +671,12 @@ impl Parameters {
                     }
                 }
             }
-            let mut dec = tract_core::optim::Optimizer::declutter();
+            if let Some(nodes) = matches.values_of("float-fallback") {
+                for node in nodes {
+                    m.force_float_fallback(node)?;
+                }
+            }
+            let mut dec = tract_core::optim::Optimizer::declutter().with_options(optimizer_options.clone());
             if let Some(steps) = matches.value_of("declutter-step") {
                 dec = dec.stopping_at(steps.parse()?);
             }
@@ -730,7 +742,7 @@ impl Parameters {
         }
         stage!("before-optimize", typed_model -> typed_model, Ok);
         stage!("optimize", typed_model -> typed_model, |mut m:TypedModel| {
-            let mut opt = tract_core::optim::Optimizer::codegen();
+            let mut opt = tract_core::optim::Optimizer::codegen().with_options(optimizer_options.clone());
             if let Some(steps) = matches.value_of("optimize-step") {
                 opt = opt.stopping_at(steps.parse()?);
             }
@@ -939,6 +951,7 @@ pub fn display_params_from_clap(
         konst: matches.is_present("const"),
         cost: matches.is_present("cost"),
         profile: matches.is_present("profile"),
+        profile_fused_specs: matches.is_present("profile-fused-specs"),
         folded: matches.is_present("folded"),
         left_column_width: 0,
         invariants: matches.is_present("invariants"),
@@ -954,6 +967,7 @@ pub fn display_params_from_clap(
         expect_core: root_matches.value_of("pass").unwrap_or("declutter") == "declutter"
             && !root_matches.is_present("optimize"),
         outlet_labels: matches.is_present("outlet-labels"),
+        patches: matches.is_present("patches"),
         io: if matches.is_present("io-long") {
             display_params::Io::Long
         } else if matches.is_present("io-none") {
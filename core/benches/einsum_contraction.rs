@@ -0,0 +1,35 @@
+use criterion::*;
+use tract_core::internal::*;
+use tract_core::ops::einsum::EinSum;
+
+fn operand(shape: &[usize], seed: usize) -> TValue {
+    let len = shape.iter().product::<usize>();
+    let data = (0..len).map(|i| ((i * seed + 1) % 11) as f32 - 5.).collect::<Vec<_>>();
+    Tensor::from_shape(shape, &data).unwrap().into_tvalue()
+}
+
+/// 8 batches of 64x64 products, evaluated as batched matrix products, against the loop nest
+/// running them with an extra scalar operand of one (n-ary einsums are not decomposed).
+fn einsum_contraction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("einsum_contraction");
+    for expr in ["bmk,bkn->bmn", "kmb,nbk->bnm"] {
+        let shape = |operand: &str| {
+            operand.chars().map(|c| if c == 'b' { 8 } else { 64 }).collect::<Vec<_>>()
+        };
+        let operands = expr.split("->").next().unwrap().split(',').collect::<Vec<_>>();
+        let inputs = tvec!(operand(&shape(operands[0]), 3), operand(&shape(operands[1]), 7));
+        let decomposed = EinSum::new(expr.parse().unwrap(), f32::datum_type());
+        group.bench_function(BenchmarkId::new("decomposed", expr), |b| {
+            b.iter(|| decomposed.eval(inputs.clone()).unwrap())
+        });
+        let looped = EinSum::new(expr.replace("->", ",->").parse().unwrap(), f32::datum_type());
+        let mut looped_inputs = inputs.clone();
+        looped_inputs.push(tensor0(1f32).into_tvalue());
+        group.bench_function(BenchmarkId::new("loop_nest", expr), |b| {
+            b.iter(|| looped.eval(looped_inputs.clone()).unwrap())
+        });
+    }
+}
+
+criterion_group!(benches, einsum_contraction);
+criterion_main!(benches);
@@ -0,0 +1,38 @@
+use criterion::*;
+use tract_core::internal::*;
+use tract_core::ops::einsum::EinSum;
+use tract_core::ops::matmul::pack::KPadding;
+use tract_core::optim::OptimizerOptions;
+
+/// A 64x37 by 37x64 product with k symbolic, bounded to 37, with and without k padding.
+fn k_padding(c: &mut Criterion) {
+    let mut model = TypedModel::default();
+    let k = model.symbol_table.sym("K");
+    let a = model.add_source("a", f32::fact(dims!(64, k))).unwrap();
+    let b = model.add_source("b", f32::fact(dims!(k, 64))).unwrap();
+    let op = EinSum::new("mk,kn->mn".parse().unwrap(), f32::datum_type());
+    let output = model.wire_node("einsum", op, &[a, b]).unwrap();
+    model.set_output_outlets(&output).unwrap();
+    let inputs = tvec!(
+        Tensor::zero::<f32>(&[64, 37]).unwrap().into_tvalue(),
+        Tensor::zero::<f32>(&[37, 64]).unwrap().into_tvalue()
+    );
+    let mut group = c.benchmark_group("k_padding");
+    for k_padding in
+        [None, Some(KPadding { max_k: 64, multiple: 1 }), Some(KPadding { max_k: 64, multiple: 8 })]
+    {
+        let symbol_bounds = SymbolValues::default().with(&k, 37);
+        let options = OptimizerOptions { k_padding, symbol_bounds, ..Default::default() };
+        let plan = model.clone().into_optimized_with_options(&options).unwrap();
+        let plan = plan.into_runnable().unwrap();
+        let mut state = SimpleState::new(&plan).unwrap();
+        let id = match k_padding {
+            Some(p) => format!("multiple_{}", p.multiple),
+            None => "unpadded".to_string(),
+        };
+        group.bench_function(id, |b| b.iter(|| state.run(inputs.clone()).unwrap()));
+    }
+}
+
+criterion_group!(benches, k_padding);
+criterion_main!(benches);
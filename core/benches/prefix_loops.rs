@@ -0,0 +1,37 @@
+use criterion::*;
+use tract_core::internal::*;
+use tract_core::ops::einsum::EinSum;
+use tract_core::ops::matmul::prefix_loops::PrefixLoopOrder;
+use tract_core::optim::OptimizerOptions;
+
+fn input(shape: &[usize], seed: usize) -> TValue {
+    let len = shape.iter().product::<usize>();
+    let data = (0..len).map(|i| ((i * 7 + seed) % 23) as f32 / 8. - 1.).collect::<Vec<_>>();
+    Tensor::from_shape(shape, &data).unwrap().into_tvalue()
+}
+
+/// Cross products of two independent batches of 64 256x256 matrices, walked in the output
+/// order, and in the planned one.
+fn prefix_loops(c: &mut Criterion) {
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", f32::fact([64, 256, 256])).unwrap();
+    let b = model.add_source("b", f32::fact([64, 256, 256])).unwrap();
+    let einsum = EinSum::new("bik,cjk->bcij".parse().unwrap(), f32::datum_type());
+    let output = model.wire_node("einsum", einsum, &[a, b]).unwrap();
+    model.set_output_outlets(&output).unwrap();
+    let inputs = tvec!(input(&[64, 256, 256], 0), input(&[64, 256, 256], 3));
+    let mut group = c.benchmark_group("prefix_loops");
+    group.sample_size(10);
+    for (name, prefix_loops) in
+        [("output_order", PrefixLoopOrder::OutputOrder), ("planned", PrefixLoopOrder::default())]
+    {
+        let options = OptimizerOptions { prefix_loops, ..OptimizerOptions::default() };
+        let plan = model.clone().into_optimized_with_options(&options).unwrap();
+        let plan = plan.into_runnable().unwrap();
+        group.bench_function(name, |b| b.iter(|| plan.run(inputs.clone()).unwrap()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, prefix_loops);
+criterion_main!(benches);
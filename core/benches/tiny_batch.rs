@@ -0,0 +1,40 @@
+use criterion::*;
+use tract_core::internal::*;
+use tract_core::ops::einsum::EinSum;
+use tract_core::ops::matmul::tiny_batch::TinyBatch;
+use tract_core::optim::OptimizerOptions;
+
+fn tensor(shape: &[usize], value: impl Fn(usize) -> f32) -> Tensor {
+    let len = shape.iter().product::<usize>();
+    Tensor::from_shape(shape, &(0..len).map(value).collect::<Vec<_>>()).unwrap()
+}
+
+/// 100 000 3x3 by 3x3 products, run by the product kernel on each batch entry, and across the
+/// batch.
+fn tiny_batch(c: &mut Criterion) {
+    let batch = 100_000;
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", f32::fact([batch, 3, 3])).unwrap();
+    let b = model.add_source("b", f32::fact([batch, 3, 3])).unwrap();
+    let op = EinSum::new("bij,bjk->bik".parse().unwrap(), f32::datum_type());
+    let output = model.wire_node("einsum", op, &[a, b]).unwrap();
+    model.set_output_outlets(&output).unwrap();
+    let inputs = tvec!(
+        tensor(&[batch, 3, 3], |i| ((i * 7) % 19) as f32 / 3. - 2.).into_tvalue(),
+        tensor(&[batch, 3, 3], |i| ((i * 5) % 23) as f32 / 7. - 1.).into_tvalue()
+    );
+    let mut group = c.benchmark_group("tiny_batch");
+    group.throughput(Throughput::Elements(batch as u64));
+    for (name, tiny_batch) in
+        [("kernel_per_product", TinyBatch::disabled()), ("across_batch", TinyBatch::default())]
+    {
+        let options = OptimizerOptions { tiny_batch, ..OptimizerOptions::default() };
+        let plan = model.clone().into_optimized_with_options(&options).unwrap();
+        let plan = plan.into_runnable().unwrap();
+        group.bench_function(name, |b| b.iter(|| plan.run(inputs.clone()).unwrap()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, tiny_batch);
+criterion_main!(benches);
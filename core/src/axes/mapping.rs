@@ -34,6 +34,35 @@ impl<'axis> AxisPattern for &'axis Axis {
     }
 }
 
+/// AxisOps adapting tensors to a transformed mapping: `inputs[i]` turns the former input #i into
+/// the new one, `outputs[o]` turns the new output #o back into the former one.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct AxesFixes {
+    pub inputs: TVec<Vec<AxisOp>>,
+    pub outputs: TVec<Vec<AxisOp>>,
+}
+
+impl AxesFixes {
+    fn none(mapping: &AxesMapping) -> AxesFixes {
+        AxesFixes {
+            inputs: tvec!(vec!(); mapping.input_count),
+            outputs: tvec!(vec!(); mapping.output_count),
+        }
+    }
+
+    fn interface_mut(&mut self, io: InOut) -> &mut Vec<AxisOp> {
+        match io {
+            InOut::In(i) => &mut self.inputs[i],
+            InOut::Out(o) => &mut self.outputs[o],
+        }
+    }
+}
+
+/// Axis labels, ASCII letters first, then any alphabetic character past Latin-1.
+fn labels() -> impl Iterator<Item = char> {
+    ('a'..='z').chain('A'..='Z').chain(('\u{100}'..).filter(|c| c.is_alphabetic()))
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct AxesMapping {
     input_count: usize,
@@ -154,6 +183,10 @@ impl AxesMapping {
         })
     }
 
+    fn interfaces(&self) -> impl Iterator<Item = InOut> {
+        (0..self.input_count).map(InOut::In).chain((0..self.output_count).map(InOut::Out))
+    }
+
     pub fn rank(&self, io: InOut) -> usize {
         match io {
             InOut::In(i) => self.iter_all_axes().map(|axis| axis.inputs[i].len()).sum(),
@@ -276,8 +309,10 @@ impl AxesMapping {
         Ok(self)
     }
 
+    /// The first label no axis uses: an ASCII letter, lowercase first, then any alphabetic
+    /// character past Latin-1, so labels do not run out.
     pub fn available_label(&self) -> char {
-        ('a'..).find(|c| self.iter_all_axes().all(|axis| axis.repr != *c)).unwrap()
+        labels().find(|c| self.iter_all_axes().all(|axis| axis.repr != *c)).unwrap()
     }
 
     pub fn is_element_wise_unary(&self) -> bool {
@@ -327,6 +362,16 @@ impl AxesMapping {
         Ok(self)
     }
 
+    /// Same mapping, with axes labelled in their order of appearance: mappings only differing
+    /// by their labels have the same canonical form.
+    pub fn canonical(&self) -> AxesMapping {
+        let mut canonical = self.clone().sorted();
+        for (ax, repr) in canonical.axes.iter_mut().zip('a'..) {
+            ax.repr = repr;
+        }
+        canonical
+    }
+
     pub fn remove_axis(&self, repr: char) -> TractResult<AxesMapping> {
         let mut axes: TVec<Axis> =
             self.axes.iter().filter(|axis| axis.repr != repr).cloned().collect();
@@ -419,33 +464,229 @@ impl AxesMapping {
         self.check()
     }
 
+    /// AxisOp sequence transforming the single input into the single output: `Rm`s, then the
+    /// `Move`s of one permutation, then `Add`s.
+    ///
+    /// Removed and added axes are both of size 1, so a removed axis can stand for an added one
+    /// instead of an Rm/Add pair. The shortest of the paired and unpaired sequences is returned.
     pub fn translate_to_axis_ops(&self) -> TractResult<Vec<AxisOp>> {
         ensure!(self.input_count() == 1);
         ensure!(self.output_count() == 1);
         ensure!(self.iter_all_axes().all(|axis| axis.inputs[0].len() <= 1));
+        ensure!(self.iter_all_axes().all(|axis| axis.outputs[0].len() <= 1));
+        let paired = self.axis_ops_pairing_rm_and_add(true);
+        let unpaired = self.axis_ops_pairing_rm_and_add(false);
+        Ok(if unpaired.len() < paired.len() { unpaired } else { paired })
+    }
+
+    fn axis_ops_pairing_rm_and_add(&self, pair: bool) -> Vec<AxisOp> {
         let rms = self
             .iter_all_axes()
             .filter(|a| a.outputs[0].len() == 0)
-            .sorted_by_key(|axis| -(axis.inputs[0][0] as isize))
+            .map(|a| a.inputs[0][0])
+            .sorted()
             .collect_vec();
         let adds = self
             .iter_all_axes()
             .filter(|a| a.inputs[0].len() == 0)
-            .sorted_by_key(|axis| axis.outputs[0][0] as isize)
+            .map(|a| a.outputs[0][0])
+            .sorted()
             .collect_vec();
-        let permutation = rms
-            .iter()
-            .chain(adds.iter())
-            .try_fold(self.clone(), |mapping, axis| mapping.remove_axis(axis.repr))?;
-        let permutation = permutation
+        let paired = if pair { rms.len().min(adds.len()) } else { 0 };
+        // (input position, output position) of the axes going through the permutation
+        let kept = self
             .iter_all_axes()
-            .sorted_by_key(|axis| axis.outputs[0][0])
-            .map(|axis| axis.inputs[0][0])
+            .filter(|a| a.inputs[0].len() == 1 && a.outputs[0].len() == 1)
+            .map(|a| (a.inputs[0][0], a.outputs[0][0]))
+            .chain(rms.iter().copied().zip(adds.iter().copied()).take(paired))
+            .collect_vec();
+        let rms = &rms[paired..];
+        let adds = &adds[paired..];
+        let permutation = kept
+            .iter()
+            .sorted_by_key(|(_, output)| *output)
+            .map(|(input, _)| kept.iter().filter(|(other, _)| other < input).count())
             .collect_vec();
         let permutation = perm_to_ops(&permutation);
-        let rms = rms.iter().map(|axis| AxisOp::Rm(axis.inputs[0][0]));
-        let adds = adds.iter().map(|axis| AxisOp::Add(axis.outputs[0][0]));
-        Ok(rms.chain(permutation).chain(adds).collect())
+        let rms = rms.iter().rev().map(|&input| AxisOp::Rm(input));
+        let adds = adds.iter().map(|&output| AxisOp::Add(output));
+        rms.chain(permutation).chain(adds).collect()
+    }
+
+    /// Same mapping with its inputs reordered: input #i is the former input #`order[i]`.
+    pub fn reorder_inputs(&self, order: &[usize]) -> TractResult<AxesMapping> {
+        ensure!(
+            order.iter().copied().sorted().eq(0..self.input_count),
+            "{order:?} is not an order of the inputs of {self}"
+        );
+        let axes: TVec<Axis> = self
+            .iter_all_axes()
+            .map(|axis| Axis {
+                inputs: order.iter().map(|&i| axis.inputs[i].clone()).collect(),
+                ..axis.clone()
+            })
+            .collect();
+        AxesMapping::new(self.input_count, self.output_count, axes)
+    }
+
+    /// Mapping taking input #`ix` with its axes permuted: its axis at `p` is the one formerly at
+    /// `perm[p]`.
+    pub fn permute_input(
+        &self,
+        ix: usize,
+        perm: &[usize],
+    ) -> TractResult<(AxesMapping, AxesFixes)> {
+        ensure!(ix < self.input_count, "No input #{ix} in {self}");
+        ensure!(
+            perm.iter().copied().sorted().eq(0..self.rank(InOut::In(ix))),
+            "{perm:?} is not a permutation of input #{ix} of {self}"
+        );
+        let mut axes = self.axes.clone();
+        for axis in &mut axes {
+            for pos in &mut axis.inputs[ix] {
+                *pos = perm.iter().position(|p| p == pos).unwrap();
+            }
+            axis.inputs[ix].sort();
+        }
+        let mut fixes = AxesFixes::none(self);
+        fixes.inputs[ix] = perm_to_ops(perm).into_vec();
+        Ok((AxesMapping::new(self.input_count, self.output_count, axes)?, fixes))
+    }
+
+    /// Mapping with the consecutive axes `a` and `b` merged in `a`, of size `a_dim * b_dim`: `b`
+    /// must follow `a` in all interfaces where either appears.
+    pub fn merge_axes(
+        &self,
+        a: char,
+        b: char,
+        a_dim: &TDim,
+        b_dim: &TDim,
+    ) -> TractResult<(AxesMapping, AxesFixes)> {
+        ensure!(a != b, "Can not merge {a} with itself");
+        let (axis_a, axis_b) = (self.axis(a)?, self.axis(b)?);
+        let split: TVec<TDim> = tvec!(a_dim.clone(), b_dim.clone());
+        let merged: TVec<TDim> = tvec!(a_dim.clone() * b_dim.clone());
+        let mut fixes = AxesFixes::none(self);
+        for io in self.interfaces() {
+            match (axis_a.interface(io), axis_b.interface(io)) {
+                ([], []) => (),
+                ([pa], [pb]) if *pb == pa + 1 => {
+                    let fix = if let InOut::In(_) = io {
+                        AxisOp::Reshape(*pa, split.clone(), merged.clone())
+                    } else {
+                        AxisOp::Reshape(*pa, merged.clone(), split.clone())
+                    };
+                    fixes.interface_mut(io).push(fix);
+                }
+                _ => {
+                    bail!("Can not merge {a} and {b} in {self}: {b} does not follow {a} in {io:?}")
+                }
+            }
+        }
+        Ok((self.remove_axis(b)?, fixes))
+    }
+
+    /// Mapping with axis `a` split in consecutive axes of sizes `parts`. The first one keeps the
+    /// label `a`, the others take the first available labels.
+    pub fn split_axis(&self, a: char, parts: &[TDim]) -> TractResult<(AxesMapping, AxesFixes)> {
+        ensure!(!parts.is_empty(), "Can not split {a} in no axis");
+        let axis = self.axis(a)?.clone();
+        let whole: TVec<TDim> = tvec!(parts.iter().cloned().product());
+        let mut extra: Vec<Axis> = labels()
+            .filter(|c| self.iter_all_axes().all(|axis| axis.repr != *c))
+            .take(parts.len() - 1)
+            .map(|repr| Axis::new(repr, self.input_count, self.output_count))
+            .collect();
+        let mut axes = self.axes.clone();
+        let mut fixes = AxesFixes::none(self);
+        for io in self.interfaces() {
+            let &[pos] = axis.interface(io) else {
+                ensure!(axis.interface(io).is_empty(), "Can not split {a}, repeated in {self}");
+                continue;
+            };
+            for other in &mut axes {
+                other
+                    .interface_mut(io)
+                    .iter_mut()
+                    .for_each(|p| *p += (*p > pos) as usize * (parts.len() - 1));
+            }
+            for (ix, new) in extra.iter_mut().enumerate() {
+                new.interface_mut(io).push(pos + 1 + ix);
+            }
+            let fix = if let InOut::In(_) = io {
+                AxisOp::Reshape(pos, whole.clone(), parts.into())
+            } else {
+                AxisOp::Reshape(pos, parts.into(), whole.clone())
+            };
+            fixes.interface_mut(io).push(fix);
+        }
+        axes.extend(extra);
+        Ok((AxesMapping::new(self.input_count, self.output_count, axes)?, fixes))
+    }
+
+    /// Mapping of `other` fed by `self`: the single output of `self` goes to input #`slot` of
+    /// `other`. The inputs of the fused mapping are the ones of `other`, with the inputs of `self`
+    /// in place of `slot`. Its outputs are the ones of `other`.
+    ///
+    /// Axes meeting at the boundary are unified, so an axis repeated in input #`slot` takes the
+    /// diagonal of the axes of `self` it meets there. Axes summed by `self` stay summed.
+    pub fn compose(&self, other: &AxesMapping, slot: usize) -> TractResult<AxesMapping> {
+        ensure!(self.output_count == 1, "Can only compose a single output mapping, got {self}");
+        ensure!(slot < other.input_count, "No input #{slot} in {other}");
+        let rank = self.rank(InOut::Out(0));
+        ensure!(
+            rank == other.rank(InOut::In(slot)),
+            "Output of {self} does not match input #{slot} of {other}"
+        );
+        ensure!(
+            self.iter_all_axes().all(|axis| axis.outputs[0].len() <= 1),
+            "Can not compose {self}, its output repeats an axis"
+        );
+        // union-find over the axes of self, then of other
+        let mut parent = (0..self.axes.len() + other.axes.len()).collect_vec();
+        fn root(parent: &[usize], mut ix: usize) -> usize {
+            while parent[ix] != ix {
+                ix = parent[ix];
+            }
+            ix
+        }
+        for pos in 0..rank {
+            let a = root(&parent, self.search((InOut::Out(0), pos))?);
+            let b = root(&parent, self.axes.len() + other.search((InOut::In(slot), pos))?);
+            parent[a.max(b)] = a.min(b);
+        }
+        let input_count = other.input_count - 1 + self.input_count;
+        let mut labels = labels();
+        let mut fused: Vec<Option<Axis>> = vec![None; parent.len()];
+        for (ix, axis) in self.iter_all_axes().chain(other.iter_all_axes()).enumerate() {
+            let target = fused[root(&parent, ix)].get_or_insert_with(|| {
+                Axis::new(labels.next().unwrap(), input_count, other.output_count)
+            });
+            if ix < self.axes.len() {
+                for (input, positions) in axis.inputs.iter().enumerate() {
+                    target.inputs[slot + input].extend(positions.iter().copied());
+                }
+            } else {
+                for (input, positions) in axis.inputs.iter().enumerate() {
+                    if input != slot {
+                        let input = if input < slot { input } else { input - 1 + self.input_count };
+                        target.inputs[input].extend(positions.iter().copied());
+                    }
+                }
+                for (output, positions) in axis.outputs.iter().enumerate() {
+                    target.outputs[output].extend(positions.iter().copied());
+                }
+            }
+        }
+        let axes: TVec<Axis> = fused
+            .into_iter()
+            .flatten()
+            .map(|mut axis| {
+                axis.inputs.iter_mut().chain(axis.outputs.iter_mut()).for_each(|p| p.sort());
+                axis
+            })
+            .collect();
+        AxesMapping::new(input_count, other.output_count, axes)
     }
 
     pub fn from_strs(
@@ -596,12 +837,15 @@ impl AxesMapping {
 impl FromStr for AxesMapping {
     type Err = TractError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        assert!(!s.contains("..."));
+        ensure!(!s.contains("..."), "Ellipsis is not supported in axes expressions ({s})");
         let s = s.replace(' ', "");
-        let (inputs, outputs) =
-            if let Some((i, r)) = s.split_once("->") { (i, r) } else { (&*s, "") };
+        // an explicit empty output is a scalar, no output is implicit
+        let (inputs, outputs) = if let Some((i, r)) = s.split_once("->") {
+            (i, r.split(',').collect())
+        } else {
+            (&*s, tvec!())
+        };
         let inputs: TVec<&str> = inputs.split(',').collect();
-        let outputs: TVec<&str> = outputs.split(',').filter(|s| s.len() > 0).collect();
         AxesMapping::from_strs(&inputs, &outputs)
     }
 }
@@ -616,6 +860,9 @@ impl Display for AxesMapping {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::ops::einsum::EinSum;
+    use proptest::prelude::*;
+    use proptest::sample::subsequence;
 
     fn m(s: &str) -> AxesMapping {
         s.parse().unwrap()
@@ -668,6 +915,15 @@ mod test {
         assert_eq!(m("i,i"), m("i,i->"))
     }
 
+    #[test]
+    fn test_parse_explicit_scalar_output() {
+        // an arrow with nothing after it is a scalar output summing all the axes, like in
+        // numpy.einsum, where it used to parse as the implicit output
+        assert_eq!(m("ab->").to_strs(), (tvec!("ab".to_string()), tvec!("".to_string())));
+        assert_eq!(m("ab"), m("ab->ab"));
+        assert_ne!(m("ab->"), m("ab"));
+    }
+
     #[test]
     fn test_parse_batch_matmul() {
         assert_eq!(
@@ -788,6 +1044,13 @@ mod test {
         )
     }
 
+    #[test]
+    fn test_canonical() {
+        assert_eq!(m("mk,kn->mn").canonical(), m("ij,jk->ik").canonical());
+        assert_eq!(m("mk,kn->mn").canonical(), m("ac,cb->ab"));
+        assert_ne!(m("mk,kn->mn").canonical(), m("mk,nk->mn").canonical());
+    }
+
     #[test]
     fn test_extract_sub_mapping() {
         assert_eq!(m("bsij,ijk->bsik").extract_sub_mapping(&[0], &[0]).unwrap(), m("bsij->bsik"));
@@ -814,10 +1077,7 @@ mod test {
     fn test_translate_to_ops_rm_add() {
         assert_eq!(m("ab->a").translate_to_axis_ops().unwrap(), vec!(AxisOp::Rm(1)));
         assert_eq!(m("ba->a").translate_to_axis_ops().unwrap(), vec!(AxisOp::Rm(0)));
-        assert_eq!(
-            m("ab->c").translate_to_axis_ops().unwrap(),
-            vec!(AxisOp::Rm(1), AxisOp::Rm(0), AxisOp::Add(0))
-        );
+        assert_eq!(m("ab->c").translate_to_axis_ops().unwrap(), vec!(AxisOp::Rm(1)));
     }
 
     #[test]
@@ -842,4 +1102,252 @@ mod test {
             vec!(AxisOp::Move(2, 0), AxisOp::Move(2, 4))
         );
     }
+
+    #[test]
+    fn test_translate_to_ops_pairs_rm_and_add() {
+        assert_eq!(m("mk->mn").translate_to_axis_ops().unwrap(), vec!());
+        assert_eq!(m("kn->mn").translate_to_axis_ops().unwrap(), vec!());
+        assert_eq!(m("mbk->bmn").translate_to_axis_ops().unwrap(), vec!(AxisOp::Move(1, 0)));
+        assert_eq!(m("bkn->bmn").translate_to_axis_ops().unwrap(), vec!());
+        assert_eq!(m("n->mn").translate_to_axis_ops().unwrap(), vec!(AxisOp::Add(0)));
+    }
+
+    // sub-mappings feeding wire_axes_fix: sums of a and b over k (k kept with size 1) and bias
+    #[test]
+    fn test_translate_to_ops_quantized_corpus() {
+        let exprs = [
+            "mk,kn,,,,,,,->mn",
+            "km,kn,,,,,,,->nm",
+            "bmk,bkn,,,,,,,->bmn",
+            "mbk,bkn,,,,,,,->bmn",
+            "bkm,kn,,,,,,,->bnm",
+            "abmk,bkn,,,,,,,->bamn",
+            "mkb,knb,,,,,,,->bmn",
+            "bhqd,bhkd,,,,,,,->bhqk",
+            "mk,kn,n,,,,,,->mn",
+            "bmk,kn,m,,,,,,->bmn",
+        ];
+        for expr in exprs {
+            let mapping = m(expr);
+            for input in [0, 1, 2] {
+                let sub = mapping.extract_sub_mapping(&[input], &[0]).unwrap();
+                if sub.rank(InOut::In(0)) == 0 {
+                    continue;
+                }
+                let dim = |axis: &Axis| 2 + (axis.repr as usize - 'a' as usize);
+                let mut shape: TVec<usize> = (0..sub.rank(InOut::In(0)))
+                    .map(|p| {
+                        let axis = sub.axis((InOut::In(0), p)).unwrap();
+                        if axis.outputs[0].len() == 0 {
+                            1
+                        } else {
+                            dim(axis)
+                        }
+                    })
+                    .collect();
+                let expected: TVec<usize> = (0..sub.rank(InOut::Out(0)))
+                    .map(|p| {
+                        let axis = sub.axis((InOut::Out(0), p)).unwrap();
+                        if axis.inputs[0].len() == 0 {
+                            1
+                        } else {
+                            dim(axis)
+                        }
+                    })
+                    .collect();
+                let ops = sub.translate_to_axis_ops().unwrap();
+                for op in &ops {
+                    op.change_shape_array(&mut shape, false).unwrap();
+                }
+                assert_eq!(shape, expected, "{expr} input #{input}: {sub} -> {ops:?}");
+                // minimality: only the rank difference is made up with Rm/Add, and the moves
+                // are those of a single permutation
+                let rank_change = ops.iter().filter(|op| !matches!(op, AxisOp::Move(..))).count();
+                assert_eq!(
+                    rank_change,
+                    sub.rank(InOut::In(0)).abs_diff(sub.rank(InOut::Out(0))),
+                    "{expr} input #{input}: {sub} -> {ops:?}"
+                );
+                let moves = ops.iter().positions(|op| matches!(op, AxisOp::Move(..))).collect_vec();
+                assert!(moves.windows(2).all(|w| w[1] == w[0] + 1));
+                assert!(moves.len() < shape.len().max(1));
+                // idempotence: the result needs no further fixing
+                let done: String = sub.axes(InOut::Out(0)).map(|a| a.repr).collect();
+                let done = m(&format!("{done}->{done}"));
+                assert_eq!(done.translate_to_axis_ops().unwrap(), vec!());
+                assert_eq!(sub.translate_to_axis_ops().unwrap(), ops);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reorder_inputs() {
+        assert_eq!(m("mk,kn->mn").reorder_inputs(&[1, 0]).unwrap(), m("kn,mk->mn"));
+        assert_eq!(m("a,b,c->abc").reorder_inputs(&[2, 0, 1]).unwrap(), m("c,a,b->abc"));
+        assert!(m("mk,kn->mn").reorder_inputs(&[0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_permute_input() {
+        let (permuted, fixes) = m("mk,kn->mn").permute_input(0, &[1, 0]).unwrap();
+        assert_eq!(permuted, m("km,kn->mn"));
+        assert_eq!(fixes.inputs[0], vec!(AxisOp::Move(1, 0)));
+        assert!(fixes.inputs[1].is_empty() && fixes.outputs[0].is_empty());
+    }
+
+    #[test]
+    fn test_merge_and_split_axes() {
+        let (two, three) = (2.to_dim(), 3.to_dim());
+        let (merged, fixes) = m("bmk,kn->bmn").merge_axes('b', 'm', &two, &three).unwrap();
+        assert_eq!(merged, m("bk,kn->bn"));
+        let (split, merged_dims): (TVec<TDim>, TVec<TDim>) =
+            (tvec!(two.clone(), three.clone()), tvec!(6.to_dim()));
+        assert_eq!(fixes.inputs[0], vec!(AxisOp::Reshape(0, split.clone(), merged_dims.clone())));
+        assert!(fixes.inputs[1].is_empty());
+        assert_eq!(fixes.outputs[0], vec!(AxisOp::Reshape(0, merged_dims, split)));
+        assert!(m("bmk,bkn->bmn").merge_axes('b', 'm', &two, &three).is_err());
+        assert!(m("bmk,kn->bmn").merge_axes('m', 'b', &two, &three).is_err());
+        let (split, _) = merged.split_axis('b', &[two, three]).unwrap();
+        assert_eq!(split, m("bak,kn->ban"));
+    }
+
+    #[test]
+    fn test_compose() {
+        assert_eq!(
+            m("mk,kn->mn").compose(&m("mn,n->m"), 0).unwrap().canonical(),
+            m("mk,kn,n->m").canonical()
+        );
+        assert_eq!(
+            m("ij->ji").compose(&m("ab,bc->ac"), 1).unwrap().canonical(),
+            m("ab,cb->ac").canonical()
+        );
+        // a repeated axis at the boundary takes the diagonal
+        assert_eq!(
+            m("i,j->ij").compose(&m("aa->a"), 0).unwrap().canonical(),
+            m("a,a->a").canonical()
+        );
+        assert!(m("i,j->ij").compose(&m("a->a"), 0).is_err());
+    }
+
+    fn eval(mapping: &AxesMapping, inputs: TVec<Tensor>) -> Tensor {
+        let inputs = inputs.into_iter().map(|t| t.into_tvalue()).collect();
+        let op = EinSum::new(mapping.clone(), f32::datum_type());
+        op.eval(inputs).unwrap().remove(0).into_tensor()
+    }
+
+    fn fix(ops: &[AxisOp], mut tensor: Tensor) -> Tensor {
+        for op in ops {
+            op.change_tensor(&mut tensor, false).unwrap();
+        }
+        tensor
+    }
+
+    /// Tensors for the inputs of `mapping`, of small integers so that sums are exact.
+    fn inputs(mapping: &AxesMapping, dims: &HashMap<char, usize>, seed: usize) -> TVec<Tensor> {
+        let (inputs, _) = mapping.to_strs();
+        inputs
+            .iter()
+            .enumerate()
+            .map(|(ix, labels)| {
+                let shape = labels.chars().map(|c| dims[&c]).collect_vec();
+                let len = shape.iter().product::<usize>();
+                let data =
+                    (0..len).map(|i| ((i * 7 + ix * 3 + seed) % 5) as f32 - 2.).collect_vec();
+                Tensor::from_shape(&shape, &data).unwrap()
+            })
+            .collect()
+    }
+
+    fn subset(labels: Vec<char>) -> impl Strategy<Value = String> {
+        let len = labels.len();
+        subsequence(labels, 0..=len).prop_shuffle().prop_map(|v| v.into_iter().collect())
+    }
+
+    /// A two inputs mapping over axes a to e.
+    fn mapping() -> impl Strategy<Value = AxesMapping> {
+        let pool = || ('a'..='e').collect_vec();
+        (subset(pool()), subset(pool()))
+            .prop_flat_map(|(a, b)| {
+                let used = a.chars().chain(b.chars()).unique().collect_vec();
+                (Just(a), Just(b), subset(used))
+            })
+            .prop_map(|(a, b, c)| AxesMapping::from_strs(&[a, b], &[c]).unwrap())
+    }
+
+    fn dims() -> impl Strategy<Value = HashMap<char, usize>> {
+        proptest::collection::vec(1usize..4, 7).prop_map(|d| ('a'..).zip(d).collect())
+    }
+
+    /// Two mappings over axes a to g, the output of the first fitting input #slot of the second.
+    fn compose_problem() -> impl Strategy<Value = (AxesMapping, AxesMapping, usize)> {
+        (mapping(), subset(('a'..='g').collect_vec()), 0..2usize)
+            .prop_flat_map(|(first, other, slot)| {
+                let mut inputs = vec![other.clone()];
+                inputs.insert(slot, first.to_strs().1[0].clone());
+                let used = inputs.iter().flat_map(|i| i.chars()).unique().collect_vec();
+                (Just(first), Just(inputs), subset(used), Just(slot))
+            })
+            .prop_map(|(first, inputs, output, slot)| {
+                (first, AxesMapping::from_strs(&inputs, &[output]).unwrap(), slot)
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn prop_compose((first, second, slot) in compose_problem(), dims in dims(), seed in 0usize..5) {
+            let first_inputs = inputs(&first, &dims, seed);
+            let mut second_inputs = inputs(&second, &dims, seed + 1);
+            second_inputs[slot] = eval(&first, first_inputs.clone());
+            let expected = eval(&second, second_inputs.clone());
+            let fused = first.compose(&second, slot).unwrap();
+            let mut fused_inputs = second_inputs;
+            fused_inputs.remove(slot);
+            fused_inputs.insert_many(slot, first_inputs);
+            prop_assert_eq!(eval(&fused, fused_inputs), expected, "{} with {} at {}: {}", first, second, slot, fused);
+        }
+
+        #[test]
+        fn prop_permute_input(
+            (mapping, perm) in mapping().prop_flat_map(|mapping| {
+                let perm = Just((0..mapping.rank(InOut::In(0))).collect_vec()).prop_shuffle();
+                (Just(mapping), perm)
+            }),
+            dims in dims(),
+            seed in 0usize..5,
+        ) {
+            let x = inputs(&mapping, &dims, seed);
+            let (permuted, fixes) = mapping.permute_input(0, &perm).unwrap();
+            let fixed = x.iter().zip(&fixes.inputs).map(|(x, ops)| fix(ops, x.clone())).collect();
+            prop_assert_eq!(eval(&permuted, fixed), eval(&mapping, x));
+        }
+
+        #[test]
+        fn prop_split_and_merge(
+            (mapping, axis) in mapping().prop_filter("no axis", |m| m.axes.len() > 0)
+                .prop_flat_map(|m| { let len = m.axes.len(); (Just(m), 0..len) }),
+            parts in (1usize..4, 1usize..4),
+            dims in dims(),
+            seed in 0usize..5,
+        ) {
+            let a = mapping.axes[axis].repr;
+            let b = mapping.available_label();
+            let parts_dims = [parts.0.to_dim(), parts.1.to_dim()];
+            let mut dims = dims;
+            dims.insert(a, parts.0 * parts.1);
+            let x = inputs(&mapping, &dims, seed);
+            let (split, fixes) = mapping.split_axis(a, &parts_dims).unwrap();
+            let fixed = x.iter().zip(&fixes.inputs).map(|(x, ops)| fix(ops, x.clone())).collect();
+            let found = fix(&fixes.outputs[0], eval(&split, fixed));
+            prop_assert_eq!(&found, &eval(&mapping, x));
+
+            dims.insert(a, parts.0);
+            dims.insert(b, parts.1);
+            let x = inputs(&split, &dims, seed);
+            let (merged, fixes) = split.merge_axes(a, b, &parts_dims[0], &parts_dims[1]).unwrap();
+            prop_assert_eq!(&merged, &mapping);
+            let fixed = x.iter().zip(&fixes.inputs).map(|(x, ops)| fix(ops, x.clone())).collect();
+            let found = fix(&fixes.outputs[0], eval(&merged, fixed));
+            prop_assert_eq!(found, eval(&split, x));
+        }
+    }
 }
@@ -3,7 +3,7 @@ use crate::internal::*;
 mod mapping;
 mod model;
 
-pub use mapping::AxesMapping;
+pub use mapping::{AxesFixes, AxesMapping};
 pub use model::{for_model, full_axis_tracking};
 
 #[derive(Debug, Clone, PartialEq, Eq, Default, Hash)]
@@ -73,4 +73,11 @@ impl Axis {
             InOut::Out(ix) => &self.outputs[ix],
         }
     }
+
+    fn interface_mut(&mut self, io: InOut) -> &mut TVec<usize> {
+        match io {
+            InOut::In(ix) => &mut self.inputs[ix],
+            InOut::Out(ix) => &mut self.outputs[ix],
+        }
+    }
 }
@@ -0,0 +1,254 @@
+//! Matrix multiplication throughput measurement over a shape sweep.
+//!
+//! Each problem is built as an EinSum graph, optimized, then timed in steady state, so the
+//! figures account for the lowering tract actually picks (operand swap, packing, kernel).
+use crate::internal::*;
+use crate::ops::einsum::EinSum;
+use crate::ops::matmul::lir_unary::LirMatMulUnary;
+use std::time::{Duration, Instant};
+
+/// A matrix multiplication to benchmark: `batch x m x k` by `batch x k x n`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatMulProblem {
+    pub a_dt: DatumType,
+    pub b_dt: DatumType,
+    /// Accumulator type.
+    pub acc_dt: DatumType,
+    /// Quantized output type. If set, the einsum is a quantized one.
+    pub q_output_dt: Option<DatumType>,
+    pub batch: TVec<usize>,
+    pub m: usize,
+    pub k: usize,
+    pub n: usize,
+    pub a_const: bool,
+    pub b_const: bool,
+}
+
+impl MatMulProblem {
+    pub fn f32(m: usize, k: usize, n: usize) -> MatMulProblem {
+        MatMulProblem {
+            a_dt: f32::datum_type(),
+            b_dt: f32::datum_type(),
+            acc_dt: f32::datum_type(),
+            q_output_dt: None,
+            batch: tvec!(),
+            m,
+            k,
+            n,
+            a_const: true,
+            b_const: false,
+        }
+    }
+
+    /// i8 by i8 with i32 accumulation, requantized to i8.
+    pub fn i8(m: usize, k: usize, n: usize) -> MatMulProblem {
+        MatMulProblem {
+            a_dt: i8::datum_type(),
+            b_dt: i8::datum_type(),
+            acc_dt: i32::datum_type(),
+            q_output_dt: Some(i8::datum_type()),
+            ..Self::f32(m, k, n)
+        }
+    }
+
+    /// Number of floating (or integer) operations, counting a multiply-add as two.
+    pub fn flops(&self) -> u64 {
+        2 * (self.batch.iter().product::<usize>() * self.m * self.k * self.n) as u64
+    }
+
+    fn shape(&self, rows: usize, cols: usize) -> TVec<usize> {
+        self.batch.iter().copied().chain([rows, cols]).collect()
+    }
+
+    /// The unoptimized model computing this problem. Its inputs are the non-constant operands.
+    pub fn model(&self) -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let mut operand = |name: &str, dt: DatumType, shape: TVec<usize>, konst: bool| {
+            if konst {
+                let t = Tensor::zero_dt(dt, &shape)?;
+                model.add_const(name, t)
+            } else {
+                model.add_source(name, dt.fact(shape))
+            }
+        };
+        let a = operand("a", self.a_dt, self.shape(self.m, self.k), self.a_const)?;
+        let b = operand("b", self.b_dt, self.shape(self.k, self.n), self.b_const)?;
+        let batch: String = ('a'..).take(self.batch.len()).collect();
+        let expr = format!("{batch}mk,{batch}kn->{batch}mn");
+        let mut inputs = tvec!(a, b);
+        let op = if let Some(q) = self.q_output_dt {
+            for (name, t) in [
+                ("bias", tensor0(0i32)),
+                ("a0", tensor0(0i32)),
+                ("a_scale", tensor0(1f32)),
+                ("b0", tensor0(0i32)),
+                ("b_scale", tensor0(1f32)),
+                ("c0", tensor0(0i32)),
+                ("c_scale", tensor0(1f32)),
+            ] {
+                inputs.push(model.add_const(name, t)?);
+            }
+            let expr = format!("{batch}mk,{batch}kn,,,,,,,->{batch}mn");
+            EinSum::newq(expr.parse()?, self.acc_dt, q)
+        } else {
+            EinSum::new(expr.parse()?, self.acc_dt)
+        };
+        let c = model.wire_node("matmul", op, &inputs)?;
+        model.set_output_outlets(&c)?;
+        Ok(model)
+    }
+}
+
+impl std::fmt::Display for MatMulProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for b in &self.batch {
+            write!(f, "{b}x")?;
+        }
+        write!(f, "{}x{}x{} {:?}", self.m, self.k, self.n, self.a_dt)?;
+        write!(f, "{}*{:?}", if self.a_const { "(const)" } else { "" }, self.b_dt)?;
+        write!(f, "{}->{:?}", if self.b_const { "(const)" } else { "" }, self.acc_dt)?;
+        if let Some(q) = self.q_output_dt {
+            write!(f, "->{q:?}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Benchmark settings.
+#[derive(Clone, Debug)]
+pub struct BenchOpts {
+    /// Time spent running the model before measuring.
+    pub warmup: Duration,
+    /// Number of measured runs.
+    pub samples: usize,
+    /// Stop sampling after this time, even if fewer runs were measured.
+    pub max_time: Duration,
+    /// Fraction of the slowest and of the fastest samples discarded as outliers.
+    pub outliers: f64,
+}
+
+impl Default for BenchOpts {
+    fn default() -> BenchOpts {
+        BenchOpts {
+            warmup: Duration::from_millis(100),
+            samples: 100,
+            max_time: Duration::from_secs(5),
+            outliers: 0.1,
+        }
+    }
+}
+
+/// Steady-state measurement of a MatMulProblem.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct BenchReport {
+    /// Problem description (see `MatMulProblem` Display).
+    pub problem: String,
+    /// Operation count of the problem, a multiply-add counting as two.
+    pub flops: u64,
+    /// Kernels selected by the optimized model.
+    pub kernels: Vec<String>,
+    /// Measured runs, after outlier rejection.
+    pub samples: usize,
+    pub median_secs: f64,
+    pub mean_secs: f64,
+    pub min_secs: f64,
+    /// Throughput at median latency.
+    pub gflops: f64,
+}
+
+/// Benchmark each problem in turn.
+pub fn bench_matmul(problems: &[MatMulProblem], opts: &BenchOpts) -> TractResult<Vec<BenchReport>> {
+    problems
+        .iter()
+        .map(|p| bench_problem(p, opts).with_context(|| format!("Benchmarking {p}")))
+        .collect()
+}
+
+fn bench_problem(problem: &MatMulProblem, opts: &BenchOpts) -> TractResult<BenchReport> {
+    let model = problem.model()?.into_optimized()?;
+    let kernels = model
+        .nodes()
+        .iter()
+        .filter_map(|n| n.op_as::<LirMatMulUnary>())
+        .map(|op| op.mmm.kernel_name().to_string())
+        .collect();
+    let inputs: TVec<TValue> = model
+        .input_outlets()?
+        .iter()
+        .map(|i| {
+            let fact = model.outlet_fact(*i)?;
+            Ok(Tensor::zero_dt(fact.datum_type, &fact.shape.as_concrete().unwrap())?.into_tvalue())
+        })
+        .collect::<TractResult<_>>()?;
+    let plan = model.into_runnable()?;
+    let mut state = SimpleState::new(&plan)?;
+
+    let start = Instant::now();
+    loop {
+        state.run(inputs.clone())?;
+        if start.elapsed() >= opts.warmup {
+            break;
+        }
+    }
+
+    let mut samples = vec![];
+    let start = Instant::now();
+    while samples.len() < opts.samples.max(1)
+        && (samples.is_empty() || start.elapsed() < opts.max_time)
+    {
+        let run = Instant::now();
+        state.run(inputs.clone())?;
+        samples.push(run.elapsed().as_secs_f64());
+    }
+    samples.sort_by(|a, b| a.total_cmp(b));
+    let discard = (samples.len() as f64 * opts.outliers.clamp(0., 0.49)) as usize;
+    let samples = &samples[discard..samples.len() - discard];
+
+    let median_secs = samples[samples.len() / 2];
+    let flops = problem.flops();
+    Ok(BenchReport {
+        problem: problem.to_string(),
+        flops,
+        kernels,
+        samples: samples.len(),
+        median_secs,
+        mean_secs: samples.iter().sum::<f64>() / samples.len() as f64,
+        min_secs: samples[0],
+        gflops: flops as f64 / median_secs / 1e9,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn smoke() -> TractResult<()> {
+        let problems = [
+            MatMulProblem::f32(16, 32, 8),
+            MatMulProblem { batch: tvec!(2), a_const: false, ..MatMulProblem::f32(4, 8, 1) },
+            MatMulProblem::i8(8, 16, 4),
+        ];
+        let opts = BenchOpts { warmup: Duration::ZERO, samples: 5, ..BenchOpts::default() };
+        let reports = bench_matmul(&problems, &opts)?;
+        for (problem, report) in problems.iter().zip(&reports) {
+            // flops agree with the cost model: one multiply-add per FMA
+            let model = problem.model()?;
+            let node = model.node_by_name("matmul")?;
+            let input_facts = model.node_input_facts(node.id)?;
+            let fmas: TDim = node
+                .op
+                .cost(&input_facts)?
+                .into_iter()
+                .filter(|(c, _)| matches!(c, Cost::FMA(_)))
+                .map(|(_, n)| n)
+                .sum();
+            assert_eq!(report.flops, 2 * fmas.to_usize()? as u64);
+            assert!(!report.kernels.is_empty());
+            assert!(report.samples > 0 && report.gflops > 0.);
+        }
+        let json = serde_json::to_value(&reports)?;
+        assert_eq!(json[0]["flops"], 2 * 16 * 32 * 8);
+        Ok(())
+    }
+}
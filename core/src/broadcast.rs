@@ -1,6 +1,65 @@
 //! N-way tensor broadcast
+use std::fmt;
 use tract_data::internal::*;
 
+/// Dimensions meeting on an axis that do not broadcast together.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BroadcastError<D> {
+    pub axis: usize,
+    pub dims: TVec<D>,
+}
+
+impl<D: fmt::Debug> fmt::Display for BroadcastError<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Dimensions {:?} do not broadcast on axis #{}", self.dims, self.axis)
+    }
+}
+
+impl<D: fmt::Debug> std::error::Error for BroadcastError<D> {}
+
+/// Resolves the dimensions meeting on an axis.
+///
+/// A dimension of 1 broadcasts to anything, symbols included. Other concrete dimensions must be
+/// equal. A symbolic dimension is assumed to match the others and wins over a concrete one, so
+/// the result does not depend on the order of the dimensions. `axis` is only used for reporting.
+pub fn broadcast_dim<'d, D: DimLike>(
+    axis: usize,
+    dims: impl IntoIterator<Item = &'d D>,
+) -> Result<D, BroadcastError<D>> {
+    let one = D::one();
+    let dims: TVec<&D> = dims.into_iter().filter(|d| **d != one).collect();
+    let concrete: TVec<usize> = dims.iter().filter_map(|d| d.to_usize().ok()).collect();
+    if concrete.iter().any(|d| *d != concrete[0]) {
+        return Err(BroadcastError { axis, dims: dims.into_iter().cloned().collect() });
+    }
+    Ok(dims
+        .iter()
+        .find(|d| d.to_usize().is_err())
+        .or(dims.first())
+        .map(|d| (*d).clone())
+        .unwrap_or(one))
+}
+
+/// Broadcasts shapes aligned on their last axis, like numpy does, under the rules of
+/// `broadcast_dim`.
+pub fn broadcast_shapes<D: DimLike>(
+    shapes: &[impl AsRef<[D]>],
+) -> Result<TVec<D>, BroadcastError<D>> {
+    let one = D::one();
+    let rank = shapes.iter().map(|shape| shape.as_ref().len()).max().unwrap_or(0);
+    (0..rank)
+        .map(|axis| {
+            broadcast_dim(
+                axis,
+                shapes.iter().map(|shape| {
+                    let shape = shape.as_ref();
+                    (axis + shape.len()).checked_sub(rank).map(|ix| &shape[ix]).unwrap_or(&one)
+                }),
+            )
+        })
+        .collect()
+}
+
 /// Computes a shape, if any, to which all shapes can be broadcasted.
 pub fn multi_broadcast<D>(shapes: &[impl AsRef<[D]>]) -> Option<TVec<D>>
 where
@@ -61,4 +120,39 @@ mod tests {
             Some(tvec![2, 3, 4, 5])
         )
     }
+
+    #[test]
+    fn one_broadcasts_to_symbol() {
+        let s = SymbolTable::default().sym("S");
+        let dims = [1.to_dim(), s.to_dim()];
+        assert_eq!(broadcast_dim(0, &dims), Ok(s.to_dim()));
+        assert_eq!(broadcast_dim(0, dims.iter().rev()), Ok(s.to_dim()));
+    }
+
+    #[test]
+    fn symbol_wins_over_integer() {
+        let s = SymbolTable::default().sym("S");
+        let dims = [3.to_dim(), s.to_dim(), 1.to_dim()];
+        assert_eq!(broadcast_dim(0, &dims), Ok(s.to_dim()));
+        assert_eq!(broadcast_dim(0, dims.iter().rev()), Ok(s.to_dim()));
+    }
+
+    #[test]
+    fn integers_must_match() {
+        let dims = [3.to_dim(), 1.to_dim(), 4.to_dim()];
+        assert_eq!(
+            broadcast_dim(2, &dims),
+            Err(BroadcastError { axis: 2, dims: tvec!(3.to_dim(), 4.to_dim()) })
+        );
+        assert_eq!(broadcast_dim::<usize>(0, &[]), Ok(1));
+    }
+
+    #[test]
+    fn shapes_are_aligned_on_last_axis() {
+        assert_eq!(broadcast_shapes(&[&[2usize, 1, 5][..], &[3, 1]]), Ok(tvec!(2, 3, 5)));
+        assert_eq!(
+            broadcast_shapes(&[&[2usize, 3][..], &[4, 3]]),
+            Err(BroadcastError { axis: 0, dims: tvec!(2, 4) })
+        );
+    }
 }
@@ -72,6 +72,8 @@ pub mod macros;
 pub mod ops;
 
 pub mod axes;
+#[cfg(feature = "bench")]
+pub mod bench;
 pub mod broadcast;
 pub mod framework;
 pub mod half;
@@ -88,7 +90,7 @@ mod late_bind;
 pub mod prelude {
     pub use crate::framework::Framework;
     pub use crate::model::*;
-    pub use crate::plan::{SimplePlan, SimpleState};
+    pub use crate::plan::{CancellationToken, OutputStats, SimplePlan, SimpleState};
     pub use crate::value::{IntoTValue, TValue};
     pub use std::sync::Arc;
     pub use tract_data::prelude::*;
@@ -178,6 +178,17 @@ impl AsRef<[TDim]> for ShapeFact {
     }
 }
 
+/// Semantic role of a tensor, guiding optimizations that depend on its lifetime.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TensorRole {
+    /// Long-lived parameter (typically weights). On a model input, the caller promises the
+    /// value does not change between runs until it bumps the state parameters generation
+    /// (see `SimpleState::bump_parameters_generation`).
+    Parameter,
+    /// Per-inference data.
+    Activation,
+}
+
 /// Fully determined tensor information for TypedModel.
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct TypedFact {
@@ -189,6 +200,8 @@ pub struct TypedFact {
     pub konst: Option<Arc<Tensor>>,
     /// optional uniform value
     pub uniform: Option<Arc<Tensor>>,
+    /// optional semantic role
+    pub role: Option<TensorRole>,
 }
 
 impl TypedFact {
@@ -208,14 +221,14 @@ impl TypedFact {
     }
 
     pub fn dt_scalar(datum_type: DatumType) -> TypedFact {
-        TypedFact { datum_type, shape: ShapeFact::scalar(), konst: None, uniform: None }
+        TypedFact { datum_type, shape: ShapeFact::scalar(), konst: None, uniform: None, role: None }
     }
 
     pub fn dt_shape<S>(datum_type: DatumType, shape: S) -> TypedFact
     where
         S: Into<ShapeFact>,
     {
-        TypedFact { datum_type, shape: shape.into(), konst: None, uniform: None }
+        TypedFact { datum_type, shape: shape.into(), konst: None, uniform: None, role: None }
     }
 
     pub fn rank(&self) -> usize {
@@ -266,6 +279,10 @@ impl TypedFact {
     pub fn without_value(&self) -> Self {
         Self::dt_shape(self.datum_type, self.shape.clone())
     }
+
+    pub fn with_role(self, role: TensorRole) -> Self {
+        Self { role: Some(role), ..self }
+    }
 }
 
 impl Fact for TypedFact {
@@ -344,6 +361,7 @@ impl From<Arc<Tensor>> for TypedFact {
             shape: ShapeFact::from_dims(t.shape().iter().map(TDim::from)),
             uniform: t.as_uniform().map(Arc::new),
             konst: Some(t),
+            role: None,
         }
     }
 }
@@ -357,10 +375,14 @@ impl<'a> From<&'a TypedFact> for TypedFact {
 impl fmt::Debug for TypedFact {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match self.konst {
-            Some(ref k) => write!(fmt, "{k:?}"),
-            None if self.rank() > 0 => write!(fmt, "{:?},{:?}", self.shape, self.datum_type),
-            None => write!(fmt, "{:?}", self.datum_type),
+            Some(ref k) => write!(fmt, "{k:?}")?,
+            None if self.rank() > 0 => write!(fmt, "{:?},{:?}", self.shape, self.datum_type)?,
+            None => write!(fmt, "{:?}", self.datum_type)?,
+        }
+        if let Some(role) = self.role {
+            write!(fmt, " ({role:?})")?;
         }
+        Ok(())
     }
 }
 
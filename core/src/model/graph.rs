@@ -39,6 +39,10 @@ where
     pub properties: HashMap<String, Arc<Tensor>>,
     /// symbol table
     pub symbol_table: SymbolTable,
+    /// labels of the patches that created or rewired each node, when tracked by the optimizer
+    pub node_patches: Option<HashMap<usize, Vec<String>>>,
+    /// node properties, carried over to the nodes replacing them by patches
+    pub node_properties: HashMap<usize, HashMap<String, Arc<Tensor>>>,
 }
 
 impl<F, O> Default for Graph<F, O>
@@ -54,6 +58,8 @@ where
             outlet_labels: HashMap::new(),
             properties: HashMap::new(),
             symbol_table: Default::default(),
+            node_patches: None,
+            node_properties: HashMap::new(),
         }
     }
 }
@@ -420,6 +426,37 @@ where
         self.outlet_labels.iter().find(|(_k, v)| **v == label).map(|(k, _v)| *k)
     }
 
+    // patch provenance
+
+    /// Labels of the patches that created or rewired a node, oldest first.
+    ///
+    /// Empty unless patch tracking was enabled in the optimizer options.
+    pub fn node_patches(&self, id: usize) -> &[String] {
+        self.node_patches.as_ref().and_then(|p| p.get(&id)).map(|p| &**p).unwrap_or(&[])
+    }
+
+    // node properties
+
+    /// Get a property of a node.
+    pub fn node_property(&self, id: usize, key: &str) -> Option<&Arc<Tensor>> {
+        self.node_properties.get(&id).and_then(|props| props.get(key))
+    }
+
+    /// Set a property of a node.
+    ///
+    /// Properties follow the node through optimisation: a patch replacing the node passes them
+    /// on to the node replacing its output.
+    pub fn set_node_property(
+        &mut self,
+        id: usize,
+        key: impl Into<String>,
+        value: Arc<Tensor>,
+    ) -> TractResult<()> {
+        ensure!(id < self.nodes.len(), "Invalid node id {id}");
+        self.node_properties.entry(id).or_default().insert(key.into(), value);
+        Ok(())
+    }
+
     // misc
 
     /// Computes an evalutation order for the graph inputs and outputs
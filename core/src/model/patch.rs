@@ -247,13 +247,16 @@ where
         let prior_target_inputs = target.input_outlets()?.len();
         let prior_target_outputs = target.output_outlets()?.len();
         let ModelPatch {
-            model: patch,
+            context,
+            model: mut patch,
             taps: mut mapping,
             shunts: shunt_outlet_by,
             obliterate,
             inputs: replaced_inputs,
             ..
         } = self;
+        let first_new_node = target.nodes.len();
+        let mut patch_properties = std::mem::take(&mut patch.node_properties);
         let mut all_inputs = HashMap::new(); // new_node_id_in_model -> [ patch_outlet_id ]
         let mut model_input_outlets = target.input_outlets()?.to_vec();
         for node in patch.nodes {
@@ -281,6 +284,9 @@ where
             }
             let facts = outputs.into_iter().map(|of| of.fact).collect();
             let added_node_id = target.add_node(name, op, facts)?;
+            if let Some(props) = patch_properties.remove(&patch_node_id) {
+                target.node_properties.insert(added_node_id, props);
+            }
             for ix in 0..n_outputs {
                 mapping.insert(OutletId::new(patch_node_id, ix), OutletId::new(added_node_id, ix));
             }
@@ -296,11 +302,13 @@ where
         }
         debug_assert_eq!(target.input_outlets()?.len(), prior_target_inputs);
         debug_assert_eq!(target.output_outlets()?.len(), prior_target_outputs);
+        let mut rewired = vec![];
         for (&outlet, &by) in shunt_outlet_by.iter().sorted() {
             let replace_by = mapping[&by];
             let succs = target.nodes()[outlet.node].outputs[outlet.slot].successors.clone();
             for succ in succs {
                 target.add_edge(replace_by, succ)?;
+                rewired.push(succ.node);
             }
             for o in target.outputs.iter_mut() {
                 if *o == outlet {
@@ -310,6 +318,14 @@ where
             if let Some(label) = target.outlet_labels.remove(&outlet) {
                 target.set_outlet_label(replace_by, label)?;
             }
+            // the node replacing an output inherits the properties of the node it replaces
+            if replace_by.node >= first_new_node
+                && !target.node_properties.contains_key(&replace_by.node)
+            {
+                if let Some(props) = target.node_properties.get(&outlet.node).cloned() {
+                    target.node_properties.insert(replace_by.node, props);
+                }
+            }
         }
         if target.outputs.len() > target.outputs.iter().sorted().dedup().count() {
             bail!("Duplicate usage of node as output");
@@ -323,8 +339,31 @@ where
         }
         debug_assert_eq!(target.input_outlets()?.len(), prior_target_inputs);
         debug_assert_eq!(target.output_outlets()?.len(), prior_target_outputs);
+        if let Some(patches) = target.node_patches.as_mut() {
+            // new nodes inherit the history of the node they replace, then get this patch label
+            for (&outlet, &by) in shunt_outlet_by.iter().sorted() {
+                let by = mapping[&by].node;
+                if by >= first_new_node && !patches.contains_key(&by) {
+                    if let Some(history) = patches.get(&outlet.node).cloned() {
+                        patches.insert(by, history);
+                    }
+                }
+            }
+            if let Some(label) = context.first() {
+                for node in (first_new_node..target.nodes.len()).chain(rewired).sorted().dedup() {
+                    patches.entry(node).or_default().push(label.clone());
+                }
+            }
+        }
         for node in obliterate {
             target.node_mut(node).op = target.create_dummy();
+            // release constant values now instead of waiting for the next compaction
+            for output in &mut target.node_mut(node).outputs {
+                if let Some(fact) = output.fact.as_any_mut().downcast_mut::<TypedFact>() {
+                    fact.konst = None;
+                    fact.uniform = None;
+                }
+            }
         }
         debug_assert_eq!(target.input_outlets()?.len(), prior_target_inputs);
         debug_assert_eq!(target.output_outlets()?.len(), prior_target_outputs);
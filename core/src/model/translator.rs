@@ -57,6 +57,21 @@ where
         target.outputs = source.output_outlets()?.iter().map(|o| mapping[o]).collect();
         target.symbol_table = source.symbol_table.clone();
         target.properties = source.properties.clone();
+        target.node_patches = source.node_patches.as_ref().map(|patches| {
+            patches
+                .iter()
+                .filter_map(|(id, labels)| {
+                    mapping.get(&OutletId::new(*id, 0)).map(|o| (o.node, labels.clone()))
+                })
+                .collect()
+        });
+        target.node_properties = source
+            .node_properties
+            .iter()
+            .filter_map(|(id, props)| {
+                mapping.get(&OutletId::new(*id, 0)).map(|o| (o.node, props.clone()))
+            })
+            .collect();
         Ok((target, mapping))
     }
 }
@@ -1,7 +1,8 @@
 use crate::internal::*;
 use crate::model::*;
 use crate::ops;
-use crate::optim::OptimizerSession;
+use crate::optim::report::OptimizationReport;
+use crate::optim::{OptimizerOptions, OptimizerSession};
 use crate::plan::{FrozenSimpleState, SimplePlan, SimpleState};
 
 /// A model with completely determined types and shapes.
@@ -55,6 +56,12 @@ impl SpecialOps<TypedFact, Box<dyn TypedOp>> for TypedModel {
                     .map(|f| f.konst.clone().map(|t| t.into_tvalue()))
                     .collect::<Option<TVec<_>>>()
                 {
+                    // a packed matmul constant is digested as it was before packing
+                    let unpacked = op
+                        .as_op()
+                        .downcast_ref::<crate::ops::matmul::pack::MatMatMulPack>()
+                        .filter(|pack| pack.mask_axis.is_none())
+                        .map(|_| tensors[0].clone());
                     if let Ok(outputs) = op.eval(tensors) {
                         return outputs
                             .into_iter()
@@ -62,7 +69,11 @@ impl SpecialOps<TypedFact, Box<dyn TypedOp>> for TypedModel {
                             .map(|(ix, o)| {
                                 let name =
                                     if ix == 0 { name.clone() } else { format!("{name}.{ix}") };
-                                self.add_const(name, o)
+                                let wire = self.add_const(name, o)?;
+                                if let Some(unpacked) = &unpacked {
+                                    crate::ops::konst::record_digest(self, wire.node, unpacked)?;
+                                }
+                                Ok(wire)
                             })
                             .collect::<TractResult<TVec<OutletId>>>();
                     }
@@ -97,6 +108,34 @@ impl TypedModel {
         self.optimize()?;
         Ok(self)
     }
+
+    /// Declutter and optimize the model, using non-default optimizer options.
+    pub fn into_optimized_with_options(
+        mut self,
+        options: &OptimizerOptions,
+    ) -> TractResult<TypedModel> {
+        crate::optim::Optimizer::declutter().with_options(options.clone()).optimize(&mut self)?;
+        crate::optim::Optimizer::codegen().with_options(options.clone()).optimize(&mut self)?;
+        Ok(self)
+    }
+
+    /// Declutter and optimize the model, reporting the outcomes of the matrix multiplication
+    /// lowering rules.
+    pub fn into_optimized_with_report(
+        mut self,
+        options: &OptimizerOptions,
+    ) -> TractResult<(TypedModel, OptimizationReport)> {
+        crate::optim::Optimizer::declutter().with_options(options.clone()).optimize(&mut self)?;
+        let einsums = self.nodes().iter().filter(|n| n.op_is::<ops::einsum::EinSum>()).count();
+        let optimizer = crate::optim::Optimizer::codegen().with_options(options.clone());
+        let mut session = optimizer.session();
+        session.optimize(&mut self)?;
+        let mut report = session.into_report();
+        report.einsums = einsums;
+        report.summarize(&self);
+        Ok((self, report))
+    }
+
     #[cfg(not(all(debug_assertions, feature = "paranoid_assertions")))]
     #[inline]
     pub fn check_consistency(&self) -> TractResult<()> {
@@ -112,7 +151,10 @@ impl TypedModel {
             if node.id != *node_id {
                 bail!("Node at position {} has id {}", node_id, node.id);
             }
-            let output_facts = node.op.output_facts(&input_facts)?;
+            let output_facts = node
+                .op
+                .output_facts(&input_facts)
+                .with_context(|| format!("in output_facts invocation for {node}"))?;
             if node.outputs.len() != output_facts.len() {
                 bail!(
                     "Inconsistent model, node output count mismatch. Op says {}, node says {}. {}",
@@ -144,6 +186,66 @@ impl TypedModel {
         Ok(())
     }
 
+    /// Tag the `input`-th model input with a semantic role.
+    ///
+    /// Tagging an input as a `TensorRole::Parameter` is a promise its value only changes when
+    /// the state parameters generation is bumped, letting the optimized model cache what it
+    /// derives from it (like packed matrix multiplication operands).
+    pub fn set_input_role(&mut self, input: usize, role: TensorRole) -> TractResult<()> {
+        let outlet = self.input_outlets()?[input];
+        let fact = self.outlet_fact(outlet)?.clone().with_role(role);
+        let source = self.node_mut(outlet.node).op_as_mut::<ops::source::TypedSource>();
+        source.context("Model input is not a source")?.fact = fact.clone();
+        self.set_outlet_fact(outlet, fact)
+    }
+
+    /// Compute only some rows of the `output`-th model output along `axis`: the ones indexed by
+    /// a new, last, model input, a 1D tensor of i64 (like the beams surviving a search step).
+    ///
+    /// The rows are gathered from the inputs of the ops computing the output, as far up the
+    /// model as the ops allow it (see `TypedOp::select_rows`), so only they are computed. Past
+    /// the first op that can not, the values are computed whole.
+    pub fn with_output_rows(mut self, output: usize, axis: usize) -> TractResult<TypedModel> {
+        let outlet = *self.output_outlets()?.get(output).context("No such output")?;
+        ensure!(axis < self.outlet_fact(outlet)?.rank(), "No axis #{axis} in output #{output}");
+        let name = self.node(outlet.node).name.clone();
+        let r = self.symbol_table.new_with_prefix("R");
+        let rows = self.add_source(format!("{name}.rows"), i64::fact([r.to_dim()]))?;
+        let gather = ops::array::Gather::new(axis);
+        let selected = self.wire_node(format!("{name}.rows"), gather, &[outlet, rows])?[0];
+        let mut outputs = self.output_outlets()?.to_vec();
+        outputs[output] = selected;
+        self.set_output_outlets(&outputs)?;
+        // node ids are stable until the final compaction
+        let mut pending = vec![selected.node];
+        while let Some(gather) = pending.pop() {
+            let gather = self.node(gather);
+            let axis = gather.op_as::<ops::array::Gather>().unwrap().axis;
+            let prec = self.node(gather.inputs[0].node);
+            if prec.outputs.len() != 1
+                || prec.outputs[0].successors.len() != 1
+                || self.output_outlets()?.contains(&gather.inputs[0])
+            {
+                continue;
+            }
+            let mut patch = TypedModelPatch::new(format!("Select rows of {prec}"));
+            let patch_rows = patch.tap_model(&self, rows)?;
+            let Some(wires) = prec.op.select_rows(&mut patch, &self, prec, axis, patch_rows)?
+            else {
+                continue;
+            };
+            patch.shunt_outside(&self, gather.id.into(), wires[0])?;
+            let first_new = self.nodes().len();
+            patch.apply(&mut self)?;
+            pending.extend((first_new..self.nodes().len()).filter(|&n| {
+                let node = self.node(n);
+                node.op_is::<ops::array::Gather>() && node.inputs.get(1) == Some(&rows)
+            }));
+        }
+        self.compact()?;
+        Ok(self)
+    }
+
     pub fn into_decluttered(mut self) -> TractResult<TypedModel> {
         self.declutter()?;
         Ok(self)
@@ -192,6 +294,103 @@ impl TypedModel {
     pub fn axes_mapping(&self) -> TractResult<AxesMapping> {
         crate::axes::for_model(self)
     }
+
+    /// Run the quantized einsum `name` in f32 on its dequantized operands, requantizing the
+    /// result to its output type. The rewrite happens at declutter.
+    pub fn force_float_fallback(&mut self, name: &str) -> TractResult<()> {
+        use crate::ops::einsum::{EinSum, FLOAT_FALLBACK};
+        let id = self.node_id_by_name(name)?;
+        ensure!(
+            matches!(self.node(id).op_as::<EinSum>(), Some(op) if op.q_params.is_some()),
+            "{} is not a quantized einsum",
+            self.node(id)
+        );
+        self.set_node_property(id, FLOAT_FALLBACK, rctensor0(true))
+    }
+
+    /// Accumulate the f32 einsum `name` in f64, for ill-conditioned products where f32 sums lose
+    /// too much. Its operands and output stay f32. The rewrite happens at codegen.
+    pub fn force_extended_accumulation(&mut self, name: &str) -> TractResult<()> {
+        use crate::ops::einsum::{EinSum, EXTENDED_ACCUMULATION};
+        let id = self.node_id_by_name(name)?;
+        ensure!(
+            matches!(self.node(id).op_as::<EinSum>(),
+                Some(op) if op.q_params.is_none() && op.operating_dt == f32::datum_type()),
+            "{} is not a f32 einsum",
+            self.node(id)
+        );
+        self.set_node_property(id, EXTENDED_ACCUMULATION, rctensor0(true))
+    }
+
+    /// Digest and byte length of each constant of the model, by Const node name.
+    ///
+    /// The packed forms of the matmul constants report the digest of the tensor before packing,
+    /// so the digests do not depend on the kernels picked by the optimizer (see
+    /// `ops::konst::ContentDigest`).
+    pub fn constant_digests(&self) -> TractResult<Vec<(String, u64, usize)>> {
+        crate::ops::konst::constant_digests(self)
+    }
+
+    /// What codegen would do to each einsum of the model, by node name, without rewriting it
+    /// (see `ops::einsum::codegen::plan`). Expects a decluttered model.
+    pub fn lowering_plans(
+        &self,
+        options: &OptimizerOptions,
+    ) -> TractResult<Vec<(String, crate::ops::einsum::codegen::LoweringPlan)>> {
+        crate::ops::einsum::codegen::lowering_plans(self, options)
+    }
+
+    /// Report the matrix multiplications with lossy or overflowing accumulators, or saturating
+    /// quantized outputs.
+    pub fn check_numerics_policy(
+        &self,
+    ) -> TractResult<Vec<crate::ops::matmul::numerics::NumericsIssue>> {
+        crate::ops::matmul::numerics::check_numerics_policy(self)
+    }
+
+    /// Quantize the constant f32 weights of the einsums selected by `policy` to i8, per output
+    /// channel, and quantize their activations on the fly. Expects a decluttered model.
+    ///
+    /// Returns the converted einsums with their weight quantization error.
+    pub fn dynamic_quantize_matmuls(
+        &mut self,
+        policy: &crate::ops::einsum::dynamic_quant::DynamicQuantizationPolicy,
+    ) -> TractResult<Vec<crate::ops::einsum::dynamic_quant::DynamicQuantizationReport>> {
+        crate::ops::einsum::dynamic_quant::dynamic_quantize_matmuls(self, policy)
+    }
+
+    /// Run the model on `calibration` inputs with each f32 einsum in turn switched to f16, and
+    /// rank the einsums by the error they induce on the outputs, largest first. See
+    /// `ops::einsum::reduced_precision`.
+    pub fn f16_error_budget(
+        &self,
+        calibration: &[TVec<TValue>],
+    ) -> TractResult<Vec<crate::ops::einsum::reduced_precision::PrecisionImpact>> {
+        crate::ops::einsum::reduced_precision::f16_error_budget(self, calibration)
+    }
+
+    /// Switch to f16 the einsums of `impacts` (from `f16_error_budget`) inducing a relative
+    /// error up to `max_relative_error`, returning their names.
+    pub fn apply_f16(
+        &mut self,
+        impacts: &[crate::ops::einsum::reduced_precision::PrecisionImpact],
+        max_relative_error: f32,
+    ) -> TractResult<Vec<String>> {
+        crate::ops::einsum::reduced_precision::apply_f16(self, impacts, max_relative_error)
+    }
+
+    /// Make the einsums matched by `selector` operate in `dt`, casting their operands when
+    /// needed and updating the facts downstream. Returns the names of the einsums changed.
+    ///
+    /// The operating type picked at import is kept as a node property, see
+    /// `ops::einsum::operating_dt`.
+    pub fn set_einsum_operating_dt(
+        &mut self,
+        selector: &crate::ops::einsum::operating_dt::EinSumSelector,
+        dt: DatumType,
+    ) -> TractResult<Vec<String>> {
+        crate::ops::einsum::operating_dt::set_einsum_operating_dt(self, selector, dt)
+    }
 }
 
 #[cfg(test)]
@@ -203,4 +402,72 @@ mod test {
         fn is_sync<T: Sync>() {}
         is_sync::<TypedModel>();
     }
+
+    #[test]
+    fn patch_provenance() -> TractResult<()> {
+        use crate::ops::einsum::EinSum;
+        use crate::ops::matmul::lir_unary::LirMatMulUnary;
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", f32::fact([8]))?;
+        let b = model.add_source("b", f32::fact([4]))?;
+        let einsum = EinSum::new("m,n->mn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", einsum, &[a, b])?;
+        model.set_output_outlets(&c)?;
+        assert!(model.clone().into_optimized()?.node_patches.is_none());
+        let options = OptimizerOptions { track_patches: true, ..OptimizerOptions::default() };
+        let optimized = model.into_optimized_with_options(&options)?;
+        let lir = optimized.nodes.iter().find(|n| n.op_is::<LirMatMulUnary>()).unwrap();
+        // declutter relabels m,n->mn canonically before codegen
+        assert_eq!(
+            optimized.node_patches(lir.id),
+            ["declutter #2 \"einsum\" EinSum", "inject k axis", "Einsum to LirMatMulUnary"]
+        );
+        Ok(())
+    }
+
+    fn fma(model: &TypedModel, rows: i64) -> TractResult<TDim> {
+        let mut total = TDim::from(0);
+        for node in &model.nodes {
+            let inputs = model.node_input_facts(node.id)?;
+            for (cost, n) in node.op.cost(&inputs)? {
+                if cost == Cost::FMA(f32::datum_type()) {
+                    total += n;
+                }
+            }
+        }
+        let mut values = SymbolValues::default();
+        for r in model.input_fact(model.inputs.len() - 1)?.shape[0].symbols() {
+            values.set(&r, rows);
+        }
+        Ok(total.eval(&values))
+    }
+
+    #[test]
+    fn output_rows_are_computed_alone() -> TractResult<()> {
+        use crate::ops::einsum::EinSum;
+        let mut w = Tensor::zero::<f32>(&[32, 2000])?;
+        w.as_slice_mut::<f32>()?.iter_mut().enumerate().for_each(|(ix, x)| *x = (ix % 7) as f32);
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", f32::fact([64, 32]))?;
+        let w = model.add_const("w", w)?;
+        let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", einsum, &[a, w])?;
+        model.set_output_outlets(&c)?;
+        let a = Tensor::from_shape(&[64, 32], &(0..64 * 32).map(|x| x as f32).collect::<Vec<_>>())?;
+        let full = model.clone().into_runnable()?.run(tvec!(a.clone().into()))?;
+        let expected = full[0].to_array_view::<f32>()?.select(ndarray::Axis(0), &[0, 3, 7]);
+        let rows = tensor1(&[0i64, 3, 7]);
+        let optimized = model.clone().into_optimized()?;
+        let full_fma = fma(&optimized, 3)?;
+        // selecting before and after codegen
+        for selected in
+            [model.with_output_rows(0, 0)?.into_optimized()?, optimized.with_output_rows(0, 0)?]
+        {
+            assert_eq!(fma(&selected, 3)? * 64, full_fma.clone() * 3);
+            let found =
+                selected.into_runnable()?.run(tvec!(a.clone().into(), rows.clone().into()))?;
+            assert_eq!(found[0].to_array_view::<f32>()?, expected.view().into_dyn());
+        }
+        Ok(())
+    }
 }
@@ -70,5 +70,18 @@ impl TypedOp for Topk {
         Ok(tvec!(fact_values, fact_indices))
     }
 
+    fn codegen_with_session(
+        &self,
+        session: &mut crate::optim::OptimizerSession,
+        model: &TypedModel,
+        node: &TypedNode,
+    ) -> TractResult<Option<TypedModelPatch>> {
+        if let Some(tile) = session.options().fused_top_k {
+            crate::ops::matmul::top_k::LirMatMulTopk::fuse(self, model, node, tile)
+        } else {
+            Ok(None)
+        }
+    }
+
     as_op!();
 }
@@ -11,7 +11,7 @@ use crate::ops::array::Pad;
 use crate::ops::array::PadMode;
 use crate::ops::binary::TypedBinOp;
 use crate::ops::cnn::PaddingSpec;
-use crate::ops::einsum::EinSum;
+use crate::ops::einsum::{EinSum, QOverflow};
 use crate::ops::math::Add;
 use crate::ops::math::Div;
 use crate::ops::math::Mul;
@@ -232,7 +232,7 @@ impl ConvUnary {
 
         let wire = self.wire_remove_group(model, name, &[wire], &mmm_output_shape, c_axis)?;
         let wire = self.wire_rm_n_if_needed(model, name, &wire)?;
-        let wire = qmm::requant(model, name, wire[0], c_dt, abc_scale, c0)?;
+        let wire = qmm::requant(model, name, wire[0], c_dt, abc_scale, c0, QOverflow::Saturate)?;
         Self::wire_geo_reshape(model, name, &[wire], &output_shape)
     }
 
@@ -608,10 +608,10 @@ impl ConvUnary {
                 }
                 let bias = patch.add_const(format!("{name}.bias"), bias)?;
                 inputs.insert(2, bias);
-                let op = EinSum { axes, operating_dt: i32::datum_type(), q_params: self.q_params };
+                let op = EinSum { q_params: self.q_params, ..EinSum::new(axes, i32::datum_type()) };
                 patch.wire_node(format!("{}.einsum", node.name), op, &inputs)?[0]
             } else {
-                let op = EinSum { axes, operating_dt: input_facts[0].datum_type, q_params: None };
+                let op = EinSum::new(axes, input_facts[0].datum_type);
                 let mut wire = patch.wire_node(format!("{}.einsum", node.name), op, &inputs)?[0];
                 if let Some(b) = self.bias.as_ref().filter(|_| self.q_params.is_none()) {
                     anyhow::ensure!(b.rank() == 0 || b.rank() == 1);
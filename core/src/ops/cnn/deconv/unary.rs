@@ -122,7 +122,7 @@ impl DeconvUnary {
         }
         let einsum = target.wire_node(
             format!("{name}.einsum"),
-            EinSum { axes: expr.parse()?, operating_dt: self.kernel.datum_type(), q_params: None },
+            EinSum::new(expr.parse()?, self.kernel.datum_type()),
             &[kernel, input[0]],
         )?;
 
@@ -1,8 +1,8 @@
 use tract_ndarray::Ix2;
-use tract_num_traits::One;
 
 use super::codegen::*;
 use super::EinSum;
+use crate::broadcast::broadcast_shapes;
 use crate::internal::*;
 
 pub fn decompose(op: &EinSum, model: &TypedModel, node: &TypedNode) -> TractResult<TypedModel> {
@@ -35,6 +35,7 @@ fn decompose_one_in_place(model: &mut TypedModel) -> TractResult<()> {
                 p.apply(model)?;
                 model.compact()?;
             }
+            AxesOrPatch::Declined(reason) => bail!("Can not decompose {node}: {reason}"),
         };
     };
     let (m, k, n) = (m.repr, k.repr, n.repr);
@@ -79,17 +80,15 @@ pub struct BasicMatMul {
 }
 
 impl BasicMatMul {
-    fn output_shape<D: DimLike + One>(&self, a: &[D], b: &[D]) -> TVec<D> {
-        let mut output: TVec<D> = (0..a.len() - 2)
-            .map(|ix| if a[ix].is_one() { b[ix].clone() } else { a[ix].clone() })
-            .collect();
+    fn output_shape<D: DimLike>(&self, a: &[D], b: &[D]) -> TractResult<TVec<D>> {
+        let mut output = broadcast_shapes(&[&a[..a.len() - 2], &b[..b.len() - 2]])?;
         output.push(a[a.len() - 2 + self.transpose_a as usize].clone());
         output.push(b[b.len() - 2 + !self.transpose_b as usize].clone());
         if self.transpose_c {
             let len = output.len();
             output.swap(len - 2, len - 1);
         }
-        output
+        Ok(output)
     }
 
     fn eval_t<T: Datum + tract_ndarray::LinalgScalar>(
@@ -141,7 +140,7 @@ impl EvalOp for BasicMatMul {
 
     fn eval(&self, mut inputs: TVec<TValue>) -> TractResult<TVec<TValue>> {
         let (a, b) = args_2!(inputs);
-        let mut c = Tensor::zero_dt(a.datum_type(), &self.output_shape(a.shape(), b.shape()))?;
+        let mut c = Tensor::zero_dt(a.datum_type(), &self.output_shape(a.shape(), b.shape())?)?;
         dispatch_numbers!(Self::eval_t(a.datum_type())(self, &mut c, &a, &b)).unwrap();
         Ok(tvec!(c.into_tvalue()))
     }
@@ -157,7 +156,34 @@ impl TypedOp for BasicMatMul {
             a.shape[a.rank() - 2 + !self.transpose_a as usize]
                 == b.shape[b.rank() - 2 + self.transpose_b as usize]
         );
-        Ok(tvec!(a.datum_type.fact(self.output_shape(&a.shape, &b.shape))))
+        Ok(tvec!(a.datum_type.fact(self.output_shape(&a.shape, &b.shape)?)))
+    }
+
+    fn change_axes(
+        &self,
+        model: &TypedModel,
+        node: &TypedNode,
+        _io: InOut,
+        change: &AxisOp,
+    ) -> TractResult<Option<AxisChangeConsequence>> {
+        // only the batch prefix can change, the same way on both inputs and the output
+        let batch = node.outputs[0].fact.rank() - 2;
+        let valid = match change {
+            AxisOp::Add(add) => *add <= batch,
+            AxisOp::Rm(rm) => {
+                let (inputs, outputs) = model.node_facts(node.id)?;
+                *rm < batch
+                    && inputs[0].shape[*rm].is_one()
+                    && inputs[1].shape[*rm].is_one()
+                    && outputs[0].shape[*rm].is_one()
+            }
+            AxisOp::Move(from, to) => *from < batch && *to < batch,
+            _ => false,
+        };
+        if !valid {
+            return Ok(None);
+        }
+        Ok(Some(AxisChangeConsequence::new(model, node, None, change)))
     }
 
     as_op!();
@@ -373,14 +399,42 @@ mod test {
         .check()
     }
 
+    #[test]
+    fn batch_axis_changes_cross_matmul() -> TractResult<()> {
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", f32::fact([3, 4]))?;
+        let b = model.add_source("b", f32::fact([4, 5]))?;
+        let a = model.wire_node("a.add", AxisOp::Add(0), &[a])?[0];
+        let b = model.wire_node("b.add", AxisOp::Add(0), &[b])?[0];
+        let c = model.wire_node("mm", BasicMatMul::default(), &[a, b])?[0];
+        let c = model.wire_node("c.rm", AxisOp::Rm(0), &[c])?;
+        model.set_output_outlets(&c)?;
+        let model = model.into_decluttered()?;
+        assert!(model.nodes.iter().all(|n| !n.op_is::<AxisOp>()));
+        assert_eq!(model.output_fact(0)?.shape, f32::fact([3, 5]).shape);
+        Ok(())
+    }
+
+    #[test]
+    fn matmul_axes_changes_are_refused() -> TractResult<()> {
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", f32::fact([2, 3, 4]))?;
+        let b = model.add_source("b", f32::fact([2, 4, 5]))?;
+        let c = model.wire_node("mm", BasicMatMul::default(), &[a, b])?[0];
+        model.set_output_outlets(&[c])?;
+        let node = model.node(c.node);
+        let op = node.op_as::<BasicMatMul>().unwrap();
+        for change in [AxisOp::Move(2, 0), AxisOp::Move(0, 1), AxisOp::Rm(0), AxisOp::Add(3)] {
+            assert!(op.change_axes(&model, node, InOut::In(0), &change)?.is_none());
+        }
+        assert!(op.change_axes(&model, node, InOut::In(0), &AxisOp::Add(1))?.is_some());
+        Ok(())
+    }
+
     #[test]
     fn q() -> TractResult<()> {
         let qp = QParams::ZpScale { zero_point: 0, scale: 0.1 };
-        let op = EinSum {
-            axes: "mk,kn,m,,,,,,->mn".parse()?,
-            operating_dt: i32::datum_type(),
-            q_params: Some(DatumType::QI8(qp)),
-        };
+        let op = EinSum::newq("mk,kn,m,,,,,,->mn".parse()?, i32::datum_type(), DatumType::QI8(qp));
         let mut model = TypedModelPatch::default();
         let inputs = [
             model.add_source("a", DatumType::QI8(qp).fact(&[3, 2]))?,
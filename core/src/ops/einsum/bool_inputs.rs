@@ -0,0 +1,70 @@
+//! Boolean operands, promoted to i32.
+
+use super::*;
+
+fn bool_matmul_model(a_const: Option<Tensor>) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let a = if let Some(a) = a_const {
+        model.add_const("a", a)?
+    } else {
+        model.add_source("a", bool::fact([3, 4]))?
+    };
+    let b = model.add_source("b", bool::fact([4, 5]))?;
+    let c = model.wire_node(
+        "einsum",
+        EinSum::new("ij,jk->ik".parse()?, bool::datum_type()),
+        &[a, b],
+    )?;
+    model.set_output_outlets(&c)?;
+    Ok(model)
+}
+
+fn bool_tensor(shape: &[usize], seed: usize) -> Tensor {
+    let len = shape.iter().product::<usize>();
+    let data: Vec<bool> = (0..len).map(|i| (i * 7 + seed) % 3 == 0).collect();
+    tensor1(&data).into_shape(shape).unwrap()
+}
+
+fn promoted_reference(a: &Tensor, b: &Tensor) -> TractResult<Tensor> {
+    let a = a.cast_to::<i32>()?.into_owned();
+    let b = b.cast_to::<i32>()?.into_owned();
+    let mut output = EinSum::new("ij,jk->ik".parse()?, i32::datum_type())
+        .eval(tvec!(a.into_tvalue(), b.into_tvalue()))?;
+    Ok(output.remove(0).into_tensor())
+}
+
+#[test]
+fn bool_inputs_promote_to_i32() -> TractResult<()> {
+    let op = EinSum::new("ij,jk->ik".parse()?, bool::datum_type());
+    assert_eq!(op.operating_dt, i32::datum_type());
+    let model = bool_matmul_model(None)?;
+    assert_eq!(model.outlet_fact(model.output_outlets()?[0])?.datum_type, i32::datum_type());
+    Ok(())
+}
+
+#[test]
+fn bool_einsum_unoptimized_and_optimized() -> TractResult<()> {
+    let a = bool_tensor(&[3, 4], 0);
+    let b = bool_tensor(&[4, 5], 1);
+    let expected = promoted_reference(&a, &b)?;
+    let model = bool_matmul_model(None)?;
+    let inputs = tvec!(a.clone().into_tvalue(), b.clone().into_tvalue());
+    let found = model.clone().into_runnable()?.run(inputs.clone())?.remove(0);
+    found.close_enough(&expected, Approximation::Exact)?;
+    let optimized = model.into_optimized()?;
+    assert!(optimized.nodes.iter().all(|n| !n.op_is::<EinSum>()));
+    let found = optimized.into_runnable()?.run(inputs)?.remove(0);
+    found.close_enough(&expected, Approximation::Exact)?;
+    Ok(())
+}
+
+#[test]
+fn bool_einsum_with_const_operand() -> TractResult<()> {
+    let a = bool_tensor(&[3, 4], 2);
+    let b = bool_tensor(&[4, 5], 3);
+    let expected = promoted_reference(&a, &b)?;
+    let optimized = bool_matmul_model(Some(a))?.into_optimized()?;
+    let found = optimized.into_runnable()?.run(tvec!(b.into_tvalue()))?.remove(0);
+    found.close_enough(&expected, Approximation::Exact)?;
+    Ok(())
+}
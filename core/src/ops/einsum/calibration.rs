@@ -0,0 +1,98 @@
+//! Calibration taps: the i32 accumulators of the quantized einsums, exposed as model outputs.
+//!
+//! With `OptimizerOptions::calibration_taps`, the lowering of each quantized einsum flags its i32
+//! accumulator, after zero point compensation and bias, with the `CALIBRATION_TAP` property. The
+//! optimizer turns each flagged wire into an extra model output, labelled after the einsum, as
+//! soon as the patch creating it is applied. As a model output, the accumulator is not fused in
+//! the requantization that follows it: running the optimized model yields it as a tensor.
+use crate::internal::*;
+use tract_itertools::Itertools;
+
+/// Node property of the accumulator of a quantized einsum to expose as a model output: the
+/// label of the output.
+pub const CALIBRATION_TAP: &str = "einsum.calibration_tap";
+
+/// Turn the outputs of the nodes flagged with `CALIBRATION_TAP` into model outputs.
+pub(crate) fn register_taps(model: &mut TypedModel) -> TractResult<()> {
+    let taps = model
+        .node_properties
+        .iter_mut()
+        .filter_map(|(id, props)| Some((*id, props.remove(CALIBRATION_TAP)?)))
+        .sorted_by_key(|(id, _)| *id)
+        .collect_vec();
+    for (id, label) in taps {
+        let outlet = OutletId::new(id, 0);
+        if !model.outputs.contains(&outlet) {
+            model.outputs.push(outlet);
+            model.set_outlet_label(outlet, label.to_scalar::<String>()?.clone())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::einsum::EinSum;
+    use crate::optim::OptimizerOptions;
+    use tract_ndarray::prelude::*;
+
+    /// Wire a quantized dense layer, a0 = 1 and b0 = 0, with an i8 output.
+    fn layer(
+        model: &mut TypedModel,
+        name: &str,
+        x: OutletId,
+        w: &Array2<i8>,
+    ) -> TractResult<OutletId> {
+        let n = w.shape()[1];
+        let w = model.add_const(format!("{name}.w"), w.clone().into_tensor())?;
+        let bias = (0..n as i32).map(|i| i * 3 - 4).collect_vec();
+        let bias = model.add_const(format!("{name}.bias"), tensor1(&bias))?;
+        let q = [tensor0(1i8), tensor0(0.05f32), tensor0(0i8), tensor0(0.02f32), tensor0(-2i8)];
+        let mut inputs = tvec!(x, w, bias);
+        for (ix, t) in q.into_iter().enumerate() {
+            inputs.push(model.add_const(format!("{name}.q{ix}"), t)?);
+        }
+        inputs.push(model.add_const(format!("{name}.c_scale"), tensor0(0.2f32))?);
+        let op = EinSum::newq("mk,kn,n,,,,,,->mn".parse()?, i32::datum_type(), i8::datum_type());
+        Ok(model.wire_node(name, op, &inputs)?[0])
+    }
+
+    fn accumulator(x: ArrayView2<i8>, w: &Array2<i8>) -> Array2<i32> {
+        let x = x.mapv(|x| x as i32 - 1);
+        let bias = Array1::from_shape_fn(w.shape()[1], |n| n as i32 * 3 - 4);
+        x.dot(&w.mapv(|w| w as i32)) + bias
+    }
+
+    #[test]
+    fn taps_expose_accumulators() -> TractResult<()> {
+        let w1 = Array2::from_shape_fn((8, 6), |(k, n)| ((k * 5 + n * 3) % 11) as i8 - 5);
+        let w2 = Array2::from_shape_fn((6, 5), |(k, n)| ((k * 7 + n) % 13) as i8 - 6);
+        let mut model = TypedModel::default();
+        let x = model.add_source("x", i8::fact([4, 8]))?;
+        let h = layer(&mut model, "layer1", x, &w1)?;
+        let y = layer(&mut model, "layer2", h, &w2)?;
+        model.set_output_outlets(&[h, y])?;
+
+        let options = OptimizerOptions { calibration_taps: true, ..OptimizerOptions::default() };
+        let optimized = model.clone().into_optimized_with_options(&options)?;
+        assert_eq!(optimized.output_outlets()?.len(), 4);
+        for (ix, label) in [(2, "layer1.accumulator"), (3, "layer2.accumulator")] {
+            let outlet = optimized.output_outlets()?[ix];
+            assert_eq!(optimized.outlet_label(outlet), Some(label));
+            assert_eq!(optimized.outlet_fact(outlet)?.datum_type, i32::datum_type());
+        }
+        assert_eq!(model.clone().into_optimized()?.output_outlets()?.len(), 2);
+
+        let x = Array2::from_shape_fn((4, 8), |(m, k)| ((m * 8 + k) % 17) as i8 - 8);
+        let reference = model.into_runnable()?.run(tvec!(x.clone().into_tvalue()))?;
+        let outputs = optimized.into_runnable()?.run(tvec!(x.clone().into_tvalue()))?;
+        assert_eq!(outputs[..2], reference[..]);
+        let h = outputs[0].to_array_view::<i8>()?.into_dimensionality::<Ix2>()?;
+        let acc1 = outputs[2].to_array_view::<i32>()?.into_dimensionality::<Ix2>()?;
+        let acc2 = outputs[3].to_array_view::<i32>()?.into_dimensionality::<Ix2>()?;
+        assert_eq!(acc1, accumulator(x.view(), &w1));
+        assert_eq!(acc2, accumulator(h, &w2));
+        Ok(())
+    }
+}
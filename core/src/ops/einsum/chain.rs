@@ -0,0 +1,307 @@
+//! Contraction order of chained einsums.
+//!
+//! Exporters often produce an einsum feeding another one through axis moves, insertions or
+//! removals, the intermediate tensor existing only for the second contraction. The two einsums
+//! and the axis ops are composed in a single three operand expression (see
+//! `AxesMapping::compose`), which is split back in the pair of binary einsums with the smallest
+//! loops: the axis ops disappear, and the operands may be contracted in another order, like two
+//! weights multiplied together before meeting the activations. The loops never get larger.
+use super::codegen::is_extended_accumulation;
+use super::EinSum;
+use crate::axes::Axis;
+use crate::internal::*;
+use crate::ops::change_axes::AxisOp;
+
+/// Is `op` a plain binary float einsum, fit for chaining ? Einsums flagged for extended
+/// accumulation are left as they are.
+fn is_chainable(op: &EinSum, model: &TypedModel, node: &TypedNode) -> bool {
+    op.q_params.is_none()
+        && node.inputs.len() == 2
+        && op.operating_dt.is_float()
+        && !op.operands_swapped
+        && !op.k_split_part
+        && !is_extended_accumulation(model, node)
+}
+
+/// The einsum feeding input #`slot` of `node`, through pure axis ops, with these ops. Each
+/// tensor on the way must only feed the next node.
+fn producer<'a>(
+    model: &'a TypedModel,
+    node: &TypedNode,
+    slot: usize,
+) -> TractResult<Option<(&'a TypedNode, &'a EinSum, TVec<&'a TypedNode>)>> {
+    let mut outlet = node.inputs[slot];
+    let mut axis_ops = tvec!();
+    loop {
+        if model.outlet_successors(outlet).len() != 1 || model.output_outlets()?.contains(&outlet) {
+            return Ok(None);
+        }
+        let precursor = model.node(outlet.node);
+        if let Some(op) = precursor.op_as::<AxisOp>() {
+            if !matches!(op, AxisOp::Move(..) | AxisOp::Add(_) | AxisOp::Rm(_)) {
+                return Ok(None);
+            }
+            axis_ops.push(precursor);
+            outlet = precursor.inputs[0];
+        } else {
+            let Some(op) = precursor.op_as::<EinSum>() else { return Ok(None) };
+            return Ok(is_chainable(op, model, precursor).then_some((precursor, op, axis_ops)));
+        }
+    }
+}
+
+/// The shapes of `outlets`, if they are all concrete.
+fn concrete_shapes(
+    model: &TypedModel,
+    outlets: &[OutletId],
+) -> TractResult<Option<TVec<TVec<usize>>>> {
+    let mut shapes = tvec!();
+    for outlet in outlets {
+        let Some(shape) = model.outlet_fact(*outlet)?.shape.as_concrete() else { return Ok(None) };
+        shapes.push(shape.into());
+    }
+    Ok(Some(shapes))
+}
+
+/// Iterations of the loops of `axes` over inputs of `shapes`.
+fn volume(axes: &AxesMapping, shapes: &[&[usize]]) -> usize {
+    axes.iter_all_axes()
+        .map(|axis| {
+            (0..shapes.len())
+                .flat_map(|ix| axis.inputs[ix].iter().map(move |&pos| shapes[ix][pos]))
+                .max()
+                .unwrap_or(1)
+        })
+        .product()
+}
+
+/// A pair of binary einsums computing a three operand one: inputs `pair` first, then the
+/// result with `last`.
+struct Split {
+    pair: [usize; 2],
+    last: usize,
+    first: AxesMapping,
+    second: AxesMapping,
+    cost: usize,
+}
+
+/// Split `fused` in a binary einsum of `pair`, whose output keeps the axes needed later (the
+/// output ones first, in their order), and a binary einsum of this output with the last
+/// operand.
+fn split(fused: &AxesMapping, shapes: &[&[usize]], last: usize) -> TractResult<Split> {
+    let pair = [0, 1, 2].into_iter().filter(|&ix| ix != last).collect::<TVec<_>>();
+    let pair = [pair[0], pair[1]];
+    let (inputs, outputs) = fused.to_strs();
+    let in_pair = |axis: &&Axis| pair.iter().any(|&ix| axis.inputs[ix].len() > 0);
+    let kept = |axis: &&Axis| axis.inputs[last].len() > 0 || axis.outputs[0].len() > 0;
+    let mut intermediate: String = outputs[0]
+        .chars()
+        .filter(|&c| fused.axis(c).map(|a| in_pair(&a) && kept(&a)).unwrap_or(false))
+        .collect();
+    for c in inputs[pair[0]].chars().chain(inputs[pair[1]].chars()) {
+        let axis = fused.axis(c)?;
+        if kept(&axis) && !intermediate.contains(c) {
+            intermediate.push(c);
+        }
+    }
+    let first = AxesMapping::from_strs(&[&inputs[pair[0]], &inputs[pair[1]]], &[&intermediate])?;
+    let second_inputs = if last < pair[0] {
+        [&inputs[last], &intermediate]
+    } else {
+        [&intermediate, &inputs[last]]
+    };
+    let second = AxesMapping::from_strs(&second_inputs, &outputs)?;
+    let intermediate_shape: TVec<usize> = intermediate
+        .chars()
+        .map(|c| {
+            let axis = fused.axis(c).unwrap();
+            pair.iter()
+                .flat_map(|&ix| axis.inputs[ix].iter().map(move |&pos| shapes[ix][pos]))
+                .max()
+                .unwrap_or(1)
+        })
+        .collect();
+    let second_shapes = if last < pair[0] {
+        [shapes[last], &intermediate_shape]
+    } else {
+        [&intermediate_shape, shapes[last]]
+    };
+    let cost =
+        volume(&first, &[shapes[pair[0]], shapes[pair[1]]]) + volume(&second, &second_shapes);
+    Ok(Split { pair, last, first, second, cost })
+}
+
+/// Compose an einsum with the einsum feeding it through axis ops, and split the result back in
+/// the cheapest pair of einsums. The pair must be cheaper than the chain, or as cheap with the
+/// axis ops gone.
+pub(super) fn declutter_chained_einsums(
+    op: &EinSum,
+    model: &TypedModel,
+    node: &TypedNode,
+) -> TractResult<Option<TypedModelPatch>> {
+    if !is_chainable(op, model, node) {
+        return Ok(None);
+    }
+    for slot in 0..2 {
+        let Some((producer, first, axis_ops)) = producer(model, node, slot)? else { continue };
+        if first.operating_dt != op.operating_dt {
+            continue;
+        }
+        let mut fused = first.axes.clone();
+        for axis_op in axis_ops.iter().rev() {
+            let inputs = model.node_input_facts(axis_op.id)?;
+            let outputs = model.node_output_facts(axis_op.id)?;
+            fused = fused.compose(&axis_op.op.axes_mapping(&inputs, &outputs)?, 0)?;
+        }
+        let fused = fused.compose(&op.axes, slot)?;
+        if fused.iter_all_axes().any(|a| a.inputs.iter().chain(&a.outputs).any(|p| p.len() > 1)) {
+            continue;
+        }
+        let mut operands = node.inputs.clone();
+        operands.splice(slot..slot + 1, producer.inputs.iter().copied());
+        let (Some(shapes), Some(node_shapes)) =
+            (concrete_shapes(model, &operands)?, concrete_shapes(model, &node.inputs)?)
+        else {
+            continue;
+        };
+        let shapes: TVec<&[usize]> = shapes.iter().map(|s| &**s).collect();
+        let node_shapes: TVec<&[usize]> = node_shapes.iter().map(|s| &**s).collect();
+        let chain_cost =
+            volume(&first.axes, &shapes[slot..slot + 2]) + volume(&op.axes, &node_shapes);
+        // the split of the chain as it is comes first, to be kept on ties
+        let original = if slot == 0 { 2 } else { 0 };
+        let best = [original, 1, 2 - original]
+            .into_iter()
+            .map(|last| split(&fused, &shapes, last))
+            .collect::<TractResult<TVec<Split>>>()?
+            .into_iter()
+            .min_by_key(|s| s.cost)
+            .unwrap();
+        if best.cost > chain_cost || (best.cost == chain_cost && axis_ops.is_empty()) {
+            continue;
+        }
+        let mut patch = TypedModelPatch::new(format!(
+            "Reorder the contractions of {} and {}",
+            producer.name, node.name
+        ));
+        let taps = operands
+            .iter()
+            .map(|o| patch.tap_model(model, *o))
+            .collect::<TractResult<TVec<OutletId>>>()?;
+        let intermediate = patch.wire_node(
+            format!("{}.pair", node.name),
+            EinSum::new(best.first, op.operating_dt),
+            &[taps[best.pair[0]], taps[best.pair[1]]],
+        )?[0];
+        let inputs = if best.last < best.pair[0] {
+            [taps[best.last], intermediate]
+        } else {
+            [intermediate, taps[best.last]]
+        };
+        let wire =
+            patch.wire_node(&node.name, EinSum::new(best.second, op.operating_dt), &inputs)?;
+        patch.shunt_outside(model, node.id.into(), wire[0])?;
+        return Ok(Some(patch));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn operand(shape: &[usize], seed: usize) -> TractResult<Tensor> {
+        let len = shape.iter().product::<usize>();
+        let values = (0..len).map(|i| ((i * 7 + seed) % 11) as f32 - 5.).collect::<Vec<_>>();
+        Tensor::from_shape(shape, &values)
+    }
+
+    /// `x` [b, m, k] by `w1` [k, n], moved to [b, n, m], by `w2` [m, p].
+    fn chain_model(extra_consumer: bool) -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let x = model.add_source("x", f32::fact([2, 3, 4]))?;
+        let w1 = model.add_source("w1", f32::fact([4, 5]))?;
+        let w2 = model.add_source("w2", f32::fact([3, 6]))?;
+        let first = EinSum::new("bmk,kn->bmn".parse()?, f32::datum_type());
+        let first = model.wire_node("first", first, &[x, w1])?[0];
+        let moved = model.wire_node("move", AxisOp::Move(2, 1), &[first])?[0];
+        let second = EinSum::new("bnm,mp->bnp".parse()?, f32::datum_type());
+        let second = model.wire_node("second", second, &[moved, w2])?[0];
+        let mut outputs = tvec!(second);
+        if extra_consumer {
+            outputs.push(model.wire_node("other", AxisOp::Add(0), &[moved])?[0]);
+        }
+        model.set_output_outlets(&outputs)?;
+        Ok(model)
+    }
+
+    fn inputs() -> TractResult<TVec<TValue>> {
+        Ok(tvec!(
+            operand(&[2, 3, 4], 0)?.into_tvalue(),
+            operand(&[4, 5], 1)?.into_tvalue(),
+            operand(&[3, 6], 2)?.into_tvalue()
+        ))
+    }
+
+    fn count<O: Op>(model: &TypedModel) -> usize {
+        model.nodes().iter().filter(|n| n.op_is::<O>()).count()
+    }
+
+    #[test]
+    fn move_between_einsums_is_composed() -> TractResult<()> {
+        let model = chain_model(false)?;
+        let node = model.node_by_name("second")?;
+        let op = node.op_as::<EinSum>().unwrap();
+        let patch = declutter_chained_einsums(op, &model, node)?.unwrap();
+        let mut patched = model.clone();
+        patch.apply(&mut patched)?;
+        let patched = patched.into_decluttered()?;
+        // one intermediate, between the two einsums
+        assert_eq!(count::<EinSum>(&patched), 2);
+        assert_eq!(count::<AxisOp>(&patched), 0);
+        let expected = model.into_runnable()?.run(inputs()?)?;
+        let found = patched.into_runnable()?.run(inputs()?)?;
+        assert_eq!(found, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn cheaper_order_is_picked() -> TractResult<()> {
+        // activations widened by w1 then narrowed by w2: multiplying the two weights together
+        // first takes 2×16×2 + 64×2×2 iterations instead of 64×2×16 + 64×16×2
+        let mut model = TypedModel::default();
+        let x = model.add_source("x", f32::fact([64, 2]))?;
+        let w1 = model.add_source("w1", f32::fact([2, 16]))?;
+        let w2 = model.add_source("w2", f32::fact([16, 2]))?;
+        let first = EinSum::new("bk,kn->bn".parse()?, f32::datum_type());
+        let first = model.wire_node("first", first, &[x, w1])?[0];
+        let second = EinSum::new("bn,np->bp".parse()?, f32::datum_type());
+        let second = model.wire_node("second", second, &[first, w2])?;
+        model.set_output_outlets(&second)?;
+        let decluttered = model.clone().into_decluttered()?;
+        let pair = decluttered.node_by_name("second.pair")?;
+        assert_eq!(pair.inputs, [decluttered.input_outlets()?[1], decluttered.input_outlets()?[2]]);
+        let inputs = tvec!(
+            operand(&[64, 2], 0)?.into_tvalue(),
+            operand(&[2, 16], 1)?.into_tvalue(),
+            operand(&[16, 2], 2)?.into_tvalue()
+        );
+        let expected = model.into_runnable()?.run(inputs.clone())?;
+        let found = decluttered.into_runnable()?.run(inputs)?;
+        found[0].close_enough(&expected[0], Approximation::Close)?;
+        Ok(())
+    }
+
+    #[test]
+    fn intermediate_with_two_consumers_stays() -> TractResult<()> {
+        let model = chain_model(true)?;
+        let node = model.node_by_name("second")?;
+        let op = node.op_as::<EinSum>().unwrap();
+        assert!(declutter_chained_einsums(op, &model, node)?.is_none());
+        let decluttered = model.clone().into_decluttered()?;
+        assert_eq!(count::<EinSum>(&decluttered), 2);
+        let expected = model.into_runnable()?.run(inputs()?)?;
+        assert_eq!(decluttered.into_runnable()?.run(inputs()?)?, expected);
+        Ok(())
+    }
+}
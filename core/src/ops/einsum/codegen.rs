@@ -1,50 +1,774 @@
+use super::calibration::CALIBRATION_TAP;
 use super::*;
-use crate::ops::cast::cast;
-use crate::ops::math::add;
+use crate::ops::binary::wire_with_rank_broadcast;
+use crate::ops::binary::TypedBinOp;
+use crate::ops::cast::{cast, Cast};
+use crate::ops::konst::{record_digest, Const};
+use crate::ops::math::{add, div, max, mul, round_half_to_even, sub, Mul};
+use crate::ops::matmul::compressed::compressed_matrix;
+use crate::ops::matmul::external::{registered_gemm_backend, ExternalGemm};
 use crate::ops::matmul::lir_unary::{
     AddMatMulGeometry, LirMatMulUnary, MapOutputAxisToInput, ProtoFusedSpec,
 };
 use crate::ops::matmul::mir_quant::{
-    combine_scales, compensate_zero_points, requant, wire_offset_u8_as_i8,
+    clamp_and_cast_to, combine_scales, compensate_zero_points, requant, wire_in_place,
+    wire_offset_u8_as_i8,
 };
 use crate::ops::matmul::pack::MatMatMulPack;
-use crate::ops::nn::{Reduce, Reducer};
+use crate::ops::matmul::strided::StridedInputSpec;
+use crate::ops::matmul::tiling::MacroTiles;
+use crate::ops::matmul::tiny_batch::TinyBatchMatMul;
+use crate::ops::matmul::weight_variants::WeightVariants;
+use crate::ops::matmul::BoundedShape;
+use crate::ops::nn::{IntegerSum, Reduce, Reducer};
+use crate::optim::report::DeclineReason;
+use crate::optim::OptimizerOptions;
 
 pub enum AxesOrPatch<'a> {
     Axes(&'a Axis, &'a Axis, &'a Axis),
     Patch(TypedModelPatch),
+    Declined(DeclineReason),
+}
+
+/// Outcome of the einsum codegen: a patch, or why the einsum is left as is.
+pub(crate) type CodegenOutcome = Result<TypedModelPatch, DeclineReason>;
+
+/// Label of the patch swapping the operands of an einsum before lowering it (see
+/// `Graph::node_patches`).
+pub(crate) const SWAP_OPERANDS_PATCH: &str = "swap einsum operands";
+
+/// Suffix of an einsum nested in a patch rewriting another einsum. The `#` is reserved to
+/// codegen: a `.einsum` suffix picked by the user is part of the node name.
+pub(crate) const INNER_EINSUM_SUFFIX: &str = "#einsum";
+
+/// Base of the names of nodes wired by codegen patches for the einsum `name`.
+///
+/// Names derive from the einsum node name and a fixed role only, so optimizing a model twice
+/// yields the same names. A nested einsum produced by a previous rewrite shares the base of the
+/// node it came from, so rewriting a node several times does not stack suffixes.
+pub(crate) fn codegen_base_name(name: &str) -> &str {
+    name.strip_suffix(INNER_EINSUM_SUFFIX).unwrap_or(name)
+}
+
+/// Name of the node playing `role` in a codegen patch for the einsum `name`.
+pub(crate) fn codegen_node_name(name: &str, role: impl std::fmt::Display) -> String {
+    format!("{}.{role}", codegen_base_name(name))
+}
+
+/// Name of the einsum nested in a codegen patch for the einsum `name`.
+pub(crate) fn inner_einsum_name(name: &str) -> String {
+    format!("{}{INNER_EINSUM_SUFFIX}", codegen_base_name(name))
+}
+
+/// Facts of the inputs of `node`, which may be a node rewired outside the model (see `plan`).
+fn input_facts<'a>(model: &'a TypedModel, node: &TypedNode) -> TractResult<TVec<&'a TypedFact>> {
+    node.inputs.iter().map(|i| model.outlet_fact(*i)).collect()
 }
 
 pub(crate) fn codegen(
     op: &EinSum,
     model: &TypedModel,
     node: &TypedNode,
+    options: &OptimizerOptions,
 ) -> TractResult<Option<TypedModelPatch>> {
+    Ok(codegen_or_decline(op, model, node, options)?.ok())
+}
+
+/// Codegen, with the reason it declines to rewrite the einsum (see `OptimizationReport`).
+pub(crate) fn codegen_or_decline(
+    op: &EinSum,
+    model: &TypedModel,
+    node: &TypedNode,
+    options: &OptimizerOptions,
+) -> TractResult<CodegenOutcome> {
+    match lowering(op, model, node, options)? {
+        Lowering::Declined(reason) => Ok(Err(reason)),
+        Lowering::ExtendedAccumulation => extended_accumulation(op, model, node).map(Ok),
+        Lowering::SumSingleInputAxis(axis) => sum_single_input_axis(op, model, node, axis).map(Ok),
+        Lowering::FuseSharedInput(fusion) => {
+            fuse_shared_input_einsums(op, model, node, fusion).map(Ok)
+        }
+        Lowering::BatchSharedConstant(batch) => {
+            batch_shared_constant_einsums(op, model, node, batch).map(Ok)
+        }
+        Lowering::Mkn(rewrite) => Ok(match wire_mkn_rewrite(op, model, node, rewrite)? {
+            AxesOrPatch::Patch(patch) => Ok(patch),
+            AxesOrPatch::Declined(reason) => Err(reason),
+            AxesOrPatch::Axes(..) => bail!("No axis to fix in {node}"),
+        }),
+        Lowering::Dequantize(k_axis, float_fallback) => {
+            dequant_output(op, model, node, k_axis, float_fallback, options)
+                .context("Dequantizing output")
+                .map(Ok)
+        }
+        Lowering::TinyBatch(layout) => tiny_batch_mat_mul(model, node, *layout).map(Ok),
+        Lowering::SplitK(split) => split_k(op, model, node, split).map(Ok),
+        Lowering::ExternalGemm(gemm) => {
+            TypedModelPatch::replace_single_op(model, node, &node.inputs, gemm)
+                .map(|patch| Ok(patch.with_context("External GEMM")))
+        }
+        Lowering::SwapOperands => TypedModelPatch::replace_single_op(
+            model,
+            node,
+            &[node.inputs[1], node.inputs[0]],
+            swapped_operands(op)?,
+        )
+        .map(|patch| Ok(patch.with_context(SWAP_OPERANDS_PATCH))),
+        Lowering::Kernel(kernel) => wire_lir_mat_mul_unary(model, node, *kernel, options)
+            .context("Translating to LirMatMul")
+            .map(Ok),
+    }
+}
+
+/// What the codegen of an einsum would do to it, computed without building any patch (see
+/// `plan`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoweringPlan {
+    /// The operands are swapped first, so A is the parameter, or the operand with the largest of
+    /// m and n. The step is then planned for the swapped einsum.
+    pub swap_operands: bool,
+    pub step: LoweringStep,
+}
+
+/// The rewrite of an einsum by codegen. The einsums wired by the rewrites other than the kernel
+/// are lowered in turn by the next codegen passes, and not planned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoweringStep {
+    /// Lowered to a `LirMatMulUnary`.
+    Kernel(KernelPlan),
+    /// A f32 einsum flagged with `EXTENDED_ACCUMULATION`, computed in f64.
+    ExtendedAccumulation,
+    /// An axis of a single operand summed before the product.
+    SumSingleInputAxis(char),
+    /// Fused with the einsums applying other constant weights to the same input, by name.
+    FuseSharedInput(Vec<String>),
+    /// Batched with the einsums applying the same constant operand to other inputs, by name.
+    BatchSharedConstant(Vec<String>),
+    /// Several k axes merged into one.
+    MergeKAxes(Vec<char>),
+    /// A missing k, m or n axis injected. An injection failing when wired leaves the einsum as
+    /// is (see `DeclineReason::AxisInjection`).
+    InjectAxis(char),
+    /// The contraction split in this many partial products.
+    SplitK(usize),
+    /// Computed by a `TinyBatchMatMul`.
+    TinyBatch,
+    /// Delegated to the registered external GEMM backend.
+    ExternalGemm,
+    /// A quantized einsum, rewritten as an integer product with zero point compensation and
+    /// requantization, or as a product of its dequantized operands.
+    Dequantize {
+        float_fallback: bool,
+    },
+    Declined(DeclineReason),
+}
+
+/// The `LirMatMulUnary` lowering an einsum.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KernelPlan {
+    /// Name of the matrix multiplication kernel.
+    pub kernel: String,
+    /// The fused specs of the op, by name (see `ProtoFusedSpec::name`). The consumers of the
+    /// product fused later by the op itself are not planned.
+    pub fused_specs: Vec<String>,
+    /// Float type of a cast consuming the product, folded in the store.
+    pub folded_cast: Option<DatumType>,
+    /// Operands packed by blocks in the op (see `ops::matmul::tiling`).
+    pub macro_tiled: bool,
+}
+
+impl std::fmt::Display for LoweringPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.swap_operands {
+            write!(f, "swap operands, then ")?;
+        }
+        match &self.step {
+            LoweringStep::Kernel(k) => {
+                write!(f, "{} [{}]", k.kernel, k.fused_specs.join(", "))?;
+                if let Some(dt) = k.folded_cast {
+                    write!(f, " storing {dt:?}")?;
+                }
+                if k.macro_tiled {
+                    write!(f, " macro tiled")?;
+                }
+                Ok(())
+            }
+            LoweringStep::ExtendedAccumulation => write!(f, "f64 accumulation"),
+            LoweringStep::SumSingleInputAxis(axis) => write!(f, "sum axis {axis}"),
+            LoweringStep::FuseSharedInput(nodes) => write!(f, "fuse {}", nodes.join(", ")),
+            LoweringStep::BatchSharedConstant(nodes) => write!(f, "batch {}", nodes.join(", ")),
+            LoweringStep::MergeKAxes(axes) => write!(f, "merge k axes {}", axes.iter().join("")),
+            LoweringStep::InjectAxis(axis) => write!(f, "inject {axis} axis"),
+            LoweringStep::SplitK(parts) => write!(f, "split k in {parts}"),
+            LoweringStep::TinyBatch => write!(f, "tiny batch"),
+            LoweringStep::ExternalGemm => write!(f, "external GEMM"),
+            LoweringStep::Dequantize { float_fallback: false } => write!(f, "dequantize"),
+            LoweringStep::Dequantize { float_fallback: true } => write!(f, "float fallback"),
+            LoweringStep::Declined(reason) => write!(f, "declined: {reason}"),
+        }
+    }
+}
+
+/// What codegen would do to the einsum `node` with the default optimizer options, without
+/// rewriting the model. Runs the same decisions as `codegen`, stopping short of the patches.
+pub fn plan(op: &EinSum, model: &TypedModel, node: &TypedNode) -> TractResult<LoweringPlan> {
+    plan_with_options(op, model, node, &OptimizerOptions::default())
+}
+
+/// `plan`, with non-default optimizer options.
+pub fn plan_with_options(
+    op: &EinSum,
+    model: &TypedModel,
+    node: &TypedNode,
+    options: &OptimizerOptions,
+) -> TractResult<LoweringPlan> {
+    let lowering = lowering(op, model, node, options)?;
+    if !matches!(lowering, Lowering::SwapOperands) {
+        return Ok(LoweringPlan { swap_operands: false, step: lowering_step(node, lowering) });
+    }
+    // the swapped einsum takes the place of the node, with the same successors
+    let swapped = swapped_operands(op)?;
+    let node = TypedNode {
+        inputs: vec![node.inputs[1], node.inputs[0]],
+        op: Box::new(swapped.clone()),
+        ..node.clone()
+    };
+    let step = match self::lowering(&swapped, model, &node, options)? {
+        Lowering::SwapOperands => bail!("{node} swapped back and forth"),
+        lowering => lowering_step(&node, lowering),
+    };
+    Ok(LoweringPlan { swap_operands: true, step })
+}
+
+/// The plan of each einsum of the model, by node name.
+pub fn lowering_plans(
+    model: &TypedModel,
+    options: &OptimizerOptions,
+) -> TractResult<Vec<(String, LoweringPlan)>> {
+    model
+        .nodes()
+        .iter()
+        .filter_map(|node| node.op_as::<EinSum>().map(|op| (op, node)))
+        .map(|(op, node)| {
+            let plan = plan_with_options(op, model, node, options)
+                .with_context(|| format!("Planning the lowering of {node}"))?;
+            Ok((node.name.clone(), plan))
+        })
+        .collect()
+}
+
+fn lowering_step(node: &TypedNode, lowering: Lowering) -> LoweringStep {
+    match lowering {
+        Lowering::Declined(reason) => LoweringStep::Declined(reason),
+        Lowering::ExtendedAccumulation => LoweringStep::ExtendedAccumulation,
+        Lowering::SumSingleInputAxis(axis) => LoweringStep::SumSingleInputAxis(axis.repr),
+        Lowering::FuseSharedInput(fusion) => LoweringStep::FuseSharedInput(
+            fusion.group.iter().map(|(n, _)| n.name.clone()).collect(),
+        ),
+        Lowering::BatchSharedConstant(batch) => {
+            LoweringStep::BatchSharedConstant(batch.group.iter().map(|n| n.name.clone()).collect())
+        }
+        Lowering::Mkn(MknAxes::MergeK(axes)) => {
+            LoweringStep::MergeKAxes(axes.iter().map(|a| a.repr).collect())
+        }
+        Lowering::Mkn(MknAxes::InjectK) => LoweringStep::InjectAxis('k'),
+        Lowering::Mkn(MknAxes::InjectM(_)) => LoweringStep::InjectAxis('m'),
+        Lowering::Mkn(MknAxes::InjectN(..)) => LoweringStep::InjectAxis('n'),
+        Lowering::Mkn(MknAxes::Declined(reason)) => LoweringStep::Declined(reason),
+        // `lowering` goes on with the m, k and n axes, and `plan` with the swapped operands
+        Lowering::Mkn(MknAxes::Axes(..)) | Lowering::SwapOperands => unreachable!(),
+        Lowering::Dequantize(_, float_fallback) => LoweringStep::Dequantize { float_fallback },
+        Lowering::TinyBatch(_) => LoweringStep::TinyBatch,
+        Lowering::SplitK(split) => LoweringStep::SplitK(split.parts),
+        Lowering::ExternalGemm(_) => LoweringStep::ExternalGemm,
+        Lowering::Kernel(kernel) => LoweringStep::Kernel(KernelPlan {
+            kernel: kernel.lir.mmm.kernel_name().to_string(),
+            fused_specs: kernel.lir.micro_ops.iter().map(|spec| spec.name()).collect(),
+            folded_cast: (kernel.replaced != node.id.into())
+                .then_some(kernel.lir.c_fact.datum_type),
+            macro_tiled: kernel.lir.macro_tiles.is_some(),
+        }),
+    }
+}
+
+/// The rewrite the codegen of an einsum picks, with what wiring its patch needs.
+enum Lowering<'a> {
+    Declined(DeclineReason),
+    ExtendedAccumulation,
+    SumSingleInputAxis(&'a Axis),
+    FuseSharedInput(SharedInputFusion<'a>),
+    BatchSharedConstant(SharedConstantBatch<'a>),
+    /// A k, m or n axis to merge or inject.
+    Mkn(MknAxes<'a>),
+    /// A quantized einsum, with its k axis, and whether it runs on float operands.
+    Dequantize(&'a Axis, bool),
+    TinyBatch(Box<TinyBatchLayout>),
+    SplitK(KSplitParts),
+    ExternalGemm(ExternalGemm),
+    SwapOperands,
+    Kernel(Box<KernelChoice>),
+}
+
+/// Pick the rewrite of the einsum, in order of precedence.
+fn lowering<'a>(
+    op: &'a EinSum,
+    model: &'a TypedModel,
+    node: &'a TypedNode,
+    options: &OptimizerOptions,
+) -> TractResult<Lowering<'a>> {
     if (op.q_params.is_none() && node.inputs.len() != 2)
         || (op.q_params.is_some() && node.inputs.len() != 9)
     {
-        return Ok(None);
+        return Ok(Lowering::Declined(DeclineReason::OperandCount));
+    }
+    if !op.can_rewrite(model, node)? {
+        return Ok(Lowering::Declined(DeclineReason::RankMismatch));
+    }
+    if op.q_params.is_none()
+        && op.operating_dt == f32::datum_type()
+        && is_extended_accumulation(model, node)
+    {
+        return Ok(Lowering::ExtendedAccumulation);
+    }
+    if is_large_constant(model, node, options)? {
+        return Ok(Lowering::Declined(DeclineReason::LargeConstant));
+    }
+    if op.axes.iter_all_axes().any(|axis| axis.inputs.iter().any(|i| i.len() > 1)) {
+        return Ok(Lowering::Declined(DeclineReason::Diagonal));
+    }
+    if let Some(axis) = single_input_axis(op, node) {
+        if op.q_params.is_some() {
+            return Ok(Lowering::Declined(DeclineReason::QuantizedReduction));
+        }
+        return Ok(Lowering::SumSingleInputAxis(axis));
+    }
+    if let Some(fusion) = shared_input_fusion(op, model, node, options.fuse_shared_inputs)? {
+        return Ok(Lowering::FuseSharedInput(fusion));
+    }
+    if options.batch_shared_constant_einsums {
+        if let Some(batch) = shared_constant_batch(op, model, node)? {
+            return Ok(Lowering::BatchSharedConstant(batch));
+        }
     }
-    let (m_axis, k_axis, n_axis) = match ensure_mkn_axes(op, model, node)? {
-        AxesOrPatch::Axes(m, k, n) => (m, k, n),
-        AxesOrPatch::Patch(p) => return Ok(Some(p)),
+    let (m_axis, k_axis, n_axis) = match mkn_axes(op, model, node)? {
+        MknAxes::Axes(m, k, n) => (m, k, n),
+        MknAxes::Declined(reason) => return Ok(Lowering::Declined(reason)),
+        rewrite => return Ok(Lowering::Mkn(rewrite)),
     };
-    if op.q_params.is_none() {
-        lir_mat_mul_unary(op, model, node, (m_axis, k_axis, n_axis))
-            .context("Translating to LirMatMul")
-    } else {
-        dequant_output(op, model, node, (m_axis, k_axis, n_axis)).context("Dequantizing output")
+    if op.q_params.is_some() {
+        return Ok(Lowering::Dequantize(k_axis, needs_float_fallback(model, node)?));
+    }
+    if let Some(layout) = tiny_batch_layout(op, model, node, (m_axis, k_axis, n_axis), options)? {
+        return Ok(Lowering::TinyBatch(Box::new(layout)));
+    }
+    if let Some(split) = k_split_parts(op, model, node, k_axis, options)? {
+        return Ok(Lowering::SplitK(split));
     }
+    if let Some(gemm) = external_gemm(op, model, node, (m_axis, k_axis, n_axis), options)? {
+        return Ok(Lowering::ExternalGemm(gemm));
+    }
+    lir_mat_mul_unary(op, model, node, (m_axis, k_axis, n_axis), options)
+        .context("Translating to LirMatMul")
 }
 
-pub(super) fn ensure_mkn_axes<'a>(
+fn swapped_operands(op: &EinSum) -> TractResult<EinSum> {
+    Ok(EinSum { axes: op.axes.reorder_inputs(&[1, 0])?, operands_swapped: true, ..op.clone() })
+}
+
+/// An einsum of constants with an output too large to be folded (see
+/// `OptimizerOptions::const_fold_max_bytes`) is not rewritten: the nodes replacing it would be
+/// evaluated as they are wired.
+pub(crate) fn is_large_constant(
+    model: &TypedModel,
+    node: &TypedNode,
+    options: &OptimizerOptions,
+) -> TractResult<bool> {
+    Ok(!options.const_foldable(node) && input_facts(model, node)?.iter().all(|f| f.konst.is_some()))
+}
+
+/// An axis of a single input absent from the output (like k in "ijk,jl->il"), summed before
+/// the product: it is no matrix product axis. Quantized einsums keep it, as the sum would have
+/// to be zero point compensated.
+fn single_input_axis<'a>(op: &'a EinSum, node: &TypedNode) -> Option<&'a Axis> {
+    // quantization parameters are no operands
+    let operands = if op.q_params.is_some() { 2 } else { node.inputs.len() };
+    op.axes.iter_all_axes().find(|axis| {
+        axis.outputs[0].len() == 0
+            && axis.inputs.iter().map(|i| i.len()).sum::<usize>() == 1
+            && axis.inputs[..operands].iter().any(|i| i.len() == 1)
+    })
+}
+
+/// Sum the axis of a single input before the product.
+fn sum_single_input_axis(
+    op: &EinSum,
+    model: &TypedModel,
+    node: &TypedNode,
+    axis: &Axis,
+) -> TractResult<TypedModelPatch> {
+    let slot = axis.inputs.iter().position(|i| i.len() == 1).unwrap();
+    let position = axis.inputs[slot][0];
+    let mut patch = TypedModelPatch::new(format!("Sum axis {} of {node}", axis.repr));
+    let mut inputs: TVec<OutletId> =
+        node.inputs.iter().map(|i| patch.tap_model(model, *i)).collect::<TractResult<_>>()?;
+    if model.outlet_fact(node.inputs[slot])?.datum_type != op.operating_dt {
+        inputs[slot] = patch.wire_node(
+            codegen_node_name(&node.name, format_args!("cast_{}", axis.repr)),
+            cast(op.operating_dt),
+            &[inputs[slot]],
+        )?[0];
+    }
+    inputs[slot] = patch.wire_node(
+        codegen_node_name(&node.name, format_args!("sum_{}", axis.repr)),
+        Reduce::new(tvec!(position), Reducer::Sum),
+        &[inputs[slot]],
+    )?[0];
+    inputs[slot] = patch.wire_node(
+        codegen_node_name(&node.name, format_args!("rm_{}", axis.repr)),
+        AxisOp::Rm(position),
+        &[inputs[slot]],
+    )?[0];
+    let axes = op.axes.remove_axis(axis.repr)?;
+    let wire = patch.wire_node(&node.name, EinSum { axes, ..op.clone() }, &inputs)?;
+    patch.shunt_outside(model, node.id.into(), wire[0])?;
+    Ok(patch)
+}
+
+/// Einsums applying different constant weights to the same input, fused by
+/// `fuse_shared_input_einsums`.
+struct SharedInputFusion<'a> {
+    shared: OutletId,
+    weights_slot: usize,
+    weights_axis: usize,
+    output_axis: usize,
+    group: TVec<(&'a TypedNode, Arc<Tensor>)>,
+}
+
+/// Is the einsum `node` flagged with `EXTENDED_ACCUMULATION` ? Such an einsum is not fused nor
+/// batched with its siblings.
+pub(super) fn is_extended_accumulation(model: &TypedModel, node: &TypedNode) -> bool {
+    model.node_property(node.id, EXTENDED_ACCUMULATION).is_some()
+}
+
+/// Compute a f32 einsum flagged with `EXTENDED_ACCUMULATION` as a f64 einsum on its operands
+/// cast to f64, its output cast back to f32. linalg has no kernel accumulating f32 operands in
+/// f64, so the f64 einsum is lowered to the f64 kernel (the output cast is folded in its store,
+/// and the cast of a constant operand in its packing).
+fn extended_accumulation(
+    op: &EinSum,
+    model: &TypedModel,
+    node: &TypedNode,
+) -> TractResult<TypedModelPatch> {
+    let f64_dt = f64::datum_type();
+    let mut patch = TypedModelPatch::new("Extended accumulation");
+    let mut inputs = tvec!();
+    for (slot, input) in node.inputs.iter().enumerate() {
+        let label = ['a', 'b'][slot];
+        let fact = model.outlet_fact(*input)?;
+        let wire = if let Some(konst) = &fact.konst {
+            let name = codegen_node_name(&node.name, format_args!("f64_{label}"));
+            patch.add_const(name, konst.cast_to_dt(f64_dt)?.into_owned())?
+        } else {
+            let wire = patch.tap_model(model, *input)?;
+            let name = codegen_node_name(&node.name, format_args!("cast_{label}"));
+            patch.wire_node(name, cast(f64_dt), &[wire])?[0]
+        };
+        inputs.push(wire);
+    }
+    let extended = EinSum { operating_dt: f64_dt, ..op.clone() };
+    let wire = patch.wire_node(codegen_node_name(&node.name, "f64"), extended, &inputs)?;
+    let wire = patch.wire_node(&node.name, cast(f32::datum_type()), &wire)?[0];
+    patch.shunt_outside(model, node.id.into(), wire)?;
+    Ok(patch)
+}
+
+/// Einsums applying different constant weights to the same input, to be fused in a single one
+/// (see `FuseSharedInputs`).
+fn shared_input_fusion<'a>(
+    op: &EinSum,
+    model: &'a TypedModel,
+    node: &'a TypedNode,
+    settings: FuseSharedInputs,
+) -> TractResult<Option<SharedInputFusion<'a>>> {
+    if op.q_params.is_some()
+        || op.shared_input_fused
+        || node.inputs.len() != 2
+        || settings.max_group < 2
+    {
+        return Ok(None);
+    }
+    for slot in 0..2 {
+        let shared = node.inputs[slot];
+        let weights_slot = 1 - slot;
+        let Some(weights) = model.outlet_fact(node.inputs[weights_slot])?.konst.clone() else {
+            continue;
+        };
+        if model.outlet_fact(shared)?.konst.is_some() {
+            continue;
+        }
+        let &[n_axis] = &*op
+            .axes
+            .iter_all_axes()
+            .filter(|a| {
+                a.inputs[slot].len() == 0
+                    && a.inputs[weights_slot].len() == 1
+                    && a.outputs[0].len() == 1
+            })
+            .collect_vec()
+        else {
+            continue;
+        };
+        let weights_axis = n_axis.inputs[weights_slot][0];
+        let output_axis = n_axis.outputs[0][0];
+        let same_geometry = |other: &Tensor| {
+            other.datum_type() == weights.datum_type()
+                && other.rank() == weights.rank()
+                && (0..weights.rank())
+                    .all(|ax| ax == weights_axis || other.shape()[ax] == weights.shape()[ax])
+        };
+        let mut group = tvec!((node, weights.clone()));
+        for succ in model.outlet_successors(shared) {
+            let sibling = model.node(succ.node);
+            if group.len() == settings.max_group || sibling.id == node.id || succ.slot != slot {
+                continue;
+            }
+            // skip nodes left dangling by a previous patch, until the model is compacted
+            if sibling.outputs[0].successors.is_empty()
+                && !model.output_outlets()?.contains(&sibling.id.into())
+            {
+                continue;
+            }
+            let Some(sibling_op) = sibling.op_as::<EinSum>() else { continue };
+            if sibling.inputs.len() != 2
+                || is_extended_accumulation(model, sibling)
+                || sibling_op.q_params.is_some()
+                || sibling_op.shared_input_fused
+                || sibling_op.operating_dt != op.operating_dt
+                || sibling_op.axes != op.axes
+            {
+                continue;
+            }
+            let Some(other) = model.outlet_fact(sibling.inputs[weights_slot])?.konst.clone() else {
+                continue;
+            };
+            if same_geometry(&other) {
+                group.push((sibling, other));
+            }
+        }
+        if group.len() < 2 {
+            continue;
+        }
+        return Ok(Some(SharedInputFusion {
+            shared,
+            weights_slot,
+            weights_axis,
+            output_axis,
+            group,
+        }));
+    }
+    Ok(None)
+}
+
+/// Fuse einsums applying different constant weights to the same input into a single one
+/// against the weights concatenated along n, so the shared input is packed and swept once.
+/// Each original output is then a slice of the fused one.
+fn fuse_shared_input_einsums(
+    op: &EinSum,
+    model: &TypedModel,
+    node: &TypedNode,
+    fusion: SharedInputFusion,
+) -> TractResult<TypedModelPatch> {
+    let SharedInputFusion { shared, weights_slot, weights_axis, output_axis, group } = fusion;
+    let name = &node.name;
+    let mut patch = TypedModelPatch::new(format!("Fuse {} einsums on {shared:?}", group.len()));
+    let fused_weights = Tensor::stack_tensors(
+        weights_axis,
+        &group.iter().map(|(_, w)| w.clone()).collect::<TVec<_>>(),
+    )?;
+    let mut inputs = tvec!(patch.tap_model(model, shared)?);
+    inputs.insert(
+        weights_slot,
+        patch.add_const(codegen_node_name(name, "fused_weights"), fused_weights)?,
+    );
+    let fused = EinSum { shared_input_fused: true, ..op.clone() };
+    let fused = patch.wire_node(codegen_node_name(name, "fused"), fused, &inputs)?[0];
+    let mut start = 0;
+    for (sibling, weights) in &group {
+        let end = start + weights.shape()[weights_axis];
+        let slice = patch.wire_node(
+            codegen_node_name(&sibling.name, "fused_slice"),
+            Slice::new(output_axis, start, end),
+            &[fused],
+        )?[0];
+        patch.shunt_outside(model, sibling.id.into(), slice)?;
+        start = end;
+    }
+    Ok(patch)
+}
+
+/// Einsums applying the same constant operand to distinct inputs, batched by
+/// `batch_shared_constant_einsums`.
+struct SharedConstantBatch<'a> {
+    weights_slot: usize,
+    group: Vec<&'a TypedNode>,
+}
+
+/// Einsums applying the same constant operand to distinct inputs of the same shape (like a
+/// projection shared by the unrolled layers of a model). The inputs must be mutually
+/// independent: none of them may be computed from the output of another einsum of the batch.
+fn shared_constant_batch<'a>(
+    op: &EinSum,
+    model: &'a TypedModel,
+    node: &'a TypedNode,
+) -> TractResult<Option<SharedConstantBatch<'a>>> {
+    if op.q_params.is_some() || node.inputs.len() != 2 {
+        return Ok(None);
+    }
+    for weights_slot in 0..2 {
+        let slot = 1 - weights_slot;
+        let weights = node.inputs[weights_slot];
+        let input_fact = model.outlet_fact(node.inputs[slot])?;
+        if model.outlet_fact(weights)?.konst.is_none() || input_fact.konst.is_some() {
+            continue;
+        }
+        let mut group = vec![(node, ancestors(model, node.inputs[slot].node))];
+        for succ in model.outlet_successors(weights) {
+            let sibling = model.node(succ.node);
+            if sibling.id == node.id || succ.slot != weights_slot {
+                continue;
+            }
+            // skip nodes left dangling by a previous patch, until the model is compacted
+            if sibling.outputs[0].successors.is_empty()
+                && !model.output_outlets()?.contains(&sibling.id.into())
+            {
+                continue;
+            }
+            let Some(sibling_op) = sibling.op_as::<EinSum>() else { continue };
+            if sibling.inputs.len() != 2
+                || is_extended_accumulation(model, sibling)
+                || sibling_op.q_params.is_some()
+                || sibling_op.operating_dt != op.operating_dt
+                || sibling_op.axes != op.axes
+            {
+                continue;
+            }
+            // the inputs are stacked: their shapes must match, symbols included
+            let fact = model.outlet_fact(sibling.inputs[slot])?;
+            if fact.datum_type != input_fact.datum_type
+                || fact.shape != input_fact.shape
+                || fact.konst.is_some()
+            {
+                continue;
+            }
+            let sibling_ancestors = ancestors(model, sibling.inputs[slot].node);
+            if group.iter().any(|(other, other_ancestors)| {
+                sibling_ancestors.contains(other.id) || other_ancestors.contains(sibling.id)
+            }) {
+                continue;
+            }
+            group.push((sibling, sibling_ancestors));
+        }
+        if group.len() < 2 {
+            continue;
+        }
+        let group = group.into_iter().map(|(node, _)| node).collect();
+        return Ok(Some(SharedConstantBatch { weights_slot, group }));
+    }
+    Ok(None)
+}
+
+/// Batch einsums applying the same constant operand to distinct inputs into a single one, over
+/// the inputs stacked along a new leading axis, so the constant is swept by a single kernel
+/// call. Each original output is then a slice of the batched one.
+fn batch_shared_constant_einsums(
+    op: &EinSum,
+    model: &TypedModel,
+    node: &TypedNode,
+    batch: SharedConstantBatch,
+) -> TractResult<TypedModelPatch> {
+    let SharedConstantBatch { weights_slot, group } = batch;
+    let slot = 1 - weights_slot;
+    let weights = node.inputs[weights_slot];
+    let name = &node.name;
+    let mut patch = TypedModelPatch::new(format!("Batch {} einsums on {weights:?}", group.len()));
+    let mut stacked = tvec!();
+    for member in &group {
+        let input = patch.tap_model(model, member.inputs[slot])?;
+        stacked.push(
+            patch.wire_node(
+                codegen_node_name(&member.name, "batch_add_axis"),
+                AxisOp::Add(0),
+                &[input],
+            )?[0],
+        );
+    }
+    let stacked = patch.wire_node(
+        codegen_node_name(name, "batch_stack"),
+        TypedConcat { axis: 0 },
+        &stacked,
+    )?;
+    let mut inputs = tvec!(stacked[0]);
+    inputs.insert(weights_slot, patch.tap_model(model, weights)?);
+    let repr = op.axes.available_label();
+    let axes = op
+        .axes
+        .clone()
+        .with_extra_axis(repr, InOut::In(slot), 0)?
+        .with_extra_axis_occurency(repr, InOut::Out(0), 0)?;
+    let batched = patch.wire_node(
+        codegen_node_name(name, "batched"),
+        EinSum { axes, ..op.clone() },
+        &inputs,
+    )?[0];
+    for (ix, member) in group.iter().enumerate() {
+        let slice = patch.wire_node(
+            codegen_node_name(&member.name, "batch_slice"),
+            Slice::new(0, ix, ix + 1),
+            &[batched],
+        )?;
+        let output = patch.wire_node(
+            codegen_node_name(&member.name, "batch_rm_axis"),
+            AxisOp::Rm(0),
+            &slice,
+        )?[0];
+        patch.shunt_outside(model, member.id.into(), output)?;
+    }
+    Ok(patch)
+}
+
+/// Nodes `node` is computed from, itself included.
+fn ancestors(model: &TypedModel, node: usize) -> bit_set::BitSet {
+    let mut done = bit_set::BitSet::with_capacity(model.nodes().len());
+    let mut todo = vec![node];
+    while let Some(node) = todo.pop() {
+        if done.insert(node) {
+            todo.extend(model.node(node).inputs.iter().map(|i| i.node));
+        }
+    }
+    done
+}
+
+/// The m, k and n axes of an einsum, or what to do to get them.
+pub(super) enum MknAxes<'a> {
+    Axes(&'a Axis, &'a Axis, &'a Axis),
+    /// Several k axes, to merge in a single one.
+    MergeK(TVec<&'a Axis>),
+    InjectK,
+    /// A missing m axis, with the k axis.
+    InjectM(&'a Axis),
+    /// A missing n axis, with the k and m axes.
+    InjectN(&'a Axis, &'a Axis),
+    Declined(DeclineReason),
+}
+
+pub(super) fn mkn_axes<'a>(
     op: &'a EinSum,
     model: &TypedModel,
     node: &TypedNode,
-) -> TractResult<AxesOrPatch<'a>> {
-    let input_facts = model.node_input_facts(node.id)?;
+) -> TractResult<MknAxes<'a>> {
+    let input_facts = input_facts(model, node)?;
     let input_shapes: TVec<&[TDim]> = input_facts.iter().map(|f| &*f.shape).collect();
-    let output_shape = super::eval::output_shape(&op.axes, &input_shapes);
+    let output_shape = super::eval::output_shape(&op.axes, &input_shapes)?;
     let candidate_k_axes: TVec<&Axis> = op
         .axes
         .iter_all_axes()
@@ -61,13 +785,15 @@ pub(super) fn ensure_mkn_axes<'a>(
         .collect::<TVec<_>>();
 
     let k_axis = if non_trivial_k_axis.len() > 1 {
-        // TODO: handle case where multiple consecutive k in the same order in both input.
-        bail!("Multiple k-axis candidate found");
+        return Ok(match mergeable_k_axes(&input_facts, &non_trivial_k_axis) {
+            Ok(k_axes) => MknAxes::MergeK(k_axes),
+            Err(reason) => MknAxes::Declined(reason),
+        });
     } else {
         non_trivial_k_axis.get(0).copied().or_else(|| candidate_k_axes.get(0)).copied()
     };
     let Some(k_axis) = k_axis else {
-        return Ok(AxesOrPatch::Patch(inject_k_axis(op, model, node)?));
+        return Ok(MknAxes::InjectK);
     };
     let m_axis = op
         .axes
@@ -79,7 +805,7 @@ pub(super) fn ensure_mkn_axes<'a>(
         })
         .max_by_key(|a| &output_shape[a.outputs[0][0]]);
     let Some(m_axis) = m_axis else {
-        return Ok(AxesOrPatch::Patch(inject_m_or_n_axis(op, model, node, false, &[k_axis])?));
+        return Ok(MknAxes::InjectM(k_axis));
     };
     let n_axis = op
         .axes
@@ -91,9 +817,98 @@ pub(super) fn ensure_mkn_axes<'a>(
         })
         .max_by_key(|a| &output_shape[a.outputs[0][0]]);
     let Some(n_axis) = n_axis else {
-        return Ok(AxesOrPatch::Patch(inject_m_or_n_axis(op, model, node, true, &[k_axis, m_axis])?));
+        return Ok(MknAxes::InjectN(k_axis, m_axis));
     };
-    Ok(AxesOrPatch::Axes(m_axis, k_axis, n_axis))
+    Ok(MknAxes::Axes(m_axis, k_axis, n_axis))
+}
+
+pub(super) fn ensure_mkn_axes<'a>(
+    op: &'a EinSum,
+    model: &TypedModel,
+    node: &TypedNode,
+) -> TractResult<AxesOrPatch<'a>> {
+    let axes = mkn_axes(op, model, node)?;
+    wire_mkn_rewrite(op, model, node, axes)
+}
+
+/// Wire the merge or the injection of the axes making the m, k and n axes of an einsum.
+fn wire_mkn_rewrite<'a>(
+    op: &EinSum,
+    model: &TypedModel,
+    node: &TypedNode,
+    axes: MknAxes<'a>,
+) -> TractResult<AxesOrPatch<'a>> {
+    Ok(match axes {
+        MknAxes::Axes(m, k, n) => AxesOrPatch::Axes(m, k, n),
+        MknAxes::MergeK(k_axes) => AxesOrPatch::Patch(merge_k_axes(op, model, node, &k_axes)?),
+        MknAxes::InjectK => injected(inject_k_axis(op, model, node)),
+        MknAxes::InjectM(k) => injected(inject_m_or_n_axis(op, model, node, false, &[k])),
+        MknAxes::InjectN(k, m) => injected(inject_m_or_n_axis(op, model, node, true, &[k, m])),
+        MknAxes::Declined(reason) => AxesOrPatch::Declined(reason),
+    })
+}
+
+/// Several k axes can be merged in a single one if they are consecutive and in the same order
+/// in both inputs, with concrete dimensions. They are returned in order.
+fn mergeable_k_axes<'a>(
+    input_facts: &[&TypedFact],
+    k_axes: &[&&'a Axis],
+) -> Result<TVec<&'a Axis>, DeclineReason> {
+    let k_axes: TVec<&Axis> =
+        k_axes.iter().map(|a| **a).sorted_by_key(|a| a.inputs[0][0]).collect();
+    for slot in 0..2 {
+        let first = k_axes[0].inputs[slot][0];
+        if k_axes.iter().enumerate().any(|(ix, a)| a.inputs[slot][0] != first + ix) {
+            return Err(DeclineReason::MultipleKAxes);
+        }
+    }
+    if k_axes.iter().any(|a| input_facts[0].shape[a.inputs[0][0]].to_usize().is_err()) {
+        return Err(DeclineReason::SymbolicKAxes);
+    }
+    Ok(k_axes)
+}
+
+/// Merge k axes that are consecutive and in the same order in both inputs into a single one.
+pub(super) fn merge_k_axes(
+    op: &EinSum,
+    model: &TypedModel,
+    node: &TypedNode,
+    k_axes: &[&Axis],
+) -> TractResult<TypedModelPatch> {
+    let input_facts = input_facts(model, node)?;
+    let dims: TVec<TDim> =
+        k_axes.iter().map(|a| input_facts[0].shape[a.inputs[0][0]].clone()).collect();
+    let k: TDim = dims.iter().product();
+    let (mut inputs, outputs) = op.axes.to_strs();
+    for input in &mut inputs[0..2] {
+        input.retain(|c| k_axes[1..].iter().all(|a| a.repr != c));
+    }
+    let axes = AxesMapping::from_strs(&inputs, &outputs)?;
+    let name = &node.name;
+    let mut patch = TypedModelPatch::new("merge k axes");
+    let mut wire =
+        node.inputs.iter().map(|i| patch.tap_model(model, *i)).collect::<TractResult<TVec<_>>>()?;
+    for slot in 0..2 {
+        wire[slot] = patch.wire_node(
+            codegen_node_name(name, format_args!("merge_k.{slot}")),
+            AxisOp::Reshape(k_axes[0].inputs[slot][0], dims.clone(), tvec!(k.clone())),
+            &[wire[slot]],
+        )?[0];
+    }
+    wire = patch.wire_node(name, EinSum { axes, ..op.clone() }, &wire)?;
+    patch.shunt_outside(model, node.id.into(), wire[0])?;
+    Ok(patch)
+}
+
+/// A failed injection leaves the einsum as it is: it is still evaluated, just not lowered.
+pub(super) fn injected<'a>(patch: TractResult<TypedModelPatch>) -> AxesOrPatch<'a> {
+    match patch {
+        Ok(patch) => AxesOrPatch::Patch(patch),
+        Err(e) => {
+            log::warn!("{e:?}");
+            AxesOrPatch::Declined(DeclineReason::AxisInjection)
+        }
+    }
 }
 
 pub(super) fn inject_k_axis(
@@ -101,6 +916,7 @@ pub(super) fn inject_k_axis(
     model: &TypedModel,
     node: &TypedNode,
 ) -> TractResult<TypedModelPatch> {
+    let context = |step: &str| format!("Injecting k axis in {node} ({}): {step}", op.axes);
     let mut new_axes = op.axes.clone();
     let name = &node.name;
     let mut patch = TypedModelPatch::new("inject k axis");
@@ -110,20 +926,29 @@ pub(super) fn inject_k_axis(
         new_axes.iter_all_axes().find(|a| a.outputs[0].len() == 0).map(|axis| axis.repr);
     if let Some(axis) = possible_k_axis {
         let input_to_fix = (new_axes.axis(axis)?.inputs[0].len() > 0) as usize;
-        new_axes = new_axes.with_extra_axis_occurency(axis, InOut::In(input_to_fix), 0)?;
-        wire[input_to_fix] =
-            patch.wire_node(format!("{name}.add_k"), AxisOp::Add(0), &[wire[input_to_fix]])?[0];
+        new_axes = new_axes
+            .with_extra_axis_occurency(axis, InOut::In(input_to_fix), 0)
+            .with_context(|| context(&format!("adding {axis} to input #{input_to_fix}")))?;
+        wire[input_to_fix] = patch.wire_node(
+            codegen_node_name(name, "add_k"),
+            AxisOp::Add(0),
+            &[wire[input_to_fix]],
+        )?[0];
     } else {
         let repr = new_axes.available_label();
-        new_axes = new_axes.with_extra_axis(repr, InOut::In(0), 0)?.with_extra_axis_occurency(
-            repr,
-            InOut::In(1),
-            0,
-        )?;
-        wire[0] = patch.wire_node(format!("{name}.add_k.0"), AxisOp::Add(0), &[wire[0]])?[0];
-        wire[1] = patch.wire_node(format!("{name}.add_k.1"), AxisOp::Add(0), &[wire[1]])?[0];
+        new_axes = new_axes
+            .with_extra_axis(repr, InOut::In(0), 0)
+            .and_then(|axes| axes.with_extra_axis_occurency(repr, InOut::In(1), 0))
+            .with_context(|| context(&format!("adding {repr} to both inputs")))?;
+        wire[0] =
+            patch.wire_node(codegen_node_name(name, "add_k.0"), AxisOp::Add(0), &[wire[0]])?[0];
+        wire[1] =
+            patch.wire_node(codegen_node_name(name, "add_k.1"), AxisOp::Add(0), &[wire[1]])?[0];
     };
-    wire = patch.wire_node(&node.name, EinSum { axes: new_axes, ..op.clone() }, &wire)?;
+    let step = format!("wiring {new_axes}");
+    wire = patch
+        .wire_node(&node.name, EinSum { axes: new_axes, ..op.clone() }, &wire)
+        .with_context(|| context(&step))?;
     patch.shunt_outside(model, node.id.into(), wire[0])?;
     Ok(patch)
 }
@@ -137,7 +962,10 @@ pub(super) fn inject_m_or_n_axis(
 ) -> TractResult<TypedModelPatch> {
     let input_to_fix = is_n as usize;
     let label = if is_n { "n" } else { "m" };
-    let input_facts = model.node_input_facts(node.id)?;
+    let context = |step: &str| format!("Injecting {label} axis in {node} ({}): {step}", op.axes);
+    // a temporary label for the axis linked to an existing one
+    let tmp = op.axes.available_label();
+    let input_facts = input_facts(model, node)?;
     let quasi_m_or_n_axis = op.axes.iter_all_axes().filter(|a| !exclude.contains(a)).find(|a| {
         (a.inputs[1 - input_to_fix].len() == 0
             || input_facts[1 - input_to_fix].shape[a.inputs[1 - input_to_fix][0]].is_one())
@@ -149,41 +977,67 @@ pub(super) fn inject_m_or_n_axis(
         node.inputs.iter().map(|i| patch.tap_model(model, *i)).collect::<TractResult<TVec<_>>>()?;
     if let Some(axis) = quasi_m_or_n_axis {
         if axis.inputs[input_to_fix].len() == 1 {
-            let new_axes =
-                op.axes.clone().with_extra_axis('$', InOut::Out(0), 0)?.linking(axis.repr, '$')?;
+            let new_axes = op
+                .axes
+                .clone()
+                .with_extra_axis(tmp, InOut::Out(0), 0)
+                .and_then(|axes| axes.linking(axis.repr, tmp))
+                .with_context(|| context(&format!("adding {} to the output", axis.repr)))?;
+            let step = format!("wiring {new_axes}");
+            wire = patch
+                .wire_node(name, EinSum { axes: new_axes, ..op.clone() }, &wire)
+                .with_context(|| context(&step))?;
             wire = patch.wire_node(
-                format!("{name}.einsum"),
-                EinSum { axes: new_axes, ..op.clone() },
+                codegen_node_name(name, format_args!("rm_{label}")),
+                AxisOp::Rm(0),
                 &wire,
             )?;
-            wire = patch.wire_node(&node.name, AxisOp::Rm(0), &wire)?;
         } else {
             let new_axes = op
                 .axes
                 .clone()
-                .with_extra_axis('$', InOut::In(input_to_fix), 0)?
-                .linking(axis.repr, '$')?;
-            wire[input_to_fix] =
-                patch.wire_node(format!("{name}.add_{label}"), AxisOp::Add(0), &[wire[input_to_fix]])?
-                    [0];
-            wire = patch.wire_node(&node.name, EinSum { axes: new_axes, ..op.clone() }, &wire)?;
+                .with_extra_axis(tmp, InOut::In(input_to_fix), 0)
+                .and_then(|axes| axes.linking(axis.repr, tmp))
+                .with_context(|| {
+                    context(&format!("adding {} to input #{input_to_fix}", axis.repr))
+                })?;
+            wire[input_to_fix] = patch.wire_node(
+                codegen_node_name(name, format_args!("add_{label}")),
+                AxisOp::Add(0),
+                &[wire[input_to_fix]],
+            )?[0];
+            let step = format!("wiring {new_axes}");
+            wire = patch
+                .wire_node(&node.name, EinSum { axes: new_axes, ..op.clone() }, &wire)
+                .with_context(|| context(&step))?;
         }
     } else {
-        let repr = op.axes.available_label();
+        let repr = tmp;
         let new_axes = op
             .axes
             .clone()
-            .with_extra_axis(repr, InOut::In(input_to_fix), 0)?
-            .with_extra_axis('$', InOut::Out(0), 0)?
-            .linking(repr, '$')?;
-        wire[input_to_fix] =
-            patch.wire_node(format!("{name}.add_{label}"), AxisOp::Add(0), &[wire[input_to_fix]])?[0];
+            .with_extra_axis(repr, InOut::In(input_to_fix), 0)
+            .and_then(|axes| {
+                let tmp = axes.available_label();
+                axes.with_extra_axis(tmp, InOut::Out(0), 0)?.linking(repr, tmp)
+            })
+            .with_context(|| {
+                context(&format!("adding {repr} to input #{input_to_fix} and the output"))
+            })?;
+        wire[input_to_fix] = patch.wire_node(
+            codegen_node_name(name, format_args!("add_{label}")),
+            AxisOp::Add(0),
+            &[wire[input_to_fix]],
+        )?[0];
+        let step = format!("wiring {new_axes}");
+        wire = patch
+            .wire_node(name, EinSum { axes: new_axes, ..op.clone() }, &wire)
+            .with_context(|| context(&step))?;
         wire = patch.wire_node(
-            format!("{name}.einsum"),
-            EinSum { axes: new_axes, ..op.clone() },
+            codegen_node_name(name, format_args!("rm_{label}")),
+            AxisOp::Rm(0),
             &wire,
         )?;
-        wire = patch.wire_node(&node.name, AxisOp::Rm(0), &wire)?;
     }
     patch.shunt_outside(model, node.id.into(), wire[0])?;
     Ok(patch)
@@ -197,49 +1051,104 @@ fn wire_axes_fix(
     mut outlet: TVec<OutletId>,
 ) -> TractResult<TVec<OutletId>> {
     for (ix, axis_op) in mapping.translate_to_axis_ops()?.into_iter().enumerate() {
-        outlet = patch.wire_node(format!("{name}.fix_{var}.{ix})"), axis_op, &outlet)?;
+        outlet = patch.wire_node(
+            codegen_node_name(name, format_args!("fix_{var}.{ix}")),
+            axis_op,
+            &outlet,
+        )?;
     }
     Ok(outlet)
 }
 
+/// Lay out c0 and c_scale as the output: per-channel output quantization parameters are vectors
+/// along an output axis.
+fn wire_output_q_params(
+    patch: &mut TypedModelPatch,
+    op: &EinSum,
+    name: &str,
+    [c0, c_scale]: [OutletId; 2],
+) -> TractResult<[OutletId; 2]> {
+    let c0 =
+        wire_axes_fix(patch, name, "c0", &op.axes.extract_sub_mapping(&[7], &[0])?, tvec!(c0))?;
+    let c_scale = tvec!(c_scale);
+    let c_scale =
+        wire_axes_fix(patch, name, "c_scale", &op.axes.extract_sub_mapping(&[8], &[0])?, c_scale)?;
+    Ok([c0[0], c_scale[0]])
+}
+
+/// Lower a quantized einsum to an i32 einsum followed by zero point compensation and
+/// requantization.
+///
+/// A float bias (left unquantized by some exporters) is quantized here to the accumulator grid
+/// (`round(bias / (a_scale * b_scale))`) when a and b scales are scalar constants. Otherwise it
+/// is added after dequantization, and requantization happens from the float domain.
+/// A quantized einsum with operands linalg has no integer kernel for runs in float (see
+/// `float_fallback`).
+fn needs_float_fallback(model: &TypedModel, node: &TypedNode) -> TractResult<bool> {
+    // u8 operands are offset to i8
+    let kernel_dt = |ix: usize| -> TractResult<DatumType> {
+        let dt = model.outlet_fact(node.inputs[ix])?.datum_type.unquantized();
+        Ok(if dt == DatumType::U8 { DatumType::I8 } else { dt })
+    };
+    // linalg has an i8 kernel on every platform (a portable one if nothing better)
+    let (a_dt, b_dt) = (kernel_dt(0)?, kernel_dt(1)?);
+    Ok((a_dt != DatumType::I8 || b_dt != DatumType::I8)
+        && tract_linalg::ops().mmm(a_dt, b_dt, i32::datum_type(), None, None, None).is_none())
+}
+
 fn dequant_output(
     op: &EinSum,
     model: &TypedModel,
     node: &TypedNode,
-    (_, k_axis, _): (&Axis, &Axis, &Axis),
-) -> TractResult<Option<TypedModelPatch>> {
-    let name = &node.name;
+    k_axis: &Axis,
+    float_fallback: bool,
+    options: &OptimizerOptions,
+) -> TractResult<TypedModelPatch> {
+    if float_fallback {
+        return self::float_fallback(op, model, node);
+    }
+    let name = codegen_base_name(&node.name);
     let mut patch = TypedModelPatch::new("Dequantizing einsum");
-    let taps: Vec<OutletId> =
-        node.inputs.iter().map(|i| patch.tap_model(model, *i)).collect::<TractResult<Vec<_>>>()?;
-    let [a, b, bias, mut a0, a_scale, mut b0, b_scale, c0, c_scale] = *taps else {
+    let taps = tap_quantized_inputs(&mut patch, model, node, name)?;
+    let [a, b, mut bias, mut a0, a_scale, mut b0, b_scale, c0, c_scale] = *taps else {
         bail!("Expect exactly 9 inputs")
     };
+    let [c0, c_scale] = wire_output_q_params(&mut patch, op, name, [c0, c_scale])?;
+
+    let qp = op.q_params.unwrap();
+    let mut float_bias = false;
+    if model.outlet_fact(node.inputs[2])?.datum_type.is_float() {
+        // a dequantize-only einsum keeps the float bias exact
+        if let Some(bias_q) = quantize_const_bias(model, node)?.filter(|_| !qp.is_float()) {
+            bias = patch.add_const(codegen_node_name(name, "bias_q"), bias_q)?;
+        } else {
+            float_bias = true;
+        }
+    }
 
-    let a = wire_offset_u8_as_i8(&mut patch, &node.name, a, "a", &mut a0, "a0")?;
-    let b = wire_offset_u8_as_i8(&mut patch, &node.name, b, "b", &mut b0, "b0")?;
+    let a = wire_offset_u8_as_i8(&mut patch, name, a, "a", &mut a0, "a0")?;
+    let b = wire_offset_u8_as_i8(&mut patch, name, b, "b", &mut b0, "b0")?;
+    // vector zero points (like ONNX MatMulInteger per-row a0) are laid out as the output
+    let a0_axes = op.axes.extract_sub_mapping(&[3], &[0])?;
+    let a0 = wire_axes_fix(&mut patch, name, "a0", &a0_axes, tvec!(a0))?[0];
+    let b0_axes = op.axes.extract_sub_mapping(&[5], &[0])?;
+    let b0 = wire_axes_fix(&mut patch, name, "b0", &b0_axes, tvec!(b0))?[0];
 
     let mut output = patch.wire_node(
         &node.name,
-        EinSum {
-            q_params: None,
-            axes: op.axes.extract_sub_mapping(&[0, 1], &[0])?,
-            operating_dt: op.operating_dt,
-        },
+        EinSum::new(op.axes.extract_sub_mapping(&[0, 1], &[0])?, op.operating_dt),
         &[a, b],
     )?;
 
-    let a_i32 = patch.wire_node(format!("{name}.a_as_i32"), cast(i32::datum_type()), &[a])?[0];
-    let b_i32 = patch.wire_node(format!("{name}.b_as_i32"), cast(i32::datum_type()), &[b])?[0];
     let sum_a = patch.wire_node(
-        format!("{name}.sum_a"),
-        Reduce::new(tvec!(k_axis.inputs[0][0]), Reducer::Sum),
-        &[a_i32],
+        codegen_node_name(name, "sum_a"),
+        IntegerSum::new(tvec!(k_axis.inputs[0][0])),
+        &[a],
     )?;
     let sum_b = patch.wire_node(
-        format!("{name}.sum_b"),
-        Reduce::new(tvec!(k_axis.inputs[1][0]), Reducer::Sum),
-        &[b_i32],
+        codegen_node_name(name, "sum_b"),
+        IntegerSum::new(tvec!(k_axis.inputs[1][0])),
+        &[b],
     )?;
 
     let sum_a =
@@ -250,25 +1159,683 @@ fn dequant_output(
     let bias =
         wire_axes_fix(&mut patch, name, "bias", &op.axes.extract_sub_mapping(&[2], &[0])?, bias)?;
 
-    let abc_scale = combine_scales(&mut patch, name, a_scale, b_scale, c_scale)?;
-
-    output = patch.wire_node(format!("{name}.add_bias"), add(), &[output[0], bias[0]])?;
+    if !float_bias {
+        let add_bias = codegen_node_name(name, "add_bias");
+        output = tvec!(wire_in_place(&mut patch, &add_bias, add(), [output[0], bias[0]])?);
+    }
 
     let k = model.outlet_fact(node.inputs[0])?.shape[k_axis.inputs[0][0]].clone();
+    if let Ok(k) = k.to_i64() {
+        // zero point compensation computes a0 * k in i32
+        ensure!(k <= i32::MAX as i64, "{}: k={k} overflows the i32 accumulator", node.name);
+    }
     let output = compensate_zero_points(&mut patch, name, output[0], k, a0, b0, sum_a[0], sum_b[0])
         .context("Zero point compensation")?;
-    let output = requant(&mut patch, name, output, op.q_params.unwrap(), abc_scale, c0)?;
+    if !float_bias && is_accumulate_only(op, model, node)? {
+        patch.shunt_outside(model, node.id.into(), output)?;
+        return Ok(patch);
+    }
+    if options.calibration_taps {
+        let label = codegen_node_name(name, "accumulator");
+        patch.set_node_property(output.node, CALIBRATION_TAP, rctensor0(label))?;
+    }
+    if float_bias || qp.is_float() {
+        let ab_scale = wire_with_rank_broadcast(
+            &codegen_node_name(name, "ab_scale"),
+            &mut patch,
+            mul(),
+            &[a_scale, b_scale],
+        )?[0];
+        let mut real = wire_dequant(&mut patch, name, "acc", output, ab_scale)?;
+        if float_bias {
+            let bias = patch.wire_node(
+                codegen_node_name(name, "bias_as_f32"),
+                cast(f32::datum_type()),
+                &[bias[0]],
+            )?[0];
+            let add_bias = codegen_node_name(name, "add_bias");
+            real = wire_in_place(&mut patch, &add_bias, add(), [real, bias])?;
+        }
+        let output = if qp.is_float() {
+            dequantized(&mut patch, name, real, op.q_activation, qp)?
+        } else {
+            let output = requant_from_real(&mut patch, name, real, [c_scale, c0], op.q_activation)?;
+            clamp_and_cast_to(&mut patch, name, qp, output, op.q_overflow)?
+        };
+        patch.shunt_outside(model, node.id.into(), output)?;
+        return Ok(patch);
+    }
+
+    let abc_scale = combine_scales(&mut patch, name, a_scale, b_scale, c_scale)?;
+    let output = if let Some(activation) = op.q_activation {
+        wire_fused_activation(&mut patch, name, output, a_scale, b_scale, activation)?
+    } else {
+        output
+    };
+    let output = requant(&mut patch, name, output, qp, abc_scale, c0, op.q_overflow)?;
     patch.shunt_outside(model, node.id.into(), output)?;
-    Ok(Some(patch))
+    Ok(patch)
+}
+
+/// The constant behind a quantization parameter input, looking through the ops only changing
+/// the rank of a single value or nothing at all (a `Reshape` to or from `[1]`, an axis added or
+/// removed, a `Cast` to its own type) when the optimizer left them unfolded.
+fn resolved_q_param(model: &TypedModel, outlet: OutletId) -> TractResult<Option<Arc<Tensor>>> {
+    if let Some(konst) = &model.outlet_fact(outlet)?.konst {
+        return Ok(Some(konst.clone()));
+    }
+    let node = model.node(outlet.node);
+    let trivial = if let Some(op) = node.op_as::<AxisOp>() {
+        match op {
+            AxisOp::Add(_) | AxisOp::Rm(_) => true,
+            AxisOp::Reshape(_, from, to) => from.iter().chain(to.iter()).all(|d| d.is_one()),
+            _ => false,
+        }
+    } else if let Some(op) = node.op_as::<Cast>() {
+        model.outlet_fact(node.inputs[0])?.datum_type == op.to
+    } else {
+        false
+    };
+    if !trivial {
+        return Ok(None);
+    }
+    let Some(input) = resolved_q_param(model, node.inputs[0])? else { return Ok(None) };
+    Ok(Some(node.op.eval(tvec!(input.into_tvalue()))?.remove(0).into_arc_tensor()))
+}
+
+/// Tap the inputs of a quantized einsum, wiring the quantization parameters resolved to
+/// constants behind trivial ops (see `resolved_q_param`) as constants, for the lowering to
+/// make the same decisions as on directly wired constants.
+fn tap_quantized_inputs(
+    patch: &mut TypedModelPatch,
+    model: &TypedModel,
+    node: &TypedNode,
+    name: &str,
+) -> TractResult<Vec<OutletId>> {
+    const Q_PARAMS: [&str; 7] = ["bias", "a0", "a_scale", "b0", "b_scale", "c0", "c_scale"];
+    let mut taps = vec![];
+    for (ix, input) in node.inputs.iter().enumerate() {
+        let resolved = if ix >= 2 && model.outlet_fact(*input)?.konst.is_none() {
+            resolved_q_param(model, *input)?
+        } else {
+            None
+        };
+        taps.push(if let Some(konst) = resolved {
+            patch.add_const(codegen_node_name(name, Q_PARAMS[ix - 2]), konst)?
+        } else {
+            patch.tap_model(model, *input)?
+        });
+    }
+    Ok(taps)
+}
+
+/// A quantized einsum to i32 with unit scales and no output zero point or activation (like ONNX
+/// MatMulInteger) only accumulates: its lowering stops after the zero point compensation.
+fn is_accumulate_only(op: &EinSum, model: &TypedModel, node: &TypedNode) -> TractResult<bool> {
+    if op.q_params != Some(i32::datum_type()) || op.q_activation.is_some() {
+        return Ok(false);
+    }
+    let is_const = |ix: usize, value: f32| -> TractResult<bool> {
+        let Some(konst) = resolved_q_param(model, node.inputs[ix])? else { return Ok(false) };
+        Ok(konst.cast_to::<f32>()?.as_slice::<f32>()?.iter().all(|x| *x == value))
+    };
+    Ok(is_const(4, 1.)? && is_const(6, 1.)? && is_const(7, 0.)? && is_const(8, 1.)?)
+}
+
+/// Lower a quantized einsum with operands linalg has no integer kernel for (like i16
+/// activations), or flagged with `FLOAT_FALLBACK`, to a f32 einsum on the zero-point-centered
+/// operands, then requantize.
+pub(super) fn float_fallback(
+    op: &EinSum,
+    model: &TypedModel,
+    node: &TypedNode,
+) -> TractResult<TypedModelPatch> {
+    let name = codegen_base_name(&node.name);
+    let mut patch = TypedModelPatch::new("Quantized einsum in f32");
+    let taps = tap_quantized_inputs(&mut patch, model, node, name)?;
+    let [_, _, bias, _, a_scale, _, b_scale, c0, c_scale] = *taps else {
+        bail!("Expect exactly 9 inputs")
+    };
+    let [c0, c_scale] = wire_output_q_params(&mut patch, op, name, [c0, c_scale])?;
+    let mut centered = |slot: usize, zp_slot: usize, var: &str| -> TractResult<OutletId> {
+        let (wire, mut zero_point) = (taps[slot], taps[zp_slot]);
+        // a vector zero point runs along one of the operand axes
+        if model.outlet_fact(node.inputs[zp_slot])?.rank() == 1 {
+            let axis = op.axes.axis((InOut::In(zp_slot), 0))?.inputs[slot][0];
+            let rank = model.outlet_fact(node.inputs[slot])?.rank();
+            for (ix, position) in (0..axis).chain(axis + 1..rank).enumerate() {
+                zero_point = patch.wire_node(
+                    codegen_node_name(name, format_args!("{var}0_axis_{ix}")),
+                    AxisOp::Add(position),
+                    &[zero_point],
+                )?[0];
+            }
+        }
+        let wire = patch.wire_node(
+            codegen_node_name(name, format_args!("{var}_as_f32")),
+            cast(f32::datum_type()),
+            &[wire],
+        )?[0];
+        let zero_point = patch.wire_node(
+            codegen_node_name(name, format_args!("{var}0_as_f32")),
+            cast(f32::datum_type()),
+            &[zero_point],
+        )?[0];
+        Ok(wire_with_rank_broadcast(
+            &codegen_node_name(name, format_args!("{var}_centered")),
+            &mut patch,
+            sub(),
+            &[wire, zero_point],
+        )?[0])
+    };
+    let a = centered(0, 3, "a")?;
+    let b = centered(1, 5, "b")?;
+    let output = patch.wire_node(
+        &node.name,
+        EinSum::new(op.axes.extract_sub_mapping(&[0, 1], &[0])?, f32::datum_type()),
+        &[a, b],
+    )?[0];
+    let ab_scale = wire_with_rank_broadcast(
+        &codegen_node_name(name, "ab_scale"),
+        &mut patch,
+        mul(),
+        &[a_scale, b_scale],
+    )?[0];
+    let real = wire_with_rank_broadcast(
+        &codegen_node_name(name, "dequant_acc"),
+        &mut patch,
+        mul(),
+        &[output, ab_scale],
+    )?[0];
+    let bias = wire_axes_fix(
+        &mut patch,
+        name,
+        "bias",
+        &op.axes.extract_sub_mapping(&[2], &[0])?,
+        tvec!(bias),
+    )?[0];
+    let bias = if model.outlet_fact(node.inputs[2])?.datum_type.is_float() {
+        patch.wire_node(codegen_node_name(name, "bias_as_f32"), cast(f32::datum_type()), &[bias])?
+            [0]
+    } else {
+        wire_dequant(&mut patch, name, "bias", bias, ab_scale)?
+    };
+    let add_bias = codegen_node_name(name, "add_bias");
+    let real = wire_in_place(&mut patch, &add_bias, add(), [real, bias])?;
+    let qp = op.q_params.unwrap();
+    let output = if qp.is_float() {
+        dequantized(&mut patch, name, real, op.q_activation, qp)?
+    } else {
+        let output = requant_from_real(&mut patch, name, real, [c_scale, c0], op.q_activation)?;
+        clamp_and_cast_to(&mut patch, name, qp, output, op.q_overflow)?
+    };
+    patch.shunt_outside(model, node.id.into(), output)?;
+    Ok(patch)
+}
+
+/// Quantize a constant float bias to the i32 accumulator grid. None if the bias or the a and b
+/// scales are not constant, or if the scales are not scalars.
+fn quantize_const_bias(model: &TypedModel, node: &TypedNode) -> TractResult<Option<Tensor>> {
+    let konst = |ix: usize| resolved_q_param(model, node.inputs[ix]);
+    let (Some(bias), Some(a_scale), Some(b_scale)) = (konst(2)?, konst(4)?, konst(6)?) else {
+        return Ok(None);
+    };
+    if a_scale.len() != 1 || b_scale.len() != 1 {
+        return Ok(None);
+    }
+    let ab_scale = a_scale.cast_to_scalar::<f32>()? * b_scale.cast_to_scalar::<f32>()?;
+    let bias = bias.cast_to::<f32>()?;
+    let bias_q = bias.to_array_view::<f32>()?.mapv(|b| round_ties_to_even(b / ab_scale) as i32);
+    Ok(Some(bias_q.into_tensor()))
+}
+
+/// Cast `wire` to f32 and scale it by the product of a and b scales.
+fn wire_dequant(
+    patch: &mut TypedModelPatch,
+    name: &str,
+    role: &str,
+    wire: OutletId,
+    ab_scale: OutletId,
+) -> TractResult<OutletId> {
+    let wire = patch.wire_node(
+        codegen_node_name(name, format_args!("{role}_as_f32")),
+        cast(f32::datum_type()),
+        &[wire],
+    )?[0];
+    wire_in_place(
+        patch,
+        &codegen_node_name(name, format_args!("dequant_{role}")),
+        mul(),
+        [wire, ab_scale],
+    )
+}
+
+/// Apply the activation, if any, to a real domain wire.
+fn wire_real_activation(
+    patch: &mut TypedModelPatch,
+    name: &str,
+    wire: OutletId,
+    activation: Option<QActivation>,
+) -> TractResult<OutletId> {
+    let Some(activation) = activation else { return Ok(wire) };
+    if let Some(ew) = activation.as_element_wise() {
+        Ok(patch.wire_node(codegen_node_name(name, "activation"), ew, &[wire])?[0])
+    } else {
+        let zero = patch.add_const(codegen_node_name(name, "activation.zero"), tensor0(0f32))?;
+        let activation = codegen_node_name(name, "activation");
+        Ok(wire_with_rank_broadcast(&activation, patch, max(), &[wire, zero])?[0])
+    }
+}
+
+/// Output of a dequantize-only einsum (float q_params): the real value after the activation,
+/// in the output type.
+fn dequantized(
+    patch: &mut TypedModelPatch,
+    name: &str,
+    real: OutletId,
+    activation: Option<QActivation>,
+    qp: DatumType,
+) -> TractResult<OutletId> {
+    let wire = wire_real_activation(patch, name, real, activation)?;
+    if qp == f32::datum_type() {
+        Ok(wire)
+    } else {
+        Ok(patch.wire_node(codegen_node_name(name, "cast_output"), cast(qp), &[wire])?[0])
+    }
+}
+
+/// Requantize from the float domain to an i32 wire offset by c0. The activation, if any, is
+/// applied to the real value first.
+fn requant_from_real(
+    patch: &mut TypedModelPatch,
+    name: &str,
+    real: OutletId,
+    [c_scale, c0]: [OutletId; 2],
+    activation: Option<QActivation>,
+) -> TractResult<OutletId> {
+    let mut wire = wire_real_activation(patch, name, real, activation)?;
+    wire = wire_in_place(patch, &codegen_node_name(name, "quant"), div(), [wire, c_scale])?;
+    wire = patch.wire_node(codegen_node_name(name, "round"), round_half_to_even(), &[wire])?[0];
+    wire = patch.wire_node(codegen_node_name(name, "as_i32"), cast(i32::datum_type()), &[wire])?[0];
+    let c0 =
+        patch.wire_node(codegen_node_name(name, "cast_c0"), cast(i32::datum_type()), &[c0])?[0];
+    wire_in_place(patch, &codegen_node_name(name, "zeropoint"), add(), [wire, c0])
+}
+
+/// Apply a fused activation to the i32 accumulator, in the real domain (scaled by a and b
+/// scales), and round back to the accumulator grid.
+fn wire_fused_activation(
+    patch: &mut TypedModelPatch,
+    name: &str,
+    wire: OutletId,
+    a_scale: OutletId,
+    b_scale: OutletId,
+    activation: QActivation,
+) -> TractResult<OutletId> {
+    let Some(ew) = activation.as_element_wise() else {
+        let zero = patch.add_const(codegen_node_name(name, "activation.zero"), tensor0(0i32))?;
+        return Ok(wire_with_rank_broadcast(
+            &codegen_node_name(name, "activation"),
+            patch,
+            max(),
+            &[wire, zero],
+        )?[0]);
+    };
+    let ab_scale = wire_with_rank_broadcast(
+        &codegen_node_name(name, "activation.scale"),
+        patch,
+        mul(),
+        &[a_scale, b_scale],
+    )?[0];
+    let mut wire = patch.wire_node(
+        codegen_node_name(name, "activation.as_f32"),
+        cast(f32::datum_type()),
+        &[wire],
+    )?[0];
+    wire = wire_with_rank_broadcast(
+        &codegen_node_name(name, "activation.dequant"),
+        patch,
+        mul(),
+        &[wire, ab_scale],
+    )?[0];
+    wire = patch.wire_node(codegen_node_name(name, "activation"), ew, &[wire])?[0];
+    wire = wire_with_rank_broadcast(
+        &codegen_node_name(name, "activation.quant"),
+        patch,
+        div(),
+        &[wire, ab_scale],
+    )?[0];
+    wire = patch.wire_node(
+        codegen_node_name(name, "activation.round"),
+        round_half_to_even(),
+        &[wire],
+    )?[0];
+    Ok(patch.wire_node(
+        codegen_node_name(name, "activation.as_i32"),
+        cast(i32::datum_type()),
+        &[wire],
+    )?[0])
+}
+
+/// Wire the packed form of an einsum operand.
+///
+/// Constant operands are packed right away, in the storage picked by the optimizer options.
+/// When the einsum was the only consumer of a Const node, the node is discarded by the patch so
+/// the unpacked tensor is released as soon as the patch is applied.
+fn wire_packed_operand(
+    patch: &mut TypedModelPatch,
+    model: &TypedModel,
+    node: &TypedNode,
+    slot: usize,
+    pack: MatMatMulPack,
+    operand_dt: DatumType,
+    options: &OptimizerOptions,
+) -> TractResult<OutletId> {
+    let name = codegen_node_name(&node.name, format_args!("pack_{}", ['a', 'b'][slot]));
+    let outlet = node.inputs[slot];
+    let fact = model.outlet_fact(outlet)?;
+    let compressed = compressed_matrix(model.node(outlet.node));
+    let expanded = compressed.map(|c| c.to_dense()).transpose()?.map(Arc::new);
+    if let Some(konst) = fact.konst.as_ref().or(expanded.as_ref()) {
+        let packed = if let Some(cache) = &options.packed_weight_cache {
+            cache.get_or_pack(&pack, konst, operand_dt, &options.packed_constants)?
+        } else {
+            pack.pack(&*konst.cast_to_dt(operand_dt)?, &options.packed_constants)?.into_arc_tensor()
+        };
+        if model.node(outlet.node).op_is::<Const>()
+            && model.outlet_successors(outlet).len() == 1
+            && !model.output_outlets()?.contains(&outlet)
+        {
+            patch.obliterate(outlet.node)?;
+        }
+        let wire = patch.add_const(name, packed)?;
+        if fact.konst.is_some() {
+            record_digest(patch, wire.node, konst)?;
+        }
+        return Ok(wire);
+    }
+    if operand_dt == fact.datum_type {
+        if let Some((data, mask, axis)) = fold_operand_mask(patch, model, node, outlet, &pack)? {
+            let pack = MatMatMulPack { mask_axis: Some(axis), parameter: false, ..pack };
+            return Ok(patch.wire_node(name, pack, &[data, mask])?[0]);
+        }
+    }
+    let mut wire = patch.tap_model(model, outlet)?;
+    if operand_dt != fact.datum_type {
+        wire = patch.wire_node(
+            codegen_node_name(&node.name, format_args!("cast_{}", ['a', 'b'][slot])),
+            cast(operand_dt),
+            &[wire],
+        )?[0];
+    }
+    let bounded_output = BoundedShape::new(&pack.output_shape(&fact.shape), &options.symbol_bounds);
+    Ok(patch.wire_node(name, MatMatMulPack { bounded_output, ..pack }, &[wire])?[0])
+}
+
+/// Chunking of a long contraction by `split_k`.
+struct KSplitParts {
+    a_k: usize,
+    b_k: usize,
+    k: usize,
+    parts: usize,
+}
+
+/// A long enough contraction to be split in `OptimizerOptions::k_split` parts.
+fn k_split_parts(
+    op: &EinSum,
+    model: &TypedModel,
+    node: &TypedNode,
+    k_axis: &Axis,
+    options: &OptimizerOptions,
+) -> TractResult<Option<KSplitParts>> {
+    let Some(KSplit { threshold, parts }) = options.k_split else { return Ok(None) };
+    if op.k_split_part || parts < 2 {
+        return Ok(None);
+    }
+    let input_facts = input_facts(model, node)?;
+    let (&[a_k], &[b_k]) = (&*k_axis.inputs[0], &*k_axis.inputs[1]) else { return Ok(None) };
+    let (Ok(k), Ok(b_k_dim)) =
+        (input_facts[0].shape[a_k].to_usize(), input_facts[1].shape[b_k].to_usize())
+    else {
+        return Ok(None);
+    };
+    if k != b_k_dim || k <= threshold || k < parts {
+        return Ok(None);
+    }
+    Ok(Some(KSplitParts { a_k, b_k, k, parts }))
+}
+
+/// Split a long contraction in partial products over consecutive k chunks, summed pairwise.
+fn split_k(
+    op: &EinSum,
+    model: &TypedModel,
+    node: &TypedNode,
+    KSplitParts { a_k, b_k, k, parts }: KSplitParts,
+) -> TractResult<TypedModelPatch> {
+    let mut patch = TypedModelPatch::new(format!("Split k in {parts} for {node}"));
+    let a_input = patch.tap_model(model, node.inputs[0])?;
+    let b_input = patch.tap_model(model, node.inputs[1])?;
+    let mut partials = tvec!();
+    for part in 0..parts {
+        let (start, end) = (k * part / parts, k * (part + 1) / parts);
+        let name = |role: &str| codegen_node_name(&node.name, format!("k_split_{part}.{role}"));
+        let a = patch.wire_node(name("a"), Slice::new(a_k, start, end), &[a_input])?[0];
+        let b = patch.wire_node(name("b"), Slice::new(b_k, start, end), &[b_input])?[0];
+        let einsum = EinSum { k_split_part: true, ..op.clone() };
+        let inner = inner_einsum_name(&codegen_node_name(&node.name, format!("k_split_{part}")));
+        partials.push(patch.wire_node(inner, einsum, &[a, b])?[0]);
+    }
+    let mut level = 0;
+    while partials.len() > 1 {
+        partials = partials
+            .chunks(2)
+            .enumerate()
+            .map(|(ix, pair)| match pair {
+                &[x, y] => {
+                    let name = codegen_node_name(&node.name, format!("k_sum_{level}_{ix}"));
+                    Ok(patch.wire_node(name, add(), &[x, y])?[0])
+                }
+                _ => Ok(pair[0]),
+            })
+            .collect::<TractResult<_>>()?;
+        level += 1;
+    }
+    patch.shunt_outside(model, node.id.into(), partials[0])?;
+    Ok(patch)
 }
 
-fn lir_mat_mul_unary(
+/// A `TinyBatchMatMul`, with the axes fixes laying its operands out as [batch.., m, k] and
+/// [batch.., k, n], and its output out as the einsum output.
+struct TinyBatchLayout {
+    op: TinyBatchMatMul,
+    fixes: [AxesMapping; 3],
+}
+
+/// A large batch of tiny float products, to be computed by a `TinyBatchMatMul` (see
+/// `ops::matmul::tiny_batch`).
+fn tiny_batch_layout(
     op: &EinSum,
     model: &TypedModel,
     node: &TypedNode,
     (m_axis, k_axis, n_axis): (&Axis, &Axis, &Axis),
-) -> TractResult<Option<TypedModelPatch>> {
-    let input_facts = model.node_input_facts(node.id)?;
+    options: &OptimizerOptions,
+) -> TractResult<Option<TinyBatchLayout>> {
+    let input_facts = input_facts(model, node)?;
+    let dt = op.operating_dt;
+    if !(dt == f32::datum_type() || dt == f64::datum_type())
+        || input_facts.iter().any(|f| f.datum_type != dt)
+        || !m_axis.inputs[1].is_empty()
+        || !n_axis.inputs[0].is_empty()
+    {
+        return Ok(None);
+    }
+    let (&[a_m], &[a_k], &[b_n]) = (&*m_axis.inputs[0], &*k_axis.inputs[0], &*n_axis.inputs[1])
+    else {
+        return Ok(None);
+    };
+    let mkn = [&input_facts[0].shape[a_m], &input_facts[0].shape[a_k], &input_facts[1].shape[b_n]];
+    let Ok(&[m, k, n]) =
+        mkn.iter().map(|d| d.to_usize()).collect::<TractResult<TVec<_>>>().as_deref()
+    else {
+        return Ok(None);
+    };
+    // the other axes are batch axes of both operands, with no broadcasting
+    let mut batch_axes = vec![];
+    let mut batch = 1usize;
+    for axis in op.axes.iter_all_axes().filter(|a| ![m_axis, k_axis, n_axis].contains(a)) {
+        let (&[a], &[b], &[c]) = (&*axis.inputs[0], &*axis.inputs[1], &*axis.outputs[0]) else {
+            return Ok(None);
+        };
+        let dim = &input_facts[0].shape[a];
+        let Ok(dim_value) = dim.to_usize() else { return Ok(None) };
+        if dim != &input_facts[1].shape[b] {
+            return Ok(None);
+        }
+        batch = batch.saturating_mul(dim_value);
+        batch_axes.push((c, axis.repr));
+    }
+    if !options.tiny_batch.selects((m, k, n), batch) {
+        return Ok(None);
+    }
+    let batch_reprs = batch_axes.iter().sorted().map(|(_, repr)| repr).collect::<String>();
+    let (inputs, outputs) = op.axes.to_strs();
+    let layout = |x: &Axis, y: &Axis| format!("{batch_reprs}{}{}", x.repr, y.repr);
+    let a_fix = AxesMapping::from_strs(&[&*inputs[0]], &[layout(m_axis, k_axis)])?;
+    let b_fix = AxesMapping::from_strs(&[&*inputs[1]], &[layout(k_axis, n_axis)])?;
+    let c_fix = AxesMapping::from_strs(&[layout(m_axis, n_axis)], &[&*outputs[0]])?;
+    Ok(Some(TinyBatchLayout { op: TinyBatchMatMul { m, k, n }, fixes: [a_fix, b_fix, c_fix] }))
+}
+
+/// Replace a large batch of tiny float products by a `TinyBatchMatMul`.
+fn tiny_batch_mat_mul(
+    model: &TypedModel,
+    node: &TypedNode,
+    TinyBatchLayout { op, fixes: [a_fix, b_fix, c_fix] }: TinyBatchLayout,
+) -> TractResult<TypedModelPatch> {
+    let name = &node.name;
+    let mut patch = TypedModelPatch::new(format!("Tiny batched products for {node}"));
+    let a = patch.tap_model(model, node.inputs[0])?;
+    let b = patch.tap_model(model, node.inputs[1])?;
+    let a = wire_axes_fix(&mut patch, name, "a", &a_fix, tvec!(a))?;
+    let b = wire_axes_fix(&mut patch, name, "b", &b_fix, tvec!(b))?;
+    let c = patch.wire_node(codegen_node_name(name, "tiny_batch"), op, &[a[0], b[0]])?;
+    let c = wire_axes_fix(&mut patch, name, "c", &c_fix, c)?;
+    patch.shunt_outside(model, node.id.into(), c[0])?;
+    Ok(patch)
+}
+
+/// An ExternalGemm on the unpacked operands of a large enough einsum, if a backend supporting
+/// its types is registered and its layout maps to row-major matrices.
+fn external_gemm(
+    op: &EinSum,
+    model: &TypedModel,
+    node: &TypedNode,
+    (m_axis, k_axis, n_axis): (&Axis, &Axis, &Axis),
+    options: &OptimizerOptions,
+) -> TractResult<Option<ExternalGemm>> {
+    let Some(threshold) = options.external_gemm_threshold else { return Ok(None) };
+    let Some(backend) = registered_gemm_backend() else { return Ok(None) };
+    let input_facts = input_facts(model, node)?;
+    let (a_dt, b_dt) = (input_facts[0].datum_type, input_facts[1].datum_type);
+    if !backend.supports(a_dt, b_dt, op.operating_dt) {
+        return Ok(None);
+    }
+    let &[a_m] = &*m_axis.inputs[0] else { return Ok(None) };
+    let &[a_k] = &*k_axis.inputs[0] else { return Ok(None) };
+    let &[b_k] = &*k_axis.inputs[1] else { return Ok(None) };
+    let &[b_n] = &*n_axis.inputs[1] else { return Ok(None) };
+    let &[c_m] = &*m_axis.outputs[0] else { return Ok(None) };
+    let &[c_n] = &*n_axis.outputs[0] else { return Ok(None) };
+    let c_rank = node.outputs[0].fact.rank();
+    if !m_axis.inputs[1].is_empty()
+        || !n_axis.inputs[0].is_empty()
+        || a_k + 1 != input_facts[0].rank()
+        || b_n + 1 != input_facts[1].rank()
+        || c_n + 1 != c_rank
+    {
+        return Ok(None);
+    }
+    let mkn = [&input_facts[0].shape[a_m], &input_facts[0].shape[a_k], &input_facts[1].shape[b_n]];
+    let Ok(mkn) = mkn.iter().map(|d| d.to_usize()).collect::<TractResult<Vec<usize>>>() else {
+        return Ok(None);
+    };
+    if mkn.iter().product::<usize>() < threshold {
+        return Ok(None);
+    }
+    // batch axes are in the output, at most once per input
+    let mut c_axes = tvec!((None, None); c_rank);
+    for axis in op.axes.iter_all_axes() {
+        if axis.inputs[0].len() > 1 || axis.inputs[1].len() > 1 {
+            return Ok(None);
+        }
+        if axis == k_axis {
+            continue;
+        }
+        let &[c] = &*axis.outputs[0] else { return Ok(None) };
+        c_axes[c] = (axis.inputs[0].first().copied(), axis.inputs[1].first().copied());
+    }
+    Ok(Some(ExternalGemm { backend, c_dt: op.operating_dt, a_m, b_k, c_m, c_axes }))
+}
+
+/// Operand computed as a product by a 0/1 mask varying only along the k or the mn axis: wire
+/// the unmasked operand and the boolean mask, so the pack zeroes the masked values and the
+/// product is left dead.
+fn fold_operand_mask(
+    patch: &mut TypedModelPatch,
+    model: &TypedModel,
+    node: &TypedNode,
+    outlet: OutletId,
+    pack: &MatMatMulPack,
+) -> TractResult<Option<(OutletId, OutletId, usize)>> {
+    let mul = model.node(outlet.node);
+    let fact = model.outlet_fact(outlet)?;
+    if !matches!(mul.op_as::<TypedBinOp>(), Some(bin) if bin.0.is::<Mul>())
+        || fact.datum_type.is_quantized()
+        || fact.datum_type == bool::datum_type()
+        || model.outlet_successors(outlet).len() != 1
+        || model.output_outlets()?.contains(&outlet)
+    {
+        return Ok(None);
+    }
+    for (data, mask) in [(mul.inputs[0], mul.inputs[1]), (mul.inputs[1], mul.inputs[0])] {
+        let data_fact = model.outlet_fact(data)?;
+        let mask_fact = model.outlet_fact(mask)?;
+        if data_fact.without_value() != fact.without_value() || mask_fact.rank() != fact.rank() {
+            continue;
+        }
+        let Some(axis) = [pack.k_axis, pack.mn_axis].into_iter().find(|&axis| {
+            mask_fact.shape[axis] == fact.shape[axis]
+                && mask_fact.shape.iter().enumerate().all(|(ix, d)| ix == axis || d.is_one())
+        }) else {
+            continue;
+        };
+        let mask = if let Some(konst) = &mask_fact.konst {
+            let values = konst.cast_to::<f64>()?;
+            if values.as_slice::<f64>()?.iter().any(|&v| v != 0.0 && v != 1.0) {
+                continue;
+            }
+            let name = codegen_node_name(&node.name, "mask");
+            patch.add_const(name, konst.cast_to::<bool>()?.into_owned())?
+        } else if model.node(mask.node).op_as::<Cast>().is_some()
+            && model.outlet_fact(model.node(mask.node).inputs[0])?.datum_type == bool::datum_type()
+        {
+            patch.tap_model(model, model.node(mask.node).inputs[0])?
+        } else {
+            continue;
+        };
+        return Ok(Some((patch.tap_model(model, data)?, mask, axis)));
+    }
+    Ok(None)
+}
+
+fn lir_mat_mul_unary<'a>(
+    op: &EinSum,
+    model: &TypedModel,
+    node: &TypedNode,
+    (m_axis, k_axis, n_axis): (&Axis, &Axis, &Axis),
+    options: &OptimizerOptions,
+) -> TractResult<Lowering<'a>> {
+    let input_facts = input_facts(model, node)?;
     let a_m = m_axis.inputs[0][0];
     let a_k = k_axis.inputs[0][0];
     let b_n = n_axis.inputs[1][0];
@@ -278,66 +1845,262 @@ fn lir_mat_mul_unary(
     let m = &input_facts[0].shape[a_m];
     let k = &input_facts[0].shape[a_k];
     let n = &input_facts[1].shape[b_n];
-    if m < n {
-        let expr = op
-            .axes
-            .iter_all_axes()
-            .map(|axis| {
-                let mut axis = axis.clone();
-                axis.inputs.swap(0, 1);
-                axis
-            })
-            .collect::<TVec<Axis>>();
-        return TypedModelPatch::replace_single_op(
-            model,
-            node,
-            &[node.inputs[1], node.inputs[0]],
-            EinSum { axes: AxesMapping::new(node.inputs.len(), 1, expr)?, ..op.clone() },
-        )
-        .map(Some);
+    // a parameter operand goes to A, so its packing can be shared or cached across runs.
+    // Otherwise, the largest of m and n goes to A.
+    let is_parameter = |fact: &TypedFact| fact.role == Some(TensorRole::Parameter);
+    let (a_parameter, b_parameter) = (is_parameter(input_facts[0]), is_parameter(input_facts[1]));
+    if (a_parameter == b_parameter && m < n) || (b_parameter && !a_parameter) {
+        return Ok(Lowering::SwapOperands);
     }
-    let a_dt = input_facts[0].datum_type;
-    let b_dt = input_facts[1].datum_type;
     let dt = op.operating_dt;
-    let mmm = tract_linalg::ops()
-        .mmm(a_dt, b_dt, dt, m.to_usize().ok(), k.to_usize().ok(), n.to_usize().ok())
-        .unwrap();
-    let name = &node.name;
-    let mut patch = TypedModelPatch::new("Einsum to LirMatMulUnary");
-    let a = patch.tap_model(model, node.inputs[0])?;
-    let b = patch.tap_model(model, node.inputs[1])?;
-    let pack_a = MatMatMulPack { packer: mmm.a_pack(), k_axis: a_k, mn_axis: a_m };
-    let pack_b = MatMatMulPack { packer: mmm.b_pack(), k_axis: b_k, mn_axis: b_n };
-    let pa = patch.wire_node(format!("{name}.pack_a"), pack_a, &[a])?[0];
-    let pb = patch.wire_node(format!("{name}.pack_b"), pack_b, &[b])?[0];
+    // bool operands are contracted as 0/1: the integer path uses i8 operands
+    let bool_as = if dt.is_float() { dt } else { i8::datum_type() };
+    let operand_dt = |fact: &TypedFact| {
+        if fact.datum_type == bool::datum_type() {
+            bool_as
+        } else {
+            fact.datum_type
+        }
+    };
+    let a_dt = operand_dt(input_facts[0]);
+    let b_dt = operand_dt(input_facts[1]);
+    let Some(mmm) = tract_linalg::ops().mmm(
+        a_dt,
+        b_dt,
+        dt,
+        m.to_usize().ok(),
+        k.to_usize().ok(),
+        n.to_usize().ok(),
+    ) else {
+        return Ok(Lowering::Declined(DeclineReason::UnsupportedDatumType));
+    };
+    let f32_dt = f32::datum_type();
+    let mmm = match &options.mmm_f32_kernel {
+        Some(name) if (a_dt, b_dt, dt) == (f32_dt, f32_dt, f32_dt) => tract_linalg::ops()
+            .mmm_f32_impls()
+            .iter()
+            .find(|mmm| mmm.kernel_name() == name)
+            .with_context(|| format!("No f32 kernel named {name}"))?
+            .clone(),
+        _ => mmm,
+    };
+    // compressed constants are expanded by the kernel panel by panel, unless their packed form
+    // is smaller (see `ops::matmul::compressed`)
+    let compressed = [(0, a_k, a_m, mmm.a_pack()), (1, b_k, b_n, mmm.b_pack())].map(
+        |(slot, k_axis, mn_axis, packer)| {
+            let c = compressed_matrix(model.node(node.inputs[slot].node))?;
+            let (Ok(k), Ok(mn)) = (k.to_usize(), c.shape().get(mn_axis)?.to_usize()) else {
+                return None;
+            };
+            let spec = c.input_spec(k_axis, mn_axis).filter(|_| {
+                mmm.internal_type() == f32::datum_type()
+                    && [a_dt, b_dt][slot] == f32::datum_type()
+                    && !c.expand_once(&packer, k, mn)
+            });
+            Some((c, spec))
+        },
+    );
+    let compressed_on_demand = compressed.iter().flatten().any(|c| c.1.is_some());
+    // a single column packed for a matrix-vector kernel is the column itself: a non-constant B
+    // contiguous along k is fed to the kernel as is
+    let b_unpacked = n.is_one()
+        && mmm.nr() == 1
+        && input_facts[1].konst.is_none()
+        && compressed[1].is_none()
+        && input_facts[1].shape.iter().skip(b_k + 1).all(|d| d.is_one())
+        && mmm.b_pack().end_padding_record() == 0
+        && mmm.b_pack().alignment() <= b_dt.alignment();
+    // operands computed at runtime, too large to be packed whole, are packed by blocks in the op
+    let macro_tiles = if !b_unpacked
+        && !a_parameter
+        && !b_parameter
+        && input_facts.iter().take(2).all(|f| f.konst.is_none())
+        && compressed.iter().all(|c| c.is_none())
+        && (a_dt, b_dt) == (input_facts[0].datum_type, input_facts[1].datum_type)
+    {
+        if let (Ok(m), Ok(k), Ok(n)) = (m.to_usize(), k.to_usize(), n.to_usize()) {
+            options
+                .macro_tiling
+                .block_sizes(&*mmm, (a_dt.size_of(), b_dt.size_of()), (m, k, n))
+                .map(|(m, n)| MacroTiles {
+                    m,
+                    n,
+                    a_axes: (a_k, a_m),
+                    b_axes: (b_k, b_n),
+                    item_sizes: (a_dt.size_of(), b_dt.size_of()),
+                })
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+    // constant f32 weights are packed by each plan state, in the precision of its policy
+    let weight_variants = input_facts[0]
+        .konst
+        .clone()
+        .filter(|_| {
+            options.weight_variants
+                && dt == f32::datum_type()
+                && input_facts[0].datum_type == f32::datum_type()
+                && input_facts[0].rank() == 2
+                && compressed[0].is_none()
+                && mmm.internal_type() == f32::datum_type()
+        })
+        .map(|master| WeightVariants { master, k_axis: a_k, mn_axis: a_m });
+    // operands packed by the op, or fed as is, are not padded
+    let k_padded = options
+        .k_padding
+        .filter(|_| {
+            !b_unpacked
+                && macro_tiles.is_none()
+                && !compressed_on_demand
+                && weight_variants.is_none()
+        })
+        .and_then(|padding| padding.padded_k(k, &options.symbol_bounds));
+    // a single use, non-constant float B, neither tiled nor padded, may be read strided by the
+    // kernel rather than packed by a pack node (see `ops::matmul::strided`)
+    let b_strided = !b_unpacked
+        && macro_tiles.is_none()
+        && k_padded.is_none()
+        && !b_parameter
+        && model.outlet_successors(node.inputs[1]).len() == 1
+        && dt.is_float()
+        && input_facts[1].konst.is_none()
+        && compressed[1].is_none()
+        && b_dt == input_facts[1].datum_type
+        && k.to_usize().is_ok()
+        && options.b_packing.strided(&*mmm, m, n);
+    let pack_a = MatMatMulPack {
+        packer: mmm.a_pack(),
+        k_axis: a_k,
+        mn_axis: a_m,
+        parameter: a_parameter,
+        mask_axis: None,
+        bounded_output: None,
+        input_slice: None,
+        k_padded,
+    };
+    let pack_b = MatMatMulPack {
+        packer: mmm.b_pack(),
+        k_axis: b_k,
+        mn_axis: b_n,
+        parameter: b_parameter,
+        mask_axis: None,
+        bounded_output: None,
+        input_slice: None,
+        k_padded,
+    };
+    // the operand of a compressed constant expanded by the kernel is its compressed data
+    let mut storages = [None, None];
+    let mut compressed_data = [None, None];
+    for (slot, c) in compressed.into_iter().enumerate() {
+        let Some((c, Some(spec))) = c else { continue };
+        let k = k.to_usize()?;
+        storages[slot] = Some(unsafe {
+            if slot == 0 {
+                mmm.a_virtual_input(spec, k)
+            } else {
+                mmm.b_virtual_input(spec, k)
+            }
+        });
+        compressed_data[slot] = Some(c.data());
+    }
+    let [a_data, b_data] = compressed_data;
+    let a_operand = if let Some(data) = a_data {
+        OperandWire::Compressed(data)
+    } else if macro_tiles.is_some() || weight_variants.is_some() {
+        OperandWire::Input(a_dt)
+    } else {
+        OperandWire::Packed(Box::new(pack_a), a_dt)
+    };
+    let b_operand = if let Some(data) = b_data {
+        OperandWire::Compressed(data)
+    } else if b_unpacked || b_strided || macro_tiles.is_some() {
+        OperandWire::Input(b_dt)
+    } else {
+        OperandWire::Packed(Box::new(pack_b), b_dt)
+    };
 
+    // each prefix axis of the output is paired with its own axis in the (packed) inputs, whatever
+    // their relative orders
     let mut c_to_a_axis_mapping = tvec!();
     let mut c_to_b_axis_mapping = tvec!();
     for axis in op.axes.iter_all_axes().filter(|&axis| ![m_axis, k_axis, n_axis].contains(&axis)) {
         if let (&[c], &[a]) = (&*axis.outputs[0], &*axis.inputs[0]) {
             if input_facts[0].shape[a] != 1.to_dim() {
-                let a = a - (a > a_m) as usize - (a > a_k) as usize;
+                let a = if macro_tiles.is_some() {
+                    a
+                } else {
+                    a - (a > a_m) as usize - (a > a_k) as usize
+                };
                 c_to_a_axis_mapping.push((c, a));
             }
         }
         if let (&[c], &[b]) = (&*axis.outputs[0], &*axis.inputs[1]) {
             if input_facts[1].shape[b] != 1.to_dim() {
-                let b = b - (b > b_n) as usize - (b > b_k) as usize;
+                let b = if b_unpacked || b_strided || macro_tiles.is_some() {
+                    b
+                } else {
+                    b - (b > b_n) as usize - (b > b_k) as usize
+                };
                 c_to_b_axis_mapping.push((c, b));
             }
         }
     }
 
-    let c_fact = op.output_facts(&input_facts)?.remove(0);
-    let name = &node.name;
+    let mut c_fact = op.output_facts(&input_facts)?.remove(0);
+    // the output is stored along the m and n axes of the einsum as it is now, operands swapped
+    // or not
+    ensure!(
+        c_fact.shape[c_m] == *m && c_fact.shape[c_n] == *n,
+        "Output axes {c_m} and {c_n} of {node} are not its m={m} and n={n} axes ({})",
+        op.axes
+    );
+    // a float cast consuming the einsum output is folded in the Store when the kernel can
+    // convert the accumulator while storing
+    let mut replaced: OutletId = node.id.into();
+    if let &[succ] = &*node.outputs[0].successors {
+        let succ = model.node(succ.node);
+        if let Some(cast) = succ.op_as::<Cast>() {
+            if c_fact.datum_type.is_float()
+                && cast.to.is_float()
+                && mmm.can_store(cast.to)
+                && !model.output_outlets()?.contains(&node.id.into())
+            {
+                c_fact = cast.to.fact(c_fact.shape.clone());
+                replaced = succ.id.into();
+            }
+        }
+    }
+    let [a_storage, b_storage] = storages;
     let geo = AddMatMulGeometry {
-        k: k.to_dim(),
-        a_storage: None,
-        b_storage: None,
+        k: k_padded.map(|k| k.to_dim()).unwrap_or_else(|| k.to_dim()),
+        a_storage,
+        b_storage: if b_storage.is_some() {
+            b_storage
+        } else if b_strided {
+            let spec = StridedInputSpec { k_axis: b_k, mn_axis: b_n };
+            Some(unsafe { mmm.b_virtual_input(Box::new(spec), k.to_usize()?) })
+        } else {
+            None
+        },
         mmm: mmm.clone(),
         c_to_a_axis_mapping: MapOutputAxisToInput(c_to_a_axis_mapping),
         c_to_b_axis_mapping: MapOutputAxisToInput(c_to_b_axis_mapping),
     };
+    // a product crossing independent batch axes of both operands reads each packed matrix of
+    // the larger one once (see `ops::matmul::prefix_loops`)
+    let prefix_loops = if let (Some(c_shape), Ok(m), Ok(k), Ok(n), None) =
+        (c_fact.shape.as_concrete(), m.to_usize(), geo.k.to_usize(), n.to_usize(), &macro_tiles)
+    {
+        let a_bytes = mmm.a_pack().len(k, m) * a_dt.size_of();
+        let b_bytes = mmm.b_pack().len(k, n) * b_dt.size_of();
+        let maps = (&geo.c_to_a_axis_mapping, &geo.c_to_b_axis_mapping);
+        options.prefix_loops.plan(c_shape, (c_m, c_n), maps, (a_bytes, b_bytes))
+    } else {
+        None
+    };
     let output = unsafe { mmm.c_view(c_m, c_n) };
     let lir = LirMatMulUnary::new(
         mmm,
@@ -347,7 +2110,67 @@ fn lir_mat_mul_unary(
         vec![ProtoFusedSpec::AddMatMul(geo, 0, 1), ProtoFusedSpec::Store(output)],
     )
     .context("Creating LirMatMulUnary")?;
-    let output = patch.wire_node(name, lir, &[pa, pb])?[0];
-    patch.shunt_outside(model, node.id.into(), output)?;
-    Ok(Some(patch))
+    let bounded_output = BoundedShape::new(&lir.c_fact.shape, &options.symbol_bounds);
+    let lir = LirMatMulUnary {
+        bounded_output,
+        operands_swapped: op.operands_swapped,
+        macro_tiles,
+        weight_variants,
+        prefix_loops,
+        ..lir
+    };
+    let operands = [a_operand, b_operand];
+    Ok(Lowering::Kernel(Box::new(KernelChoice { lir, operands, replaced })))
+}
+
+/// How an operand of a `LirMatMulUnary` is wired.
+enum OperandWire {
+    /// The einsum input, cast to this type if it is not its own.
+    Input(DatumType),
+    /// The data of a compressed constant, expanded by the kernel.
+    Compressed(Arc<Tensor>),
+    /// Packed in this type (see `wire_packed_operand`).
+    Packed(Box<MatMatMulPack>, DatumType),
+}
+
+/// A `LirMatMulUnary` lowering an einsum, with how its operands are wired.
+struct KernelChoice {
+    lir: LirMatMulUnary,
+    operands: [OperandWire; 2],
+    /// The einsum output, or the cast consuming it folded in the store.
+    replaced: OutletId,
+}
+
+fn wire_lir_mat_mul_unary(
+    model: &TypedModel,
+    node: &TypedNode,
+    KernelChoice { lir, operands, replaced }: KernelChoice,
+    options: &OptimizerOptions,
+) -> TractResult<TypedModelPatch> {
+    let mut patch = TypedModelPatch::new("Einsum to LirMatMulUnary");
+    let mut wires = tvec!();
+    for (slot, operand) in operands.into_iter().enumerate() {
+        let label = ['a', 'b'][slot];
+        let wire = match operand {
+            OperandWire::Input(dt) => {
+                let mut wire = patch.tap_model(model, node.inputs[slot])?;
+                if dt != model.outlet_fact(node.inputs[slot])?.datum_type {
+                    let name = codegen_node_name(&node.name, format_args!("cast_{label}"));
+                    wire = patch.wire_node(name, cast(dt), &[wire])?[0];
+                }
+                wire
+            }
+            OperandWire::Compressed(data) => {
+                let name = codegen_node_name(&node.name, format_args!("compressed_{label}"));
+                patch.add_const(name, data)?
+            }
+            OperandWire::Packed(pack, dt) => {
+                wire_packed_operand(&mut patch, model, node, slot, *pack, dt, options)?
+            }
+        };
+        wires.push(wire);
+    }
+    let output = patch.wire_node(&node.name, lir, &wires)?[0];
+    patch.shunt_outside(model, replaced, output)?;
+    Ok(patch)
 }
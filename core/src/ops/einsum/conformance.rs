@@ -0,0 +1,301 @@
+//! numpy.einsum conformance.
+//!
+//! Each case is an expression and input shapes, checked against a reference implementation
+//! written independently of the production eval: it parses the expression itself and sums
+//! products over the full index space with nested loops. The case runs through `EinSum::eval`,
+//! the optimized model and, for two operand expressions, the quantized einsum with identity
+//! scales and zero zero points.
+//!
+//! Supported semantics, as numpy:
+//! * without `->`, the output has the labels appearing once, in alphabetical order,
+//! * a label repeated in an input takes the diagonal,
+//! * a label missing from the output is summed over, even if it appears in a single input,
+//! * dimensions of 1 broadcast against the other dimensions of their label,
+//! * scalar operands have an empty label list.
+//!
+//! As an extension, an output label missing from the inputs is an axis of 1 (numpy rejects it).
+//!
+//! Rejected expressions, with an error instead of numbers (see `REJECTED`):
+//! * ellipsis (`...`),
+//! * a label repeated in the output,
+//! * input ranks not matching their label count,
+//! * dimensions of a label disagreeing, neither being 1.
+use crate::internal::*;
+use crate::ops::einsum::EinSum;
+use tract_ndarray::{ArrayD, Dimension};
+
+/// Expressions and input shapes tract agrees with numpy on.
+const SUPPORTED: &[(&str, &[&[usize]])] = &[
+    // unary: permutations, reductions, diagonals
+    ("i->i", &[&[4]]),
+    ("i->", &[&[4]]),
+    ("i", &[&[4]]),
+    ("ij->ji", &[&[2, 3]]),
+    ("ij->i", &[&[2, 3]]),
+    ("ij->j", &[&[2, 3]]),
+    ("ij->", &[&[2, 3]]),
+    ("ij", &[&[2, 3]]),
+    ("ji", &[&[2, 3]]),
+    ("ijk->kji", &[&[2, 3, 4]]),
+    ("ijk->ik", &[&[2, 3, 4]]),
+    ("ijk->jki", &[&[2, 3, 4]]),
+    ("kji", &[&[2, 3, 4]]),
+    ("ii->i", &[&[3, 3]]),
+    ("ii->", &[&[3, 3]]),
+    ("ii", &[&[3, 3]]),
+    ("iij->ij", &[&[3, 3, 2]]),
+    ("iji->j", &[&[3, 2, 3]]),
+    ("iii->i", &[&[2, 2, 2]]),
+    // binary: products and contractions
+    ("ij,jk->ik", &[&[2, 3], &[3, 4]]),
+    ("ij,jk", &[&[2, 3], &[3, 4]]),
+    ("ij,jk->ki", &[&[2, 3], &[3, 4]]),
+    ("ij,kj->ik", &[&[2, 3], &[4, 3]]),
+    ("ji,jk->ik", &[&[3, 2], &[3, 4]]),
+    ("ij,jk->", &[&[2, 3], &[3, 4]]),
+    ("ij,jk->ijk", &[&[2, 3], &[3, 4]]),
+    ("i,i->", &[&[5], &[5]]),
+    ("i,i", &[&[5], &[5]]),
+    ("i,i->i", &[&[5], &[5]]),
+    ("i,j->ij", &[&[2], &[3]]),
+    ("i,j", &[&[2], &[3]]),
+    ("j,i", &[&[2], &[3]]),
+    ("ij,j->i", &[&[2, 3], &[3]]),
+    ("ij,i->j", &[&[2, 3], &[2]]),
+    ("ij,ij->ij", &[&[2, 3], &[2, 3]]),
+    ("ij,ij->", &[&[2, 3], &[2, 3]]),
+    ("ij,ji->", &[&[2, 3], &[3, 2]]),
+    ("ij,ji->ij", &[&[2, 3], &[3, 2]]),
+    ("bij,bjk->bik", &[&[2, 2, 3], &[2, 3, 4]]),
+    ("bij,jk->bik", &[&[2, 2, 3], &[3, 4]]),
+    ("bij,bjk->bki", &[&[2, 2, 3], &[2, 3, 4]]),
+    ("ijk,ikl->jl", &[&[2, 3, 4], &[2, 4, 2]]),
+    ("Ab,bC->AC", &[&[2, 3], &[3, 4]]),
+    ("bA,Cb", &[&[3, 2], &[4, 3]]),
+    ("ii,ij->j", &[&[3, 3], &[3, 2]]),
+    ("ij,jj->i", &[&[2, 3], &[3, 3]]),
+    // summed-only axes: labels of a single input, absent from the output
+    ("ij,k->i", &[&[2, 3], &[4]]),
+    ("i,jk->i", &[&[2], &[3, 4]]),
+    ("ijk,jl->il", &[&[2, 3, 4], &[3, 2]]),
+    ("ik,kj->", &[&[2, 3], &[3, 4]]),
+    // broadcasting of dimensions of 1
+    ("ij,ij->ij", &[&[1, 3], &[2, 3]]),
+    ("ij,ij->ij", &[&[2, 1], &[1, 3]]),
+    ("bij,bjk->bik", &[&[1, 2, 3], &[3, 3, 4]]),
+    ("ij,j->i", &[&[2, 1], &[3]]),
+    ("ij,jk->ik", &[&[2, 1], &[3, 4]]),
+    // scalar operands
+    (",ij->ij", &[&[], &[2, 3]]),
+    ("i,->i", &[&[4], &[]]),
+    (",->", &[&[], &[]]),
+    (",i->", &[&[], &[4]]),
+    ("", &[&[]]),
+    // more than two operands
+    ("ij,jk,kl->il", &[&[2, 3], &[3, 4], &[4, 2]]),
+    ("i,i,i->", &[&[3], &[3], &[3]]),
+    ("i,j,k->ijk", &[&[2], &[3], &[2]]),
+    ("i,j,k", &[&[2], &[3], &[2]]),
+    // extension: output labels missing from the inputs
+    ("ij->ijk", &[&[2, 3]]),
+    ("i,i->ki", &[&[3], &[3]]),
+];
+
+/// Expressions and input shapes tract rejects, with the expected error message fragment.
+const REJECTED: &[(&str, &[&[usize]], &str)] = &[
+    ("...ij,...jk->...ik", &[&[2, 2, 3], &[2, 3, 4]], "Ellipsis"),
+    ("i->ii", &[&[3]], "appears more than once in output"),
+    ("ij,jk->ik", &[&[2, 3, 1], &[3, 4]], "has rank"),
+    ("ij,jk->ik", &[&[2, 3], &[4, 2]], "inconsistent dimensions"),
+    ("ii->i", &[&[2, 3]], "inconsistent dimensions"),
+];
+
+/// An expression, parsed independently of `AxesMapping`.
+struct Reference {
+    inputs: Vec<Vec<char>>,
+    output: Vec<char>,
+}
+
+impl Reference {
+    fn parse(expr: &str) -> Reference {
+        let (inputs, output) =
+            expr.split_once("->").map(|(i, o)| (i, Some(o))).unwrap_or((expr, None));
+        let inputs: Vec<Vec<char>> = inputs.split(',').map(|i| i.chars().collect()).collect();
+        let output = if let Some(output) = output {
+            output.chars().collect()
+        } else {
+            let mut once: Vec<char> = inputs
+                .iter()
+                .flatten()
+                .copied()
+                .filter(|l| inputs.iter().flatten().filter(|x| *x == l).count() == 1)
+                .collect();
+            once.sort();
+            once
+        };
+        Reference { inputs, output }
+    }
+
+    /// Explicit expression, with the quantization parameter operands when `q` is set.
+    fn explicit(&self, q: bool) -> String {
+        let mut inputs: Vec<String> = self.inputs.iter().map(|i| i.iter().collect()).collect();
+        if q {
+            inputs.extend(std::iter::repeat(String::new()).take(7));
+        }
+        format!("{}->{}", inputs.join(","), self.output.iter().collect::<String>())
+    }
+
+    fn eval(&self, inputs: &[ArrayD<f32>]) -> ArrayD<f32> {
+        let mut labels: Vec<char> =
+            self.inputs.iter().flatten().chain(&self.output).copied().collect();
+        labels.sort();
+        labels.dedup();
+        let dim = |label: char| -> usize {
+            let mut dim = 1;
+            for (input, labels) in inputs.iter().zip(&self.inputs) {
+                for (ix, _) in labels.iter().enumerate().filter(|(_, l)| **l == label) {
+                    dim = dim.max(input.shape()[ix]);
+                }
+            }
+            dim
+        };
+        let dims: Vec<usize> = labels.iter().map(|l| dim(*l)).collect();
+        let output_shape: Vec<usize> =
+            self.output.iter().map(|l| dims[labels.iter().position(|x| x == l).unwrap()]).collect();
+        let mut output = ArrayD::<f32>::zeros(output_shape);
+        let mut coords = vec![0usize; labels.len()];
+        let coord = |coords: &[usize], label: char, dim: usize| -> usize {
+            if dim == 1 {
+                0
+            } else {
+                coords[labels.iter().position(|x| *x == label).unwrap()]
+            }
+        };
+        loop {
+            let mut product = 1f32;
+            for (input, input_labels) in inputs.iter().zip(&self.inputs) {
+                let index: Vec<usize> = input_labels
+                    .iter()
+                    .zip(input.shape())
+                    .map(|(l, dim)| coord(&coords, *l, *dim))
+                    .collect();
+                product *= input[&*index];
+            }
+            let index: Vec<usize> = self
+                .output
+                .iter()
+                .zip(output.shape().to_vec())
+                .map(|(l, dim)| coord(&coords, *l, dim))
+                .collect();
+            output[&*index] += product;
+            let Some(axis) = (0..labels.len()).rev().find(|ix| coords[*ix] + 1 < dims[*ix]) else {
+                break;
+            };
+            coords[axis] += 1;
+            coords[axis + 1..].iter_mut().for_each(|c| *c = 0);
+        }
+        output
+    }
+}
+
+fn input(shape: &[usize], seed: usize) -> ArrayD<f32> {
+    ArrayD::from_shape_fn(shape, |ix| {
+        let ix = ix.slice().iter().fold(seed, |acc, x| acc * 7 + x);
+        (ix % 7) as f32 - 3.
+    })
+}
+
+fn model(expr: &str, shapes: &[&[usize]]) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let inputs = shapes
+        .iter()
+        .enumerate()
+        .map(|(ix, shape)| model.add_source(format!("input.{ix}"), f32::fact(*shape)))
+        .collect::<TractResult<TVec<_>>>()?;
+    let op = EinSum::new(expr.parse()?, f32::datum_type());
+    let output = model.wire_node("einsum", op, &inputs)?;
+    model.set_output_outlets(&output)?;
+    Ok(model)
+}
+
+/// The quantized einsum with i8 operands, identity scales and zero zero points, to i32.
+fn q_model(expr: &str, shapes: &[&[usize]]) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let mut inputs = tvec!(
+        model.add_source("a", i8::fact(shapes[0]))?,
+        model.add_source("b", i8::fact(shapes[1]))?,
+        model.add_const("bias", rctensor0(0i32))?,
+    );
+    for (name, value) in [("a0", 0i8), ("b0", 0i8), ("c0", 0i8)] {
+        let zp = model.add_const(name, rctensor0(value))?;
+        let scale = model.add_const(format!("{name}.scale"), rctensor0(1f32))?;
+        inputs.push(zp);
+        inputs.push(scale);
+    }
+    let op = EinSum::newq(expr.parse()?, i32::datum_type(), i32::datum_type());
+    let output = model.wire_node("einsum", op, &inputs)?;
+    model.set_output_outlets(&output)?;
+    Ok(model)
+}
+
+fn run(model: TypedModel, inputs: &[ArrayD<f32>], dt: DatumType) -> TractResult<ArrayD<f32>> {
+    let inputs = inputs
+        .iter()
+        .map(|i| Ok(i.clone().into_tensor().cast_to_dt(dt)?.into_owned().into_tvalue()))
+        .collect::<TractResult<TVec<_>>>()?;
+    let output = model.into_runnable()?.run(inputs)?.remove(0);
+    output.cast_to::<f32>()?.into_owned().into_array::<f32>()
+}
+
+fn check(expr: &str, shapes: &[&[usize]]) -> TractResult<()> {
+    let reference = Reference::parse(expr);
+    let inputs: Vec<ArrayD<f32>> =
+        shapes.iter().enumerate().map(|(ix, shape)| input(shape, ix)).collect();
+    let expected = reference.eval(&inputs);
+
+    let op = EinSum::new(expr.parse()?, f32::datum_type());
+    let tensors = inputs.iter().map(|i| i.clone().into_tvalue()).collect();
+    let found = op.eval(tensors)?.remove(0).into_tensor().into_array::<f32>()?;
+    ensure!(found == expected, "eval: expected {expected}, found {found}");
+
+    let found = run(model(expr, shapes)?.into_optimized()?, &inputs, f32::datum_type())?;
+    ensure!(found == expected, "optimized: expected {expected}, found {found}");
+
+    if shapes.len() == 2 {
+        let q_expr = reference.explicit(true);
+        for optimized in [false, true] {
+            let mut model = q_model(&q_expr, shapes)?;
+            if optimized {
+                model = model.into_optimized()?;
+            }
+            let found = run(model, &inputs, i8::datum_type())?;
+            ensure!(
+                found == expected,
+                "quantized (optimized: {optimized}): expected {expected}, found {found}"
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn supported() -> TractResult<()> {
+    for (expr, shapes) in SUPPORTED {
+        check(expr, shapes).with_context(|| format!("{expr} {shapes:?}"))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn rejected() -> TractResult<()> {
+    for (expr, shapes, message) in REJECTED {
+        let Err(e) = model(expr, shapes) else {
+            bail!("{expr} {shapes:?} should be rejected");
+        };
+        ensure!(
+            format!("{e:?}").contains(message),
+            "{expr} {shapes:?} rejected with {e:?}, expected {message:?}"
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,347 @@
+//! Einsum declutter rules.
+
+use super::test_util::wire_q_params;
+use super::*;
+
+#[test]
+fn dedup_relabelled_einsums() -> TractResult<()> {
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", f32::fact([4, 8]))?;
+    let b = model.add_const("b", Tensor::zero::<f32>(&[8, 5])?)?;
+    let op =
+        |expr: &str| -> TractResult<EinSum> { Ok(EinSum::new(expr.parse()?, f32::datum_type())) };
+    let qk = model.wire_node("qk", op("mk,kn->mn")?, &[a, b])?;
+    let qk_masked = model.wire_node("qk_masked", op("ij,jk->ik")?, &[a, b])?;
+    let sum = model.wire_node("sum", ops::math::add(), &[qk[0], qk_masked[0]])?;
+    model.set_output_outlets(&sum)?;
+    assert!(model.node(qk[0].node).same_as(model.node(qk_masked[0].node)));
+    let optimized = model.into_optimized()?;
+    let matmuls = optimized
+        .nodes
+        .iter()
+        .filter(|n| n.op_is::<crate::ops::matmul::lir_unary::LirMatMulUnary>())
+        .count();
+    assert_eq!(matmuls, 1);
+    Ok(())
+}
+
+fn check_single_input(expr: &str, shape: &[usize], expected: &[&str]) -> TractResult<()> {
+    let mut model = TypedModel::default();
+    let x = model.add_source("x", f32::fact(shape))?;
+    let c = model.wire_node("einsum", EinSum::new(expr.parse()?, f32::datum_type()), &[x])?;
+    model.set_output_outlets(&c)?;
+    let decluttered = model.clone().into_decluttered()?;
+    let ops = decluttered.nodes.iter().skip(1).map(|n| n.op.name()).collect_vec();
+    assert_eq!(ops, expected);
+    let len = shape.iter().product::<usize>();
+    let input = Tensor::from_shape(shape, &(0..len).map(|i| i as f32).collect_vec())?;
+    let reference = model.into_runnable()?.run(tvec!(input.clone().into_tvalue()))?;
+    let found = decluttered.into_runnable()?.run(tvec!(input.into_tvalue()))?;
+    found[0].close_enough(&reference[0], Approximation::Exact)
+}
+
+#[test]
+fn single_input_transpose() -> TractResult<()> {
+    check_single_input("ij->ji", &[2, 3], &["MoveAxis"])
+}
+
+#[test]
+fn single_input_identity() -> TractResult<()> {
+    check_single_input("ij->ij", &[2, 3], &[])
+}
+
+#[test]
+fn single_input_permutation() -> TractResult<()> {
+    check_single_input("ijk->kij", &[2, 3, 4], &["MoveAxis"])
+}
+
+#[test]
+fn single_input_sum() -> TractResult<()> {
+    check_single_input("ijk->ik", &[2, 3, 4], &["Reduce<Sum>", "RmAxis"])
+}
+
+/// Declutter `x` against a constant `id` operand in `slot`, checking the einsum is gone iff
+/// `vanishes`.
+fn check_identity_operand(
+    expr: &str,
+    x_shape: &[usize],
+    id: Tensor,
+    slot: usize,
+    vanishes: bool,
+) -> TractResult<()> {
+    let mut model = TypedModel::default();
+    let x = model.add_source("x", f32::fact(x_shape))?;
+    let id = model.add_const("id", id)?;
+    let inputs = if slot == 0 { [id, x] } else { [x, id] };
+    let op = EinSum::new(expr.parse()?, f32::datum_type());
+    let c = model.wire_node("einsum", op, &inputs)?;
+    model.set_output_outlets(&c)?;
+    let decluttered = model.clone().into_decluttered()?;
+    assert_eq!(decluttered.nodes.iter().any(|n| n.op_is::<EinSum>()), !vanishes);
+    let len = x_shape.iter().product::<usize>();
+    let input = Tensor::from_shape(x_shape, &(0..len).map(|i| i as f32).collect_vec())?;
+    let reference = model.into_runnable()?.run(tvec!(input.clone().into_tvalue()))?;
+    let found = decluttered.into_runnable()?.run(tvec!(input.into_tvalue()))?;
+    found[0].close_enough(&reference[0], Approximation::Exact)
+}
+
+fn eye(d: usize) -> Tensor {
+    tract_ndarray::Array2::<f32>::eye(d).into_tensor()
+}
+
+#[test]
+fn left_identity_operand() -> TractResult<()> {
+    check_identity_operand("ij,jk->ik", &[3, 5], eye(3), 0, true)
+}
+
+#[test]
+fn right_identity_operand() -> TractResult<()> {
+    check_identity_operand("ij,jk->ik", &[5, 3], eye(3), 1, true)?;
+    // reordering the output leaves the permutation
+    check_identity_operand("ij,jk->ki", &[5, 3], eye(3), 1, true)
+}
+
+#[test]
+fn integer_identity_operand() -> TractResult<()> {
+    let id = tract_ndarray::Array2::<i32>::eye(3).into_tensor();
+    check_identity_operand("ij,jk->ik", &[5, 3], id, 1, true)
+}
+
+#[test]
+fn batched_identity_operand() -> TractResult<()> {
+    let id = eye(4).broadcast_into_rank(3)?;
+    check_identity_operand("bij,bjk->bik", &[2, 3, 4], id.clone(), 1, true)?;
+    let repeated = Tensor::stack_tensors(0, &[id.clone(), id])?;
+    check_identity_operand("bij,bjk->bik", &[2, 3, 4], repeated, 1, true)
+}
+
+#[test]
+fn near_identity_operand_is_kept() -> TractResult<()> {
+    let mut id = eye(3);
+    id.as_slice_mut::<f32>()?[1] = 1e-8;
+    check_identity_operand("ij,jk->ik", &[5, 3], id, 1, false)
+}
+
+fn dump(model: &TypedModel) -> String {
+    model
+        .nodes()
+        .iter()
+        .map(|n| format!("{n} {:?} {:?}", n.op.info().unwrap(), n.outputs[0].fact))
+        .join("\n")
+}
+
+#[test]
+fn alpha_renamed_einsums_declutter_identically() -> TractResult<()> {
+    let model = |expr: &str| -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", f32::fact([2, 3, 4]))?;
+        let b = model.add_source("b", f32::fact([2, 4, 5]))?;
+        let op = EinSum::new(expr.parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", op, &[a, b])?;
+        model.set_output_outlets(&c)?;
+        model.into_decluttered()
+    };
+    let reference = model("bmk,bkn->bmn")?;
+    let renamed = model("zxy,zyw->zxw")?;
+    assert_eq!(dump(&reference), dump(&renamed));
+    let op = reference.node_by_name("einsum")?.op_as::<EinSum>().unwrap();
+    assert_eq!(op.axes, op.axes.canonical());
+    assert_eq!(&*op.axes.to_string(), "abd,adc->abc");
+    let hash = |model: &TypedModel| {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        model.node_by_name("einsum").unwrap().op_as::<EinSum>().unwrap().hash(&mut hasher);
+        std::hash::Hasher::finish(&hasher)
+    };
+    assert_eq!(hash(&reference), hash(&renamed));
+    Ok(())
+}
+
+/// a.ij (optionally negated) times b.jk (optionally multiplied or divided by a scalar).
+fn scaled_operands_model(
+    neg_a: bool,
+    b_scale: Option<(ops::binary::TypedBinOp, f32)>,
+    const_b: bool,
+) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let mut a = model.add_source("a", f32::fact([3, 4]))?;
+    if neg_a {
+        a = model.wire_node("neg", ops::math::neg(), &[a])?[0];
+    }
+    let mut b = if const_b {
+        model.add_const("b", scaled_operands_b())?
+    } else {
+        model.add_source("b", f32::fact([4, 5]))?
+    };
+    if let Some((op, scalar)) = b_scale {
+        let scalar = model.add_const("scalar", tensor0(scalar))?;
+        b = ops::binary::wire_with_rank_broadcast("scale", &mut model, op, &[b, scalar])?[0];
+    }
+    let op = EinSum::new("ij,jk->ik".parse()?, f32::datum_type());
+    let c = model.wire_node("einsum", op, &[a, b])?;
+    model.set_output_outlets(&c)?;
+    Ok(model)
+}
+
+fn scaled_operands_b() -> Tensor {
+    Tensor::from_shape(&[4, 5], &(0..20).map(|i| i as f32 / 4. - 2.).collect_vec()).unwrap()
+}
+
+fn check_scaled_operands(model: TypedModel, leftover: usize) -> TractResult<()> {
+    let a = Tensor::from_shape(&[3, 4], &(0..12).map(|i| i as f32 - 5.).collect_vec())?;
+    let mut inputs = tvec!(a.into_tvalue());
+    if model.input_outlets()?.len() == 2 {
+        inputs.push(scaled_operands_b().into_tvalue());
+    }
+    let expected = model.clone().into_runnable()?.run(inputs.clone())?.remove(0);
+    let decluttered = model.into_decluttered()?;
+    let einsum = decluttered.nodes.iter().find(|n| n.op_is::<EinSum>()).unwrap();
+    for input in &einsum.inputs {
+        let prec = decluttered.node(input.node);
+        ensure!(prec.op_is::<ops::konst::Const>() || prec.op_is::<ops::source::TypedSource>());
+    }
+    let scalings = decluttered.nodes.iter().filter(|n| n.op_is::<ops::binary::TypedBinOp>());
+    assert_eq!(scalings.count(), leftover);
+    let found = decluttered.clone().into_runnable()?.run(inputs.clone())?.remove(0);
+    found.close_enough(&expected, Approximation::Exact)?;
+    let found = decluttered.into_optimized()?.into_runnable()?.run(inputs)?.remove(0);
+    found.close_enough(&expected, Approximation::Close)
+}
+
+#[test]
+fn negated_a_folds_in_constant_b() -> TractResult<()> {
+    check_scaled_operands(scaled_operands_model(true, None, true)?, 0)
+}
+
+#[test]
+fn scaled_b_moves_to_output() -> TractResult<()> {
+    check_scaled_operands(scaled_operands_model(false, Some((ops::math::mul(), 2.)), false)?, 1)
+}
+
+#[test]
+fn negated_a_and_divided_b() -> TractResult<()> {
+    let model = scaled_operands_model(true, Some((ops::math::div(), 4.)), false)?;
+    check_scaled_operands(model, 1)
+}
+
+#[test]
+fn shared_negation_stays() -> TractResult<()> {
+    let mut model = scaled_operands_model(true, None, true)?;
+    let neg = model.node_by_name("neg")?.id;
+    model.set_output_outlets(&[model.output_outlets()?[0], neg.into()])?;
+    let decluttered = model.into_decluttered()?;
+    let einsum = decluttered.nodes.iter().find(|n| n.op_is::<EinSum>()).unwrap();
+    assert_eq!(decluttered.node(einsum.inputs[0].node).name, "neg");
+    Ok(())
+}
+
+/// An einsum of constants: `expr` over a and b, or a quantized einsum over a and b if
+/// `q_dt` is given. The node is added as is, as `wire_node` would evaluate it right away.
+fn const_einsum_model(
+    expr: &str,
+    a: Tensor,
+    b: Tensor,
+    operating_dt: DatumType,
+    q_dt: Option<DatumType>,
+) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let mut inputs = tvec!(model.add_const("a", a)?, model.add_const("b", b)?);
+    let op = if let Some(q_dt) = q_dt {
+        inputs.extend(wire_q_params(
+            &mut model,
+            "",
+            [
+                tensor0(5i32),
+                tensor0(2i8),
+                tensor0(0.05f32),
+                tensor0(-1i8),
+                tensor0(0.03f32),
+                tensor0(1i8),
+                tensor0(0.02f32),
+            ],
+        )?);
+        EinSum::newq(expr.parse()?, operating_dt, q_dt)
+    } else {
+        EinSum::new(expr.parse()?, operating_dt)
+    };
+    let facts: TVec<TypedFact> =
+        inputs.iter().map(|i| model.outlet_fact(*i).cloned()).collect::<TractResult<_>>()?;
+    let output_facts = op.output_facts(&facts.iter().collect::<TVec<_>>())?;
+    let einsum = model.add_node("einsum", op, output_facts)?;
+    for (ix, input) in inputs.iter().enumerate() {
+        model.add_edge(*input, InletId::new(einsum, ix))?;
+    }
+    model.set_output_outlets(&[einsum.into()])?;
+    Ok(model)
+}
+
+/// Declutter with a folding threshold, check the einsum is folded (or not) and its output
+/// unchanged.
+fn check_const_folding(
+    model: TypedModel,
+    max_bytes: Option<usize>,
+    folded: bool,
+) -> TractResult<()> {
+    let expected = model.clone().into_runnable()?.run(tvec!())?.remove(0);
+    let mut decluttered = model;
+    let options =
+        crate::optim::OptimizerOptions { const_fold_max_bytes: max_bytes, ..Default::default() };
+    crate::optim::Optimizer::declutter().with_options(options).optimize(&mut decluttered)?;
+    let konst = decluttered.outlet_fact(decluttered.output_outlets()?[0])?.konst.clone();
+    assert_eq!(decluttered.nodes().len() == 1, folded, "{decluttered}");
+    if folded {
+        assert_eq!(&*konst.unwrap(), &*expected);
+    } else {
+        let found = decluttered.into_runnable()?.run(tvec!())?.remove(0);
+        assert_eq!(found, expected);
+    }
+    Ok(())
+}
+
+fn const_operand(shape: &[usize], seed: usize) -> Tensor {
+    let len = shape.iter().product::<usize>();
+    let data = (0..len).map(|i| ((i * seed + 3) % 13) as f32 - 6.).collect_vec();
+    Tensor::from_shape(shape, &data).unwrap()
+}
+
+#[test]
+fn float_einsum_of_constants_folded() -> TractResult<()> {
+    let (a, b) = (const_operand(&[4, 3], 5), const_operand(&[3, 6], 7));
+    let model = const_einsum_model("mk,kn->mn", a, b, f32::datum_type(), None)?;
+    check_const_folding(model, None, true)
+}
+
+#[test]
+fn mixed_type_einsum_of_constants_folded() -> TractResult<()> {
+    let a = const_operand(&[4, 3], 5).cast_to::<f16>()?.into_owned();
+    let b = const_operand(&[3, 6], 7);
+    let model = const_einsum_model("mk,kn->mn", a, b, f32::datum_type(), None)?;
+    check_const_folding(model, None, true)
+}
+
+#[test]
+fn broadcast_einsum_of_constants_folded() -> TractResult<()> {
+    let (a, b) = (const_operand(&[1, 4, 3], 5), const_operand(&[2, 3, 6], 7));
+    let model = const_einsum_model("bmk,bkn->bmn", a, b, f32::datum_type(), None)?;
+    check_const_folding(model, None, true)
+}
+
+#[test]
+fn quantized_einsum_of_constants_folded() -> TractResult<()> {
+    let a = const_operand(&[4, 3], 5).cast_to::<i8>()?.into_owned();
+    let b = const_operand(&[3, 6], 7).cast_to::<i8>()?.into_owned();
+    let model =
+        const_einsum_model("mk,kn,,,,,,,->mn", a, b, i32::datum_type(), Some(i8::datum_type()))?;
+    let expected = model.clone().into_runnable()?.run(tvec!())?.remove(0);
+    assert_eq!(expected.datum_type(), i8::datum_type());
+    assert!(expected.as_slice::<i8>()?.iter().any(|x| *x != 0));
+    check_const_folding(model, None, true)
+}
+
+#[test]
+fn large_einsum_of_constants_kept() -> TractResult<()> {
+    let (a, b) = (const_operand(&[4, 3], 5), const_operand(&[3, 6], 7));
+    let model = const_einsum_model("mk,kn->mn", a, b, f32::datum_type(), None)?;
+    // 4x6 f32 outputs are 96 bytes
+    check_const_folding(model.clone(), Some(96), true)?;
+    check_const_folding(model, Some(95), false)
+}
@@ -0,0 +1,208 @@
+//! Dequantization of quantized einsum outputs, and dequantize-only einsums.
+
+use super::test_util::{dequant_lowered, ensure_within_one_step, wire_q_params};
+use super::*;
+use crate::ops::quant::DequantizeLinearF32;
+
+fn quantized_model(d: usize, both_dynamic: bool) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", i8::fact([d, d]))?;
+    let b = if both_dynamic {
+        model.add_source("b", i8::fact([d, d]))?
+    } else {
+        let b = (0..d * d).map(|i| ((i * 7) % 23) as i8 - 11).collect_vec();
+        model.add_const("b", Tensor::from_shape(&[d, d], &b)?)?
+    };
+    let bias = (0..d).map(|i| i as i32 * 3 - 300).collect_vec();
+    let mut inputs = tvec!(a, b);
+    inputs.extend(wire_q_params(
+        &mut model,
+        "",
+        [
+            Tensor::from_shape(&[d, 1], &bias)?,
+            tensor0(2i8),
+            tensor0(0.05f32),
+            tensor0(-1i8),
+            tensor0(0.03f32),
+            tensor0(1i8),
+            tensor0(2f32),
+        ],
+    )?);
+    let op = EinSum::newq("mk,kn,mn,,,,,,->mn".parse()?, i32::datum_type(), i8::datum_type());
+    let c = model.wire_node("einsum", op, &inputs)?;
+    model.set_output_outlets(&c)?;
+    Ok(model)
+}
+
+#[test]
+fn dequant_transients_peak_memory() -> TractResult<()> {
+    let d = 256;
+    for both_dynamic in [false, true] {
+        let model = quantized_model(d, both_dynamic)?;
+        // the i32 accumulator is updated in place, only the i8 output coexists with it
+        let plan = SimplePlan::new(dequant_lowered(&model)?)?;
+        let peak = plan.peak_memory(&SymbolValues::default())?;
+        assert!(peak <= d * d * (4 + 1), "peak: {peak}");
+        // fused: packed operands and output
+        let plan = SimplePlan::new(model.into_optimized()?)?;
+        let peak = plan.peak_memory(&SymbolValues::default())?;
+        assert!(peak <= d * d * 3 + 4 * 1024, "peak: {peak}");
+    }
+    Ok(())
+}
+
+#[test]
+fn dequant_lowering_outputs() -> TractResult<()> {
+    let d = 32;
+    for both_dynamic in [false, true] {
+        let model = quantized_model(d, both_dynamic)?;
+        let input = |seed: usize| {
+            let v = (0..d * d).map(|i| ((i * seed) % 251) as u8 as i8).collect_vec();
+            Tensor::from_shape(&[d, d], &v).unwrap().into_tvalue()
+        };
+        let inputs = if both_dynamic { tvec!(input(3), input(5)) } else { tvec!(input(3)) };
+        let reference = model.clone().into_runnable()?.run(inputs.clone())?;
+        let lowered = dequant_lowered(&model)?.into_runnable()?.run(inputs.clone())?;
+        let optimized = model.into_optimized()?.into_runnable()?.run(inputs)?;
+        optimized[0].close_enough(&reference[0], Approximation::Exact)?;
+        // before fusion, the requantization rounds ties differently
+        ensure_within_one_step(&lowered[0], &reference[0])?;
+    }
+    Ok(())
+}
+
+/// a [4, 8] by constant b [8, 3], requantized to i8 then dequantized to f32, with the
+/// requantization parameters of the dequantizer.
+fn dequantized_model(bias: Tensor, with_dequant: bool) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let mut inputs = tvec!(model.add_source("a", i8::fact([4, 8]))?);
+    inputs.push(model.add_const("b", Tensor::from_shape(&[8, 3], &dequantized_b())?)?);
+    inputs.extend(wire_q_params(
+        &mut model,
+        "",
+        [
+            bias,
+            tensor0(2i8),
+            tensor0(0.05f32),
+            tensor0(-1i8),
+            tensor0(0.03f32),
+            tensor0(1i8),
+            tensor0(0.5f32),
+        ],
+    )?);
+    let qp = if with_dequant { i8::datum_type() } else { f32::datum_type() };
+    let op = EinSum::newq("mk,kn,,,,,,,->mn".parse()?, i32::datum_type(), qp);
+    let mut wire = model.wire_node("einsum", op, &inputs)?;
+    if with_dequant {
+        wire = model.wire_node("dequant", DequantizeLinearF32::new(0.5, 1), &wire)?;
+    }
+    model.set_output_outlets(&wire)?;
+    Ok(model)
+}
+
+fn dequantized_b() -> Vec<i8> {
+    (0..24).map(|i| ((i * 29) % 41) as i8 - 20).collect_vec()
+}
+
+/// (a - a0) · (b - b0) * a_scale * b_scale + bias, in f64.
+fn dequantized_reference(a: &[i8], bias: &Tensor) -> TractResult<Tensor> {
+    let b = dequantized_b();
+    let bias = if bias.datum_type().is_float() {
+        bias.cast_to_scalar::<f32>()? as f64
+    } else {
+        bias.cast_to_scalar::<i32>()? as f64 * 0.05f64 * 0.03f64
+    };
+    let mut expected = vec![0f32; 12];
+    for (i, j) in tract_itertools::iproduct!(0..4, 0..3) {
+        let acc: i32 = (0..8).map(|k| (a[i * 8 + k] as i32 - 2) * (b[k * 3 + j] as i32 + 1)).sum();
+        expected[i * 3 + j] = (acc as f64 * 0.05f64 * 0.03f64 + bias) as f32;
+    }
+    Tensor::from_shape(&[4, 3], &expected)
+}
+
+#[test]
+fn dequantize_only_einsum() -> TractResult<()> {
+    let a = (0..32).map(|i| ((i * 37) % 61) as i8 - 30).collect_vec();
+    let input = || tvec!(Tensor::from_shape(&[4, 8], &a).unwrap().into_tvalue());
+    for bias in [tensor0(300i32), tensor0(0.7f32)] {
+        let expected = dequantized_reference(&a, &bias)?;
+        let requantized = dequantized_model(bias.clone(), true)?;
+        let tight = |found: &Tensor| -> TractResult<()> {
+            ensure!(found.datum_type() == f32::datum_type());
+            let found = found.as_slice::<f32>()?;
+            for (f, e) in found.iter().zip(expected.as_slice::<f32>()?) {
+                // well below the 0.5 step of the i8 output grid
+                ensure!((f - e).abs() < 1e-4, "found {found:?}, expected {expected:?}");
+            }
+            Ok(())
+        };
+        // the requantizing model rounds to the output grid
+        let rounded = requantized.clone().into_runnable()?.run(input())?;
+        assert!(tight(&rounded[0]).is_err());
+
+        let dequantize_only = dequantized_model(bias, false)?;
+        let output_fact = dequantize_only.outlet_fact(dequantize_only.output_outlets()?[0])?;
+        assert_eq!(output_fact.datum_type, f32::datum_type());
+        tight(&dequantize_only.clone().into_runnable()?.run(input())?[0])?;
+        tight(&dequant_lowered(&dequantize_only)?.into_runnable()?.run(input())?[0])?;
+
+        let decluttered = requantized.into_decluttered()?;
+        let dequantizers = decluttered.nodes().iter().filter(|n| n.op_is::<DequantizeLinearF32>());
+        assert_eq!(dequantizers.count(), 0);
+        let einsum = decluttered.node_by_name("einsum")?.op_as::<EinSum>().unwrap();
+        assert_eq!(einsum.q_params, Some(f32::datum_type()));
+        tight(&decluttered.clone().into_runnable()?.run(input())?[0])?;
+        let optimized = decluttered.into_optimized()?;
+        for node in optimized.nodes() {
+            let requantizing = ["round", "requant", "zeropoint", "clamp"];
+            assert!(requantizing.iter().all(|r| !node.name.contains(r)), "{node}");
+        }
+        tight(&optimized.into_runnable()?.run(input())?[0])?;
+    }
+    Ok(())
+}
+
+/// `einsum * scalar`, `scalar * einsum` or `einsum / scalar` on a dequantize-only einsum.
+fn scaled_dequantized_model(bias: Tensor, scalar: f32, op: &str) -> TractResult<TypedModel> {
+    let mut model = dequantized_model(bias, false)?;
+    let y = model.output_outlets()?[0];
+    let s = model.add_const("scalar", tensor2(&[[scalar]]))?;
+    let (op, inputs) = match op {
+        "y*s" => (ops::math::mul(), [y, s]),
+        "s*y" => (ops::math::mul(), [s, y]),
+        "y/s" => (ops::math::div(), [y, s]),
+        "s/y" => (ops::math::div(), [s, y]),
+        _ => unreachable!(),
+    };
+    let wire = model.wire_node("scale", op, &inputs)?;
+    model.set_output_outlets(&wire)?;
+    Ok(model)
+}
+
+#[test]
+fn scalar_folds_in_dequantize_only_einsum() -> TractResult<()> {
+    let a = (0..32).map(|i| ((i * 37) % 61) as i8 - 30).collect_vec();
+    let input = || tvec!(Tensor::from_shape(&[4, 8], &a).unwrap().into_tvalue());
+    for bias in [tensor0(300i32), tensor0(0.7f32)] {
+        for (scalar, op) in
+            tract_itertools::iproduct!([0.5f32, 1.7, 2f32.powi(-7)], ["y*s", "s*y", "y/s"])
+        {
+            let model = scaled_dequantized_model(bias.clone(), scalar, op)?;
+            let reference = model.clone().into_runnable()?.run(input())?.remove(0);
+            let decluttered = model.into_decluttered()?;
+            assert!(decluttered.node_by_name("scale").is_err(), "{scalar} {op}");
+            let found = decluttered.clone().into_runnable()?.run(input())?.remove(0);
+            if scalar.log2().fract() == 0. {
+                assert_eq!(found, reference, "{scalar} {op}");
+            } else {
+                found.close_enough(&reference, true)?;
+            }
+            let optimized = decluttered.into_optimized()?.into_runnable()?.run(input())?;
+            optimized[0].close_enough(&reference, true)?;
+        }
+    }
+    // not a scale
+    let decluttered = scaled_dequantized_model(tensor0(0i32), 0.5, "s/y")?.into_decluttered()?;
+    assert!(decluttered.node_by_name("scale").is_ok());
+    Ok(())
+}
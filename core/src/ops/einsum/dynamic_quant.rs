@@ -0,0 +1,303 @@
+//! Dynamic quantization: f32 einsums with constant weights run on i8 operands.
+//!
+//! Weights are quantized once, per output channel and symmetrically. Activations are quantized
+//! on the fly, per tensor, from their observed range. The einsum becomes a dequantize-only
+//! quantized einsum, scaled per channel afterwards.
+use super::codegen::{codegen_node_name, inner_einsum_name};
+use super::EinSum;
+use crate::internal::*;
+use crate::ops;
+use crate::ops::binary::wire_with_rank_broadcast;
+use crate::ops::cast::cast;
+use crate::ops::nn::{Reduce, Reducer};
+use ndarray::{ArrayD, Axis as NdAxis};
+use tract_itertools::Itertools;
+
+/// Which einsums `TypedModel::dynamic_quantize_matmuls` converts.
+#[derive(Clone, Debug)]
+pub struct DynamicQuantizationPolicy {
+    /// Smallest weight, in elements, worth quantizing.
+    pub min_weight_size: usize,
+}
+
+impl Default for DynamicQuantizationPolicy {
+    fn default() -> Self {
+        DynamicQuantizationPolicy { min_weight_size: 1024 }
+    }
+}
+
+/// An einsum converted by `TypedModel::dynamic_quantize_matmuls`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicQuantizationReport {
+    /// Name of the einsum node.
+    pub node: String,
+    /// Largest absolute difference between a weight and its dequantized i8 value.
+    pub max_weight_error: f32,
+}
+
+pub(crate) fn dynamic_quantize_matmuls(
+    model: &mut TypedModel,
+    policy: &DynamicQuantizationPolicy,
+) -> TractResult<Vec<DynamicQuantizationReport>> {
+    let mut reports = vec![];
+    for id in model.eval_order()? {
+        let node = model.node(id);
+        let Some(op) = node.op_as::<EinSum>() else { continue };
+        let Some(weight_ix) = weight_input(model, node, op, policy)? else { continue };
+        let (patch, max_weight_error) =
+            quantize(model, node, op, weight_ix).with_context(|| format!("Quantizing {node}"))?;
+        reports.push(DynamicQuantizationReport { node: node.name.clone(), max_weight_error });
+        patch.apply(model)?;
+    }
+    model.compact()?;
+    Ok(reports)
+}
+
+/// Slot of the constant weight of an f32 einsum between a weight and an activation.
+fn weight_input(
+    model: &TypedModel,
+    node: &TypedNode,
+    op: &EinSum,
+    policy: &DynamicQuantizationPolicy,
+) -> TractResult<Option<usize>> {
+    if op.q_params.is_some() || op.operating_dt != f32::datum_type() || node.inputs.len() != 2 {
+        return Ok(None);
+    }
+    let facts = model.node_input_facts(node.id)?;
+    if facts.iter().any(|f| f.datum_type != f32::datum_type()) {
+        return Ok(None);
+    }
+    match (&facts[0].konst, &facts[1].konst) {
+        (Some(w), None) if w.len() >= policy.min_weight_size => Ok(Some(0)),
+        (None, Some(w)) if w.len() >= policy.min_weight_size => Ok(Some(1)),
+        _ => Ok(None),
+    }
+}
+
+fn quantize(
+    model: &TypedModel,
+    node: &TypedNode,
+    op: &EinSum,
+    weight_ix: usize,
+) -> TractResult<(TypedModelPatch, f32)> {
+    let weight = model.outlet_fact(node.inputs[weight_ix])?.konst.clone().unwrap();
+    let weight = weight.to_array_view::<f32>()?;
+    let input_ix = 1 - weight_ix;
+
+    // per channel scales, the channels being the weight axes kept in the output
+    let contracted = op
+        .axes
+        .axes(InOut::In(weight_ix))
+        .map(|axis| axis.outputs[0].is_empty())
+        .collect::<TVec<_>>();
+    let mut scales = weight.mapv(f32::abs);
+    for (ix, _) in contracted.iter().enumerate().filter(|(_, c)| **c) {
+        scales = scales
+            .map_axis(NdAxis(ix), |lane| lane.fold(0f32, |m, x| m.max(*x)))
+            .insert_axis(NdAxis(ix));
+    }
+    scales.mapv_inplace(|m| if m > 0. { m / 127. } else { 1. });
+    let quantized = (&weight / &scales).mapv(|x| x.round().clamp(-127., 127.) as i8);
+    let max_weight_error = ndarray::Zip::from(&weight)
+        .and(&quantized)
+        .and_broadcast(&scales)
+        .fold(0f32, |e, w, q, s| e.max((w - *q as f32 * s).abs()));
+
+    let name = &node.name;
+    let mut patch = TypedModelPatch::new(format!("Dynamic quantization of {node}"));
+    let input = patch.tap_model(model, node.inputs[input_ix])?;
+    let (input, input_zp, input_scale) = wire_quantize_activation(&mut patch, name, input)?;
+    let weight = patch.add_const(codegen_node_name(name, "weight_i8"), quantized.into_tensor())?;
+    let mut inputs = tvec!(input, weight);
+    if weight_ix == 0 {
+        inputs.swap(0, 1);
+    }
+    let (a0, a_scale, b0, b_scale) = if weight_ix == 0 {
+        (None, None, Some(input_zp), Some(input_scale))
+    } else {
+        (Some(input_zp), Some(input_scale), None, None)
+    };
+    let mut scalar = |role: &str, wire: Option<OutletId>, t: Tensor| -> TractResult<OutletId> {
+        wire.map(Ok).unwrap_or_else(|| patch.add_const(codegen_node_name(name, role), t))
+    };
+    inputs.push(scalar("bias", None, tensor0(0f32))?);
+    inputs.push(scalar("a0", a0, tensor0(0i32))?);
+    inputs.push(scalar("a_scale", a_scale, tensor0(1f32))?);
+    inputs.push(scalar("b0", b0, tensor0(0i32))?);
+    inputs.push(scalar("b_scale", b_scale, tensor0(1f32))?);
+    inputs.push(scalar("c0", None, tensor0(0i32))?);
+    inputs.push(scalar("c_scale", None, tensor0(1f32))?);
+    let mut axes = op.axes.clone();
+    for slot in 2..9 {
+        axes = axes.with_extra_input(slot)?;
+    }
+    let einsum = EinSum::newq(axes, i32::datum_type(), f32::datum_type());
+    let wire = patch.wire_node(inner_einsum_name(name), einsum, &inputs)?;
+
+    // channel scales, laid out as the output
+    let output_rank = op.axes.rank(InOut::Out(0));
+    let mut channels = op
+        .axes
+        .axes(InOut::In(weight_ix))
+        .zip(contracted.iter())
+        .filter(|(_, c)| !**c)
+        .map(|(axis, _)| axis.outputs[0][0])
+        .collect::<TVec<_>>();
+    let mut scales: ArrayD<f32> = scales;
+    for ix in (0..contracted.len()).rev().filter(|ix| contracted[*ix]) {
+        scales = scales.index_axis_move(NdAxis(ix), 0);
+    }
+    let order = (0..channels.len()).sorted_by_key(|ix| channels[*ix]).collect_vec();
+    scales = scales.permuted_axes(order);
+    channels.sort();
+    let mut shape = vec![1; output_rank];
+    for (ix, pos) in channels.iter().enumerate() {
+        shape[*pos] = scales.shape()[ix];
+    }
+    let scales = scales.as_standard_layout().into_owned().into_shape(shape)?;
+    let scales = patch.add_const(codegen_node_name(name, "weight_scales"), scales.into_tensor())?;
+    let wire = wire_with_rank_broadcast(name, &mut patch, ops::math::mul(), &[wire[0], scales])?;
+    patch.shunt_outside(model, node.id.into(), wire[0])?;
+    Ok((patch, max_weight_error))
+}
+
+/// Quantize `input` to i8 from its range, extended to include zero. Returns the quantized
+/// input, its zero point (i32 scalar) and scale (f32 scalar).
+fn wire_quantize_activation(
+    patch: &mut TypedModelPatch,
+    name: &str,
+    input: OutletId,
+) -> TractResult<(OutletId, OutletId, OutletId)> {
+    use ops::math::{add, div, max, min, round_half_to_even, sub};
+    let rank = patch.outlet_fact(input)?.rank();
+    let mut bound = |reducer: Reducer| -> TractResult<OutletId> {
+        let role = format!("input_{reducer:?}").to_lowercase();
+        let all_axes = (0..rank).collect();
+        let mut wire = patch.wire_node(
+            codegen_node_name(name, &role),
+            Reduce { axes: all_axes, reducer },
+            &[input],
+        )?[0];
+        for axis in (0..rank).rev() {
+            wire = patch.wire_node(
+                codegen_node_name(name, format_args!("{role}.rm{axis}")),
+                AxisOp::Rm(axis),
+                &[wire],
+            )?[0];
+        }
+        Ok(wire)
+    };
+    let (low, high) = (bound(Reducer::Min)?, bound(Reducer::Max)?);
+    let node_name = |role: &str| codegen_node_name(name, role);
+    let mut konst = |role: &str, v: f32| patch.add_const(node_name(role), tensor0(v));
+    let zero = konst("input_zero", 0.)?;
+    let levels = konst("input_levels", 255.)?;
+    let smallest = konst("input_smallest_scale", f32::MIN_POSITIVE)?;
+    let (i8_min, i8_max) = (konst("input_i8_min", -128.)?, konst("input_i8_max", 127.)?);
+
+    let low = wire_binary(patch, &node_name("input_low"), min(), low, zero)?;
+    let high = wire_binary(patch, &node_name("input_high"), max(), high, zero)?;
+    let range = wire_binary(patch, &node_name("input_range"), sub(), high, low)?;
+    let scale = wire_binary(patch, &node_name("input_scale_raw"), div(), range, levels)?;
+    let scale = wire_binary(patch, &node_name("input_scale"), max(), scale, smallest)?;
+    // the zero point maps low to -128, and stays in the i8 range as low <= 0
+    let zp = wire_binary(patch, &node_name("input_zp_raw"), div(), low, scale)?;
+    let zp = wire_binary(patch, &node_name("input_zp_shifted"), sub(), i8_min, zp)?;
+    let zp = patch.wire_node(node_name("input_zp"), round_half_to_even(), &[zp])?[0];
+
+    let q = wire_binary(patch, &node_name("input_scaled"), div(), input, scale)?;
+    let q = patch.wire_node(node_name("input_rounded"), round_half_to_even(), &[q])?[0];
+    let q = wire_binary(patch, &node_name("input_centered"), add(), q, zp)?;
+    let q = wire_binary(patch, &node_name("input_clamp_low"), max(), q, i8_min)?;
+    let q = wire_binary(patch, &node_name("input_clamp_high"), min(), q, i8_max)?;
+    let q = patch.wire_node(node_name("input_i8"), cast(i8::datum_type()), &[q])?[0];
+    let zp = patch.wire_node(node_name("input_zp_i32"), cast(i32::datum_type()), &[zp])?[0];
+    Ok((q, zp, scale))
+}
+
+fn wire_binary(
+    patch: &mut TypedModelPatch,
+    name: &str,
+    op: ops::binary::TypedBinOp,
+    a: OutletId,
+    b: OutletId,
+) -> TractResult<OutletId> {
+    Ok(wire_with_rank_broadcast(name, patch, op, &[a, b])?[0])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn weights(shape: &[usize], seed: usize) -> Tensor {
+        let len = shape.iter().product::<usize>();
+        let data = (0..len).map(|i| ((i * seed + 7) % 23) as f32 / 23. - 0.5).collect::<Vec<_>>();
+        Tensor::from_shape(shape, &data).unwrap()
+    }
+
+    /// Two dense layers with a relu, the second weight laid out as `nk`.
+    fn mlp() -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let x = model.add_source("x", f32::fact([4, 32]))?;
+        let w1 = model.add_const("w1", weights(&[32, 64], 5))?;
+        let h = model.wire_node(
+            "layer1",
+            EinSum::new("mk,kn->mn".parse()?, f32::datum_type()),
+            &[x, w1],
+        )?;
+        let zero = model.add_const("zero", tensor2(&[[0f32]]))?;
+        let h = model.wire_node("relu", ops::math::max(), &[h[0], zero])?;
+        let w2 = model.add_const("w2", weights(&[16, 64], 3))?;
+        let y = model.wire_node(
+            "layer2",
+            EinSum::new("nk,mk->mn".parse()?, f32::datum_type()),
+            &[w2, h[0]],
+        )?;
+        model.set_output_outlets(&y)?;
+        Ok(model)
+    }
+
+    fn input() -> TVec<TValue> {
+        let x = (0..128).map(|i| ((i * 13) % 29) as f32 / 7. - 2.).collect::<Vec<_>>();
+        tvec!(Tensor::from_shape(&[4, 32], &x).unwrap().into_tvalue())
+    }
+
+    #[test]
+    fn quantized_mlp_matches_float() -> TractResult<()> {
+        let model = mlp()?;
+        let reference = model.clone().into_runnable()?.run(input())?.remove(0);
+        let mut quantized = model;
+        let policy = DynamicQuantizationPolicy { min_weight_size: 512 };
+        let reports = quantized.dynamic_quantize_matmuls(&policy)?;
+        assert_eq!(reports.iter().map(|r| &*r.node).collect::<Vec<_>>(), ["layer1", "layer2"]);
+        // half a quantization step of the largest channel
+        assert!(reports.iter().all(|r| r.max_weight_error <= 0.5 / 127. * 0.5 + 1e-6));
+        let einsums =
+            quantized.nodes().iter().filter_map(|n| n.op_as::<EinSum>()).collect::<Vec<_>>();
+        assert!(einsums.iter().all(|op| op.q_params == Some(f32::datum_type())));
+        // named after the einsums like the codegen patches are
+        for name in ["layer1#einsum", "layer1.weight_i8", "layer2.input_i8", "layer2.a0"] {
+            quantized.node_by_name(name)?;
+        }
+        let tolerance = 0.05 * reference.to_array_view::<f32>()?.fold(0f32, |m, x| m.max(x.abs()));
+        for model in [quantized.clone(), quantized.into_optimized()?] {
+            let found = model.into_runnable()?.run(input())?.remove(0);
+            let error = ndarray::Zip::from(found.to_array_view::<f32>()?)
+                .and(reference.to_array_view::<f32>()?)
+                .fold(0f32, |e, f, r| e.max((f - r).abs()));
+            ensure!(error <= tolerance, "error {error} above {tolerance}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn small_weights_stay_float() -> TractResult<()> {
+        let mut model = mlp()?;
+        let policy = DynamicQuantizationPolicy { min_weight_size: 1025 };
+        let reports = model.dynamic_quantize_matmuls(&policy)?;
+        assert_eq!(reports.iter().map(|r| &*r.node).collect::<Vec<_>>(), ["layer1"]);
+        let layer2 = model.node_by_name("layer2")?.op_as::<EinSum>().unwrap();
+        assert!(layer2.q_params.is_none());
+        Ok(())
+    }
+}
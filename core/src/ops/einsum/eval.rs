@@ -1,138 +1,437 @@
-use super::AxesMapping;
+use super::{AxesMapping, QActivation, QOverflow};
+use crate::broadcast::broadcast_dim;
 use crate::internal::*;
+use std::sync::Mutex;
 use tract_data::itertools::Itertools;
 use tract_linalg::Scaler;
 use tract_ndarray::{Axis, Dimension};
 use tract_num_traits::{One, Zero};
 
-pub fn output_shape<D: DimLike>(expr: &AxesMapping, inputs: &[&[D]]) -> TVec<D> {
+/// Output shape of an einsum, broadcasting the input dimensions of each output axis (see
+/// `broadcast_dim`).
+pub fn output_shape<D: DimLike>(expr: &AxesMapping, inputs: &[&[D]]) -> TractResult<TVec<D>> {
     expr.iter_all_axes()
         .filter(|a| a.outputs[0].len() > 0)
         .sorted_by_key(|axis| axis.outputs[0][0])
         .map(|axis| {
-            axis.inputs[0..inputs.len()]
+            let dims = axis.inputs[0..inputs.len()].iter().enumerate().flat_map(
+                |(input_id, positions)| positions.iter().map(move |p| &inputs[input_id][*p]),
+            );
+            Ok(broadcast_dim(axis.outputs[0][0], dims)?)
+        })
+        .collect()
+}
+
+/// The iteration `eval_t` runs for an axes mapping and concrete input shapes.
+///
+/// Output and summing axes are flattened, with the stride of each input along them (zero where
+/// the input is broadcast).
+#[derive(Debug)]
+pub struct LoopNest {
+    input_shapes: TVec<TVec<usize>>,
+    output_shape: TVec<usize>,
+    summing_shape: TVec<usize>,
+    output_strides: TVec<TVec<isize>>,
+    summing_strides: TVec<TVec<isize>>,
+}
+
+impl LoopNest {
+    pub fn new(expr: &AxesMapping, shapes: &[&[usize]]) -> TractResult<LoopNest> {
+        let strides = |axis: &crate::axes::Axis| -> TVec<isize> {
+            shapes
                 .iter()
                 .enumerate()
-                .flat_map(|(input_id, positions)| {
-                    positions.iter().map(move |p| inputs[input_id][*p].clone())
+                .map(|(input_id, shape)| {
+                    axis.inputs[input_id]
+                        .iter()
+                        .filter(|p| shape[**p] != 1)
+                        .map(|p| shape[p + 1..].iter().product::<usize>() as isize)
+                        .sum()
                 })
-                .find(|x| x != &1.into())
-                .unwrap_or_else(|| 1.into())
+                .collect()
+        };
+        let output_axes = expr
+            .iter_all_axes()
+            .filter(|a| a.outputs[0].len() > 0)
+            .sorted_by_key(|axis| axis.outputs[0][0])
+            .collect_vec();
+        let summing_axes = expr
+            .iter_all_axes()
+            .filter(|a| {
+                a.outputs[0].len() == 0 && a.inputs[0..shapes.len()].iter().any(|i| i.len() > 0)
+            })
+            .collect_vec();
+        // inputs with a dimension of 1 broadcast along a summing axis
+        let summing_shape = summing_axes
+            .iter()
+            .map(|axis| {
+                (0..shapes.len())
+                    .flat_map(|input_id| axis.inputs[input_id].iter().map(move |p| (input_id, p)))
+                    .map(|(input_id, p)| shapes[input_id][*p])
+                    .max()
+                    .unwrap()
+            })
+            .collect();
+        Ok(LoopNest {
+            input_shapes: shapes.iter().map(|s| (*s).into()).collect(),
+            output_shape: output_shape(expr, shapes)?,
+            summing_shape,
+            output_strides: output_axes.iter().map(|a| strides(a)).collect(),
+            summing_strides: summing_axes.iter().map(|a| strides(a)).collect(),
         })
-        .collect()
+    }
+
+    fn offsets(strides: &[TVec<isize>], coords: &[usize], offsets: &mut [isize]) {
+        offsets.iter_mut().for_each(|o| *o = 0);
+        for (x, strides) in coords.iter().zip(strides) {
+            for (o, stride) in offsets.iter_mut().zip(strides) {
+                *o += *x as isize * stride;
+            }
+        }
+    }
+}
+
+/// Loop nest for the last input shapes an einsum was evaluated with.
+///
+/// Cloning an op gives the clone an empty cache, as clones often get a different mapping.
+#[derive(Debug, Default)]
+pub struct LoopNestCache(Mutex<Option<(AxesMapping, Arc<LoopNest>)>>);
+
+impl Clone for LoopNestCache {
+    fn clone(&self) -> LoopNestCache {
+        LoopNestCache::default()
+    }
+}
+
+impl LoopNestCache {
+    pub fn get(&self, expr: &AxesMapping, shapes: &[&[usize]]) -> TractResult<Arc<LoopNest>> {
+        let mut cached = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((cached_expr, nest)) = &*cached {
+            if cached_expr == expr
+                && nest.input_shapes.len() == shapes.len()
+                && nest.input_shapes.iter().zip(shapes).all(|(a, b)| &**a == *b)
+            {
+                return Ok(nest.clone());
+            }
+        }
+        let nest = Arc::new(LoopNest::new(expr, shapes)?);
+        *cached = Some((expr.clone(), nest.clone()));
+        Ok(nest)
+    }
 }
 
 pub fn eval_t<Acc: Datum + Zero + One>(
     expr: &AxesMapping,
+    nests: &LoopNestCache,
     inputs: TVec<TValue>,
 ) -> TractResult<Tensor> {
     let shapes: TVec<_> = inputs.iter().map(|t| t.shape()).collect();
-    let output_shape = output_shape(expr, &shapes);
+    let nest = nests.get(expr, &shapes)?;
+    let mirror = symmetric_output_axes(expr, &inputs);
     let inputs: TVec<Cow<Tensor>> =
         inputs.iter().map(|t| t.cast_to::<Acc>()).collect::<TractResult<_>>()?;
-    let inputs: TVec<tract_ndarray::ArrayViewD<Acc>> =
-        inputs.iter().map(|t| t.to_array_view::<Acc>()).collect::<TractResult<_>>()?;
-    let summing_axes: TVec<_> = expr
-        .iter_all_axes()
-        .filter(|a| {
-            a.outputs[0].len() == 0 && a.inputs[0..inputs.len()].iter().any(|i| i.len() > 0)
-        })
-        .collect();
-    let summing_shape: TVec<usize> = summing_axes
-        .iter()
-        .map(|axis| {
-            axis.inputs
-                .iter()
-                .take(inputs.len())
-                .enumerate()
-                .find_map(|(input_id, positions)| {
-                    if positions.len() > 0 {
-                        Some(inputs[input_id].shape()[positions[0]])
-                    } else {
-                        None
-                    }
-                })
-                .unwrap()
-        })
-        .collect();
-    let output = tract_ndarray::ArrayD::<Acc>::from_shape_fn(&*output_shape, |coords| {
-        let coords = coords.as_array_view();
-        let mut views = inputs.clone();
-        for (axis, x) in expr
-            .iter_all_axes()
-            .filter(|a| a.outputs[0].len() > 0)
-            .sorted_by_key(|axis| axis.outputs[0][0])
-            .zip(coords)
-        {
-            for (input_id, input_axis_positions) in axis.inputs[0..inputs.len()].iter().enumerate()
-            {
-                for position in input_axis_positions {
-                    let x = if views[input_id].shape()[*position] == 1 { 0 } else { *x };
-                    views[input_id]
-                        .slice_axis_inplace(tract_ndarray::Axis(*position), (x..=x).into());
-                }
+    let inputs: TVec<&[Acc]> =
+        inputs.iter().map(|t| t.as_slice::<Acc>()).collect::<TractResult<_>>()?;
+    let mut bases = tvec!(0isize; inputs.len());
+    let mut offsets = tvec!(0isize; inputs.len());
+    let output = tract_ndarray::ArrayD::<Acc>::from_shape_fn(&*nest.output_shape, |coords| {
+        let coords = coords.slice();
+        if let Some((i, j)) = mirror {
+            if coords[i] > coords[j] {
+                return Acc::zero();
             }
         }
+        LoopNest::offsets(&nest.output_strides, coords, &mut bases);
         let mut sum: Acc = Acc::zero();
-        for sum_coords in tract_ndarray::indices(&*summing_shape) {
-            let mut views = views.clone();
-            let sum_coords = sum_coords.as_array_view();
-            for (axis, x) in summing_axes.iter().zip(sum_coords) {
-                for (input_id, input_axis_positions) in
-                    axis.inputs.iter().take(inputs.len()).enumerate()
-                {
-                    for position in input_axis_positions {
-                        views[input_id].slice_axis_inplace(Axis(*position), (*x..=*x).into())
-                    }
-                }
-            }
+        for sum_coords in tract_ndarray::indices(&*nest.summing_shape) {
+            LoopNest::offsets(&nest.summing_strides, sum_coords.slice(), &mut offsets);
             let mut product = Acc::one();
-            for v in &views {
-                debug_assert_eq!(v.len(), 1);
-                product = product * v.iter().next().unwrap().clone();
+            for ((input, base), offset) in inputs.iter().zip(&bases).zip(&offsets) {
+                product = product * input[(base + offset) as usize].clone();
             }
             sum = sum + product;
         }
         sum
     });
+    let output = if let Some((i, j)) = mirror {
+        let mut output = output;
+        for mut coords in tract_ndarray::indices(output.shape()) {
+            if coords[i] > coords[j] {
+                let lower = coords.clone();
+                coords.slice_mut().swap(i, j);
+                output[&lower] = output[&coords].clone();
+            }
+        }
+        output
+    } else {
+        output
+    };
     Ok(output.into_tensor())
 }
 
-pub fn eval_q(expr: &AxesMapping, qp: DatumType, inputs: TVec<TValue>) -> TractResult<Tensor> {
+/// Detect A×Aᵀ patterns, like "ik,jk->ij" with both operands being the same tensor.
+///
+/// The output is then symmetric (per batch slice) and only one triangle needs to be computed.
+/// Returns the positions of the two mirrored output axes.
+fn symmetric_output_axes(expr: &AxesMapping, inputs: &[TValue]) -> Option<(usize, usize)> {
+    let [a, b] = inputs else { return None };
+    if a.len() == 0
+        || a.datum_type() != b.datum_type()
+        || a.shape() != b.shape()
+        || unsafe { a.as_ptr_unchecked::<u8>() != b.as_ptr_unchecked::<u8>() }
+    {
+        return None;
+    }
+    let mut mirrored = tvec!();
+    for axis in expr.iter_all_axes() {
+        if axis.inputs[0] == axis.inputs[1] {
+            continue;
+        }
+        // the mirror axis uses the other operand at the same positions
+        expr.iter_all_axes()
+            .find(|other| other.inputs[0] == axis.inputs[1] && other.inputs[1] == axis.inputs[0])?;
+        if axis.outputs[0].len() != 1 {
+            return None;
+        }
+        mirrored.push(axis.outputs[0][0]);
+    }
+    if let [i, j] = &*mirrored {
+        Some((*i.min(j), *i.max(j)))
+    } else {
+        None
+    }
+}
+
+pub fn eval_q(
+    expr: &AxesMapping,
+    nests: &LoopNestCache,
+    qp: DatumType,
+    activation: Option<QActivation>,
+    overflow: QOverflow,
+    inputs: TVec<TValue>,
+) -> TractResult<Tensor> {
     let [a, b, bias, a0, a_scale, b0, b_scale, c0, c_scale] = &*inputs else {
         bail!("Expect exactly 9 inputs")
     };
 
-    let mut a = a.cast_to::<i32>()?.into_owned();
-    let a0 = a0.cast_to_scalar::<i32>()?;
-    a.as_slice_mut::<i32>()?.iter_mut().for_each(|x| *x -= a0);
-    let mut b = b.cast_to::<i32>()?.into_owned();
-    let b0 = b0.cast_to_scalar::<i32>()?;
-    b.as_slice_mut::<i32>()?.iter_mut().for_each(|x| *x -= b0);
-
-    let mut output =
-        eval_t::<i32>(expr, tvec!(a.into_tvalue(), b.into_tvalue()))?.into_array::<i32>()?;
-    let scale = a_scale.cast_to_scalar::<f32>()? * b_scale.cast_to_scalar::<f32>()?
-        / c_scale.cast_to_scalar::<f32>()?;
-    let scale = Scaler::new(scale, tract_linalg::mmm::RoundingPolicy::Even);
-    let c0 = c0.cast_to_scalar::<i32>()?;
-
-    if bias.rank() == 0 {
-        output += inputs[2].cast_to_scalar::<i32>()?;
+    // 16-bit operands products may overflow an i32 accumulator for realistic k
+    let wide = [a, b].iter().any(|t| t.datum_type().unquantized().size_of() == 2);
+    // c0 and c_scale are scalars, or vectors along an output axis (per-channel quantization)
+    let rank = expr.rank(InOut::Out(0));
+    let c0 = along_output::<i32>(expr, 7, &*c0.cast_to::<i32>()?, rank)?;
+    let c_scale = along_output::<f32>(expr, 8, &*c_scale.cast_to::<f32>()?, rank)?;
+
+    let mut output = if wide || bias.datum_type().is_float() || qp.is_float() {
+        // requantize from the real domain
+        let ab_scale = a_scale.cast_to_scalar::<f32>()? * b_scale.cast_to_scalar::<f32>()?;
+        let mut real = if wide {
+            centered_product::<i64>(expr, nests, a, a0, b, b0)?
+                .mapv(|x| (x as f64 * ab_scale as f64) as f32)
+        } else {
+            centered_product::<i32>(expr, nests, a, a0, b, b0)?.mapv(|x| x as f32 * ab_scale)
+        };
+        let bias = if bias.datum_type().is_float() {
+            bias.cast_to::<f32>()?.into_owned()
+        } else {
+            bias.cast_to::<f32>()?.to_array_view::<f32>()?.mapv(|x| x * ab_scale).into_tensor()
+        };
+        if bias.rank() == 0 {
+            real += *bias.to_scalar::<f32>()?;
+        } else {
+            let mut bias_shape = tvec!(1; real.ndim());
+            bias_shape[expr.axis((InOut::In(2), 0))?.outputs[0][0]] = bias.len();
+            real = real + bias.to_array_view::<f32>()?.into_shape(&*bias_shape)?;
+        }
+        if let Some(activation) = activation {
+            real.mapv_inplace(|x| activation.eval(x));
+        }
+        if qp.is_float() {
+            // dequantize-only: no requantization
+            return Ok(real.into_tensor().cast_to_dt(qp)?.into_owned());
+        }
+        let mut output = tract_ndarray::ArrayD::<i32>::zeros(real.shape());
+        tract_ndarray::Zip::from(&mut output)
+            .and(&real)
+            .and_broadcast(&c_scale)
+            .and_broadcast(&c0)
+            .for_each(|o, x, s, z| {
+                *o = (round_ties_to_even(x / s) as i64 + *z as i64).clamp_cast()
+            });
+        output
     } else {
-        let mut bias_shape = tvec!(1; output.ndim());
-        bias_shape[expr.axis((InOut::In(2), 0))?.outputs[0][0]] = bias.len();
-        let bias = bias.to_array_view::<i32>()?.into_shape(&*bias_shape)?;
-        output = output + bias;
+        let mut output = centered_product::<i32>(expr, nests, a, a0, b, b0)?;
+        if bias.rank() == 0 {
+            output += inputs[2].cast_to_scalar::<i32>()?;
+        } else {
+            let mut bias_shape = tvec!(1; output.ndim());
+            bias_shape[expr.axis((InOut::In(2), 0))?.outputs[0][0]] = bias.len();
+            let bias = bias.to_array_view::<i32>()?.into_shape(&*bias_shape)?;
+            output = output + bias;
+        }
+
+        if let Some(activation) = activation {
+            let ab_scale = a_scale.cast_to_scalar::<f32>()? * b_scale.cast_to_scalar::<f32>()?;
+            output.mapv_inplace(|x| activation.eval_accumulator(x, ab_scale));
+        }
+
+        let ab_scale = a_scale.cast_to_scalar::<f32>()? * b_scale.cast_to_scalar::<f32>()?;
+        let scale =
+            c_scale.mapv(|c| Scaler::new(ab_scale / c, tract_linalg::mmm::RoundingPolicy::Even));
+        tract_ndarray::Zip::from(&mut output)
+            .and_broadcast(&scale)
+            .and_broadcast(&c0)
+            .for_each(|x, s, z| *x = *x * *s + z);
+        output
+    };
+
+    let unquantized = qp.unquantized();
+    if unquantized == i32::datum_type() {
+        return Ok(output.into_tensor().cast_to_dt(qp)?.into_owned());
+    }
+    match overflow {
+        QOverflow::Saturate => {
+            let min = unquantized.min_value().cast_to_scalar::<i32>()?;
+            let max = unquantized.max_value().cast_to_scalar::<i32>()?;
+            output.mapv_inplace(|x| x.clamp(min, max));
+            Ok(output.into_tensor().cast_to_dt(qp)?.into_owned())
+        }
+        // unquantized integer casts wrap, the quantized ones saturate
+        QOverflow::Wrap => {
+            let wrapped = output.into_tensor().cast_to_dt(unquantized)?.into_owned();
+            Ok(wrapped.cast_to_dt(qp)?.into_owned())
+        }
+    }
+}
+
+/// A scalar or vector input of the einsum, as an array of rank `rank` broadcastable to the
+/// output: a vector is laid out along the output axis it maps to.
+fn along_output<T: Datum + Copy>(
+    expr: &AxesMapping,
+    slot: usize,
+    t: &Tensor,
+    rank: usize,
+) -> TractResult<tract_ndarray::ArrayD<T>> {
+    let mut shape = tvec!(1; rank);
+    if t.rank() == 1 {
+        shape[expr.axis((InOut::In(slot), 0))?.outputs[0][0]] = t.len();
+    }
+    Ok(t.to_array_view::<T>()?.into_shape(&*shape)?.to_owned())
+}
+
+/// Contract `a - a0` and `b - b0` in Acc.
+fn centered_product<Acc: Datum + Copy + Zero + One + std::ops::SubAssign>(
+    expr: &AxesMapping,
+    nests: &LoopNestCache,
+    a: &Tensor,
+    a0: &Tensor,
+    b: &Tensor,
+    b0: &Tensor,
+) -> TractResult<tract_ndarray::ArrayD<Acc>> {
+    // zero points are scalars, or vectors along an axis of their operand (like per-row a0)
+    let centered = |t: &Tensor, slot: usize, zp: &Tensor, zp_slot: usize| -> TractResult<TValue> {
+        let mut t = t.cast_to::<Acc>()?.into_owned();
+        if zp.rank() == 0 {
+            let zp = zp.cast_to_scalar::<Acc>()?;
+            t.as_slice_mut::<Acc>()?.iter_mut().for_each(|x| *x -= zp);
+        } else {
+            let axis = expr.axis((InOut::In(zp_slot), 0))?.inputs[slot][0];
+            let zp = zp.cast_to::<Acc>()?;
+            let mut view = t.to_array_view_mut::<Acc>()?;
+            for (mut lane, zp) in view.axis_iter_mut(Axis(axis)).zip(zp.as_slice::<Acc>()?) {
+                lane.map_inplace(|x| *x -= *zp);
+            }
+        }
+        Ok(t.into_tvalue())
+    };
+    let (a, b) = (centered(a, 0, a0, 3)?, centered(b, 1, b0, 5)?);
+    eval_t::<Acc>(expr, nests, tvec!(a, b))?.into_array::<Acc>()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eval(expr: &AxesMapping, a: TValue, b: TValue) -> TractResult<Tensor> {
+        eval_t::<f32>(expr, &LoopNestCache::default(), tvec!(a, b))
+    }
+
+    /// A single operand fed twice is evaluated on one triangle of the `mirrored` output axes,
+    /// and gives the same output as two copies of it, evaluated in full.
+    fn check_gram(expr: &str, shape: &[usize], mirrored: (usize, usize)) -> TractResult<()> {
+        let expr: AxesMapping = expr.parse()?;
+        let len = shape.iter().product::<usize>();
+        let a = Tensor::from_shape(shape, &(0..len).map(|i| (i % 13) as f32 - 6.).collect_vec())?
+            .into_tvalue();
+        let copy = a.deep_clone().into_tvalue();
+        assert_eq!(symmetric_output_axes(&expr, &[a.clone(), copy.clone()]), None);
+        assert_eq!(symmetric_output_axes(&expr, &[a.clone(), a.clone()]), Some(mirrored));
+        assert_eq!(eval(&expr, a.clone(), copy)?, eval(&expr, a.clone(), a)?);
+        Ok(())
     }
 
-    output.mapv_inplace(|x| x * scale);
-    output.mapv_inplace(|x| x + c0);
+    #[test]
+    fn gram_matrix() -> TractResult<()> {
+        check_gram("ik,jk->ij", &[64, 32], (0, 1))
+    }
+
+    #[test]
+    fn gram_matrix_batched() -> TractResult<()> {
+        check_gram("bik,bjk->bij", &[3, 16, 8], (1, 2))
+    }
+
+    #[test]
+    fn gram_matrix_transposed_output() -> TractResult<()> {
+        check_gram("ki,kj->ji", &[8, 16], (0, 1))
+    }
+
+    #[test]
+    fn asymmetric_use_of_same_input() -> TractResult<()> {
+        let expr: AxesMapping = "ik,kj->ij".parse()?;
+        let a = tensor2(&[[1f32, 2.], [3., 4.]]).into_tvalue();
+        assert_eq!(symmetric_output_axes(&expr, &[a.clone(), a.clone()]), None);
+        assert_eq!(eval(&expr, a.clone(), a)?, tensor2(&[[7f32, 10.], [15., 22.]]));
+        Ok(())
+    }
+
+    #[test]
+    fn loop_nest_is_cached() -> TractResult<()> {
+        let op = super::super::EinSum::new("bik,bkj->bij".parse()?, f32::datum_type());
+        let operand = |shape: &[usize], seed: usize| {
+            let len = shape.iter().product::<usize>();
+            let data = (0..len).map(|i| ((i * seed) % 7) as f32 - 3.).collect_vec();
+            Tensor::from_shape(shape, &data).unwrap().into_tvalue()
+        };
+        // b is broadcast along the batch axis
+        let (a, b) = (operand(&[2, 3, 4], 3), operand(&[1, 4, 5], 5));
+        let mut expected = tract_ndarray::ArrayD::<f32>::zeros(vec![2, 3, 5]);
+        let (av, bv) = (a.to_array_view::<f32>()?, b.to_array_view::<f32>()?);
+        for (bt, i, k, j) in tract_data::itertools::iproduct!(0..2, 0..3, 0..4, 0..5) {
+            expected[[bt, i, j]] += av[[bt, i, k]] * bv[[0, k, j]];
+        }
+        let expected = expected.into_tensor();
+
+        // the nest cached by the op, as it is after its first evaluation
+        let cached =
+            |op: &super::super::EinSum, a: &[usize]| op.loop_nests.get(&op.axes, &[a, &[1, 4, 5]]);
+        op.eval(tvec!(a.clone(), b.clone()))?;
+        let nest = cached(&op, &[2, 3, 4])?;
+        for _ in 0..10_000 {
+            let output = op.eval(tvec!(a.clone(), b.clone()))?;
+            assert_eq!(*output[0], expected);
+        }
+        assert!(Arc::ptr_eq(&nest, &cached(&op, &[2, 3, 4])?));
+        let uncached = eval_t::<f32>(&op.axes, &LoopNestCache::default(), tvec!(a, b))?;
+        assert_eq!(uncached, expected);
 
-    if qp.unquantized() == i8::datum_type() {
-        output.mapv_inplace(|x| x.clamp(i8::MIN as _, i8::MAX as _))
-    } else if qp.unquantized() == u8::datum_type() {
-        output.mapv_inplace(|x| x.clamp(u8::MIN as _, u8::MAX as _))
+        // new shapes invalidate the nest (b still being broadcast, so this is no plain
+        // contraction)
+        op.eval(tvec!(operand(&[3, 3, 4], 3), operand(&[1, 4, 5], 5)))?;
+        let reshaped = cached(&op, &[3, 3, 4])?;
+        assert!(!Arc::ptr_eq(&nest, &reshaped));
+        assert_eq!(reshaped.input_shapes[0][..], [3, 3, 4]);
+        // clones start from an empty cache
+        let clone = op.clone();
+        assert!(!Arc::ptr_eq(&reshaped, &cached(&clone, &[3, 3, 4])?));
+        Ok(())
     }
-    Ok(output.into_tensor().cast_to_dt(qp)?.into_owned())
 }
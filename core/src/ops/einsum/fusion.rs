@@ -0,0 +1,189 @@
+//! Einsums fused with their neighbours: shared inputs, shared weights and following reductions.
+
+use super::*;
+use crate::ops::matmul::pack::MatMatMulPack;
+
+/// Projections of a [m, d] input by constant [d, d] weights, one einsum per name.
+fn projections_model(m: usize, d: usize, names: &[&str]) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let x = model.add_source("x", f32::fact([m, d]))?;
+    let mut outputs = tvec!();
+    for (ix, name) in names.iter().enumerate() {
+        let w = (0..d * d).map(|i| ((i * (ix + 3)) % 11) as f32 / 8. - 0.6).collect_vec();
+        let w = model.add_const(format!("{name}.w"), Tensor::from_shape(&[d, d], &w)?)?;
+        let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        outputs.push(model.wire_node(*name, op, &[x, w])?[0]);
+    }
+    model.set_output_outlets(&outputs)?;
+    Ok(model)
+}
+
+#[test]
+fn fuse_qkv_projections() -> TractResult<()> {
+    let (m, d) = (4, 768);
+    let model = projections_model(m, d, &["q", "k", "v"])?;
+    let optimized = model.clone().into_optimized()?;
+    let count = |f: &dyn Fn(&TypedNode) -> bool| optimized.nodes.iter().filter(|n| f(n)).count();
+    assert_eq!(count(&|n| n.op_is::<crate::ops::matmul::lir_unary::LirMatMulUnary>()), 1);
+    assert_eq!(count(&|n| n.op_is::<MatMatMulPack>()), 1);
+
+    let input =
+        Tensor::from_shape(&[m, d], &(0..m * d).map(|i| (i % 5) as f32 - 2.).collect_vec())?;
+    let reference = model.into_runnable()?.run(tvec!(input.clone().into_tvalue()))?;
+    let found = optimized.into_runnable()?.run(tvec!(input.into_tvalue()))?;
+    for (r, f) in reference.iter().zip(found.iter()) {
+        f.close_enough(r, Approximation::Approximate)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn fuse_shared_inputs_by_bounded_groups() -> TractResult<()> {
+    let model = projections_model(4, 32, &["a", "b", "c", "d", "e"])?;
+    let input = Tensor::from_shape(&[4, 32], &(0..128).map(|i| (i % 5) as f32).collect_vec())?;
+    let reference = model.clone().into_runnable()?.run(tvec!(input.clone().into_tvalue()))?;
+    for (fuse_shared_inputs, matmuls) in [
+        (FuseSharedInputs::default(), 2),
+        (FuseSharedInputs { max_group: 2 }, 3),
+        (FuseSharedInputs { max_group: 8 }, 1),
+        (FuseSharedInputs::disabled(), 5),
+    ] {
+        let options = OptimizerOptions { fuse_shared_inputs, ..OptimizerOptions::default() };
+        let optimized = model.clone().into_optimized_with_options(&options)?;
+        assert_eq!(count_matmuls(&optimized), matmuls, "{fuse_shared_inputs:?}");
+        let found = optimized.into_runnable()?.run(tvec!(input.clone().into_tvalue()))?;
+        for (r, f) in reference.iter().zip(found.iter()) {
+            f.close_enough(r, Approximation::Approximate)?;
+        }
+    }
+    Ok(())
+}
+
+/// `inputs` einsums "mk,kn->mn" of the same constant weights.
+fn shared_weights_model(inputs: &[TypedFact]) -> TractResult<TypedModel> {
+    let (k, n) = (32, 24);
+    let mut model = TypedModel::default();
+    let w = (0..k * n).map(|i| ((i * 7) % 13) as f32 / 8. - 0.7).collect_vec();
+    let w = model.add_const("w", Tensor::from_shape(&[k, n], &w)?)?;
+    let mut outputs = tvec!();
+    for (ix, fact) in inputs.iter().enumerate() {
+        let x = model.add_source(format!("x{ix}"), fact.clone())?;
+        let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        outputs.push(model.wire_node(format!("layer{ix}"), op, &[x, w])?[0]);
+    }
+    model.set_output_outlets(&outputs)?;
+    Ok(model)
+}
+
+fn count_matmuls(model: &TypedModel) -> usize {
+    model
+        .nodes
+        .iter()
+        .filter(|n| n.op_is::<crate::ops::matmul::lir_unary::LirMatMulUnary>())
+        .count()
+}
+
+#[test]
+fn batch_einsums_sharing_constant() -> TractResult<()> {
+    let model = shared_weights_model(&vec![f32::fact([4, 32]); 8])?;
+    assert_eq!(count_matmuls(&model.clone().into_optimized()?), 8);
+    let options =
+        OptimizerOptions { batch_shared_constant_einsums: true, ..OptimizerOptions::default() };
+    let optimized = model.clone().into_optimized_with_options(&options)?;
+    assert_eq!(count_matmuls(&optimized), 1);
+
+    let inputs: TVec<TValue> = (0..8)
+        .map(|ix| {
+            let x = (0..4 * 32).map(|i| ((i + ix * 3) % 9) as f32 - 4.).collect_vec();
+            Ok(Tensor::from_shape(&[4, 32], &x)?.into_tvalue())
+        })
+        .collect::<TractResult<_>>()?;
+    let reference = model.into_runnable()?.run(inputs.clone())?;
+    let found = optimized.into_runnable()?.run(inputs)?;
+    assert_eq!(found.len(), 8);
+    for (r, f) in reference.iter().zip(found.iter()) {
+        f.close_enough(r, Approximation::Approximate)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn batch_refuses_different_symbols() -> TractResult<()> {
+    let symbols = SymbolTable::default();
+    let facts = [
+        f32::fact(&[symbols.sym("S").to_dim(), 32.to_dim()]),
+        f32::fact(&[symbols.sym("T").to_dim(), 32.to_dim()]),
+    ];
+    let options =
+        OptimizerOptions { batch_shared_constant_einsums: true, ..OptimizerOptions::default() };
+    let optimized = shared_weights_model(&facts)?.into_optimized_with_options(&options)?;
+    assert_eq!(count_matmuls(&optimized), 2);
+    Ok(())
+}
+
+#[test]
+fn batch_refuses_dependent_einsums() -> TractResult<()> {
+    let mut model = TypedModel::default();
+    let w = (0..32 * 32).map(|i| ((i * 7) % 13) as f32 / 8. - 0.7).collect_vec();
+    let w = model.add_const("w", Tensor::from_shape(&[32, 32], &w)?)?;
+    let mut wire = model.add_source("x", f32::fact([4, 32]))?;
+    for ix in 0..3 {
+        let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        wire = model.wire_node(format!("layer{ix}"), op, &[wire, w])?[0];
+    }
+    model.set_output_outlets(&[wire])?;
+    let options =
+        OptimizerOptions { batch_shared_constant_einsums: true, ..OptimizerOptions::default() };
+    assert_eq!(count_matmuls(&model.into_optimized_with_options(&options)?), 3);
+    Ok(())
+}
+
+/// A product of b batches of m×k by k×n matrices, summed over `summed` output axes.
+fn summed_batch_model(b_shape: [usize; 3], summed: &[usize]) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", f32::fact([6, 3, 8]))?;
+    let b = model.add_source("b", f32::fact(b_shape))?;
+    let op = EinSum::new("bmk,bkn->bmn".parse()?, f32::datum_type());
+    let c = model.wire_node("einsum", op, &[a, b])?;
+    let reduce = ops::nn::Reduce::new(summed.into(), ops::nn::Reducer::Sum);
+    let c = model.wire_node("sum", reduce, &c)?;
+    model.set_output_outlets(&c)?;
+    Ok(model)
+}
+
+fn check_summed_batch(b_shape: [usize; 3], summed: &[usize], fused: bool) -> TractResult<()> {
+    let model = summed_batch_model(b_shape, summed)?;
+    let a = (0..144).map(|i| (i % 7) as f32 - 3.).collect_vec();
+    let a = Tensor::from_shape(&[6, 3, 8], &a)?;
+    let b_len = b_shape.iter().product::<usize>();
+    let b = (0..b_len).map(|i| (i % 5) as f32 / 2. - 1.).collect_vec();
+    let inputs = tvec!(a.into_tvalue(), Tensor::from_shape(&b_shape, &b)?.into_tvalue());
+    let expected = model.clone().into_runnable()?.run(inputs.clone())?.remove(0);
+    let decluttered = model.into_decluttered()?;
+    assert_eq!(!decluttered.nodes.iter().any(|n| n.op_is::<ops::nn::Reduce>()), fused);
+    let optimized = decluttered.into_optimized()?;
+    let unreduced = optimized
+        .nodes
+        .iter()
+        .flat_map(|n| &n.outputs)
+        .any(|o| o.fact.shape.as_concrete() == Some(&[6, 3, 5]));
+    assert_eq!(unreduced, !fused);
+    let found = optimized.into_runnable()?.run(inputs)?.remove(0);
+    found.close_enough(&expected, Approximation::Close)
+}
+
+#[test]
+fn summed_batch_axis_is_contracted() -> TractResult<()> {
+    check_summed_batch([6, 8, 5], &[0], true)
+}
+
+#[test]
+fn summed_axis_of_one_input_is_kept() -> TractResult<()> {
+    // n is not in A
+    check_summed_batch([6, 8, 5], &[2], false)
+}
+
+#[test]
+fn summed_broadcast_batch_axis_is_kept() -> TractResult<()> {
+    check_summed_batch([1, 8, 5], &[0], false)
+}
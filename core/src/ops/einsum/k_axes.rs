@@ -0,0 +1,92 @@
+//! Contraction axes split across reshapes, or split in chunks when long.
+
+use super::*;
+
+fn split_k_model(k1: TDim, k2: TDim) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", f32::fact(&[5.to_dim(), k1.clone(), k2.clone()]))?;
+    let k = k1.clone() * &k2;
+    let reshape = AxisOp::Reshape(1, tvec!(k1, k2), tvec!(k.clone()));
+    let a = model.wire_node("reshape", reshape, &[a])?;
+    let b = if let Ok(k) = k.to_usize() {
+        let b = (0..k * 3).map(|i| (i % 7) as f32 - 3.).collect_vec();
+        model.add_const("b", Tensor::from_shape(&[k, 3], &b)?)?
+    } else {
+        model.add_source("b", f32::fact(&[k, 3.into()]))?
+    };
+    let c = model.wire_node(
+        "einsum",
+        EinSum::new("mk,kn->mn".parse()?, f32::datum_type()),
+        &[a[0], b],
+    )?;
+    model.set_output_outlets(&c)?;
+    Ok(model)
+}
+
+#[test]
+fn absorb_reshape_splitting_k() -> TractResult<()> {
+    let model = split_k_model(8.to_dim(), 64.to_dim())?;
+    let a = (0..5 * 8 * 64).map(|i| (i % 5) as f32 / 4.).collect_vec();
+    let a = tvec!(Tensor::from_shape(&[5, 8, 64], &a)?.into_tvalue());
+    let expected = model.clone().into_runnable()?.run(a.clone())?.remove(0);
+    let decluttered = model.clone().into_decluttered()?;
+    assert!(decluttered.node_by_name("reshape").is_err());
+    assert!(decluttered.nodes.iter().all(|n| !n.op_is::<AxisOp>()));
+    let found = decluttered.clone().into_runnable()?.run(a.clone())?.remove(0);
+    found.close_enough(&expected, Approximation::Close)?;
+    let optimized = model.into_optimized()?;
+    assert!(optimized.node_by_name("reshape").is_err());
+    assert!(optimized.nodes.iter().all(|n| !n.op_is::<EinSum>()));
+    let found = optimized.into_runnable()?.run(a)?.remove(0);
+    found.close_enough(&expected, Approximation::Close)?;
+    Ok(())
+}
+
+#[test]
+fn keep_reshape_splitting_symbolic_k() -> TractResult<()> {
+    let s = SymbolTable::default().sym("S");
+    let decluttered = split_k_model(8.to_dim(), s.to_dim())?.into_decluttered()?;
+    assert!(decluttered.node_by_name("reshape").is_ok());
+    Ok(())
+}
+
+fn long_k_matmul(k: usize) -> TractResult<(TypedModel, TVec<TValue>)> {
+    let (m, n) = (5, 3);
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", f32::fact([m, k]))?;
+    let b = (0..k * n).map(|i| ((i * 7) % 19) as f32 / 8. - 1.).collect_vec();
+    let b = model.add_const("b", Tensor::from_shape(&[k, n], &b)?)?;
+    let c =
+        model.wire_node("einsum", EinSum::new("mk,kn->mn".parse()?, f32::datum_type()), &[a, b])?;
+    model.set_output_outlets(&c)?;
+    let a = (0..m * k).map(|i| ((i * 5) % 23) as f32 / 16. - 0.7).collect_vec();
+    Ok((model, tvec!(Tensor::from_shape(&[m, k], &a)?.into_tvalue())))
+}
+
+#[test]
+fn split_long_k() -> TractResult<()> {
+    use crate::ops::matmul::lir_unary::LirMatMulUnary;
+    let (model, inputs) = long_k_matmul(10_007)?;
+    let unsplit = model.clone().into_optimized()?.into_runnable()?.run(inputs.clone())?;
+    for parts in [2, 3, 4] {
+        let options = OptimizerOptions {
+            k_split: Some(KSplit { threshold: 4096, parts }),
+            ..OptimizerOptions::default()
+        };
+        let optimized = model.clone().into_optimized_with_options(&options)?;
+        let lirs = optimized.nodes().iter().filter(|n| n.op_is::<LirMatMulUnary>()).count();
+        assert_eq!(lirs, parts);
+        let plan = optimized.into_runnable()?;
+        let found = plan.run(inputs.clone())?;
+        assert_eq!(plan.run(inputs.clone())?, found);
+        found[0].close_enough(&unsplit[0], Approximation::Approximate)?;
+    }
+    // short enough
+    let options = OptimizerOptions {
+        k_split: Some(KSplit { threshold: 10_007, parts: 4 }),
+        ..OptimizerOptions::default()
+    };
+    let optimized = model.into_optimized_with_options(&options)?;
+    assert_eq!(optimized.nodes().iter().filter(|n| n.op_is::<LirMatMulUnary>()).count(), 1);
+    Ok(())
+}
@@ -0,0 +1,367 @@
+//! Lowering of einsums to matrix multiplication kernels.
+
+use super::*;
+use crate::ops::cast::cast;
+use crate::ops::matmul::pack::MatMatMulPack;
+
+#[test]
+fn lowering_plans_match_optimization() -> TractResult<()> {
+    use crate::ops::matmul::lir_unary::LirMatMulUnary;
+    use codegen::{LoweringPlan, LoweringStep};
+    let mut model = TypedModel::default();
+    let op =
+        |expr: &str, dt: DatumType| -> TractResult<EinSum> { Ok(EinSum::new(expr.parse()?, dt)) };
+    let f32_dt = f32::datum_type();
+    let w = (0..64 * 32).map(|i| ((i * 7) % 13) as f32 / 8. - 0.7).collect_vec();
+    let w = model.add_const("w", Tensor::from_shape(&[64, 32], &w)?)?;
+    let x = model.add_source("x", f32::fact([4, 64]))?;
+    let proj = model.wire_node("proj", op("mk,kn->mn", f32_dt)?, &[x, w])?[0];
+    let y = model.add_source("y", f32::fact([32, 8]))?;
+    let wide = model.wire_node("wide", op("mk,kn->mn", f32_dt)?, &[w, y])?;
+    let half = model.wire_node("half", cast(f16::datum_type()), &wide)?[0];
+    let v = model.add_source("v", f32::fact([32]))?;
+    let gemv = model.wire_node("gemv", op("mk,k->m", f32_dt)?, &[w, v])?[0];
+    let c = model.add_source("c", f32::fact([8, 2, 4]))?;
+    let d = model.add_source("d", f32::fact([4, 6, 2]))?;
+    let scattered = model.wire_node("scattered", op("mjk,knj->mn", f32_dt)?, &[c, d])?[0];
+    let e = model.add_source("e", i16::fact([8, 4]))?;
+    let f = model.add_source("f", i16::fact([4, 6]))?;
+    let int = model.wire_node("int", op("mk,kn->mn", i16::datum_type())?, &[e, f])?[0];
+    model.set_output_outlets(&[proj, half, gemv, scattered, int])?;
+    let model = model.into_decluttered()?;
+
+    let options = OptimizerOptions::default();
+    let plans: HashMap<String, LoweringPlan> =
+        model.lowering_plans(&options)?.into_iter().collect();
+    assert_eq!(plans.len(), 5);
+    assert!(plans["proj"].swap_operands);
+    assert!(!plans["wide"].swap_operands);
+    assert_eq!(plans["gemv"].step, LoweringStep::InjectAxis('n'));
+    // planning leaves the model as is
+    assert_eq!(model.nodes().iter().filter(|n| n.op_is::<EinSum>()).count(), 5);
+
+    let (optimized, report) = model.into_optimized_with_report(&options)?;
+    for name in ["proj", "wide", "gemv"] {
+        let lir = optimized.node_by_name(name)?.op_as::<LirMatMulUnary>().unwrap();
+        if let LoweringStep::Kernel(kernel) = &plans[name].step {
+            assert_eq!(lir.operands_swapped, plans[name].swap_operands);
+            assert_eq!(lir.mmm.kernel_name(), kernel.kernel);
+            let specs = lir.micro_ops.iter().map(|s| s.name()).collect_vec();
+            assert_eq!(specs, kernel.fused_specs);
+            assert_eq!(lir.c_fact.datum_type, kernel.folded_cast.unwrap_or(f32_dt));
+        } else {
+            assert_eq!(name, "gemv");
+        }
+    }
+    assert_eq!(report.declined.len(), 2);
+    for (name, reason) in &report.declined {
+        assert_eq!(plans[name].step, LoweringStep::Declined(*reason));
+    }
+    Ok(())
+}
+
+/// `x` multiplied by a 0/1 mask of `mask_shape` feeds the einsum, as A (with a const B) or as
+/// B (with a const A). The mask is a cast bool input or a const.
+fn check_masked_operand(mask_shape: [usize; 2], x_is_a: bool, dynamic: bool) -> TractResult<()> {
+    let (m, k, n) = (16, 32, 8);
+    let (x_shape, w_shape) = if x_is_a { ([m, k], [k, n]) } else { ([k, n], [m, k]) };
+    let mut model = TypedModel::default();
+    let x = model.add_source("x", f32::fact(x_shape))?;
+    let mask_len = mask_shape.iter().product::<usize>();
+    let mask_data: Vec<bool> = (0..mask_len).map(|i| (i * 7 + 3) % 5 > 1).collect();
+    let mask_data = Tensor::from_shape(&mask_shape, &mask_data)?;
+    let mask = if dynamic {
+        let mask = model.add_source("mask", bool::fact(mask_shape))?;
+        model.wire_node("mask.cast", cast(f32::datum_type()), &[mask])?[0]
+    } else {
+        model.add_const("mask", mask_data.cast_to::<f32>()?.into_owned())?
+    };
+    let masked = model.wire_node("masked", crate::ops::math::mul(), &[x, mask])?[0];
+    let w = (0..m * k).map(|i| ((i * 5) % 11) as f32 / 4. - 1.).collect_vec();
+    let w = model.add_const("w", Tensor::from_shape(&w_shape, &w[..w_shape[0] * w_shape[1]])?)?;
+    let inputs = if x_is_a { [masked, w] } else { [w, masked] };
+    let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+    let c = model.wire_node("einsum", op, &inputs)?;
+    model.set_output_outlets(&c)?;
+
+    // a small B would otherwise be read strided, without a pack node to fold the mask in
+    let b_packing = crate::ops::matmul::strided::BPacking::Packed;
+    let options = OptimizerOptions { b_packing, ..Default::default() };
+    let optimized = model.clone().into_optimized_with_options(&options)?;
+    assert!(optimized.nodes.iter().all(|n| !n.op_is::<crate::ops::binary::TypedBinOp>()));
+    let pack = optimized.nodes.iter().find_map(|n| n.op_as::<MatMatMulPack>()).unwrap();
+    assert!(pack.mask_axis.is_some());
+
+    let x_len = x_shape.iter().product::<usize>();
+    let x_data = (0..x_len).map(|i| (i % 13) as f32 - 6.).collect_vec();
+    let mut inputs = tvec!(Tensor::from_shape(&x_shape, &x_data)?.into_tvalue());
+    if dynamic {
+        inputs.push(mask_data.into_tvalue());
+    }
+    let reference = model.into_runnable()?.run(inputs.clone())?;
+    let found = optimized.into_runnable()?.run(inputs)?;
+    found[0].close_enough(&reference[0], Approximation::Approximate)
+}
+
+#[test]
+fn masked_rows_fold_into_pack_a() -> TractResult<()> {
+    check_masked_operand([16, 1], true, true)
+}
+
+#[test]
+fn masked_k_fold_into_pack_a() -> TractResult<()> {
+    check_masked_operand([1, 32], true, true)
+}
+
+#[test]
+fn masked_cols_fold_into_pack_b() -> TractResult<()> {
+    check_masked_operand([1, 8], false, true)
+}
+
+#[test]
+fn masked_k_fold_into_pack_b() -> TractResult<()> {
+    check_masked_operand([32, 1], false, true)
+}
+
+#[test]
+fn const_mask_folds_into_pack() -> TractResult<()> {
+    check_masked_operand([16, 1], true, false)
+}
+
+fn check_matrix_vector(m: usize, n: usize, expected_packs: usize) -> TractResult<()> {
+    let k = 64;
+    let mut model = TypedModel::default();
+    let a_data = (0..m * k).map(|i| (i % 9) as f32 - 4.).collect_vec();
+    let b_data = (0..k * n).map(|i| (i % 7) as f32 / 3. - 1.).collect_vec();
+    let a_data = Tensor::from_shape(&[m, k], &a_data)?;
+    let b_data = Tensor::from_shape(&[k, n], &b_data)?;
+    let mut inputs = tvec!();
+    let a = if m == 1 {
+        inputs.push(a_data.into_tvalue());
+        model.add_source("a", f32::fact([m, k]))?
+    } else {
+        model.add_const("a", a_data)?
+    };
+    let b = if n == 1 {
+        inputs.push(b_data.into_tvalue());
+        model.add_source("b", f32::fact([k, n]))?
+    } else {
+        model.add_const("b", b_data)?
+    };
+    let c =
+        model.wire_node("einsum", EinSum::new("mk,kn->mn".parse()?, f32::datum_type()), &[a, b])?;
+    model.set_output_outlets(&c)?;
+    let expected = model.clone().into_runnable()?.run(inputs.clone())?.remove(0);
+    let optimized = model.into_optimized()?;
+    let packs = optimized.nodes.iter().filter(|n| n.op_is::<MatMatMulPack>()).count();
+    assert_eq!(packs, expected_packs);
+    let found = optimized.into_runnable()?.run(inputs)?.remove(0);
+    found.close_enough(&expected, Approximation::Approximate)
+}
+
+#[test]
+fn matrix_vector_m_is_one() -> TractResult<()> {
+    check_matrix_vector(1, 33, 0)
+}
+
+#[test]
+fn matrix_vector_n_is_one() -> TractResult<()> {
+    check_matrix_vector(33, 1, 0)
+}
+
+#[test]
+fn matrix_vector_m_and_n_are_one() -> TractResult<()> {
+    check_matrix_vector(1, 1, 1)
+}
+
+#[test]
+fn matrix_vector_with_unit_axes() -> TractResult<()> {
+    // exporters write the vector as a single row, possibly broadcast along a batch axis:
+    // it still reaches the kernel as is
+    for (expr, a_shape, b_shape) in
+        [("mk,nk->mn", &[33, 64][..], &[1, 64][..]), ("bmk,bnk->bmn", &[3, 33, 64], &[1, 1, 64])]
+    {
+        let mut model = TypedModel::default();
+        let a_len = a_shape.iter().product::<usize>();
+        let a_data = (0..a_len).map(|i| (i % 9) as f32 - 4.).collect_vec();
+        let a = model.add_const("a", Tensor::from_shape(a_shape, &a_data)?)?;
+        let b = model.add_source("b", f32::fact(b_shape))?;
+        let c =
+            model.wire_node("einsum", EinSum::new(expr.parse()?, f32::datum_type()), &[a, b])?;
+        model.set_output_outlets(&c)?;
+        let b_data = (0..64).map(|i| (i % 7) as f32 / 3. - 1.).collect_vec();
+        let inputs = tvec!(Tensor::from_shape(b_shape, &b_data)?.into_tvalue());
+        let expected = model.clone().into_runnable()?.run(inputs.clone())?.remove(0);
+        let optimized = model.into_optimized()?;
+        let lir = optimized.node_by_name("einsum")?;
+        assert!(lir.op_is::<crate::ops::matmul::lir_unary::LirMatMulUnary>());
+        assert!(optimized.node(lir.inputs[1].node).op_is::<crate::ops::source::TypedSource>());
+        let packs = optimized.nodes.iter().filter(|n| n.op_is::<MatMatMulPack>()).count();
+        assert_eq!(packs, 0);
+        let found = optimized.into_runnable()?.run(inputs)?.remove(0);
+        found.close_enough(&expected, Approximation::Approximate)?;
+    }
+    Ok(())
+}
+
+/// m < n: the operands are swapped before lowering, making the original m the kernel n.
+fn swapped_matmul_with_epilogue(batched: bool) -> TractResult<TypedModel> {
+    let (m, k, n) = (3, 8, 16);
+    let mut model = TypedModel::default();
+    let (expr, a_shape, b_shape) = if batched {
+        ("bmk,bkn->bmn", vec![1, m, k], vec![1, k, n])
+    } else {
+        ("mk,kn->mn", vec![m, k], vec![k, n])
+    };
+    let a = model.add_source("a", f32::fact(&a_shape))?;
+    let b = model.add_source("b", f32::fact(&b_shape))?;
+    let mut c =
+        model.wire_node("einsum", EinSum::new(expr.parse()?, f32::datum_type()), &[a, b])?;
+    if batched {
+        c = model.wire_node("rm", AxisOp::Rm(0), &c)?;
+    }
+    let per_m = (0..m).map(|i| i as f32 - 1.5).collect_vec();
+    let per_m = model.add_const("per_m", Tensor::from_shape(&[m, 1], &per_m)?)?;
+    c = model.wire_node("bias", ops::math::add(), &[c[0], per_m])?;
+    let per_n = (0..n).map(|i| (i % 5) as f32 / 4.).collect_vec();
+    let per_n = model.add_const("per_n", Tensor::from_shape(&[1, n], &per_n)?)?;
+    c = model.wire_node("scale", ops::math::mul(), &[per_n, c[0]])?;
+    model.set_output_outlets(&c)?;
+    Ok(model)
+}
+
+#[test]
+fn swapped_operands_keep_epilogue_orientation() -> TractResult<()> {
+    use crate::ops::matmul::lir_unary::{LirMatMulUnary, ProtoFusedSpec};
+    use crate::ops::matmul::lowering::lowering_decisions;
+    use crate::optim::OptimizerOptions;
+    for batched in [false, true] {
+        let model = swapped_matmul_with_epilogue(batched)?;
+        let shape = |fact: &TypedFact| fact.shape.as_concrete().unwrap().to_vec();
+        let inputs = tvec!(
+            Tensor::from_shape(
+                &shape(model.input_fact(0)?),
+                &(0..24).map(|i| ((i * 7) % 11) as f32 - 5.).collect_vec(),
+            )?
+            .into_tvalue(),
+            Tensor::from_shape(
+                &shape(model.input_fact(1)?),
+                &(0..128).map(|i| ((i * 5) % 13) as f32 / 2. - 3.).collect_vec(),
+            )?
+            .into_tvalue(),
+        );
+        let reference = model.clone().into_runnable()?.run(inputs.clone())?.remove(0);
+        let options = OptimizerOptions { track_patches: true, ..OptimizerOptions::default() };
+        let optimized = model.into_optimized_with_options(&options)?;
+        assert!(lowering_decisions(&optimized)?[0].swapped);
+        let lir = optimized.nodes().iter().find_map(|n| n.op_as::<LirMatMulUnary>()).unwrap();
+        assert!(lir.operands_swapped);
+        // the kernel m is the einsum n, the per-m bias is applied per kernel column
+        assert!(lir.micro_ops.iter().any(|op| matches!(op, ProtoFusedSpec::BinPerCol(..))));
+        assert!(lir.micro_ops.iter().any(|op| matches!(op, ProtoFusedSpec::BinPerRow(..))));
+        let found = optimized.into_runnable()?.run(inputs)?.remove(0);
+        found.close_enough(&reference, Approximation::Close)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn swapped_operands_with_symbolic_m() -> TractResult<()> {
+    use crate::ops::matmul::lir_unary::LirMatMulUnary;
+    use crate::ops::matmul::lowering::lowering_decisions;
+    use crate::optim::OptimizerOptions;
+    let (batch, k, n) = (2, 8, 5);
+    let mut model = TypedModel::default();
+    let m = model.symbol_table.sym("M");
+    let a = model.add_source("a", f32::fact(dims!(batch, m, k)))?;
+    let b = model.add_source("b", f32::fact([batch, k, n]))?;
+    let op = EinSum::new("bmk,bkn->bmn".parse()?, f32::datum_type());
+    let c = model.wire_node("einsum", op, &[a, b])?;
+    model.set_output_outlets(&c)?;
+    let reference = model.clone().into_runnable()?;
+    let options = OptimizerOptions { track_patches: true, ..OptimizerOptions::default() };
+    let optimized = model.into_optimized_with_options(&options)?;
+    // the symbolic m orders before the concrete n: the operands are swapped
+    assert!(lowering_decisions(&optimized)?[0].swapped);
+    let lir = optimized.nodes().iter().find_map(|n| n.op_as::<LirMatMulUnary>()).unwrap();
+    assert!(lir.operands_swapped);
+    assert_eq!((lir.c_m_axis, lir.c_n_axis), (2, 1));
+    let optimized = optimized.into_runnable()?;
+    for m in [3, 17] {
+        let a = (0..batch * m * k).map(|i| ((i * 7) % 11) as f32 - 5.).collect_vec();
+        let b = (0..batch * k * n).map(|i| ((i * 5) % 13) as f32 / 2. - 3.).collect_vec();
+        let inputs = tvec!(
+            Tensor::from_shape(&[batch, m, k], &a)?.into_tvalue(),
+            Tensor::from_shape(&[batch, k, n], &b)?.into_tvalue()
+        );
+        let expected = reference.run(inputs.clone())?.remove(0);
+        let found = optimized.run(inputs)?.remove(0);
+        assert_eq!(found.shape(), &[batch, m, n]);
+        found.close_enough(&expected, Approximation::Close)?;
+    }
+    Ok(())
+}
+
+/// Check every relative order of the `batch` axes (sized 2, 3, 4...) in the operands and
+/// the output of a product against the einsum eval, lowered with operands packed in the
+/// model, packed by macro tiles in the op, with a constant B, and as a matrix-vector
+/// product.
+fn check_batch_axes_orders(batch: &str) -> TractResult<()> {
+    use crate::ops::matmul::tiling::MacroTiling;
+    let perms = batch.chars().permutations(batch.len()).map(String::from_iter).collect_vec();
+    let tiny_blocks = MacroTiling { min_packed_bytes: 0, a_block_bytes: 64, b_block_bytes: 64 };
+    let input = |shape: &[usize], seed: usize| -> TractResult<Tensor> {
+        let len = shape.iter().product::<usize>();
+        let data = (0..len).map(|i| ((i * 7 + seed) % 13) as f32 - 6.).collect_vec();
+        Tensor::from_shape(shape, &data)
+    };
+    for (a, b, c, n) in tract_itertools::iproduct!(&perms, &perms, &perms, [3, 1]) {
+        let expr = format!("{a}mk,{b}kn->{c}mn");
+        let size = |label: char| match label {
+            'm' => 5,
+            'k' => 7,
+            'n' => n,
+            _ => batch.find(label).unwrap() + 2,
+        };
+        let shape = |labels: String| labels.chars().map(size).collect_vec();
+        let (a_shape, b_shape) = (shape(format!("{a}mk")), shape(format!("{b}kn")));
+        let (a_value, b_value) = (input(&a_shape, 0)?, input(&b_shape, 5)?);
+        for const_b in [false, true] {
+            let mut model = TypedModel::default();
+            let a = model.add_source("a", f32::fact(&a_shape))?;
+            let b = if const_b {
+                model.add_const("b", b_value.clone())?
+            } else {
+                model.add_source("b", f32::fact(&b_shape))?
+            };
+            let op = EinSum::new(expr.parse()?, f32::datum_type());
+            let c = model.wire_node("einsum", op, &[a, b])?;
+            model.set_output_outlets(&c)?;
+            let mut inputs = tvec!(a_value.clone().into_tvalue());
+            if !const_b {
+                inputs.push(b_value.clone().into_tvalue());
+            }
+            let expected = model.clone().into_runnable()?.run(inputs.clone())?;
+            for macro_tiling in [MacroTiling::disabled(), tiny_blocks.clone()] {
+                let options = crate::optim::OptimizerOptions { macro_tiling, ..Default::default() };
+                let optimized = model.clone().into_optimized_with_options(&options)?;
+                ensure!(!optimized.nodes.iter().any(|n| n.op_is::<EinSum>()), "{expr}");
+                let found = optimized.into_runnable()?.run(inputs.clone())?;
+                found[0].close_enough(&expected[0], Approximation::Exact).with_context(|| {
+                    format!("{expr} n={n} const_b={const_b} {:?}", options.macro_tiling)
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn two_batch_axes_in_any_order() -> TractResult<()> {
+    check_batch_axes_orders("ab")
+}
+
+#[test]
+fn three_batch_axes_in_any_order() -> TractResult<()> {
+    check_batch_axes_orders("abc")
+}
@@ -1,35 +1,387 @@
 use std::fmt::Debug;
 
 use crate::internal::*;
+use crate::ops;
 use crate::ops::array::Slice;
+use crate::optim::report::RuleOutcome;
+use crate::optim::{OptimizerOptions, OptimizerSession};
 use crate::tract_data::itertools::Itertools;
 
+mod chain;
+mod contraction;
 mod eval;
+pub use eval::LoopNestCache;
 
 use super::array::TypedConcat;
 use super::math::add;
 mod as_matmul;
-mod codegen;
+pub mod calibration;
+pub mod codegen;
+pub mod dynamic_quant;
+pub mod operating_dt;
+pub mod reduced_precision;
 
+pub(crate) use codegen::SWAP_OPERANDS_PATCH;
+
+/// Node property asking declutter to run a quantized einsum in f32 on its dequantized operands
+/// (see `TypedModel::force_float_fallback`).
+pub const FLOAT_FALLBACK: &str = "einsum.float_fallback";
+
+/// Node property asking codegen to accumulate a f32 einsum in f64, its operands and output
+/// staying f32 (see `TypedModel::force_extended_accumulation`).
+pub const EXTENDED_ACCUMULATION: &str = "einsum.extended_accumulation";
+
+/// Contraction splitting of the einsums with a k longer than `threshold` in `parts` partial
+/// products.
+///
+/// Chunk boundaries only depend on k and `parts`, so a split einsum gives the same results from
+/// one run to the next. Quantized einsums are not split.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KSplit {
+    pub threshold: usize,
+    pub parts: usize,
+}
+
+impl KSplit {
+    /// One partial product per available core.
+    pub fn for_available_parallelism(threshold: usize) -> KSplit {
+        let parts = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        KSplit { threshold, parts }
+    }
+}
+
+/// Fusion of the einsums applying different constant weights to the same input (like the Q, K
+/// and V projections of an attention block) in a single one, by groups of at most `max_group`.
+///
+/// It is on by default: the shared input is then packed and swept once per group instead of
+/// once per einsum, for the same products. Groups of four cover the attention projections and
+/// the gates of a recurrent cell, while bounding the size of the concatenated weights and of the
+/// fused output the einsum outputs are sliced from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FuseSharedInputs {
+    pub max_group: usize,
+}
+
+impl Default for FuseSharedInputs {
+    fn default() -> FuseSharedInputs {
+        FuseSharedInputs { max_group: 4 }
+    }
+}
+
+impl FuseSharedInputs {
+    /// Never fuse.
+    pub fn disabled() -> FuseSharedInputs {
+        FuseSharedInputs { max_group: 1 }
+    }
+}
+
+#[cfg(test)]
+mod bool_inputs;
+#[cfg(test)]
+mod conformance;
+#[cfg(test)]
+mod declutter;
+#[cfg(test)]
+mod dequantize;
+#[cfg(test)]
+mod fusion;
+#[cfg(test)]
+mod k_axes;
+#[cfg(test)]
+mod lowering;
+#[cfg(test)]
+mod node_names;
+#[cfg(test)]
+mod precision;
 #[cfg(test)]
 mod proptest;
+#[cfg(test)]
+mod q_activation;
+#[cfg(test)]
+mod quantized;
+#[cfg(test)]
+mod shapes;
+#[cfg(test)]
+pub(crate) mod test_util;
 
-#[derive(Clone, Hash)]
+#[derive(Clone)]
 pub struct EinSum {
     pub axes: AxesMapping,
     pub operating_dt: DatumType,
     // if present, assume we're a binary op.
     // 9 inputs are: A,B,bias, A0,Ascale, B0,BScale, C0,Cscale
+    // bias is either i32 (on the accumulator grid) or float (in the real domain)
+    // a float output type stops after dequantization: c0 and c_scale are then ignored
     pub q_params: Option<DatumType>,
+    // quantized only: activation applied before requantization
+    pub q_activation: Option<QActivation>,
+    // quantized only: how the requantized accumulator is cast to an out-of-range output
+    pub q_overflow: QOverflow,
+    // set by codegen when it swaps A and B before lowering, so the lowered op knows its kernel
+    // m is the einsum n
+    pub operands_swapped: bool,
+    // partial product of a split contraction (see KSplit), not to be split again
+    pub k_split_part: bool,
+    // fused from einsums sharing an input (see FuseSharedInputs), not to be fused again
+    pub shared_input_fused: bool,
+    // reference evaluation loop nest, for the last input shapes
+    pub loop_nests: LoopNestCache,
+}
+
+/// Activation fused in a quantized einsum.
+///
+/// It is applied on the real value of the accumulator (after bias and zero point compensation)
+/// before requantization, so no precision is lost to an intermediate quantization.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QActivation {
+    Relu,
+    LeakyRelu(f32),
+    GeluApproximate,
+}
+
+impl Eq for QActivation {}
+
+#[allow(clippy::derived_hash_with_manual_eq)]
+impl std::hash::Hash for QActivation {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        if let QActivation::LeakyRelu(alpha) = self {
+            alpha.to_bits().hash(state)
+        }
+    }
+}
+
+/// Cast of the requantized accumulator to a narrower quantized output type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum QOverflow {
+    /// Out-of-range values are clamped to the output type bounds (like ONNX and TFLite).
+    #[default]
+    Saturate,
+    /// Out-of-range values keep their low-order bits, like a `as` cast.
+    Wrap,
+}
+
+impl QActivation {
+    pub fn eval(&self, x: f32) -> f32 {
+        match self {
+            QActivation::Relu => x.max(0.),
+            QActivation::LeakyRelu(alpha) => x * if x < 0. { *alpha } else { 1. },
+            QActivation::GeluApproximate => ops::nn::gelu_approximate_f32(x),
+        }
+    }
+
+    /// Apply the activation to an i32 accumulator worth `scale` each.
+    pub fn eval_accumulator(&self, acc: i32, scale: f32) -> i32 {
+        match self {
+            QActivation::Relu => acc.max(0),
+            _ => round_ties_to_even(self.eval(acc as f32 * scale) / scale) as i32,
+        }
+    }
+
+    /// Element-wise op computing the activation in f32, None for Relu which works in i32.
+    pub fn as_element_wise(&self) -> Option<ops::element_wise::ElementWiseOp> {
+        match self {
+            QActivation::Relu => None,
+            QActivation::LeakyRelu(alpha) => Some(ops::nn::leaky_relu(*alpha)),
+            QActivation::GeluApproximate => Some(ops::nn::gelu_approximate()),
+        }
+    }
+
+    fn from_node(model: &TypedModel, node: &TypedNode) -> TractResult<Option<QActivation>> {
+        if let Some(ew) = node.op_as::<ops::element_wise::ElementWiseOp>() {
+            if let Some(leaky) = ew.0.downcast_ref::<ops::nn::LeakyRelu>() {
+                return Ok(Some(QActivation::LeakyRelu(leaky.alpha)));
+            } else if ew.0.is::<ops::nn::GeluApproximate>() {
+                return Ok(Some(QActivation::GeluApproximate));
+            }
+        } else if let Some(bin) = node.op_as::<ops::binary::TypedBinOp>() {
+            if bin.0.is::<ops::math::Max>() {
+                let zero = node.inputs.iter().any(|i| {
+                    let uniform = model.outlet_fact(*i).ok().and_then(|f| f.uniform.as_ref());
+                    uniform.and_then(|u| u.cast_to_scalar::<f32>().ok()) == Some(0.)
+                });
+                if zero {
+                    return Ok(Some(QActivation::Relu));
+                }
+            }
+        }
+        Ok(None)
+    }
 }
 
 impl EinSum {
+    /// Bool inputs are contracted as 0/1 integers: a bool operating type is promoted to i32.
     pub fn new(axes: AxesMapping, operating_dt: DatumType) -> EinSum {
-        EinSum { axes, operating_dt, q_params: None }
+        EinSum {
+            axes,
+            operating_dt: Self::promote_operating_dt(operating_dt),
+            q_params: None,
+            q_activation: None,
+            q_overflow: QOverflow::Saturate,
+            operands_swapped: false,
+            k_split_part: false,
+            shared_input_fused: false,
+            loop_nests: Default::default(),
+        }
     }
 
     pub fn newq(axes: AxesMapping, operating_dt: DatumType, output_type: DatumType) -> EinSum {
-        EinSum { axes, operating_dt, q_params: Some(output_type) }
+        EinSum {
+            axes,
+            operating_dt: Self::promote_operating_dt(operating_dt),
+            q_params: Some(output_type),
+            q_activation: None,
+            q_overflow: QOverflow::Saturate,
+            operands_swapped: false,
+            k_split_part: false,
+            shared_input_fused: false,
+            loop_nests: Default::default(),
+        }
+    }
+
+    /// Same as `new`, checking the axes mapping is consistent with `input_count` wires.
+    pub fn try_new(
+        axes: AxesMapping,
+        operating_dt: DatumType,
+        input_count: usize,
+    ) -> TractResult<EinSum> {
+        let op = Self::new(axes, operating_dt);
+        op.check_input_count(input_count)?;
+        Ok(op)
+    }
+
+    /// Same as `newq`, checking the axes mapping has the nine inputs of a quantized einsum.
+    pub fn try_newq(
+        axes: AxesMapping,
+        operating_dt: DatumType,
+        output_type: DatumType,
+    ) -> TractResult<EinSum> {
+        let op = Self::newq(axes, operating_dt, output_type);
+        op.check_input_count(9)?;
+        Ok(op)
+    }
+
+    fn check_input_count(&self, input_count: usize) -> TractResult<()> {
+        ensure!(
+            self.axes.output_count() == 1,
+            "{} has {} outputs, expected one",
+            self.axes,
+            self.axes.output_count()
+        );
+        ensure!(
+            input_count == self.axes.input_count(),
+            "{} expects {} inputs, got {}",
+            self.axes,
+            self.axes.input_count(),
+            input_count
+        );
+        Ok(())
+    }
+
+    /// Check the input facts against the axes mapping: one fact per input, each with the rank
+    /// the mapping gives it.
+    pub fn check_input_ranks(&self, inputs: &[&TypedFact]) -> TractResult<()> {
+        self.check_input_count(inputs.len())?;
+        for (ix, fact) in inputs.iter().enumerate() {
+            let rank = self.axes.rank(InOut::In(ix));
+            ensure!(
+                fact.rank() == rank,
+                "Input #{ix} of {} has rank {}, expected {rank} ({fact:?})",
+                self.axes,
+                fact.rank()
+            );
+        }
+        Ok(())
+    }
+
+    /// Check the expression for what numpy rejects: output labels appear once. (Output labels
+    /// missing from the inputs are axes of 1, as axis changes leave them.)
+    fn check_expr(&self) -> TractResult<()> {
+        for axis in self.axes.iter_all_axes() {
+            ensure!(
+                axis.outputs[0].len() <= 1,
+                "{}: label {} appears more than once in output",
+                self.axes,
+                axis.repr
+            );
+        }
+        Ok(())
+    }
+
+    /// Check the known dimensions of each axis agree across its occurrences, dimensions of 1
+    /// broadcasting against the others.
+    fn check_input_dims(&self, inputs: &[&TypedFact]) -> TractResult<()> {
+        for (ix, axis) in self.axes.iter_all_axes().enumerate() {
+            let dims = axis
+                .inputs
+                .iter()
+                .zip(inputs)
+                .flat_map(|(positions, fact)| positions.iter().map(|p| &fact.shape[*p]));
+            crate::broadcast::broadcast_dim(ix, dims).with_context(|| {
+                format!("{}: inconsistent dimensions for axis {}", self.axes, axis.repr)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Check the output quantization parameters (c0 and c_scale) against the output shape: each
+    /// is a scalar, or a vector along an output axis (per-channel output quantization).
+    fn check_output_q_params(&self, inputs: &[&TypedFact], output: &[TDim]) -> TractResult<()> {
+        for (slot, param) in [(7, "c0"), (8, "c_scale")] {
+            let fact = inputs[slot];
+            if fact.rank() == 0 {
+                continue;
+            }
+            let axis = self.axes.axis((InOut::In(slot), 0))?;
+            ensure!(
+                fact.rank() == 1 && axis.outputs[0].len() == 1,
+                "{param} of {} must be a scalar or a vector along an output axis, got {fact:?}",
+                self.axes,
+            );
+            let dim = &output[axis.outputs[0][0]];
+            ensure!(
+                fact.shape[0].is_one() || &fact.shape[0] == dim,
+                "{param} of {} runs along output axis {} of length {dim}, got {fact:?}",
+                self.axes,
+                axis.repr,
+            );
+        }
+        Ok(())
+    }
+
+    /// Rewrites index the input shapes through the axes mapping: they leave the node alone if the
+    /// input ranks do not match it.
+    fn can_rewrite(&self, model: &TypedModel, node: &TypedNode) -> TractResult<bool> {
+        let input_facts: TVec<&TypedFact> =
+            node.inputs.iter().map(|i| model.outlet_fact(*i)).collect::<TractResult<_>>()?;
+        if let Err(e) = self.check_input_ranks(&input_facts) {
+            log::warn!("Leaving {node} as is: {e}");
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// A quantized einsum flagged with FLOAT_FALLBACK becomes a f32 einsum on the dequantized
+    /// operands, requantized to the same output type: its consumers are left unchanged.
+    fn declutter_float_fallback(
+        &self,
+        model: &TypedModel,
+        node: &TypedNode,
+    ) -> TractResult<Option<TypedModelPatch>> {
+        if !matches!(self.q_params, Some(qp) if !qp.is_float())
+            || model.node_property(node.id, FLOAT_FALLBACK).is_none()
+        {
+            return Ok(None);
+        }
+        codegen::float_fallback(self, model, node).map(Some)
+    }
+
+    fn promote_operating_dt(dt: DatumType) -> DatumType {
+        if dt == bool::datum_type() {
+            i32::datum_type()
+        } else {
+            dt
+        }
     }
 
     #[allow(unused_variables)]
@@ -172,6 +524,494 @@ impl EinSum {
         Ok(None)
     }
 
+    /// Absorb a Sum reducing output axes found in all the inputs, like a batch axis: they become
+    /// contraction axes, and the einsum no longer computes the unreduced output. Codegen merges
+    /// them with the other k axes. The reduced axes are added back as unit axes.
+    fn declutter_reduced_output(
+        &self,
+        model: &TypedModel,
+        node: &TypedNode,
+    ) -> TractResult<Option<TypedModelPatch>> {
+        if self.q_params.is_some() || self.k_split_part {
+            return Ok(None);
+        }
+        let Some(succ) = single_succ(model, node)? else { return Ok(None) };
+        let Some(reduce) = succ.op_as::<ops::nn::Reduce>() else { return Ok(None) };
+        if reduce.reducer != ops::nn::Reducer::Sum || reduce.axes.is_empty() {
+            return Ok(None);
+        }
+        let input_facts = model.node_input_facts(node.id)?;
+        let output_shape = &node.outputs[0].fact.shape;
+        let mut axes = self.axes.clone();
+        for &position in reduce.axes.iter().sorted().rev() {
+            let axis = self.axes.axis((InOut::Out(0), position))?;
+            let in_all_inputs = axis.inputs.iter().zip(&input_facts).all(|(positions, fact)| {
+                matches!(&**positions, &[p] if fact.shape[p] == output_shape[position])
+            });
+            if !in_all_inputs {
+                return Ok(None);
+            }
+            axes = axes.remove_output_axis(0, position)?;
+        }
+        let mut patch = TypedModelPatch::new("Contract reduced einsum output axes");
+        let inputs = node
+            .inputs
+            .iter()
+            .map(|i| patch.tap_model(model, *i))
+            .collect::<TractResult<TVec<_>>>()?;
+        let mut wire = patch.wire_node(&node.name, EinSum { axes, ..self.clone() }, &inputs)?;
+        for &position in reduce.axes.iter().sorted() {
+            wire = patch.wire_node(
+                format!("{}.keep_axis_{position}", node.name),
+                AxisOp::Add(position),
+                &wire,
+            )?;
+        }
+        patch.shunt_outside(model, succ.id.into(), wire[0])?;
+        Ok(Some(patch))
+    }
+
+    /// Absorb a Reshape merging several axes into a contraction axis of one of the inputs.
+    ///
+    /// The contraction is performed over the unmerged axes, and the other input is reshaped
+    /// accordingly (codegen merges them back in a single k axis).
+    fn declutter_split_k(
+        &self,
+        model: &TypedModel,
+        node: &TypedNode,
+    ) -> TractResult<Option<TypedModelPatch>> {
+        if self.q_params.is_some() || node.inputs.len() != 2 {
+            return Ok(None);
+        }
+        for (slot, input) in node.inputs.iter().enumerate() {
+            let precursor = model.node(input.node);
+            let Some(AxisOp::Reshape(at, from, to)) = precursor.op_as::<AxisOp>() else {
+                continue;
+            };
+            if to.len() != 1
+                || from.len() < 2
+                || model.outlet_successors(*input).len() != 1
+                || model.output_outlets()?.contains(input)
+                || from.iter().any(|d| d.to_usize().is_err())
+            {
+                continue;
+            }
+            let other = 1 - slot;
+            let axis = self.axes.axis((InOut::In(slot), *at))?;
+            if axis.outputs[0].len() > 0
+                || axis.inputs[slot].len() != 1
+                || axis.inputs[other].len() != 1
+                || model.outlet_fact(node.inputs[other])?.shape[axis.inputs[other][0]] != to[0]
+            {
+                continue;
+            }
+            let fresh: String = ('a'..)
+                .filter(|c| self.axes.iter_all_axes().all(|a| a.repr != *c))
+                .take(from.len() - 1)
+                .collect();
+            let split = format!("{}{}", axis.repr, fresh);
+            let (mut inputs, outputs) = self.axes.to_strs();
+            for input in &mut inputs {
+                *input = input.replace(axis.repr, &split);
+            }
+            let axes = AxesMapping::from_strs(&inputs, &outputs)?;
+            let mut patch =
+                TypedModelPatch::new(format!("Absorb reshape on contraction axis {}", axis.repr));
+            patch.dont_apply_twice = Some(format!("{}-absorb-reshape-{slot}", node.name));
+            let mut wires = tvec!(
+                patch.tap_model(model, node.inputs[0])?,
+                patch.tap_model(model, node.inputs[1])?
+            );
+            wires[slot] = patch.tap_model(model, precursor.inputs[0])?;
+            wires[other] = patch.wire_node(
+                format!("{}.split_{}", node.name, axis.repr),
+                AxisOp::Reshape(axis.inputs[other][0], to.clone(), from.clone()),
+                &[wires[other]],
+            )?[0];
+            let wire = patch.wire_node(&node.name, Self { axes, ..self.clone() }, &wires)?;
+            patch.shunt_outside(model, node.id.into(), wire[0])?;
+            return Ok(Some(patch));
+        }
+        Ok(None)
+    }
+
+    /// A single-input einsum without repeated axes is a permutation of its input axes, after
+    /// summing over the axes missing from the output: rewrite it to a Reduce::Sum followed by
+    /// plain AxisOps (or nothing at all).
+    fn declutter_single_input(
+        &self,
+        model: &TypedModel,
+        node: &TypedNode,
+    ) -> TractResult<Option<TypedModelPatch>> {
+        if self.q_params.is_some()
+            || node.inputs.len() != 1
+            || self.axes.iter_all_axes().any(|a| a.inputs[0].len() > 1 || a.outputs[0].len() > 1)
+        {
+            return Ok(None);
+        }
+        let mut patch = TypedModelPatch::new(format!("Single input einsum {}", self.axes));
+        let mut wire = patch.tap_model(model, node.inputs[0])?;
+        if model.outlet_fact(node.inputs[0])?.datum_type != self.operating_dt {
+            wire = patch.wire_node(
+                format!("{}.cast", node.name),
+                ops::cast::cast(self.operating_dt),
+                &[wire],
+            )?[0];
+        }
+        let summed: TVec<&Axis> =
+            self.axes.iter_all_axes().filter(|a| a.outputs[0].is_empty()).collect();
+        let mut axes = self.axes.clone();
+        if !summed.is_empty() {
+            let positions: TVec<usize> = summed.iter().map(|a| a.inputs[0][0]).sorted().collect();
+            wire = patch.wire_node(
+                format!("{}.sum", node.name),
+                ops::nn::Reduce::new(positions.clone(), ops::nn::Reducer::Sum),
+                &[wire],
+            )?[0];
+            for &position in positions.iter().rev() {
+                wire = patch.wire_node(
+                    format!("{}.rm_{position}", node.name),
+                    AxisOp::Rm(position),
+                    &[wire],
+                )?[0];
+            }
+            for axis in &summed {
+                axes = axes.remove_axis(axis.repr)?;
+            }
+        }
+        for (ix, op) in axes.translate_to_axis_ops()?.into_iter().enumerate() {
+            wire = patch.wire_node(format!("{}.axis_{ix}", node.name), op, &[wire])?[0];
+        }
+        patch.shunt_outside(model, node.id.into(), wire)?;
+        Ok(Some(patch))
+    }
+
+    /// A constant operand being exactly an identity matrix (possibly repeated along batch axes)
+    /// contracted with the other operand only renames the contracted axis: the einsum becomes a
+    /// single input one, itself decluttered to axis ops.
+    fn declutter_identity_operand(
+        &self,
+        model: &TypedModel,
+        node: &TypedNode,
+    ) -> TractResult<Option<TypedModelPatch>> {
+        if self.q_params.is_some() || node.inputs.len() != 2 {
+            return Ok(None);
+        }
+        let (inputs, outputs) = self.axes.to_strs();
+        for slot in 0..2 {
+            let Some(konst) = &model.outlet_fact(node.inputs[slot])?.konst else { continue };
+            let x_fact = model.outlet_fact(node.inputs[1 - slot])?;
+            let x: Vec<char> = inputs[1 - slot].chars().collect();
+            let id: Vec<char> = inputs[slot].chars().collect();
+            if id.iter().duplicates().next().is_some() {
+                continue;
+            }
+            let shape = konst.shape();
+            for (c, r) in (0..id.len()).permutations(2).map(|p| (p[0], p[1])) {
+                // c is contracted with x, r replaces it
+                let Some(x_c) = x.iter().position(|l| *l == id[c]) else { continue };
+                if shape[c] != shape[r]
+                    || x_fact.shape[x_c] != shape[c].to_dim()
+                    || x.contains(&id[r])
+                    || outputs[0].contains(id[c])
+                {
+                    continue;
+                }
+                // other axes are batch axes of x, or summed axes of 1
+                if (0..id.len()).filter(|ix| *ix != c && *ix != r).any(|ix| {
+                    !x.contains(&id[ix]) && (shape[ix] != 1 || outputs[0].contains(id[ix]))
+                }) {
+                    continue;
+                }
+                if !is_identity(konst, c, r)? {
+                    continue;
+                }
+                let renamed: String =
+                    x.iter().map(|l| if *l == id[c] { id[r] } else { *l }).collect();
+                let axes = AxesMapping::from_strs(&[renamed], &outputs)?;
+                let mut patch = TypedModelPatch::new(format!("Identity operand of {node}"));
+                let wire = patch.tap_model(model, node.inputs[1 - slot])?;
+                let wire = patch.wire_node(&node.name, EinSum { axes, ..self.clone() }, &[wire])?;
+                patch.shunt_outside(model, node.id.into(), wire[0])?;
+                return Ok(Some(patch));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Relabel the axes in their canonical order, so that alpha-renamed einsums (`ij,jk->ik`
+    /// and `ab,bc->ac`) end up identical.
+    fn declutter_canonical_labels(
+        &self,
+        model: &TypedModel,
+        node: &TypedNode,
+    ) -> TractResult<Option<TypedModelPatch>> {
+        let axes = self.axes.canonical();
+        if axes == self.axes {
+            return Ok(None);
+        }
+        let op = EinSum { axes, ..self.clone() };
+        TypedModelPatch::replace_single_op(model, node, &node.inputs, op).map(Some)
+    }
+
+    /// Absorb a dequantize, activation, quantize chain following a quantized einsum.
+    ///
+    /// The dequantization must undo the einsum requantization exactly (same constant scale and
+    /// zero point), the einsum then requantizes straight to the final quantization parameters.
+    fn declutter_fused_activation(
+        &self,
+        model: &TypedModel,
+        node: &TypedNode,
+    ) -> TractResult<Option<TypedModelPatch>> {
+        if self.q_activation.is_some() {
+            return Ok(None);
+        }
+        let Some(dequant) = self.output_dequantizer(model, node)? else { return Ok(None) };
+        let Some(activation) = single_succ(model, dequant)? else { return Ok(None) };
+        let Some(q_activation) = QActivation::from_node(model, activation)? else {
+            return Ok(None);
+        };
+        let Some(quant) = single_succ(model, activation)? else { return Ok(None) };
+        let Some(quant_op) = quant.op_as::<ops::element_wise::ElementWiseOp>() else {
+            return Ok(None);
+        };
+        let (c0, scale, dt) =
+            if let Some(q) = quant_op.0.downcast_ref::<ops::quant::QuantizeLinearI8>() {
+                (tensor0(q.zero_point), q.scale, i8::datum_type())
+            } else if let Some(q) = quant_op.0.downcast_ref::<ops::quant::QuantizeLinearU8>() {
+                (tensor0(q.zero_point), q.scale, u8::datum_type())
+            } else {
+                return Ok(None);
+            };
+        let mut patch =
+            TypedModelPatch::new(format!("Fuse {} in quantized einsum", activation.name));
+        let mut inputs = node.inputs[0..7]
+            .iter()
+            .map(|i| patch.tap_model(model, *i))
+            .collect::<TractResult<TVec<_>>>()?;
+        inputs.push(patch.add_const(format!("{}.c0", node.name), c0)?);
+        inputs.push(patch.add_const(format!("{}.c_scale", node.name), tensor0(scale.recip()))?);
+        let op = Self { q_params: Some(dt), q_activation: Some(q_activation), ..self.clone() };
+        let wire = patch.wire_node(&node.name, op, &inputs)?;
+        patch.shunt_outside(model, quant.id.into(), wire[0])?;
+        Ok(Some(patch))
+    }
+
+    /// The single successor of a quantized einsum node, if it is a DequantizeLinearF32
+    /// undoing the requantization (same c0 and c_scale).
+    fn output_dequantizer<'m>(
+        &self,
+        model: &'m TypedModel,
+        node: &TypedNode,
+    ) -> TractResult<Option<&'m TypedNode>> {
+        if !matches!(self.q_params, Some(qp) if !qp.is_float()) || node.inputs.len() != 9 {
+            return Ok(None);
+        }
+        let Some(dequant) = single_succ(model, node)? else { return Ok(None) };
+        let Some(dequant_op) = dequant.op_as::<ops::quant::DequantizeLinearF32>() else {
+            return Ok(None);
+        };
+        let c0 = &model.outlet_fact(node.inputs[7])?.konst;
+        let c_scale = &model.outlet_fact(node.inputs[8])?.konst;
+        let (Some(c0), Some(c_scale)) = (c0, c_scale) else { return Ok(None) };
+        if c0.len() != 1
+            || c_scale.len() != 1
+            || c0.cast_to_scalar::<i32>()? != dequant_op.zero_point
+            || c_scale.cast_to_scalar::<f32>()? != dequant_op.scale
+        {
+            return Ok(None);
+        }
+        Ok(Some(dequant))
+    }
+
+    /// A quantized einsum immediately dequantized (like ONNX QLinearMatMul followed by
+    /// DequantizeLinear) becomes a dequantize-only einsum, skipping the requantization rounding.
+    ///
+    /// Chains requantized further down are left to the quantization rewrites.
+    fn declutter_dequantized_output(
+        &self,
+        model: &TypedModel,
+        node: &TypedNode,
+    ) -> TractResult<Option<TypedModelPatch>> {
+        let Some(dequant) = self.output_dequantizer(model, node)? else { return Ok(None) };
+        let mut next = dequant;
+        while let Some(succ) = single_succ(model, next)? {
+            if let Some(ew) = succ.op_as::<ops::element_wise::ElementWiseOp>() {
+                if ew.0.is::<ops::quant::QuantizeLinearI8>()
+                    || ew.0.is::<ops::quant::QuantizeLinearU8>()
+                {
+                    return Ok(None);
+                }
+            }
+            next = succ;
+        }
+        let mut patch = TypedModelPatch::new("Dequantize-only einsum");
+        let inputs = node
+            .inputs
+            .iter()
+            .map(|i| patch.tap_model(model, *i))
+            .collect::<TractResult<TVec<_>>>()?;
+        let op = Self { q_params: Some(f32::datum_type()), ..self.clone() };
+        let wire = patch.wire_node(&node.name, op, &inputs)?;
+        patch.shunt_outside(model, dequant.id.into(), wire[0])?;
+        Ok(Some(patch))
+    }
+
+    /// A dequantize-only einsum multiplied or divided by a constant scalar takes the scalar in
+    /// its a scale, and in its bias if it is a float one. The real domain scale is computed
+    /// before rounding anything, and a power of two scale is exact.
+    fn declutter_output_scalar(
+        &self,
+        model: &TypedModel,
+        node: &TypedNode,
+    ) -> TractResult<Option<TypedModelPatch>> {
+        if !matches!(self.q_params, Some(qp) if qp.is_float())
+            || self.q_activation.is_some()
+            || node.inputs.len() != 9
+        {
+            return Ok(None);
+        }
+        let Some(succ) = single_succ(model, node)? else { return Ok(None) };
+        let Some(bin) = succ.op_as::<ops::binary::TypedBinOp>() else { return Ok(None) };
+        let slot = succ.inputs.iter().position(|i| i.node == node.id).unwrap();
+        let is_div = bin.0.is::<ops::math::Div>();
+        if !(bin.0.is::<ops::math::Mul>() || is_div && slot == 0)
+            || succ.outputs[0].fact != node.outputs[0].fact
+        {
+            return Ok(None);
+        }
+        let Some(scalar) = &model.outlet_fact(succ.inputs[1 - slot])?.konst else {
+            return Ok(None);
+        };
+        if scalar.len() != 1 || !scalar.datum_type().is_float() {
+            return Ok(None);
+        }
+        let scalar = scalar.cast_to_scalar::<f32>()?;
+        let scalar = if is_div { scalar.recip() } else { scalar };
+
+        let mut patch = TypedModelPatch::new("Fold scalar in dequantize-only einsum");
+        let mut inputs = node
+            .inputs
+            .iter()
+            .map(|i| patch.tap_model(model, *i))
+            .collect::<TractResult<TVec<_>>>()?;
+        let scalar = patch.add_const(format!("{}.scalar", node.name), tensor0(scalar))?;
+        let mut scaled = |slot: usize, name: &str| -> TractResult<()> {
+            let prefix = format!("{}.{name}", node.name);
+            let wires = [inputs[slot], scalar];
+            let mul = ops::math::mul();
+            inputs[slot] =
+                ops::binary::wire_with_rank_broadcast(&prefix, &mut patch, mul, &wires)?[0];
+            Ok(())
+        };
+        scaled(4, "scaled_a_scale")?;
+        // an integer bias lives on the accumulator grid, it follows a_scale
+        if model.outlet_fact(node.inputs[2])?.datum_type.is_float() {
+            scaled(2, "scaled_bias")?;
+        }
+        let wire = patch.wire_node(&node.name, self.clone(), &inputs)?;
+        patch.shunt_outside(model, succ.id.into(), wire[0])?;
+        Ok(Some(patch))
+    }
+
+    /// The scalar factor applied by an operand producer only feeding this einsum: a Neg, or a
+    /// multiplication or division by a constant scalar leaving the operand fact unchanged.
+    fn operand_scalar(
+        model: &TypedModel,
+        node: &TypedNode,
+        outlet: OutletId,
+    ) -> TractResult<Option<(OutletId, f64)>> {
+        let prec = model.node(outlet.node);
+        if single_succ(model, prec)?.map(|succ| succ.id) != Some(node.id) {
+            return Ok(None);
+        }
+        let facts = model.node_input_facts(prec.id)?;
+        let output_fact = &prec.outputs[0].fact;
+        let same_fact = |slot: usize| {
+            facts[slot].datum_type == output_fact.datum_type
+                && facts[slot].shape == output_fact.shape
+        };
+        if let Some(ew) = prec.op_as::<ops::element_wise::ElementWiseOp>() {
+            if ew.0.is::<ops::math::Neg>() && same_fact(0) {
+                return Ok(Some((prec.inputs[0], -1.0)));
+            }
+        } else if let Some(bin) = prec.op_as::<ops::binary::TypedBinOp>() {
+            let is_div = bin.0.is::<ops::math::Div>();
+            if !bin.0.is::<ops::math::Mul>() && !is_div {
+                return Ok(None);
+            }
+            for slot in 0..2 {
+                let Some(scalar) = &facts[1 - slot].konst else { continue };
+                if scalar.len() != 1
+                    || !scalar.datum_type().is_float()
+                    || !same_fact(slot)
+                    || is_div && slot != 0
+                {
+                    continue;
+                }
+                let scalar = scalar.cast_to_scalar::<f64>()?;
+                let scalar = if is_div { scalar.recip() } else { scalar };
+                return Ok(Some((prec.inputs[slot], scalar)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Negated or scaled float operands are tapped before their scaling: the factor is folded
+    /// in the other operand if it is a constant only used here, or applied once to the output,
+    /// where codegen fuses it in the kernel.
+    fn declutter_operand_scalar(
+        &self,
+        model: &TypedModel,
+        node: &TypedNode,
+    ) -> TractResult<Option<TypedModelPatch>> {
+        if self.q_params.is_some() || !self.operating_dt.is_float() || node.inputs.len() != 2 {
+            return Ok(None);
+        }
+        let mut factor = 1.0f64;
+        let mut operands = node.inputs.clone();
+        for (operand, input) in operands.iter_mut().zip(&node.inputs) {
+            if let Some((outlet, scalar)) = Self::operand_scalar(model, node, *input)? {
+                *operand = outlet;
+                factor *= scalar;
+            }
+        }
+        if operands == node.inputs {
+            return Ok(None);
+        }
+        let mut patch = TypedModelPatch::new(format!("Fold operand scalars in {node}"));
+        let mut inputs = operands
+            .iter()
+            .map(|i| patch.tap_model(model, *i))
+            .collect::<TractResult<TVec<_>>>()?;
+        for slot in 0..2 {
+            if factor == 1.0 || operands[slot] != node.inputs[slot] {
+                continue;
+            }
+            let Some(konst) = &model.outlet_fact(operands[slot])?.konst else { continue };
+            let users = &model.node(operands[slot].node).outputs[operands[slot].slot].successors;
+            if users.len() != 1 || !konst.datum_type().is_float() {
+                continue;
+            }
+            let scaled = konst.cast_to::<f64>()?.to_array_view::<f64>()?.mapv(|x| x * factor);
+            let scaled = scaled.into_tensor().cast_to_dt(konst.datum_type())?.into_owned();
+            let name = format!("{}.scaled_{}", node.name, ["a", "b"][slot]);
+            inputs[slot] = patch.add_const(name, scaled)?;
+            factor = 1.0;
+        }
+        let mut wire = patch.wire_node(&node.name, self.clone(), &inputs)?;
+        if factor != 1.0 {
+            let dt = node.outputs[0].fact.datum_type;
+            let scalar = tensor0(factor).cast_to_dt(dt)?.into_owned();
+            let scalar = patch.add_const(format!("{}.factor", node.name), scalar)?;
+            let name = format!("{}.scaled", node.name);
+            let (mul, wires) = (ops::math::mul(), [wire[0], scalar]);
+            wire = ops::binary::wire_with_rank_broadcast(&name, &mut patch, mul, &wires)?;
+        }
+        patch.shunt_outside(model, node.id.into(), wire[0])?;
+        Ok(Some(patch))
+    }
+
     pub fn decompose_in_legacy_ops(
         &self,
         model: &TypedModel,
@@ -181,6 +1021,24 @@ impl EinSum {
     }
 }
 
+fn single_succ<'m>(model: &'m TypedModel, node: &TypedNode) -> TractResult<Option<&'m TypedNode>> {
+    if node.outputs[0].successors.len() != 1 || model.output_outlets()?.contains(&node.id.into()) {
+        return Ok(None);
+    }
+    Ok(Some(model.node(node.outputs[0].successors[0].node)))
+}
+
+// consistent with same_as: mappings only differing by their labels hash the same
+impl std::hash::Hash for EinSum {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.axes.canonical().hash(state);
+        self.operating_dt.hash(state);
+        self.q_params.hash(state);
+        self.q_activation.hash(state);
+        self.q_overflow.hash(state);
+    }
+}
+
 impl Debug for EinSum {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "EinSum {} ({:?})", self.axes, self.operating_dt)
@@ -197,10 +1055,28 @@ impl Op for EinSum {
         if let Some(qp) = self.q_params {
             info.push(format!("Quantized output: {qp:?}"));
         }
+        if let Some(act) = self.q_activation {
+            info.push(format!("Fused activation: {act:?}"));
+        }
+        if self.q_params.is_some() && self.q_overflow != QOverflow::Saturate {
+            info.push(format!("Output overflow: {:?}", self.q_overflow));
+        }
+        if self.operands_swapped {
+            info.push("Operands swapped for lowering".to_string());
+        }
         Ok(info)
     }
 
     op_as_typed_op!();
+
+    fn same_as(&self, other: &dyn Op) -> bool {
+        let Some(other) = other.downcast_ref::<Self>() else { return false };
+        self.operating_dt == other.operating_dt
+            && self.q_params == other.q_params
+            && self.q_activation == other.q_activation
+            && self.q_overflow == other.q_overflow
+            && self.axes.canonical() == other.axes.canonical()
+    }
 }
 
 impl EvalOp for EinSum {
@@ -209,10 +1085,26 @@ impl EvalOp for EinSum {
     }
 
     fn eval(&self, inputs: TVec<TValue>) -> TractResult<TVec<TValue>> {
+        self.check_input_count(inputs.len())?;
         let output = if let Some(qp) = self.q_params {
-            eval::eval_q(&self.axes, qp, inputs)
+            eval::eval_q(
+                &self.axes,
+                &self.loop_nests,
+                qp,
+                self.q_activation,
+                self.q_overflow,
+                inputs,
+            )
         } else {
-            dispatch_numbers!(eval::eval_t(self.operating_dt)(&self.axes, inputs))
+            let shapes = inputs.iter().map(|t| t.shape());
+            let plan = contraction::Contraction::new(&self.axes, &shapes.collect::<TVec<_>>());
+            let dt = self.operating_dt;
+            // pure contractions run as batched matrix products, the loop nest takes the rest
+            if let Some(plan) = plan {
+                dispatch_numbers!(contraction::Contraction::eval(dt)(&plan, &inputs))
+            } else {
+                dispatch_numbers!(eval::eval_t(dt)(&self.axes, &self.loop_nests, inputs))
+            }
         }?;
         Ok(tvec!(output.into_tvalue()))
     }
@@ -220,19 +1112,24 @@ impl EvalOp for EinSum {
 
 impl TypedOp for EinSum {
     fn output_facts(&self, inputs: &[&TypedFact]) -> TractResult<TVec<TypedFact>> {
-        ensure!(inputs.len() == self.axes.input_count());
-        ensure!(inputs
-            .iter()
-            .enumerate()
-            .all(|(ix, fact)| fact.rank() == self.axes.rank(InOut::In(ix))));
+        ensure!(
+            self.operating_dt != bool::datum_type(),
+            "EinSum can not operate on bool, use EinSum::new to promote bool inputs to i32"
+        );
+        self.check_expr()?;
+        self.check_input_ranks(inputs)?;
         let shapes: TVec<&[TDim]> = inputs.iter().map(|t| &*t.shape).collect();
         if let Some(qp) = self.q_params {
             ensure!(inputs.len() == 9);
-            Ok(tvec!(qp.fact(eval::output_shape(&self.axes, &shapes[0..2]))))
+            let shape = eval::output_shape(&self.axes, &shapes[0..2])?;
+            self.check_output_q_params(inputs, &shape)?;
+            self.check_input_dims(inputs)?;
+            Ok(tvec!(qp.fact(shape)))
         } else {
+            self.check_input_dims(inputs)?;
             Ok(tvec!(TypedFact::dt_shape(
                 self.operating_dt,
-                eval::output_shape(&self.axes, &shapes)
+                eval::output_shape(&self.axes, &shapes)?
             )))
         }
     }
@@ -247,7 +1144,7 @@ impl TypedOp for EinSum {
 
     fn cost(&self, inputs: &[&TypedFact]) -> TractResult<TVec<(Cost, TDim)>> {
         let shapes: TVec<&[TDim]> = inputs.iter().map(|t| &*t.shape).collect();
-        let oshape = eval::output_shape(&self.axes, &shapes);
+        let oshape = eval::output_shape(&self.axes, &shapes)?;
         let ks = self
             .axes
             .iter_all_axes()
@@ -281,6 +1178,38 @@ impl TypedOp for EinSum {
         patch.wire_node(prefix, self.clone(), inputs).map(Some)
     }
 
+    fn select_rows(
+        &self,
+        patch: &mut TypedModelPatch,
+        model: &TypedModel,
+        node: &TypedNode,
+        output_axis: usize,
+        rows: OutletId,
+    ) -> TractResult<Option<TVec<OutletId>>> {
+        let axis = self.axes.axis((InOut::Out(0), output_axis))?;
+        let mut inputs = tvec!();
+        let mut gathered = false;
+        for (ix, input) in node.inputs.iter().enumerate() {
+            let mut wire = patch.tap_model(model, *input)?;
+            match &*axis.inputs[ix] {
+                [] => (),
+                // broadcast along the axis
+                &[pos] if model.outlet_fact(*input)?.shape[pos].is_one() => (),
+                &[pos] => {
+                    let name = format!("{}.rows.{ix}", node.name);
+                    wire = patch.wire_node(name, ops::array::Gather::new(pos), &[wire, rows])?[0];
+                    gathered = true;
+                }
+                _ => return Ok(None),
+            }
+            inputs.push(wire);
+        }
+        if !gathered {
+            return Ok(None);
+        }
+        patch.wire_node(&node.name, self.clone(), &inputs).map(Some)
+    }
+
     #[allow(unused_variables)]
     fn change_axes(
         &self,
@@ -314,12 +1243,75 @@ impl TypedOp for EinSum {
         }))
     }
 
+    fn declutter_with_session(
+        &self,
+        session: &mut OptimizerSession,
+        model: &TypedModel,
+        node: &TypedNode,
+    ) -> TractResult<Option<TypedModelPatch>> {
+        if codegen::is_large_constant(model, node, session.options())? {
+            return Ok(None);
+        }
+        self.declutter(model, node)
+    }
+
     fn declutter(
         &self,
         model: &TypedModel,
         node: &TypedNode,
     ) -> TractResult<Option<TypedModelPatch>> {
-        self.declutter_after_concat(model, node)
+        if !self.can_rewrite(model, node)? {
+            return Ok(None);
+        }
+        if let Some(patch) = self.declutter_float_fallback(model, node)? {
+            return Ok(Some(patch));
+        }
+        if let Some(patch) = self.declutter_single_input(model, node)? {
+            return Ok(Some(patch));
+        }
+        if let Some(patch) = self.declutter_identity_operand(model, node)? {
+            return Ok(Some(patch));
+        }
+        if let Some(patch) = self.declutter_after_concat(model, node)? {
+            return Ok(Some(patch));
+        }
+        if let Some(patch) = self.declutter_fused_activation(model, node)? {
+            return Ok(Some(patch));
+        }
+        if let Some(patch) = self.declutter_dequantized_output(model, node)? {
+            return Ok(Some(patch));
+        }
+        if let Some(patch) = self.declutter_output_scalar(model, node)? {
+            return Ok(Some(patch));
+        }
+        if let Some(patch) = self.declutter_operand_scalar(model, node)? {
+            return Ok(Some(patch));
+        }
+        if let Some(patch) = self.declutter_reduced_output(model, node)? {
+            return Ok(Some(patch));
+        }
+        if let Some(patch) = self.declutter_split_k(model, node)? {
+            return Ok(Some(patch));
+        }
+        if let Some(patch) = chain::declutter_chained_einsums(self, model, node)? {
+            return Ok(Some(patch));
+        }
+        self.declutter_canonical_labels(model, node)
+    }
+
+    fn codegen_with_session(
+        &self,
+        session: &mut OptimizerSession,
+        model: &TypedModel,
+        node: &TypedNode,
+    ) -> TractResult<Option<TypedModelPatch>> {
+        let outcome = codegen::codegen_or_decline(self, model, node, session.options())?;
+        let record = match &outcome {
+            Ok(patch) => RuleOutcome::Patched(patch.context.join(" >> ")),
+            Err(reason) => RuleOutcome::Declined(*reason),
+        };
+        session.report_mut().record("einsum codegen", &node.name, record);
+        Ok(outcome.ok())
     }
 
     fn codegen(
@@ -327,8 +1319,25 @@ impl TypedOp for EinSum {
         model: &TypedModel,
         node: &TypedNode,
     ) -> TractResult<Option<TypedModelPatch>> {
-        codegen::codegen(self, model, node)
+        codegen::codegen(self, model, node, &OptimizerOptions::default())
     }
 
     as_op!();
 }
+
+/// Is `t` exactly one on the diagonal of its axes `c` and `r`, zero elsewhere ?
+fn is_identity(t: &Tensor, c: usize, r: usize) -> TractResult<bool> {
+    let dt = t.datum_type();
+    if dt.is_quantized() || !dt.is_float() && !dt.is_integer() {
+        return Ok(false);
+    }
+    // compared in place: a large constant is not worth a f64 copy
+    fn is_identity_t<T>(t: &Tensor, c: usize, r: usize) -> TractResult<bool>
+    where
+        T: Datum + tract_num_traits::AsPrimitive<f64>,
+    {
+        let view = t.to_array_view::<T>()?;
+        Ok(view.indexed_iter().all(|(ix, v)| v.as_() == if ix[c] == ix[r] { 1.0 } else { 0.0 }))
+    }
+    dispatch_numbers!(is_identity_t(dt)(t, c, r))
+}
@@ -0,0 +1,91 @@
+//! Names and metadata of the nodes wired by the codegen patches.
+
+use super::*;
+
+fn two_layer_model() -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let mut wire = model.add_source("input", f32::fact([8]))?;
+    for (layer, (k, n)) in [(8, 16), (16, 4)].into_iter().enumerate() {
+        let w = (0..k * n).map(|i| (i % 5) as f32 - 2.).collect_vec();
+        let w = model.add_const(format!("layer{layer}.w"), Tensor::from_shape(&[k, n], &w)?)?;
+        wire = model.wire_node(
+            format!("layer{layer}.matmul"),
+            EinSum::new("k,kn->n".parse()?, f32::datum_type()),
+            &[wire, w],
+        )?[0];
+    }
+    model.set_output_outlets(&[wire])?;
+    Ok(model)
+}
+
+/// Two einsums of the same input, the second one named as if nested in the first one.
+fn sibling_einsums_model() -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let input = model.add_source("input", f32::fact([8]))?;
+    let mut outputs = tvec!();
+    for name in ["x", "x.einsum"] {
+        let w = (0..8 * 16).map(|i| (i % 5) as f32 - 2.).collect_vec();
+        let w = model.add_const(format!("{name}.w"), Tensor::from_shape(&[8, 16], &w)?)?;
+        let op = EinSum::new("k,kn->n".parse()?, f32::datum_type());
+        outputs.push(model.wire_node(name, op, &[input, w])?[0]);
+    }
+    model.set_output_outlets(&outputs)?;
+    Ok(model)
+}
+
+#[test]
+fn codegen_node_names_are_stable() -> TractResult<()> {
+    let names = |model: TypedModel| -> TractResult<Vec<String>> {
+        Ok(model.into_optimized()?.nodes.iter().map(|n| n.name.clone()).sorted().collect())
+    };
+    let first = names(two_layer_model()?)?;
+    let second = names(two_layer_model()?)?;
+    assert_eq!(first, second);
+    assert!(first.iter().all_unique());
+    assert!(first.iter().all(|n| !n.contains(')') && !n.contains("..")));
+    assert!(first.iter().all(|n| !n.contains(&codegen::INNER_EINSUM_SUFFIX.repeat(2))));
+    let siblings = names(sibling_einsums_model()?)?;
+    assert!(siblings.iter().all_unique(), "{siblings:?}");
+    Ok(())
+}
+
+#[test]
+fn codegen_keeps_node_metadata() -> TractResult<()> {
+    use crate::ops::matmul::lir_unary::LirMatMulUnary;
+    // m < n: operands are swapped before lowering. m,n->mn also needs a k axis injected.
+    let cases: [(&str, &[usize], &[usize]); 2] =
+        [("mk,kn->mn", &[2, 8], &[8, 16]), ("m,n->mn", &[2], &[16])];
+    for (expr, a_shape, b_shape) in cases {
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", f32::fact(a_shape))?;
+        let b = model.add_source("b", f32::fact(b_shape))?;
+        let op = EinSum::new(expr.parse()?, f32::datum_type());
+        let c = model.wire_node("layer3.proj", op, &[a, b])?;
+        model.set_output_outlets(&c)?;
+        model.set_outlet_label(c[0], "logits".to_string())?;
+        model.set_node_property(c[0].node, "source_layer", rctensor0(3i64))?;
+        let optimized = model.into_optimized()?;
+        let lir = optimized.nodes.iter().find(|n| n.op_is::<LirMatMulUnary>()).unwrap();
+        assert_eq!(lir.name, "layer3.proj");
+        assert_eq!(
+            optimized.node_property(lir.id, "source_layer"),
+            Some(&rctensor0(3i64)),
+            "{expr}"
+        );
+        let labelled = optimized.find_outlet_label("logits").unwrap();
+        assert_eq!(labelled, optimized.output_outlets()?[0]);
+        assert!(optimized.node_property(labelled.node, "source_layer").is_some());
+    }
+    Ok(())
+}
+
+#[test]
+fn nested_einsum_names() {
+    assert_eq!(codegen::codegen_base_name("l.mm"), "l.mm");
+    assert_eq!(codegen::codegen_base_name("l.mm#einsum"), "l.mm");
+    assert_eq!(codegen::inner_einsum_name("l.mm#einsum"), "l.mm#einsum");
+    assert_eq!(codegen::codegen_node_name("l.mm#einsum", "pack_a"), "l.mm.pack_a");
+    // a suffix of the user's own is kept
+    assert_eq!(codegen::codegen_base_name("l.mm.einsum"), "l.mm.einsum");
+    assert_eq!(codegen::codegen_node_name("l.mm.einsum", "pack_a"), "l.mm.einsum.pack_a");
+}
@@ -0,0 +1,317 @@
+//! Operating type overrides: einsums accumulating in another type than the importer picked.
+//!
+//! `TypedModel::set_einsum_operating_dt` switches the selected einsums to a new operating type,
+//! casts their operands if the kernels can not consume them as they are, and updates the facts
+//! downstream. The operating type picked by the importer is kept in the `OPERATING_DT_OVERRIDE`
+//! node property.
+use super::EinSum;
+use crate::internal::*;
+use crate::ops::cast::{cast, Cast};
+use std::collections::HashSet;
+use std::fmt;
+
+/// Node property holding the name of the operating type an einsum had before
+/// `TypedModel::set_einsum_operating_dt` first overrode it.
+pub const OPERATING_DT_OVERRIDE: &str = "einsum.operating_dt_override";
+
+pub type FactsPredicate = Arc<dyn Fn(&[&TypedFact]) -> bool + Send + Sync>;
+
+/// The einsums `TypedModel::set_einsum_operating_dt` applies to.
+#[derive(Clone)]
+pub enum EinSumSelector {
+    /// Einsums with a name matching the pattern, where `*` matches any string.
+    Name(String),
+    /// Einsums with input facts satisfying the predicate.
+    Facts(FactsPredicate),
+}
+
+impl EinSumSelector {
+    pub fn name(pattern: impl Into<String>) -> EinSumSelector {
+        EinSumSelector::Name(pattern.into())
+    }
+
+    pub fn facts(predicate: impl Fn(&[&TypedFact]) -> bool + Send + Sync + 'static) -> Self {
+        EinSumSelector::Facts(Arc::new(predicate))
+    }
+
+    fn matches(&self, model: &TypedModel, node: &TypedNode) -> TractResult<bool> {
+        Ok(match self {
+            EinSumSelector::Name(pattern) => matches_pattern(pattern, &node.name),
+            EinSumSelector::Facts(predicate) => predicate(&model.node_input_facts(node.id)?),
+        })
+    }
+}
+
+impl fmt::Debug for EinSumSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EinSumSelector::Name(pattern) => write!(f, "Name({pattern:?})"),
+            EinSumSelector::Facts(_) => write!(f, "Facts(..)"),
+        }
+    }
+}
+
+/// The operating type the einsum `id` had before it was overridden, if it was.
+pub fn original_operating_dt(model: &TypedModel, id: usize) -> TractResult<Option<DatumType>> {
+    model
+        .node_property(id, OPERATING_DT_OVERRIDE)
+        .map(|name| name.to_scalar::<String>()?.parse())
+        .transpose()
+}
+
+pub(crate) fn set_einsum_operating_dt(
+    model: &mut TypedModel,
+    selector: &EinSumSelector,
+    dt: DatumType,
+) -> TractResult<Vec<String>> {
+    let mut targets = vec![];
+    for id in model.eval_order()? {
+        let node = model.node(id);
+        if node.op_is::<EinSum>() && selector.matches(model, node)? {
+            targets.push(id);
+        }
+    }
+    ensure!(!targets.is_empty(), "No einsum matches {selector:?}");
+    for &id in &targets {
+        let node = model.node(id);
+        check_operating_dt(model, node, node.op_as::<EinSum>().unwrap(), dt)
+            .with_context(|| format!("Setting the operating type of {node} to {dt:?}"))?;
+    }
+    let mut names = vec![];
+    for id in targets {
+        let op = model.node(id).op_as::<EinSum>().unwrap().clone();
+        if op.operating_dt == dt {
+            continue;
+        }
+        let mut changed = HashSet::new();
+        for slot in 0..model.node(id).inputs.len() {
+            changed.extend(rewire_operand(model, id, slot, dt)?);
+        }
+        if model.node_property(id, OPERATING_DT_OVERRIDE).is_none() {
+            let original = format!("{:?}", op.operating_dt);
+            model.set_node_property(id, OPERATING_DT_OVERRIDE, rctensor0(original))?;
+        }
+        model.node_mut(id).op = Box::new(EinSum { operating_dt: dt, ..op });
+        changed.insert(id);
+        // the next einsums may consume this one
+        refresh_facts(model, changed)?;
+        names.push(model.node(id).name.clone());
+    }
+    model.compact()?;
+    Ok(names)
+}
+
+fn check_operating_dt(
+    model: &TypedModel,
+    node: &TypedNode,
+    op: &EinSum,
+    dt: DatumType,
+) -> TractResult<()> {
+    ensure!(op.q_params.is_none(), "A quantized einsum operating type follows its quantization");
+    ensure!(
+        (dt.is_float() || dt.is_integer()) && !dt.is_quantized(),
+        "An einsum can only operate on a plain number type"
+    );
+    for slot in 0..node.inputs.len() {
+        let input = model.outlet_fact(operand_source(model, node.id, slot))?.datum_type;
+        ensure!(
+            !(input.is_integer() && dt.is_integer() && input.size_of() > dt.size_of()),
+            "Input #{slot} ({input:?}) does not fit in {dt:?}"
+        );
+    }
+    Ok(())
+}
+
+fn cast_name(einsum: &str, slot: usize) -> String {
+    format!("{einsum}.operating_dt.{slot}")
+}
+
+/// The operand of an einsum before the cast a previous override may have put in front of it.
+fn operand_source(model: &TypedModel, id: usize, slot: usize) -> OutletId {
+    let node = model.node(id);
+    let input = model.node(node.inputs[slot].node);
+    if input.op_is::<Cast>() && input.name == cast_name(&node.name, slot) {
+        input.inputs[0]
+    } else {
+        node.inputs[slot]
+    }
+}
+
+/// Integer operands are accumulated in a wider integer as they are, the others are cast to the
+/// operating type. Returns the cast node, if any.
+fn rewire_operand(
+    model: &mut TypedModel,
+    id: usize,
+    slot: usize,
+    dt: DatumType,
+) -> TractResult<Option<usize>> {
+    let source = operand_source(model, id, slot);
+    let source_dt = model.outlet_fact(source)?.datum_type;
+    let input = model.node(id).inputs[slot];
+    if source_dt == dt || !(source_dt.is_float() || dt.is_float()) {
+        model.add_edge(source, InletId::new(id, slot))?;
+        return Ok(None);
+    }
+    if input != source {
+        model.node_mut(input.node).op = Box::new(cast(dt));
+        return Ok(Some(input.node));
+    }
+    // not wired, as constants would be folded, losing their original value
+    let name = cast_name(&model.node(id).name, slot);
+    let facts = cast(dt).output_facts(&[model.outlet_fact(source)?])?;
+    let cast_node = model.add_node(name, cast(dt), facts)?;
+    model.add_edge(source, InletId::new(cast_node, 0))?;
+    model.add_edge(OutletId::new(cast_node, 0), InletId::new(id, slot))?;
+    Ok(Some(cast_node))
+}
+
+/// Recompute the output facts of the `changed` nodes and of the nodes downstream.
+fn refresh_facts(model: &mut TypedModel, mut changed: HashSet<usize>) -> TractResult<()> {
+    for id in model.eval_order()? {
+        let node = model.node(id);
+        if !changed.contains(&id) && !node.inputs.iter().any(|i| changed.contains(&i.node)) {
+            continue;
+        }
+        let inputs = model.node_input_facts(id)?;
+        let facts = node
+            .op
+            .output_facts(&inputs)
+            .with_context(|| format!("Updating the output facts of {node}"))?;
+        if facts.iter().zip(node.outputs.iter()).all(|(fact, outlet)| fact == &outlet.fact) {
+            continue;
+        }
+        drop(inputs);
+        for (ix, fact) in facts.into_iter().enumerate() {
+            model.set_outlet_fact(OutletId::new(id, ix), fact)?;
+        }
+        changed.insert(id);
+    }
+    Ok(())
+}
+
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let Some((head, tail)) = pattern.split_once('*') else { return pattern == name };
+    let Some(rest) = name.strip_prefix(head) else { return false };
+    (0..=rest.len())
+        .filter(|&ix| rest.is_char_boundary(ix))
+        .any(|ix| matches_pattern(tail, &rest[ix..]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops;
+    use crate::ops::matmul::lir_unary::LirMatMulUnary;
+
+    fn weights(shape: &[usize]) -> Tensor {
+        let len = shape.iter().product::<usize>();
+        let data = (0..len).map(|i| (i % 7) as f32 / 7. - 0.4).collect::<Vec<_>>();
+        Tensor::from_shape(shape, &data).unwrap()
+    }
+
+    /// Two dense layers with a relu in between.
+    fn mlp() -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let x = model.add_source("x", f32::fact([4, 16]))?;
+        let w0 = model.add_const("w0", weights(&[16, 32]))?;
+        let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let wire = model.wire_node("layer0", einsum, &[x, w0])?[0];
+        let zero = model.add_const("zero", tensor2(&[[0f32]]))?;
+        let wire = model.wire_node("relu", ops::math::max(), &[wire, zero])?[0];
+        let w1 = model.add_const("w1", weights(&[32, 8]))?;
+        let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let wire = model.wire_node("layer1", einsum, &[wire, w1])?;
+        model.set_output_outlets(&wire)?;
+        Ok(model)
+    }
+
+    fn run(model: &TypedModel) -> TractResult<Tensor> {
+        let x = Tensor::from_shape(&[4, 16], &(0..64).map(|i| i as f32 / 16.).collect::<Vec<_>>())?;
+        let output = model.clone().into_runnable()?.run(tvec!(x.into_tvalue()))?.remove(0);
+        Ok(output.into_tensor())
+    }
+
+    #[test]
+    fn override_one_einsum_to_f64() -> TractResult<()> {
+        let model = mlp()?;
+        let mut overridden = model.clone();
+        let names = overridden
+            .set_einsum_operating_dt(&EinSumSelector::name("layer1"), f64::datum_type())?;
+        assert_eq!(names, ["layer1"]);
+        let layer1 = overridden.node_by_name("layer1")?;
+        assert_eq!(layer1.outputs[0].fact, f64::fact([4, 8]));
+        assert_eq!(overridden.output_fact(0)?, &f64::fact([4, 8]));
+        assert_eq!(original_operating_dt(&overridden, layer1.id)?, Some(f32::datum_type()));
+        for name in ["layer0", "relu"] {
+            let (before, after) = (model.node_by_name(name)?, overridden.node_by_name(name)?);
+            assert_eq!(format!("{:?}", before.op), format!("{:?}", after.op));
+            assert_eq!(before.outputs[0].fact, after.outputs[0].fact);
+            assert_eq!(original_operating_dt(&overridden, after.id)?, None);
+        }
+        let reference = run(&model)?.cast_to::<f64>()?.into_owned();
+        run(&overridden)?.close_enough(&reference, Approximation::Approximate)?;
+
+        let optimized = overridden.into_optimized()?;
+        let mut internal_dts = optimized
+            .nodes()
+            .iter()
+            .filter_map(|n| n.op_as::<LirMatMulUnary>())
+            .map(|lir| lir.mmm.internal_type())
+            .collect::<Vec<_>>();
+        internal_dts.sort();
+        assert_eq!(internal_dts, [f32::datum_type(), f64::datum_type()]);
+        Ok(())
+    }
+
+    #[test]
+    fn override_back_drops_casts() -> TractResult<()> {
+        let model = mlp()?;
+        let mut overridden = model.clone();
+        overridden.set_einsum_operating_dt(&EinSumSelector::name("layer*"), f64::datum_type())?;
+        assert_eq!(overridden.nodes().len(), model.nodes().len() + 3);
+        overridden.set_einsum_operating_dt(&EinSumSelector::name("layer0"), f32::datum_type())?;
+        assert_eq!(overridden.nodes().len(), model.nodes().len() + 1);
+        assert_eq!(overridden.node_by_name("relu")?.outputs[0].fact, f32::fact([4, 32]));
+        let layer0 = overridden.node_id_by_name("layer0")?;
+        assert_eq!(original_operating_dt(&overridden, layer0)?, Some(f32::datum_type()));
+        Ok(())
+    }
+
+    #[test]
+    fn select_by_facts() -> TractResult<()> {
+        let mut model = mlp()?;
+        let selector =
+            EinSumSelector::facts(|facts| facts[1].shape.as_concrete() == Some(&[32, 8]));
+        let names = model.set_einsum_operating_dt(&selector, i32::datum_type())?;
+        assert_eq!(names, ["layer1"]);
+        assert_eq!(model.output_fact(0)?, &i32::fact([4, 8]));
+        assert_eq!(model.node_by_name("layer0")?.outputs[0].fact, f32::fact([4, 32]));
+        Ok(())
+    }
+
+    #[test]
+    fn incompatible_operating_dt() -> TractResult<()> {
+        let mut model = mlp()?;
+        let err = model
+            .set_einsum_operating_dt(&EinSumSelector::name("layer0"), String::datum_type())
+            .unwrap_err();
+        assert!(format!("{err:?}").contains("layer0"), "{err:?}");
+        assert!(model
+            .set_einsum_operating_dt(&EinSumSelector::name("conv*"), f64::datum_type())
+            .is_err());
+        let mut layers = model.nodes().iter().filter_map(|n| n.op_as::<EinSum>());
+        assert!(
+            layers.clone().count() == 2 && layers.all(|op| op.operating_dt == f32::datum_type())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn name_patterns() {
+        assert!(matches_pattern("layer1", "layer1"));
+        assert!(!matches_pattern("layer1", "layer10"));
+        assert!(matches_pattern("layer*", "layer10"));
+        assert!(matches_pattern("*.matmul*", "block3.matmul.1"));
+        assert!(!matches_pattern("*.matmul", "block3.matmul.1"));
+    }
+}
@@ -0,0 +1,185 @@
+//! Output casts and per node accumulation precision.
+
+use super::test_util::wire_q_params;
+use super::*;
+use crate::ops::cast::{cast, Cast};
+
+#[test]
+fn cast_to_f16_fused_in_store() -> TractResult<()> {
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", f32::fact([13, 40]))?;
+    let b = (0..40 * 17).map(|i| (i % 11) as f32 / 8. - 0.5).collect_vec();
+    let b = model.add_const("b", Tensor::from_shape(&[40, 17], &b)?)?;
+    let c =
+        model.wire_node("einsum", EinSum::new("mk,kn->mn".parse()?, f32::datum_type()), &[a, b])?;
+    let c = model.wire_node("cast", cast(f16::datum_type()), &c)?;
+    model.set_output_outlets(&c)?;
+    let a = (0..13 * 40).map(|i| (i % 7) as f32 / 3. - 1.).collect_vec();
+    let a = tvec!(Tensor::from_shape(&[13, 40], &a)?.into_tvalue());
+    let expected = model.clone().into_runnable()?.run(a.clone())?.remove(0);
+    let optimized = model.into_optimized()?;
+    assert!(optimized.nodes.iter().all(|n| !n.op_is::<Cast>()));
+    let found = optimized.into_runnable()?.run(a)?.remove(0);
+    assert_eq!(found.datum_type(), f16::datum_type());
+    found.close_enough(&expected, Approximation::Close)?;
+    Ok(())
+}
+
+/// Two quantized matmuls, both model outputs. The second one takes its a scale as an input.
+fn two_quantized_matmuls(fallback: Option<&str>) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let mut wire = model.add_source("x", i8::fact([4, 8]))?;
+    let a_scale = model.add_source("a_scale", f32::scalar_fact())?;
+    let mut outputs = tvec!();
+    for (layer, n) in [("first", 8), ("second", 3)] {
+        let w = (0..8 * n).map(|i| ((i * 29) % 41) as i8 - 20).collect_vec();
+        let mut inputs = tvec!(wire);
+        inputs.push(model.add_const(format!("{layer}.w"), Tensor::from_shape(&[8, n], &w)?)?);
+        inputs.extend(wire_q_params(
+            &mut model,
+            layer,
+            [
+                tensor0(0i32),
+                tensor0(2i8),
+                tensor0(0.05f32),
+                tensor0(-1i8),
+                tensor0(0.03f32),
+                tensor0(1i8),
+                tensor0(0.07f32),
+            ],
+        )?);
+        if layer == "second" {
+            inputs[4] = a_scale;
+        }
+        let op = EinSum::newq("mk,kn,,,,,,,->mn".parse()?, i32::datum_type(), i8::datum_type());
+        wire = model.wire_node(layer, op, &inputs)?[0];
+        outputs.push(wire);
+    }
+    model.set_output_outlets(&outputs)?;
+    if let Some(name) = fallback {
+        model.force_float_fallback(name)?;
+    }
+    Ok(model)
+}
+
+#[test]
+fn forced_float_fallback() -> TractResult<()> {
+    use crate::ops::matmul::lir_unary::LirMatMulUnary;
+    assert!(two_quantized_matmuls(Some("x")).is_err());
+    let x = (0..32).map(|i| ((i * 37) % 61) as i8 - 30).collect_vec();
+    let inputs = tvec!(Tensor::from_shape(&[4, 8], &x)?.into_tvalue(), tensor0(0.05f32).into());
+    let w = (0..24).map(|i| ((i * 29) % 41) as f64 - 20.).collect_vec();
+    // real output of the second layer, computed from the first layer output
+    let error = |outputs: &[TValue]| -> TractResult<f64> {
+        let first = outputs[0].as_slice::<i8>()?;
+        let second = outputs[1].as_slice::<i8>()?;
+        let mut error = 0f64;
+        for (i, j) in tract_itertools::iproduct!(0..4, 0..3) {
+            let acc =
+                (0..8).map(|k| (first[i * 8 + k] as f64 - 2.) * (w[k * 3 + j] + 1.)).sum::<f64>();
+            let real = acc * 0.05 * 0.03;
+            error = error.max((real - (second[i * 3 + j] as f64 - 1.) * 0.07).abs());
+        }
+        Ok(error)
+    };
+    let kernels = |model: &TypedModel| -> Vec<DatumType> {
+        let lirs = model.nodes().iter().filter_map(|n| n.op_as::<LirMatMulUnary>());
+        lirs.map(|lir| lir.mmm.internal_type()).sorted().collect()
+    };
+
+    let default = two_quantized_matmuls(None)?.into_optimized()?;
+    assert_eq!(kernels(&default), vec![i32::datum_type(); 2]);
+    let default_error = error(&default.into_runnable()?.run(inputs.clone())?)?;
+
+    let model = two_quantized_matmuls(Some("second"))?;
+    let decluttered = model.clone().into_decluttered()?;
+    let island = decluttered.node_by_name("second")?.op_as::<EinSum>().unwrap();
+    assert!(island.q_params.is_none() && island.operating_dt == f32::datum_type());
+    let optimized = decluttered.into_optimized()?;
+    assert_eq!(kernels(&optimized), vec![i32::datum_type(), f32::datum_type()]);
+    // same i8 interface
+    assert_eq!(optimized.output_fact(1)?.datum_type, i8::datum_type());
+    let outputs = optimized.into_runnable()?.run(inputs.clone())?;
+    let reference = model.into_runnable()?.run(inputs)?;
+    assert_eq!(outputs[0], reference[0]);
+    let fallback_error = error(&outputs)?;
+    assert!(fallback_error <= 0.07 / 2. + 1e-6, "{fallback_error}");
+    assert!(fallback_error <= default_error, "{fallback_error} > {default_error}");
+    Ok(())
+}
+
+/// Two sibling products of x [1024, 4] by the same ill-conditioned 1024×1024 weights, made
+/// of terms from 1e-6 to 1e6 cancelling out in pairs, with the small ones in between.
+fn ill_conditioned_siblings(extended: Option<&str>) -> TractResult<TypedModel> {
+    let k = 1024;
+    let w = (0..k * k)
+        .map(|ix| {
+            let (i, k) = (ix / 1024, ix % 1024);
+            let big = 10f32.powi(((i * 7 + k / 3) % 7) as i32);
+            match k % 3 {
+                _ if k == 1023 => 0.,
+                0 => big,
+                1 => 10f32.powi(-(((i + k) % 7) as i32)),
+                _ => -big,
+            }
+        })
+        .collect_vec();
+    let w = Tensor::from_shape(&[k, k], &w)?;
+    let mut model = TypedModel::default();
+    let x = model.add_source("x", f32::fact([k, 4]))?;
+    let mut outputs = tvec!();
+    for name in ["marked", "unmarked"] {
+        let w = model.add_const(format!("{name}.w"), w.clone())?;
+        let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        outputs.push(model.wire_node(name, op, &[w, x])?[0]);
+    }
+    model.set_output_outlets(&outputs)?;
+    if let Some(name) = extended {
+        model.force_extended_accumulation(name)?;
+    }
+    Ok(model)
+}
+
+#[test]
+fn forced_extended_accumulation() -> TractResult<()> {
+    use crate::ops::konst::Const;
+    use crate::ops::matmul::lir_unary::LirMatMulUnary;
+    assert!(ill_conditioned_siblings(Some("x")).is_err());
+    let x = (0..1024 * 4).map(|ix| (1 + (ix / 4 / 3 + ix % 4) % 4) as f32).collect_vec();
+    let x = Tensor::from_shape(&[1024, 4], &x)?;
+    let model = ill_conditioned_siblings(None)?;
+    let w = model.node_by_name("marked.w")?.op_as::<Const>().unwrap().0.clone();
+    let (w, x64) = (w.cast_to::<f64>()?, x.cast_to::<f64>()?);
+    let (w, x64) = (w.to_array_view::<f64>()?, x64.to_array_view::<f64>()?);
+    let reference = w
+        .into_dimensionality::<tract_ndarray::Ix2>()?
+        .dot(&x64.into_dimensionality::<tract_ndarray::Ix2>()?);
+    let error = |output: &TValue| -> TractResult<f64> {
+        let output = output.cast_to::<f64>()?;
+        let output = output.to_array_view::<f64>()?;
+        Ok(output.iter().zip(reference.iter()).map(|(a, b)| (a - b).abs()).sum::<f64>())
+    };
+    let inputs = tvec!(x.into_tvalue());
+
+    let default = model.into_optimized()?.into_runnable()?.run(inputs.clone())?;
+    let extended = ill_conditioned_siblings(Some("marked"))?;
+    let plans: HashMap<_, _> = extended
+        .clone()
+        .into_decluttered()?
+        .lowering_plans(&Default::default())?
+        .into_iter()
+        .collect();
+    assert_eq!(plans["marked"].to_string(), "f64 accumulation");
+    assert_ne!(plans["unmarked"].to_string(), "f64 accumulation");
+    let extended = extended.into_optimized()?;
+    let kernels = extended.nodes().iter().filter_map(|n| n.op_as::<LirMatMulUnary>());
+    let kernels = kernels.map(|lir| lir.mmm.internal_type()).sorted().collect_vec();
+    assert_eq!(kernels, [f32::datum_type(), f64::datum_type()]);
+    assert_eq!(extended.output_fact(0)?.datum_type, f32::datum_type());
+    let outputs = extended.into_runnable()?.run(inputs)?;
+    let (marked, unmarked) = (error(&outputs[0])?, error(&default[0])?);
+    assert!(marked * 1000. < unmarked, "{marked} vs {unmarked}");
+    // the sibling is untouched
+    assert_eq!(outputs[1], default[1]);
+    Ok(())
+}
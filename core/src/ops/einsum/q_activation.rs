@@ -0,0 +1,101 @@
+//! Activations fused in quantized einsums.
+
+use super::test_util::{ensure_within_one_step, wire_q_params};
+use super::*;
+
+fn fused_activation_reference(a: &[i8], b: &[i8], activation: QActivation) -> Tensor {
+    let c: Vec<i8> = (0..4 * 3)
+        .map(|ix| {
+            let (m, n) = (ix / 3, ix % 3);
+            let acc = (0..8)
+                .map(|k| (a[m * 8 + k] as f32 - 2.) * 0.05 * (b[k * 3 + n] as f32 + 1.) * 0.03)
+                .sum::<f32>();
+            let c = (activation.eval(acc) / 0.08).round() as i32 - 3;
+            c.clamp(i8::MIN as i32, i8::MAX as i32) as i8
+        })
+        .collect();
+    Tensor::from_shape(&[4, 3], &c).unwrap()
+}
+
+fn fused_activation_model(b: &[i8], activation: QActivation) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", i8::fact([4, 8]))?;
+    let mut inputs = tvec!(a);
+    inputs.push(model.add_const("b", Tensor::from_shape(&[8, 3], b)?)?);
+    inputs.extend(wire_q_params(
+        &mut model,
+        "",
+        [
+            tensor0(0i32),
+            tensor0(2i8),
+            tensor0(0.05f32),
+            tensor0(-1i8),
+            tensor0(0.03f32),
+            tensor0(1i8),
+            tensor0(0.1f32),
+        ],
+    )?);
+    let op = EinSum::newq("mk,kn,,,,,,,->mn".parse()?, i32::datum_type(), i8::datum_type());
+    let mut wire = model.wire_node("einsum", op, &inputs)?;
+    wire = model.wire_node("dequant", ops::quant::DequantizeLinearF32::new(0.1, 1), &wire)?;
+    wire = match activation {
+        QActivation::Relu => {
+            let zero = model.add_const("zero", tensor0(0f32))?;
+            ops::binary::wire_with_rank_broadcast(
+                "activation",
+                &mut model,
+                ops::math::max(),
+                &[wire[0], zero],
+            )?
+        }
+        _ => model.wire_node("activation", activation.as_element_wise().unwrap(), &wire)?,
+    };
+    wire = model.wire_node("quant", ops::quant::quantize_linear_i8(0.08f32.recip(), -3), &wire)?;
+    model.set_output_outlets(&wire)?;
+    Ok(model)
+}
+
+fn check_fused_activation(a: &[i8], b: &[i8], activation: QActivation) -> TractResult<()> {
+    let model = fused_activation_model(b, activation)?;
+    let expected = fused_activation_reference(a, b, activation);
+    let input = tvec!(Tensor::from_shape(&[4, 8], a)?.into_tvalue());
+    let decluttered = model.into_decluttered()?;
+    assert!(decluttered.nodes.iter().all(|n| !n.op_is::<ops::quant::DequantizeLinearF32>()));
+    let einsum = decluttered.node_by_name("einsum")?.op_as::<EinSum>().unwrap();
+    assert_eq!(einsum.q_activation, Some(activation));
+    ensure_within_one_step(
+        &decluttered.clone().into_runnable()?.run(input.clone())?.remove(0),
+        &expected,
+    )?;
+    ensure_within_one_step(
+        &decluttered.into_optimized()?.into_runnable()?.run(input)?.remove(0),
+        &expected,
+    )
+}
+
+::proptest::proptest! {
+    #[test]
+    fn fused_relu(a in ::proptest::collection::vec(-20i8..20, 32),
+                  b in ::proptest::collection::vec(-20i8..20, 24)) {
+        check_fused_activation(&a, &b, QActivation::Relu).unwrap()
+    }
+
+    #[test]
+    fn fused_gelu(a in ::proptest::collection::vec(-20i8..20, 32),
+                  b in ::proptest::collection::vec(-20i8..20, 24)) {
+        check_fused_activation(&a, &b, QActivation::GeluApproximate).unwrap()
+    }
+}
+
+#[test]
+fn fused_activation_regression() -> TractResult<()> {
+    // a case the proptests above once failed on
+    let mut a = vec![13i8, -4, -12, -15, 8, -18, -5, -18];
+    a.resize(32, 0);
+    let mut b = vec![0i8; 24];
+    for (ix, v) in [-7i8, 19, 13, -6, -9, -17, -17, 14].into_iter().enumerate() {
+        b[ix * 3 + 1] = v;
+    }
+    check_fused_activation(&a, &b, QActivation::Relu)?;
+    check_fused_activation(&a, &b, QActivation::GeluApproximate)
+}
@@ -0,0 +1,523 @@
+//! Quantized einsums: operand types, quantization parameters and output requantization.
+
+use super::test_util::{dequant_lowered, ensure_within_one_step, wire_q_params};
+use super::*;
+use crate::ops::nn::IntegerSum;
+
+fn float_bias_model(dynamic_scales: bool) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", i8::fact([4, 8]))?;
+    let b = (0..24).map(|i| (i % 11) as i8 - 5).collect_vec();
+    let b = model.add_const("b", Tensor::from_shape(&[8, 3], &b)?)?;
+    let mut inputs = tvec!(a, b);
+    inputs.extend(wire_q_params(
+        &mut model,
+        "",
+        [
+            tensor1(&[0.31f32, -0.7, 1.2]),
+            tensor0(2i8),
+            tensor0(0.05f32),
+            tensor0(-1i8),
+            tensor0(0.03f32),
+            tensor0(1i8),
+            tensor0(0.1f32),
+        ],
+    )?);
+    if dynamic_scales {
+        inputs[4] = model.add_source("dynamic_a_scale", f32::scalar_fact())?;
+    }
+    let op = EinSum::newq("mk,kn,n,,,,,,->mn".parse()?, i32::datum_type(), i8::datum_type());
+    let c = model.wire_node("einsum", op, &inputs)?;
+    model.set_output_outlets(&c)?;
+    Ok(model)
+}
+
+fn check_float_bias(dynamic_scales: bool) -> TractResult<()> {
+    let model = float_bias_model(dynamic_scales)?;
+    let a = (0..32).map(|i| (i % 13) as i8 - 6).collect_vec();
+    let b = (0..24).map(|i| (i % 11) as i8 - 5).collect_vec();
+    let bias = [0.31f32, -0.7, 1.2];
+    let expected: Vec<i8> = (0..4 * 3)
+        .map(|ix| {
+            let (m, n) = (ix / 3, ix % 3);
+            let acc = (0..8)
+                .map(|k| (a[m * 8 + k] as f32 - 2.) * 0.05 * (b[k * 3 + n] as f32 + 1.) * 0.03)
+                .sum::<f32>();
+            ((acc + bias[n]) / 0.1).round() as i8 + 1
+        })
+        .collect();
+    let mut inputs = tvec!(Tensor::from_shape(&[4, 8], &a)?.into_tvalue());
+    if dynamic_scales {
+        inputs.push(tensor0(0.05f32).into_tvalue());
+    }
+    let expected = tensor1(&expected);
+    ensure_within_one_step(
+        &model.clone().into_runnable()?.run(inputs.clone())?.remove(0),
+        &expected,
+    )?;
+
+    let decluttered = model.into_decluttered()?;
+    let node = decluttered.node_by_name("einsum")?;
+    let patch = codegen::codegen(
+        node.op_as::<EinSum>().unwrap(),
+        &decluttered,
+        node,
+        &OptimizerOptions::default(),
+    )?
+    .unwrap();
+    let has_node = |name: &str| patch.model.nodes.iter().any(|n| n.name == name);
+    assert_eq!(has_node("einsum.bias_q"), !dynamic_scales);
+    assert_eq!(has_node("einsum.bias_as_f32"), dynamic_scales);
+    ensure_within_one_step(
+        &decluttered.into_optimized()?.into_runnable()?.run(inputs)?.remove(0),
+        &expected,
+    )
+}
+
+#[test]
+fn float_bias_with_const_scales() -> TractResult<()> {
+    check_float_bias(false)
+}
+
+#[test]
+fn float_bias_with_dynamic_scales() -> TractResult<()> {
+    check_float_bias(true)
+}
+
+/// Wire `konst` through `ops` with `add_node`, for the optimizer to see them as they are
+/// instead of folding them on wiring.
+fn wire_unfolded(
+    model: &mut TypedModel,
+    name: &str,
+    konst: Tensor,
+    ops: Vec<Box<dyn TypedOp>>,
+) -> TractResult<OutletId> {
+    let mut wire = model.add_const(name, konst)?;
+    for (ix, op) in ops.into_iter().enumerate() {
+        let facts = op.output_facts(&[&model.outlet_fact(wire)?.without_value()])?;
+        let node = model.add_node(format!("{name}.wrap_{ix}"), op, facts)?;
+        model.add_edge(wire, InletId::new(node, 0))?;
+        wire = node.into();
+    }
+    Ok(wire)
+}
+
+/// A quantized einsum with u8 activations and a float bias. Its quantization parameters are
+/// constants wired directly, or behind a different combination of trivial ops each.
+fn wrapped_q_params_model(wrapped: bool, output_dt: DatumType) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", u8::fact([4, 8]))?;
+    let b = (0..24).map(|i| (i % 11) as i8 - 5).collect_vec();
+    let b = model.add_const("b", Tensor::from_shape(&[8, 3], &b)?)?;
+    let accumulate_only = output_dt == i32::datum_type();
+    let (scale, c0) = if accumulate_only { (1f32, 0i32) } else { (0.05, 1) };
+    let reshape = |from: &[usize], to: &[usize]| -> Box<dyn TypedOp> {
+        let dims = |shape: &[usize]| shape.iter().map(|d| d.to_dim()).collect();
+        Box::new(AxisOp::Reshape(0, dims(from), dims(to)))
+    };
+    let add = |axis: usize| -> Box<dyn TypedOp> { Box::new(AxisOp::Add(axis)) };
+    let rm = |axis: usize| -> Box<dyn TypedOp> { Box::new(AxisOp::Rm(axis)) };
+    let cast = |dt: DatumType| -> Box<dyn TypedOp> { Box::new(ops::cast::cast(dt)) };
+    let q_params = [
+        ("bias", tensor1(&[0.31f32, -0.7, 1.2]), vec![add(0), rm(0)]),
+        ("a0", tensor0(128u8), vec![cast(u8::datum_type())]),
+        ("a_scale", tensor1(&[scale]), vec![reshape(&[1], &[])]),
+        ("b0", tensor0(-1i8), vec![add(0), reshape(&[1], &[])]),
+        ("b_scale", tensor0(scale), vec![cast(f32::datum_type()), add(0), rm(0)]),
+        ("c0", tensor0(c0), vec![reshape(&[], &[1]), rm(0)]),
+        ("c_scale", tensor2(&[[scale * 2.]]), vec![rm(0), cast(f32::datum_type()), rm(0)]),
+    ];
+    let mut inputs = tvec!(a, b);
+    for (name, konst, ops) in q_params {
+        inputs.push(if wrapped {
+            wire_unfolded(&mut model, name, konst, ops)?
+        } else {
+            let mut konst = konst.into_tvalue();
+            for op in ops {
+                konst = op.eval(tvec!(konst))?.remove(0);
+            }
+            model.add_const(name, konst.into_arc_tensor())?
+        });
+    }
+    let op = EinSum::newq("mk,kn,n,,,,,,->mn".parse()?, i32::datum_type(), output_dt);
+    let c = model.wire_node("einsum", op, &inputs)?;
+    model.set_output_outlets(&c)?;
+    Ok(model)
+}
+
+/// Lower the wrapped and direct versions as they are (decluttering would fold the wrappers),
+/// with folding off: they must lower to the same graph, node for node, and compute the same
+/// output.
+fn check_wrapped_q_params(output_dt: DatumType) -> TractResult<()> {
+    let options = OptimizerOptions { const_fold_max_bytes: Some(0), ..Default::default() };
+    let a = (0..32).map(|i| (i * 7 % 23) as u8 + 116).collect_vec();
+    let input = tvec!(Tensor::from_shape(&[4, 8], &a)?.into_tvalue());
+    let mut outputs = vec![];
+    let mut graphs = vec![];
+    for wrapped in [false, true] {
+        let mut optimized = wrapped_q_params_model(wrapped, output_dt)?;
+        crate::optim::Optimizer::codegen()
+            .with_options(options.clone())
+            .optimize(&mut optimized)?;
+        let graph = optimized
+            .eval_order()?
+            .into_iter()
+            .map(|n| {
+                let node = optimized.node(n);
+                format!("{} {:?}", node.op.name(), node.outputs[0].fact.without_value())
+            })
+            .collect_vec();
+        graphs.push(graph);
+        outputs.push(optimized.into_runnable()?.run(input.clone())?.remove(0));
+    }
+    assert_eq!(graphs[0], graphs[1]);
+    assert_eq!(outputs[0], outputs[1]);
+    Ok(())
+}
+
+#[test]
+fn wrapped_q_params_requantized() -> TractResult<()> {
+    check_wrapped_q_params(i8::datum_type())
+}
+
+#[test]
+fn wrapped_q_params_accumulate_only() -> TractResult<()> {
+    check_wrapped_q_params(i32::datum_type())
+}
+
+#[test]
+fn u8_zero_point_sums_without_i32_copies() -> TractResult<()> {
+    let (m, k, n) = (3, 1024, 5);
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", u8::fact([m, k]))?;
+    let b = model.add_source("b", u8::fact([k, n]))?;
+    let mut wire = tvec!(a, b);
+    wire.extend(wire_q_params(
+        &mut model,
+        "",
+        [
+            tensor0(0i32),
+            tensor0(128u8),
+            tensor0(0.01f32),
+            tensor0(3u8),
+            tensor0(0.02f32),
+            tensor0(0u8),
+            tensor0(8f32),
+        ],
+    )?);
+    let op = EinSum::newq("mk,kn,,,,,,,->mn".parse()?, i32::datum_type(), u8::datum_type());
+    let c = model.wire_node("einsum", op, &wire)?;
+    model.set_output_outlets(&c)?;
+
+    let optimized = model.clone().into_optimized()?;
+    for node in &optimized.nodes {
+        let fact = &node.outputs[0].fact;
+        let shape = fact.shape.as_concrete();
+        assert!(
+            fact.datum_type != i32::datum_type()
+                || (shape != Some(&[m, k]) && shape != Some(&[k, n])),
+            "{node}"
+        );
+    }
+
+    // rows and columns of 255, overflowing i16 when summed over k
+    let a = (0..m * k).map(|i| if i / k == 0 { 255 } else { (i * 7 % 256) as u8 }).collect_vec();
+    let b = (0..k * n).map(|i| if i % n == 0 { 255 } else { (i * 13 % 256) as u8 }).collect_vec();
+    let full = Tensor::from_shape(&[1, k], &[255u8; 1024])?;
+    let sum = IntegerSum::new(tvec!(1)).eval(tvec!(full.into_tvalue()))?;
+    assert_eq!(*sum[0], tensor2(&[[255 * 1024]]));
+    let inputs = tvec!(
+        Tensor::from_shape(&[m, k], &a)?.into_tvalue(),
+        Tensor::from_shape(&[k, n], &b)?.into_tvalue()
+    );
+    let expected = model.into_runnable()?.run(inputs.clone())?.remove(0);
+    let found = optimized.into_runnable()?.run(inputs)?.remove(0);
+    ensure_within_one_step(&found, &expected)
+}
+
+#[test]
+fn i16_activations_i8_weights() -> TractResult<()> {
+    let k = 4096;
+    let (a_scale, b_scale, c_scale) = (1f32 / 32768., 1f32 / 128., 0.2f32);
+    let a = (0..2 * k).map(|i| if i < k || i % 2 == 0 { i16::MAX } else { i16::MIN }).collect_vec();
+    let b = (0..k * 3)
+        .map(|i| match i % 3 {
+            0 => i8::MAX,
+            1 => i8::MIN,
+            _ => [i8::MIN, i8::MAX, 0][(i / 3) % 3],
+        })
+        .collect_vec();
+    let mut model = TypedModel::default();
+    let mut inputs = tvec!(model.add_source("a", i16::fact([2, k]))?);
+    inputs.push(model.add_const("b", Tensor::from_shape(&[k, 3], &b)?)?);
+    inputs.extend(wire_q_params(
+        &mut model,
+        "",
+        [
+            tensor1(&[1000i32, 0, -1000]),
+            tensor0(-1i16),
+            tensor0(a_scale),
+            tensor0(1i8),
+            tensor0(b_scale),
+            tensor0(3i16),
+            tensor0(c_scale),
+        ],
+    )?);
+    let op = EinSum::newq("mk,kn,n,,,,,,->mn".parse()?, i32::datum_type(), i16::datum_type());
+    let c = model.wire_node("einsum", op, &inputs)?;
+    model.set_output_outlets(&c)?;
+
+    let bias = [1000., 0., -1000.];
+    let expected = (0..2 * 3)
+        .map(|ix| {
+            let (m, n) = (ix / 3, ix % 3);
+            let acc = (0..k)
+                .map(|k_| (a[m * k + k_] as f64 + 1.) * (b[k_ * 3 + n] as f64 - 1.))
+                .sum::<f64>();
+            let real = (acc + bias[n]) * a_scale as f64 * b_scale as f64;
+            ((real / c_scale as f64).round() + 3.).clamp(i16::MIN as f64, i16::MAX as f64)
+        })
+        .collect_vec();
+    let input = tvec!(Tensor::from_shape(&[2, k], &a)?.into_tvalue());
+    let expected = tensor1(&expected);
+    ensure_within_one_step(
+        &model.clone().into_runnable()?.run(input.clone())?.remove(0),
+        &expected,
+    )?;
+    ensure_within_one_step(
+        &model.into_optimized()?.into_runnable()?.run(input)?.remove(0),
+        &expected,
+    )
+}
+
+/// ONNX MatMulInteger: i32 accumulation of the zero point centered operands, no scales.
+fn check_mat_mul_integer(a: Tensor, a0: Tensor, b: Tensor, b0: Tensor) -> TractResult<()> {
+    let (m, k, n) = (a.shape()[0], a.shape()[1], b.shape()[1]);
+    let mut model = TypedModel::default();
+    let a_fact = TypedFact::dt_shape(a.datum_type(), a.shape());
+    let mut inputs = tvec!(model.add_source("a", a_fact)?, model.add_const("b", b.clone())?);
+    let a0_axes = if a0.rank() == 1 { "m" } else { "" };
+    let b0_axes = if b0.rank() == 1 { "n" } else { "" };
+    inputs.extend(wire_q_params(
+        &mut model,
+        "",
+        [
+            tensor0(0i32),
+            a0.clone(),
+            tensor0(1f32),
+            b0.clone(),
+            tensor0(1f32),
+            tensor0(0i32),
+            tensor0(1f32),
+        ],
+    )?);
+    let expr = format!("mk,kn,,{a0_axes},,{b0_axes},,,->mn");
+    let op = EinSum::newq(expr.parse()?, i32::datum_type(), i32::datum_type());
+    let c = model.wire_node("einsum", op, &inputs)?;
+    model.set_output_outlets(&c)?;
+
+    let as_i32 = |t: &Tensor| t.cast_to::<i32>().unwrap().into_owned();
+    let (a_i, b_i, a0_i, b0_i) = (as_i32(&a), as_i32(&b), as_i32(&a0), as_i32(&b0));
+    let (a_i, b_i) = (a_i.as_slice::<i32>()?, b_i.as_slice::<i32>()?);
+    let zp =
+        |t: &Tensor, ix: usize| t.as_slice::<i32>().unwrap()[if t.rank() == 1 { ix } else { 0 }];
+    let mut expected = vec![0i32; m * n];
+    for (i, j, x) in tract_itertools::iproduct!(0..m, 0..n, 0..k) {
+        expected[i * n + j] += (a_i[i * k + x] - zp(&a0_i, i)) * (b_i[x * n + j] - zp(&b0_i, j));
+    }
+    let expected = Tensor::from_shape(&[m, n], &expected)?;
+
+    let reference = model.clone().into_runnable()?.run(tvec!(a.clone().into_tvalue()))?;
+    reference[0].close_enough(&expected, Approximation::Exact)?;
+    // accumulation only: the lowering does not scale nor requantize
+    let lowered = dequant_lowered(&model)?;
+    for node in lowered.nodes() {
+        assert!(!node.name.contains("scale") && !node.name.contains("requant"), "{node}");
+    }
+    lowered.into_runnable()?.run(tvec!(a.clone().into_tvalue()))?[0]
+        .close_enough(&expected, Approximation::Exact)?;
+    let optimized = model.into_optimized()?;
+    let found = optimized.into_runnable()?.run(tvec!(a.into_tvalue()))?;
+    found[0].close_enough(&expected, Approximation::Exact)
+}
+
+fn int_tensor<T: Datum + Copy>(shape: &[usize], f: impl Fn(usize) -> T) -> Tensor {
+    let len = shape.iter().product::<usize>();
+    Tensor::from_shape(shape, &(0..len).map(f).collect_vec()).unwrap()
+}
+
+#[test]
+fn mat_mul_integer_per_tensor_zero_points() -> TractResult<()> {
+    let a = int_tensor(&[5, 7], |i| ((i * 37) % 255) as u8);
+    let b = int_tensor(&[7, 3], |i| (((i * 11) % 200) as i32 - 100) as i8);
+    check_mat_mul_integer(a, tensor0(130u8), b, tensor0(-3i8))
+}
+
+#[test]
+fn mat_mul_integer_per_row_zero_points() -> TractResult<()> {
+    let a = int_tensor(&[5, 7], |i| (((i * 37) % 200) as i32 - 100) as i8);
+    let a0 = int_tensor(&[5], |i| i as i8 * 3 - 6);
+    let b = int_tensor(&[7, 3], |i| ((i * 53) % 255) as u8);
+    check_mat_mul_integer(a, a0, b, tensor0(128u8))
+}
+
+#[test]
+fn mat_mul_integer_per_row_u8_zero_points() -> TractResult<()> {
+    let a = int_tensor(&[5, 7], |i| ((i * 37) % 255) as u8);
+    let a0 = int_tensor(&[5], |i| (i * 50) as u8);
+    let b = int_tensor(&[7, 3], |i| (((i * 11) % 200) as i32 - 100) as i8);
+    check_mat_mul_integer(a, a0, b, tensor0(0i8))
+}
+
+/// i8 product with per-channel output quantization along `c_axis` ("m", "n", or "" for
+/// scalar c0 and c_scale).
+fn per_channel_output_model(c_axis: &str, c0: Tensor, c_scale: Tensor) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", i8::fact([4, 6]))?;
+    let b = (0..30).map(|i| ((i * 7) % 41) as i8 - 20).collect_vec();
+    let mut inputs = tvec!(a, model.add_const("b", Tensor::from_shape(&[6, 5], &b)?)?);
+    inputs.extend(wire_q_params(
+        &mut model,
+        "",
+        [
+            tensor0(0i32),
+            tensor0(1i8),
+            tensor0(0.05f32),
+            tensor0(0i8),
+            tensor0(0.02f32),
+            c0,
+            c_scale,
+        ],
+    )?);
+    let expr = format!("mk,kn,,,,,,{c_axis},{c_axis}->mn");
+    let op = EinSum::newq(expr.parse()?, i32::datum_type(), i8::datum_type());
+    let c = model.wire_node("einsum", op, &inputs)?;
+    model.set_output_outlets(&c)?;
+    Ok(model)
+}
+
+#[test]
+fn per_channel_output_quantization() -> TractResult<()> {
+    let a = int_tensor(&[4, 6], |i| ((i * 13) % 41) as i8 - 20);
+    let run = |model: TypedModel| -> TractResult<Vec<i8>> {
+        let output = model.into_runnable()?.run(tvec!(a.clone().into_tvalue()))?.remove(0);
+        Ok(output.as_slice::<i8>()?.to_vec())
+    };
+    let scalar = per_channel_output_model("", tensor0(3i8), tensor0(0.1f32))?;
+    let expected_scalar = run(scalar.clone())?;
+    assert_eq!(run(scalar.into_optimized()?)?, expected_scalar);
+
+    let a_i = a.as_slice::<i8>()?;
+    let b = (0..30).map(|i| (i * 7) % 41 - 20).collect_vec();
+    for (axis, len) in [("m", 4), ("n", 5)] {
+        // a uniform vector is the scalar case
+        let uniform =
+            per_channel_output_model(axis, tensor1(&vec![3i8; len]), tensor1(&vec![0.1f32; len]))?;
+        assert_eq!(run(uniform.clone())?, expected_scalar);
+        assert_eq!(run(uniform.into_optimized()?)?, expected_scalar);
+
+        let c0 = (0..len).map(|i| i as i8 * 2 - 3).collect_vec();
+        let c_scale = (0..len).map(|i| 0.05 * (1 << i) as f32).collect_vec();
+        let model = per_channel_output_model(axis, tensor1(&c0), tensor1(&c_scale))?;
+        let mut expected = vec![];
+        for (i, j) in tract_itertools::iproduct!(0..4, 0..5) {
+            let acc: i32 = (0..6).map(|x| (a_i[i * 6 + x] as i32 - 1) * b[x * 5 + j]).sum();
+            let channel = if axis == "m" { i } else { j };
+            let real = acc as f32 * 0.05 * 0.02 / c_scale[channel];
+            expected.push((real.round() as i32 + c0[channel] as i32).clamp(-128, 127));
+        }
+        for found in
+            [run(model.clone())?, run(dequant_lowered(&model)?)?, run(model.into_optimized()?)?]
+        {
+            for (found, expected) in found.iter().zip(&expected) {
+                assert!((*found as i32 - expected).abs() <= 1, "{axis}: {found:?} {expected:?}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Unit scales quantized product of `a` (m x 3) by a constant b (3 x 2), with a bias along
+/// m pushing the accumulator of the rows of a starting with 1, 0, 0 to `acc` and `acc + 1`.
+fn overflowing_model(acc: &[i32], dt: DatumType, overflow: QOverflow) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", i8::fact([acc.len(), 3]))?;
+    let b = tensor2(&[[1i8, 2], [0, 1], [1, 0]]);
+    let mut inputs = tvec!(a, model.add_const("b", b)?);
+    let bias = acc.iter().map(|x| x - 1).collect_vec();
+    inputs.extend(wire_q_params(
+        &mut model,
+        "",
+        [
+            tensor1(&bias),
+            tensor0(0i8),
+            tensor0(1f32),
+            tensor0(0i8),
+            tensor0(1f32),
+            tensor0(0i8),
+            tensor0(1f32),
+        ],
+    )?);
+    let op = EinSum {
+        q_overflow: overflow,
+        ..EinSum::newq("mk,kn,m,,,,,,->mn".parse()?, i32::datum_type(), dt)
+    };
+    let c = model.wire_node("einsum", op, &inputs)?;
+    model.set_output_outlets(&c)?;
+    Ok(model)
+}
+
+#[test]
+fn output_overflow_at_type_bounds() -> TractResult<()> {
+    for (dt, min, max) in [(i8::datum_type(), -128, 127), (u8::datum_type(), 0, 255)] {
+        let acc = [min, min + 1, min - 1, max, max - 1, max + 1, -100_000, 100_000];
+        let a = [1i8, 0, 0].repeat(acc.len());
+        let a = Tensor::from_shape(&[acc.len(), 3], &a)?.into_tvalue();
+        for overflow in [QOverflow::Saturate, QOverflow::Wrap] {
+            let expected = acc
+                .iter()
+                .flat_map(|&x| [x, x + 1])
+                .map(|x| match overflow {
+                    QOverflow::Saturate => x.clamp(min, max),
+                    QOverflow::Wrap if dt == i8::datum_type() => x as i8 as i32,
+                    QOverflow::Wrap => x as u8 as i32,
+                })
+                .collect_vec();
+            let model = overflowing_model(&acc, dt, overflow)?;
+            let optimized = model.clone().into_optimized()?;
+            assert!(optimized
+                .nodes()
+                .iter()
+                .any(|n| n.op_is::<crate::ops::matmul::lir_unary::LirMatMulUnary>()));
+            for model in [model, optimized] {
+                let found = model.into_runnable()?.run(tvec!(a.clone()))?.remove(0);
+                assert_eq!(found.datum_type(), dt);
+                let found = found.cast_to::<i32>()?;
+                assert_eq!(found.as_slice::<i32>()?, &*expected, "{dt:?} {overflow:?}");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn misaligned_output_quantization_is_an_error() -> TractResult<()> {
+    // along the contracted axis
+    let err = per_channel_output_model("k", tensor1(&[0i8; 6]), tensor1(&[1f32; 6])).unwrap_err();
+    let message = format!("{err:#}");
+    assert!(message.contains("einsum"), "{message}");
+    assert!(
+        message
+            .contains("c0 of mk,kn,,,,,,k,k->mn must be a scalar or a vector along an output axis"),
+        "{message}"
+    );
+    // wrong length
+    let err = per_channel_output_model("m", tensor1(&[0i8; 4]), tensor1(&[1f32; 5])).unwrap_err();
+    let message = format!("{err:#}");
+    assert!(message.contains("einsum"), "{message}");
+    assert!(
+        message.contains("c_scale of mk,kn,,,,,,m,m->mn runs along output axis m of length 4"),
+        "{message}"
+    );
+    Ok(())
+}
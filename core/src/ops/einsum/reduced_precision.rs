@@ -0,0 +1,200 @@
+//! Reduced precision: f32 einsums running in f16, picked by their impact on the outputs.
+//!
+//! `TypedModel::f16_error_budget` runs the model on calibration inputs with each candidate einsum
+//! alone switched to f16, and ranks the einsums by the error it induces on the model outputs.
+//! `TypedModel::apply_f16` then switches the einsums of a small enough impact, all together.
+use super::EinSum;
+use crate::internal::*;
+use crate::ops::cast::cast;
+use tract_itertools::Itertools;
+
+/// The impact on the model outputs of running an einsum in f16, the rest staying in f32.
+///
+/// Errors are relative to the largest absolute value of each output over the calibration
+/// inputs, so that outputs close to zero do not dominate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrecisionImpact {
+    /// Name of the einsum node.
+    pub node: String,
+    /// Largest relative error over the outputs and calibration inputs.
+    pub max_relative_error: f32,
+    /// Mean relative error over the outputs and calibration inputs.
+    pub mean_relative_error: f32,
+}
+
+pub(crate) fn f16_error_budget(
+    model: &TypedModel,
+    calibration: &[TVec<TValue>],
+) -> TractResult<Vec<PrecisionImpact>> {
+    ensure!(!calibration.is_empty(), "No calibration input");
+    let reference = run_all(model, calibration).context("Running the f32 model")?;
+    let mut impacts = vec![];
+    for id in model.eval_order()? {
+        let node = model.node(id);
+        let Some(op) = node.op_as::<EinSum>() else { continue };
+        if !is_candidate(model, node, op)? {
+            continue;
+        }
+        let mut patched = model.clone();
+        f16_patch(model, node, op)?.apply(&mut patched)?;
+        let outputs = run_all(&patched, calibration)
+            .with_context(|| format!("Running the model with {node} in f16"))?;
+        let (max_relative_error, mean_relative_error) = relative_errors(&reference, &outputs)?;
+        impacts.push(PrecisionImpact {
+            node: node.name.clone(),
+            max_relative_error,
+            mean_relative_error,
+        });
+    }
+    impacts.sort_by(|a, b| {
+        b.max_relative_error
+            .total_cmp(&a.max_relative_error)
+            .then(b.mean_relative_error.total_cmp(&a.mean_relative_error))
+    });
+    Ok(impacts)
+}
+
+pub(crate) fn apply_f16(
+    model: &mut TypedModel,
+    impacts: &[PrecisionImpact],
+    max_relative_error: f32,
+) -> TractResult<Vec<String>> {
+    let mut applied = vec![];
+    for impact in impacts.iter().filter(|i| i.max_relative_error <= max_relative_error) {
+        let node = model.node_by_name(&impact.node)?;
+        let op = node.op_as::<EinSum>().with_context(|| format!("{node} is not an einsum"))?;
+        ensure!(is_candidate(model, node, op)?, "{node} can not run in f16");
+        let patch = f16_patch(model, node, op)?;
+        patch.apply(model)?;
+        applied.push(impact.node.clone());
+    }
+    model.compact()?;
+    Ok(applied)
+}
+
+/// An f32 einsum of f32 inputs.
+fn is_candidate(model: &TypedModel, node: &TypedNode, op: &EinSum) -> TractResult<bool> {
+    Ok(op.q_params.is_none()
+        && op.operating_dt == f32::datum_type()
+        && model.node_input_facts(node.id)?.iter().all(|f| f.datum_type == f32::datum_type()))
+}
+
+/// The einsum running in f16, its inputs and output cast around it.
+fn f16_patch(model: &TypedModel, node: &TypedNode, op: &EinSum) -> TractResult<TypedModelPatch> {
+    let name = &node.name;
+    let mut patch = TypedModelPatch::new(format!("f16 {node}"));
+    let mut inputs = tvec!();
+    for (ix, input) in node.inputs.iter().enumerate() {
+        let wire = patch.tap_model(model, *input)?;
+        let to_f16 = cast(f16::datum_type());
+        inputs.push(patch.wire_node(format!("{name}.to_f16.{ix}"), to_f16, &[wire])?[0]);
+    }
+    let einsum = EinSum { operating_dt: f16::datum_type(), ..op.clone() };
+    let wire = patch.wire_node(format!("{name}.f16"), einsum, &inputs)?;
+    let wire = patch.wire_node(name, cast(f32::datum_type()), &wire)?[0];
+    patch.shunt_outside(model, node.id.into(), wire)?;
+    Ok(patch)
+}
+
+fn run_all(model: &TypedModel, calibration: &[TVec<TValue>]) -> TractResult<Vec<TVec<TValue>>> {
+    let plan = SimplePlan::new(model)?;
+    calibration.iter().map(|inputs| plan.run(inputs.clone())).collect()
+}
+
+/// Max and mean relative errors of `found` against `reference`, over all their outputs.
+fn relative_errors(reference: &[TVec<TValue>], found: &[TVec<TValue>]) -> TractResult<(f32, f32)> {
+    let outputs = reference[0].len();
+    let mut max = 0f32;
+    let (mut sum, mut count) = (0f64, 0usize);
+    for output in 0..outputs {
+        let views = |runs: &[TVec<TValue>]| -> TractResult<Vec<Tensor>> {
+            runs.iter().map(|run| run[output].cast_to::<f32>().map(|t| t.into_owned())).collect()
+        };
+        let (reference, found) = (views(reference)?, views(found)?);
+        let scale = reference
+            .iter()
+            .flat_map(|t| t.as_slice::<f32>().unwrap().iter())
+            .fold(0f32, |m, x| m.max(x.abs()))
+            .max(f32::MIN_POSITIVE);
+        for (r, f) in reference.iter().zip_eq(found.iter()) {
+            for (r, f) in r.as_slice::<f32>()?.iter().zip_eq(f.as_slice::<f32>()?) {
+                let error = (r - f).abs() / scale;
+                // an overflow to infinity or nan is as bad as it gets
+                let error = if error.is_nan() { f32::INFINITY } else { error };
+                max = max.max(error);
+                sum += error as f64;
+                count += 1;
+            }
+        }
+    }
+    Ok((max, (sum / count.max(1) as f64) as f32))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops;
+
+    fn weights(shape: &[usize], seed: usize) -> Tensor {
+        let len = shape.iter().product::<usize>();
+        let data = (0..len).map(|i| ((i * seed + 7) % 23) as f32 / 23. - 0.3).collect::<Vec<_>>();
+        Tensor::from_shape(shape, &data).unwrap()
+    }
+
+    /// Three dense layers with relus. The last one has large positive weights: its products
+    /// overflow the f16 range.
+    fn mlp() -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let mut wire = model.add_source("x", f32::fact([4, 16]))?;
+        let zero = model.add_const("zero", tensor2(&[[0f32]]))?;
+        for (ix, k) in [16, 32].into_iter().enumerate() {
+            let w = model.add_const(format!("w{ix}"), weights(&[k, 32], 5 + ix))?;
+            let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+            wire = model.wire_node(format!("layer{ix}"), einsum, &[wire, w])?[0];
+            wire = model.wire_node(format!("relu{ix}"), ops::math::max(), &[wire, zero])?[0];
+        }
+        let w = weights(&[32, 4], 3).into_array::<f32>()?.mapv(|x| x.abs() * 20000.);
+        let w = model.add_const("w_out", w.into_tensor())?;
+        let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let y = model.wire_node("output", einsum, &[wire, w])?;
+        model.set_output_outlets(&y)?;
+        Ok(model)
+    }
+
+    fn calibration() -> Vec<TVec<TValue>> {
+        (0..3)
+            .map(|seed| {
+                let x = (0..64).map(|i| ((i * 13 + seed) % 29) as f32 / 7.).collect::<Vec<_>>();
+                tvec!(Tensor::from_shape(&[4, 16], &x).unwrap().into_tvalue())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn last_layer_is_most_sensitive() -> TractResult<()> {
+        let model = mlp()?;
+        let impacts = model.f16_error_budget(&calibration())?;
+        let nodes = impacts.iter().map(|i| &*i.node).collect::<Vec<_>>();
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(nodes[0], "output");
+        assert!(impacts[0].max_relative_error > 1.);
+        assert!(impacts[1..].iter().all(|i| i.max_relative_error < 1e-2), "{impacts:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn f16_applied_within_threshold() -> TractResult<()> {
+        let model = mlp()?;
+        let calibration = calibration();
+        let impacts = model.f16_error_budget(&calibration)?;
+        let mut reduced = model.clone();
+        let applied = reduced.apply_f16(&impacts, 1e-2)?;
+        assert_eq!(applied.iter().sorted().collect::<Vec<_>>(), ["layer0", "layer1"]);
+        let einsums = reduced.nodes().iter().filter_map(|n| n.op_as::<EinSum>()).collect_vec();
+        assert_eq!(einsums.iter().filter(|e| e.operating_dt == f16::datum_type()).count(), 2);
+        let reference = run_all(&model, &calibration)?;
+        let found = run_all(&reduced, &calibration)?;
+        assert!(relative_errors(&reference, &found)?.0 < 2e-2);
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! Shapes of einsum operands: ranks, input counts, broadcasting and axis injections.
+
+use super::*;
+
+#[test]
+fn rank_mismatch_is_an_error() -> TractResult<()> {
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", f32::fact([2, 8, 4]))?;
+    let b = model.add_source("b", f32::fact([4, 2]))?;
+    let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+    let err = model.wire_node("einsum", einsum, &[a, b]).unwrap_err();
+    let message = format!("{err:#}");
+    assert!(message.contains("einsum"), "{message}");
+    assert!(message.contains("Input #0 of mk,kn->mn has rank 3, expected 2"), "{message}");
+    Ok(())
+}
+
+#[test]
+fn input_count_mismatch() -> TractResult<()> {
+    let f32 = f32::datum_type();
+    assert!(EinSum::try_new("mk,kn,k->mn".parse()?, f32, 2).is_err());
+    assert!(EinSum::try_new("mk,kn->mn,nm".parse()?, f32, 2).is_err());
+    let i32 = i32::datum_type();
+    assert!(EinSum::try_newq("mk,kn->mn".parse()?, i32, i8::datum_type()).is_err());
+    let einsum = EinSum::try_new("mk,kn->mn".parse()?, f32, 2)?;
+
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", f32::fact([8, 4]))?;
+    let b = model.add_source("b", f32::fact([4, 2]))?;
+    let bad = EinSum::new("mk,kn,k->mn".parse()?, f32);
+    let message = format!("{:#}", model.wire_node("einsum", bad.clone(), &[a, b]).unwrap_err());
+    assert!(message.contains("einsum"), "{message}");
+    assert!(message.contains("mk,kn,k->mn expects 3 inputs, got 2"), "{message}");
+
+    let inputs = tvec!(tensor2(&[[1f32]]).into_tvalue(), tensor2(&[[2f32]]).into_tvalue());
+    assert!(bad.eval(inputs.clone()).is_err());
+    assert_eq!(einsum.eval(inputs)?[0], tensor2(&[[2f32]]).into_tvalue());
+    Ok(())
+}
+
+#[test]
+fn rank_mismatch_defers_rewrites() -> TractResult<()> {
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", f32::fact([8, 4]))?;
+    let b = model.add_source("b", f32::fact([4, 2]))?;
+    let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+    let c = model.wire_node("einsum", einsum, &[a, b])?;
+    model.set_output_outlets(&c)?;
+    // an op whose mapping disagrees with the facts, as a partially typed model could hold
+    model.node_mut(c[0].node).op = Box::new(EinSum::new("bmk,kn->bmn".parse()?, f32::datum_type()));
+    let node = model.node(c[0].node);
+    assert!(node.op.declutter(&model, node)?.is_none());
+    assert!(node.op.codegen(&model, node)?.is_none());
+    Ok(())
+}
+
+#[test]
+fn injection_past_ascii_labels() -> TractResult<()> {
+    use crate::ops::matmul::lir_unary::LirMatMulUnary;
+    // an outer product over 53 axes: all ASCII letters are taken when k is injected
+    let labels: String = ('a'..='z').chain('A'..='Z').collect();
+    let extra = AxesMapping::from_strs(&[&labels], &[&labels])?.available_label();
+    assert!(extra.is_alphabetic() && !extra.is_ascii());
+    let expr = format!("{labels},{extra}->{labels}{extra}");
+    let mut a_shape = tvec![1; 52];
+    a_shape[0] = 2;
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", f32::fact(&a_shape))?;
+    let b = model.add_source("b", f32::fact([3]))?;
+    let einsum = EinSum::new(expr.parse()?, f32::datum_type());
+    let c = model.wire_node("einsum", einsum, &[a, b])?;
+    model.set_output_outlets(&c)?;
+    let inputs = tvec!(
+        Tensor::from_shape(&a_shape, &[1f32, 2.])?.into_tvalue(),
+        tensor1(&[3f32, 4., 5.]).into_tvalue()
+    );
+    let expected = model.clone().into_runnable()?.run(inputs.clone())?;
+    let optimized = model.into_optimized()?;
+    assert!(optimized.nodes.iter().any(|n| n.op_is::<LirMatMulUnary>()));
+    assert_eq!(optimized.into_runnable()?.run(inputs)?, expected);
+    Ok(())
+}
+
+#[test]
+fn failed_injection_is_reported_and_declined() -> TractResult<()> {
+    use crate::optim::report::DeclineReason;
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", f32::fact([3, 4]))?;
+    let b = model.add_source("b", f32::fact([4, 5]))?;
+    let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+    let c = model.wire_node("einsum", einsum, &[a, b])?;
+    model.set_output_outlets(&c)?;
+    let node = model.node(c[0].node);
+    let op = node.op_as::<EinSum>().unwrap();
+    // m is already there: linking it to a new output axis puts it twice in the output
+    let err = codegen::inject_m_or_n_axis(op, &model, node, false, &[]).unwrap_err();
+    let message = format!("{err:#}");
+    assert!(message.contains("Injecting m axis in #2 \"einsum\""), "{message}");
+    assert!(message.contains("mk,kn->mn"), "{message}");
+    assert!(matches!(
+        codegen::injected(Err(err)),
+        codegen::AxesOrPatch::Declined(DeclineReason::AxisInjection)
+    ));
+    Ok(())
+}
+
+/// `a` and `b` share the batch symbol, `c` has a broadcast 1 meeting it on the batch axis.
+/// The einsum output must keep `S`, so that it adds up with `a`.
+fn symbolic_broadcast_model(c_first: bool) -> TractResult<TypedModel> {
+    let mut model = TypedModel::default();
+    let s = model.symbol_table.sym("S");
+    let a = model.add_source("a", f32::fact(dims![s, 4, 3].as_ref()))?;
+    let b = model.add_source("b", f32::fact(dims![s, 3, 3].as_ref()))?;
+    let c = model.add_source("c", f32::fact([1, 3, 3]))?;
+    let inputs = if c_first { [a, c, b] } else { [a, b, c] };
+    let einsum = EinSum::new("bmk,bkn,bkn->bmn".parse()?, f32::datum_type());
+    let product = model.wire_node("einsum", einsum, &inputs)?[0];
+    let sum = model.wire_node("add", add(), &[product, a])?;
+    model.set_output_outlets(&sum)?;
+    Ok(model)
+}
+
+#[test]
+fn symbol_survives_broadcast_one() -> TractResult<()> {
+    for c_first in [false, true] {
+        let model = symbolic_broadcast_model(c_first)?;
+        let expected: TVec<TDim> = model.input_fact(0)?.shape.to_tvec();
+        let einsum = model.node_by_name("einsum")?.id;
+        assert_eq!(model.outlet_fact(einsum.into())?.shape.to_tvec(), expected);
+        assert_eq!(model.output_fact(0)?.shape.to_tvec(), expected);
+        let optimized = model.into_optimized()?;
+        assert_eq!(optimized.output_fact(0)?.shape.to_tvec(), expected);
+    }
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use crate::internal::*;
+
+/// Names of the quantization parameters a quantized einsum takes after a and b, in order.
+const Q_PARAMS: [&str; 7] = ["bias", "a0", "a_scale", "b0", "b_scale", "c0", "c_scale"];
+
+/// Wire the quantization parameters of a quantized einsum as constants named after
+/// `Q_PARAMS` (prefixed with `prefix.` if it is not empty), ready to follow a and b in its inputs.
+pub(crate) fn wire_q_params(
+    model: &mut TypedModel,
+    prefix: &str,
+    values: [Tensor; 7],
+) -> TractResult<TVec<OutletId>> {
+    Q_PARAMS
+        .iter()
+        .zip(values)
+        .map(|(name, t)| {
+            let name =
+                if prefix.is_empty() { name.to_string() } else { format!("{prefix}.{name}") };
+            model.add_const(name, t)
+        })
+        .collect()
+}
+
+/// Check a quantized output is at most one quantization step away from the expected one,
+/// element-wise.
+pub(crate) fn ensure_within_one_step(found: &Tensor, expected: &Tensor) -> TractResult<()> {
+    let (f, e) = (found.cast_to::<f64>()?, expected.cast_to::<f64>()?);
+    ensure!(f.len() == e.len(), "found {found:?} expected {expected:?}");
+    for (f, e) in f.as_slice::<f64>()?.iter().zip(e.as_slice::<f64>()?) {
+        ensure!((f - e).abs() <= 1., "found {found:?} expected {expected:?}");
+    }
+    Ok(())
+}
+
+/// The model right after the quantized einsum codegen patch, before any fusion.
+pub(crate) fn dequant_lowered(model: &TypedModel) -> TractResult<TypedModel> {
+    let mut model = model.clone().into_decluttered()?;
+    let einsum = model.node_by_name("einsum")?;
+    let patch = einsum.op.codegen(&model, einsum)?.unwrap();
+    patch.apply(&mut model)?;
+    model.into_compact()
+}
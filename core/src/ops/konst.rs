@@ -1,9 +1,149 @@
 use crate::internal::*;
 
+/// Node property of the Const nodes holding the packed form of a matmul constant: the digest and
+/// the byte length, as two u64, of the tensor before packing (see `TypedModel::constant_digests`).
+pub const CONSTANT_DIGEST: &str = "const.digest";
+
 #[derive(Debug, Clone, new, Hash)]
 pub struct Const(pub Arc<Tensor>);
 
+/// Digest of the content of a tensor: its datum type, its shape and its raw data.
+///
+/// Unlike `Hash`, it is stable across builds and platforms (64 bits FNV-1a over a fixed tag of
+/// the datum type and the little-endian data), and it is fed incrementally, so the data of a
+/// large constant can be streamed through it: `update` expects its items in little-endian order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContentDigest(u64);
+
+impl ContentDigest {
+    pub fn new(dt: DatumType, shape: &[usize]) -> ContentDigest {
+        let mut digest = ContentDigest(0xcbf29ce484222325);
+        digest.update_datum_type(dt);
+        digest.update(&(shape.len() as u64).to_le_bytes());
+        for dim in shape {
+            digest.update(&(*dim as u64).to_le_bytes());
+        }
+        digest
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+
+    /// Feed a tag of `dt` not depending on its `Debug` form, with its quantization parameters.
+    fn update_datum_type(&mut self, dt: DatumType) {
+        use DatumType::*;
+        let (tag, qparams) = match dt {
+            Bool => (0u8, None),
+            U8 => (1, None),
+            U16 => (2, None),
+            U32 => (3, None),
+            U64 => (4, None),
+            I8 => (5, None),
+            I16 => (6, None),
+            I32 => (7, None),
+            I64 => (8, None),
+            F16 => (9, None),
+            F32 => (10, None),
+            F64 => (11, None),
+            TDim => (12, None),
+            Blob => (13, None),
+            String => (14, None),
+            QI8(qp) => (15, Some(qp)),
+            QU8(qp) => (16, Some(qp)),
+            QI32(qp) => (17, Some(qp)),
+            #[cfg(feature = "complex")]
+            ComplexI16 => (18, None),
+            #[cfg(feature = "complex")]
+            ComplexI32 => (19, None),
+            #[cfg(feature = "complex")]
+            ComplexI64 => (20, None),
+            #[cfg(feature = "complex")]
+            ComplexF16 => (21, None),
+            #[cfg(feature = "complex")]
+            ComplexF32 => (22, None),
+            #[cfg(feature = "complex")]
+            ComplexF64 => (23, None),
+        };
+        self.update(&[tag]);
+        match qparams {
+            Some(QParams::MinMax { min, max }) => {
+                self.update(&[0]);
+                self.update(&min.to_le_bytes());
+                self.update(&max.to_le_bytes());
+            }
+            Some(QParams::ZpScale { zero_point, scale }) => {
+                self.update(&[1]);
+                self.update(&zero_point.to_le_bytes());
+                self.update(&scale.to_le_bytes());
+            }
+            None => (),
+        }
+    }
+}
+
+/// Size of the items swapped to little-endian in a tensor of `dt`: complex numbers are swapped
+/// component by component.
+fn endian_item_size(dt: DatumType) -> usize {
+    #[cfg(feature = "complex")]
+    if dt.is_complex() {
+        return dt.size_of() / 2;
+    }
+    dt.size_of()
+}
+
+/// Digest and byte length of the content of a tensor of a plain datum type.
+pub fn tensor_digest(tensor: &Tensor) -> TractResult<(u64, usize)> {
+    let dt = tensor.datum_type();
+    ensure!(dt.is_copy(), "Can not digest a tensor of {dt:?}");
+    let len = tensor.len() * dt.size_of();
+    let mut digest = ContentDigest::new(dt, tensor.shape());
+    let bytes = unsafe { &tensor.as_bytes()[..len] };
+    if cfg!(target_endian = "little") {
+        digest.update(bytes);
+    } else {
+        let mut item = vec![0u8; endian_item_size(dt)];
+        for chunk in bytes.chunks(item.len()) {
+            item.iter_mut().zip(chunk.iter().rev()).for_each(|(i, c)| *i = *c);
+            digest.update(&item);
+        }
+    }
+    Ok((digest.finish(), len))
+}
 
+/// Record on `node`, the Const holding the packed form of `konst`, the digest of `konst`.
+pub(crate) fn record_digest(
+    model: &mut TypedModel,
+    node: usize,
+    konst: &Tensor,
+) -> TractResult<()> {
+    if !konst.datum_type().is_copy() {
+        return Ok(());
+    }
+    let (digest, len) = tensor_digest(konst)?;
+    model.set_node_property(node, CONSTANT_DIGEST, rctensor1(&[digest, len as u64]))
+}
+
+pub(crate) fn constant_digests(model: &TypedModel) -> TractResult<Vec<(String, u64, usize)>> {
+    let mut digests = vec![];
+    for node in model.nodes() {
+        let Some(konst) = node.op_as::<Const>() else { continue };
+        if let Some(recorded) = model.node_property(node.id, CONSTANT_DIGEST) {
+            let recorded = recorded.as_slice::<u64>()?;
+            digests.push((node.name.clone(), recorded[0], recorded[1] as usize));
+        } else if konst.0.datum_type().is_copy() {
+            let (digest, len) = tensor_digest(&konst.0)?;
+            digests.push((node.name.clone(), digest, len));
+        }
+    }
+    Ok(digests)
+}
 
 impl Op for Const {
     fn name(&self) -> Cow<str> {
@@ -53,3 +193,115 @@ impl TypedOp for Const {
         Ok(tvec!((Cost::Params(f32::datum_type()), self.0.len().into())))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::einsum::EinSum;
+    use crate::optim::OptimizerOptions;
+
+    fn model() -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let w = (0..64 * 48).map(|i| ((i * 7) % 31) as f32 / 31.).collect::<Vec<_>>();
+        let w = model.add_const("w", Tensor::from_shape(&[64, 48], &w)?)?;
+        let x = model.add_source("x", f32::fact([48, 4]))?;
+        let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let y = model.wire_node("y", einsum, &[w, x])?;
+        model.set_output_outlets(&y)?;
+        Ok(model)
+    }
+
+    fn digests(model: &TypedModel) -> TractResult<Vec<(u64, usize)>> {
+        let mut digests =
+            model.constant_digests()?.into_iter().map(|d| (d.1, d.2)).collect::<Vec<_>>();
+        digests.sort();
+        Ok(digests)
+    }
+
+    #[test]
+    fn streamed_digest() -> TractResult<()> {
+        let t = Tensor::from_shape(&[3, 5], &(0..15).map(|i| i as f32).collect::<Vec<_>>())?;
+        let mut digest = ContentDigest::new(f32::datum_type(), &[3, 5]);
+        for chunk in unsafe { t.as_bytes() }.chunks(7) {
+            digest.update(chunk);
+        }
+        assert_eq!(tensor_digest(&t)?, (digest.finish(), 60));
+        let reshaped = t.clone().into_shape(&[5, 3])?;
+        assert_ne!(tensor_digest(&reshaped)?.0, digest.finish());
+        assert_ne!(tensor_digest(&*t.cast_to::<f64>()?)?.0, digest.finish());
+        Ok(())
+    }
+
+    #[test]
+    fn digest_is_pinned() -> TractResult<()> {
+        // the same on every build and platform: a change breaks the recorded digests. FNV-1a of
+        // the i32 tag (7), the rank and dims as u64, and the little-endian items
+        let t = Tensor::from_shape(&[2, 2], &[1i32, -2, 3, -4])?;
+        assert_eq!(tensor_digest(&t)?, (0x981b8bb39b52a9fc, 16));
+        Ok(())
+    }
+
+    #[test]
+    fn digest_tells_quantization_parameters_apart() -> TractResult<()> {
+        let t = Tensor::from_shape(&[3], &[1i8, 2, 3])?;
+        let digest = |zero_point: i32, scale: f32| -> TractResult<u64> {
+            let dt = DatumType::QI8(QParams::ZpScale { zero_point, scale });
+            let mut t = t.clone();
+            unsafe { t.set_datum_type(dt) };
+            Ok(tensor_digest(&t)?.0)
+        };
+        assert_ne!(digest(0, 0.1)?, digest(1, 0.1)?);
+        assert_ne!(digest(0, 0.1)?, digest(0, 0.2)?);
+        assert_ne!(digest(0, 0.1)?, tensor_digest(&t)?.0);
+        Ok(())
+    }
+
+    #[test]
+    fn digest_survives_packing() -> TractResult<()> {
+        let model = model()?;
+        let before = digests(&model)?;
+        assert_eq!(before[0].1, 64 * 48 * 4);
+        let optimized = model.into_optimized()?;
+        assert!(optimized.nodes().iter().all(|n| n.name != "w"));
+        assert_eq!(digests(&optimized)?, before);
+        Ok(())
+    }
+
+    #[test]
+    fn digest_of_pack_evaluated_when_wired() -> TractResult<()> {
+        use crate::ops::matmul::pack::MatMatMulPack;
+        let mut model = model()?;
+        let before = digests(&model)?;
+        let w = model.node_by_name("w")?.id;
+        let mmm = tract_linalg::ops().mmm_f32_impls()[0].clone();
+        let pack = MatMatMulPack::new(mmm.a_pack(), 1, 0, 2)?;
+        let packed = model.wire_node("packed", pack, &[w.into()])?[0];
+        assert!(model.node(packed.node).op_is::<Const>());
+        let packed = model.constant_digests()?.into_iter().find(|d| d.0 == "packed").unwrap();
+        assert_eq!((packed.1, packed.2), before[0]);
+        Ok(())
+    }
+
+    #[test]
+    fn digest_independent_of_kernel() -> TractResult<()> {
+        let model = model()?;
+        let before = digests(&model)?;
+        let impls = tract_linalg::ops().mmm_f32_impls();
+        let mut packed = vec![];
+        for mmm in [impls.first().unwrap(), impls.last().unwrap()] {
+            let options = OptimizerOptions {
+                mmm_f32_kernel: Some(mmm.kernel_name().to_string()),
+                ..OptimizerOptions::default()
+            };
+            let optimized = model.clone().into_optimized_with_options(&options)?;
+            assert_eq!(digests(&optimized)?, before);
+            let konst = optimized.nodes().iter().find_map(|n| n.op_as::<Const>()).unwrap();
+            packed.push((mmm.a_pack(), konst.0.clone()));
+        }
+        // with kernels of different panels, the packed blobs differ but not their digests
+        if packed[0].0 != packed[1].0 {
+            assert_ne!(packed[0].1, packed[1].1);
+        }
+        Ok(())
+    }
+}
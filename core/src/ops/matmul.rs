@@ -1,8 +1,25 @@
+pub mod compressed;
+pub mod external;
+pub mod group_quant;
 pub mod lir_unary;
+pub mod lowering;
 pub mod mir_quant;
+pub mod numerics;
 pub mod pack;
+pub mod palette;
+pub mod prefix_loops;
+pub mod roofline;
+pub mod simple;
+pub mod strided;
+pub mod summary;
+pub mod tiling;
+pub mod tiny_batch;
+pub mod top_k;
+pub mod weight_variants;
 
 use crate::internal::*;
+use std::rc::Rc;
+use tract_itertools::Itertools;
 
 pub fn output_type(input: DatumType) -> DatumType {
     if input.is_float() {
@@ -11,3 +28,542 @@ pub fn output_type(input: DatumType) -> DatumType {
         i32::datum_type()
     }
 }
+
+/// Largest tensor a target can allocate, in bytes.
+pub const MAX_TENSOR_BYTES: usize = isize::MAX as usize;
+
+/// Size in bytes of a `dt` tensor of `shape`, failing instead of wrapping around when it does
+/// not fit in a usize.
+pub fn checked_byte_size(dt: DatumType, shape: &[usize]) -> TractResult<usize> {
+    shape
+        .iter()
+        .try_fold(dt.size_of(), |size, &d| size.checked_mul(d))
+        .with_context(|| format!("Size of a {dt:?} tensor of shape {shape:?} overflows usize"))
+}
+
+/// Limits on a single run of a matrix multiplication node, checked before its output is
+/// allocated, so a pathological shape fails at once instead of exhausting the memory or running
+/// for hours (see `SimplePlan::with_matmul_limits`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MatMulLimits {
+    /// Largest output, in bytes.
+    pub max_output_bytes: usize,
+    /// Largest number of kernel invocations: one per coordinates of the output prefix axes, or
+    /// per macro tile.
+    pub max_kernel_calls: usize,
+}
+
+impl Default for MatMulLimits {
+    fn default() -> MatMulLimits {
+        MatMulLimits { max_output_bytes: MAX_TENSOR_BYTES, max_kernel_calls: 1 << 30 }
+    }
+}
+
+impl MatMulLimits {
+    /// No limit but the addressable memory.
+    pub fn unlimited() -> MatMulLimits {
+        MatMulLimits { max_kernel_calls: usize::MAX, ..MatMulLimits::default() }
+    }
+
+    /// Check a product computing a `dt` output of `shape` in `kernel_calls` kernel invocations
+    /// (None if their number overflows usize).
+    pub fn check(
+        &self,
+        dt: DatumType,
+        shape: &[usize],
+        kernel_calls: Option<usize>,
+    ) -> TractResult<()> {
+        let bytes = checked_byte_size(dt, shape)?;
+        ensure!(
+            bytes <= self.max_output_bytes,
+            "Output is a {dt:?} tensor of shape {shape:?}: {bytes} bytes exceeds the {} bytes limit",
+            self.max_output_bytes
+        );
+        let calls = kernel_calls
+            .with_context(|| format!("Kernel calls for an output of shape {shape:?} overflow"))?;
+        ensure!(
+            calls <= self.max_kernel_calls,
+            "Output of shape {shape:?} takes {calls} kernel calls, exceeding the limit of {}",
+            self.max_kernel_calls
+        );
+        Ok(())
+    }
+}
+
+fn is_matmul_family(op: &dyn TypedOp) -> bool {
+    let op = op.as_op();
+    op.is::<crate::ops::einsum::EinSum>()
+        || op.is::<lir_unary::LirMatMulUnary>()
+        || op.is::<pack::MatMatMulPack>()
+        || op.is::<external::ExternalGemm>()
+        || op.is::<tiny_batch::TinyBatchMatMul>()
+}
+
+/// Check that the outputs of the matrix multiplication nodes, once `symbols` are substituted,
+/// hold in `max_bytes` (like `MAX_TENSOR_BYTES`, or `u32::MAX` for a 32-bit target). Shapes
+/// still symbolic after substitution are not checked.
+pub fn check_output_sizes(
+    model: &TypedModel,
+    symbols: &SymbolValues,
+    max_bytes: usize,
+) -> TractResult<()> {
+    for node in model.nodes().iter().filter(|n| is_matmul_family(n.op.as_ref())) {
+        for output in &node.outputs {
+            let shape = output.fact.shape.iter().map(|d| d.eval(symbols)).collect::<TVec<_>>();
+            let Ok(shape) = shape.iter().map(|d| d.to_usize()).collect::<TractResult<TVec<_>>>()
+            else {
+                continue;
+            };
+            let bytes = checked_byte_size(output.fact.datum_type, &shape)
+                .with_context(|| format!("Checking output size of {node}"))?;
+            ensure!(
+                bytes <= max_bytes,
+                "Output of {node} is a {:?} tensor of shape {shape:?}: {bytes} bytes exceeds the {max_bytes} bytes limit",
+                output.fact.datum_type,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Output shape of an op depending on symbols with an upper bound (see
+/// `OptimizerOptions::symbol_bounds`), and its worst case.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BoundedShape {
+    pub bounds: TVec<(Symbol, i64)>,
+    pub max_shape: TVec<usize>,
+}
+
+impl BoundedShape {
+    /// None if the shape is concrete or depends on a symbol without a bound.
+    pub fn new(shape: &[TDim], bounds: &SymbolValues) -> Option<BoundedShape> {
+        let symbols = shape.iter().flat_map(|d| d.symbols()).sorted().dedup().collect::<TVec<_>>();
+        if symbols.is_empty() {
+            return None;
+        }
+        let bounds: TVec<(Symbol, i64)> =
+            symbols.into_iter().map(|s| bounds[&s].map(|b| (s, b))).collect::<Option<_>>()?;
+        let values = bounds.iter().fold(SymbolValues::default(), |v, (s, b)| v.with(s, *b));
+        let max_shape =
+            shape.iter().map(|d| d.eval(&values).to_usize().ok()).collect::<Option<_>>()?;
+        Some(BoundedShape { bounds, max_shape })
+    }
+
+    /// Check the run time values of the symbols against their bounds.
+    pub fn check(&self, values: &SymbolValues) -> TractResult<()> {
+        for (symbol, bound) in &self.bounds {
+            if let Some(value) = values[symbol] {
+                ensure!(value <= *bound, "Symbol {symbol} is bounded to {bound}, got {value}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Output buffer of an op state, allocated once for the worst case of a BoundedShape and
+/// reshaped for each run.
+#[derive(Debug, Default)]
+pub(crate) struct ReusedOutput(Option<Rc<Tensor>>);
+
+impl Clone for ReusedOutput {
+    fn clone(&self) -> ReusedOutput {
+        ReusedOutput(None)
+    }
+}
+
+impl ReusedOutput {
+    pub(crate) fn allocate(
+        &mut self,
+        dt: DatumType,
+        bounded: &BoundedShape,
+        alignment: usize,
+    ) -> TractResult<()> {
+        let buffer =
+            unsafe { Tensor::uninitialized_aligned_dt(dt, &bounded.max_shape, alignment)? };
+        self.0 = Some(Rc::new(buffer));
+        Ok(())
+    }
+
+    /// Compute the output in the buffer, shaped to `shape`. A new buffer is only allocated if
+    /// the output of the previous run is still in use.
+    pub(crate) fn compute(
+        &mut self,
+        dt: DatumType,
+        bounded: &BoundedShape,
+        alignment: usize,
+        shape: &[usize],
+        f: impl FnOnce(&mut Tensor) -> TractResult<()>,
+    ) -> TractResult<TValue> {
+        ensure!(
+            shape.iter().product::<usize>() <= bounded.max_shape.iter().product(),
+            "Output shape {shape:?} exceeds its bound {:?}",
+            bounded.max_shape
+        );
+        if self.0.as_mut().and_then(Rc::get_mut).is_none() {
+            self.allocate(dt, bounded, alignment)?;
+        }
+        let buffer = self.0.as_mut().and_then(Rc::get_mut).unwrap();
+        unsafe { buffer.set_shape_unchecked(shape) };
+        f(buffer)?;
+        Ok(TValue::Var(self.0.clone().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::einsum::EinSum;
+
+    fn model() -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let n = model.symbol_table.sym("N");
+        let a = model.add_source("a", f32::fact([64, 32]))?;
+        let b = model.add_source("b", f32::fact(dims!(32, n)))?;
+        let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", op, &[a, b])?;
+        model.set_output_outlets(&c)?;
+        Ok(model)
+    }
+
+    #[test]
+    fn byte_size_overflow() -> TractResult<()> {
+        assert_eq!(checked_byte_size(f32::datum_type(), &[3, 5])?, 60);
+        assert!(checked_byte_size(f32::datum_type(), &[usize::MAX / 2, 3]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn output_sizes_against_target_limit() -> TractResult<()> {
+        let model = model()?;
+        let n = model.symbol_table.sym("N");
+        let huge = SymbolValues::default().with(&n, 1 << 26);
+        // symbolic: nothing to check
+        check_output_sizes(&model, &SymbolValues::default(), u32::MAX as usize)?;
+        // 64 * 2^26 f32 is 16GiB: fine on 64 bits, not on 32 bits
+        check_output_sizes(&model, &huge, MAX_TENSOR_BYTES)?;
+        let err = check_output_sizes(&model, &huge, u32::MAX as usize).unwrap_err().to_string();
+        assert!(err.contains("einsum") && err.contains("[64, 67108864]"), "{err}");
+        let optimized = model.into_optimized()?;
+        assert!(check_output_sizes(&optimized, &huge, u32::MAX as usize).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn peak_memory_overflow() -> TractResult<()> {
+        let model = model()?.into_optimized()?;
+        let n = model.symbol_table.sym("N");
+        let plan = SimplePlan::new(model)?;
+        let values = SymbolValues::default().with(&n, 1 << 20);
+        // at least the 64xN f32 output
+        assert!(plan.peak_memory(&values)? >= 64 << 22);
+        let values = SymbolValues::default().with(&n, i64::MAX / 64);
+        assert!(plan.peak_memory(&values).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bounded_symbol_buffers_allocated_once() -> TractResult<()> {
+        let mut model = TypedModel::default();
+        let n = model.symbol_table.sym("N");
+        let a = (0..16 * 8).map(|i| (i % 7) as f32 - 3.).collect::<Vec<_>>();
+        let a = model.add_const("a", Tensor::from_shape(&[16, 8], &a)?)?;
+        let b = model.add_source("b", f32::fact(dims!(8, n)))?;
+        let op = crate::ops::einsum::EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", op, &[a, b])?;
+        model.set_output_outlets(&c)?;
+        let options = crate::optim::OptimizerOptions {
+            symbol_bounds: SymbolValues::default().with(&n, 64),
+            ..Default::default()
+        };
+        let optimized = model.clone().into_optimized_with_options(&options)?;
+        assert!(optimized.nodes().iter().any(|n| matches!(
+            n.op_as::<lir_unary::LirMatMulUnary>(),
+            Some(op) if op.bounded_output.is_some()
+        )));
+        let reference = model.into_runnable()?;
+        let mut state = SimpleState::new(optimized.into_runnable()?)?;
+        let mut buffer = None;
+        for run in 0..100 {
+            let n = (run * 37) % 64 + 1;
+            let b = (0..8 * n).map(|i| (i % 5) as f32 - 2.).collect::<Vec<_>>();
+            let b = Tensor::from_shape(&[8, n], &b)?;
+            let found = state.run(tvec!(b.clone().into_tvalue()))?;
+            let expected = reference.run(tvec!(b.into_tvalue()))?;
+            found[0].close_enough(&expected[0], Approximation::Approximate)?;
+            // every run computes its output in the buffer of the first one
+            let address = unsafe { found[0].as_bytes().as_ptr() };
+            assert_eq!(*buffer.get_or_insert(address), address);
+        }
+        let err = state.run(tvec!(Tensor::zero::<f32>(&[8, 100])?.into_tvalue())).unwrap_err();
+        assert!(format!("{err:?}").contains("Symbol N is bounded to 64, got 100"), "{err:?}");
+        Ok(())
+    }
+
+    /// x.w plus an activation of the product shape.
+    fn residual_model() -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let x = model.add_source("x", f32::fact([8, 16]))?;
+        let y = model.add_source("y", f32::fact([8, 12]))?;
+        let w = (0..16 * 12).map(|i| (i % 7) as f32 - 3.).collect::<Vec<_>>();
+        let w = model.add_const("w", Tensor::from_shape(&[16, 12], &w)?)?;
+        let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", op, &[x, w])?;
+        let activation = model.wire_node("activation", crate::ops::nn::sigmoid(), &[y])?;
+        let c = model.wire_node("residual", crate::ops::math::add(), &[c[0], activation[0]])?;
+        model.set_output_outlets(&c)?;
+        Ok(model)
+    }
+
+    fn residual_inputs() -> TVec<TValue> {
+        let x = (0..8 * 16).map(|i| (i % 5) as f32 - 2.).collect::<Vec<_>>();
+        let y = (0..8 * 12).map(|i| (i % 9) as f32 / 4. - 1.).collect::<Vec<_>>();
+        tvec!(
+            Tensor::from_shape(&[8, 16], &x).unwrap().into_tvalue(),
+            Tensor::from_shape(&[8, 12], &y).unwrap().into_tvalue()
+        )
+    }
+
+    #[test]
+    fn output_computed_in_activation_buffer() -> TractResult<()> {
+        use lir_unary::LirMatMulUnary;
+        let model = residual_model()?;
+        let expected = model.clone().into_runnable()?.run(residual_inputs())?;
+        let optimized = model.into_optimized()?;
+        let lir = optimized.nodes.iter().find(|n| n.op_is::<LirMatMulUnary>()).unwrap();
+        let slot = lir.op_as::<LirMatMulUnary>().unwrap().in_place_input.context("Not in place")?;
+        assert_eq!(optimized.node(lir.inputs[slot].node).name, "activation");
+
+        let plan = SimplePlan::new(&optimized)?;
+        let mut state = SimpleState::new(&plan)?;
+        let mut aliased = None;
+        let found =
+            state.run_plan_with_eval(residual_inputs(), |session, op_state, node, inputs| {
+                let input = unsafe { inputs.get(slot).map(|i| i.as_ptr_unchecked::<u8>()) };
+                let outputs = crate::plan::eval(session, op_state, node, inputs)?;
+                if node.id == lir.id {
+                    aliased = Some(input == Some(unsafe { outputs[0].as_ptr_unchecked::<u8>() }));
+                }
+                TractResult::Ok(outputs)
+            })?;
+        assert_eq!(aliased, Some(true));
+        found[0].close_enough(&expected[0], Approximation::Close)?;
+
+        // without the in place declaration, the plan needs room for one more product
+        let mut out_of_place = optimized.clone();
+        out_of_place.node_mut(lir.id).op_as_mut::<LirMatMulUnary>().unwrap().in_place_input = None;
+        let symbols = SymbolValues::default();
+        let peak = plan.peak_memory(&symbols)?;
+        let out_of_place = SimplePlan::new(out_of_place)?;
+        assert_eq!(out_of_place.peak_memory(&symbols)?, peak + 8 * 12 * 4);
+        assert_eq!(out_of_place.run(residual_inputs())?[0], found[0]);
+        Ok(())
+    }
+
+    #[test]
+    fn residual_fused_with_batch_broadcast() -> TractResult<()> {
+        use crate::ops::binary::{MergeOpUnicast, TypedBinOp};
+        use lir_unary::LirMatMulUnary;
+        for residual_shape in [[3, 8, 12], [1, 8, 12]] {
+            let mut model = TypedModel::default();
+            let x = model.add_source("x", f32::fact([3, 8, 16]))?;
+            let y = model.add_source("y", f32::fact([3, 16, 12]))?;
+            let residual = model.add_source("residual", f32::fact(residual_shape))?;
+            let op = EinSum::new("bmk,bkn->bmn".parse()?, f32::datum_type());
+            let c = model.wire_node("einsum", op, &[x, y])?;
+            let c = model.wire_node("add", crate::ops::math::add(), &[c[0], residual])?;
+            model.set_output_outlets(&c)?;
+            let input = |shape: &[usize], seed: usize| {
+                let len = shape.iter().product::<usize>();
+                let data = (0..len).map(|i| ((i * 7 + seed) % 23) as f32 / 8. - 1.);
+                Tensor::from_shape(shape, &data.collect::<Vec<_>>()).map(|t| t.into_tvalue())
+            };
+            let inputs =
+                tvec!(input(&[3, 8, 16], 1)?, input(&[3, 16, 12], 2)?, input(&residual_shape, 3)?);
+            let expected = model.clone().into_runnable()?.run(inputs.clone())?;
+            let optimized = model.into_optimized()?;
+            let add = |n: &TypedNode| n.op_is::<TypedBinOp>() || n.op_is::<MergeOpUnicast>();
+            assert!(!optimized.nodes().iter().any(add));
+            let lir = optimized.nodes().iter().find(|n| n.op_is::<LirMatMulUnary>()).unwrap();
+            let op = lir.op_as::<LirMatMulUnary>().unwrap();
+            assert!(op.fused_spec_names().contains(&"add_to_matrix".to_string()));
+            assert_eq!(optimized.node(lir.inputs.last().unwrap().node).name, "residual");
+            let found = optimized.into_runnable()?.run(inputs)?;
+            found[0].close_enough(&expected[0], Approximation::Close)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn packed_operand_can_not_host_the_output() -> TractResult<()> {
+        use lir_unary::LirMatMulUnary;
+        let mut optimized = residual_model()?.into_optimized()?;
+        let lir = optimized.nodes.iter().position(|n| n.op_is::<LirMatMulUnary>()).unwrap();
+        let op = optimized.node_mut(lir).op_as_mut::<LirMatMulUnary>().unwrap();
+        // the packed weights
+        op.in_place_input = Some(0);
+        let err = SimplePlan::new(&optimized).unwrap_err();
+        assert!(format!("{err:?}").contains("read by matmul"), "{err:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn fused_spec_profile() -> TractResult<()> {
+        use crate::ops::math::{add, max};
+        use std::time::{Duration, Instant};
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", f32::fact([96, 64]))?;
+        let b = model.add_const("b", Tensor::zero::<f32>(&[64, 48])?)?;
+        let bias = model.add_const("bias", Tensor::zero::<f32>(&[96, 1])?)?;
+        let zero = model.add_const("zero", Tensor::zero::<f32>(&[1, 1])?)?;
+        let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", op, &[a, b])?;
+        let c = model.wire_node("add_bias", add(), &[c[0], bias])?;
+        let c = model.wire_node("relu", max(), &[c[0], zero])?;
+        model.set_output_outlets(&c)?;
+        let model = model.into_optimized()?;
+        let lir = model.nodes().iter().find(|n| n.op_is::<lir_unary::LirMatMulUnary>()).unwrap();
+        let (lir_id, lir_name) = (lir.id, lir.name.clone());
+        let specs = lir.op_as::<lir_unary::LirMatMulUnary>().unwrap().fused_spec_names();
+        assert!(specs.len() >= 3, "{specs:?}");
+
+        let a = tvec!(Tensor::zero::<f32>(&[96, 64])?.into_tvalue());
+        let plan = Arc::new(TypedSimplePlan::new(model.clone())?);
+        assert!(SimpleState::new(plan)?.fused_spec_profile().is_none());
+        let plan = TypedSimplePlan::new(model)?.with_fused_spec_profiling(true);
+        let mut state = SimpleState::new(Arc::new(plan))?;
+        let mut total = Duration::default();
+        for _ in 0..20 {
+            state.run_plan_with_eval(a.clone(), |session, op_state, node, inputs| {
+                let start = Instant::now();
+                let outputs = crate::plan::eval(session, op_state, node, inputs);
+                if node.id == lir_id {
+                    total += start.elapsed();
+                }
+                outputs
+            })?;
+        }
+        let profile = state.fused_spec_profile().unwrap();
+        let keys = profile.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+        let expected = specs.iter().enumerate().map(|(ix, s)| format!("{lir_name}/{ix}:{s}"));
+        assert_eq!(keys, expected.collect::<Vec<_>>());
+        // the specs times are spent within the node: how much of it depends on the machine
+        let sum = profile.iter().map(|(_, t)| *t).sum::<Duration>();
+        assert!(sum <= total, "{sum:?} {total:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn mismatched_packed_operand_is_an_error() -> TractResult<()> {
+        use lir_unary::{LirMatMulUnary, ProtoFusedSpec};
+        let optimized = model()?.into_optimized()?;
+        let lir = optimized.nodes().iter().find(|n| n.op_is::<LirMatMulUnary>()).unwrap();
+        let op = lir.op_as::<LirMatMulUnary>().unwrap();
+        let ProtoFusedSpec::AddMatMul(_, _, b) = op.micro_ops[0] else { unreachable!() };
+        let plan = SimplePlan::new(&optimized)?.with_matmul_input_checks(true);
+        let inputs = tvec!(
+            Tensor::zero::<f32>(&[64, 32])?.into_tvalue(),
+            Tensor::zero::<f32>(&[32, 40])?.into_tvalue()
+        );
+        SimpleState::new(&plan)?.run(inputs.clone())?;
+
+        // B packed for a n smaller than the one the symbols resolve to
+        let mut state = SimpleState::new(&plan)?;
+        let err = state
+            .run_plan_with_eval(inputs, |session, op_state, node, mut inputs| {
+                if node.id == lir.id {
+                    let packed = inputs[b].slice(0, 0, inputs[b].len() / 2)?;
+                    inputs[b] = packed.into_tvalue();
+                }
+                crate::plan::eval(session, op_state, node, inputs)
+            })
+            .unwrap_err();
+        let err = format!("{err:?}");
+        assert!(err.contains(&lir.name) && err.contains("B (input #"), "{err}");
+        assert!(err.contains("mn=40"), "{err}");
+        Ok(())
+    }
+
+    /// A batch of 1×k by k×1 products, one kernel call each.
+    fn batched_model(batch: usize, k: usize) -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", f32::fact([batch, 1, k]))?;
+        let b = model.add_source("b", f32::fact([batch, k, 1]))?;
+        let op = EinSum::new("bmk,bkn->bmn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", op, &[a, b])?;
+        model.set_output_outlets(&c)?;
+        let optimized = model.into_optimized()?;
+        let lir = optimized.nodes().iter().find_map(|n| n.op_as::<lir_unary::LirMatMulUnary>());
+        assert_eq!(lir.and_then(|op| op.kernel_calls(&[batch, 1, 1])), Some(batch));
+        Ok(optimized)
+    }
+
+    fn batched_inputs(batch: usize, k: usize) -> TVec<TValue> {
+        let a = Tensor::zero::<f32>(&[batch, 1, k]).unwrap();
+        tvec!(a.into_tvalue(), Tensor::zero::<f32>(&[batch, k, 1]).unwrap().into_tvalue())
+    }
+
+    #[test]
+    fn matmul_limits() -> TractResult<()> {
+        let model = batched_model(64, 16)?;
+        let plan = SimplePlan::new(&model)?;
+        SimpleState::new(&plan)?.run(batched_inputs(64, 16))?;
+
+        let calls = MatMulLimits { max_kernel_calls: 16, ..MatMulLimits::default() };
+        let plan = SimplePlan::new(&model)?.with_matmul_limits(calls);
+        let err = SimpleState::new(&plan)?.run(batched_inputs(64, 16)).unwrap_err();
+        let err = format!("{err:?}");
+        assert!(err.contains("64 kernel calls, exceeding the limit of 16"), "{err}");
+        assert!(err.contains("inputs of shapes") && err.contains("[64, 16, 1]"), "{err}");
+
+        let bytes = MatMulLimits { max_output_bytes: 128, ..MatMulLimits::default() };
+        let plan = SimplePlan::new(&model)?.with_matmul_limits(bytes);
+        let err = SimpleState::new(&plan)?.run(batched_inputs(64, 16)).unwrap_err();
+        let err = format!("{err:?}");
+        assert!(err.contains("256 bytes exceeds the 128 bytes limit"), "{err}");
+
+        // a pathological prefix fails with the default limits
+        let dt = f32::datum_type();
+        let err = MatMulLimits::default().check(dt, &[100000, 20000, 1, 1], Some(2_000_000_000));
+        assert!(err.is_err());
+        MatMulLimits::unlimited().check(dt, &[100000, 20000, 1, 1], Some(2_000_000_000))?;
+        Ok(())
+    }
+
+    #[test]
+    fn cancelled_run_fails() -> TractResult<()> {
+        use lir_unary::LirMatMulUnary;
+        let (batch, k) = (64, 16);
+        let token = CancellationToken::new();
+        let plan =
+            SimplePlan::new(batched_model(batch, k)?)?.with_cancellation_token(token.clone());
+        let mut state = SimpleState::new(&plan)?;
+        // a full run checks the token before each node, and before each kernel call
+        state.run(batched_inputs(batch, k))?;
+        assert_eq!(token.checks(), plan.order_without_consts().len() + batch);
+
+        // cancelled as the matrix multiplication starts, it makes no kernel call
+        token.reset();
+        let mut cancelled_at = None;
+        let result = state.run_plan_with_eval(
+            batched_inputs(batch, k),
+            |session, op_state, node, inputs| {
+                if node.op_is::<LirMatMulUnary>() {
+                    token.cancel();
+                    cancelled_at = Some(token.checks());
+                }
+                crate::plan::eval(session, op_state, node, inputs)
+            },
+        );
+        let err = format!("{:?}", result.unwrap_err());
+        assert!(err.contains("LirMatMulUnary") && err.contains("Run cancelled"), "{err}");
+        assert!(!err.contains("Before running"), "{err}");
+        // the check refused is the one of the first kernel call
+        assert_eq!(token.checks(), cancelled_at.unwrap() + 1);
+
+        // let go, the same state runs again
+        token.reset();
+        state.run(batched_inputs(batch, k))?;
+        assert_eq!(token.checks(), plan.order_without_consts().len() + batch);
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+//! Constant matrices stored compressed, expanded by the kernel panel by panel.
+//!
+//! The lowering of an einsum keeps the compressed data of such a constant as the operand, and
+//! the kernel expands each panel of the matrix in its scratch space as it reaches it, so the dense
+//! matrix is never held whole. Matrices whose packed form is not larger than the compressed one
+//! with a panel buffer (like a single panel) are expanded and packed at load time instead.
+//! Palettized (see `ops::matmul::palette`) and group quantized (see
+//! `ops::matmul::group_quant`) matrices are compressed.
+use super::group_quant::GroupQuantizedConst;
+use super::palette::PalettizedConst;
+use crate::internal::*;
+use tract_linalg::frame::Packer;
+use tract_linalg::mmm::VirtualInputSpec;
+
+/// A f32 matrix stored compressed.
+pub trait CompressedMatrix: std::fmt::Debug + Send + Sync {
+    fn shape(&self) -> &[usize];
+
+    /// Bytes held by the compressed form.
+    fn compressed_bytes(&self) -> usize;
+
+    fn to_dense(&self) -> TractResult<Tensor>;
+
+    /// The compressed data, fed to the product as the operand.
+    fn data(&self) -> Arc<Tensor>;
+
+    /// Expands the panels of the matrix from its data, along `k_axis` and `mn_axis`. None if the
+    /// matrix can not be expanded along these axes.
+    fn input_spec(&self, k_axis: usize, mn_axis: usize) -> Option<Box<dyn VirtualInputSpec>>;
+
+    /// Should a `k`×`mn` matrix fed to `packer` be expanded and packed once at load time rather
+    /// than panel by panel at run time ? It is when its packed form takes no more than the
+    /// compressed one with the panel buffer.
+    fn expand_once(&self, packer: &Packer, k: usize, mn: usize) -> bool {
+        let f32_size = f32::datum_type().size_of();
+        packer.len(k, mn) * f32_size
+            <= self.compressed_bytes() + packer.single_panel_len(k) * f32_size
+    }
+}
+
+/// The matrix of a constant node stored compressed.
+pub fn compressed_matrix(node: &TypedNode) -> Option<&dyn CompressedMatrix> {
+    if let Some(p) = node.op_as::<PalettizedConst>() {
+        Some(&*p.0)
+    } else if let Some(g) = node.op_as::<GroupQuantizedConst>() {
+        Some(&*g.0)
+    } else {
+        None
+    }
+}
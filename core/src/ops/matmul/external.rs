@@ -0,0 +1,250 @@
+use crate::internal::*;
+use ndarray::*;
+use std::sync::RwLock;
+
+/// A GEMM implementation external to tract (like a BLAS library), computing
+/// `c = alpha * a · b + beta * c` on row-major matrices.
+pub trait GemmBackend: std::fmt::Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Can the backend multiply `a_dt` by `b_dt` matrices into a `c_dt` one ?
+    fn supports(&self, a_dt: DatumType, b_dt: DatumType, c_dt: DatumType) -> bool;
+
+    /// `dts` are the types of a, b and c. `a` is m×k, `b` is k×n and `c` is m×n. Leading dimensions are in items: row `i` of `a`
+    /// starts `i * lda` items after `a`. Rows are contiguous.
+    ///
+    /// # Safety
+    ///
+    /// Pointers are valid for the given dimensions and types, `c` does not alias `a` or `b`.
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn gemm(
+        &self,
+        dts: (DatumType, DatumType, DatumType),
+        mkn: (usize, usize, usize),
+        alpha: f32,
+        a: *const u8,
+        lda: usize,
+        b: *const u8,
+        ldb: usize,
+        beta: f32,
+        c: *mut u8,
+        ldc: usize,
+    ) -> TractResult<()>;
+}
+
+static BACKEND: RwLock<Option<Arc<dyn GemmBackend>>> = RwLock::new(None);
+
+/// Register the backend used for einsums routed to an external GEMM (see
+/// `OptimizerOptions::external_gemm_threshold`), returning the previous one.
+pub fn register_gemm_backend(backend: Arc<dyn GemmBackend>) -> Option<Arc<dyn GemmBackend>> {
+    BACKEND.write().unwrap().replace(backend)
+}
+
+pub fn unregister_gemm_backend() -> Option<Arc<dyn GemmBackend>> {
+    BACKEND.write().unwrap().take()
+}
+
+pub fn registered_gemm_backend() -> Option<Arc<dyn GemmBackend>> {
+    BACKEND.read().unwrap().clone()
+}
+
+/// Matrix multiplication delegated to a GemmBackend, on unpacked operands.
+///
+/// k is the last axis of a, n the last axis of b and c. The other output axes are batch axes,
+/// broadcast when their dimension is 1 in an input.
+#[derive(Clone, Debug)]
+pub struct ExternalGemm {
+    pub backend: Arc<dyn GemmBackend>,
+    pub c_dt: DatumType,
+    pub a_m: usize,
+    pub b_k: usize,
+    pub c_m: usize,
+    /// Position in a and in b of each output axis.
+    pub c_axes: TVec<(Option<usize>, Option<usize>)>,
+}
+
+impl ExternalGemm {
+    fn output_shape<D: DimLike>(&self, a: &[D], b: &[D]) -> TVec<D> {
+        self.c_axes
+            .iter()
+            .map(|(a_pos, b_pos)| {
+                a_pos
+                    .map(|p| a[p].clone())
+                    .into_iter()
+                    .chain(b_pos.map(|p| b[p].clone()))
+                    .find(|d| d != &1.into())
+                    .unwrap_or_else(|| 1.into())
+            })
+            .collect()
+    }
+}
+
+impl Op for ExternalGemm {
+    fn name(&self) -> Cow<str> {
+        "ExternalGemm".into()
+    }
+
+    fn info(&self) -> TractResult<Vec<String>> {
+        Ok(vec![format!("Backend: {}", self.backend.name())])
+    }
+
+    op_as_typed_op!();
+}
+
+impl EvalOp for ExternalGemm {
+    fn is_stateless(&self) -> bool {
+        true
+    }
+
+    fn eval(&self, mut inputs: TVec<TValue>) -> TractResult<TVec<TValue>> {
+        let (a, b) = args_2!(inputs);
+        let c_shape = self.output_shape(a.shape(), b.shape());
+        let mut c = Tensor::zero_dt(self.c_dt, &c_shape)?;
+        let (a_k, c_n) = (a.rank() - 1, c.rank() - 1);
+        let (m, k, n) = (c_shape[self.c_m], a.shape()[a_k], c_shape[c_n]);
+        let mut batch_shape = c_shape.clone();
+        batch_shape[self.c_m] = 1;
+        batch_shape[c_n] = 1;
+        let offset = |t: &Tensor, pos: Option<usize>, x: usize| {
+            pos.filter(|&p| t.shape()[p] > 1).map(|p| x as isize * t.strides()[p]).unwrap_or(0)
+        };
+        let dts = (a.datum_type(), b.datum_type(), self.c_dt);
+        for coords in indices(&*batch_shape) {
+            let (mut a_offset, mut b_offset, mut c_offset) = (0, 0, 0);
+            for (ix, (&x, (a_pos, b_pos))) in coords.slice().iter().zip(&self.c_axes).enumerate() {
+                a_offset += offset(&a, *a_pos, x);
+                b_offset += offset(&b, *b_pos, x);
+                c_offset += x as isize * c.strides()[ix];
+            }
+            unsafe {
+                self.backend.gemm(
+                    dts,
+                    (m, k, n),
+                    1.0,
+                    a.as_ptr_unchecked::<u8>().offset(a_offset * dts.0.size_of() as isize),
+                    a.strides()[self.a_m] as usize,
+                    b.as_ptr_unchecked::<u8>().offset(b_offset * dts.1.size_of() as isize),
+                    b.strides()[self.b_k] as usize,
+                    0.0,
+                    c.as_ptr_mut_unchecked::<u8>().offset(c_offset * dts.2.size_of() as isize),
+                    c.strides()[self.c_m] as usize,
+                )?;
+            }
+        }
+        Ok(tvec!(c.into_tvalue()))
+    }
+}
+
+impl TypedOp for ExternalGemm {
+    fn output_facts(&self, inputs: &[&TypedFact]) -> TractResult<TVec<TypedFact>> {
+        Ok(tvec!(self.c_dt.fact(self.output_shape(&inputs[0].shape, &inputs[1].shape))))
+    }
+
+    fn cost(&self, inputs: &[&TypedFact]) -> TractResult<TVec<(Cost, TDim)>> {
+        let c_shape = self.output_shape(&inputs[0].shape, &inputs[1].shape);
+        let k = inputs[0].shape.last().unwrap().clone();
+        Ok(tvec!((Cost::FMA(self.c_dt), c_shape.iter().product::<TDim>() * k)))
+    }
+
+    as_op!();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::einsum::EinSum;
+    use crate::ops::matmul::lir_unary::LirMatMulUnary;
+    use crate::ops::matmul::pack::MatMatMulPack;
+    use crate::optim::OptimizerOptions;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tract_itertools::Itertools;
+
+    #[derive(Debug, Default)]
+    struct NaiveGemm(AtomicUsize);
+
+    impl GemmBackend for NaiveGemm {
+        fn name(&self) -> &str {
+            "naive"
+        }
+
+        fn supports(&self, a_dt: DatumType, b_dt: DatumType, c_dt: DatumType) -> bool {
+            [a_dt, b_dt, c_dt].iter().all(|dt| *dt == f32::datum_type())
+        }
+
+        unsafe fn gemm(
+            &self,
+            _dts: (DatumType, DatumType, DatumType),
+            (m, k, n): (usize, usize, usize),
+            alpha: f32,
+            a: *const u8,
+            lda: usize,
+            b: *const u8,
+            ldb: usize,
+            beta: f32,
+            c: *mut u8,
+            ldc: usize,
+        ) -> TractResult<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            let (a, b, c) = (a as *const f32, b as *const f32, c as *mut f32);
+            for i in 0..m {
+                for j in 0..n {
+                    let sum: f32 = (0..k).map(|x| *a.add(i * lda + x) * *b.add(x * ldb + j)).sum();
+                    *c.add(i * ldc + j) = alpha * sum + beta * *c.add(i * ldc + j);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn model(dt: DatumType) -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", dt.fact([2, 32, 48]))?;
+        let b = (0..48 * 16).map(|i| ((i * 7) % 13) as f32 / 4. - 1.).collect_vec();
+        let b = Tensor::from_shape(&[1, 48, 16], &b)?.cast_to_dt(dt)?.into_owned();
+        let b = model.add_const("b", b)?;
+        let c = model.wire_node("einsum", EinSum::new("bmk,bkn->bmn".parse()?, dt), &[a, b])?;
+        model.set_output_outlets(&c)?;
+        Ok(model)
+    }
+
+    fn optimized(dt: DatumType, threshold: usize) -> TractResult<TypedModel> {
+        let options =
+            OptimizerOptions { external_gemm_threshold: Some(threshold), ..Default::default() };
+        model(dt)?.into_optimized_with_options(&options)
+    }
+
+    fn count<O: Op>(model: &TypedModel) -> usize {
+        model.nodes().iter().filter(|n| n.op_is::<O>()).count()
+    }
+
+    #[test]
+    fn route_large_einsums_to_backend() -> TractResult<()> {
+        let mkn = 32 * 48 * 16;
+        // no backend: regular lowering
+        assert!(registered_gemm_backend().is_none());
+        assert_eq!(count::<ExternalGemm>(&optimized(f32::datum_type(), mkn)?), 0);
+
+        let backend = Arc::new(NaiveGemm::default());
+        register_gemm_backend(backend.clone());
+        let routed = optimized(f32::datum_type(), mkn)?;
+        let below = optimized(f32::datum_type(), mkn + 1)?;
+        let unsupported = optimized(f16::datum_type(), mkn)?;
+        unregister_gemm_backend();
+
+        assert_eq!(count::<ExternalGemm>(&routed), 1);
+        assert_eq!(count::<LirMatMulUnary>(&routed), 0);
+        assert_eq!(count::<MatMatMulPack>(&routed), 0);
+        assert_eq!(count::<ExternalGemm>(&below), 0);
+        assert_eq!(count::<LirMatMulUnary>(&below), 1);
+        assert_eq!(count::<ExternalGemm>(&unsupported), 0);
+
+        let a = (0..2 * 32 * 48).map(|i| (i % 11) as f32 - 5.).collect_vec();
+        let a = Tensor::from_shape(&[2, 32, 48], &a)?;
+        let reference = model(f32::datum_type())?.into_runnable()?.run(tvec!(a.clone().into()))?;
+        let found = routed.into_runnable()?.run(tvec!(a.into()))?;
+        found[0].close_enough(&reference[0], Approximation::Approximate)?;
+        // one call per batch entry, b being broadcast
+        assert_eq!(backend.0.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+}
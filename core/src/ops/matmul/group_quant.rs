@@ -0,0 +1,448 @@
+//! Group quantized constant matrices.
+//!
+//! Weights quantized by groups of consecutive items along k (like the 4-bit formats of language
+//! model weights) store a 4-bit unsigned value `q` per item, two per byte, and a f32 scale and a
+//! zero point per group, the item being `(q - zero_point) * scale`. A `GroupQuantizedConst`
+//! feeds such a matrix to a product: the kernel expands each panel of it to f32 with the scales
+//! applied as it reaches it, so the product itself runs on the f32 kernels (see
+//! `ops::matmul::compressed`).
+use super::compressed::CompressedMatrix;
+use crate::internal::*;
+use std::ops::Range;
+use tract_linalg::frame::{Packer, PackingWriter};
+use tract_linalg::mmm::{VirtualInput, VirtualInputSpec};
+
+/// Zero point of the groups quantized without explicit zero points, the values being symmetric
+/// around it.
+pub const DEFAULT_ZERO_POINT: u8 = 8;
+
+/// A f32 rank 2 tensor quantized to 4 bits by groups of `group_size` items along `k_axis`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupQuantized {
+    pub shape: [usize; 2],
+    pub k_axis: usize,
+    pub group_size: usize,
+    /// A u8 vector of the quantized values, in row-major order, two per byte, the first one in
+    /// the low nibble.
+    pub data: Arc<Tensor>,
+    /// The f32 scale of each group, laid out as the matrix with its k dimension divided by the
+    /// group size.
+    pub scales: Arc<Tensor>,
+    /// The u8 zero point of each group, laid out as the scales. `DEFAULT_ZERO_POINT` if none.
+    pub zero_points: Option<Arc<Tensor>>,
+}
+
+impl GroupQuantized {
+    pub fn new(
+        shape: &[usize],
+        k_axis: usize,
+        group_size: usize,
+        data: Arc<Tensor>,
+        scales: Arc<Tensor>,
+        zero_points: Option<Arc<Tensor>>,
+    ) -> TractResult<GroupQuantized> {
+        let groups = groups_shape(shape, k_axis, group_size)?;
+        let len = shape[0] * shape[1];
+        ensure!(
+            data.datum_type() == u8::datum_type() && data.len() == (len + 1) / 2,
+            "Expected {} bytes of u8 data for a {shape:?} matrix, got {data:?}",
+            (len + 1) / 2
+        );
+        ensure!(
+            scales.datum_type() == f32::datum_type() && scales.shape() == groups,
+            "Expected f32 scales of shape {groups:?}, got {scales:?}"
+        );
+        if let Some(zero_points) = &zero_points {
+            ensure!(
+                zero_points.datum_type() == u8::datum_type() && zero_points.shape() == groups,
+                "Expected u8 zero points of shape {groups:?}, got {zero_points:?}"
+            );
+            ensure!(
+                zero_points.as_slice::<u8>()?.iter().all(|zp| *zp < 16),
+                "Zero points of 4-bit values are below 16"
+            );
+        }
+        Ok(GroupQuantized {
+            shape: [shape[0], shape[1]],
+            k_axis,
+            group_size,
+            data,
+            scales,
+            zero_points,
+        })
+    }
+
+    /// Quantizes each group of `tensor` on the range of its values, with a zero point, or
+    /// symmetrically around `DEFAULT_ZERO_POINT`.
+    pub fn quantize(
+        tensor: &Tensor,
+        k_axis: usize,
+        group_size: usize,
+        with_zero_points: bool,
+    ) -> TractResult<GroupQuantized> {
+        let shape = tensor.shape();
+        let groups = groups_shape(shape, k_axis, group_size)?;
+        let values = tensor.cast_to::<f32>()?;
+        let values = values.as_slice::<f32>()?;
+        let mut quantized = vec![0u8; values.len()];
+        let mut scales = vec![0f32; groups[0] * groups[1]];
+        let mut zero_points = vec![DEFAULT_ZERO_POINT; scales.len()];
+        for (group, (scale, zero_point)) in scales.iter_mut().zip(&mut zero_points).enumerate() {
+            let (k_group, mn) = if k_axis == 0 {
+                (group / groups[1], group % groups[1])
+            } else {
+                (group % groups[1], group / groups[1])
+            };
+            let items = (k_group * group_size..(k_group + 1) * group_size)
+                .map(|k| if k_axis == 0 { k * shape[1] + mn } else { mn * shape[1] + k })
+                .collect::<Vec<_>>();
+            let min = items.iter().map(|&ix| values[ix]).fold(0f32, f32::min);
+            let max = items.iter().map(|&ix| values[ix]).fold(0f32, f32::max);
+            if with_zero_points {
+                *scale = (max - min) / 15.;
+                if *scale > 0. {
+                    *zero_point = (-min / *scale).round().clamp(0., 15.) as u8;
+                }
+            } else {
+                *scale = max.max(-min) / 7.;
+            }
+            if *scale == 0. {
+                *scale = 1.;
+            }
+            for ix in items {
+                let q = (values[ix] / *scale).round() + *zero_point as f32;
+                quantized[ix] = q.clamp(0., 15.) as u8;
+            }
+        }
+        let data = quantized
+            .chunks(2)
+            .map(|pair| pair[0] | pair.get(1).map(|q| q << 4).unwrap_or(0))
+            .collect::<Vec<u8>>();
+        let zero_points = if with_zero_points {
+            Some(Tensor::from_shape(&groups, &zero_points)?.into_arc_tensor())
+        } else {
+            None
+        };
+        GroupQuantized::new(
+            shape,
+            k_axis,
+            group_size,
+            tensor1(&data).into_arc_tensor(),
+            Tensor::from_shape(&groups, &scales)?.into_arc_tensor(),
+            zero_points,
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.shape[0] * self.shape[1]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes held by the compressed form: the data, the scales and the zero points.
+    pub fn compressed_bytes(&self) -> usize {
+        self.data.len()
+            + self.scales.len() * f32::datum_type().size_of()
+            + self.zero_points.as_ref().map(|zp| zp.len()).unwrap_or(0)
+    }
+
+    pub fn to_dense(&self) -> TractResult<Tensor> {
+        let groups = Groups::new(self);
+        let cols = self.shape[1];
+        let values = (0..self.len())
+            .map(|ix| {
+                let (k, mn) =
+                    if self.k_axis == 0 { (ix / cols, ix % cols) } else { (ix % cols, ix / cols) };
+                unsafe { groups.value(ix, k, mn) }
+            })
+            .collect::<Vec<f32>>();
+        Tensor::from_shape(&self.shape, &values)
+    }
+}
+
+/// Shape of the scales of a `shape` matrix quantized by groups of `group_size` along `k_axis`.
+fn groups_shape(shape: &[usize], k_axis: usize, group_size: usize) -> TractResult<[usize; 2]> {
+    ensure!(shape.len() == 2, "Group quantized matrices are rank 2, got {shape:?}");
+    ensure!(k_axis < 2, "Invalid k axis {k_axis} for a matrix");
+    let k = shape[k_axis];
+    ensure!(
+        group_size > 0 && k % group_size == 0,
+        "k={k} is not a multiple of the group size {group_size}"
+    );
+    let mut groups = [shape[0], shape[1]];
+    groups[k_axis] /= group_size;
+    Ok(groups)
+}
+
+impl CompressedMatrix for GroupQuantized {
+    fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    fn compressed_bytes(&self) -> usize {
+        GroupQuantized::compressed_bytes(self)
+    }
+
+    fn to_dense(&self) -> TractResult<Tensor> {
+        GroupQuantized::to_dense(self)
+    }
+
+    fn data(&self) -> Arc<Tensor> {
+        self.data.clone()
+    }
+
+    fn input_spec(&self, k_axis: usize, mn_axis: usize) -> Option<Box<dyn VirtualInputSpec>> {
+        if k_axis != self.k_axis || mn_axis != 1 - k_axis {
+            return None;
+        }
+        // the data is the operand of the product, not held by the spec
+        let matrix = GroupQuantized { data: tensor0(0u8).into_arc_tensor(), ..self.clone() };
+        Some(Box::new(GroupQuantizedInputSpec(Arc::new(matrix))))
+    }
+}
+
+/// A constant group quantized f32 matrix. It evaluates to the dense tensor, but products lowered
+/// from an einsum read it compressed.
+#[derive(Clone, Debug)]
+pub struct GroupQuantizedConst(pub Arc<GroupQuantized>);
+
+impl Op for GroupQuantizedConst {
+    fn name(&self) -> Cow<str> {
+        "GroupQuantizedConst".into()
+    }
+
+    fn info(&self) -> TractResult<Vec<String>> {
+        let g = &self.0;
+        Ok(vec![format!(
+            "{:?}, groups of {} along axis {}, {} bytes",
+            g.shape,
+            g.group_size,
+            g.k_axis,
+            g.compressed_bytes()
+        )])
+    }
+
+    op_as_typed_op!();
+}
+
+impl EvalOp for GroupQuantizedConst {
+    // not stateless, so the optimizer does not fold it to the dense tensor
+    fn is_stateless(&self) -> bool {
+        false
+    }
+
+    fn eval(&self, _inputs: TVec<TValue>) -> TractResult<TVec<TValue>> {
+        Ok(tvec!(self.0.to_dense()?.into_tvalue()))
+    }
+}
+
+impl TypedOp for GroupQuantizedConst {
+    as_op!();
+
+    fn output_facts(&self, _inputs: &[&TypedFact]) -> TractResult<TVec<TypedFact>> {
+        Ok(tvec!(f32::fact(self.0.shape)))
+    }
+
+    fn cost(&self, _inputs: &[&TypedFact]) -> TractResult<TVec<(Cost, TDim)>> {
+        Ok(tvec!((Cost::Params(f32::datum_type()), self.0.len().into())))
+    }
+}
+
+/// Expands the panels of a group quantized matrix, from its data. The spec holds the scales and
+/// zero points, the data being the operand.
+#[derive(Clone, Debug)]
+pub struct GroupQuantizedInputSpec(Arc<GroupQuantized>);
+
+impl VirtualInputSpec for GroupQuantizedInputSpec {
+    fn wrap(&self, view: &TensorView) -> Box<dyn VirtualInput> {
+        Box::new(GroupQuantizedInput {
+            matrix: self.0.clone(),
+            data: unsafe { view.as_ptr_unchecked() },
+        })
+    }
+
+    fn packed_datum_type(&self, _input: DatumType) -> DatumType {
+        f32::datum_type()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct GroupQuantizedInput {
+    matrix: Arc<GroupQuantized>,
+    data: *const u8,
+}
+
+unsafe impl Send for GroupQuantizedInput {}
+unsafe impl Sync for GroupQuantizedInput {}
+
+impl VirtualInput for GroupQuantizedInput {
+    fn input(
+        &self,
+        packer: &Packer,
+        packed: *mut u8,
+        k_range: Range<usize>,
+        mn_range: Range<usize>,
+    ) {
+        let mut writer = packer.write_single_panel_with_k_outer(packed as *mut f32);
+        let groups = Groups { data: self.data, ..Groups::new(&self.matrix) };
+        let valid = mn_range.start..mn_range.end.min(groups.mn);
+        let (k_stride, mn_stride) = groups.strides;
+        unsafe {
+            for k in k_range {
+                for mn in valid.clone() {
+                    writer.write(groups.value(k * k_stride + mn * mn_stride, k, mn));
+                }
+                for _ in valid.end..mn_range.end {
+                    writer.write(0f32);
+                }
+            }
+        }
+    }
+}
+
+/// Raw access to the items of a group quantized matrix.
+struct Groups<'a> {
+    data: *const u8,
+    scales: &'a [f32],
+    zero_points: Option<&'a [u8]>,
+    group_size: usize,
+    mn: usize,
+    /// Strides of the k and mn axes of the matrix.
+    strides: (usize, usize),
+    /// Strides of the k groups and mn axes of the scales.
+    group_strides: (usize, usize),
+}
+
+impl<'a> Groups<'a> {
+    fn new(matrix: &'a GroupQuantized) -> Groups<'a> {
+        let [rows, cols] = matrix.shape;
+        let (k, mn) = if matrix.k_axis == 0 { (rows, cols) } else { (cols, rows) };
+        let k_groups = k / matrix.group_size;
+        let (strides, group_strides) =
+            if matrix.k_axis == 0 { ((cols, 1), (mn, 1)) } else { ((1, cols), (1, k_groups)) };
+        unsafe {
+            Groups {
+                data: matrix.data.as_ptr_unchecked(),
+                scales: matrix.scales.as_slice_unchecked(),
+                zero_points: matrix.zero_points.as_ref().map(|zp| zp.as_slice_unchecked()),
+                group_size: matrix.group_size,
+                mn,
+                strides,
+                group_strides,
+            }
+        }
+    }
+
+    /// The `ix`-th item of the matrix, in row-major order, at `k` and `mn`.
+    #[inline]
+    unsafe fn value(&self, ix: usize, k: usize, mn: usize) -> f32 {
+        let byte = *self.data.add(ix / 2);
+        let q = if ix % 2 == 0 { byte & 0xF } else { byte >> 4 };
+        let group = k / self.group_size * self.group_strides.0 + mn * self.group_strides.1;
+        let zero_point = self.zero_points.map(|zp| zp[group]).unwrap_or(DEFAULT_ZERO_POINT);
+        (q as f32 - zero_point as f32) * self.scales[group]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::einsum::EinSum;
+    use crate::ops::konst::Const;
+    use crate::ops::matmul::lir_unary::{LirMatMulUnary, ProtoFusedSpec};
+
+    fn weight(shape: &[usize]) -> TractResult<Tensor> {
+        let len = shape.iter().product::<usize>();
+        let values =
+            (0..len).map(|i| ((i * 7 + i / 13) % 29) as f32 / 16. - 0.9).collect::<Vec<_>>();
+        Tensor::from_shape(shape, &values)
+    }
+
+    fn model(weight: Box<dyn TypedOp>, shape: &[usize], n: usize) -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let w = model.wire_node("w", weight, &[])?[0];
+        let x = model.add_source("x", f32::fact([shape[1], n]))?;
+        let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", einsum, &[w, x])?;
+        model.set_output_outlets(&c)?;
+        Ok(model)
+    }
+
+    fn const_bytes(model: &TypedModel) -> usize {
+        model
+            .nodes()
+            .iter()
+            .filter_map(|n| n.op_as::<Const>())
+            .map(|k| k.0.len() * k.0.datum_type().size_of())
+            .sum()
+    }
+
+    fn expanded_on_demand(model: &TypedModel) -> bool {
+        model.nodes().iter().filter_map(|n| n.op_as::<LirMatMulUnary>()).any(|lir| {
+            lir.micro_ops.iter().any(|op| {
+                matches!(op, ProtoFusedSpec::AddMatMul(geo, _, _)
+                    if geo.a_storage.as_ref().map_or(false, |s| s.to_string() == "VirtualPacking"))
+            })
+        })
+    }
+
+    #[test]
+    fn round_trip_along_either_axis() -> TractResult<()> {
+        for (k_axis, with_zero_points) in [(0, false), (0, true), (1, false), (1, true)] {
+            let dense = weight(&[8, 12])?;
+            let quantized = GroupQuantized::quantize(&dense, k_axis, 4, with_zero_points)?;
+            assert_eq!(quantized.scales.shape(), if k_axis == 0 { [2, 12] } else { [8, 3] });
+            assert_eq!(quantized.data.len(), 48);
+            // each item is within half a step of its group
+            let max_scale = quantized.scales.as_slice::<f32>()?.iter().copied().fold(0., f32::max);
+            let found = quantized.to_dense()?;
+            for (found, expected) in found.as_slice::<f32>()?.iter().zip(dense.as_slice::<f32>()?) {
+                assert!((found - expected).abs() <= max_scale / 2. + 1e-6);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn k_not_multiple_of_group_size() -> TractResult<()> {
+        let err = GroupQuantized::quantize(&weight(&[8, 100])?, 1, 64, false).unwrap_err();
+        assert!(err.to_string().contains("k=100 is not a multiple of the group size 64"));
+        let data = tensor1(&[0u8; 400]).into_arc_tensor();
+        let scales = Tensor::zero::<f32>(&[8, 1])?.into_arc_tensor();
+        assert!(GroupQuantized::new(&[8, 100], 1, 64, data, scales, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn groups_of_64_expanded_by_kernel() -> TractResult<()> {
+        let shape = [256, 4096];
+        let dense = weight(&shape)?;
+        let quantized = Arc::new(GroupQuantized::quantize(&dense, 1, 64, true)?);
+        let compressed = quantized.compressed_bytes();
+        assert!(compressed * 6 < dense.len() * 4);
+        let x = Tensor::from_shape(
+            &[4096, 4],
+            &(0..4 * 4096).map(|i| (i % 11) as f32 / 4. - 1.).collect::<Vec<_>>(),
+        )?;
+        // the reference dequantizes the whole weight up front
+        let reference =
+            model(Box::new(Const::new(quantized.to_dense()?.into_arc_tensor())), &shape, 4)?
+                .into_optimized()?
+                .into_runnable()?
+                .run(tvec!(x.clone().into_tvalue()))?;
+        let model = model(Box::new(GroupQuantizedConst(quantized)), &shape, 4)?;
+        // decluttering keeps the compressed form
+        let decluttered = model.into_decluttered()?;
+        assert!(decluttered.nodes().iter().any(|n| n.op_is::<GroupQuantizedConst>()));
+        assert_eq!(const_bytes(&decluttered), 0);
+        let optimized = decluttered.into_optimized()?;
+        assert!(expanded_on_demand(&optimized));
+        // only the 4-bit data is resident as a constant, the scales being held by the kernel op
+        assert!(const_bytes(&optimized) <= compressed, "{}", const_bytes(&optimized));
+        let found = optimized.into_runnable()?.run(tvec!(x.into_tvalue()))?;
+        found[0].close_enough(&reference[0], Approximation::Approximate)?;
+        Ok(())
+    }
+}
@@ -1,14 +1,24 @@
+use super::prefix_loops::PrefixLoops;
+use super::strided::StridedInputSpec;
+use super::tiling::MacroTiles;
+use super::weight_variants::{WeightVariant, WeightVariants};
+use super::{BoundedShape, MatMulLimits, ReusedOutput};
 use crate::internal::*;
+use crate::ops::array::Gather;
 use crate::ops::binary::wire_with_rank_broadcast;
 use crate::ops::cast::cast;
+use crate::ops::OpStateFreeze;
+use crate::plan::CancellationToken;
 use ndarray::*;
-use tract_itertools::Itertools;
+use std::alloc::Layout;
+use std::ops::Range;
+use std::time::{Duration, Instant};
 
 use tract_linalg::mmm::{
-    BinOp, FusedSpec, InputStoreSpec, MatMatMul, OutputStoreSpec, ScratchSpace, VirtualInputSpec,
+    BinOp, FusedSpec, InputStore, InputStoreSpec, MatMatMul, OutputStoreSpec, ScratchNeed,
+    ScratchSpace, VirtualInputSpec,
 };
 use tract_linalg::Scaler;
-use tract_smallvec::ToSmallVec;
 
 #[derive(Clone, Debug)]
 pub enum ProtoInputStoreSpec {
@@ -92,7 +102,18 @@ impl ProtoFusedSpec {
                 FusedSpec::AddRowColProducts(&inputs[*row], &inputs[*col])
             }
             ProtoFusedSpec::AddUnicast(store, v) => unsafe {
-                let view = inputs[*v].view_offsetting_unchecked(output_coords);
+                let v = &inputs[*v];
+                let view = if v.shape() == output.shape() {
+                    v.view_offsetting_unchecked(output_coords)
+                } else {
+                    // a tensor broadcast along batch axes is read at their first coordinate
+                    let coords: TVec<usize> = output_coords
+                        .iter()
+                        .zip(v.shape())
+                        .map(|(&c, &d)| if d == 1 { 0 } else { c })
+                        .collect();
+                    v.view_offsetting_unchecked(&coords)
+                };
                 FusedSpec::AddUnicast(store.wrap(&view))
             },
             ProtoFusedSpec::Scaler(scaler) => scaler.as_fused_spec(),
@@ -104,6 +125,18 @@ impl ProtoFusedSpec {
         fs
     }
 
+    /// The op inputs the spec reads.
+    pub fn inputs(&self) -> TVec<usize> {
+        use ProtoFusedSpec::*;
+        match self {
+            AddMatMul(_, a, b) | AddRowColProducts(a, b) => tvec!(*a, *b),
+            BinScalar(v, _) | BinPerRow(v, _, _) | BinPerCol(v, _, _) | AddUnicast(_, v) => {
+                tvec!(*v)
+            }
+            Scaler(_) | Store(_) => tvec!(),
+        }
+    }
+
     pub fn has_symbols(&self) -> bool {
         match self {
             ProtoFusedSpec::AddMatMul(geo, _, _) => geo.k.as_i64().is_none(),
@@ -159,6 +192,25 @@ impl ProtoFusedSpec {
         fs
     }
 
+    /// What the resolved spec takes in the kernel scratch space, with the operands packed at run
+    /// time of items of at most `item_size` bytes.
+    fn scratch_need(&self, item_size: usize) -> ScratchNeed {
+        use ProtoFusedSpec::*;
+        match self {
+            AddMatMul(geo, _, _) => {
+                let panel = |storage: &Option<InputStoreSpec>| {
+                    storage.as_ref().and_then(|s| s.scratch_panel_buffer_layout(item_size))
+                };
+                ScratchNeed::MatMul(panel(&geo.a_storage), panel(&geo.b_storage))
+            }
+            BinScalar(..) | Scaler(_) => ScratchNeed::Nothing,
+            BinPerRow(..) => ScratchNeed::PerRow,
+            BinPerCol(..) => ScratchNeed::PerCol,
+            AddRowColProducts(..) => ScratchNeed::RowColProducts,
+            AddUnicast(..) | Store(_) => ScratchNeed::Tile,
+        }
+    }
+
     fn cost(&self, m: &TDim, n: &TDim, idt: DatumType) -> TVec<(Cost, TDim)> {
         match self {
             ProtoFusedSpec::AddMatMul(geo, _, _) => {
@@ -188,6 +240,9 @@ impl ProtoFusedSpec {
     }
 }
 
+/// The (output axis, input axis) pairs of the prefix axes an input walks along with the output.
+/// Each output coordinate offsets the input axis it is paired with, so the pairs may come in any
+/// order, and the input axes in a different order than the output ones.
 #[derive(Clone, Debug)]
 pub struct MapOutputAxisToInput(pub TVec<(usize, usize)>);
 
@@ -199,6 +254,17 @@ impl MapOutputAxisToInput {
         }
     }
 
+    /// Offset of the input at these output coordinates, in bytes.
+    #[inline]
+    fn offset_bytes(&self, output_coords: &[usize], input: &Tensor) -> isize {
+        let offset = self
+            .0
+            .iter()
+            .map(|&(out_axis, in_axis)| output_coords[out_axis] as isize * input.strides()[in_axis])
+            .sum::<isize>();
+        offset * input.datum_type().size_of() as isize
+    }
+
     #[inline]
     fn rm_c_axis(&mut self, axis: usize) {
         for (c, _) in &mut self.0 {
@@ -251,6 +317,23 @@ pub struct LirMatMulUnary {
     pub c_m_axis: usize,
     pub c_n_axis: usize,
     pub trivial_path: bool,
+    /// Bounds of the output shape, for a symbolic output allocated once in the op state.
+    pub bounded_output: Option<BoundedShape>,
+    /// The einsum operands were swapped before lowering: the kernel m is the einsum n. Fused
+    /// specs only ever refer to `c_m_axis` and `c_n_axis`, which follow the kernel orientation.
+    pub operands_swapped: bool,
+    /// The AddMatMul operands are not packed: they are packed in the op, by blocks (see
+    /// `super::tiling`).
+    pub macro_tiles: Option<MacroTiles>,
+    /// Input of an AddUnicast spec the output is computed in: the op takes over its buffer when
+    /// it is the last consumer of it. The packed operands are never reused.
+    pub in_place_input: Option<usize>,
+    /// Input #0 is these constant weights, unpacked: each state packs them in the precision of
+    /// its policy (see `super::weight_variants`).
+    pub weight_variants: Option<WeightVariants>,
+    /// Order of the loops over the prefix axes, in the output order without (see
+    /// `super::prefix_loops`). Ignored by macro tiles.
+    pub prefix_loops: Option<PrefixLoops>,
 }
 
 impl Op for LirMatMulUnary {
@@ -269,16 +352,66 @@ impl Op for LirMatMulUnary {
         } else {
             infos.push(format!("Mult: {}", self.mmm));
         }
-        infos.push(format!("Ops: {}", self.micro_ops.iter().map(|o| o.name()).join(" . ")));
+        if self.operands_swapped {
+            infos.push("Operands swapped: kernel m is the einsum n".to_string());
+        }
+        if let Some(tiles) = &self.macro_tiles {
+            infos.push(format!("Macro tiles: {}x{}, packed in the op", tiles.m, tiles.n));
+        }
+        if let Some(slot) = self.in_place_input {
+            infos.push(format!("Output in place of input #{slot}"));
+        }
+        if self.weight_variants.is_some() {
+            infos.push("Weights packed by each state in its precision".to_string());
+        }
+        if let Some(prefix) = &self.prefix_loops {
+            infos.push(format!("Prefix loops (axis, block): {:?}", prefix.loops));
+        }
+        infos.push(format!("Ops: {}", self.fused_spec_names().join(" . ")));
         Ok(infos)
     }
 
     op_as_typed_op!();
 }
 
+#[derive(Clone, Debug, Default)]
+struct State {
+    output: ReusedOutput,
+    node_id: usize,
+    scratch: KernelScratch,
+    weights: Option<Arc<WeightVariant>>,
+}
+
+/// The kernel scratch space of a state, kept across runs. A clone starts without one.
+#[derive(Default)]
+struct KernelScratch(Option<Box<dyn ScratchSpace>>);
+
+impl Clone for KernelScratch {
+    fn clone(&self) -> KernelScratch {
+        KernelScratch(None)
+    }
+}
+
+impl std::fmt::Debug for KernelScratch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "KernelScratch({})", if self.0.is_some() { "allocated" } else { "none" })
+    }
+}
+
 #[derive(Clone, Debug)]
-struct State;
-trivial_op_state_freeeze!(State);
+struct FrozenState(usize, Option<Arc<WeightVariant>>);
+
+impl FrozenOpState for FrozenState {
+    fn unfreeze(&self) -> Box<dyn OpState> {
+        Box::new(State { node_id: self.0, weights: self.1.clone(), ..State::default() })
+    }
+}
+
+impl OpStateFreeze for State {
+    fn freeze(&self) -> Box<dyn FrozenOpState> {
+        Box::new(FrozenState(self.node_id, self.weights.clone()))
+    }
+}
 
 impl OpState for State {
     fn eval(
@@ -287,81 +420,449 @@ impl OpState for State {
         op: &dyn Op,
         inputs: TVec<TValue>,
     ) -> TractResult<TVec<TValue>> {
+        self.eval_with_scratch(session, op, inputs, &mut [])
+    }
+
+    fn scratch_layout(&self, op: &dyn Op, _symbols: &SymbolValues) -> TractResult<Option<Layout>> {
         let op = op.downcast_ref::<LirMatMulUnary>().unwrap();
+        let op = self.weights.as_ref().map(|w| &w.op).unwrap_or(op);
+        op.scratch_layout().map(Some)
+    }
+
+    fn memory_footprint(&self) -> usize {
+        self.weights
+            .as_ref()
+            .map(|w| w.weights.len() * w.weights.datum_type().size_of())
+            .unwrap_or(0)
+    }
+
+    fn eval_with_scratch(
+        &mut self,
+        session: &mut SessionState,
+        op: &dyn Op,
+        mut inputs: TVec<TValue>,
+        scratch: &mut [u8],
+    ) -> TractResult<TVec<TValue>> {
+        let mut op = op.downcast_ref::<LirMatMulUnary>().unwrap();
+        if let Some(variant) = &self.weights {
+            op = &variant.op;
+            inputs[0] = TValue::Const(variant.weights.clone());
+        }
+        if cfg!(debug_assertions) || session.check_matmul_inputs {
+            op.check_inputs(&inputs, &session.resolved_symbols)
+                .with_context(|| format!("Checking inputs of node #{}", self.node_id))?;
+        }
         unsafe {
-            if session
-                .cached_mmm_scratch_space
-                .as_deref()
-                .map(|scratch| op.mmm.can_use_scratch_space(scratch))
-                == Some(false)
-            {
-                session.cached_mmm_scratch_space = None
+            if self.scratch.0.as_deref().map(|s| op.mmm.can_use_scratch_space(s)) != Some(true) {
+                self.scratch.0 = Some(op.mmm.allocate_scratch_space_for(op.micro_ops.len()));
+            }
+            let kernel = self.scratch.0.as_deref_mut().unwrap();
+            let mut lent = Lent::carve(op, scratch, kernel)?;
+            let symbols = &session.resolved_symbols;
+            let (limits, cancellation) = (&session.matmul_limits, session.cancellation.as_ref());
+            if let Some(profile) = session.fused_spec_profile.as_mut() {
+                let times = profile.entry(self.node_id).or_insert_with(|| {
+                    op.fused_spec_names().into_iter().map(|n| (n, Duration::default())).collect()
+                });
+                let c_shape = op.c_fact.shape.eval_to_usize(symbols)?;
+                op.check_limits(limits, &inputs, &c_shape)?;
+                let mut c = Tensor::uninitialized_dt(op.c_fact.datum_type, &c_shape)?;
+                eval_into(op, symbols, &inputs, &mut c, &mut lent, None, |m, n, tiles, specs| {
+                    check_cancellation(cancellation)?;
+                    run_profiled(op, m, n, tiles, kernel, specs, times)
+                })?;
+                Ok(tvec!(c.into_tvalue()))
+            } else if let Some(bounded) = &op.bounded_output {
+                bounded.check(symbols)?;
+                let c_shape = op.c_fact.shape.eval_to_usize(symbols)?;
+                op.check_limits(limits, &inputs, &c_shape)?;
+                let dt = op.c_fact.datum_type;
+                let c = self.output.compute(dt, bounded, dt.alignment(), &c_shape, |c| {
+                    eval_into(op, symbols, &inputs, c, &mut lent, None, |m, n, tiles, specs| {
+                        check_cancellation(cancellation)?;
+                        op.run_kernel(m, n, tiles, kernel, specs)
+                    })
+                })?;
+                Ok(tvec!(c))
+            } else {
+                eval(op, symbols, limits, cancellation, kernel, &mut lent, inputs)
             }
-            let scratch = session
-                .cached_mmm_scratch_space
-                .get_or_insert_with(|| op.mmm.allocate_scratch_space());
-            eval(op, &session.resolved_symbols, scratch.as_mut(), &inputs)
         }
     }
 }
 
 impl EvalOp for LirMatMulUnary {
     fn is_stateless(&self) -> bool {
-        self.geometry.is_concrete()
+        self.geometry.is_concrete() && self.weight_variants.is_none()
     }
 
     fn state(
         &self,
-        _session: &mut SessionState,
-        _node_id: usize,
+        session: &mut SessionState,
+        node_id: usize,
     ) -> TractResult<Option<Box<dyn OpState>>> {
-        Ok(Some(Box::new(State)))
+        let mut state = State { node_id, ..State::default() };
+        if let Some(variants) = &self.weight_variants {
+            let dt = session.weight_precision.datum_type();
+            state.weights = Some(Arc::new(variants.materialize(self, dt)?));
+        }
+        if let Some(bounded) = &self.bounded_output {
+            let dt = self.c_fact.datum_type;
+            state.output.allocate(dt, bounded, dt.alignment())?;
+        }
+        state.scratch.0 =
+            Some(unsafe { self.mmm.allocate_scratch_space_for(self.micro_ops.len()) });
+        Ok(Some(Box::new(state)))
     }
 
-    fn eval(&self, inputs: TVec<TValue>) -> TractResult<TVec<TValue>> {
+    fn eval(&self, mut inputs: TVec<TValue>) -> TractResult<TVec<TValue>> {
+        if let Some(variants) = &self.weight_variants {
+            let variant = variants.materialize(self, f32::datum_type())?;
+            inputs[0] = TValue::Const(variant.weights);
+            return variant.op.eval(inputs);
+        }
+        if cfg!(debug_assertions) {
+            self.check_inputs(&inputs, &Default::default())?;
+        }
         let mut scratch = unsafe { self.mmm.allocate_scratch_space() };
-        eval(self, &Default::default(), scratch.as_mut(), &inputs)
+        let (symbols, limits) = (Default::default(), Default::default());
+        eval(self, &symbols, &limits, None, scratch.as_mut(), &mut Lent::default(), inputs)
+    }
+
+    fn in_place_input(&self) -> TractResult<Option<usize>> {
+        let Some(slot) = self.in_place_input else { return Ok(None) };
+        // the kernel reads an AddUnicast tile right before storing the output tile at the same
+        // place: any other read of the input could see output values
+        for uop in &self.micro_ops {
+            if uop.inputs().contains(&slot) && !matches!(uop, ProtoFusedSpec::AddUnicast(..)) {
+                bail!("Output can not be computed in input #{slot}, read by {}", uop.name());
+            }
+        }
+        let adds = self
+            .micro_ops
+            .iter()
+            .filter(|uop| matches!(uop, ProtoFusedSpec::AddUnicast(_, v) if *v == slot))
+            .count();
+        ensure!(adds == 1, "Output in place of input #{slot}, read by {adds} AddUnicast specs");
+        Ok(Some(slot))
     }
 }
 
-#[allow(clippy::too_many_arguments)]
+/// The parts of the scratch buffer lent to a run (see `LirMatMulUnary::scratch_layout`), the
+/// rest going to the kernel scratch space. A part is None when the buffer is too small for it:
+/// the run then allocates it.
+#[derive(Default)]
+struct Lent<'s> {
+    uops: Option<&'s mut [u8]>,
+    pack_buffers: Option<(&'s mut [u8], &'s mut [u8])>,
+}
+
+impl<'s> Lent<'s> {
+    unsafe fn carve(
+        op: &LirMatMulUnary,
+        mut scratch: &'s mut [u8],
+        kernel: &mut dyn ScratchSpace,
+    ) -> TractResult<Lent<'s>> {
+        let uops = take_bytes(&mut scratch, Layout::array::<FusedSpec>(op.micro_ops.len())?);
+        let pack_buffers = if let Some((a, b)) = op.pack_buffer_layouts()? {
+            take_bytes(&mut scratch, a).zip(take_bytes(&mut scratch, b))
+        } else {
+            None
+        };
+        if scratch.is_empty() {
+            kernel.lend(std::ptr::null_mut(), 0);
+        } else {
+            kernel.lend(scratch.as_mut_ptr(), scratch.len());
+        }
+        Ok(Lent { uops, pack_buffers })
+    }
+}
+
+/// Take the bytes of `layout` off the front of `scratch`, or None if it is too small.
+fn take_bytes<'s>(scratch: &mut &'s mut [u8], layout: Layout) -> Option<&'s mut [u8]> {
+    let pad = scratch.as_ptr().align_offset(layout.align());
+    if pad.checked_add(layout.size())? > scratch.len() {
+        return None;
+    }
+    let (taken, rest) = std::mem::take(scratch).split_at_mut(pad + layout.size());
+    *scratch = rest;
+    Some(&mut taken[pad..])
+}
+
+/// The fused specs of the kernel runs, in a lent buffer or on the heap.
+enum Uops<'s, 't> {
+    Lent(&'s mut [FusedSpec<'t>]),
+    Owned(Vec<FusedSpec<'t>>),
+}
+
+impl<'s, 't> Uops<'s, 't> {
+    /// `len` specs, in `buffer` if it is lent one, sized and aligned for them.
+    fn new(len: usize, buffer: Option<&'s mut [u8]>) -> Uops<'s, 't> {
+        let Some(buffer) = buffer else {
+            return Uops::Owned(vec![FusedSpec::ShiftLeft(0); len]);
+        };
+        debug_assert!(buffer.len() >= len * std::mem::size_of::<FusedSpec>());
+        unsafe {
+            let specs = buffer.as_mut_ptr() as *mut FusedSpec<'t>;
+            debug_assert!(specs as usize % std::mem::align_of::<FusedSpec>() == 0);
+            for ix in 0..len {
+                specs.add(ix).write(FusedSpec::ShiftLeft(0));
+            }
+            Uops::Lent(std::slice::from_raw_parts_mut(specs, len))
+        }
+    }
+}
+
+impl<'s, 't> std::ops::Deref for Uops<'s, 't> {
+    type Target = [FusedSpec<'t>];
+    fn deref(&self) -> &[FusedSpec<'t>] {
+        match self {
+            Uops::Lent(specs) => specs,
+            Uops::Owned(specs) => specs,
+        }
+    }
+}
+
+impl<'s, 't> std::ops::DerefMut for Uops<'s, 't> {
+    fn deref_mut(&mut self) -> &mut [FusedSpec<'t>] {
+        match self {
+            Uops::Lent(specs) => specs,
+            Uops::Owned(specs) => specs,
+        }
+    }
+}
+
+impl<'s, 't> Drop for Uops<'s, 't> {
+    fn drop(&mut self) {
+        if let Uops::Lent(specs) = self {
+            unsafe { std::ptr::drop_in_place(*specs as *mut [FusedSpec<'t>]) }
+        }
+    }
+}
+
+/// Fail if the run was cancelled (see `SimplePlan::with_cancellation_token`).
+fn check_cancellation(cancellation: Option<&CancellationToken>) -> TractResult<()> {
+    if let Some(token) = cancellation {
+        token.check()?;
+    }
+    Ok(())
+}
+
 fn eval(
     op: &LirMatMulUnary,
     symbols: &SymbolValues,
+    limits: &MatMulLimits,
+    cancellation: Option<&CancellationToken>,
     scratch: &mut dyn ScratchSpace,
-    inputs: &[TValue],
+    lent: &mut Lent,
+    mut inputs: TVec<TValue>,
 ) -> TractResult<TVec<TValue>> {
+    let c_shape = if op.trivial_path {
+        unsafe { op.c_fact.shape.as_concrete().unwrap_unchecked().into() }
+    } else {
+        op.c_fact.shape.eval_to_usize(symbols)?.into_owned()
+    };
+    let dt = op.c_fact.datum_type;
+    op.check_limits(limits, &inputs, &c_shape)?;
+    let in_place = op.in_place_input.filter(|&slot| {
+        op.macro_tiles.is_none()
+            && inputs[slot].is_exclusive()
+            && inputs[slot].datum_type() == dt
+            && inputs[slot].shape() == &*c_shape
+    });
+    let mut c = if let Some(slot) = in_place {
+        std::mem::replace(&mut inputs[slot], Tensor::default().into_tvalue()).into_tensor()
+    } else {
+        unsafe { Tensor::uninitialized_dt(dt, &c_shape)? }
+    };
+    eval_into(op, symbols, &inputs, &mut c, lent, in_place, |m, n, tiles, specs| unsafe {
+        check_cancellation(cancellation)?;
+        op.run_kernel(m, n, tiles, scratch, specs)
+    })?;
+    Ok(tvec!(c.into_tvalue()))
+}
+
+/// Panel rows and columns of a macro tile.
+type Tiles = (Range<usize>, Range<usize>);
+
+/// Compute the output in `c`, already shaped as the output, calling `run` with the geometry,
+/// macro tile and fused specs of each kernel invocation. With `in_place`, `c` is the buffer of
+/// this input, moved out of `inputs`: its AddUnicast reads it from `c`.
+fn eval_into(
+    op: &LirMatMulUnary,
+    symbols: &SymbolValues,
+    inputs: &[TValue],
+    c: &mut Tensor,
+    lent: &mut Lent,
+    in_place: Option<usize>,
+    mut run: impl FnMut(usize, usize, Option<&Tiles>, &[FusedSpec]) -> TractResult<()>,
+) -> TractResult<()> {
     unsafe {
-        if op.trivial_path {
-            let c_shape = op.c_fact.shape.as_concrete().unwrap_unchecked();
+        let mut uops = Uops::new(op.micro_ops.len(), lent.uops.take());
+        if let Some(tiles) = &op.macro_tiles {
+            eval_tiled_into(op, tiles, symbols, inputs, c, lent, &mut uops, run)?;
+        } else if op.trivial_path {
             let geometry = op.geometry.as_concrete().unwrap_unchecked();
-            let mut c = Tensor::uninitialized_dt(op.c_fact.datum_type, c_shape)?;
-            let uops: Vec<FusedSpec> =
-                op.micro_ops.iter().map(|o| o.resolve_trivial(inputs, &mut c)).collect();
-            op.mmm.run_with_scratch_space(geometry.m, geometry.n, scratch, &uops)?;
-            Ok(tvec!(c.into_tvalue()))
+            for (uop, o) in uops.iter_mut().zip(op.micro_ops.iter()) {
+                *uop = match o {
+                    ProtoFusedSpec::AddUnicast(store, v) if in_place == Some(*v) => {
+                        FusedSpec::AddUnicast(store.wrap(&c.view()))
+                    }
+                    _ => o.resolve_trivial(inputs, c),
+                };
+            }
+            run(geometry.m, geometry.n, None, &uops)?;
         } else {
             let geometry = op.geometry.to_concrete(symbols)?;
-            let c_shape = op.c_fact.shape.eval_to_usize(symbols)?;
-            let mut c = Tensor::uninitialized_dt(op.c_fact.datum_type, &c_shape)?;
-            let mut uops = vec![FusedSpec::ShiftLeft(0); op.micro_ops.len()];
-            let mut looping_shape: TVec<usize> = c_shape.to_smallvec();
+            let mut looping_shape: TVec<usize> = c.shape().into();
             looping_shape[op.c_m_axis] = 1;
             looping_shape[op.c_n_axis] = 1;
-            for c_coords in indices(&*looping_shape) {
+            let mut prefix = |c_coords: &[usize]| {
                 for ix in 0..op.micro_ops.len() {
-                    *uops.get_unchecked_mut(ix) = op.micro_ops.get_unchecked(ix).resolve(
-                        inputs,
-                        c_coords.slice(),
-                        symbols,
-                        &mut c,
-                    );
+                    let o = op.micro_ops.get_unchecked(ix);
+                    *uops.get_unchecked_mut(ix) = match o {
+                        ProtoFusedSpec::AddUnicast(store, v) if in_place == Some(*v) => {
+                            let view = c.view_offsetting_unchecked(c_coords);
+                            FusedSpec::AddUnicast(store.wrap(&view))
+                        }
+                        _ => o.resolve(inputs, c_coords, symbols, c),
+                    };
+                }
+                run(geometry.m, geometry.n, None, &uops)
+            };
+            if let Some(loops) = &op.prefix_loops {
+                loops.for_each(&looping_shape, prefix)?;
+            } else {
+                for c_coords in indices(&*looping_shape) {
+                    prefix(c_coords.slice())?;
                 }
-                op.mmm.run_with_scratch_space(geometry.m, geometry.n, scratch, &uops)?;
             }
-            Ok(tvec!(c.into_tvalue()))
         }
     }
+    Ok(())
+}
+
+/// A new buffer of `layout`, kept alive in `owned`.
+unsafe fn owned_buffer(owned: &mut Option<Tensor>, layout: Layout) -> TractResult<*mut u8> {
+    let dt = u8::datum_type();
+    let buffer = Tensor::uninitialized_aligned_dt(dt, &[layout.size()], layout.align())?;
+    Ok(owned.insert(buffer).as_ptr_mut_unchecked::<u8>())
+}
+
+/// Macro tiling flavour of `eval_into`: for each block of B columns, then each block of A rows,
+/// pack both blocks and run the kernel on the tiles they cover.
+#[allow(clippy::too_many_arguments)]
+unsafe fn eval_tiled_into<'t>(
+    op: &'t LirMatMulUnary,
+    tiles: &MacroTiles,
+    symbols: &SymbolValues,
+    inputs: &'t [TValue],
+    c: &mut Tensor,
+    lent: &mut Lent,
+    uops: &mut [FusedSpec<'t>],
+    mut run: impl FnMut(usize, usize, Option<&Tiles>, &[FusedSpec]) -> TractResult<()>,
+) -> TractResult<()> {
+    let geometry = op.geometry.to_concrete(symbols)?;
+    let (m, n) = (geometry.m, geometry.n);
+    let (mm_ix, geo, a_ix, b_ix) = op
+        .micro_ops
+        .iter()
+        .enumerate()
+        .find_map(|(ix, o)| match o {
+            ProtoFusedSpec::AddMatMul(geo, a, b) => Some((ix, geo, *a, *b)),
+            _ => None,
+        })
+        .context("Macro tiling without a matrix product")?;
+    let (a, b) = (&inputs[a_ix], &inputs[b_ix]);
+    let k = geo.k.eval(symbols).to_usize()?;
+    let (mr, nr) = (op.mmm.mr(), op.mmm.nr());
+    let (a_pack, b_pack) = (op.mmm.a_pack(), op.mmm.b_pack());
+    let (a_dt, b_dt) = (a.datum_type(), b.datum_type());
+    let mut owned = (None, None);
+    let (a_buf, b_buf) = match lent.pack_buffers.take() {
+        Some((a_buf, b_buf)) => (a_buf.as_mut_ptr(), b_buf.as_mut_ptr()),
+        None => {
+            let (a_layout, b_layout) = tiles.pack_buffer_layouts(&*op.mmm, (m, k, n))?;
+            (owned_buffer(&mut owned.0, a_layout)?, owned_buffer(&mut owned.1, b_layout)?)
+        }
+    };
+    let (
+        InputStoreSpec::Prepacked { panel_bytes: a_panel_bytes },
+        InputStoreSpec::Prepacked { panel_bytes: b_panel_bytes },
+    ) = (op.mmm.a_packed(a_dt.size_of(), k), op.mmm.b_packed(b_dt.size_of(), k))
+    else {
+        bail!("Macro tiles are packed in prepacked panels")
+    };
+    let mut looping_shape: TVec<usize> = c.shape().into();
+    looping_shape[op.c_m_axis] = 1;
+    looping_shape[op.c_n_axis] = 1;
+    for c_coords in indices(&*looping_shape) {
+        let a_offset = geo.c_to_a_axis_mapping.offset_bytes(c_coords.slice(), a);
+        let b_offset = geo.c_to_b_axis_mapping.offset_bytes(c_coords.slice(), b);
+        for n0 in (0..n).step_by(tiles.n) {
+            let n1 = (n0 + tiles.n).min(n);
+            MacroTiles::pack_block(&b_pack, b, b_offset, tiles.b_axes, n0..n1, b_buf);
+            for m0 in (0..m).step_by(tiles.m) {
+                let m1 = (m0 + tiles.m).min(m);
+                MacroTiles::pack_block(&a_pack, a, a_offset, tiles.a_axes, m0..m1, a_buf);
+                for ix in 0..op.micro_ops.len() {
+                    *uops.get_unchecked_mut(ix) = if ix == mm_ix {
+                        FusedSpec::AddMatMul {
+                            k,
+                            a: InputStore::Packed {
+                                ptr: a_buf,
+                                panel_bytes: a_panel_bytes as isize,
+                            },
+                            b: InputStore::Packed {
+                                ptr: b_buf,
+                                panel_bytes: b_panel_bytes as isize,
+                            },
+                        }
+                    } else {
+                        op.micro_ops.get_unchecked(ix).resolve(inputs, c_coords.slice(), symbols, c)
+                    };
+                }
+                let tile = (m0 / mr..m1.divceil(mr), n0 / nr..n1.divceil(nr));
+                run(m, n, Some(&tile), uops)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run the kernel once per prefix of the fused specs, each followed by the final store, and
+/// attribute the time differences to the specs. The last run is the complete one. The per-spec
+/// times are scaled to add up to the time of all the runs.
+unsafe fn run_profiled(
+    op: &LirMatMulUnary,
+    m: usize,
+    n: usize,
+    tiles: Option<&Tiles>,
+    scratch: &mut dyn ScratchSpace,
+    specs: &[FusedSpec],
+    times: &mut [(String, Duration)],
+) -> TractResult<()> {
+    let Some((store @ FusedSpec::Store(_), body)) = specs.split_last() else {
+        let start = Instant::now();
+        op.run_kernel(m, n, tiles, scratch, specs)?;
+        times[0].1 += start.elapsed();
+        return Ok(());
+    };
+    let mut runs = vec![];
+    for len in 0..=body.len() {
+        let prefix: Vec<FusedSpec> = body[..len].iter().chain([store]).cloned().collect();
+        let start = Instant::now();
+        op.run_kernel(m, n, tiles, scratch, &prefix)?;
+        runs.push(start.elapsed().as_secs_f64());
+    }
+    // runs[0] only stores: its time goes to the store
+    let mut spent: Vec<f64> = runs.windows(2).map(|w| (w[1] - w[0]).max(0.)).collect();
+    spent.push(runs[0]);
+    let scale = runs.iter().sum::<f64>() / spent.iter().sum::<f64>().max(f64::MIN_POSITIVE);
+    for ((_, time), spent) in times.iter_mut().zip(spent) {
+        *time += Duration::from_secs_f64(spent * scale);
+    }
+    Ok(())
 }
 
 impl TypedOp for LirMatMulUnary {
@@ -393,6 +894,83 @@ impl TypedOp for LirMatMulUnary {
         Ok(sums.into_iter().collect())
     }
 
+    /// Rows along the m (or n) axis are gathered from the input of the node packing A (or B),
+    /// so only they are packed and computed. Products fusing per row, per column or unicast
+    /// inputs are computed whole.
+    fn select_rows(
+        &self,
+        patch: &mut TypedModelPatch,
+        model: &TypedModel,
+        node: &TypedNode,
+        output_axis: usize,
+        rows: OutletId,
+    ) -> TractResult<Option<TVec<OutletId>>> {
+        use super::pack::MatMatMulPack;
+        if self.macro_tiles.is_some()
+            || self.bounded_output.is_some()
+            || self.in_place_input.is_some()
+            || (output_axis != self.c_m_axis && output_axis != self.c_n_axis)
+        {
+            return Ok(None);
+        }
+        let mut operand = None;
+        for uop in &self.micro_ops {
+            match uop {
+                ProtoFusedSpec::AddMatMul(geo, a, b) if operand.is_none() => {
+                    let (slot, storage) = if output_axis == self.c_m_axis {
+                        (*a, &geo.a_storage)
+                    } else {
+                        (*b, &geo.b_storage)
+                    };
+                    if storage.is_some() {
+                        return Ok(None);
+                    }
+                    operand = Some(slot);
+                }
+                ProtoFusedSpec::BinScalar(..)
+                | ProtoFusedSpec::Scaler(_)
+                | ProtoFusedSpec::Store(_) => (),
+                _ => return Ok(None),
+            }
+        }
+        let Some(slot) = operand else { return Ok(None) };
+        let pack_node = model.node(node.inputs[slot].node);
+        let Some(pack) = pack_node.op_as::<MatMatMulPack>() else { return Ok(None) };
+        if pack_node.outputs[0].successors.len() != 1
+            || pack.parameter
+            || pack.mask_axis.is_some()
+            || pack.bounded_output.is_some()
+            || pack.input_slice.is_some()
+        {
+            return Ok(None);
+        }
+        let mut inputs = node
+            .inputs
+            .iter()
+            .map(|i| patch.tap_model(model, *i))
+            .collect::<TractResult<TVec<_>>>()?;
+        let source = patch.tap_model(model, pack_node.inputs[0])?;
+        let name = format!("{}.rows", pack_node.name);
+        let gathered = patch.wire_node(name, Gather::new(pack.mn_axis), &[source, rows])?;
+        inputs[slot] = patch.wire_node(&pack_node.name, pack.clone(), &gathered)?[0];
+        let mut c_shape = self.c_fact.shape.to_tvec();
+        c_shape[output_axis] = patch.outlet_fact(rows)?.shape[0].clone();
+        let c_fact = self.c_fact.datum_type.fact(c_shape);
+        let op = LirMatMulUnary::new(
+            self.mmm.clone(),
+            c_fact,
+            self.c_m_axis,
+            self.c_n_axis,
+            self.micro_ops.clone(),
+        )?;
+        let op = LirMatMulUnary {
+            operands_swapped: self.operands_swapped,
+            weight_variants: self.weight_variants.clone(),
+            ..op
+        };
+        patch.wire_node(&node.name, op, &inputs).map(Some)
+    }
+
     fn fuse(&self, model: &TypedModel, node: &TypedNode) -> TractResult<Option<TypedModelPatch>> {
         use crate::ops;
         if node.outputs.len() != 1
@@ -437,7 +1015,7 @@ impl TypedOp for LirMatMulUnary {
                     let mut patch = TypedModelPatch::fuse_with_next(
                         model,
                         node,
-                        Self { c_fact, ..self.clone() },
+                        Self { c_fact, in_place_input: None, ..self.clone() },
                     )?;
                     patch.dont_apply_twice = Some(format!("Fuse {succ} into {node}"));
                     return Ok(Some(patch));
@@ -448,13 +1026,16 @@ impl TypedOp for LirMatMulUnary {
             if *axis == self.c_m_axis || *axis == self.c_n_axis {
                 return Ok(None);
             }
-            let mut new_op = self.clone();
+            let mut new_op = Self { in_place_input: None, ..self.clone() };
             new_op.c_fact.shape.remove_axis(*axis)?;
             new_op.c_m_axis -= (new_op.c_m_axis > *axis) as usize;
             new_op.c_n_axis -= (new_op.c_n_axis > *axis) as usize;
             for uop in &mut new_op.micro_ops {
                 uop.rm_c_axis(*axis);
             }
+            if let Some(loops) = &mut new_op.prefix_loops {
+                loops.rm_c_axis(*axis);
+            }
             let mut patch = TypedModelPatch::fuse_with_next(model, node, new_op)?;
             patch.dont_apply_twice = Some(format!("Fuse {succ} into {node}"));
             return Ok(Some(patch));
@@ -492,13 +1073,21 @@ impl TypedOp for LirMatMulUnary {
                 let other_input = succ.inputs[other_slot];
                 let other_input = patch.tap_model(model, other_input)?;
 
-                if patch.outlet_fact(other_input)?.shape == self.c_fact.shape {
+                let other_fact = patch.outlet_fact(other_input)?;
+                if other_fact.shape == self.c_fact.shape {
                     let other_storage = unsafe { self.mmm.c_view(self.c_m_axis, self.c_n_axis) };
-                    return self.fuse_op(
+                    let slot = node.inputs.len();
+                    let in_place = other_fact.datum_type == self.c_fact.datum_type
+                        && self.in_place_input.is_none();
+                    let fused = Self {
+                        in_place_input: if in_place { Some(slot) } else { self.in_place_input },
+                        ..self.clone()
+                    };
+                    return fused.fuse_op(
                         model,
                         node,
                         patch,
-                        vec![ProtoFusedSpec::AddUnicast(other_storage, node.inputs.len())],
+                        vec![ProtoFusedSpec::AddUnicast(other_storage, slot)],
                         &[other_input],
                     );
                 }
@@ -534,12 +1123,160 @@ impl LirMatMulUnary {
             c_n_axis,
             micro_ops,
             trivial_path: false,
+            bounded_output: None,
+            operands_swapped: false,
+            macro_tiles: None,
+            in_place_input: None,
+            weight_variants: None,
+            prefix_loops: None,
         };
         it.update_trivial_path();
         Ok(it)
     }
+
+    /// Kernel invocations computing an output of `c_shape`: one per coordinates of the prefix
+    /// axes, times the macro tiles. None if it overflows usize.
+    pub fn kernel_calls(&self, c_shape: &[usize]) -> Option<usize> {
+        if self.trivial_path {
+            return Some(1);
+        }
+        let prefix = c_shape
+            .iter()
+            .enumerate()
+            .filter(|(axis, _)| *axis != self.c_m_axis && *axis != self.c_n_axis)
+            .try_fold(1usize, |calls, (_, &dim)| calls.checked_mul(dim))?;
+        let tiles = self.macro_tiles.as_ref().map_or(1, |tiles| {
+            c_shape[self.c_m_axis].divceil(tiles.m) * c_shape[self.c_n_axis].divceil(tiles.n)
+        });
+        prefix.checked_mul(tiles)
+    }
+
+    /// Check a run computing an output of `c_shape` from `inputs` against `limits`, before the
+    /// output is allocated.
+    fn check_limits(
+        &self,
+        limits: &MatMulLimits,
+        inputs: &[TValue],
+        c_shape: &[usize],
+    ) -> TractResult<()> {
+        limits.check(self.c_fact.datum_type, c_shape, self.kernel_calls(c_shape)).with_context(
+            || {
+                let shapes = inputs.iter().map(|i| i.shape()).collect::<TVec<_>>();
+                format!("Matrix multiplication of inputs of shapes {shapes:?}")
+            },
+        )
+    }
+
+    /// Check the inputs against the geometry recorded at lowering, with the symbols resolved:
+    /// the items of each operand of the products, packed by panels of k records or read as is,
+    /// and the shape of the tensors added to the output. The kernel trusts them, a mismatch
+    /// would read or write out of bounds.
+    pub fn check_inputs(&self, inputs: &[TValue], symbols: &SymbolValues) -> TractResult<()> {
+        let c_shape = self.c_fact.shape.eval_to_usize(symbols)?;
+        let geometry = self.geometry.to_concrete(symbols)?;
+        let (m, n) = (c_shape[self.c_m_axis], c_shape[self.c_n_axis]);
+        ensure!(
+            (geometry.m, geometry.n) == (m, n),
+            "Output {c_shape:?} does not match the kernel geometry m={} n={}",
+            geometry.m,
+            geometry.n
+        );
+        let input = |slot: usize| {
+            inputs.get(slot).with_context(|| format!("Missing input #{slot}, got {}", inputs.len()))
+        };
+        for uop in &self.micro_ops {
+            match uop {
+                ProtoFusedSpec::AddMatMul(geo, a, b) => {
+                    let k = geo.k.eval(symbols).to_usize()?;
+                    let operands = [
+                        ("A", *a, &geo.a_storage, &geo.c_to_a_axis_mapping, self.mmm.a_pack(), m),
+                        ("B", *b, &geo.b_storage, &geo.c_to_b_axis_mapping, self.mmm.b_pack(), n),
+                    ];
+                    for (name, slot, storage, mapping, packer, mn) in operands {
+                        let t = input(slot)?;
+                        // the kernel loops over the output axes other than m and n
+                        let looping = mapping
+                            .0
+                            .iter()
+                            .filter(|(c, _)| *c != self.c_m_axis && *c != self.c_n_axis);
+                        for &(c_axis, axis) in looping {
+                            ensure!(
+                                t.shape().get(axis) == Some(&c_shape[c_axis]),
+                                "{name} (input #{slot}) of shape {:?} does not match the output \
+                                 {c_shape:?} on its axis #{axis}",
+                                t.shape()
+                            );
+                        }
+                        let expected = match storage {
+                            _ if self.macro_tiles.is_some() => k * mn,
+                            Some(InputStoreSpec::VirtualPacking { func, .. }) => {
+                                // other virtual inputs, like im2col, read the input their own way
+                                if func.is::<StridedInputSpec>() {
+                                    k * mn
+                                } else {
+                                    continue;
+                                }
+                            }
+                            _ => packer.len(k, mn),
+                        };
+                        // a product of the axes the kernel reads, the others are batch axes
+                        let items = (0..t.rank())
+                            .filter(|axis| mapping.0.iter().all(|(_, a)| a != axis))
+                            .map(|axis| t.shape()[axis])
+                            .product::<usize>();
+                        // packed buffers may be padded up to their alignment
+                        ensure!(
+                            items >= expected,
+                            "{name} (input #{slot}) of shape {:?}: expected {expected} items ({} \
+                             panels of k={k} for mn={mn}), got {items}",
+                            t.shape(),
+                            mn.divceil(packer.r),
+                        );
+                    }
+                }
+                ProtoFusedSpec::AddUnicast(_, v) => {
+                    let t = input(*v)?;
+                    // it may be broadcast along the batch axes, not along m and n
+                    let broadcast = |axis: usize| {
+                        axis != self.c_m_axis && axis != self.c_n_axis && t.shape()[axis] == 1
+                    };
+                    ensure!(
+                        t.rank() == c_shape.len()
+                            && (0..t.rank())
+                                .all(|ax| t.shape()[ax] == c_shape[ax] || broadcast(ax)),
+                        "Input #{v} of shape {:?} added to the output {c_shape:?}",
+                        t.shape()
+                    );
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the kernel on the whole m by n output, or on a macro tile of it.
+    unsafe fn run_kernel(
+        &self,
+        m: usize,
+        n: usize,
+        tiles: Option<&Tiles>,
+        scratch: &mut dyn ScratchSpace,
+        specs: &[FusedSpec],
+    ) -> TractResult<()> {
+        if let Some((rows, cols)) = tiles {
+            self.mmm.run_tiles(m, n, rows.clone(), cols.clone(), scratch, specs)
+        } else {
+            self.mmm.run_with_scratch_space(m, n, scratch, specs)
+        }
+    }
+
+    /// Names of the fused specs run by the kernel, in order.
+    pub fn fused_spec_names(&self) -> Vec<String> {
+        self.micro_ops.iter().map(|o| o.name()).collect()
+    }
+
     // for cost and info
-    fn guess_k(&self) -> Option<TDim> {
+    pub(crate) fn guess_k(&self) -> Option<TDim> {
         self.micro_ops
             .iter()
             .find_map(
@@ -554,7 +1291,31 @@ impl LirMatMulUnary {
             .map(|geo| geo.k.clone())
     }
 
-    fn m_n(&self) -> (TDim, TDim) {
+    /// Layout of the scratch buffer of a run: the fused specs, the macro tile packing buffers and
+    /// the kernel scratch space, in this order. It does not depend on the symbols. Operands
+    /// packed at run time are sized for items as large as the accumulator, which they never
+    /// exceed.
+    pub fn scratch_layout(&self) -> TractResult<Layout> {
+        let mut layout = Layout::array::<FusedSpec>(self.micro_ops.len())?;
+        if let Some((a, b)) = self.pack_buffer_layouts()? {
+            layout = layout.extend(a)?.0.extend(b)?.0;
+        }
+        let item_size = self.mmm.internal_type().size_of();
+        let needs: TVec<ScratchNeed> =
+            self.micro_ops.iter().map(|o| o.scratch_need(item_size)).collect();
+        Ok(layout.extend(self.mmm.scratch_space_layout(&needs))?.0)
+    }
+
+    /// Layouts of the macro tile packing buffers, if the op packs its operands.
+    fn pack_buffer_layouts(&self) -> TractResult<Option<(Layout, Layout)>> {
+        let Some(tiles) = &self.macro_tiles else { return Ok(None) };
+        let (m, n) = self.m_n();
+        let k = self.guess_k().context("Macro tiling without a matrix product")?;
+        let mkn = (m.to_usize()?, k.to_usize()?, n.to_usize()?);
+        tiles.pack_buffer_layouts(&*self.mmm, mkn).map(Some)
+    }
+
+    pub(crate) fn m_n(&self) -> (TDim, TDim) {
         match &self.geometry {
             MatrixGeometry::Concrete(ConcreteMatrixGeometry { m, n }) => (m.to_dim(), n.to_dim()),
             MatrixGeometry::Symbolic(SymbolicMatrixGeometry { m, n, .. }) => (m.clone(), n.clone()),
@@ -657,6 +1418,22 @@ impl LirMatMulUnary {
                 &additional_input,
             );
         }
+        // a tensor of the output shape, possibly broadcast along batch axes, like a residual
+        if binop == BinOp::Add
+            && other_shape.len() == self.c_fact.rank()
+            && other_shape.iter().zip(self.c_fact.shape.iter()).enumerate().all(|(ax, (o, c))| {
+                o == c || (o.is_one() && ax != self.c_m_axis && ax != self.c_n_axis)
+            })
+        {
+            let storage = unsafe { self.mmm.c_view(self.c_m_axis, self.c_n_axis) };
+            return self.fuse_op(
+                model,
+                node,
+                patch,
+                vec![ProtoFusedSpec::AddUnicast(storage, value)],
+                &additional_input,
+            );
+        }
         Ok(None)
     }
 }
@@ -0,0 +1,261 @@
+//! Lowering decisions taken by the optimizer for matrix multiplications.
+use super::external::ExternalGemm;
+use super::lir_unary::LirMatMulUnary;
+use super::tiny_batch::TinyBatchMatMul;
+use crate::internal::*;
+use crate::ops::einsum::{EinSum, SWAP_OPERANDS_PATCH};
+
+/// How a matrix multiplication node of an optimized model was lowered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoweringDecision {
+    /// Name of the node in the optimized model.
+    pub node: String,
+    /// Final op: `LirMatMulUnary`, `ExternalGemm`, `TinyBatchMatMul`, or `EinSum` if the einsum
+    /// was not lowered.
+    pub op: String,
+    /// Kernel, or external backend, computing the product.
+    pub kernel: Option<String>,
+    /// Were the einsum operands swapped to put the largest (or the parameter) one in A ?
+    pub swapped: bool,
+    /// Fused specs run by the kernel, in order.
+    pub fused_specs: Vec<String>,
+}
+
+/// Lowering decisions for the matrix multiplication nodes of `model`, in node order.
+///
+/// The model must have been optimized with `OptimizerOptions::track_patches`, as operand swaps
+/// are only visible in the patch provenance.
+pub fn lowering_decisions(model: &TypedModel) -> TractResult<Vec<LoweringDecision>> {
+    ensure!(
+        model.node_patches.is_some(),
+        "Lowering decisions require a model optimized with track_patches"
+    );
+    let mut decisions = vec![];
+    for node in model.nodes() {
+        // fusing an epilogue replaces the lowered node by one without the swap in its history
+        let swapped = model.node_patches(node.id).iter().any(|p| p == SWAP_OPERANDS_PATCH);
+        let (kernel, fused_specs, swapped) = if let Some(lir) = node.op_as::<LirMatMulUnary>() {
+            let kernel = lir.mmm.kernel_name().to_string();
+            (Some(kernel), lir.fused_spec_names(), lir.operands_swapped)
+        } else if let Some(gemm) = node.op_as::<ExternalGemm>() {
+            (Some(gemm.backend.name().to_string()), vec![], swapped)
+        } else if node.op_is::<TinyBatchMatMul>() {
+            (None, vec![], swapped)
+        } else if let Some(einsum) = node.op_as::<EinSum>() {
+            (None, vec![], einsum.operands_swapped || swapped)
+        } else {
+            continue;
+        };
+        decisions.push(LoweringDecision {
+            node: node.name.clone(),
+            op: node.op.name().to_string(),
+            kernel,
+            swapped,
+            fused_specs,
+        });
+    }
+    Ok(decisions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::optim::OptimizerOptions;
+    use serde_json::{json, Value};
+    use std::path::PathBuf;
+
+    /// Set to rewrite the golden file of the running target instead of checking it.
+    const REGEN_VAR: &str = "TRACT_REGEN_GOLDENS";
+
+    #[derive(Clone, Copy, Debug)]
+    enum Operand {
+        Input,
+        Parameter,
+        Const,
+    }
+
+    struct Problem {
+        dt: DatumType,
+        quantized: bool,
+        m: usize,
+        k: usize,
+        n: usize,
+        a: Operand,
+        b: Operand,
+    }
+
+    impl Problem {
+        fn name(&self) -> String {
+            format!(
+                "{:?}{} {}x{}x{} a:{:?} b:{:?}",
+                self.dt,
+                if self.quantized { "(q)" } else { "" },
+                self.m,
+                self.k,
+                self.n,
+                self.a,
+                self.b
+            )
+        }
+
+        fn model(&self) -> TractResult<TypedModel> {
+            let mut model = TypedModel::default();
+            let mut operand = |name: &str, shape: [usize; 2], kind: Operand| -> TractResult<_> {
+                if let Operand::Const = kind {
+                    return model.add_const(name, Tensor::zero_dt(self.dt, &shape)?);
+                }
+                let wire = model.add_source(name, self.dt.fact(shape))?;
+                if let Operand::Parameter = kind {
+                    let ix = model.inputs.len() - 1;
+                    model.set_input_role(ix, TensorRole::Parameter)?;
+                }
+                Ok(wire)
+            };
+            let a = operand("a", [self.m, self.k], self.a)?;
+            let b = operand("b", [self.k, self.n], self.b)?;
+            let mut inputs = tvec!(a, b);
+            let op = if self.quantized {
+                for (name, t) in [
+                    ("bias", tensor0(0i32)),
+                    ("a0", tensor0(0i32)),
+                    ("a_scale", tensor0(0.5f32)),
+                    ("b0", tensor0(0i32)),
+                    ("b_scale", tensor0(0.25f32)),
+                    ("c0", tensor0(3i32)),
+                    ("c_scale", tensor0(2f32)),
+                ] {
+                    inputs.push(model.add_const(name, t)?);
+                }
+                EinSum::newq("mk,kn,,,,,,,->mn".parse()?, i32::datum_type(), self.dt)
+            } else {
+                EinSum::new("mk,kn->mn".parse()?, self.dt)
+            };
+            let c = model.wire_node("matmul", op, &inputs)?;
+            model.set_output_outlets(&c)?;
+            Ok(model)
+        }
+    }
+
+    fn problems() -> Vec<Problem> {
+        use Operand::*;
+        let f32 = |m, k, n, a, b| Problem { dt: DatumType::F32, quantized: false, m, k, n, a, b };
+        let f16 = |m, k, n, a, b| Problem { dt: DatumType::F16, quantized: false, m, k, n, a, b };
+        let i8 = |m, k, n, a, b| Problem { dt: DatumType::I8, quantized: true, m, k, n, a, b };
+        vec![
+            f32(64, 32, 16, Const, Input),
+            f32(16, 32, 64, Const, Input),
+            f32(16, 32, 64, Input, Input),
+            f32(64, 32, 16, Input, Input),
+            f32(16, 32, 64, Parameter, Input),
+            f32(64, 32, 16, Input, Parameter),
+            f32(128, 64, 1, Const, Input),
+            f32(1, 64, 128, Input, Const),
+            f32(1, 64, 128, Input, Input),
+            f16(32, 16, 8, Const, Input),
+            f16(8, 16, 32, Input, Input),
+            i8(32, 64, 8, Const, Input),
+            i8(8, 64, 32, Input, Const),
+            i8(64, 32, 1, Const, Input),
+        ]
+    }
+
+    /// Identifies the kernels tract can pick on the running CPU.
+    fn feature_set() -> String {
+        #[allow(unused_mut)]
+        let mut features = vec![std::env::consts::ARCH];
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                features.push("avx2");
+            }
+            if is_x86_feature_detected!("fma") {
+                features.push("fma");
+            }
+            if is_x86_feature_detected!("avx512f") {
+                features.push("avx512f");
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            if std::arch::is_aarch64_feature_detected!("fp16") {
+                features.push("fp16");
+            }
+        }
+        features.join("-")
+    }
+
+    fn decisions_as_json(problem: &Problem) -> TractResult<Value> {
+        let options = OptimizerOptions { track_patches: true, ..OptimizerOptions::default() };
+        let optimized = problem.model()?.into_optimized_with_options(&options)?;
+        let decisions = lowering_decisions(&optimized)?
+            .into_iter()
+            .map(|d| {
+                json!({
+                    "node": d.node,
+                    "op": d.op,
+                    "kernel": d.kernel,
+                    "swapped": d.swapped,
+                    "fused_specs": d.fused_specs,
+                })
+            })
+            .collect();
+        Ok(Value::Array(decisions))
+    }
+
+    #[test]
+    fn requires_patch_tracking() -> TractResult<()> {
+        let problem = &problems()[0];
+        assert!(lowering_decisions(&problem.model()?.into_optimized()?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn swap_is_recorded() -> TractResult<()> {
+        for (problem, swapped) in [(&problems()[0], false), (&problems()[1], true)] {
+            let decisions = decisions_as_json(problem)?;
+            assert_eq!(decisions[0]["op"], "LirMatMulUnary");
+            assert_eq!(decisions[0]["swapped"], swapped, "{}", problem.name());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn golden_lowering_decisions() -> TractResult<()> {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("test_data/matmul_lowering")
+            .join(format!("{}.json", feature_set()));
+        let mut found = serde_json::Map::new();
+        for problem in problems() {
+            found.insert(problem.name(), decisions_as_json(&problem)?);
+        }
+        if std::env::var(REGEN_VAR).is_ok() {
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(&path, serde_json::to_string_pretty(&found)? + "\n")?;
+            return Ok(());
+        }
+        ensure!(
+            path.exists(),
+            "No lowering goldens for {}: set {REGEN_VAR} to create {path:?}, and commit it",
+            feature_set()
+        );
+        let expected: serde_json::Map<String, Value> =
+            serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        let mismatches = found
+            .iter()
+            .filter(|(name, decisions)| expected.get(*name) != Some(decisions))
+            .map(|(name, decisions)| {
+                let expected = expected.get(name).map(|e| e.to_string());
+                format!(
+                    "{name}:\n  expected {}\n  found    {decisions}",
+                    expected.unwrap_or_default()
+                )
+            })
+            .collect::<Vec<_>>();
+        ensure!(
+            mismatches.is_empty(),
+            "Lowering decisions differ from {path:?} (set {REGEN_VAR} to update):\n{}",
+            mismatches.join("\n")
+        );
+        Ok(())
+    }
+}
@@ -3,10 +3,14 @@ use ops::binary::wire_with_rank_broadcast;
 
 use crate::internal::*;
 use crate::ops;
+use crate::ops::einsum::QOverflow;
+use crate::plan::IN_PLACE_INPUT;
 
 /// Wires the offsetting of a matrix and zero point node.
 ///
-/// Only wires nodes of u8 type and leaves nodes of different type untouched.
+/// Only wires nodes of u8 type and leaves nodes of different type untouched. A constant zero
+/// point is offset in place, as a new constant: rules skipping the compensation of zero zero
+/// points must see its post-shift value (like 0 for a u8 zero point of 128).
 pub(crate) fn wire_offset_u8_as_i8(
     model: &mut TypedModel,
     model_name: &str,
@@ -17,25 +21,36 @@ pub(crate) fn wire_offset_u8_as_i8(
 ) -> TractResult<OutletId> {
     let fact = model.outlet_fact(matrix)?;
     if let DatumType::U8 = fact.datum_type.unquantized() {
-        match model.outlet_fact(*zero_point)?.datum_type.unquantized() {
+        let zp_fact = model.outlet_fact(*zero_point)?.clone();
+        let offset_name = format!("{model_name}.offset_{zero_point_name}_as_i8");
+        match zp_fact.datum_type.unquantized() {
             DatumType::U8 => {
-                *zero_point = model.wire_node(
-                    format!("{model_name}.offset_{zero_point_name}_as_i8"),
-                    ops::quant::offset_u8_as_i8(),
-                    &[*zero_point],
-                )?[0];
+                if let Some(konst) = &zp_fact.konst {
+                    let offset = konst
+                        .to_array_view::<u8>()?
+                        .mapv(ops::quant::offset_u8_as_i8_elementwise)
+                        .into_arc_tensor();
+                    *zero_point = model.add_const(offset_name, offset)?;
+                } else {
+                    *zero_point = model.wire_node(
+                        offset_name,
+                        ops::quant::offset_u8_as_i8(),
+                        &[*zero_point],
+                    )?[0];
+                }
             }
             DatumType::I32 => {
-                let zp_rank = model.outlet_fact(*zero_point)?.rank();
-                let cst = model.add_const(
-                    format!("{model_name}.offset_{zero_point_name}_as_i8.min"),
-                    tensor0(-128i32).broadcast_into_rank(zp_rank)?.into_arc_tensor(),
-                )?;
-                *zero_point = model.wire_node(
-                    format!("{model_name}.offset_{zero_point_name}_as_i8"),
-                    ops::math::add(),
-                    &[*zero_point, cst],
-                )?[0];
+                if let Some(konst) = &zp_fact.konst {
+                    let offset = konst.to_array_view::<i32>()?.mapv(|x| x - 128).into_arc_tensor();
+                    *zero_point = model.add_const(offset_name, offset)?;
+                } else {
+                    let cst = model.add_const(
+                        format!("{offset_name}.min"),
+                        tensor0(-128i32).broadcast_into_rank(zp_fact.rank())?.into_arc_tensor(),
+                    )?;
+                    *zero_point =
+                        model.wire_node(offset_name, ops::math::add(), &[*zero_point, cst])?[0];
+                }
             }
             _ => (),
         }
@@ -95,46 +110,36 @@ pub(crate) fn compensate_zero_points(
     let k = model.add_const(format!("{name}.k"), rctensor0(k))?;
     let k = model.wire_node(format!("{name}.cast_k"), ops::cast::cast(i32::datum_type()), &[k])?[0];
 
-    let a0_sum_b = wire_with_rank_broadcast(
-        &format!("{name}.a0_sum_b"),
+    // per-row and per-column terms are combined first, so the full-size result is only
+    // updated twice
+    let a0_k =
+        wire_with_rank_broadcast(&format!("{name}.a0_k"), model, ops::math::mul(), &[a0, k])?[0];
+
+    let sum_a_minus_a0_k = wire_with_rank_broadcast(
+        &format!("{name}.sum_a_minus_a0_k"),
         model,
-        ops::math::mul(),
-        &[a0, sum_b],
+        ops::math::sub(),
+        &[sum_a, a0_k],
     )?[0];
 
     let b0_sum_a = wire_with_rank_broadcast(
         &format!("{name}.b0_sum_a"),
         model,
         ops::math::mul(),
-        &[b0, sum_a],
+        &[b0, sum_a_minus_a0_k],
     )?[0];
 
-    let a0_k =
-        wire_with_rank_broadcast(&format!("{name}.a0_k"), model, ops::math::mul(), &[a0, k])?[0];
-
-    let a0_k_b0 =
-        wire_with_rank_broadcast(&format!("{name}.a0_k_b0"), model, ops::math::mul(), &[a0_k, b0])?
-            [0];
-
-    let result = wire_with_rank_broadcast(
-        &format!("{}.minus_a0_B", &name),
-        model,
-        ops::math::sub(),
-        &[result, a0_sum_b],
-    )?[0];
-    let result = wire_with_rank_broadcast(
-        &format!("{}.minus_b0_A", &name),
+    let a0_sum_b = wire_with_rank_broadcast(
+        &format!("{name}.a0_sum_b"),
         model,
-        ops::math::sub(),
-        &[result, b0_sum_a],
+        ops::math::mul(),
+        &[a0, sum_b],
     )?[0];
 
-    let result = wire_with_rank_broadcast(
-        &format!("{}.plus_a0_k_b0", &name),
-        model,
-        ops::math::add(),
-        &[result, a0_k_b0],
-    )?[0];
+    let result =
+        wire_in_place(model, &format!("{name}.minus_a0_B"), ops::math::sub(), [result, a0_sum_b])?;
+    let result =
+        wire_in_place(model, &format!("{name}.minus_b0_A"), ops::math::sub(), [result, b0_sum_a])?;
 
     Ok(result)
 }
@@ -146,6 +151,7 @@ pub(crate) fn requant(
     dt: DatumType,
     scale: OutletId,
     zero_point: OutletId,
+    overflow: QOverflow,
 ) -> TractResult<OutletId> {
     let wire = wire_with_rank_broadcast(
         &format!("{name}.scale"),
@@ -153,6 +159,7 @@ pub(crate) fn requant(
         ops::quant::scale(),
         &[scale, wire],
     )?[0];
+    model.set_node_property(wire.node, IN_PLACE_INPUT, rctensor0(1i64))?;
 
     let zero_point = model.wire_node(
         format!("{name}.cast_c0"),
@@ -160,25 +167,32 @@ pub(crate) fn requant(
         &[zero_point],
     )?[0];
 
-    let wire = wire_with_rank_broadcast(
-        &format!("{name}.zeropoint"),
-        model,
-        ops::math::add(),
-        &[wire, zero_point],
-    )?[0];
+    let wire =
+        wire_in_place(model, &format!("{name}.zeropoint"), ops::math::add(), [wire, zero_point])?;
 
-    clamp_and_cast_to(model, name, dt, wire)
+    clamp_and_cast_to(model, name, dt, wire, overflow)
 }
 
+/// Cast an i32 wire to `dt`, saturating or wrapping the values it can not represent.
 pub(crate) fn clamp_and_cast_to(
     model: &mut TypedModel,
     name: &str,
     dt: DatumType,
     wire: OutletId,
+    overflow: QOverflow,
 ) -> TractResult<OutletId> {
     if dt == i32::datum_type() {
         return Ok(wire);
     }
+    if overflow == QOverflow::Wrap {
+        // casting to a quantized type saturates: wrap with a plain integer cast first
+        let wire =
+            model.wire_node(format!("{name}.wrap"), ops::cast::cast(dt.unquantized()), &[wire])?;
+        if !dt.is_quantized() {
+            return Ok(wire[0]);
+        }
+        return Ok(model.wire_node(format!("{name}.cast"), ops::cast::cast(dt), &wire)?[0]);
+    }
     let rank = model.outlet_fact(wire)?.rank();
     let inf = dt
         .unquantized()
@@ -196,12 +210,25 @@ pub(crate) fn clamp_and_cast_to(
         .broadcast_into_rank(rank)?
         .into_arc_tensor();
     let sup = model.add_const(format!("{name}.max.const"), sup)?;
-    let wire = model.wire_node(format!("{name}.min"), ops::math::min(), &[wire, sup])?;
-    let wire = model.wire_node(format!("{name}.max"), ops::math::max(), &[wire[0], inf])?;
-    let wire = model.wire_node(format!("{name}.cast"), ops::cast::cast(dt), &wire)?;
+    let wire = wire_in_place(model, &format!("{name}.min"), ops::math::min(), [wire, sup])?;
+    let wire = wire_in_place(model, &format!("{name}.max"), ops::math::max(), [wire, inf])?;
+    let wire = model.wire_node(format!("{name}.cast"), ops::cast::cast(dt), &[wire])?;
     Ok(wire[0])
 }
 
+/// Wire a binary op updating its first, full-size, input: the node is tagged so the memory
+/// estimates count its output in the input buffer (see `IN_PLACE_INPUT`).
+pub(crate) fn wire_in_place(
+    model: &mut TypedModel,
+    name: &str,
+    op: impl Into<Box<dyn TypedOp>>,
+    inputs: [OutletId; 2],
+) -> TractResult<OutletId> {
+    let wire = wire_with_rank_broadcast(name, model, op, &inputs)?[0];
+    model.set_node_property(wire.node, IN_PLACE_INPUT, rctensor0(0i64))?;
+    Ok(wire)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -379,6 +406,47 @@ mod test {
         .check();
     }
 
+    #[test]
+    fn u8_const_weights_i8_activations() -> TractResult<()> {
+        // TFLite legacy weights: u8 with a zero point of 128, i8 activations with a zero point of 0
+        let (m, k, n) = (4, 16, 3);
+        let a = Array2::from_shape_fn((m, k), |(i, j)| ((i * 13 + j * 7) % 41) as i8 - 20);
+        let b = Array2::from_shape_fn((k, n), |(i, j)| ((i * 5 + j * 11) % 61) as u8 + 98);
+        let (a_scale, b_scale, c_scale) = (0.05f32, 0.02f32, 0.1f32);
+        let mut model = TypedModel::default();
+        let mut inputs = tvec!(model.add_source("a", i8::fact([m, k]))?);
+        inputs.push(model.add_const("b", b.clone().into_arc_tensor())?);
+        inputs.push(model.add_const("bias", rctensor0(0i32))?);
+        inputs.push(model.add_const("a0", rctensor0(0i8))?);
+        inputs.push(model.add_const("a_scale", rctensor0(a_scale))?);
+        inputs.push(model.add_const("b0", rctensor0(128u8))?);
+        inputs.push(model.add_const("b_scale", rctensor0(b_scale))?);
+        inputs.push(model.add_const("c0", rctensor0(0i8))?);
+        inputs.push(model.add_const("c_scale", rctensor0(c_scale))?);
+        let op = crate::ops::einsum::EinSum::newq(
+            "mk,kn,,,,,,,->mn".parse()?,
+            i32::datum_type(),
+            i8::datum_type(),
+        );
+        let c = model.wire_node("einsum", op, &inputs)?;
+        model.set_output_outlets(&c)?;
+        let model = model.into_optimized()?;
+        // the shifted zero point is a constant, not an arithmetic node
+        let offset = model.node_by_name("einsum.offset_b0_as_i8");
+        assert!(offset.map(|n| n.op_is::<crate::ops::konst::Const>()).unwrap_or(true));
+
+        let float_a = a.map(|&x| x as f32 * a_scale);
+        let float_b = b.map(|&x| (x as f32 - 128.) * b_scale);
+        let reference =
+            float_a.dot(&float_b).map(|&x| round_ties_to_right(x / c_scale).clamp(-128, 127));
+        let found = model.into_runnable()?.run(tvec!(a.into_tvalue()))?;
+        let found = found[0].to_array_view::<i8>()?;
+        for (r, f) in reference.iter().zip(found.iter()) {
+            assert!((r - *f as i32).abs() <= 1, "reference: {reference:?}, tract: {found:?}");
+        }
+        Ok(())
+    }
+
     fn round_ties_to_right(x: f32) -> i32 {
         (x + 0.5).floor() as i32
     }
@@ -473,11 +541,11 @@ mod test {
                     let result = model
                         .wire_node(
                             "einsum",
-                            crate::ops::einsum::EinSum {
-                                axes: "mk,kn,,,,,,,->mn".parse().unwrap(),
-                                operating_dt: i32::datum_type(),
-                                q_params: Some(<$c>::datum_type())
-                            },
+                            crate::ops::einsum::EinSum::newq(
+                                "mk,kn,,,,,,,->mn".parse().unwrap(),
+                                i32::datum_type(),
+                                <$c>::datum_type(),
+                            ),
                             &inputs,
                         ).unwrap();
                     model.set_output_outlets(&result).unwrap();
@@ -0,0 +1,303 @@
+//! Numerical soundness checks of the matrix multiplications of a model.
+use super::lir_unary::{LirMatMulUnary, ProtoFusedSpec};
+use crate::internal::*;
+use crate::ops::einsum::EinSum;
+use tract_itertools::Itertools;
+
+/// Class of numerical defect found by `check_numerics_policy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum NumericsIssueKind {
+    /// The accumulator type has less precision or range than an operand type.
+    LossyAccumulator,
+    /// Integer accumulation over k can exceed the range of the accumulator.
+    AccumulatorOverflow,
+    /// The output scale is too small for the typical accumulator range: outputs saturate.
+    OutputSaturation,
+}
+
+/// A numerical defect of a matrix multiplication node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NumericsIssue {
+    pub node: usize,
+    pub name: String,
+    pub kind: NumericsIssueKind,
+    pub detail: String,
+    pub suggestion: String,
+}
+
+impl std::fmt::Display for NumericsIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?} in {}: {} ({})", self.kind, self.name, self.detail, self.suggestion)
+    }
+}
+
+/// Report the matrix multiplication nodes (EinSum and LirMatMulUnary) of `model` whose
+/// accumulator type loses precision, whose integer accumulation can overflow for a concrete k,
+/// or whose quantized output scale implies systematic saturation.
+pub fn check_numerics_policy(model: &TypedModel) -> TractResult<Vec<NumericsIssue>> {
+    let mut issues = vec![];
+    for node in model.nodes() {
+        let mut report = |kind: NumericsIssueKind, detail: String, suggestion: String| {
+            issues.push(NumericsIssue {
+                node: node.id,
+                name: node.name.clone(),
+                kind,
+                detail,
+                suggestion,
+            })
+        };
+        let input_facts = model.node_input_facts(node.id)?;
+        let (acc, operands, k) = if let Some(op) = node.op_as::<EinSum>() {
+            let operand_count = if op.q_params.is_some() { 2 } else { node.inputs.len() };
+            let operands = (0..operand_count)
+                .map(|ix| Ok((input_facts[ix].datum_type, einsum_magnitude(model, node, op, ix)?)))
+                .collect::<TractResult<TVec<_>>>()?;
+            (op.operating_dt, operands, einsum_k(op, &input_facts))
+        } else if let Some(op) = node.op_as::<LirMatMulUnary>() {
+            let Some((k, a, b)) = op.micro_ops.iter().find_map(|spec| match spec {
+                ProtoFusedSpec::AddMatMul(geo, a, b) => Some((geo.k.clone(), *a, *b)),
+                _ => None,
+            }) else {
+                continue;
+            };
+            let operands = [a, b]
+                .iter()
+                .map(|ix| {
+                    let dt = input_facts[*ix].datum_type;
+                    (dt, magnitude(dt, None))
+                })
+                .collect();
+            (op.mmm.internal_type(), operands, Some(k))
+        } else {
+            continue;
+        };
+        if !is_number(acc) || operands.iter().any(|(dt, _)| !is_number(*dt)) {
+            continue;
+        }
+
+        for dt in operands.iter().map(|(dt, _)| dt.unquantized()).unique() {
+            if loses_precision(acc, dt) {
+                report(
+                    NumericsIssueKind::LossyAccumulator,
+                    format!("accumulating {dt:?} operands in {acc:?}"),
+                    format!("accumulate in {dt:?}"),
+                );
+            }
+        }
+
+        let Some(k) = k.and_then(|k| k.to_usize().ok()) else { continue };
+        let product = operands.iter().map(|(_, m)| m).product::<f64>();
+        // quantized einsums on 16-bit operands accumulate in i64 or f32, not in operating_dt
+        let widened = matches!(node.op_as::<EinSum>(), Some(op) if op.q_params.is_some())
+            && operands.iter().any(|(dt, _)| dt.unquantized().size_of() == 2);
+        if !acc.is_float() && !widened {
+            let acc_max = acc.max_value().cast_to_scalar::<f64>()?;
+            if k as f64 * product > acc_max {
+                let max_k = (acc_max / product).floor();
+                report(
+                    NumericsIssueKind::AccumulatorOverflow,
+                    format!("k={k} products of magnitude up to {product} overflow {acc:?}"),
+                    format!("accumulate in a wider type, or split k in chunks of at most {max_k}"),
+                );
+            }
+        }
+
+        if let Some(saturation) = output_saturation(model, node, k, product)? {
+            report(NumericsIssueKind::OutputSaturation, saturation.0, saturation.1);
+        }
+    }
+    Ok(issues)
+}
+
+fn is_number(dt: DatumType) -> bool {
+    dt.is_float() || dt.is_integer()
+}
+
+/// Does `acc` represent less values of `input` than `input` itself ?
+fn loses_precision(acc: DatumType, input: DatumType) -> bool {
+    let mantissa_bits = |dt: DatumType| match dt {
+        DatumType::F16 => 11,
+        DatumType::F32 => 24,
+        _ => 53,
+    };
+    let value_bits = |dt: DatumType| dt.size_of() * 8 - dt.is_signed() as usize;
+    match (acc.is_float(), input.is_float()) {
+        (true, true) => acc.size_of() < input.size_of(),
+        (false, true) => true,
+        (true, false) => value_bits(input) > mantissa_bits(acc),
+        (false, false) => value_bits(acc) < value_bits(input),
+    }
+}
+
+/// Largest magnitude of an operand of type `dt`, centered by `zero_point` if any.
+fn magnitude(dt: DatumType, zero_point: Option<f64>) -> f64 {
+    let dt = dt.unquantized();
+    let min = dt.min_value().cast_to_scalar::<f64>().unwrap_or(0.);
+    let max = dt.max_value().cast_to_scalar::<f64>().unwrap_or(0.);
+    if let Some(zp) = zero_point {
+        (max - zp).max(zp - min)
+    } else {
+        max.abs().max(min.abs())
+    }
+}
+
+/// Magnitude of einsum operand `ix`. Quantized operands are centered by their zero point: a
+/// non-constant (or per-axis) one can be anywhere in the type range.
+fn einsum_magnitude(
+    model: &TypedModel,
+    node: &TypedNode,
+    op: &EinSum,
+    ix: usize,
+) -> TractResult<f64> {
+    let dt = model.outlet_fact(node.inputs[ix])?.datum_type;
+    if op.q_params.is_none() || !dt.is_integer() {
+        return Ok(magnitude(dt, None));
+    }
+    let zp_fact = model.outlet_fact(node.inputs[3 + 2 * ix])?;
+    if let Some(zp) = zp_fact.konst.as_ref().filter(|zp| zp.len() == 1) {
+        return Ok(magnitude(dt, Some(zp.cast_to_scalar::<f64>()?)));
+    }
+    let dt = dt.unquantized();
+    Ok(dt.max_value().cast_to_scalar::<f64>()? - dt.min_value().cast_to_scalar::<f64>()?)
+}
+
+/// Product of the summed dimensions of an einsum.
+fn einsum_k(op: &EinSum, inputs: &[&TypedFact]) -> Option<TDim> {
+    let operands = if op.q_params.is_some() { 2 } else { inputs.len() };
+    op.axes
+        .iter_all_axes()
+        .filter(|axis| axis.outputs[0].is_empty())
+        .filter_map(|axis| {
+            (0..operands)
+                .find_map(|ix| axis.inputs[ix].first().map(|p| inputs[ix].shape[*p].clone()))
+        })
+        .product::<TDim>()
+        .into()
+}
+
+/// A quantized einsum saturates systematically when a typical accumulator (k products of
+/// operands spread uniformly over their range, adding up as a random walk) exceeds the output
+/// range. Scales must be constant scalars.
+fn output_saturation(
+    model: &TypedModel,
+    node: &TypedNode,
+    k: usize,
+    product: f64,
+) -> TractResult<Option<(String, String)>> {
+    let Some(op) = node.op_as::<EinSum>() else { return Ok(None) };
+    let Some(qp) = op.q_params.map(|qp| qp.unquantized()) else { return Ok(None) };
+    if !qp.is_integer() || qp.size_of() >= 4 {
+        return Ok(None);
+    }
+    let scale = |ix: usize| -> TractResult<Option<f64>> {
+        let fact = model.outlet_fact(node.inputs[ix])?;
+        let Some(konst) = fact.konst.as_ref().filter(|k| k.len() == 1) else { return Ok(None) };
+        Ok(Some(konst.cast_to_scalar::<f64>()?))
+    };
+    let (Some(a_scale), Some(b_scale), Some(c_scale)) = (scale(4)?, scale(6)?, scale(8)?) else {
+        return Ok(None);
+    };
+    // a uniform distribution over [-m, m] has a standard deviation of m / sqrt(3)
+    let typical = (k as f64).sqrt() * product / 3. * a_scale * b_scale;
+    let half_range = magnitude(qp, None);
+    if typical / c_scale <= half_range {
+        return Ok(None);
+    }
+    Ok(Some((
+        format!(
+            "typical accumulator {typical:.3e} exceeds the {qp:?} range at c_scale={c_scale:e}"
+        ),
+        format!("use a c_scale of at least {:e}", typical / half_range),
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn einsum_model(
+        a_dt: DatumType,
+        b_dt: DatumType,
+        k: usize,
+        op: EinSum,
+        q: Option<[f32; 3]>,
+    ) -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let mut inputs = tvec!(
+            model.add_source("a", a_dt.fact([4, k]))?,
+            model.add_const("b", Tensor::zero_dt(b_dt, &[k, 3])?)?
+        );
+        if let Some([a_scale, b_scale, c_scale]) = q {
+            for (name, t) in [
+                ("bias", tensor0(0i32)),
+                ("a0", tensor0(0i8)),
+                ("a_scale", tensor0(a_scale)),
+                ("b0", tensor0(0i8)),
+                ("b_scale", tensor0(b_scale)),
+                ("c0", tensor0(0i8)),
+                ("c_scale", tensor0(c_scale)),
+            ] {
+                inputs.push(model.add_const(name, t)?);
+            }
+        }
+        let c = model.wire_node("matmul", op, &inputs)?;
+        model.set_output_outlets(&c)?;
+        Ok(model)
+    }
+
+    fn kinds(model: &TypedModel) -> TractResult<Vec<NumericsIssueKind>> {
+        Ok(model.check_numerics_policy()?.into_iter().map(|issue| issue.kind).collect())
+    }
+
+    #[test]
+    fn sound_models() -> TractResult<()> {
+        let f32 = f32::datum_type();
+        let op = EinSum::new("mk,kn->mn".parse()?, f32);
+        let model = einsum_model(f32, f32, 64, op, None)?;
+        assert_eq!(kinds(&model)?, vec![]);
+        assert_eq!(kinds(&model.into_optimized()?)?, vec![]);
+        let op = EinSum::newq("mk,kn,,,,,,,->mn".parse()?, i32::datum_type(), i8::datum_type());
+        let model = einsum_model(i8::datum_type(), i8::datum_type(), 64, op, Some([0.1, 0.1, 5.]))?;
+        assert_eq!(kinds(&model)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn lossy_accumulator() -> TractResult<()> {
+        let f32 = f32::datum_type();
+        let op = EinSum::new("mk,kn->mn".parse()?, f16::datum_type());
+        let issues = einsum_model(f32, f32, 64, op, None)?.check_numerics_policy()?;
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].kind, NumericsIssueKind::LossyAccumulator);
+        assert_eq!(issues[0].name, "matmul");
+        assert!(issues[0].suggestion.contains("F32"), "{}", issues[0]);
+        Ok(())
+    }
+
+    #[test]
+    fn integer_overflow() -> TractResult<()> {
+        let (i16, i32) = (i16::datum_type(), i32::datum_type());
+        let op = EinSum::new("mk,kn->mn".parse()?, i32);
+        let model = einsum_model(i16, i16, 4, op, None)?;
+        assert_eq!(kinds(&model)?, vec![NumericsIssueKind::AccumulatorOverflow]);
+        // i8 products only overflow for a very large k
+        let op = EinSum::new("mk,kn->mn".parse()?, i32);
+        assert_eq!(
+            kinds(&einsum_model(i8::datum_type(), i8::datum_type(), 64, op, None)?)?,
+            vec![]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn output_saturation() -> TractResult<()> {
+        let op = EinSum::newq("mk,kn,,,,,,,->mn".parse()?, i32::datum_type(), i8::datum_type());
+        let model =
+            einsum_model(i8::datum_type(), i8::datum_type(), 256, op, Some([0.1, 0.1, 0.01]))?;
+        let issues = model.check_numerics_policy()?;
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].kind, NumericsIssueKind::OutputSaturation);
+        assert!(issues[0].suggestion.contains("c_scale"));
+        Ok(())
+    }
+}
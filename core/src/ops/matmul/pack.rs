@@ -1,14 +1,68 @@
+use super::{BoundedShape, ReusedOutput};
 use crate::axes::Axis;
 use crate::internal::*;
+use crate::ops::array::Slice;
+use crate::ops::OpStateFreeze;
 use ndarray::*;
 
+use std::ops::Range;
+use std::sync::{Mutex, Weak};
 use tract_linalg::frame::Packer;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct MatMatMulPack {
-    pub(crate) packer: Packer,
-    pub(crate) k_axis: usize,
-    pub(crate) mn_axis: usize,
+    pub packer: Packer,
+    pub k_axis: usize,
+    pub mn_axis: usize,
+    /// Packing a model input tagged as a parameter: the packed value is kept in the op state
+    /// until the session parameters generation changes.
+    pub parameter: bool,
+    /// Axis (k_axis or mn_axis) along which a second, boolean input masks the operand: the
+    /// packed values at masked out positions are zeroed.
+    pub mask_axis: Option<usize>,
+    /// Bounds of a symbolic output shape: the packed output is allocated once in the op state.
+    pub bounded_output: Option<BoundedShape>,
+    /// Range of the input along an axis to pack: the sliced values are read in place through
+    /// the input strides instead of being copied out by a Slice first.
+    pub input_slice: Option<(usize, Range<usize>)>,
+    /// Number of k records of the packed panels, past the input k: the padding records are
+    /// zeroed, so the product runs a k fixed at lowering over them (see `KPadding`).
+    pub k_padded: Option<usize>,
+}
+
+/// Zero padding of the k axis of the products with a small k, done by the operand packings.
+///
+/// When k is at most `max_k`, or bounded by it through the optimizer symbol bounds, both
+/// operands are packed with k rounded up to its maximum, then to a multiple of `multiple`. The
+/// padding records are zeros and add nothing to the products, so the kernel runs the padded k,
+/// known at lowering, instead of resolving the run time k and its remainder on each call.
+///
+/// The zero point compensation of quantized products is wired before the lowering to the
+/// kernel, from the einsum shapes: it keeps using the true k.
+///
+/// This pays off with kernels running k in unrolled steps. The x86_64 kernels run one k per
+/// iteration without a remainder loop: the `k_padding` bench (k bounded to 37, m=n=64) measures
+/// padded products 3 to 8% slower than the unpadded ones, the padding records being extra
+/// work. The option is off by default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KPadding {
+    pub max_k: usize,
+    pub multiple: usize,
+}
+
+impl KPadding {
+    /// The k to pack the operands of a product of `k` to, if they are to be padded.
+    pub fn padded_k(&self, k: &TDim, bounds: &SymbolValues) -> Option<usize> {
+        let max = match k.to_usize() {
+            Ok(k) => k,
+            Err(_) => BoundedShape::new(std::slice::from_ref(k), bounds)?.max_shape[0],
+        };
+        if max > self.max_k || self.multiple == 0 {
+            return None;
+        }
+        let padded = max.divceil(self.multiple) * self.multiple;
+        Some(padded).filter(|p| k.to_usize().map(|k| k != *p).unwrap_or(true))
+    }
 }
 
 impl Op for MatMatMulPack {
@@ -16,6 +70,16 @@ impl Op for MatMatMulPack {
         "MatMatMulPack".into()
     }
 
+    fn info(&self) -> TractResult<Vec<String>> {
+        let routine = if self.transposing() { "blocked transpose" } else { "panel copy" };
+        let mut info =
+            vec![format!("k axis: {} mn axis: {} ({routine})", self.k_axis, self.mn_axis)];
+        if let Some(k) = self.k_padded {
+            info.push(format!("k padded to {k}"));
+        }
+        Ok(info)
+    }
+
     fn same_as(&self, other: &dyn Op) -> bool {
         other.downcast_ref::<Self>().map(|other| other == self).unwrap_or(false)
     }
@@ -25,48 +89,141 @@ impl Op for MatMatMulPack {
 
 impl EvalOp for MatMatMulPack {
     fn is_stateless(&self) -> bool {
-        true
+        !self.parameter && self.bounded_output.is_none()
     }
 
-    fn eval(&self, mut inputs: TVec<TValue>) -> TractResult<TVec<TValue>> {
-        let b = args_1!(inputs);
-        let dt = b.datum_type();
-        unsafe {
-            let output_shape = self.output_shape(b.shape());
-            let mut packed =
-                Tensor::uninitialized_aligned_dt(dt, &output_shape, self.packer.alignment())
-                    .unwrap();
-            let mut bc_shape: TVec<usize> = b.shape().into();
-            bc_shape[self.k_axis] = 1;
-            bc_shape[self.mn_axis] = 1;
-            for coord in indices(&*bc_shape) {
-                let offset = coord
-                    .as_array_view()
-                    .iter()
-                    .zip(b.strides())
-                    .map(|(x, s)| *x as isize * s)
-                    .sum::<isize>()
-                    * b.datum_type().size_of() as isize;
-                let mut prefix: TVec<usize> = coord.slice().into();
-                prefix.remove(self.k_axis.max(self.mn_axis));
-                prefix.remove(self.k_axis.min(self.mn_axis));
-                self.packer.pack(
-                    &mut packed.view_at_prefix_mut(&prefix)?,
-                    TensorView::from_bytes(&b, offset, b.shape(), b.strides()),
-                    self.k_axis,
-                    self.mn_axis,
-                )
+    fn eval(&self, inputs: TVec<TValue>) -> TractResult<TVec<TValue>> {
+        let mask = inputs.get(1).map(|m| &**m);
+        Ok(tvec!(self.pack_masked(&inputs[0], mask, &PackedConstantStorage::Heap)?.into_tvalue()))
+    }
+
+    fn state(
+        &self,
+        _session: &mut SessionState,
+        _node_id: usize,
+    ) -> TractResult<Option<Box<dyn OpState>>> {
+        if self.parameter {
+            Ok(Some(Box::<MatMatMulPackState>::default()))
+        } else if self.bounded_output.is_some() {
+            Ok(Some(Box::<BoundedPackState>::default()))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Packed output buffer, allocated at the bounded worst case shape on the first run (the
+/// operand type is not known before).
+#[derive(Clone, Debug, Default)]
+struct BoundedPackState(ReusedOutput);
+
+#[derive(Clone, Debug)]
+struct FrozenBoundedPackState;
+
+impl FrozenOpState for FrozenBoundedPackState {
+    fn unfreeze(&self) -> Box<dyn OpState> {
+        Box::<BoundedPackState>::default()
+    }
+}
+
+impl OpStateFreeze for BoundedPackState {
+    fn freeze(&self) -> Box<dyn FrozenOpState> {
+        Box::new(FrozenBoundedPackState)
+    }
+}
+
+impl OpState for BoundedPackState {
+    fn eval(
+        &mut self,
+        session: &mut SessionState,
+        op: &dyn Op,
+        inputs: TVec<TValue>,
+    ) -> TractResult<TVec<TValue>> {
+        let op = op.downcast_ref::<MatMatMulPack>().context("Wrong op")?;
+        let bounded = op.bounded_output.as_ref().context("Expected a bounded output")?;
+        bounded.check(&session.resolved_symbols)?;
+        let (b, mask) = (&inputs[0], inputs.get(1).map(|m| &**m));
+        debug_assert!(op.check_axes(b.rank()).is_ok(), "{:?}", op.check_axes(b.rank()));
+        let shape = op.output_shape(b.shape());
+        let alignment = op.packer.alignment();
+        let packed = self.0.compute(b.datum_type(), bounded, alignment, &shape, |p| {
+            op.pack_masked_into(b, mask, p)
+        })?;
+        Ok(tvec!(packed))
+    }
+}
+
+/// Packed parameter, with the parameters generation it was packed at.
+#[derive(Clone, Debug, Default)]
+struct MatMatMulPackState(Option<(usize, Arc<Tensor>)>);
+trivial_op_state_freeeze!(MatMatMulPackState);
+
+impl OpState for MatMatMulPackState {
+    fn eval(
+        &mut self,
+        session: &mut SessionState,
+        op: &dyn Op,
+        inputs: TVec<TValue>,
+    ) -> TractResult<TVec<TValue>> {
+        match &self.0 {
+            Some((generation, packed)) if *generation == session.parameters_generation => {
+                Ok(tvec!(TValue::Const(packed.clone())))
+            }
+            _ => {
+                let op = op.downcast_ref::<MatMatMulPack>().context("Wrong op")?;
+                let mask = inputs.get(1).map(|m| &**m);
+                let packed = op
+                    .pack_masked(&inputs[0], mask, &PackedConstantStorage::Heap)?
+                    .into_arc_tensor();
+                self.0 = Some((session.parameters_generation, packed.clone()));
+                Ok(tvec!(TValue::Const(packed)))
             }
-            Ok(tvec!(packed.into_tvalue()))
         }
     }
 }
 
 impl TypedOp for MatMatMulPack {
     fn output_facts(&self, inputs: &[&TypedFact]) -> TractResult<TVec<TypedFact>> {
+        ensure!(inputs.len() == 1 + self.mask_axis.is_some() as usize);
+        self.check_axes(inputs[0].rank())?;
+        if let Some(axis) = self.mask_axis {
+            let mask = inputs[1];
+            ensure!(mask.datum_type == bool::datum_type());
+            ensure!(mask.rank() == inputs[0].rank());
+            ensure!(mask.shape.iter().enumerate().all(|(ix, d)| ix == axis || d.is_one()));
+            ensure!(mask.shape[axis] == inputs[0].shape[axis]);
+        }
+        if let Some((axis, range)) = &self.input_slice {
+            ensure!(self.mask_axis.is_none(), "Can not pack a slice of a masked operand");
+            ensure!(
+                inputs[0].shape[*axis].to_usize().map(|d| range.end <= d).unwrap_or(true),
+                "Slice {range:?} out of the input axis #{axis} ({:?})",
+                inputs[0].shape
+            );
+        }
+        if let (Some(padded), Ok(k)) = (self.k_padded, self.output_k(&inputs[0].shape).to_usize()) {
+            ensure!(k <= padded, "Can not pack k={k} in {padded} records");
+        }
         Ok(tvec!(inputs[0].datum_type.fact(self.output_shape(&inputs[0].shape))))
     }
 
+    fn fuse(&self, model: &TypedModel, node: &TypedNode) -> TractResult<Option<TypedModelPatch>> {
+        if self.input_slice.is_some() || self.mask_axis.is_some() {
+            return Ok(None);
+        }
+        let prec = model.node(node.inputs[0].node);
+        let Some(slice) = prec.op_as::<Slice>() else { return Ok(None) };
+        let (Ok(start), Ok(end)) = (slice.start.to_usize(), slice.end.to_usize()) else {
+            return Ok(None);
+        };
+        let op = MatMatMulPack { input_slice: Some((slice.axis, start..end)), ..self.clone() };
+        let mut patch = TypedModelPatch::new("Pack slice in place");
+        let input = patch.tap_model(model, prec.inputs[0])?;
+        let wire = patch.wire_node(&node.name, op, &[input])?;
+        patch.shunt_outside(model, node.id.into(), wire[0])?;
+        Ok(Some(patch))
+    }
+
     fn axes_mapping(
         &self,
         inputs: &[&TypedFact],
@@ -76,23 +233,977 @@ impl TypedOp for MatMatMulPack {
             .filter(|&ix| ix != self.k_axis && ix != self.mn_axis)
             .enumerate()
             .zip('a'..)
-            .map(|((o, i), repr)| Axis::new(repr, 1, 1).input(0, i).output(0, o))
+            .map(|((o, i), repr)| Axis::new(repr, inputs.len(), 1).input(0, i).output(0, o))
             .collect();
-        axes.push(Axis::new('K', 1, 1).input(0, self.k_axis));
-        axes.push(Axis::new('M', 1, 1).input(0, self.mn_axis));
-        axes.push(Axis::new('P', 1, 1).output(0, outputs[0].rank() - 1));
-        AxesMapping::new(1, 1, axes)
+        axes.push(Axis::new('K', inputs.len(), 1).input(0, self.k_axis));
+        axes.push(Axis::new('M', inputs.len(), 1).input(0, self.mn_axis));
+        axes.push(Axis::new('P', inputs.len(), 1).output(0, outputs[0].rank() - 1));
+        if inputs.len() == 2 {
+            for axis in &mut axes {
+                if let Some(&pos) = axis.inputs[0].first() {
+                    axis.inputs[1].push(pos);
+                }
+            }
+        }
+        AxesMapping::new(inputs.len(), 1, axes)
     }
 
     as_op!();
 }
 
 impl MatMatMulPack {
-    fn output_shape<D: DimLike>(&self, input: &[D]) -> TVec<D> {
+    /// A plain packing of the `k_axis` and `mn_axis` of an input of rank `rank`.
+    pub fn new(
+        packer: Packer,
+        k_axis: usize,
+        mn_axis: usize,
+        rank: usize,
+    ) -> TractResult<MatMatMulPack> {
+        let op = MatMatMulPack {
+            packer,
+            k_axis,
+            mn_axis,
+            parameter: false,
+            mask_axis: None,
+            bounded_output: None,
+            input_slice: None,
+            k_padded: None,
+        };
+        op.check_axes(rank)?;
+        Ok(op)
+    }
+
+    /// Check the axes the op refers to are distinct where they must be, and exist in an input
+    /// of rank `rank`.
+    pub fn check_axes(&self, rank: usize) -> TractResult<()> {
+        ensure!(
+            self.k_axis != self.mn_axis,
+            "Packing with axis #{} as both k and mn axis",
+            self.k_axis
+        );
+        ensure!(
+            self.k_axis < rank && self.mn_axis < rank,
+            "Packing k axis #{} and mn axis #{} of a rank {rank} input",
+            self.k_axis,
+            self.mn_axis
+        );
+        if let Some(axis) = self.mask_axis {
+            ensure!(
+                axis == self.k_axis || axis == self.mn_axis,
+                "Mask axis #{axis} is neither the k axis nor the mn axis"
+            );
+        }
+        if let Some((axis, _)) = &self.input_slice {
+            ensure!(*axis < rank, "Slicing axis #{axis} of a rank {rank} input");
+        }
+        Ok(())
+    }
+
+    /// Pack `b`, allocating the packed tensor according to `storage`.
+    pub fn pack(&self, b: &Tensor, storage: &PackedConstantStorage) -> TractResult<Tensor> {
+        self.pack_masked(b, None, storage)
+    }
+
+    /// Pack `b`, zeroing the values where `mask` (see `mask_axis`) is false.
+    pub fn pack_masked(
+        &self,
+        b: &Tensor,
+        mask: Option<&Tensor>,
+        storage: &PackedConstantStorage,
+    ) -> TractResult<Tensor> {
+        debug_assert!(self.check_axes(b.rank()).is_ok(), "{:?}", self.check_axes(b.rank()));
+        let output_shape = self.output_shape(b.shape());
+        let alignment = self.packer.alignment();
+        let mut packed = storage.allocate(b.datum_type(), &output_shape, alignment)?;
+        self.pack_masked_into(b, mask, &mut packed)?;
+        Ok(packed)
+    }
+
+    /// Pack `b` in `packed`, already shaped as the output.
+    pub(crate) fn pack_masked_into(
+        &self,
+        b: &Tensor,
+        mask: Option<&Tensor>,
+        packed: &mut Tensor,
+    ) -> TractResult<()> {
+        let dt = b.datum_type();
+        // the packed operand is a view of b: its shape, with b strides
+        let mut shape: TVec<usize> = b.shape().into();
+        let mut start = 0;
+        if let Some((axis, range)) = &self.input_slice {
+            ensure!(range.end <= shape[*axis], "Slice {range:?} out of {:?}", b.shape());
+            shape[*axis] = range.len();
+            start = range.start as isize * b.strides()[*axis];
+        }
+        let (k, mn) = (shape[self.k_axis], shape[self.mn_axis]);
+        let packed_k = self.k_padded.unwrap_or(k);
+        ensure!(k <= packed_k, "Can not pack k={k} in {packed_k} records");
+        let mut bc_shape = shape.clone();
+        bc_shape[self.k_axis] = 1;
+        bc_shape[self.mn_axis] = 1;
+        unsafe {
+            for coord in indices(&*bc_shape) {
+                let offset = coord
+                    .as_array_view()
+                    .iter()
+                    .zip(b.strides())
+                    .fold(start, |acc, (x, s)| acc + *x as isize * s)
+                    * dt.size_of() as isize;
+                let mut prefix: TVec<usize> = coord.slice().into();
+                prefix.remove(self.k_axis.max(self.mn_axis));
+                prefix.remove(self.k_axis.min(self.mn_axis));
+                let mut view = packed.view_at_prefix_mut(&prefix)?;
+                if packed_k > k {
+                    let ptr = view.as_ptr_mut_unchecked::<u8>();
+                    self.pack_padded(ptr, b, offset, &shape, packed_k);
+                } else {
+                    let input = TensorView::from_bytes(b, offset, &shape, b.strides());
+                    if self.transposing() {
+                        self.packer.pack_transposing(&mut view, input, self.k_axis, self.mn_axis);
+                    } else {
+                        self.packer.pack(&mut view, input, self.k_axis, self.mn_axis);
+                    }
+                }
+                if let (Some(axis), Some(mask)) = (self.mask_axis, mask) {
+                    let ptr = view.as_ptr_mut_unchecked::<u8>();
+                    let along_k = axis == self.k_axis;
+                    let (item_size, mask) = (dt.size_of(), mask.as_slice()?);
+                    self.zero_masked(ptr, item_size, (k, mn), packed_k, along_k, mask);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Pack the operand of `shape` at `offset` bytes in `b` panel by panel, each panel taking
+    /// `packed_k` records: the records past the operand k are zeroed.
+    unsafe fn pack_padded(
+        &self,
+        packed: *mut u8,
+        b: &Tensor,
+        offset: isize,
+        shape: &[usize],
+        packed_k: usize,
+    ) {
+        let (k, mn) = (shape[self.k_axis], shape[self.mn_axis]);
+        let (r, item_size) = (self.packer.r, b.datum_type().size_of());
+        let panel_len = self.packer.single_panel_len(packed_k);
+        let mut panel_shape: TVec<usize> = shape.into();
+        for panel in 0..mn.divceil(r) {
+            panel_shape[self.mn_axis] = r.min(mn - panel * r);
+            let offset =
+                offset + (panel * r) as isize * b.strides()[self.mn_axis] * item_size as isize;
+            let input = TensorView::from_bytes(b, offset, &panel_shape, b.strides());
+            let ptr = packed.add(panel * panel_len * item_size);
+            if self.transposing() {
+                self.packer.pack_transposing_to(ptr, &input, self.k_axis, self.mn_axis);
+            } else {
+                self.packer.pack_to(ptr, &input, self.k_axis, self.mn_axis);
+            }
+            std::ptr::write_bytes(ptr.add(k * r * item_size), 0, (packed_k - k) * r * item_size);
+        }
+    }
+
+    /// Is k inner to mn in the (contiguous) input ? The k-outer panels are then a transposition
+    /// of the input, tiled to stay in cache instead of gathered with strided writes.
+    pub(crate) fn transposing(&self) -> bool {
+        self.k_axis > self.mn_axis
+    }
+
+    /// Zero the packed values at the masked out k (or mn) indices. Panels are `r` wide along mn
+    /// and store `packed_k` contiguous records of `r` values.
+    unsafe fn zero_masked(
+        &self,
+        packed: *mut u8,
+        item_size: usize,
+        (k, mn): (usize, usize),
+        packed_k: usize,
+        along_k: bool,
+        mask: &[bool],
+    ) {
+        let r = self.packer.r;
+        let panel_len = self.packer.single_panel_len(packed_k);
+        for (ix, _) in mask.iter().enumerate().filter(|(_, m)| !**m) {
+            if along_k {
+                for panel in 0..mn.divceil(r) {
+                    let offset = panel * panel_len + ix * r;
+                    std::ptr::write_bytes(packed.add(offset * item_size), 0, r * item_size);
+                }
+            } else {
+                let panel = packed.add((ix / r * panel_len + ix % r) * item_size);
+                for k in 0..k {
+                    std::ptr::write_bytes(panel.add(k * r * item_size), 0, item_size);
+                }
+            }
+        }
+    }
+
+    /// Recover an operand of `k` by `mn` values from its packed form: the unpacked tensor has
+    /// the packed prefix axes, followed by k and mn.
+    pub fn unpack(&self, packed: &Tensor, k: usize, mn: usize) -> TractResult<Tensor> {
+        let dt = packed.datum_type();
+        ensure!(dt.is_copy(), "Can not unpack {dt:?} values");
+        let packed_len = self.packer.len(k, mn);
+        let Some((&len, prefix)) = packed.shape().split_last() else {
+            bail!("Packed tensors have at least one axis")
+        };
+        ensure!(len == packed_len, "Expected {packed_len} packed values for {k}x{mn}, got {len}");
+        let mut shape: TVec<usize> = prefix.into();
+        shape.push(k);
+        shape.push(mn);
+        let mut unpacked = unsafe { Tensor::uninitialized_dt(dt, &shape)? };
+        let (r, panel_len, item_size) =
+            (self.packer.r, self.packer.single_panel_len(k), dt.size_of());
+        unsafe {
+            let src = packed.as_ptr_unchecked::<u8>();
+            let dst = unpacked.as_ptr_mut_unchecked::<u8>();
+            for p in 0..prefix.iter().product() {
+                let src = src.add(p * packed_len * item_size);
+                let dst = dst.add(p * k * mn * item_size);
+                for (x, y) in tract_itertools::iproduct!(0..mn, 0..k) {
+                    let from = (x / r) * panel_len + y * r + x % r;
+                    std::ptr::copy_nonoverlapping(
+                        src.add(from * item_size),
+                        dst.add((y * mn + x) * item_size),
+                        item_size,
+                    );
+                }
+            }
+        }
+        Ok(unpacked)
+    }
+
+    /// The k of the packed operand, before padding.
+    fn output_k<D: DimLike>(&self, input: &[D]) -> D {
+        match &self.input_slice {
+            Some((axis, range)) if *axis == self.k_axis => range.len().into(),
+            _ => input[self.k_axis].clone(),
+        }
+    }
+
+    pub(crate) fn output_shape<D: DimLike>(&self, input: &[D]) -> TVec<D> {
         let mut packed_shape: TVec<D> = input.into();
+        if let Some((axis, range)) = &self.input_slice {
+            packed_shape[*axis] = range.len().into();
+        }
+        let k = self.k_padded.map(D::from).unwrap_or_else(|| self.output_k(input));
+        let mn = packed_shape[self.mn_axis].clone();
         packed_shape.remove(self.mn_axis.max(self.k_axis));
         packed_shape.remove(self.mn_axis.min(self.k_axis));
-        packed_shape.push(self.packer.len(input[self.k_axis].clone(), input[self.mn_axis].clone()));
+        packed_shape.push(self.packer.len(k, mn));
         packed_shape
     }
 }
+
+/// The fact of `input` packed by `packer` along `k_axis` and `mn_axis`.
+pub fn packed_fact(
+    input: &TypedFact,
+    packer: &Packer,
+    k_axis: usize,
+    mn_axis: usize,
+) -> TractResult<TypedFact> {
+    let op = MatMatMulPack::new(packer.clone(), k_axis, mn_axis, input.rank())?;
+    Ok(op.output_facts(&[input])?.remove(0))
+}
+
+/// Allocator for the buffers receiving packed constant operands.
+pub trait PackedConstantArena: std::fmt::Debug + Send + Sync {
+    /// Allocate an uninitialized tensor. The arena may hand out externally stored tensors
+    /// (see `Tensor::from_external_storage`).
+    fn allocate(&self, dt: DatumType, shape: &[usize], alignment: usize) -> TractResult<Tensor>;
+}
+
+/// Where codegen puts the packed form of constant matrix multiplication operands.
+#[derive(Clone, Debug, Default)]
+pub enum PackedConstantStorage {
+    /// Regular heap allocation.
+    #[default]
+    Heap,
+    /// Packed constants of at least `threshold` bytes are allocated by `arena`.
+    Arena { arena: Arc<dyn PackedConstantArena>, threshold: usize },
+}
+
+impl PackedConstantStorage {
+    /// Spill packed constants of at least `threshold` bytes to memory-mapped temporary files.
+    #[cfg(feature = "mmap")]
+    pub fn mmap(threshold: usize) -> PackedConstantStorage {
+        PackedConstantStorage::Arena { arena: Arc::new(MmapArena::default()), threshold }
+    }
+
+    /// Will a constant with this packed size be allocated outside of the heap ?
+    pub fn spills(&self, bytes: usize) -> bool {
+        match self {
+            PackedConstantStorage::Heap => false,
+            PackedConstantStorage::Arena { threshold, .. } => bytes >= *threshold,
+        }
+    }
+
+    pub fn allocate(
+        &self,
+        dt: DatumType,
+        shape: &[usize],
+        alignment: usize,
+    ) -> TractResult<Tensor> {
+        let bytes = shape.iter().product::<usize>() * dt.size_of();
+        match self {
+            PackedConstantStorage::Arena { arena, .. } if self.spills(bytes) => {
+                arena.allocate(dt, shape, alignment)
+            }
+            _ => unsafe { Tensor::uninitialized_aligned_dt(dt, shape, alignment) },
+        }
+    }
+}
+
+/// Packed constant operands shared by all the models optimized with the same cache (see
+/// `OptimizerOptions::packed_weight_cache`).
+///
+/// Entries are keyed by the packing and a digest of the unpacked constant, and only hold weak
+/// references: a packed tensor lives as long as one of the models using it.
+#[derive(Debug, Default)]
+pub struct PackedWeightCache(Mutex<HashMap<PackedWeightKey, Weak<Tensor>>>);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PackedWeightKey {
+    pack: MatMatMulPack,
+    dt: DatumType,
+    shape: TVec<usize>,
+    digest: u64,
+}
+
+impl PackedWeightCache {
+    /// The packed form of `konst` cast to `dt`, packing it if no live model holds it yet.
+    pub fn get_or_pack(
+        &self,
+        pack: &MatMatMulPack,
+        konst: &Tensor,
+        dt: DatumType,
+        storage: &PackedConstantStorage,
+    ) -> TractResult<Arc<Tensor>> {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        konst.hash(&mut hasher);
+        let key = PackedWeightKey {
+            pack: pack.clone(),
+            dt,
+            shape: konst.shape().into(),
+            digest: hasher.finish(),
+        };
+        if let Some(packed) = self.lock()?.get(&key).and_then(Weak::upgrade) {
+            return Ok(packed);
+        }
+        // pack without holding the lock, another thread may have done the same meanwhile
+        let packed = pack.pack(&*konst.cast_to_dt(dt)?, storage)?.into_arc_tensor();
+        let mut map = self.lock()?;
+        if let Some(packed) = map.get(&key).and_then(Weak::upgrade) {
+            return Ok(packed);
+        }
+        map.retain(|_, packed| packed.strong_count() > 0);
+        map.insert(key, Arc::downgrade(&packed));
+        Ok(packed)
+    }
+
+    /// Number of packed tensors still held by a model.
+    pub fn len(&self) -> usize {
+        self.0.lock().map(|map| map.values().filter(|p| p.strong_count() > 0).count()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(
+        &self,
+    ) -> TractResult<std::sync::MutexGuard<'_, HashMap<PackedWeightKey, Weak<Tensor>>>> {
+        self.0.lock().map_err(|_| format_err!("Poisoned packed weight cache"))
+    }
+}
+
+/// Arena backing each tensor by a memory-mapped file, unlinked right after creation.
+#[cfg(feature = "mmap")]
+#[derive(Clone, Debug, Default)]
+pub struct MmapArena {
+    /// Directory for the backing files. Defaults to the system temporary directory.
+    pub dir: Option<std::path::PathBuf>,
+}
+
+#[cfg(feature = "mmap")]
+impl PackedConstantArena for MmapArena {
+    fn allocate(&self, dt: DatumType, shape: &[usize], alignment: usize) -> TractResult<Tensor> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let bytes = shape.iter().product::<usize>() * dt.size_of();
+        let dir = self.dir.clone().unwrap_or_else(std::env::temp_dir);
+        let path = dir.join(format!(
+            "tract-packed-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("Creating {path:?}"))?;
+        // mapping keeps the file alive, we do not want it to outlive the process
+        let _ = std::fs::remove_file(&path);
+        file.set_len(bytes.max(1) as u64)?;
+        let mut map = unsafe { memmap2::MmapMut::map_mut(&file)? };
+        let data = map.as_mut_ptr();
+        unsafe { Tensor::from_external_storage(dt, shape, alignment, data, Arc::new(map)) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::einsum::EinSum;
+    use crate::ops::matmul::lir_unary::LirMatMulUnary;
+    use crate::optim::OptimizerOptions;
+    use crate::tract_data::itertools::Itertools;
+
+    /// Heap-backed arena, handing out externally stored tensors.
+    #[derive(Debug)]
+    struct VecArena;
+
+    impl PackedConstantArena for VecArena {
+        fn allocate(
+            &self,
+            dt: DatumType,
+            shape: &[usize],
+            alignment: usize,
+        ) -> TractResult<Tensor> {
+            let bytes = shape.iter().product::<usize>() * dt.size_of();
+            let mut buffer = vec![0u8; bytes + alignment];
+            let offset = buffer.as_ptr().align_offset(alignment);
+            let data = unsafe { buffer.as_mut_ptr().add(offset) };
+            unsafe { Tensor::from_external_storage(dt, shape, alignment, data, Arc::new(buffer)) }
+        }
+    }
+
+    fn const_a_model(a: Arc<Tensor>) -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let a = model.add_const("a", a)?;
+        let b = model.add_source("b", f32::fact([32, 16]))?;
+        let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", einsum, &[a, b])?;
+        model.set_output_outlets(&c)?;
+        Ok(model)
+    }
+
+    fn run(model: TypedModel, b: &Tensor) -> TractResult<Tensor> {
+        let mut outputs = model.into_runnable()?.run(tvec!(b.clone().into_tvalue()))?;
+        Ok(outputs.remove(0).into_tensor())
+    }
+
+    fn check_spilled(storage: PackedConstantStorage) -> TractResult<()> {
+        let a = Tensor::from_shape(&[64, 32], &(0..64 * 32).map(|i| i as f32).collect_vec())?;
+        let b = Tensor::from_shape(&[32, 16], &(0..32 * 16).map(|i| i as f32 / 7.).collect_vec())?;
+        let model = const_a_model(a.into_arc_tensor())?;
+        let reference = run(model.clone(), &b)?;
+        let options = OptimizerOptions { packed_constants: storage, ..OptimizerOptions::default() };
+        let optimized = model.into_optimized_with_options(&options)?;
+        let packed = optimized
+            .nodes()
+            .iter()
+            .find(|n| n.name == "einsum.pack_a")
+            .and_then(|n| n.op_as::<crate::ops::konst::Const>())
+            .context("Expected a packed constant")?;
+        assert!(packed.0.is_externally_stored());
+        run(optimized, &b)?.close_enough(&reference, true)
+    }
+
+    #[test]
+    fn packed_constant_in_arena() -> TractResult<()> {
+        check_spilled(PackedConstantStorage::Arena { arena: Arc::new(VecArena), threshold: 1024 })
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn packed_constant_in_mmap() -> TractResult<()> {
+        check_spilled(PackedConstantStorage::mmap(1024))
+    }
+
+    #[test]
+    fn small_packed_constant_stays_on_heap() -> TractResult<()> {
+        let storage =
+            PackedConstantStorage::Arena { arena: Arc::new(VecArena), threshold: 1 << 20 };
+        assert!(!storage.allocate(f32::datum_type(), &[64, 32], 16)?.is_externally_stored());
+        Ok(())
+    }
+
+    #[test]
+    fn packed_weights_shared_across_models() -> TractResult<()> {
+        let a =
+            Tensor::from_shape(&[256, 32], &(0..256 * 32).map(|i| (i % 17) as f32).collect_vec())?;
+        let b = Tensor::from_shape(&[32, 16], &(0..32 * 16).map(|i| i as f32 / 7.).collect_vec())?;
+        let cache = Arc::new(PackedWeightCache::default());
+        let options = OptimizerOptions {
+            packed_weight_cache: Some(cache.clone()),
+            ..OptimizerOptions::default()
+        };
+        let packed = |model: &TypedModel| -> TractResult<Arc<Tensor>> {
+            let node = model.node_by_name("einsum.pack_a")?;
+            Ok(node.op_as::<crate::ops::konst::Const>().context("Expected a const")?.0.clone())
+        };
+        // each model gets its own copy of the unpacked weights
+        let model = const_a_model(a.clone().into_arc_tensor())?;
+        let reference = run(model.clone(), &b)?;
+        let first = model.clone().into_optimized_with_options(&options)?;
+        let second =
+            const_a_model(a.clone().into_arc_tensor())?.into_optimized_with_options(&options)?;
+        assert!(Arc::ptr_eq(&packed(&first)?, &packed(&second)?));
+        assert_eq!(cache.len(), 1);
+
+        // dropping either model keeps the packed weights alive for the other
+        drop(first);
+        let third = model.into_optimized_with_options(&options)?;
+        assert!(Arc::ptr_eq(&packed(&second)?, &packed(&third)?));
+        run(second, &b)?.close_enough(&reference, true)?;
+        assert_eq!(cache.len(), 1);
+        run(third, &b)?.close_enough(&reference, true)?;
+        assert!(cache.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn parameter_input_packed_once_per_generation() -> TractResult<()> {
+        let mut model = TypedModel::default();
+        let x = model.add_source("x", f32::fact([64, 32]))?;
+        let w = model.add_source("w", f32::fact([32, 16]))?;
+        let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", einsum, &[x, w])?;
+        model.set_output_outlets(&c)?;
+        model.set_input_role(1, TensorRole::Parameter)?;
+        let mut optimized = model.clone().into_optimized()?;
+        // m > n, but the parameter still goes to A
+        let pack_a = optimized.node_by_name("einsum.pack_a")?;
+        assert!(pack_a.op_as::<MatMatMulPack>().unwrap().parameter);
+        // expose the packed parameter, to see when it is packed again
+        let packed_w = OutletId::new(pack_a.id, 0);
+        let outputs = [optimized.output_outlets()?[0], packed_w];
+        optimized.set_output_outlets(&outputs)?;
+
+        let x = Tensor::from_shape(&[64, 32], &(0..64 * 32).map(|i| i as f32).collect_vec())?;
+        let w = |seed: usize| {
+            let w = (0..32 * 16).map(|i| ((i + seed) % 7) as f32).collect_vec();
+            Tensor::from_shape(&[32, 16], &w)
+        };
+        let reference = |w: &Tensor| -> TractResult<Tensor> {
+            let inputs = tvec!(x.clone().into_tvalue(), w.clone().into_tvalue());
+            Ok(model.clone().into_runnable()?.run(inputs)?.remove(0).into_tensor())
+        };
+        let mut state = SimpleState::new(optimized.into_runnable()?)?;
+        let first = state.run(tvec!(x.clone().into_tvalue(), w(0)?.into_tvalue()))?;
+        let packed_at = |found: &TVec<TValue>| unsafe { found[1].as_bytes().as_ptr() };
+        for _ in 0..100 {
+            let found = state.run(tvec!(x.clone().into_tvalue(), w(0)?.into_tvalue()))?;
+            found[0].close_enough(&reference(&w(0)?)?, true)?;
+            // w is only packed once
+            assert_eq!(packed_at(&found), packed_at(&first));
+        }
+
+        state.bump_parameters_generation();
+        let found = state.run(tvec!(x.clone().into_tvalue(), w(1)?.into_tvalue()))?;
+        found[0].close_enough(&reference(&w(1)?)?, true)?;
+        assert_ne!(packed_at(&found), packed_at(&first));
+        Ok(())
+    }
+
+    /// A broadcast along the batch axis is packed once, whatever the batch size and wherever
+    /// the broadcast axis sits in the operand.
+    fn check_broadcast_operand_packed_once(expr: &str, a_shape: [usize; 3]) -> TractResult<()> {
+        let (k, n) = (64, 8);
+        let a_len = a_shape.iter().product::<usize>();
+        let a = Tensor::from_shape(&a_shape, &(0..a_len).map(|i| (i % 13) as f32).collect_vec())?;
+        for batch in [1, 16] {
+            let mut model = TypedModel::default();
+            let x = model.add_source("a", f32::fact(a_shape))?;
+            let w = model.add_source("b", f32::fact([batch, k, n]))?;
+            let einsum = EinSum::new(expr.parse()?, f32::datum_type());
+            let c = model.wire_node("einsum", einsum, &[x, w])?;
+            model.set_output_outlets(&c)?;
+            let optimized = model.clone().into_optimized()?;
+            let pack = optimized
+                .nodes()
+                .iter()
+                .find(|n| n.op_is::<MatMatMulPack>() && n.inputs[0].node == 0)
+                .context("No pack for a")?;
+            let op = pack.op_as::<MatMatMulPack>().unwrap();
+            let volume = pack.outputs[0].fact.shape.volume();
+            assert_eq!(volume, op.packer.len(k, 64).to_dim());
+            // one pack per operand, whatever the batch size
+            assert_eq!(optimized.nodes().iter().filter(|n| n.op_is::<MatMatMulPack>()).count(), 2);
+
+            let b = (0..batch * k * n).map(|i| (i % 7) as f32 / 3.).collect_vec();
+            let b = Tensor::from_shape(&[batch, k, n], &b)?;
+            let inputs = tvec!(a.clone().into_tvalue(), b.into_tvalue());
+            let reference = model.into_runnable()?.run(inputs.clone())?;
+            let plan = optimized.into_runnable()?;
+            let found = plan.run(inputs)?;
+            found[0].close_enough(&reference[0], true)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn broadcast_leading_batch_packed_once() -> TractResult<()> {
+        check_broadcast_operand_packed_once("bmk,bkn->bmn", [1, 64, 64])
+    }
+
+    #[test]
+    fn broadcast_inner_batch_packed_once() -> TractResult<()> {
+        check_broadcast_operand_packed_once("mbk,bkn->bmn", [64, 1, 64])
+    }
+
+    #[test]
+    fn broadcast_batch_between_k_and_m_packed_once() -> TractResult<()> {
+        check_broadcast_operand_packed_once("kbm,bkn->bmn", [64, 1, 64])
+    }
+
+    #[test]
+    fn unpacked_constant_released_by_codegen_patch() -> TractResult<()> {
+        let a = Tensor::zero::<f32>(&[64, 32])?.into_arc_tensor();
+        let mut model = const_a_model(a.clone())?;
+        let einsum = model.node_by_name("einsum")?.id;
+        let patch = model.node(einsum).op.codegen(&model, model.node(einsum))?.unwrap();
+        patch.apply(&mut model)?;
+        // the patch holds the packed constant, and nothing holds the unpacked one anymore
+        assert!(model.node_by_name("einsum.pack_a")?.op_is::<crate::ops::konst::Const>());
+        assert_eq!(Arc::strong_count(&a), 1);
+        Ok(())
+    }
+
+    /// Resident set size of the process, and its peak since the last reset, in bytes.
+    #[cfg(target_os = "linux")]
+    fn resident_set_size() -> TractResult<(usize, usize)> {
+        let status = std::fs::read_to_string("/proc/self/status")?;
+        let field = |key: &str| -> TractResult<usize> {
+            let line = status.lines().find_map(|l| l.strip_prefix(key)).context("No such field")?;
+            Ok(line.trim().trim_end_matches("kB").trim().parse::<usize>()? * 1024)
+        };
+        Ok((field("VmRSS:")?, field("VmHWM:")?))
+    }
+
+    // The peak is process wide, run alone:
+    // cargo test -p tract-core --lib large_constant_packed_near_one_copy -- --ignored
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore]
+    fn large_constant_packed_near_one_copy() -> TractResult<()> {
+        let (m, k) = (8192, 8192);
+        let copy = m * k * 4;
+        let mut a = Tensor::zero::<f32>(&[m, k])?;
+        a.as_slice_mut::<f32>()?.iter_mut().enumerate().for_each(|(i, x)| *x = (i % 7) as f32);
+        let mut model = TypedModel::default();
+        let a = model.add_const("a", a)?;
+        let b = model.add_source("b", f32::fact([k, 16]))?;
+        let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", einsum, &[a, b])?;
+        model.set_output_outlets(&c)?;
+
+        // reset the peak to the current size, holding the unpacked constant
+        std::fs::write("/proc/self/clear_refs", "5")?;
+        let (before, _) = resident_set_size()?;
+        let optimized = model.into_optimized()?;
+        let (after, peak) = resident_set_size()?;
+        assert!(optimized.node_by_name("einsum.pack_a")?.op_is::<crate::ops::konst::Const>());
+        let mb = |bytes: usize| bytes >> 20;
+        // the packed copy is made while the unpacked one is alive, and nothing else
+        assert!(
+            peak < before + copy + copy / 8,
+            "peak {} MB, before {} MB, copy {} MB",
+            mb(peak),
+            mb(before),
+            mb(copy)
+        );
+        // the unpacked constant is gone once packed
+        assert!(after < before + copy / 8, "after {} MB, before {} MB", mb(after), mb(before));
+        Ok(())
+    }
+
+    /// The operand is a slice of a larger tensor along `axis`: the pack reads it in place, and
+    /// the product matches the one computed on a sliced copy.
+    fn check_slice_packed_in_place(axis: usize, range: Range<usize>) -> TractResult<()> {
+        let x_shape = [4, 12, 16];
+        let x_len = x_shape.iter().product::<usize>();
+        let x = Tensor::from_shape(&x_shape, &(0..x_len).map(|i| (i % 13) as f32).collect_vec())?;
+        let k = if axis == 2 { range.len() } else { 16 };
+        let w = (0..k * 5).map(|i| (i % 7) as f32 / 3.).collect_vec();
+        let w = Tensor::from_shape(&[k, 5], &w)?;
+
+        let mut model = TypedModel::default();
+        let source = model.add_source("x", f32::fact(x_shape))?;
+        let weights = model.add_source("w", f32::fact([k, 5]))?;
+        let slice = Slice::new(axis, range.start, range.end);
+        let sliced = model.wire_node("slice", slice, &[source])?;
+        let einsum = EinSum::new("bmk,kn->bmn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", einsum, &[sliced[0], weights])?;
+        model.set_output_outlets(&c)?;
+
+        let optimized = model.clone().into_optimized()?;
+        assert!(!optimized.nodes().iter().any(|n| n.op_is::<Slice>()));
+        let pack = optimized
+            .nodes()
+            .iter()
+            .filter_map(|n| n.op_as::<MatMatMulPack>())
+            .find(|p| p.input_slice.is_some())
+            .context("Slice not packed in place")?;
+        assert_eq!(pack.input_slice, Some((axis, range)));
+
+        let inputs = tvec!(x.into_tvalue(), w.into_tvalue());
+        let reference = model.into_runnable()?.run(inputs.clone())?;
+        let found = optimized.into_runnable()?.run(inputs)?;
+        found[0].close_enough(&reference[0], true)
+    }
+
+    #[test]
+    fn slice_along_batch_packed_in_place() -> TractResult<()> {
+        check_slice_packed_in_place(0, 1..3)
+    }
+
+    #[test]
+    fn slice_along_m_packed_in_place() -> TractResult<()> {
+        check_slice_packed_in_place(1, 4..11)
+    }
+
+    #[test]
+    fn slice_along_k_packed_in_place() -> TractResult<()> {
+        check_slice_packed_in_place(2, 3..13)
+    }
+
+    /// Pack a with k inner (transposed panels) or outer to m, and check the product.
+    fn check_pack_layout(expr: &str, a_shape: [usize; 2], routine: &str) -> TractResult<()> {
+        let (k, n) = (150, 9);
+        let a_len = a_shape.iter().product::<usize>();
+        let a = Tensor::from_shape(&a_shape, &(0..a_len).map(|i| (i % 13) as f32).collect_vec())?;
+        let b =
+            Tensor::from_shape(&[k, n], &(0..k * n).map(|i| (i % 7) as f32 / 3.).collect_vec())?;
+        let mut model = TypedModel::default();
+        let x = model.add_source("a", f32::fact(a_shape))?;
+        let w = model.add_source("b", f32::fact([k, n]))?;
+        let c =
+            model.wire_node("einsum", EinSum::new(expr.parse()?, f32::datum_type()), &[x, w])?;
+        model.set_output_outlets(&c)?;
+        let optimized = model.clone().into_optimized()?;
+        let pack = optimized
+            .nodes()
+            .iter()
+            .find(|n| n.op_is::<MatMatMulPack>() && n.inputs[0].node == 0)
+            .context("No pack for a")?;
+        assert!(pack.op.info()?[0].ends_with(routine), "{:?}", pack.op.info()?);
+        let inputs = tvec!(a.into_tvalue(), b.into_tvalue());
+        let reference = model.into_runnable()?.run(inputs.clone())?;
+        let found = optimized.into_runnable()?.run(inputs)?;
+        found[0].close_enough(&reference[0], true)
+    }
+
+    #[test]
+    fn k_inner_operand_transpose_packed() -> TractResult<()> {
+        check_pack_layout("mk,kn->mn", [67, 150], "(blocked transpose)")
+    }
+
+    #[test]
+    fn k_outer_operand_copy_packed() -> TractResult<()> {
+        check_pack_layout("km,kn->mn", [150, 67], "(panel copy)")
+    }
+
+    #[test]
+    fn padded_pack_zeroes_records_past_k() -> TractResult<()> {
+        let (k, mn) = (37, 21);
+        for (k_axis, mn_axis) in [(0, 1), (1, 0)] {
+            let mut shape = [0; 2];
+            shape[k_axis] = k;
+            shape[mn_axis] = mn;
+            let data = (0..k * mn).map(|i| i as f32 + 1.).collect_vec();
+            let input = Tensor::from_shape(&shape, &data)?;
+            let pack = MatMatMulPack {
+                packer: Packer::new(8, 32, 0),
+                k_axis,
+                mn_axis,
+                parameter: false,
+                mask_axis: None,
+                bounded_output: None,
+                input_slice: None,
+                k_padded: Some(40),
+            };
+            let packed = pack.pack(&input, &PackedConstantStorage::Heap)?;
+            assert_eq!(packed.shape(), &[pack.packer.len(40, mn)]);
+            let unpacked = pack.unpack(&packed, 40, mn)?;
+            let input = if k_axis == 0 { input } else { input.permute_axes(&[1, 0])? };
+            assert_eq!(unpacked.slice(0, 0, k)?, input);
+            assert!(unpacked.slice(0, k, 40)?.as_slice::<f32>()?.iter().all(|x| *x == 0.));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pack_with_shared_axis_rejected() {
+        let err = MatMatMulPack::new(Packer::new(8, 32, 0), 1, 1, 2).unwrap_err();
+        assert!(err.to_string().contains("both k and mn axis"), "{err}");
+    }
+
+    #[test]
+    fn pack_with_axis_out_of_range_rejected() {
+        for (k_axis, mn_axis) in [(2, 0), (0, 2)] {
+            let err = MatMatMulPack::new(Packer::new(8, 32, 0), k_axis, mn_axis, 2).unwrap_err();
+            assert!(err.to_string().contains("rank 2 input"), "{err}");
+        }
+    }
+
+    #[test]
+    fn pack_with_mask_axis_not_packed_rejected() -> TractResult<()> {
+        let pack = MatMatMulPack::new(Packer::new(8, 32, 0), 1, 2, 3)?;
+        let pack = MatMatMulPack { mask_axis: Some(0), ..pack };
+        assert!(pack.check_axes(3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn misconfigured_pack_rejected_at_wiring() -> TractResult<()> {
+        let valid = MatMatMulPack::new(Packer::new(8, 32, 0), 1, 2, 3)?;
+        let invalid = [
+            MatMatMulPack { mn_axis: 1, ..valid.clone() },
+            MatMatMulPack { k_axis: 3, ..valid.clone() },
+            MatMatMulPack { input_slice: Some((3, 0..2)), ..valid },
+        ];
+        for op in invalid {
+            let mut model = TypedModel::default();
+            let b = model.add_source("b", f32::fact([2, 19, 13]))?;
+            let err = model.wire_node("pack_b", op.clone(), &[b]).unwrap_err();
+            assert!(format!("{err:?}").contains("pack_b"), "{op:?}: {err:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn externally_wired_pack_round_trip() -> TractResult<()> {
+        let (k, mn) = (19, 13);
+        let packer = Packer::new(8, 32, 0);
+        let fact = f32::fact([3, k, mn]);
+        let mut model = TypedModel::default();
+        let b = model.add_source("b", fact.clone())?;
+        let op = MatMatMulPack::new(packer.clone(), 1, 2, fact.rank())?;
+        let packed = model.wire_node("pack_b", op.clone(), &[b])?;
+        model.set_output_outlets(&packed)?;
+        assert_eq!(model.outlet_fact(packed[0])?, &packed_fact(&fact, &packer, 1, 2)?);
+        let input =
+            Tensor::from_shape(&[3, k, mn], &(0..3 * k * mn).map(|i| i as f32).collect_vec())?;
+        let found = run(model, &input)?;
+        assert_eq!(found.shape(), &[3, packer.len(k, mn)]);
+        assert_eq!(op.unpack(&found, k, mn)?, input);
+        Ok(())
+    }
+
+    /// A 64x64 product over k (a symbol K, with an optional bound, if None), lowered with the k
+    /// padding option, against the reference, with the k the lowered product runs.
+    fn check_k_padding(
+        k: Option<usize>,
+        const_b: bool,
+        bound: Option<i64>,
+        runs: &[usize],
+    ) -> TractResult<TDim> {
+        let mut model = TypedModel::default();
+        let symbol = model.symbol_table.sym("K");
+        let k = k.map(|k| k.to_dim()).unwrap_or_else(|| symbol.to_dim());
+        let bounds = bound.map(|b| SymbolValues::default().with(&symbol, b)).unwrap_or_default();
+        let a = model.add_source("a", f32::fact(dims!(64, k.clone())))?;
+        let b = if const_b {
+            let k = k.to_usize()?;
+            let b = (0..k * 64).map(|i| (i % 7) as f32 / 3.).collect_vec();
+            model.add_const("b", Tensor::from_shape(&[k, 64], &b)?)?
+        } else {
+            model.add_source("b", f32::fact(dims!(k.clone(), 64)))?
+        };
+        let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", op, &[a, b])?;
+        model.set_output_outlets(&c)?;
+        let options = OptimizerOptions {
+            k_padding: Some(KPadding { max_k: 64, multiple: 8 }),
+            symbol_bounds: bounds,
+            ..Default::default()
+        };
+        let optimized = model.clone().into_optimized_with_options(&options)?;
+        let reference = model.into_runnable()?;
+        let plan = optimized.clone().into_runnable()?;
+        for &k in runs {
+            let a = (0..64 * k).map(|i| (i % 11) as f32 - 5.).collect_vec();
+            let mut inputs = tvec!(Tensor::from_shape(&[64, k], &a)?.into_tvalue());
+            if !const_b {
+                let b = (0..k * 64).map(|i| (i % 7) as f32 / 3.).collect_vec();
+                inputs.push(Tensor::from_shape(&[k, 64], &b)?.into_tvalue());
+            }
+            let expected = reference.run(inputs.clone())?;
+            let found = plan.run(inputs)?;
+            found[0].close_enough(&expected[0], Approximation::Approximate)?;
+        }
+        let lir = optimized.nodes().iter().find_map(|n| n.op_as::<LirMatMulUnary>());
+        lir.and_then(|lir| lir.guess_k()).context("No lowered product")
+    }
+
+    #[test]
+    fn small_k_padded_in_packing() -> TractResult<()> {
+        for const_b in [false, true] {
+            assert_eq!(check_k_padding(Some(37), const_b, None, &[37])?, 40.to_dim());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn large_or_aligned_k_not_padded() -> TractResult<()> {
+        for k in [40, 67] {
+            assert_eq!(check_k_padding(Some(k), false, None, &[k])?, k.to_dim());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bounded_k_padded_to_its_bound() -> TractResult<()> {
+        assert_eq!(check_k_padding(None, false, Some(37), &[37, 29, 1])?, 40.to_dim());
+        let unbounded = check_k_padding(None, false, None, &[37])?;
+        assert!(unbounded.to_usize().is_err(), "{unbounded}");
+        Ok(())
+    }
+
+    #[test]
+    fn quantized_product_padded_with_true_k_compensation() -> TractResult<()> {
+        let (m, k, n) = (16, 37, 24);
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", i8::fact([m, k]))?;
+        let b = (0..k * n).map(|i| ((i * 7) % 23) as i8 - 11).collect_vec();
+        let b = model.add_const("b", Tensor::from_shape(&[k, n], &b)?)?;
+        let mut inputs = tvec!(a, b, model.add_const("bias", tensor0(0i32))?);
+        for (name, t) in [
+            ("a0", tensor0(3i8)),
+            ("a_scale", tensor0(1f32)),
+            ("b0", tensor0(-2i8)),
+            ("b_scale", tensor0(1f32)),
+            ("c0", tensor0(0i32)),
+            ("c_scale", tensor0(1f32)),
+        ] {
+            inputs.push(model.add_const(name, t)?);
+        }
+        let expr = "mk,kn,,,,,,,->mn".parse()?;
+        let op = EinSum::newq(expr, i32::datum_type(), i32::datum_type());
+        let c = model.wire_node("einsum", op, &inputs)?;
+        model.set_output_outlets(&c)?;
+        let a = (0..m * k).map(|i| ((i * 5) % 251) as u8 as i8).collect_vec();
+        let a = Tensor::from_shape(&[m, k], &a)?;
+        let expected = {
+            let a = a.cast_to::<i32>()?.into_owned().into_array::<i32>()? - 3;
+            let b = model.outlet_fact(b)?.konst.clone().unwrap();
+            let b = b.cast_to::<i32>()?.into_owned().into_array::<i32>()? + 2;
+            let (a, b) = (a.into_dimensionality::<Ix2>()?, b.into_dimensionality::<Ix2>()?);
+            a.dot(&b).into_tensor()
+        };
+        for k_padding in [None, Some(KPadding { max_k: 64, multiple: 8 })] {
+            let options = OptimizerOptions { k_padding, ..Default::default() };
+            let optimized = model.clone().into_optimized_with_options(&options)?;
+            let lir = optimized.nodes().iter().find_map(|n| n.op_as::<LirMatMulUnary>());
+            let padded = lir.context("No LirMatMulUnary")?.guess_k() == Some(40.to_dim());
+            assert_eq!(padded, k_padding.is_some());
+            let found = optimized.into_runnable()?.run(tvec!(a.clone().into_tvalue()))?;
+            assert_eq!(*found[0], expected, "{k_padding:?}");
+        }
+        Ok(())
+    }
+}
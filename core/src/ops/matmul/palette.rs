@@ -0,0 +1,364 @@
+//! Palettized constant matrices.
+//!
+//! A weight matrix clustered to at most 16 distinct values is stored as a palette of these values
+//! and a 4-bit index into it per item, two per byte: an eighth of its f32 size. A
+//! `PalettizedConst` feeds it to a product: the lowering keeps the indices as the operand, and
+//! the kernel expands each panel of the matrix in its scratch space as it reaches it (see
+//! `ops::matmul::compressed`).
+use super::compressed::CompressedMatrix;
+use crate::internal::*;
+use std::ops::Range;
+use tract_linalg::frame::{Packer, PackingWriter};
+use tract_linalg::mmm::{VirtualInput, VirtualInputSpec};
+
+/// Most values a palette holds: an index is four bits.
+pub const PALETTE_MAX_LEN: usize = 16;
+
+/// A f32 tensor stored as a palette of its values and a 4-bit index into it per item.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palettized {
+    pub shape: TVec<usize>,
+    /// The values, in increasing order.
+    pub palette: Vec<f32>,
+    /// A u8 vector of the item indices in the palette, in row-major order, two per byte, the
+    /// first one in the low nibble.
+    pub indices: Arc<Tensor>,
+}
+
+impl Palettized {
+    /// Compresses `tensor`, replacing each value by the nearest one of the palette the
+    /// `clustering` function picks for them all (see `distinct` and `uniform`).
+    pub fn from_dense(
+        tensor: &Tensor,
+        clustering: impl FnOnce(&[f32]) -> TractResult<Vec<f32>>,
+    ) -> TractResult<Palettized> {
+        let values = tensor.cast_to::<f32>()?;
+        let values = values.as_slice::<f32>()?;
+        let mut palette = clustering(values)?;
+        ensure!(
+            !palette.is_empty() && palette.len() <= PALETTE_MAX_LEN,
+            "A palette holds 1 to {PALETTE_MAX_LEN} values, got {}",
+            palette.len()
+        );
+        ensure!(palette.iter().all(|x| x.is_finite()), "Non finite palette value in {palette:?}");
+        palette.sort_by(|a, b| a.total_cmp(b));
+        let nearest = |x: f32| -> u8 {
+            let ix = palette.partition_point(|p| *p < x);
+            if ix == palette.len() || (ix > 0 && x - palette[ix - 1] <= palette[ix] - x) {
+                (ix.max(1) - 1) as u8
+            } else {
+                ix as u8
+            }
+        };
+        let indices = values
+            .chunks(2)
+            .map(|pair| nearest(pair[0]) | pair.get(1).map(|x| nearest(*x) << 4).unwrap_or(0))
+            .collect::<Vec<u8>>();
+        Ok(Palettized {
+            shape: tensor.shape().into(),
+            palette,
+            indices: tensor1(&indices).into_arc_tensor(),
+        })
+    }
+
+    /// The index in the palette of the `ix`-th item, in row-major order.
+    #[inline]
+    pub fn index(&self, ix: usize) -> usize {
+        let byte = unsafe { *self.indices.as_ptr_unchecked::<u8>().add(ix / 2) };
+        index(byte, ix)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes held by the compressed form.
+    pub fn compressed_bytes(&self) -> usize {
+        self.indices.len() + self.palette.len() * f32::datum_type().size_of()
+    }
+
+    pub fn to_dense(&self) -> TractResult<Tensor> {
+        let values = (0..self.len()).map(|ix| self.palette[self.index(ix)]).collect::<Vec<_>>();
+        Tensor::from_shape(&self.shape, &values)
+    }
+}
+
+impl CompressedMatrix for Palettized {
+    fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    fn compressed_bytes(&self) -> usize {
+        Palettized::compressed_bytes(self)
+    }
+
+    fn to_dense(&self) -> TractResult<Tensor> {
+        Palettized::to_dense(self)
+    }
+
+    fn data(&self) -> Arc<Tensor> {
+        self.indices.clone()
+    }
+
+    fn input_spec(&self, k_axis: usize, mn_axis: usize) -> Option<Box<dyn VirtualInputSpec>> {
+        let &[rows, cols] = &*self.shape else { return None };
+        Some(Box::new(PalettizedInputSpec {
+            palette: self.palette.clone(),
+            shape: [rows, cols],
+            k_axis,
+            mn_axis,
+        }))
+    }
+}
+
+#[inline]
+fn index(byte: u8, ix: usize) -> usize {
+    (if ix % 2 == 0 { byte & 0xF } else { byte >> 4 }) as usize
+}
+
+/// The distinct values of a tensor already clustered, as a palette.
+pub fn distinct(values: &[f32]) -> TractResult<Vec<f32>> {
+    let mut palette: Vec<f32> = vec![];
+    for x in values {
+        if !palette.iter().any(|p| p.to_bits() == x.to_bits()) {
+            ensure!(palette.len() < PALETTE_MAX_LEN, "More than {PALETTE_MAX_LEN} distinct values");
+            palette.push(*x);
+        }
+    }
+    Ok(palette)
+}
+
+/// A palette of `len` values evenly spread from the smallest to the largest of `values`.
+pub fn uniform(len: usize) -> impl FnOnce(&[f32]) -> TractResult<Vec<f32>> {
+    move |values| {
+        let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        ensure!(min.is_finite() && max.is_finite(), "No finite range to spread a palette over");
+        if len < 2 || min == max {
+            return Ok(vec![min]);
+        }
+        Ok((0..len).map(|i| min + (max - min) * i as f32 / (len - 1) as f32).collect())
+    }
+}
+
+/// A constant palettized f32 tensor. It evaluates to the dense tensor, but products lowered
+/// from an einsum read it compressed.
+#[derive(Clone, Debug)]
+pub struct PalettizedConst(pub Arc<Palettized>);
+
+impl Op for PalettizedConst {
+    fn name(&self) -> Cow<str> {
+        "PalettizedConst".into()
+    }
+
+    fn info(&self) -> TractResult<Vec<String>> {
+        let p = &self.0;
+        Ok(vec![format!(
+            "{:?}, {} values, {} bytes",
+            p.shape,
+            p.palette.len(),
+            p.compressed_bytes()
+        )])
+    }
+
+    op_as_typed_op!();
+}
+
+impl EvalOp for PalettizedConst {
+    // not stateless, so the optimizer does not fold it to the dense tensor
+    fn is_stateless(&self) -> bool {
+        false
+    }
+
+    fn eval(&self, _inputs: TVec<TValue>) -> TractResult<TVec<TValue>> {
+        Ok(tvec!(self.0.to_dense()?.into_tvalue()))
+    }
+}
+
+impl TypedOp for PalettizedConst {
+    as_op!();
+
+    fn output_facts(&self, _inputs: &[&TypedFact]) -> TractResult<TVec<TypedFact>> {
+        Ok(tvec!(f32::fact(&*self.0.shape)))
+    }
+
+    fn cost(&self, _inputs: &[&TypedFact]) -> TractResult<TVec<(Cost, TDim)>> {
+        Ok(tvec!((Cost::Params(f32::datum_type()), self.0.len().into())))
+    }
+}
+
+/// Expands the panels of a palettized rank 2 matrix, from its indices.
+#[derive(Clone, Debug)]
+pub struct PalettizedInputSpec {
+    pub palette: Vec<f32>,
+    pub shape: [usize; 2],
+    pub k_axis: usize,
+    pub mn_axis: usize,
+}
+
+impl VirtualInputSpec for PalettizedInputSpec {
+    fn wrap(&self, view: &TensorView) -> Box<dyn VirtualInput> {
+        let strides = [self.shape[1] as isize, 1];
+        let mut palette = [0f32; PALETTE_MAX_LEN];
+        palette[..self.palette.len()].copy_from_slice(&self.palette);
+        Box::new(PalettizedInput {
+            indices: unsafe { view.as_ptr_unchecked() },
+            palette,
+            mn: self.shape[self.mn_axis],
+            k_stride: strides[self.k_axis],
+            mn_stride: strides[self.mn_axis],
+        })
+    }
+
+    fn packed_datum_type(&self, _input: DatumType) -> DatumType {
+        f32::datum_type()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct PalettizedInput {
+    indices: *const u8,
+    palette: [f32; PALETTE_MAX_LEN],
+    mn: usize,
+    k_stride: isize,
+    mn_stride: isize,
+}
+
+unsafe impl Send for PalettizedInput {}
+unsafe impl Sync for PalettizedInput {}
+
+impl VirtualInput for PalettizedInput {
+    fn input(
+        &self,
+        packer: &Packer,
+        packed: *mut u8,
+        k_range: Range<usize>,
+        mn_range: Range<usize>,
+    ) {
+        let mut writer = packer.write_single_panel_with_k_outer(packed as *mut f32);
+        let valid = mn_range.start..mn_range.end.min(self.mn);
+        unsafe {
+            for k in k_range {
+                let row = k as isize * self.k_stride;
+                for x in valid.clone() {
+                    let ix = (row + x as isize * self.mn_stride) as usize;
+                    let byte = *self.indices.add(ix / 2);
+                    writer.write(self.palette[index(byte, ix)]);
+                }
+                for _ in valid.end..mn_range.end {
+                    writer.write(0f32);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::einsum::EinSum;
+    use crate::ops::konst::Const;
+    use crate::ops::matmul::lir_unary::{LirMatMulUnary, ProtoFusedSpec};
+
+    fn weight(shape: &[usize]) -> TractResult<Tensor> {
+        let palette = (0..16).map(|i| i as f32 / 4. - 2.).collect::<Vec<_>>();
+        let len = shape.iter().product::<usize>();
+        let values = (0..len).map(|i| palette[(i * 7 + i / 13) % 16]).collect::<Vec<_>>();
+        Tensor::from_shape(shape, &values)
+    }
+
+    fn model(weight: Box<dyn TypedOp>, shape: &[usize], n: usize) -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let w = model.wire_node("w", weight, &[])?[0];
+        let x = model.add_source("x", f32::fact([shape[1], n]))?;
+        let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", einsum, &[w, x])?;
+        model.set_output_outlets(&c)?;
+        Ok(model)
+    }
+
+    fn const_bytes(model: &TypedModel) -> usize {
+        model
+            .nodes()
+            .iter()
+            .filter_map(|n| n.op_as::<Const>())
+            .map(|k| k.0.len() * k.0.datum_type().size_of())
+            .sum()
+    }
+
+    fn expanded_on_demand(model: &TypedModel) -> bool {
+        model.nodes().iter().filter_map(|n| n.op_as::<LirMatMulUnary>()).any(|lir| {
+            lir.micro_ops.iter().any(|op| {
+                matches!(op, ProtoFusedSpec::AddMatMul(geo, _, _)
+                    if geo.a_storage.as_ref().map_or(false, |s| s.to_string() == "VirtualPacking"))
+            })
+        })
+    }
+
+    #[test]
+    fn round_trip() -> TractResult<()> {
+        let dense = weight(&[7, 5])?;
+        let palettized = Palettized::from_dense(&dense, distinct)?;
+        assert_eq!(palettized.indices.len(), 18);
+        assert_eq!(palettized.to_dense()?, dense);
+        Ok(())
+    }
+
+    #[test]
+    fn uniform_clustering_picks_nearest() -> TractResult<()> {
+        let dense = tensor1(&[0f32, 0.2, 0.4, 0.9, 1.]);
+        let palettized = Palettized::from_dense(&dense, uniform(3))?;
+        assert_eq!(palettized.palette, [0., 0.5, 1.]);
+        assert_eq!(palettized.to_dense()?, tensor1(&[0f32, 0., 0.5, 1., 1.]));
+        Ok(())
+    }
+
+    #[test]
+    fn large_weight_stays_compressed() -> TractResult<()> {
+        let shape = [4096, 4096];
+        let dense = weight(&shape)?;
+        let palettized = Arc::new(Palettized::from_dense(&dense, distinct)?);
+        let compressed = palettized.compressed_bytes();
+        assert!(compressed * 7 < dense.len() * 4);
+        let x = Tensor::from_shape(
+            &[4096, 4],
+            &(0..4 * 4096).map(|i| (i % 11) as f32 - 5.).collect::<Vec<_>>(),
+        )?;
+        let reference = model(Box::new(Const::new(dense.into_arc_tensor())), &shape, 4)?
+            .into_optimized()?
+            .into_runnable()?
+            .run(tvec!(x.clone().into_tvalue()))?;
+        let optimized =
+            model(Box::new(PalettizedConst(palettized)), &shape, 4)?.into_optimized()?;
+        assert!(expanded_on_demand(&optimized));
+        assert!(const_bytes(&optimized) <= compressed + 1024, "{}", const_bytes(&optimized));
+        let found = optimized.into_runnable()?.run(tvec!(x.into_tvalue()))?;
+        assert_eq!(found, reference);
+        Ok(())
+    }
+
+    #[test]
+    fn single_panel_is_expanded_once() -> TractResult<()> {
+        let shape = [4, 256];
+        let dense = weight(&shape)?;
+        let palettized = Arc::new(Palettized::from_dense(&dense, distinct)?);
+        let x = Tensor::from_shape(
+            &[256, 3],
+            &(0..3 * 256).map(|i| (i % 11) as f32 - 5.).collect::<Vec<_>>(),
+        )?;
+        let reference = model(Box::new(Const::new(dense.into_arc_tensor())), &shape, 3)?
+            .into_runnable()?
+            .run(tvec!(x.clone().into_tvalue()))?;
+        let optimized =
+            model(Box::new(PalettizedConst(palettized)), &shape, 3)?.into_optimized()?;
+        assert!(!expanded_on_demand(&optimized));
+        assert!(!optimized.nodes().iter().any(|n| n.op_is::<PalettizedConst>()));
+        let found = optimized.into_runnable()?.run(tvec!(x.into_tvalue()))?;
+        found[0].close_enough(&reference[0], Approximation::Close)?;
+        Ok(())
+    }
+}
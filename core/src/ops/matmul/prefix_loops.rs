@@ -0,0 +1,231 @@
+//! Loop order over the prefix axes of the batched matrix products.
+//!
+//! A `LirMatMulUnary` runs its kernel once per coordinates of the output prefix axes (all its
+//! axes but m and n). When both operands walk along prefix axes of their own, like in the cross
+//! products of two independent batches `bik,cjk->bcij`, each packed A matrix meets every packed
+//! B matrix: walked in the output order, all of B streams through the caches once per A
+//! matrix. `PrefixLoops` orders the loops so the matrices of the larger operand are read once,
+//! each of them meeting the matrices of the smaller one in turn, and when the smaller one does
+//! not fit in the per-core cache either, walks the two sets of axes by blocks fitting together.
+use super::lir_unary::MapOutputAxisToInput;
+use crate::internal::*;
+use ndarray::indices;
+
+/// Order of the loops over the prefix axes of the lowered products.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrefixLoopOrder {
+    /// The order of the output axes.
+    OutputOrder,
+    /// Planned for a per-core cache of this many bytes (see `PrefixLoops::plan`).
+    Planned { cache_bytes: usize },
+}
+
+impl Default for PrefixLoopOrder {
+    fn default() -> PrefixLoopOrder {
+        PrefixLoopOrder::Planned { cache_bytes: super::tiling::per_core_cache_bytes() }
+    }
+}
+
+/// Loops over the prefix axes of the output, outermost first. Each loop walks its axis by
+/// blocks of `block` coordinates: the blocks of all the loops are walked first, then the
+/// coordinates in each block, in the same order. A block of 1 walks the axis in the outer loops,
+/// a block of the whole axis in the inner loops.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PrefixLoops {
+    /// (output axis, block) pairs.
+    pub loops: TVec<(usize, usize)>,
+}
+
+impl PrefixLoopOrder {
+    /// Loops over the prefix axes of an output of `c_shape`, `a_bytes` and `b_bytes` being the
+    /// sizes of a packed matrix of each operand, or None if the output order reads each of
+    /// them once anyway.
+    pub fn plan(
+        &self,
+        c_shape: &[usize],
+        (c_m_axis, c_n_axis): (usize, usize),
+        (a_axes, b_axes): (&MapOutputAxisToInput, &MapOutputAxisToInput),
+        (a_bytes, b_bytes): (usize, usize),
+    ) -> Option<PrefixLoops> {
+        let PrefixLoopOrder::Planned { cache_bytes } = *self else { return None };
+        let walks = |map: &MapOutputAxisToInput, axis: usize| map.0.iter().any(|p| p.0 == axis);
+        let (mut shared, mut a_only, mut b_only, mut neither) =
+            (tvec!(), tvec!(), tvec!(), tvec!());
+        for axis in (0..c_shape.len()).filter(|&ax| ax != c_m_axis && ax != c_n_axis) {
+            match (walks(a_axes, axis), walks(b_axes, axis)) {
+                _ if c_shape[axis] == 1 => (),
+                (true, true) => shared.push(axis),
+                (true, false) => a_only.push(axis),
+                (false, true) => b_only.push(axis),
+                (false, false) => neither.push(axis),
+            }
+        }
+        if a_only.is_empty() || b_only.is_empty() {
+            return None;
+        }
+        let volume = |axes: &[usize]| axes.iter().map(|&ax| c_shape[ax]).product::<usize>();
+        let a_total = volume(&a_only).saturating_mul(a_bytes);
+        let b_total = volume(&b_only).saturating_mul(b_bytes);
+        let (outer, outer_bytes, inner, inner_bytes, inner_total) = if a_total >= b_total {
+            (a_only, a_bytes, b_only, b_bytes, b_total)
+        } else {
+            (b_only, b_bytes, a_only, a_bytes, a_total)
+        };
+        let mut loops: TVec<(usize, usize)> = shared.iter().map(|&ax| (ax, 1)).collect();
+        if inner_total <= cache_bytes {
+            // the smaller operand stays in cache while the larger one streams through once
+            loops.extend(outer.iter().map(|&ax| (ax, 1)));
+            loops.extend(inner.iter().map(|&ax| (ax, c_shape[ax])));
+        } else {
+            // blocks of both, half the cache each, along the innermost axis of each set
+            let block = |axes: &[usize], bytes: usize| -> TVec<(usize, usize)> {
+                let last = *axes.last().unwrap();
+                let count = (cache_bytes / 2 / bytes.max(1)).clamp(1, c_shape[last]);
+                axes.iter().map(|&ax| (ax, if ax == last { count } else { 1 })).collect()
+            };
+            loops.extend(block(&outer, outer_bytes));
+            loops.extend(block(&inner, inner_bytes));
+        }
+        // neither operand moves along these: innermost
+        loops.extend(neither.iter().map(|&ax| (ax, c_shape[ax])));
+        Some(PrefixLoops { loops })
+    }
+}
+
+impl PrefixLoops {
+    /// Call `f` with the coordinates of each prefix of an output of `shape`, the m and n axes
+    /// being at 1 as well as the axes without a loop.
+    pub(crate) fn for_each(
+        &self,
+        shape: &[usize],
+        mut f: impl FnMut(&[usize]) -> TractResult<()>,
+    ) -> TractResult<()> {
+        let mut coords: TVec<usize> = tvec!(0; shape.len());
+        let blocks: TVec<usize> =
+            self.loops.iter().map(|&(axis, block)| shape[axis].divceil(block)).collect();
+        for origin in indices(&*blocks) {
+            let extents: TVec<usize> = self
+                .loops
+                .iter()
+                .enumerate()
+                .map(|(ix, &(axis, block))| block.min(shape[axis] - origin[ix] * block))
+                .collect();
+            for offset in indices(&*extents) {
+                for (ix, &(axis, block)) in self.loops.iter().enumerate() {
+                    coords[axis] = origin[ix] * block + offset[ix];
+                }
+                f(&coords)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn rm_c_axis(&mut self, axis: usize) {
+        self.loops.retain(|(ax, _)| *ax != axis);
+        for (ax, _) in &mut self.loops {
+            *ax -= (*ax > axis) as usize;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::einsum::EinSum;
+    use crate::ops::matmul::lir_unary::LirMatMulUnary;
+    use crate::optim::OptimizerOptions;
+    use tract_itertools::Itertools;
+
+    fn map(axes: &[usize]) -> MapOutputAxisToInput {
+        MapOutputAxisToInput(axes.iter().enumerate().map(|(ix, &ax)| (ax, ix)).collect())
+    }
+
+    #[test]
+    fn plans() {
+        let roomy = PrefixLoopOrder::Planned { cache_bytes: 1 << 20 };
+        let tight = PrefixLoopOrder::Planned { cache_bytes: 4096 };
+        let shape = [6, 8, 8, 32];
+        let (a, b) = (map(&[0]), map(&[1]));
+        let plan = |order: PrefixLoopOrder, bytes| order.plan(&shape, (2, 3), (&a, &b), bytes);
+        // the larger operand outer, the smaller one read from the cache
+        assert_eq!(plan(roomy, (1024, 4096)).unwrap().loops[..], [(1, 1), (0, 6)]);
+        assert_eq!(plan(roomy, (4096, 1024)).unwrap().loops[..], [(0, 1), (1, 8)]);
+        // neither fits: blocks of half the cache of each
+        assert_eq!(plan(tight, (1024, 1024)).unwrap().loops[..], [(1, 2), (0, 2)]);
+        assert_eq!(plan(tight, (1024, 2048)).unwrap().loops[..], [(1, 1), (0, 2)]);
+        // shared axes outermost, broadcast ones innermost
+        let shape = [2, 3, 6, 5, 8, 32];
+        let (a, b) = (map(&[0, 1]), map(&[0, 2]));
+        let loops = roomy.plan(&shape, (4, 5), (&a, &b), (64, 64)).unwrap().loops;
+        assert_eq!(loops[..], [(0, 1), (2, 1), (1, 3), (3, 5)]);
+        // nothing to reuse across: a single set of independent axes, or only shared ones
+        assert!(roomy.plan(&shape, (4, 5), (&map(&[0, 1]), &map(&[0])), (64, 64)).is_none());
+        assert!(roomy.plan(&shape, (4, 5), (&map(&[1]), &map(&[1])), (64, 64)).is_none());
+        assert!(PrefixLoopOrder::OutputOrder.plan(&shape, (4, 5), (&a, &b), (64, 64)).is_none());
+    }
+
+    #[test]
+    fn blocked_walk() -> TractResult<()> {
+        let loops = PrefixLoops { loops: tvec!((1, 2), (0, 2)) };
+        let mut walked = vec![];
+        loops.for_each(&[3, 3, 1, 1], |c| {
+            walked.push((c[0], c[1]));
+            Ok(())
+        })?;
+        #[rustfmt::skip]
+        assert_eq!(walked, [
+            (0, 0), (1, 0), (0, 1), (1, 1),
+            (2, 0), (2, 1),
+            (0, 2), (1, 2),
+            (2, 2),
+        ]);
+        Ok(())
+    }
+
+    /// Cross products of two independent batches, b of A by c of B.
+    fn cross(b: usize, c: usize, (m, k, n): (usize, usize, usize)) -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", f32::fact([b, m, k]))?;
+        let b = model.add_source("b", f32::fact([c, n, k]))?;
+        let einsum = EinSum::new("bik,cjk->bcij".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", einsum, &[a, b])?;
+        model.set_output_outlets(&c)?;
+        Ok(model)
+    }
+
+    fn input(shape: &[usize], seed: usize) -> TractResult<TValue> {
+        let len = shape.iter().product::<usize>();
+        let data = (0..len).map(|i| ((i * 7 + seed) % 23) as f32 / 8. - 1.).collect::<Vec<_>>();
+        Ok(Tensor::from_shape(shape, &data)?.into_tvalue())
+    }
+
+    fn optimized(model: &TypedModel, prefix_loops: PrefixLoopOrder) -> TractResult<TypedModel> {
+        let options = OptimizerOptions { prefix_loops, ..OptimizerOptions::default() };
+        model.clone().into_optimized_with_options(&options)
+    }
+
+    fn lir(model: &TypedModel) -> &LirMatMulUnary {
+        model.nodes().iter().find_map(|n| n.op_as::<LirMatMulUnary>()).unwrap()
+    }
+
+    #[test]
+    fn asymmetric_cross_products() -> TractResult<()> {
+        for (b, c, mkn) in [(3, 7, (8, 32, 40)), (7, 2, (40, 32, 8)), (5, 4, (17, 9, 23))] {
+            let model = cross(b, c, mkn)?;
+            let inputs = tvec!(input(&[b, mkn.0, mkn.1], 0)?, input(&[c, mkn.2, mkn.1], 3)?);
+            let reference = model.clone().into_runnable()?.run(inputs.clone())?.remove(0);
+            let unplanned = optimized(&model, PrefixLoopOrder::OutputOrder)?;
+            assert!(lir(&unplanned).prefix_loops.is_none());
+            let expected = unplanned.into_runnable()?.run(inputs.clone())?.remove(0);
+            expected.close_enough(&reference, Approximation::Approximate)?;
+            for cache_bytes in [1, 4096, 16384, 1 << 20] {
+                let planned = optimized(&model, PrefixLoopOrder::Planned { cache_bytes })?;
+                let loops = lir(&planned).prefix_loops.clone().unwrap().loops;
+                assert_eq!(loops.iter().map(|l| l.0).sorted().collect::<Vec<_>>(), [0, 1]);
+                let found = planned.into_runnable()?.run(inputs.clone())?.remove(0);
+                assert_eq!(found, expected, "b={b} c={c} {mkn:?} cache:{cache_bytes} {loops:?}");
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+//! Roofline estimates of the lowered matrix multiplications.
+//!
+//! A product moving `bytes` to and from memory for `flops` operations can not run faster than
+//! `min(peak_gflops, bytes_per_sec * flops / bytes)`: below the ridge point of the machine
+//! (its peak operations per byte), it is bound by memory bandwidth, above it by arithmetic.
+//! Comparing the measured throughput of a node to this bound tells how much there is to gain
+//! optimizing it.
+use super::lir_unary::{LirMatMulUnary, ProtoFusedSpec};
+use crate::internal::*;
+use crate::ops::einsum::EinSum;
+use std::time::{Duration, Instant};
+use tract_itertools::Itertools;
+
+lazy_static::lazy_static! {
+    static ref MEASURED_PEAKS: MachinePeaks = MachinePeaks::measure();
+}
+
+/// Operations and memory traffic of the products of a `LirMatMulUnary`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MatMulTraffic {
+    /// Operation count, a multiply-add counting as two.
+    pub flops: u64,
+    /// Bytes of the A operands, as the kernel reads them (packed, if they are).
+    pub a_bytes: u64,
+    /// Bytes of the B operands, as the kernel reads them (packed, if they are).
+    pub b_bytes: u64,
+    /// Bytes of the output.
+    pub c_bytes: u64,
+}
+
+impl MatMulTraffic {
+    pub fn bytes(&self) -> u64 {
+        self.a_bytes + self.b_bytes + self.c_bytes
+    }
+
+    /// Operations per byte moved.
+    pub fn arithmetic_intensity(&self) -> f64 {
+        self.flops as f64 / self.bytes().max(1) as f64
+    }
+}
+
+impl LirMatMulUnary {
+    /// Operations and memory traffic of the node products, for these input facts.
+    ///
+    /// Each operand counts once, at the size of the input the kernel reads: an operand broadcast
+    /// along a prefix axis is read again for each of its coordinates, but from the caches.
+    pub fn traffic(
+        &self,
+        inputs: &[&TypedFact],
+        symbols: &SymbolValues,
+    ) -> TractResult<MatMulTraffic> {
+        let bytes = |fact: &TypedFact| -> TractResult<u64> {
+            let volume = fact.shape.volume().eval(symbols).to_usize()?;
+            Ok((volume * fact.datum_type.size_of()) as u64)
+        };
+        let (mut a_slots, mut b_slots) = (tvec!(), tvec!());
+        for op in &self.micro_ops {
+            if let ProtoFusedSpec::AddMatMul(_, a, b) = op {
+                a_slots.push(*a);
+                b_slots.push(*b);
+            }
+        }
+        let mut traffic = MatMulTraffic { c_bytes: bytes(&self.c_fact)?, ..Default::default() };
+        for slot in a_slots.iter().sorted().dedup() {
+            traffic.a_bytes += bytes(inputs[*slot])?;
+        }
+        for slot in b_slots.iter().sorted().dedup() {
+            traffic.b_bytes += bytes(inputs[*slot])?;
+        }
+        for (cost, count) in self.cost(inputs)? {
+            if matches!(cost, Cost::FMA(_)) {
+                traffic.flops += 2 * count.eval(symbols).to_usize()? as u64;
+            }
+        }
+        Ok(traffic)
+    }
+}
+
+/// Peak memory bandwidth and arithmetic throughput of a machine, on one thread.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MachinePeaks {
+    /// Bytes per second, reading and writing memory.
+    pub bandwidth: f64,
+    /// Operations per second, a multiply-add counting as two.
+    pub flops: f64,
+}
+
+impl MachinePeaks {
+    /// Peaks of the host, measured the first time they are asked for.
+    pub fn measured() -> MachinePeaks {
+        *MEASURED_PEAKS
+    }
+
+    fn measure() -> MachinePeaks {
+        MachinePeaks { bandwidth: measure_bandwidth(), flops: measure_flops().unwrap_or(f64::NAN) }
+    }
+
+    /// Operations per byte at which the products stop being bound by memory.
+    pub fn ridge_point(&self) -> f64 {
+        self.flops / self.bandwidth
+    }
+
+    /// Best throughput reachable at this arithmetic intensity, in operations per second.
+    pub fn attainable_flops(&self, intensity: f64) -> f64 {
+        self.flops.min(self.bandwidth * intensity)
+    }
+}
+
+/// Best of a few copies of a buffer larger than the caches, STREAM style.
+fn measure_bandwidth() -> f64 {
+    let len = 8 << 20;
+    let mut from = vec![1f32; len];
+    let mut to = vec![0f32; len];
+    let mut best = Duration::MAX;
+    for run in 0..5 {
+        // each copy differs from the previous one, so none of them can be skipped
+        from[0] = run as f32;
+        let start = Instant::now();
+        to.copy_from_slice(&from);
+        best = best.min(start.elapsed());
+        assert_eq!(to[0], run as f32);
+    }
+    (2 * len * std::mem::size_of::<f32>()) as f64 / best.as_secs_f64()
+}
+
+/// Best of a few runs of a product of f32 matrices fitting in the caches.
+fn measure_flops() -> TractResult<f64> {
+    let size = 256;
+    let mut model = TypedModel::default();
+    let a = model.add_source("a", f32::fact([size, size]))?;
+    let b = model.add_const("b", Tensor::zero::<f32>(&[size, size])?)?;
+    let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+    let c = model.wire_node("c", op, &[a, b])?;
+    model.set_output_outlets(&c)?;
+    let plan = model.into_optimized()?.into_runnable()?;
+    let mut state = SimpleState::new(&plan)?;
+    let input = tvec!(Tensor::zero::<f32>(&[size, size])?.into_tvalue());
+    let mut best = Duration::MAX;
+    for _ in 0..10 {
+        let start = Instant::now();
+        state.run(input.clone())?;
+        best = best.min(start.elapsed());
+    }
+    Ok((2 * size * size * size) as f64 / best.as_secs_f64())
+}
+
+/// What bounds the throughput of a product.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bound {
+    Memory,
+    Compute,
+}
+
+/// Roofline estimate of a `LirMatMulUnary` node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RooflineEstimate {
+    pub node: usize,
+    pub traffic: MatMulTraffic,
+    pub bound: Bound,
+    /// Best reachable throughput, in operations per second.
+    pub attainable_flops: f64,
+    /// Measured throughput, in operations per second, when the node was profiled.
+    pub measured_flops: Option<f64>,
+}
+
+impl RooflineEstimate {
+    /// Measured throughput over the attainable one.
+    pub fn efficiency(&self) -> Option<f64> {
+        self.measured_flops.map(|m| m / self.attainable_flops)
+    }
+
+    /// The node was profiled, and ran at less than `fraction` of its attainable throughput.
+    pub fn is_far_below(&self, fraction: f64) -> bool {
+        self.efficiency().map(|e| e < fraction).unwrap_or(false)
+    }
+}
+
+/// Roofline estimates of the `LirMatMulUnary` nodes of `model`, with their measured throughput
+/// if `profile` has their run time. Nodes whose dimensions `symbols` does not resolve are
+/// skipped.
+pub fn roofline(
+    model: &TypedModel,
+    peaks: &MachinePeaks,
+    symbols: &SymbolValues,
+    profile: &HashMap<usize, Duration>,
+) -> TractResult<Vec<RooflineEstimate>> {
+    let mut estimates = vec![];
+    for node in model.eval_order()?.into_iter().map(|id| model.node(id)) {
+        let Some(op) = node.op_as::<LirMatMulUnary>() else { continue };
+        let inputs = model.node_input_facts(node.id)?;
+        let Ok(traffic) = op.traffic(&inputs, symbols) else { continue };
+        if traffic.flops == 0 {
+            continue;
+        }
+        let intensity = traffic.arithmetic_intensity();
+        let bound = if intensity < peaks.ridge_point() { Bound::Memory } else { Bound::Compute };
+        let measured_flops = profile
+            .get(&node.id)
+            .filter(|time| !time.is_zero())
+            .map(|time| traffic.flops as f64 / time.as_secs_f64());
+        estimates.push(RooflineEstimate {
+            node: node.id,
+            traffic,
+            bound,
+            attainable_flops: peaks.attainable_flops(intensity),
+            measured_flops,
+        });
+    }
+    Ok(estimates)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lowered(a: &[usize], b: &[usize], expr: &str) -> TractResult<(TypedModel, usize)> {
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", f32::fact(a))?;
+        let b = model.add_source("b", f32::fact(b))?;
+        let c = model.wire_node("c", EinSum::new(expr.parse()?, f32::datum_type()), &[a, b])?;
+        model.set_output_outlets(&c)?;
+        let model = model.into_optimized()?;
+        let lir = model.nodes().iter().filter(|n| n.op_is::<LirMatMulUnary>()).collect::<Vec<_>>();
+        ensure!(lir.len() == 1, "Expected one LirMatMulUnary");
+        let id = lir[0].id;
+        Ok((model, id))
+    }
+
+    fn traffic(model: &TypedModel, id: usize) -> TractResult<(&LirMatMulUnary, MatMulTraffic)> {
+        let op = model.node(id).op_as::<LirMatMulUnary>().unwrap();
+        let inputs = model.node_input_facts(id)?;
+        Ok((op, op.traffic(&inputs, &SymbolValues::default())?))
+    }
+
+    /// Bytes of an f32 operand packed by `packer`, for one matrix.
+    fn packed_bytes(packer: &tract_linalg::frame::Packer, k: usize, mn: usize) -> u64 {
+        (packer.len(k, mn) * 4) as u64
+    }
+
+    #[test]
+    fn single_product() -> TractResult<()> {
+        let (model, id) = lowered(&[24, 32], &[32, 16], "mk,kn->mn")?;
+        let (op, traffic) = traffic(&model, id)?;
+        assert_eq!(traffic.flops, 2 * 24 * 32 * 16);
+        assert_eq!(traffic.c_bytes, 24 * 16 * 4);
+        let (m, n) = op.m_n();
+        let (m, n) = (m.to_usize()?, n.to_usize()?);
+        assert_eq!(traffic.a_bytes, packed_bytes(&op.mmm.a_pack(), 32, m));
+        assert_eq!(traffic.b_bytes, packed_bytes(&op.mmm.b_pack(), 32, n));
+        Ok(())
+    }
+
+    #[test]
+    fn broadcast_operand_counts_once() -> TractResult<()> {
+        let (model, id) = lowered(&[3, 24, 32], &[1, 32, 16], "bmk,bkn->bmn")?;
+        let (op, traffic) = traffic(&model, id)?;
+        assert_eq!(traffic.flops, 2 * 3 * 24 * 32 * 16);
+        assert_eq!(traffic.c_bytes, 3 * 24 * 16 * 4);
+        let (m, n) = op.m_n();
+        let (m, n) = (m.to_usize()?, n.to_usize()?);
+        // the batched operand is packed three times, the broadcast one once
+        let (a_batch, b_batch) = if op.operands_swapped { (1, 3) } else { (3, 1) };
+        assert_eq!(traffic.a_bytes, a_batch * packed_bytes(&op.mmm.a_pack(), 32, m));
+        assert_eq!(traffic.b_bytes, b_batch * packed_bytes(&op.mmm.b_pack(), 32, n));
+        Ok(())
+    }
+
+    #[test]
+    fn bounds() -> TractResult<()> {
+        let peaks = MachinePeaks { bandwidth: 10e9, flops: 100e9 };
+        assert_eq!(peaks.ridge_point(), 10.);
+        let (model, id) = lowered(&[1, 256], &[256, 256], "mk,kn->mn")?;
+        let (_, matvec) = traffic(&model, id)?;
+        let mut profile = HashMap::default();
+        profile.insert(id, Duration::from_secs_f64(matvec.flops as f64 / 1e9));
+        let [estimate] = &*roofline(&model, &peaks, &SymbolValues::default(), &profile)? else {
+            bail!("Expected one estimate")
+        };
+        // about 2 operations per 4 bytes of the matrix
+        assert_eq!(estimate.bound, Bound::Memory);
+        let expected = 10e9 * matvec.arithmetic_intensity();
+        assert!((estimate.attainable_flops - expected).abs() < 1.);
+        assert!((estimate.measured_flops.unwrap() - 1e9).abs() < 1e3);
+        assert!(estimate.is_far_below(0.5));
+
+        let (model, id) = lowered(&[256, 256], &[256, 256], "mk,kn->mn")?;
+        let estimates = roofline(&model, &peaks, &SymbolValues::default(), &HashMap::default())?;
+        assert_eq!(estimates[0].node, id);
+        assert_eq!(estimates[0].bound, Bound::Compute);
+        assert_eq!(estimates[0].attainable_flops, 100e9);
+        assert!(!estimates[0].is_far_below(0.5));
+        Ok(())
+    }
+
+    #[test]
+    #[ignore]
+    // Measures the host, which takes a while and depends on the machine load.
+    fn measured_peaks_are_cached() {
+        let peaks = MachinePeaks::measured();
+        assert!(peaks.bandwidth > 0. && peaks.flops > 0.);
+        assert_eq!(peaks, MachinePeaks::measured());
+    }
+}
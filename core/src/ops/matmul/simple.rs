@@ -0,0 +1,243 @@
+//! Matrix multiplication through tract kernels, without building a model.
+use crate::internal::*;
+use ndarray::Dimension;
+use std::sync::Mutex;
+use tract_linalg::mmm::{FusedSpec, MatMatMul, ScratchSpace};
+
+/// A reusable `c = a · b` for fixed m, k, n and types.
+///
+/// Operands are row-major `[..., m, k]` and `[..., k, n]` tensors, their batch prefixes
+/// broadcast against each other as numpy does. The kernel is selected once, and the packing
+/// buffers and kernel scratch space are kept from one run to the next.
+#[derive(Debug)]
+pub struct Gemm {
+    mmm: Box<dyn MatMatMul>,
+    m: usize,
+    k: usize,
+    n: usize,
+    a_dt: DatumType,
+    b_dt: DatumType,
+    c_dt: DatumType,
+    packed_b: Option<Tensor>,
+    buffers: Mutex<Option<GemmBuffers>>,
+}
+
+struct GemmBuffers {
+    packed_a: Tensor,
+    packed_b: Tensor,
+    scratch: Box<dyn ScratchSpace>,
+}
+
+impl std::fmt::Debug for GemmBuffers {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "GemmBuffers({:?}, {:?})", self.packed_a.shape(), self.packed_b.shape())
+    }
+}
+
+impl Gemm {
+    /// `(a_dt, b_dt, c_dt)` are the types of the operands and of the output. Integer operands
+    /// accumulate in i32.
+    pub fn new(
+        m: usize,
+        k: usize,
+        n: usize,
+        (a_dt, b_dt, c_dt): (DatumType, DatumType, DatumType),
+    ) -> TractResult<Gemm> {
+        let acc = if c_dt.is_float() { c_dt } else { i32::datum_type() };
+        let mmm = tract_linalg::ops()
+            .mmm(a_dt, b_dt, acc, Some(m), Some(k), Some(n))
+            .with_context(|| format!("No matmul kernel for {a_dt:?}*{b_dt:?}->{acc:?}"))?;
+        ensure!(mmm.can_store(c_dt), "Kernel {} can not store {c_dt:?}", mmm.kernel_name());
+        Ok(Gemm { mmm, m, k, n, a_dt, b_dt, c_dt, packed_b: None, buffers: Mutex::new(None) })
+    }
+
+    /// Pack a constant `[k, n]` b once for all, to be used with `run_with_packed_b`.
+    pub fn with_packed_b(mut self, b: &Tensor) -> TractResult<Gemm> {
+        ensure!(b.datum_type() == self.b_dt, "Expected b of type {:?}", self.b_dt);
+        ensure!(b.shape() == [self.k, self.n], "Expected b of shape {:?}", [self.k, self.n]);
+        let packer = self.mmm.b_pack();
+        let mut packed = unsafe {
+            Tensor::uninitialized_aligned_dt(
+                self.b_dt,
+                &[packer.len(self.k, self.n)],
+                packer.alignment(),
+            )?
+        };
+        unsafe { packer.pack(packed.view_mut(), b.view(), 0, 1) };
+        self.packed_b = Some(packed);
+        Ok(self)
+    }
+
+    pub fn kernel_name(&self) -> &'static str {
+        self.mmm.kernel_name()
+    }
+
+    /// Shape of the output for operands of these shapes.
+    pub fn output_shape(&self, a: &[usize], b: &[usize]) -> TractResult<TVec<usize>> {
+        ensure!(a.len() >= 2 && a[a.len() - 2..] == [self.m, self.k], "Expected a [.., m, k]");
+        ensure!(b.len() >= 2 && b[b.len() - 2..] == [self.k, self.n], "Expected b [.., k, n]");
+        let mut shape = crate::broadcast::multi_broadcast(&[&a[..a.len() - 2], &b[..b.len() - 2]])
+            .context("Incompatible batch dimensions")?;
+        shape.extend([self.m, self.n]);
+        Ok(shape)
+    }
+
+    /// Compute `a · b` in `c`, which must have the output shape.
+    pub fn run(&self, a: &Tensor, b: &Tensor, c: &mut Tensor) -> TractResult<()> {
+        ensure!(b.datum_type() == self.b_dt, "Expected b of type {:?}", self.b_dt);
+        self.run_inner(a, Some(b), b.shape(), c)
+    }
+
+    /// Compute `a · b` in `c`, b being the tensor packed by `with_packed_b`.
+    pub fn run_with_packed_b(&self, a: &Tensor, c: &mut Tensor) -> TractResult<()> {
+        ensure!(self.packed_b.is_some(), "No packed b, see Gemm::with_packed_b");
+        self.run_inner(a, None, &[self.k, self.n], c)
+    }
+
+    fn run_inner(
+        &self,
+        a: &Tensor,
+        b: Option<&Tensor>,
+        b_shape: &[usize],
+        c: &mut Tensor,
+    ) -> TractResult<()> {
+        ensure!(a.datum_type() == self.a_dt, "Expected a of type {:?}", self.a_dt);
+        ensure!(c.datum_type() == self.c_dt, "Expected c of type {:?}", self.c_dt);
+        let c_shape = self.output_shape(a.shape(), b_shape)?;
+        ensure!(c.shape() == &*c_shape, "Expected c of shape {c_shape:?}");
+        let a_batch = &a.shape()[..a.rank() - 2];
+        let b_batch = &b_shape[..b_shape.len() - 2];
+        let a_count = a_batch.iter().product::<usize>();
+        let b_count = if b.is_some() { b_batch.iter().product::<usize>() } else { 0 };
+        let (a_packer, b_packer) = (self.mmm.a_pack(), self.mmm.b_pack());
+        let a_len = a_packer.len(self.k, self.m);
+        let b_len = b_packer.len(self.k, self.n);
+
+        let mut buffers = self.buffers.lock().map_err(|_| format_err!("Poisoned Gemm buffers"))?;
+        let fits = |t: &Tensor, count: usize, len: usize| t.len() >= count * len;
+        if !buffers.as_ref().map_or(false, |buf| {
+            fits(&buf.packed_a, a_count, a_len) && fits(&buf.packed_b, b_count, b_len)
+        }) {
+            let allocate = |dt: DatumType, count: usize, len: usize, alignment: usize| unsafe {
+                Tensor::uninitialized_aligned_dt(dt, &[count.max(1), len], alignment)
+            };
+            *buffers = Some(GemmBuffers {
+                packed_a: allocate(self.a_dt, a_count, a_len, a_packer.alignment())?,
+                packed_b: allocate(self.b_dt, b_count, b_len, b_packer.alignment())?,
+                scratch: unsafe { self.mmm.allocate_scratch_space() },
+            });
+        }
+        let GemmBuffers { packed_a, packed_b, scratch } = buffers.as_mut().unwrap();
+
+        unsafe {
+            for (ix, coords) in ndarray::indices(a_batch).into_iter().enumerate() {
+                let mut dst = packed_a.view_at_prefix_mut(&[ix])?;
+                a_packer.pack(&mut dst, a.view_at_prefix(coords.slice())?, 1, 0);
+            }
+            if let Some(b) = b {
+                for (ix, coords) in ndarray::indices(b_batch).into_iter().enumerate() {
+                    let mut dst = packed_b.view_at_prefix_mut(&[ix])?;
+                    b_packer.pack(&mut dst, b.view_at_prefix(coords.slice())?, 0, 1);
+                }
+            }
+            let batch_index = |batch: &[usize], coords: &[usize]| {
+                // the operand batch prefix is right-aligned with the output one
+                let offset = coords.len() - batch.len();
+                batch.iter().zip(&coords[offset..]).fold(0, |ix, (d, x)| ix * d + x % d)
+            };
+            let a_store = self.mmm.a_packed(self.a_dt.size_of(), self.k);
+            let b_store = self.mmm.b_packed(self.b_dt.size_of(), self.k);
+            let c_store = self.mmm.c_view(0, 1);
+            for coords in ndarray::indices(&c_shape[..c_shape.len() - 2]) {
+                let coords = coords.slice();
+                let a = packed_a.view_at_prefix(&[batch_index(a_batch, coords)])?;
+                let b = if let Some(packed) = &self.packed_b {
+                    packed.view()
+                } else {
+                    packed_b.view_at_prefix(&[batch_index(b_batch, coords)])?
+                };
+                let c = c.view_at_prefix_mut(coords)?;
+                let specs = [
+                    FusedSpec::AddMatMul { k: self.k, a: a_store.wrap(&a), b: b_store.wrap(&b) },
+                    FusedSpec::Store(c_store.wrap(&c)),
+                ];
+                self.mmm.run_with_scratch_space(self.m, self.n, &mut **scratch, &specs)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tract_itertools::Itertools;
+    use tract_ndarray::prelude::*;
+
+    fn tensor(shape: &[usize], seed: usize) -> Tensor {
+        let len = shape.iter().product::<usize>();
+        let data = (0..len).map(|i| ((i * seed) % 17) as f32 / 4. - 2.).collect_vec();
+        Tensor::from_shape(shape, &data).unwrap()
+    }
+
+    /// Where the packing buffers of `gemm` currently live.
+    fn buffer_addresses(gemm: &Gemm) -> Option<(*const u8, *const u8)> {
+        let buffers = gemm.buffers.lock().unwrap();
+        buffers.as_ref().map(|buf| unsafe {
+            (buf.packed_a.as_bytes().as_ptr(), buf.packed_b.as_bytes().as_ptr())
+        })
+    }
+
+    fn reference(a: &Tensor, b: &Tensor) -> TractResult<Tensor> {
+        let a = a.to_array_view::<f32>()?.into_dimensionality::<Ix3>()?;
+        let b = b.to_array_view::<f32>()?.into_dimensionality::<Ix2>()?;
+        let c = a.outer_iter().map(|a| a.dot(&b)).collect_vec();
+        let views = c.iter().map(|c| c.view()).collect_vec();
+        Ok(ndarray::stack(Axis(0), &views)?.into_tensor())
+    }
+
+    #[test]
+    fn repeated_runs_reuse_buffers() -> TractResult<()> {
+        let (m, k, n) = (24, 40, 16);
+        let f32 = f32::datum_type();
+        let gemm = Gemm::new(m, k, n, (f32, f32, f32))?;
+        let b = tensor(&[k, n], 5);
+        let mut c = Tensor::zero::<f32>(&[3, m, n])?;
+        assert!(buffer_addresses(&gemm).is_none());
+        let mut addresses = None;
+        for seed in 1..6 {
+            let a = tensor(&[3, m, k], seed);
+            // b broadcast along the batch axis
+            gemm.run(&a, &b.clone().into_shape(&[1, k, n])?, &mut c)?;
+            c.close_enough(&reference(&a, &b)?, true)?;
+            let current = buffer_addresses(&gemm).unwrap();
+            assert_eq!(*addresses.get_or_insert(current), current);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn packed_b() -> TractResult<()> {
+        let (m, k, n) = (7, 33, 5);
+        let f32 = f32::datum_type();
+        let b = tensor(&[k, n], 3);
+        let gemm = Gemm::new(m, k, n, (f32, f32, f32))?.with_packed_b(&b)?;
+        let a = tensor(&[2, m, k], 7);
+        let mut c = Tensor::zero::<f32>(&[2, m, n])?;
+        gemm.run_with_packed_b(&a, &mut c)?;
+        c.close_enough(&reference(&a, &b)?, true)
+    }
+
+    #[test]
+    fn invalid_operands() -> TractResult<()> {
+        let f32 = f32::datum_type();
+        let gemm = Gemm::new(4, 8, 2, (f32, f32, f32))?;
+        let mut c = Tensor::zero::<f32>(&[4, 2])?;
+        assert!(gemm.run(&tensor(&[4, 7], 1), &tensor(&[8, 2], 1), &mut c).is_err());
+        assert!(gemm.run(&tensor(&[2, 4, 8], 1), &tensor(&[3, 8, 2], 1), &mut c).is_err());
+        let a = Tensor::zero::<i8>(&[4, 8])?;
+        assert!(gemm.run(&a, &tensor(&[8, 2], 1), &mut c).is_err());
+        assert!(gemm.run_with_packed_b(&tensor(&[4, 8], 1), &mut c).is_err());
+        gemm.run(&tensor(&[4, 8], 1), &tensor(&[8, 2], 1), &mut c)
+    }
+}
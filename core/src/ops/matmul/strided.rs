@@ -0,0 +1,180 @@
+//! B operands read strided by the kernel.
+//!
+//! A non constant B is normally packed whole by a `MatMatMulPack` node before the product: a
+//! pass over B writing a second copy of it, that the kernel then reads back. In strided mode,
+//! the product reads B where it is: the kernel packs each panel of B in its scratch space as it
+//! reaches it, and keeps the last one. With a single panel of B (n up to the kernel nr), this
+//! is a single pass over B, with no buffer and no pack node. With more panels, each of them is
+//! packed again for each row panel of A: a slower inner loop, only worth it for a few rows.
+use crate::internal::*;
+use std::ops::Range;
+use tract_linalg::frame::{Packer, PackingWriter};
+use tract_linalg::mmm::{MatMatMul, VirtualInput, VirtualInputSpec};
+
+/// How the lowering feeds a non constant, float, B operand to the kernel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum BPacking {
+    /// Strided when it is estimated cheaper (see `BPacking::strided_is_cheaper`).
+    #[default]
+    Auto,
+    /// Packed by a pack node before the product.
+    Packed,
+    /// Read strided, whenever the product allows it.
+    Strided,
+}
+
+impl BPacking {
+    /// Should the lowering of a m×k by k×n product by `mmm` read B strided ? `Auto` requires
+    /// concrete m and n.
+    pub fn strided(&self, mmm: &dyn MatMatMul, m: &TDim, n: &TDim) -> bool {
+        match self {
+            BPacking::Packed => false,
+            BPacking::Strided => true,
+            BPacking::Auto => {
+                if let (Ok(m), Ok(n)) = (m.to_usize(), n.to_usize()) {
+                    Self::strided_is_cheaper(mmm, m, n)
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    /// The cost estimate of `BPacking::Auto`, in item copies over k. Packed: B is copied to the
+    /// packed buffer, then read back by the kernel, 2·n. Strided: each panel is gathered in the
+    /// kernel scratch space and read there, 2·n again, but once per row panel of A when B has
+    /// more than one panel. On a tie, strided wins: it saves the pack node and its buffer.
+    pub fn strided_is_cheaper(mmm: &dyn MatMatMul, m: usize, n: usize) -> bool {
+        let repacks = if n <= mmm.nr() { 1 } else { m.divceil(mmm.mr()) };
+        let strided = 2 * n * repacks;
+        let packed = 2 * n;
+        strided <= packed
+    }
+}
+
+/// Reads a B operand in place, with its k and n axes.
+#[derive(Clone, Debug, Hash)]
+pub struct StridedInputSpec {
+    pub k_axis: usize,
+    pub mn_axis: usize,
+}
+
+impl StridedInputSpec {
+    fn wrap_t<T: Datum + Copy>(&self, view: &TensorView) -> Box<dyn VirtualInput> {
+        Box::new(StridedInput::<T> {
+            ptr: unsafe { view.as_ptr_unchecked() },
+            mn: view.shape()[self.mn_axis],
+            k_stride: view.strides()[self.k_axis],
+            mn_stride: view.strides()[self.mn_axis],
+        })
+    }
+}
+
+impl VirtualInputSpec for StridedInputSpec {
+    fn wrap(&self, view: &TensorView) -> Box<dyn VirtualInput> {
+        dispatch_copy!(Self::wrap_t(view.datum_type())(self, view))
+    }
+}
+
+#[derive(Clone, Debug)]
+struct StridedInput<T: Datum + Copy> {
+    ptr: *const T,
+    mn: usize,
+    k_stride: isize,
+    mn_stride: isize,
+}
+
+unsafe impl<T: Datum + Copy> Send for StridedInput<T> {}
+unsafe impl<T: Datum + Copy> Sync for StridedInput<T> {}
+
+impl<T: Datum + Copy> VirtualInput for StridedInput<T> {
+    fn input(
+        &self,
+        packer: &Packer,
+        packed: *mut u8,
+        k_range: Range<usize>,
+        mn_range: Range<usize>,
+    ) {
+        let mut writer = packer.write_single_panel_with_k_outer(packed as *mut T);
+        let valid = mn_range.start..mn_range.end.min(self.mn);
+        unsafe {
+            for k in k_range {
+                let row = self.ptr.offset(k as isize * self.k_stride);
+                for x in valid.clone() {
+                    writer.write(*row.offset(x as isize * self.mn_stride));
+                }
+                for _ in valid.end..mn_range.end {
+                    writer.write(T::default());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::einsum::EinSum;
+    use crate::ops::matmul::lir_unary::{LirMatMulUnary, ProtoFusedSpec};
+    use crate::ops::matmul::pack::MatMatMulPack;
+    use crate::optim::OptimizerOptions;
+
+    fn model(expr: &str, a_shape: &[usize], b_shape: &[usize]) -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", f32::fact(a_shape))?;
+        let b = model.add_source("b", f32::fact(b_shape))?;
+        let c =
+            model.wire_node("einsum", EinSum::new(expr.parse()?, f32::datum_type()), &[a, b])?;
+        model.set_output_outlets(&c)?;
+        Ok(model)
+    }
+
+    fn input(shape: &[usize], seed: usize) -> TractResult<TValue> {
+        let len = shape.iter().product::<usize>();
+        let data = (0..len).map(|i| ((i * 7 + seed) % 23) as f32 / 8. - 1.).collect::<Vec<_>>();
+        Ok(Tensor::from_shape(shape, &data)?.into_tvalue())
+    }
+
+    /// Is B read strided by the product of the optimized model ?
+    fn b_strided(model: &TypedModel) -> bool {
+        let lir = model.nodes().iter().find_map(|n| n.op_as::<LirMatMulUnary>()).unwrap();
+        let packs = model.nodes().iter().filter(|n| n.op_is::<MatMatMulPack>()).count();
+        let ProtoFusedSpec::AddMatMul(geo, _, _) = &lir.micro_ops[0] else { unreachable!() };
+        let strided = geo.b_storage.is_some();
+        assert_eq!(packs, 2 - strided as usize);
+        strided
+    }
+
+    #[test]
+    fn strided_matches_packed() -> TractResult<()> {
+        // k outer and inner to n, one panel of B or several
+        for (expr, a_shape, b_shape) in [
+            ("bmk,bkn->bmn", [2, 67, 129], [2, 129, 3]),
+            ("bmk,bnk->bmn", [2, 67, 129], [2, 3, 129]),
+            ("bmk,bkn->bmn", [2, 67, 129], [2, 129, 45]),
+            ("bmk,bnk->bmn", [2, 67, 129], [2, 45, 129]),
+        ] {
+            let model = model(expr, &a_shape, &b_shape)?;
+            let options = OptimizerOptions { b_packing: BPacking::Strided, ..Default::default() };
+            let strided = model.clone().into_optimized_with_options(&options)?;
+            assert!(b_strided(&strided));
+            let options = OptimizerOptions { b_packing: BPacking::Packed, ..Default::default() };
+            let packed = model.into_optimized_with_options(&options)?;
+            assert!(!b_strided(&packed));
+            let inputs = tvec!(input(&a_shape, 0)?, input(&b_shape, 3)?);
+            let expected = packed.into_runnable()?.run(inputs.clone())?;
+            let found = strided.into_runnable()?.run(inputs)?;
+            assert_eq!(found[0], expected[0]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn auto_picks_strided_for_small_n() -> TractResult<()> {
+        let optimized = model("mk,kn->mn", &[256, 256], &[256, 4])?.into_optimized()?;
+        assert!(b_strided(&optimized));
+        let optimized = model("mk,kn->mn", &[256, 256], &[256, 1024])?.into_optimized()?;
+        assert!(!b_strided(&optimized));
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+//! Axes and dimensions of matrix multiplication nodes, for visualization and debugging tools.
+use super::lir_unary::LirMatMulUnary;
+use crate::internal::*;
+use crate::ops::einsum::EinSum;
+
+/// One axis of an einsum, resolved against the facts of its inputs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AxisSummary {
+    /// Label of the axis in the einsum expression.
+    pub label: char,
+    /// Positions of the axis in each input, empty if the input does not have it.
+    pub inputs: Vec<Vec<usize>>,
+    /// Position of the axis in the output, `None` for a contracted axis.
+    pub output: Option<usize>,
+    /// Dimension of the axis, in TDim notation if it is symbolic.
+    pub dim: String,
+}
+
+/// The axes of an einsum node, in the order of its mapping.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AxesSummary {
+    /// Einsum expression, as it is parsed by `AxesMapping::from_str`.
+    pub expr: String,
+    pub axes: Vec<AxisSummary>,
+}
+
+/// A prefix (batch) axis of a lowered matrix multiplication output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PrefixAxisSummary {
+    pub axis: usize,
+    pub dim: String,
+}
+
+/// The problem geometry of a `LirMatMulUnary` node.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GeometrySummary {
+    pub m: String,
+    /// `None` if the kernel only runs fused ops, without a product.
+    pub k: Option<String>,
+    pub n: String,
+    pub c_m_axis: usize,
+    pub c_n_axis: usize,
+    /// The kernel m is the einsum n.
+    pub operands_swapped: bool,
+    pub prefix: Vec<PrefixAxisSummary>,
+}
+
+/// Summary of a matrix multiplication node, whatever stage of the optimization it comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MatMulSummary {
+    EinSum(AxesSummary),
+    LirMatMulUnary(GeometrySummary),
+}
+
+impl EinSum {
+    /// Axes of the einsum with the dimensions they take for these input facts.
+    pub fn axes_summary(&self, inputs: &[&TypedFact]) -> TractResult<AxesSummary> {
+        ensure!(
+            inputs.len() == self.axes.input_count(),
+            "Expected {} input facts, got {}",
+            self.axes.input_count(),
+            inputs.len()
+        );
+        let output = self.output_facts(inputs)?.remove(0);
+        let axes = self
+            .axes
+            .iter_all_axes()
+            .map(|axis| {
+                let output_pos = axis.outputs[0].first().copied();
+                let dim = if let Some(pos) = output_pos {
+                    output.shape[pos].clone()
+                } else {
+                    // contracted axis: the inputs can only disagree on broadcast 1s
+                    axis.inputs
+                        .iter()
+                        .zip(inputs)
+                        .flat_map(|(positions, fact)| positions.iter().map(|p| &fact.shape[*p]))
+                        .find(|d| !d.is_one())
+                        .cloned()
+                        .unwrap_or_else(|| 1.to_dim())
+                };
+                AxisSummary {
+                    label: axis.repr,
+                    inputs: axis.inputs.iter().map(|p| p.to_vec()).collect(),
+                    output: output_pos,
+                    dim: dim.to_string(),
+                }
+            })
+            .collect();
+        Ok(AxesSummary { expr: self.axes.to_string(), axes })
+    }
+}
+
+impl LirMatMulUnary {
+    /// m, k and n of the product, and the axes of the output the kernel loops over.
+    pub fn geometry_summary(&self) -> GeometrySummary {
+        let (m, n) = self.m_n();
+        let prefix = (0..self.c_fact.rank())
+            .filter(|&axis| axis != self.c_m_axis && axis != self.c_n_axis)
+            .map(|axis| PrefixAxisSummary { axis, dim: self.c_fact.shape[axis].to_string() })
+            .collect();
+        GeometrySummary {
+            m: m.to_string(),
+            k: self.guess_k().map(|k| k.to_string()),
+            n: n.to_string(),
+            c_m_axis: self.c_m_axis,
+            c_n_axis: self.c_n_axis,
+            operands_swapped: self.operands_swapped,
+            prefix,
+        }
+    }
+}
+
+/// Summary of node `id` of `model`, or `None` if it is not a matrix multiplication.
+pub fn matmul_summary(model: &TypedModel, id: usize) -> TractResult<Option<MatMulSummary>> {
+    let node = model.node(id);
+    if let Some(einsum) = node.op_as::<EinSum>() {
+        let inputs = model.node_input_facts(id)?;
+        Ok(Some(MatMulSummary::EinSum(einsum.axes_summary(&inputs)?)))
+    } else if let Some(lir) = node.op_as::<LirMatMulUnary>() {
+        Ok(Some(MatMulSummary::LirMatMulUnary(lir.geometry_summary())))
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn batched_matmul() -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let s = model.symbol_table.sym("S");
+        let a = model.add_source("a", f32::fact(dims![2, s, 8].as_ref()))?;
+        let b = model.add_const("b", Tensor::zero::<f32>(&[1, 8, 16])?)?;
+        let op = EinSum::new("bmk,bkn->bmn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", op, &[a, b])?;
+        model.set_output_outlets(&c)?;
+        Ok(model)
+    }
+
+    #[test]
+    fn einsum_axes() -> TractResult<()> {
+        let model = batched_matmul()?;
+        let Some(MatMulSummary::EinSum(summary)) = matmul_summary(&model, 2)? else {
+            bail!("Expected an einsum summary")
+        };
+        assert_eq!(summary.expr, "bmk,bkn->bmn");
+        let dims = summary.axes.iter().map(|a| (a.label, a.dim.as_str())).collect::<Vec<_>>();
+        assert_eq!(dims, [('b', "2"), ('m', "S"), ('n', "16"), ('k', "8")]);
+        let k = &summary.axes[3];
+        assert_eq!(k.inputs, [vec![2], vec![1]]);
+        assert_eq!(k.output, None);
+        assert_eq!(summary.axes[2].output, Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn lowered_geometry() -> TractResult<()> {
+        let model = batched_matmul()?.into_optimized()?;
+        let summaries = (0..model.nodes().len())
+            .filter_map(|id| matmul_summary(&model, id).transpose())
+            .collect::<TractResult<Vec<_>>>()?;
+        let [MatMulSummary::LirMatMulUnary(geo)] = &*summaries else {
+            bail!("Expected a single LirMatMulUnary, got {summaries:?}")
+        };
+        // the optimizer is free to pick any of the output axes as m or n
+        assert_eq!(geo.k.as_deref(), Some("8"));
+        let output = ["2", "S", "16"];
+        assert_eq!(geo.m, output[geo.c_m_axis]);
+        assert_eq!(geo.n, output[geo.c_n_axis]);
+        let [prefix] = &*geo.prefix else { bail!("Expected one prefix axis") };
+        assert_eq!(prefix.dim, output[prefix.axis]);
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() -> TractResult<()> {
+        let model = batched_matmul()?;
+        let einsum = matmul_summary(&model, 2)?.unwrap();
+        let optimized = model.into_optimized()?;
+        let lir = optimized.nodes().iter().find_map(|n| matmul_summary(&optimized, n.id).unwrap());
+        for summary in [einsum, lir.unwrap()] {
+            let json = serde_json::to_string(&summary)?;
+            let back: MatMulSummary = serde_json::from_str(&json)?;
+            assert_eq!(back, summary);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+//! Macro tiling of large matrix products.
+//!
+//! Packing whole operands before multiplying them needs a second copy of both, and each row of
+//! A panels then streams through all of packed B, missing every cache level once the operands
+//! are large enough. In macro tiling mode, the `LirMatMulUnary` works on the unpacked operands:
+//! it packs a block of columns of B, sized for the last level cache, then loops over blocks of
+//! rows of A, sized for the per-core cache, packing each one in turn and running the kernel on
+//! the tiles of C they cover. The packing buffers are only as large as the blocks.
+use crate::internal::*;
+use std::alloc::Layout;
+use std::ops::Range;
+use tract_linalg::frame::Packer;
+use tract_linalg::mmm::MatMatMul;
+
+lazy_static::lazy_static! {
+    static ref DATA_CACHE_SIZES: Option<(usize, usize)> = data_cache_sizes();
+}
+
+/// Per-core (level 2) and last level data cache sizes, in bytes, as reported by sysfs.
+fn data_cache_sizes() -> Option<(usize, usize)> {
+    let mut sizes = vec![];
+    for index in 0.. {
+        let dir = format!("/sys/devices/system/cpu/cpu0/cache/index{index}");
+        let Ok(kind) = std::fs::read_to_string(format!("{dir}/type")) else { break };
+        if kind.trim() == "Instruction" {
+            continue;
+        }
+        let read = |file: &str| std::fs::read_to_string(format!("{dir}/{file}")).ok();
+        let level = read("level")?.trim().parse::<usize>().ok()?;
+        let size = read("size")?;
+        let size = size.trim();
+        let size = if let Some(kb) = size.strip_suffix('K') {
+            kb.parse::<usize>().ok()? * 1024
+        } else if let Some(mb) = size.strip_suffix('M') {
+            mb.parse::<usize>().ok()? * 1024 * 1024
+        } else {
+            size.parse::<usize>().ok()?
+        };
+        sizes.push((level, size));
+    }
+    let l2 = sizes.iter().find(|(level, _)| *level == 2)?.1;
+    let last = sizes.iter().max_by_key(|(level, _)| *level)?.1;
+    Some((l2, last))
+}
+
+/// Per-core data cache size of the host, or of a typical desktop CPU.
+pub(crate) fn per_core_cache_bytes() -> usize {
+    DATA_CACHE_SIZES.map(|(l2, _)| l2).unwrap_or(1 << 20)
+}
+
+/// Thresholds of the macro tiling mode. The default ones derive from the data cache sizes of
+/// the host, or of a typical desktop CPU when they can not be detected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MacroTiling {
+    /// Tile the products whose packed operands take more than this many bytes.
+    pub min_packed_bytes: usize,
+    /// Size of a packed block of rows of A (by k), kept in the per-core cache.
+    pub a_block_bytes: usize,
+    /// Size of a packed block of columns of B (by k), kept in the last level cache.
+    pub b_block_bytes: usize,
+}
+
+impl Default for MacroTiling {
+    fn default() -> MacroTiling {
+        let (l2, last) = DATA_CACHE_SIZES.unwrap_or((1 << 20, 8 << 20));
+        MacroTiling::for_cache_sizes(l2, last)
+    }
+}
+
+impl MacroTiling {
+    /// Thresholds for a per-core cache of `l2` bytes and a last level cache of `last` bytes.
+    pub fn for_cache_sizes(l2: usize, last: usize) -> MacroTiling {
+        MacroTiling { min_packed_bytes: 4 * last, a_block_bytes: l2 / 2, b_block_bytes: last / 2 }
+    }
+
+    /// Never tile.
+    pub fn disabled() -> MacroTiling {
+        MacroTiling { min_packed_bytes: usize::MAX, ..MacroTiling::default() }
+    }
+
+    /// Rows of A and columns of B per block for a m·k·n product with operands of `item_sizes`
+    /// bytes, or None if it is small enough to be packed whole.
+    pub fn block_sizes(
+        &self,
+        mmm: &dyn MatMatMul,
+        (a_item, b_item): (usize, usize),
+        (m, k, n): (usize, usize, usize),
+    ) -> Option<(usize, usize)> {
+        let (mr, nr) = (mmm.mr(), mmm.nr());
+        let packed = mmm.a_pack().len(k, m) * a_item + mmm.b_pack().len(k, n) * b_item;
+        if packed <= self.min_packed_bytes {
+            return None;
+        }
+        let a_panels = (self.a_block_bytes / (k * mr * a_item).max(1)).clamp(1, m.divceil(mr));
+        let b_panels = (self.b_block_bytes / (k * nr * b_item).max(1)).clamp(1, n.divceil(nr));
+        Some((a_panels * mr, b_panels * nr))
+    }
+}
+
+/// Macro tiling of a `LirMatMulUnary`: its A and B inputs are not packed, they are packed in
+/// the op, by blocks.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MacroTiles {
+    /// Rows of A per block, a multiple of the kernel mr.
+    pub m: usize,
+    /// Columns of B per block, a multiple of the kernel nr.
+    pub n: usize,
+    /// k and m axes of the A input.
+    pub a_axes: (usize, usize),
+    /// k and n axes of the B input.
+    pub b_axes: (usize, usize),
+    /// Item sizes of the A and B inputs, in bytes.
+    pub item_sizes: (usize, usize),
+}
+
+impl MacroTiles {
+    /// Layouts of the buffers the blocks of A and B are packed in, for a m·k·n product.
+    pub(crate) fn pack_buffer_layouts(
+        &self,
+        mmm: &dyn MatMatMul,
+        (m, k, n): (usize, usize, usize),
+    ) -> TractResult<(Layout, Layout)> {
+        let (a_pack, b_pack) = (mmm.a_pack(), mmm.b_pack());
+        let a = a_pack.len(k, self.m.min(m)) * self.item_sizes.0;
+        let b = b_pack.len(k, self.n.min(n)) * self.item_sizes.1;
+        Ok((
+            Layout::from_size_align(a, a_pack.alignment())?,
+            Layout::from_size_align(b, b_pack.alignment())?,
+        ))
+    }
+
+    /// Pack the `mn` range of the matrix of `input` starting `offset` bytes in (the coordinates
+    /// of its prefix axes), as the first panels of the `packed` buffer.
+    pub(crate) unsafe fn pack_block(
+        packer: &Packer,
+        input: &Tensor,
+        offset: isize,
+        (k_axis, mn_axis): (usize, usize),
+        mn: Range<usize>,
+        packed: *mut u8,
+    ) {
+        let mut shape: TVec<usize> = input.shape().into();
+        shape[mn_axis] = mn.len();
+        let start = mn.start as isize * input.strides()[mn_axis];
+        let offset = offset + start * input.datum_type().size_of() as isize;
+        let view = TensorView::from_bytes(input, offset, &shape, input.strides());
+        // k inner to mn: see MatMatMulPack::transposing
+        if k_axis > mn_axis {
+            packer.pack_transposing_to(packed, &view, k_axis, mn_axis);
+        } else {
+            packer.pack_to(packed, &view, k_axis, mn_axis);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::einsum::EinSum;
+    use crate::ops::math::{add, max};
+    use crate::ops::matmul::lir_unary::LirMatMulUnary;
+    use crate::ops::matmul::pack::MatMatMulPack;
+    use crate::optim::OptimizerOptions;
+
+    /// A batched product of two model inputs, with a per-column bias and a relu to fuse.
+    fn model(expr: &str, a_shape: &[usize], b_shape: &[usize]) -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", f32::fact(a_shape))?;
+        let b = model.add_source("b", f32::fact(b_shape))?;
+        let c =
+            model.wire_node("einsum", EinSum::new(expr.parse()?, f32::datum_type()), &[a, b])?;
+        let n = model.outlet_fact(c[0])?.shape[2].to_usize()?;
+        let bias = (0..n).map(|i| (i % 5) as f32 - 2.).collect::<Vec<_>>();
+        let bias = model.add_const("bias.value", Tensor::from_shape(&[1, 1, n], &bias)?)?;
+        let c = model.wire_node("bias", add(), &[c[0], bias])?;
+        let zero = model.add_const("relu.zero", tensor3(&[[[0f32]]]))?;
+        let c = model.wire_node("relu", max(), &[c[0], zero])?;
+        model.set_output_outlets(&c)?;
+        Ok(model)
+    }
+
+    fn input(shape: &[usize], seed: usize) -> TractResult<TValue> {
+        let len = shape.iter().product::<usize>();
+        let data = (0..len).map(|i| ((i * 7 + seed) % 23) as f32 / 8. - 1.).collect::<Vec<_>>();
+        Ok(Tensor::from_shape(shape, &data)?.into_tvalue())
+    }
+
+    fn tiny_blocks() -> MacroTiling {
+        MacroTiling { min_packed_bytes: 0, a_block_bytes: 4096, b_block_bytes: 8192 }
+    }
+
+    #[test]
+    fn tiled_matches_packed() -> TractResult<()> {
+        // k outer and inner to m and n, to go through both packing routines
+        for (expr, a_shape, b_shape) in [
+            ("bmk,bkn->bmn", [2, 67, 129], [2, 129, 45]),
+            ("bkm,bnk->bmn", [2, 129, 67], [2, 45, 129]),
+        ] {
+            let model = model(expr, &a_shape, &b_shape)?;
+            let options = OptimizerOptions { macro_tiling: tiny_blocks(), ..Default::default() };
+            let tiled = model.clone().into_optimized_with_options(&options)?;
+            let lir = tiled.nodes().iter().find_map(|n| n.op_as::<LirMatMulUnary>()).unwrap();
+            assert!(lir.macro_tiles.is_some());
+            assert!(!tiled.nodes().iter().any(|n| n.op_is::<MatMatMulPack>()));
+            let packed = model.into_optimized()?;
+            assert!(packed.nodes().iter().any(|n| n.op_is::<MatMatMulPack>()));
+            let inputs = tvec!(input(&a_shape, 0)?, input(&b_shape, 3)?);
+            let expected = packed.into_runnable()?.run(inputs.clone())?;
+            let found = tiled.into_runnable()?.run(inputs)?;
+            assert_eq!(found[0], expected[0]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn tiled_plan_peaks_lower() -> TractResult<()> {
+        let model = model("bmk,bkn->bmn", &[1, 64, 256], &[1, 256, 64])?;
+        let options = OptimizerOptions { macro_tiling: tiny_blocks(), ..Default::default() };
+        let tiled = SimplePlan::new(model.clone().into_optimized_with_options(&options)?)?;
+        let packed = SimplePlan::new(model.into_optimized()?)?;
+        let symbols = SymbolValues::default();
+        assert!(tiled.peak_memory(&symbols)? < packed.peak_memory(&symbols)?);
+        Ok(())
+    }
+
+    #[test]
+    fn block_sizes() {
+        let mmm = tract_linalg::ops()
+            .mmm(f32::datum_type(), f32::datum_type(), f32::datum_type(), None, None, None)
+            .unwrap();
+        let (mr, nr) = (mmm.mr(), mmm.nr());
+        let tiling = MacroTiling::for_cache_sizes(1 << 20, 8 << 20);
+        assert_eq!(tiling.block_sizes(&*mmm, (4, 4), (256, 256, 256)), None);
+        let (m, n) = tiling.block_sizes(&*mmm, (4, 4), (4096, 4096, 4096)).unwrap();
+        assert!(m % mr == 0 && (m == mr || m * 4096 * 4 <= 1 << 19));
+        assert!(n % nr == 0 && (n == nr || n * 4096 * 4 <= 4 << 20));
+        // a block is at least one panel, at most the whole operand
+        let (m, n) = tiling.block_sizes(&*mmm, (4, 4), (3, 1 << 24, 5)).unwrap();
+        assert_eq!((m, n), (mr, nr));
+        assert_eq!(MacroTiling::disabled().block_sizes(&*mmm, (4, 4), (4096, 4096, 4096)), None);
+    }
+}
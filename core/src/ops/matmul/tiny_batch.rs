@@ -0,0 +1,260 @@
+//! Batches of tiny matrix products, like a few hundred thousand 3x3 by 3x3 products.
+//!
+//! A product kernel can not fill a single vector register with such matrices, and running it on
+//! each batch entry in turn spends most of the time in the kernel calls. `TinyBatchMatMul` runs
+//! the products across the batch instead: blocks of `LANES` batch entries are laid out value by
+//! value (struct of arrays), so each multiply-add of the contraction is an element-wise
+//! operation over a whole block.
+use crate::internal::*;
+use std::ops::Mul;
+use tract_num_traits::Zero;
+
+/// Batch entries computed together.
+const LANES: usize = 64;
+
+/// Selection of the einsums lowered to a `TinyBatchMatMul`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TinyBatch {
+    /// Largest m, k and n of the products.
+    pub max_mkn: usize,
+    /// Smallest number of products in the batch.
+    pub min_batch: usize,
+}
+
+impl Default for TinyBatch {
+    fn default() -> TinyBatch {
+        TinyBatch { max_mkn: 8, min_batch: 4 * LANES }
+    }
+}
+
+impl TinyBatch {
+    /// Always lower to a product kernel.
+    pub fn disabled() -> TinyBatch {
+        TinyBatch { min_batch: usize::MAX, ..TinyBatch::default() }
+    }
+
+    /// Is a batch of `batch` m·k·n products tiny enough ?
+    pub fn selects(&self, (m, k, n): (usize, usize, usize), batch: usize) -> bool {
+        m.min(n) > 0 && m.max(k).max(n) <= self.max_mkn && batch >= self.min_batch
+    }
+}
+
+/// Batched products of A [batch.., m, k] by B [batch.., k, n] to C [batch.., m, n], the batch
+/// axes of A and B being the same.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TinyBatchMatMul {
+    pub m: usize,
+    pub k: usize,
+    pub n: usize,
+}
+
+impl Op for TinyBatchMatMul {
+    fn name(&self) -> Cow<str> {
+        "TinyBatchMatMul".into()
+    }
+
+    fn info(&self) -> TractResult<Vec<String>> {
+        Ok(vec![format!("{}x{}x{} products, {LANES} at a time", self.m, self.k, self.n)])
+    }
+
+    op_as_typed_op!();
+}
+
+impl EvalOp for TinyBatchMatMul {
+    fn is_stateless(&self) -> bool {
+        true
+    }
+
+    fn eval(&self, mut inputs: TVec<TValue>) -> TractResult<TVec<TValue>> {
+        let (a, b) = args_2!(inputs);
+        let (a, b) = (&*a, &*b);
+        let mut shape: TVec<usize> = a.shape().into();
+        shape[a.rank() - 1] = self.n;
+        let dt = a.datum_type();
+        let mut c = unsafe { Tensor::uninitialized_dt(dt, &shape)? };
+        match dt {
+            DatumType::F32 => self.eval_t::<f32>(a, b, &mut c)?,
+            DatumType::F64 => self.eval_t::<f64>(a, b, &mut c)?,
+            _ => bail!("{} does not compute {dt:?} products", self.name()),
+        }
+        Ok(tvec!(c.into_tvalue()))
+    }
+}
+
+impl TinyBatchMatMul {
+    fn eval_t<T: Datum + Copy + Zero + Mul<Output = T>>(
+        &self,
+        a: &Tensor,
+        b: &Tensor,
+        c: &mut Tensor,
+    ) -> TractResult<()> {
+        let (m, k, n) = (self.m, self.k, self.n);
+        let (a, b, c) = (a.as_slice::<T>()?, b.as_slice::<T>()?, c.as_slice_mut::<T>()?);
+        let batch = c.len() / (m * n);
+        // values of the current block, by position in the matrices then by batch entry
+        let mut a_block = vec![[T::zero(); LANES]; m * k];
+        let mut b_block = vec![[T::zero(); LANES]; k * n];
+        let mut c_block = vec![[T::zero(); LANES]; m * n];
+        for start in (0..batch).step_by(LANES) {
+            let lanes = LANES.min(batch - start);
+            gather(&mut a_block, &a[start * m * k..][..lanes * m * k], lanes);
+            gather(&mut b_block, &b[start * k * n..][..lanes * k * n], lanes);
+            for i in 0..m {
+                for j in 0..n {
+                    let mut acc = [T::zero(); LANES];
+                    for p in 0..k {
+                        let (x, y) = (&a_block[i * k + p], &b_block[p * n + j]);
+                        for l in 0..LANES {
+                            acc[l] = acc[l] + x[l] * y[l];
+                        }
+                    }
+                    c_block[i * n + j] = acc;
+                }
+            }
+            let c = &mut c[start * m * n..][..lanes * m * n];
+            for (x, values) in c_block.iter().enumerate() {
+                for l in 0..lanes {
+                    c[l * m * n + x] = values[l];
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lay out the `lanes` matrices of `matrices` value by value in `block`.
+fn gather<T: Copy>(block: &mut [[T; LANES]], matrices: &[T], lanes: usize) {
+    let len = block.len();
+    for (x, values) in block.iter_mut().enumerate() {
+        for l in 0..lanes {
+            values[l] = matrices[l * len + x];
+        }
+    }
+}
+
+impl TypedOp for TinyBatchMatMul {
+    fn output_facts(&self, inputs: &[&TypedFact]) -> TractResult<TVec<TypedFact>> {
+        ensure!(inputs.len() == 2);
+        let (a, b) = (inputs[0], inputs[1]);
+        ensure!(
+            a.datum_type == b.datum_type,
+            "Mixed {:?} and {:?} operands",
+            a.datum_type,
+            b.datum_type
+        );
+        let rank = a.rank();
+        ensure!(
+            rank >= 2 && b.rank() == rank,
+            "Expected operands of a same rank, got {a:?} and {b:?}"
+        );
+        ensure!(
+            a.shape[..rank - 2] == b.shape[..rank - 2]
+                && a.shape[rank - 2] == self.m.to_dim()
+                && a.shape[rank - 1] == self.k.to_dim()
+                && b.shape[rank - 2] == self.k.to_dim()
+                && b.shape[rank - 1] == self.n.to_dim(),
+            "{}x{}x{} products of {a:?} by {b:?}",
+            self.m,
+            self.k,
+            self.n
+        );
+        let mut shape = a.shape.to_tvec();
+        shape[rank - 1] = self.n.to_dim();
+        Ok(tvec!(a.datum_type.fact(shape)))
+    }
+
+    fn cost(&self, inputs: &[&TypedFact]) -> TractResult<TVec<(Cost, TDim)>> {
+        let rank = inputs[0].rank();
+        let batch = inputs[0].shape[..rank - 2].iter().product::<TDim>();
+        Ok(tvec!((Cost::FMA(inputs[0].datum_type), batch * (self.m * self.k * self.n))))
+    }
+
+    as_op!();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::einsum::EinSum;
+    use crate::ops::matmul::lir_unary::LirMatMulUnary;
+    use crate::optim::OptimizerOptions;
+    use tract_itertools::Itertools;
+
+    fn tensor<T: Datum + Copy>(shape: &[usize], value: impl Fn(usize) -> T) -> Tensor {
+        let len = shape.iter().product::<usize>();
+        Tensor::from_shape(shape, &(0..len).map(value).collect_vec()).unwrap()
+    }
+
+    fn model(expr: &str, dt: DatumType, a: &[usize], b: &[usize]) -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", dt.fact(a))?;
+        let b = model.add_source("b", dt.fact(b))?;
+        let c = model.wire_node("einsum", EinSum::new(expr.parse()?, dt), &[a, b])?;
+        model.set_output_outlets(&c)?;
+        Ok(model)
+    }
+
+    fn optimized(model: &TypedModel, tiny_batch: TinyBatch) -> TractResult<TypedModel> {
+        let options = OptimizerOptions { tiny_batch, ..OptimizerOptions::default() };
+        model.clone().into_optimized_with_options(&options)
+    }
+
+    fn is_tiny_batch(model: &TypedModel) -> bool {
+        model.nodes().iter().any(|n| n.op_is::<TinyBatchMatMul>())
+            && !model.nodes().iter().any(|n| n.op_is::<LirMatMulUnary>())
+    }
+
+    fn run(model: &TypedModel, a: &Tensor, b: &Tensor) -> TractResult<Tensor> {
+        let inputs = tvec!(a.clone().into_tvalue(), b.clone().into_tvalue());
+        Ok(model.clone().into_runnable()?.run(inputs)?.remove(0).into_tensor())
+    }
+
+    /// The products of `bij,bjk->bik`, accumulated in order over j.
+    fn reference(a: &Tensor, b: &Tensor) -> TractResult<Tensor> {
+        let (batch, m, k, n) = (a.shape()[0], a.shape()[1], a.shape()[2], b.shape()[2]);
+        let (a, b) = (a.as_slice::<f32>()?, b.as_slice::<f32>()?);
+        let mut c = vec![0f32; batch * m * n];
+        for (x, i, j) in tract_itertools::iproduct!(0..batch, 0..m, 0..n) {
+            c[(x * m + i) * n + j] =
+                (0..k).fold(0., |acc, p| acc + a[(x * m + i) * k + p] * b[(x * k + p) * n + j]);
+        }
+        Tensor::from_shape(&[batch, m, n], &c)
+    }
+
+    #[test]
+    fn tiny_products_computed_across_batch() -> TractResult<()> {
+        for (batch, m, k, n) in [(1000, 3, 3, 3), (333, 1, 8, 5), (257, 7, 1, 2)] {
+            let a = tensor(&[batch, m, k], |i| ((i * 7) % 19) as f32 / 3. - 2.);
+            let b = tensor(&[batch, k, n], |i| ((i * 5) % 23) as f32 / 7. - 1.);
+            let model = model("bij,bjk->bik", f32::datum_type(), a.shape(), b.shape())?;
+            let optimized = optimized(&model, TinyBatch::default())?;
+            assert!(is_tiny_batch(&optimized), "{optimized}");
+            assert_eq!(run(&optimized, &a, &b)?, reference(&a, &b)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn batch_axes_laid_out_around_products() -> TractResult<()> {
+        let (a_shape, b_shape) = ([3, 20, 16, 5], [20, 16, 4, 5]);
+        let a = tensor(&a_shape, |i| ((i * 3) % 11) as f64 - 5.);
+        let b = tensor(&b_shape, |i| ((i * 13) % 17) as f64 / 4.);
+        let model = model("icbj,cbkj->kbci", f64::datum_type(), &a_shape, &b_shape)?;
+        let optimized = optimized(&model, TinyBatch::default())?;
+        assert!(is_tiny_batch(&optimized), "{optimized}");
+        let expected = run(&model, &a, &b)?;
+        assert_eq!(expected.shape(), &[4, 16, 20, 3]);
+        run(&optimized, &a, &b)?.close_enough(&expected, Approximation::Close)
+    }
+
+    #[test]
+    fn small_batch_or_opt_out_lowered_to_kernel() -> TractResult<()> {
+        let large = model("bij,bjk->bik", f32::datum_type(), &[1000, 3, 3], &[1000, 3, 3])?;
+        assert!(!is_tiny_batch(&optimized(&large, TinyBatch::disabled())?));
+        let small = model("bij,bjk->bik", f32::datum_type(), &[16, 3, 3], &[16, 3, 3])?;
+        assert!(!is_tiny_batch(&optimized(&small, TinyBatch::default())?));
+        let wide = model("bij,bjk->bik", f32::datum_type(), &[1000, 3, 16], &[1000, 16, 3])?;
+        assert!(!is_tiny_batch(&optimized(&wide, TinyBatch::default())?));
+        Ok(())
+    }
+}
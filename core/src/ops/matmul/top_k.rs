@@ -0,0 +1,342 @@
+//! Matrix product followed by a top-k over one of its output axes, like a classifier head
+//! keeping its best logits out of a large vocabulary.
+//!
+//! The fused op runs the product kernel by tiles of panels along the scored axis and keeps a
+//! running top-k of each line, so the full score matrix is never materialized: only one tile is.
+//! Each score is computed by the very kernel invocation the unfused product would run, so the
+//! selection is exact, ties included.
+
+use super::lir_unary::{LirMatMulUnary, ProtoFusedSpec};
+use crate::internal::*;
+use crate::ops::array::Topk;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use tract_linalg::mmm::{FusedSpec, InputStore, InputStoreSpec};
+
+#[derive(Clone, Debug)]
+pub struct LirMatMulTopk {
+    pub mm: LirMatMulUnary,
+    pub topk: Topk,
+    /// Panels of the scored axis computed by each kernel run.
+    pub tile_panels: usize,
+}
+
+impl LirMatMulTopk {
+    /// Replace the `Topk` node and the product computing its input by the fused op, with tiles
+    /// of about `tile` scores along the scored axis.
+    ///
+    /// Only plain f32 products are fused: a concrete output with no other non-trivial axis,
+    /// an operand on the scored axis packed ahead of time, and no other fused spec than the
+    /// final store.
+    pub(crate) fn fuse(
+        topk: &Topk,
+        model: &TypedModel,
+        node: &TypedNode,
+        tile: usize,
+    ) -> TractResult<Option<TypedModelPatch>> {
+        let prec = model.node(node.inputs[0].node);
+        let Some(mm) = prec.op_as::<LirMatMulUnary>() else { return Ok(None) };
+        if prec.outputs[0].successors.len() != 1
+            || model.output_outlets()?.contains(&node.inputs[0])
+            || mm.c_fact.datum_type != f32::datum_type()
+            || !mm.trivial_path
+            || mm.macro_tiles.is_some()
+            || mm.bounded_output.is_some()
+            || mm.in_place_input.is_some()
+        {
+            return Ok(None);
+        }
+        let m_scored = topk.axis == mm.c_m_axis;
+        if !m_scored && topk.axis != mm.c_n_axis {
+            return Ok(None);
+        }
+        if topk.k > mm.c_fact.shape[topk.axis].to_usize()? {
+            return Ok(None);
+        }
+        let [ProtoFusedSpec::AddMatMul(geo, _, _), ProtoFusedSpec::Store(_)] = &*mm.micro_ops
+        else {
+            return Ok(None);
+        };
+        let storage = if m_scored { &geo.a_storage } else { &geo.b_storage };
+        if matches!(storage, Some(InputStoreSpec::VirtualPacking { .. })) {
+            return Ok(None);
+        }
+        let panel = if m_scored { mm.mmm.mr() } else { mm.mmm.nr() };
+        let fused = LirMatMulTopk {
+            mm: mm.clone(),
+            topk: topk.clone(),
+            tile_panels: (tile / panel).max(1),
+        };
+        let mut patch = TypedModelPatch::new(format!("Fuse {} with {}", prec.name, node.name));
+        let inputs = prec
+            .inputs
+            .iter()
+            .map(|i| patch.tap_model(model, *i))
+            .collect::<TractResult<TVec<_>>>()?;
+        let outputs = patch.wire_node(&node.name, fused, &inputs)?;
+        for (ix, output) in outputs.into_iter().enumerate() {
+            patch.shunt_outside(model, OutletId::new(node.id, ix), output)?;
+        }
+        Ok(Some(patch))
+    }
+
+    /// Scores computed by a kernel run, along the scored axis.
+    pub fn tile_len(&self) -> usize {
+        let panel =
+            if self.topk.axis == self.mm.c_m_axis { self.mm.mmm.mr() } else { self.mm.mmm.nr() };
+        self.tile_panels * panel
+    }
+}
+
+impl Op for LirMatMulTopk {
+    fn name(&self) -> Cow<str> {
+        "LirMatMulTopk".into()
+    }
+
+    fn info(&self) -> TractResult<Vec<String>> {
+        let mut infos = self.mm.info()?;
+        infos.push(format!(
+            "{} {} along axis {}, by tiles of {}",
+            if self.topk.largest { "Largest" } else { "Smallest" },
+            self.topk.k,
+            self.topk.axis,
+            self.tile_len()
+        ));
+        Ok(infos)
+    }
+
+    op_as_typed_op!();
+}
+
+/// A score kept in a running top-k. The ordering is the one of `Topk`: best first, then the
+/// lowest index first.
+#[derive(Clone, Copy, Debug)]
+struct Candidate {
+    /// The score, negated when looking for the largest ones.
+    key: f32,
+    index: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Candidate) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Candidate) -> Ordering {
+        self.key.total_cmp(&other.key).then(self.index.cmp(&other.index))
+    }
+}
+
+/// The k best candidates offered so far, the worst of them on top.
+fn offer(best: &mut BinaryHeap<Candidate>, k: usize, candidate: Candidate) {
+    if best.len() < k {
+        best.push(candidate);
+    } else if best.peek().map(|worst| candidate < *worst).unwrap_or(false) {
+        best.pop();
+        best.push(candidate);
+    }
+}
+
+impl EvalOp for LirMatMulTopk {
+    fn is_stateless(&self) -> bool {
+        true
+    }
+
+    fn eval(&self, inputs: TVec<TValue>) -> TractResult<TVec<TValue>> {
+        let mm = &self.mm;
+        let [ProtoFusedSpec::AddMatMul(geo, a_ix, b_ix), ProtoFusedSpec::Store(store)] =
+            &*mm.micro_ops
+        else {
+            bail!("Top-k fused with a product with other specs than a store")
+        };
+        let c_shape: TVec<usize> =
+            mm.c_fact.shape.as_concrete().context("Symbolic top-k product")?.into();
+        let (m, n) = (c_shape[mm.c_m_axis], c_shape[mm.c_n_axis]);
+        let k = geo.k.to_usize()?;
+        let (a, b) = (&inputs[*a_ix], &inputs[*b_ix]);
+        let m_scored = self.topk.axis == mm.c_m_axis;
+        let (len, lines) = if m_scored { (m, n) } else { (n, m) };
+        unsafe {
+            let a_spec = geo
+                .a_storage
+                .clone()
+                .unwrap_or_else(|| mm.mmm.a_packed(a.datum_type().size_of(), k));
+            let b_spec = geo
+                .b_storage
+                .clone()
+                .unwrap_or_else(|| mm.mmm.b_packed(b.datum_type().size_of(), k));
+            let (tiled, tiled_spec) = if m_scored { (a, &a_spec) } else { (b, &b_spec) };
+            let &InputStoreSpec::Prepacked { panel_bytes } = tiled_spec else {
+                bail!("Top-k product tiled along an operand packed at run time")
+            };
+            let mut scratch = mm.mmm.allocate_scratch_space();
+            let mut best = vec![BinaryHeap::with_capacity(self.topk.k + 1); lines];
+            let mut tile_shape = c_shape.clone();
+            let panel = if m_scored { mm.mmm.mr() } else { mm.mmm.nr() };
+            let tile_len = self.tile_panels * panel;
+            for start in (0..len).step_by(tile_len) {
+                let width = tile_len.min(len - start);
+                tile_shape[self.topk.axis] = width;
+                let mut tile = Tensor::uninitialized::<f32>(&tile_shape)?;
+                let tiled = InputStore::Packed {
+                    ptr: tiled.as_ptr_unchecked::<u8>().add(start / panel * panel_bytes) as _,
+                    panel_bytes: panel_bytes as isize,
+                };
+                let (a, b) = if m_scored {
+                    (tiled, b_spec.wrap(&b.view()))
+                } else {
+                    (a_spec.wrap(&a.view()), tiled)
+                };
+                let specs = [
+                    FusedSpec::AddMatMul { k, a, b },
+                    FusedSpec::Store(store.wrap(&tile.view_mut())),
+                ];
+                let (tm, tn) = if m_scored { (width, n) } else { (m, width) };
+                mm.mmm.run_tiles(
+                    tm,
+                    tn,
+                    0..tm.divceil(mm.mmm.mr()),
+                    0..tn.divceil(mm.mmm.nr()),
+                    scratch.as_mut(),
+                    &specs,
+                )?;
+                let scores = tile.as_slice::<f32>()?;
+                let (score_stride, line_stride) = if m_scored {
+                    (tile.strides()[mm.c_m_axis], tile.strides()[mm.c_n_axis])
+                } else {
+                    (tile.strides()[mm.c_n_axis], tile.strides()[mm.c_m_axis])
+                };
+                for (line, best) in best.iter_mut().enumerate() {
+                    for ix in 0..width {
+                        let x = scores[line * line_stride as usize + ix * score_stride as usize];
+                        let key = if self.topk.largest { -x } else { x };
+                        offer(best, self.topk.k, Candidate { key, index: start + ix });
+                    }
+                }
+            }
+            let mut output_shape = c_shape;
+            output_shape[self.topk.axis] = self.topk.k;
+            let mut values = Tensor::zero::<f32>(&output_shape)?;
+            let mut indices = Tensor::zero::<i64>(&output_shape)?;
+            let strides = values.strides().to_vec();
+            let (rank_stride, line_stride) = if m_scored {
+                (strides[mm.c_m_axis], strides[mm.c_n_axis])
+            } else {
+                (strides[mm.c_n_axis], strides[mm.c_m_axis])
+            };
+            let values_slice = values.as_slice_mut::<f32>()?;
+            let indices_slice = indices.as_slice_mut::<i64>()?;
+            for (line, best) in best.into_iter().enumerate() {
+                for (rank, c) in best.into_sorted_vec().into_iter().enumerate() {
+                    let offset = line * line_stride as usize + rank * rank_stride as usize;
+                    values_slice[offset] = if self.topk.largest { -c.key } else { c.key };
+                    indices_slice[offset] = c.index as i64;
+                }
+            }
+            Ok(tvec!(values.into_tvalue(), indices.into_tvalue()))
+        }
+    }
+}
+
+impl TypedOp for LirMatMulTopk {
+    fn output_facts(&self, _inputs: &[&TypedFact]) -> TractResult<TVec<TypedFact>> {
+        self.topk.output_facts(&[&self.mm.c_fact])
+    }
+
+    fn cost(&self, inputs: &[&TypedFact]) -> TractResult<TVec<(Cost, TDim)>> {
+        self.mm.cost(inputs)
+    }
+
+    as_op!();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::einsum::EinSum;
+    use crate::optim::OptimizerOptions;
+    use tract_data::itertools::Itertools;
+
+    /// A [m, hidden] × [hidden, n] product followed by a top-k over n. The weight columns repeat
+    /// with a period of 37, so the scores have many exact duplicates.
+    fn classifier_head(m: usize, n: usize, k: usize, largest: bool) -> TractResult<TypedModel> {
+        let hidden = 24;
+        let mut model = TypedModel::default();
+        let x = model.add_source("x", f32::fact([m, hidden]))?;
+        let w =
+            (0..hidden * n).map(|i| ((i / n * 5 + i % n % 37 * 3) % 17) as f32 - 8.).collect_vec();
+        let w = model.add_const("w", Tensor::from_shape(&[hidden, n], &w)?)?;
+        let scores = model.wire_node(
+            "scores",
+            EinSum::new("mk,kn->mn".parse()?, f32::datum_type()),
+            &[x, w],
+        )?;
+        let top = model.wire_node("top", Topk { axis: 1, largest, k }, &scores)?;
+        model.set_output_outlets(&top)?;
+        Ok(model)
+    }
+
+    fn check_fused_top_k(m: usize, n: usize, k: usize, largest: bool) -> TractResult<()> {
+        let model = classifier_head(m, n, k, largest)?;
+        let options = OptimizerOptions { fused_top_k: Some(64), ..Default::default() };
+        let fused = model.clone().into_optimized_with_options(&options)?;
+        let unfused = model.into_optimized()?;
+        let op = fused
+            .nodes()
+            .iter()
+            .find_map(|n| n.op_as::<LirMatMulTopk>())
+            .context("No fused top-k")?;
+        ensure!(op.tile_len() < n);
+        for node in fused.nodes().iter().filter(|node| !node.op_is::<crate::ops::konst::Const>()) {
+            for output in &node.outputs {
+                let volume = output.fact.shape.volume().to_usize()?;
+                ensure!(volume < m * n, "{node} materializes the {volume} scores");
+            }
+        }
+        let x = (0..m * 24).map(|i| ((i * 7) % 5) as f32 - 2.).collect_vec();
+        let x = tvec!(Tensor::from_shape(&[m, 24], &x)?.into_tvalue());
+        let expected = unfused.into_runnable()?.run(x.clone())?;
+        let found = fused.into_runnable()?.run(x)?;
+        assert_eq!(found[0], expected[0]);
+        assert_eq!(found[1], expected[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn fused_top_k_of_single_row() -> TractResult<()> {
+        check_fused_top_k(1, 1000, 10, true)
+    }
+
+    #[test]
+    fn fused_top_k_of_batch() -> TractResult<()> {
+        check_fused_top_k(5, 777, 12, true)
+    }
+
+    #[test]
+    fn fused_top_k_along_kernel_n() -> TractResult<()> {
+        // more rows than scores: the operands are not swapped, the scores are the kernel n
+        check_fused_top_k(300, 100, 7, true)
+    }
+
+    #[test]
+    fn fused_smallest_k() -> TractResult<()> {
+        check_fused_top_k(3, 500, 40, false)
+    }
+
+    #[test]
+    fn top_k_not_fused_by_default() -> TractResult<()> {
+        let optimized = classifier_head(1, 300, 10, true)?.into_optimized()?;
+        assert!(optimized.nodes().iter().all(|n| !n.op_is::<LirMatMulTopk>()));
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+//! Constant weights packed by each plan state in the precision it picks.
+//!
+//! With `OptimizerOptions::weight_variants`, the constant f32 weights of the matrix products are
+//! kept once in the model, unpacked, as the A operand of their `LirMatMulUnary`. Each plan state
+//! packs them when it is created, in the precision of its `WeightPrecision` policy: in f32, or
+//! in f16, halving their footprint, the kernel expanding each f16 panel to f32 as it reaches it.
+//! States of the same model may so run with different precisions at the same time.
+use super::lir_unary::{LirMatMulUnary, ProtoFusedSpec};
+use super::pack::{MatMatMulPack, PackedConstantStorage};
+use crate::internal::*;
+use std::ops::Range;
+use tract_linalg::frame::Packer;
+use tract_linalg::mmm::{VirtualInput, VirtualInputSpec};
+
+/// Precision a plan state packs the weights of the matrix products in, when the model was
+/// optimized with `OptimizerOptions::weight_variants`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum WeightPrecision {
+    PreferF32,
+    PreferF16,
+    /// f16 on ARM, where memory is usually the constraint, f32 elsewhere.
+    #[default]
+    Auto,
+}
+
+impl WeightPrecision {
+    pub fn datum_type(&self) -> DatumType {
+        match self {
+            WeightPrecision::PreferF32 => f32::datum_type(),
+            WeightPrecision::PreferF16 => f16::datum_type(),
+            WeightPrecision::Auto if cfg!(any(target_arch = "arm", target_arch = "aarch64")) => {
+                f16::datum_type()
+            }
+            WeightPrecision::Auto => f32::datum_type(),
+        }
+    }
+}
+
+/// The constant weights of a `LirMatMulUnary`: its input #0, unpacked, in f32.
+#[derive(Clone, Debug)]
+pub struct WeightVariants {
+    pub master: Arc<Tensor>,
+    pub k_axis: usize,
+    pub mn_axis: usize,
+}
+
+/// The op of a state, running on the weights packed in its precision.
+#[derive(Clone, Debug)]
+pub(crate) struct WeightVariant {
+    pub op: LirMatMulUnary,
+    pub weights: Arc<Tensor>,
+}
+
+impl WeightVariants {
+    /// Pack the weights in `dt`, and specialize `op` for them.
+    pub(crate) fn materialize(
+        &self,
+        op: &LirMatMulUnary,
+        dt: DatumType,
+    ) -> TractResult<WeightVariant> {
+        ensure!(
+            dt == f32::datum_type() || dt == f16::datum_type(),
+            "Weights can not be packed in {dt:?}"
+        );
+        let k = self.master.shape()[self.k_axis];
+        let pack = MatMatMulPack::new(op.mmm.a_pack(), self.k_axis, self.mn_axis, 2)?;
+        let weights = self.master.cast_to_dt(dt)?;
+        let weights = pack.pack(&weights, &PackedConstantStorage::Heap)?.into_arc_tensor();
+        let mut micro_ops = op.micro_ops.clone();
+        for uop in &mut micro_ops {
+            if let ProtoFusedSpec::AddMatMul(geo, 0, _) = uop {
+                geo.a_storage = if dt == f16::datum_type() {
+                    Some(unsafe { op.mmm.a_virtual_input(Box::new(F16PanelsSpec), k) })
+                } else {
+                    None
+                };
+            }
+        }
+        let op = LirMatMulUnary { micro_ops, weight_variants: None, ..op.clone() };
+        Ok(WeightVariant { op, weights })
+    }
+}
+
+/// Expands the panels of weights packed in f16 to f32.
+#[derive(Clone, Debug)]
+struct F16PanelsSpec;
+
+impl VirtualInputSpec for F16PanelsSpec {
+    fn wrap(&self, view: &TensorView) -> Box<dyn VirtualInput> {
+        Box::new(F16Panels(unsafe { view.as_ptr_unchecked::<f16>() }))
+    }
+
+    fn packed_datum_type(&self, _input: DatumType) -> DatumType {
+        f32::datum_type()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct F16Panels(*const f16);
+
+unsafe impl Send for F16Panels {}
+unsafe impl Sync for F16Panels {}
+
+impl VirtualInput for F16Panels {
+    fn input(&self, packer: &Packer, packed: *mut u8, k: Range<usize>, mn: Range<usize>) {
+        debug_assert_eq!(k.start, 0);
+        let len = packer.single_panel_len(k.end);
+        unsafe {
+            let panel = self.0.add(mn.start / packer.r * len);
+            let packed = packed as *mut f32;
+            for ix in 0..len {
+                *packed.add(ix) = (*panel.add(ix)).to_f32();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::einsum::EinSum;
+    use crate::optim::OptimizerOptions;
+
+    fn model() -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let w = (0..64 * 48).map(|i| ((i * 7) % 31) as f32 / 31. - 0.5).collect::<Vec<_>>();
+        let w = model.add_const("w", Tensor::from_shape(&[64, 48], &w)?)?;
+        let x = model.add_source("x", f32::fact([48, 4]))?;
+        let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let y = model.wire_node("y", einsum, &[w, x])?;
+        model.set_output_outlets(&y)?;
+        Ok(model)
+    }
+
+    #[test]
+    fn states_with_different_precisions() -> TractResult<()> {
+        let model = model()?;
+        let options = OptimizerOptions { weight_variants: true, ..OptimizerOptions::default() };
+        let optimized = model.clone().into_optimized_with_options(&options)?;
+        let lir = optimized.nodes().iter().find_map(|n| n.op_as::<LirMatMulUnary>()).unwrap();
+        assert!(lir.weight_variants.is_some());
+        let plan = SimplePlan::new(optimized)?;
+        let mut f32_state =
+            SimpleState::new_with_weight_precision(&plan, WeightPrecision::PreferF32)?;
+        let mut f16_state =
+            SimpleState::new_with_weight_precision(&plan, WeightPrecision::PreferF16)?;
+        // the packed weights may be padded up to a multiple of the kernel rows
+        assert!(f32_state.memory_footprint() >= 64 * 48 * 4);
+        assert_eq!(f16_state.memory_footprint() * 2, f32_state.memory_footprint());
+
+        let x = (0..48 * 4).map(|i| ((i * 5) % 17) as f32 / 4. - 2.).collect::<Vec<_>>();
+        let x = Tensor::from_shape(&[48, 4], &x)?;
+        let reference = model.into_runnable()?.run(tvec!(x.clone().into_tvalue()))?.remove(0);
+        let reference = reference.as_slice::<f32>()?;
+        let error = |found: TValue| -> TractResult<f32> {
+            let scale = reference.iter().fold(0f32, |m, x| m.max(x.abs()));
+            let found = found.as_slice::<f32>()?;
+            Ok(found.iter().zip(reference).map(|(f, r)| (f - r).abs() / scale).fold(0., f32::max))
+        };
+        let f16_error = error(f16_state.run(tvec!(x.clone().into_tvalue()))?.remove(0))?;
+        assert!(f16_error > 1e-5 && f16_error < 1e-2, "{f16_error}");
+        // the states run independently, each with its own weights
+        assert!(error(f32_state.run(tvec!(x.clone().into_tvalue()))?.remove(0))? < 1e-6);
+        assert_eq!(error(f16_state.run(tvec!(x.into_tvalue()))?.remove(0))?, f16_error);
+        Ok(())
+    }
+
+    #[test]
+    fn off_by_default() -> TractResult<()> {
+        let optimized = model()?.into_optimized()?;
+        let lir = optimized.nodes().iter().find_map(|n| n.op_as::<LirMatMulUnary>()).unwrap();
+        assert!(lir.weight_variants.is_none());
+        let plan = SimplePlan::new(optimized)?;
+        assert_eq!(SimpleState::new(&plan)?.memory_footprint(), 0);
+        Ok(())
+    }
+}
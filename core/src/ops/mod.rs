@@ -85,6 +85,37 @@ pub trait OpState: fmt::Debug + dyn_clone::DynClone + OpStateFreeze + Downcast {
         op: &dyn Op,
         inputs: TVec<TValue>,
     ) -> TractResult<TVec<TValue>>;
+
+    /// Layout of the scratch buffer the op needs at most to run with the `symbols` values, or
+    /// None if it needs none or the symbols do not size it yet. The plan state allocates one
+    /// arena for the largest declaration and lends it to `eval_with_scratch`.
+    #[allow(unused_variables)]
+    fn scratch_layout(
+        &self,
+        op: &dyn Op,
+        symbols: &SymbolValues,
+    ) -> TractResult<Option<std::alloc::Layout>> {
+        Ok(None)
+    }
+
+    /// Bytes of the data the state derived from the model when it was created, like weights it
+    /// packed. Buffers reused across runs are not counted.
+    fn memory_footprint(&self) -> usize {
+        0
+    }
+
+    /// Evaluate in the `scratch` buffer, lent by the caller: it may be smaller than declared by
+    /// `scratch_layout`, even empty, when the caller has no arena.
+    #[allow(unused_variables)]
+    fn eval_with_scratch(
+        &mut self,
+        session: &mut SessionState,
+        op: &dyn Op,
+        inputs: TVec<TValue>,
+        scratch: &mut [u8],
+    ) -> TractResult<TVec<TValue>> {
+        self.eval(session, op, inputs)
+    }
 }
 dyn_clone::clone_trait_object!(OpState);
 impl_downcast!(OpState);
@@ -105,6 +136,13 @@ pub trait EvalOp {
     }
 
     fn is_stateless(&self) -> bool;
+
+    /// Input slot whose buffer the op may compute its output in, when it is the last consumer of
+    /// this input and both have the same type and shape (see `crate::plan::IN_PLACE_INPUT`).
+    /// Fails on a configuration where the output would overwrite data the op still has to read.
+    fn in_place_input(&self) -> TractResult<Option<usize>> {
+        Ok(None)
+    }
 }
 
 /// A base operation
@@ -212,6 +250,21 @@ pub trait TypedOp:
         Ok(None)
     }
 
+    /// Wire in `patch` the node computing only some rows of its output along `output_axis`,
+    /// the ones indexed by `rows` (a 1D tensor of the patch), by gathering them from its inputs
+    /// (see `TypedModel::with_output_rows`). None if the op can only compute its whole output.
+    #[allow(unused_variables)]
+    fn select_rows(
+        &self,
+        patch: &mut TypedModelPatch,
+        model: &TypedModel,
+        node: &TypedNode,
+        output_axis: usize,
+        rows: OutletId,
+    ) -> TractResult<Option<TVec<OutletId>>> {
+        Ok(None)
+    }
+
     /// Transforms the op in an equivalent one, operating on dt (i8 or u8).
     ///
     /// Returns None if the op can not be translated.
@@ -241,6 +294,17 @@ pub trait TypedOp:
         target.wire_node(&node.name, node.op.clone(), &inputs)
     }
 
+    /// Translate the op into the most efficient form possible for execution.
+    #[allow(unused_variables)]
+    fn codegen_with_session(
+        &self,
+        session: &mut OptimizerSession,
+        model: &TypedModel,
+        node: &TypedNode,
+    ) -> TractResult<Option<TypedModelPatch>> {
+        self.codegen(model, node)
+    }
+
     /// Translate the op into the most efficient form possible for execution.
     ///
     /// This transformation is supposed to be final, no more pass are expected
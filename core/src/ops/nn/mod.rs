@@ -3,7 +3,7 @@ mod reduce;
 mod softmax;
 
 pub use self::data_formats::{BaseDataShape, DataFormat, DataShape, SymDataShape};
-pub use self::reduce::{Reduce, Reducer};
+pub use self::reduce::{IntegerSum, Reduce, Reducer};
 pub use self::softmax::Softmax;
 
 pub use crate::internal::*;
@@ -21,3 +21,12 @@ element_wise!(hard_swish, HardSwish,
 element_wise!(leaky_relu, LeakyRelu { alpha: f32 },
  [f32] => |op, xs| { xs.iter_mut().for_each(|x| *x *= if *x < 0. { op.alpha } else { 1.0 }); Ok(()) }
 );
+
+/// Tanh approximation of Gelu.
+pub fn gelu_approximate_f32(x: f32) -> f32 {
+    0.5 * x * (1. + ((2. / std::f32::consts::PI).sqrt() * (x + 0.044715 * x * x * x)).tanh())
+}
+
+element_wise!(gelu_approximate, GeluApproximate,
+ [f32] => |_, xs| { xs.iter_mut().for_each(|x| *x = gelu_approximate_f32(*x)); Ok(()) }
+);
@@ -4,7 +4,7 @@ use std::convert::TryFrom;
 use tract_data::internal::ClampCast;
 use tract_data::itertools::Itertools;
 use tract_ndarray::prelude::*;
-use tract_num_traits::Bounded;
+use tract_num_traits::{AsPrimitive, Bounded};
 
 macro_rules! r {
     ($($path:ident)::* ($dt:expr) ($($args:expr),*)) => {
@@ -334,3 +334,96 @@ impl TypedOp for Reduce {
 
     as_op!();
 }
+
+/// Sum of small integers (i8, u8, i16, u16) over `axes`, accumulated in i32 without a widened
+/// copy of the input, keeping reduced axes with dimension 1.
+#[derive(Clone, Debug, new, Hash)]
+pub struct IntegerSum {
+    pub axes: TVec<usize>,
+}
+
+impl IntegerSum {
+    fn eval_t<T: Datum + AsPrimitive<i32>>(&self, input: &Tensor) -> TractResult<Tensor> {
+        let input = input.to_array_view::<T>()?;
+        let Some((&first, others)) = self.axes.split_first() else {
+            return Ok(input.mapv(|x| x.as_()).into_tensor());
+        };
+        let mut sum =
+            input.fold_axis(Axis(first), 0i32, |acc, x| acc + x.as_()).insert_axis(Axis(first));
+        for &axis in others {
+            sum = sum.sum_axis(Axis(axis)).insert_axis(Axis(axis));
+        }
+        Ok(sum.into_tensor())
+    }
+}
+
+impl Op for IntegerSum {
+    fn name(&self) -> Cow<str> {
+        "IntegerSum".into()
+    }
+    fn info(&self) -> TractResult<Vec<String>> {
+        Ok(vec![format!("axes: {:?}", self.axes)])
+    }
+    op_as_typed_op!();
+}
+
+impl EvalOp for IntegerSum {
+    fn is_stateless(&self) -> bool {
+        true
+    }
+
+    fn eval(&self, inputs: TVec<TValue>) -> TractResult<TVec<TValue>> {
+        let input = &inputs[0];
+        let sum = match input.datum_type().unquantized() {
+            DatumType::I8 => self.eval_t::<i8>(input)?,
+            DatumType::U8 => self.eval_t::<u8>(input)?,
+            DatumType::I16 => self.eval_t::<i16>(input)?,
+            DatumType::U16 => self.eval_t::<u16>(input)?,
+            DatumType::I32 => self.eval_t::<i32>(input)?,
+            dt => bail!("IntegerSum does not support {dt:?}"),
+        };
+        Ok(tvec!(sum.into_tvalue()))
+    }
+}
+
+impl TypedOp for IntegerSum {
+    fn output_facts(&self, inputs: &[&TypedFact]) -> TractResult<TVec<TypedFact>> {
+        ensure!(self.axes.iter().tuple_windows().all(|(a, b)| a < b));
+        ensure!(inputs[0].datum_type.is_integer() && inputs[0].datum_type.size_of() <= 4);
+        let mut shape: TVec<_> = inputs[0].shape.to_tvec();
+        for &ax in &self.axes {
+            shape[ax] = 1.to_dim();
+        }
+        Ok(tvec!(i32::fact(shape)))
+    }
+
+    fn axes_mapping(
+        &self,
+        inputs: &[&TypedFact],
+        outputs: &[&TypedFact],
+    ) -> TractResult<AxesMapping> {
+        Reduce::new(self.axes.clone(), Reducer::Sum).axes_mapping(inputs, outputs)
+    }
+
+    fn change_axes(
+        &self,
+        model: &TypedModel,
+        node: &TypedNode,
+        _io: InOut,
+        change: &AxisOp,
+    ) -> TractResult<Option<AxisChangeConsequence>> {
+        let mut axes = tvec!();
+        for reduced in &self.axes {
+            if let Some(axis) = change.transform_axis(*reduced) {
+                axes.push(axis);
+            } else {
+                return Ok(None);
+            }
+        }
+        axes.sort();
+        let op = Some(Box::new(Self { axes }) as _);
+        Ok(Some(AxisChangeConsequence::new(model, node, op, change)))
+    }
+
+    as_op!();
+}
@@ -69,8 +69,8 @@ fn info_quantize_linear_i8(q: &QuantizeLinearI8) -> TractResult<Vec<String>> {
 
 #[derive(Clone, Debug, new)]
 pub struct DequantizeLinearF32 {
-    scale: f32,
-    zero_point: i32,
+    pub scale: f32,
+    pub zero_point: i32,
 }
 
 impl DequantizeLinearF32 {
@@ -446,11 +446,8 @@ pub mod scale {
         let b_scale = model.add_const("b_scale", tensor0(1f32)).unwrap();
         let c0 = model.add_const("c0", tensor0(0i8)).unwrap();
         let c_scale = model.add_const("c_scale", tensor0(scale)).unwrap();
-        let op = EinSum {
-            axes: "mk,kn,,,,,,,->mn".parse().unwrap(),
-            operating_dt: i32::datum_type(),
-            q_params: Some(i8::datum_type()),
-        };
+        let op =
+            EinSum::newq("mk,kn,,,,,,,->mn".parse().unwrap(), i32::datum_type(), i8::datum_type());
         let output = model.wire_node("mmm", op, &[a, b, bias, a0, a_scale, b0, b_scale, c0, c_scale]).unwrap();
         model.set_output_outlets(&output).unwrap();
 
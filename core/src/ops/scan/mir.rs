@@ -1,6 +1,6 @@
 use crate::ops::einsum::EinSum;
 use crate::ops::konst::Const;
-use crate::optim::OptimizerSession;
+use crate::optim::{Optimizer, OptimizerSession};
 
 use super::lir::{LirScan, LirScanOpParams};
 use tract_data::internal::*;
@@ -22,7 +22,11 @@ impl Scan {
         if optimize_inner {
             model = model.into_optimized()?;
         }
-        let plan = SimplePlan::new(model)?;
+        self.to_codegen_op_with_body(model)
+    }
+
+    fn to_codegen_op_with_body(&self, body: TypedModel) -> TractResult<LirScan> {
+        let plan = SimplePlan::new(body)?;
 
         Ok(LirScan::new(Arc::new(LirScanOpParams::new(
             self.skip,
@@ -855,6 +859,33 @@ impl TypedOp for Scan {
         target.wire_node(&node.name, op, &inputs)
     }
 
+    /// The body goes through the same declutter and codegen pipeline as the outer model, with
+    /// the same options. The outcomes of the rules on its nodes are recorded in the outer
+    /// session report, under the name of the scan node.
+    fn codegen_with_session(
+        &self,
+        session: &mut OptimizerSession,
+        model: &TypedModel,
+        node: &TypedNode,
+    ) -> TractResult<Option<TypedModelPatch>> {
+        let mut body = self.body.clone();
+        let options = session.options().clone();
+        Optimizer::declutter().with_options(options.clone()).optimize(&mut body)?;
+        let optimizer = Optimizer::codegen().with_options(options);
+        let mut body_session = optimizer.session();
+        body_session.optimize(&mut body).with_context(|| format!("Optimizing body of {node}"))?;
+        for record in body_session.into_report().records {
+            let name = format!("{}.{}", node.name, record.node);
+            session.report_mut().record(&record.rule, &name, record.outcome);
+        }
+        Ok(Some(TypedModelPatch::replace_single_op(
+            model,
+            node,
+            &node.inputs,
+            self.to_codegen_op_with_body(body)?,
+        )?))
+    }
+
     fn codegen(
         &self,
         model: &TypedModel,
@@ -868,3 +899,67 @@ impl TypedOp for Scan {
         )?))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::math::add;
+    use crate::ops::matmul::lir_unary::LirMatMulUnary;
+    use crate::optim::OptimizerOptions;
+
+    /// A recurrent cell, h <- (x + h)·w, over chunks of 64 rows of x.
+    fn recurrent_model() -> TractResult<TypedModel> {
+        let (m, d) = (64, 256);
+        let mut body = TypedModel::default();
+        let x = body.add_source("x", f32::fact([m, d]))?;
+        let h = body.add_source("h", f32::fact([m, d]))?;
+        let w = (0..d * d).map(|i| ((i * 7) % 11) as f32 / 64. - 0.08).collect::<Vec<_>>();
+        let w = body.add_const("w", Tensor::from_shape(&[d, d], &w)?)?;
+        let sum = body.wire_node("sum", add(), &[x, h])?;
+        let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let h = body.wire_node("cell", op, &[sum[0], w])?;
+        body.set_output_outlets(&h)?;
+        let chunk = ScanInfo { axis: 0, chunk: m as isize };
+        let input_mapping = vec![InputMapping::Scan(chunk), InputMapping::State];
+        let output_mapping = vec![OutputMapping {
+            scan: Some((0, chunk)),
+            full_dim_hint: None,
+            last_value_slot: None,
+            state: true,
+        }];
+        let scan = Scan::new(body, input_mapping, output_mapping, 0)?;
+
+        let mut model = TypedModel::default();
+        let x = model.add_source("x", f32::fact([4 * m, d]))?;
+        let h0 = model.add_const("h0", Tensor::zero::<f32>(&[m, d])?)?;
+        let y = model.wire_node("scan", scan, &[x, h0])?;
+        model.set_output_outlets(&y)?;
+        Ok(model)
+    }
+
+    #[test]
+    fn body_einsum_is_lowered() -> TractResult<()> {
+        let model = recurrent_model()?;
+        let optimized = model.clone().into_optimized()?;
+        let scan = optimized.nodes().iter().find_map(|n| n.op_as::<LirScan>()).unwrap();
+        assert!(scan.plan.model().nodes().iter().any(|n| n.op_is::<LirMatMulUnary>()));
+
+        let len = 4 * 64 * 256;
+        let x = (0..len).map(|i| ((i * 5) % 13) as f32 / 8. - 0.75).collect::<Vec<_>>();
+        let x = Tensor::from_shape(&[4 * 64, 256], &x)?;
+        let expected = model.into_runnable()?.run(tvec!(x.clone().into_tvalue()))?;
+        let found = optimized.into_runnable()?.run(tvec!(x.into_tvalue()))?;
+        found[0].close_enough(&expected[0], Approximation::Approximate)
+    }
+
+    #[test]
+    fn body_rules_are_reported() -> TractResult<()> {
+        let options = OptimizerOptions::default();
+        let (_, report) = recurrent_model()?.into_optimized_with_report(&options)?;
+        assert!(report
+            .records
+            .iter()
+            .any(|r| r.rule == "einsum codegen" && r.node.starts_with("scan.")));
+        Ok(())
+    }
+}
@@ -78,7 +78,8 @@ impl TypedOp for TypedSource {
         values: &SymbolValues,
     ) -> TractResult<TVec<OutletId>> {
         let shape: TVec<_> = self.fact.shape.iter().map(|d| d.eval(values)).collect();
-        target.wire_node(&node.name, Self { fact: self.fact.datum_type.fact(&*shape) }, &[])
+        let fact = TypedFact { role: self.fact.role, ..self.fact.datum_type.fact(&*shape) };
+        target.wire_node(&node.name, Self { fact }, &[])
     }
 
     as_op!();
@@ -1,4 +1,5 @@
 use crate::internal::*;
+use crate::ops::matmul::pack::{PackedConstantStorage, PackedWeightCache};
 use std::collections::HashSet;
 use std::fmt::Debug;
 use tract_itertools::Itertools;
@@ -7,11 +8,13 @@ pub mod change_axes;
 mod op_optim;
 mod prop_const;
 mod push_split_down;
+pub mod report;
 mod slice;
 
 use self::change_axes::ChangeAxes;
 use self::prop_const::PropConst;
 use self::push_split_down::PushSplitDown;
+use self::report::OptimizationReport;
 use self::slice::PushSliceUp;
 use op_optim::OpOptim;
 
@@ -26,15 +29,90 @@ pub trait TypedPass: Debug + Send + Sync + dyn_clone::DynClone {
 
 dyn_clone::clone_trait_object!(TypedPass);
 
+/// Settings exposed to the ops through the optimizer session.
+#[derive(Clone, Debug, Default)]
+pub struct OptimizerOptions {
+    /// Allocation of the packed forms of constant matrix multiplication operands.
+    pub packed_constants: PackedConstantStorage,
+    /// Share the packed constants with the other models optimized with the same cache.
+    pub packed_weight_cache: Option<Arc<PackedWeightCache>>,
+    /// Delegate the einsums with at least this many multiply-adds per matrix product (m·k·n)
+    /// to the registered external GEMM backend (see `ops::matmul::external`).
+    pub external_gemm_threshold: Option<usize>,
+    /// Upper bounds of the model symbols. Matrix multiplications whose output depends on
+    /// bounded symbols allocate their worst case buffers with the model state, instead of on
+    /// each run, and fail on values beyond the bounds.
+    pub symbol_bounds: SymbolValues,
+    /// Record in the model the labels of the patches creating or rewiring each node (see
+    /// `Graph::node_patches`).
+    pub track_patches: bool,
+    /// Split the contraction of the float einsums with a long k in partial products over
+    /// k chunks, summed by a tree of additions (see `ops::einsum::KSplit`).
+    pub k_split: Option<crate::ops::einsum::KSplit>,
+    /// Thresholds above which matrix products pack their operands by cache sized blocks in
+    /// the kernel op instead of whole (see `ops::matmul::tiling`).
+    pub macro_tiling: crate::ops::matmul::tiling::MacroTiling,
+    /// Order of the loops over the prefix axes of the products crossing independent batch axes
+    /// of both operands (see `ops::matmul::prefix_loops`).
+    pub prefix_loops: crate::ops::matmul::prefix_loops::PrefixLoopOrder,
+    /// Pack the operands of the products with a small, known or bounded, k with zero padding
+    /// up to a fixed k (see `ops::matmul::pack::KPadding`).
+    pub k_padding: Option<crate::ops::matmul::pack::KPadding>,
+    /// Feed the non constant float B operands of the products to the kernel strided instead of
+    /// packing them in a pack node (see `ops::matmul::strided`).
+    pub b_packing: crate::ops::matmul::strided::BPacking,
+    /// Largest output, in bytes, of a node with constant inputs evaluated to a constant by the
+    /// optimizer (like an einsum of constants, in any of its float or quantized forms). Larger
+    /// outputs are left to be computed at run time instead of being embedded in the model.
+    /// Nodes wired on constants in the first place are evaluated by `wire_node`, whatever their
+    /// size.
+    pub const_fold_max_bytes: Option<usize>,
+    /// Fuse the matrix products followed by a `Topk` over one of their output axes, computing
+    /// the scores by tiles of about this many along the axis and keeping only the running top-k
+    /// (see `ops::matmul::top_k`).
+    pub fused_top_k: Option<usize>,
+    /// Compute the large batches of tiny float products across the batch instead of running a
+    /// product kernel on each of them (see `ops::matmul::tiny_batch`).
+    pub tiny_batch: crate::ops::matmul::tiny_batch::TinyBatch,
+    /// Keep the constant f32 weights of the matrix products unpacked, for each plan state to
+    /// pack them in the precision of its policy (see `ops::matmul::weight_variants`).
+    pub weight_variants: bool,
+    /// Force the kernel of the f32 einsum products, by name, among
+    /// `tract_linalg::ops().mmm_f32_impls()`, instead of the one linalg picks for their shape.
+    pub mmm_f32_kernel: Option<String>,
+    /// Expose the i32 accumulator of each quantized einsum as an extra model output, and keep it
+    /// from being fused in the requantization (see `ops::einsum::calibration`).
+    pub calibration_taps: bool,
+    /// Batch the float einsums applying the same constant operand to independent inputs of the
+    /// same shape into a single product over the stacked inputs (see `ops::einsum`).
+    pub batch_shared_constant_einsums: bool,
+    /// Fuse the float einsums applying different constant weights to the same input in a single
+    /// product against the concatenated weights (see `ops::einsum::FuseSharedInputs`).
+    pub fuse_shared_inputs: crate::ops::einsum::FuseSharedInputs,
+}
+
+impl OptimizerOptions {
+    /// Do the outputs of the node hold in `const_fold_max_bytes` ? Outputs of an unknown size
+    /// can not be evaluated anyway.
+    pub fn const_foldable(&self, node: &TypedNode) -> bool {
+        let Some(max_bytes) = self.const_fold_max_bytes else { return true };
+        node.outputs.iter().all(|o| {
+            let len = o.fact.shape.volume().to_usize().unwrap_or(0);
+            len.saturating_mul(o.fact.datum_type.size_of()) <= max_bytes
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct Optimizer {
     pub passes: Vec<Box<dyn TypedPass>>,
     pub steps: Option<usize>,
+    pub options: OptimizerOptions,
 }
 
 impl Optimizer {
     fn passes(passes: Vec<Box<dyn TypedPass>>) -> Optimizer {
-        Optimizer { passes, steps: None }
+        Optimizer { passes, steps: None, options: OptimizerOptions::default() }
     }
 
     pub fn add_pass(&mut self, idx: usize, pass: Box<dyn TypedPass>) {
@@ -51,6 +129,10 @@ impl Optimizer {
         Optimizer { steps: Some(steps), ..self }
     }
 
+    pub fn with_options(self, options: OptimizerOptions) -> Optimizer {
+        Optimizer { options, ..self }
+    }
+
     pub fn declutter() -> Optimizer {
         Optimizer::passes(vec![
             Box::new(PropConst),
@@ -64,11 +146,7 @@ impl Optimizer {
     pub fn codegen() -> Optimizer {
         Optimizer::passes(vec![
             Box::new(PropConst),
-            Box::new(OpOptim(
-                "codegen",
-                |op, _session, model, node| TypedOp::codegen(op, model, node),
-                0,
-            )),
+            Box::new(OpOptim("codegen", TypedOp::codegen_with_session, 0)),
             Box::new(OpOptim("declutter", TypedOp::declutter_with_session, 0)),
             Box::new(PushSplitDown),
             Box::new(OpOptim(
@@ -84,7 +162,12 @@ impl Optimizer {
     }
 
     pub fn session(&self) -> OptimizerSession {
-        OptimizerSession { optimizer: self, counter: 0, seen: Default::default() }
+        OptimizerSession {
+            optimizer: self,
+            counter: 0,
+            seen: Default::default(),
+            report: Default::default(),
+        }
     }
 }
 
@@ -93,11 +176,32 @@ pub struct OptimizerSession<'o> {
     optimizer: &'o Optimizer,
     counter: usize,
     seen: HashSet<String>,
+    report: OptimizationReport,
 }
 
 impl<'o> OptimizerSession<'o> {
+    pub fn options(&self) -> &OptimizerOptions {
+        &self.optimizer.options
+    }
+
+    /// Outcomes of the rules run so far.
+    pub fn report(&self) -> &OptimizationReport {
+        &self.report
+    }
+
+    pub fn report_mut(&mut self) -> &mut OptimizationReport {
+        &mut self.report
+    }
+
+    pub fn into_report(self) -> OptimizationReport {
+        self.report
+    }
+
     pub fn optimize(&mut self, model: &mut TypedModel) -> TractResult<()> {
         model.check_consistency().context("during optimizer preflight check")?;
+        if self.options().track_patches && model.node_patches.is_none() {
+            model.node_patches = Some(HashMap::default());
+        }
         model.compact().context("during optimizer preflight compaction")?;
         for i in 0.. {
             let old = self.counter;
@@ -168,6 +272,9 @@ impl<'o> OptimizerSession<'o> {
             }
             debug!("applying patch #{}: {}", self.counter, patch.context.iter().rev().join(" >> "),);
             patch.apply(model)?;
+            if self.options().calibration_taps {
+                crate::ops::einsum::calibration::register_taps(model)?;
+            }
             model
                 .check_consistency()
                 .context("Checking target model consistency after patching")?;
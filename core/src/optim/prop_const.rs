@@ -1,7 +1,8 @@
 use tract_data::UndeterminedSymbol;
 
 use crate::internal::*;
-use crate::ops::konst::Const;
+use crate::ops::konst::{record_digest, Const};
+use crate::ops::matmul::pack::MatMatMulPack;
 use crate::optim::OptimizerSession;
 
 #[derive(Clone, Debug)]
@@ -13,7 +14,7 @@ impl super::TypedPass for PropConst {
     }
     fn next(
         &mut self,
-        _session: &mut OptimizerSession,
+        session: &mut OptimizerSession,
         model: &TypedModel,
     ) -> TractResult<Option<TypedModelPatch>> {
         let mut patch = TypedModelPatch::default();
@@ -25,13 +26,21 @@ impl super::TypedPass for PropConst {
                 let wire = patch.add_const(&node.name, k)?;
                 patch.shunt_outside(model, node.id.into(), wire)?;
             }
-            if node.op.is_stateless() && !node.op_is::<Const>() {
+            if node.op.is_stateless()
+                && !node.op_is::<Const>()
+                && session.options().const_foldable(node)
+            {
                 if let Some(inputs) = model
                     .node_input_facts(n)?
                     .iter()
                     .map(|f| f.konst.clone().map(|t| t.into_tvalue()))
-                    .collect()
+                    .collect::<Option<TVec<_>>>()
                 {
+                    // a packed matmul constant is digested as it was before packing
+                    let unpacked = node
+                        .op_as::<MatMatMulPack>()
+                        .filter(|pack| pack.mask_axis.is_none())
+                        .map(|_| inputs[0].clone());
                     match node.op.eval(inputs) {
                         Ok(res) => {
                             for (ix, output) in res.into_iter().enumerate() {
@@ -40,6 +49,9 @@ impl super::TypedPass for PropConst {
                                     name = format!("{name}.{ix}");
                                 }
                                 let wire = patch.add_const(name, output.into_arc_tensor())?;
+                                if let Some(unpacked) = &unpacked {
+                                    record_digest(&mut patch, wire.node, unpacked)?;
+                                }
                                 patch.shunt_outside(model, (n, ix).into(), wire)?;
                             }
                         }
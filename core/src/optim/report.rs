@@ -0,0 +1,182 @@
+//! Outcomes of the optimization rules, collected by the optimizer session (see
+//! `TypedModel::into_optimized_with_report`).
+use crate::internal::*;
+use crate::ops::einsum::EinSum;
+use crate::ops::matmul::lir_unary::{LirMatMulUnary, ProtoFusedSpec};
+use std::collections::HashSet;
+use std::fmt;
+
+/// Why a rule left a node as it is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DeclineReason {
+    /// An einsum without two operands (nine for a quantized one).
+    OperandCount,
+    /// Input ranks not matching the einsum expression.
+    RankMismatch,
+    /// A label repeated in an operand, taking its diagonal.
+    Diagonal,
+    /// A quantized einsum summing an axis of a single operand.
+    QuantizedReduction,
+    /// Several contracted axes that can not be merged in a single k axis, as they are not
+    /// consecutive and in the same order in both operands.
+    MultipleKAxes,
+    /// Several contracted axes with symbolic dimensions.
+    SymbolicKAxes,
+    /// No matrix multiplication kernel for the operand and accumulator types.
+    UnsupportedDatumType,
+    /// Constant operands, with an output too large to be folded: the rewritten einsum would be
+    /// evaluated when wired.
+    LargeConstant,
+    /// A missing k, m or n axis could not be injected.
+    AxisInjection,
+}
+
+impl fmt::Display for DeclineReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            DeclineReason::OperandCount => "unexpected operand count",
+            DeclineReason::RankMismatch => "input ranks do not match the expression",
+            DeclineReason::Diagonal => "diagonal of an operand",
+            DeclineReason::QuantizedReduction => "quantized sum over an axis of a single operand",
+            DeclineReason::MultipleKAxes => "multiple k axes that can not be merged",
+            DeclineReason::SymbolicKAxes => "multiple k axes with symbolic dimensions",
+            DeclineReason::UnsupportedDatumType => "no kernel for the datum types",
+            DeclineReason::LargeConstant => "constant operands, output too large to be folded",
+            DeclineReason::AxisInjection => "could not inject a missing k, m or n axis",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// What a rule did to a node, the last time it ran on it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RuleOutcome {
+    /// The node was rewritten by a patch, with this context.
+    Patched(String),
+    Declined(DeclineReason),
+}
+
+/// Outcome of a rule on a node.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RuleRecord {
+    pub rule: String,
+    pub node: String,
+    pub outcome: RuleOutcome,
+}
+
+/// Summary of the optimization of a model.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OptimizationReport {
+    /// EinSum nodes of the decluttered model, before codegen.
+    pub einsums: usize,
+    /// LirMatMulUnary nodes of the optimized model.
+    pub lowered: usize,
+    /// EinSum nodes left in the optimized model, with the reason of their last decline.
+    pub declined: Vec<(String, DeclineReason)>,
+    /// Size of the packed constant matrix multiplication operands of the optimized model.
+    pub packed_constant_bytes: usize,
+    /// Last outcome of each rule on each node it ran on, in order of first run.
+    pub records: Vec<RuleRecord>,
+}
+
+impl OptimizationReport {
+    /// Record the outcome of `rule` on `node`, replacing the previous one.
+    pub fn record(&mut self, rule: &str, node: &str, outcome: RuleOutcome) {
+        if let Some(record) = self.records.iter_mut().find(|r| r.rule == rule && r.node == node) {
+            record.outcome = outcome;
+        } else {
+            self.records.push(RuleRecord { rule: rule.into(), node: node.into(), outcome });
+        }
+    }
+
+    /// Number of einsums left in the optimized model for `reason`.
+    pub fn declined_count(&self, reason: DeclineReason) -> usize {
+        self.declined.iter().filter(|(_, r)| *r == reason).count()
+    }
+
+    /// Fill the model level counts from the optimized model.
+    pub(crate) fn summarize(&mut self, model: &TypedModel) {
+        let mut packed = HashSet::new();
+        self.lowered = 0;
+        self.declined.clear();
+        for node in model.nodes() {
+            if let Some(lir) = node.op_as::<LirMatMulUnary>() {
+                self.lowered += 1;
+                for uop in &lir.micro_ops {
+                    if let ProtoFusedSpec::AddMatMul(_, a, b) = uop {
+                        packed.extend([node.inputs[*a], node.inputs[*b]]);
+                    }
+                }
+            } else if node.op_is::<EinSum>() {
+                let reason = self.records.iter().rev().find_map(|r| match r.outcome {
+                    RuleOutcome::Declined(reason) if r.node == node.name => Some(reason),
+                    _ => None,
+                });
+                if let Some(reason) = reason {
+                    self.declined.push((node.name.clone(), reason));
+                }
+            }
+        }
+        self.packed_constant_bytes = packed
+            .into_iter()
+            .filter_map(|outlet| model.outlet_fact(outlet).ok()?.konst.clone())
+            .map(|k| k.len() * k.datum_type().size_of())
+            .sum();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::optim::OptimizerOptions;
+
+    /// One einsum lowered, and one for each of three decline reasons.
+    fn model() -> TractResult<TypedModel> {
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", f32::fact([8, 4]))?;
+        let b = model.add_source("b", f32::fact([4, 6]))?;
+        let bias = model.add_source("bias", f32::fact([6]))?;
+        let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let lowered = model.wire_node("lowered", op, &[a, b])?;
+        let op = EinSum::new("mk,kn,n->mn".parse()?, f32::datum_type());
+        let three = model.wire_node("three", op, &[a, b, bias])?;
+        let c = model.add_source("c", f32::fact([8, 2, 4]))?;
+        let d = model.add_source("d", f32::fact([4, 6, 2]))?;
+        let op = EinSum::new("mjk,knj->mn".parse()?, f32::datum_type());
+        let scattered = model.wire_node("scattered", op, &[c, d])?;
+        let e = model.add_source("e", i16::fact([8, 4]))?;
+        let f = model.add_source("f", i16::fact([4, 6]))?;
+        let op = EinSum::new("mk,kn->mn".parse()?, i16::datum_type());
+        let int = model.wire_node("int", op, &[e, f])?;
+        model.set_output_outlets(&[lowered[0], three[0], scattered[0], int[0]])?;
+        Ok(model)
+    }
+
+    #[test]
+    fn einsum_decline_reasons() -> TractResult<()> {
+        let (_, report) = model()?.into_optimized_with_report(&OptimizerOptions::default())?;
+        assert_eq!(report.einsums, 4);
+        assert_eq!(report.lowered, 1);
+        assert_eq!(report.declined.len(), 3);
+        assert_eq!(report.declined_count(DeclineReason::OperandCount), 1);
+        assert_eq!(report.declined_count(DeclineReason::MultipleKAxes), 1);
+        assert_eq!(report.declined_count(DeclineReason::UnsupportedDatumType), 1);
+        let lowered = report.records.iter().find(|r| r.node == "lowered").unwrap();
+        assert!(matches!(lowered.outcome, RuleOutcome::Patched(_)));
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() -> TractResult<()> {
+        let (_, report) = model()?.into_optimized_with_report(&OptimizerOptions::default())?;
+        let json = serde_json::to_string(&report)?;
+        let back: OptimizationReport = serde_json::from_str(&json)?;
+        assert_eq!(back, report);
+        Ok(())
+    }
+}
@@ -1,11 +1,15 @@
 use std::borrow::Borrow;
 use std::fmt::{Debug, Display};
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
 
 use crate::internal::*;
 use crate::model::order::eval_order_for_nodes;
 use crate::model::{Fact, Graph, OutletId};
 use crate::ops::konst::Const;
+use crate::ops::matmul::weight_variants::WeightPrecision;
+use crate::ops::matmul::MatMulLimits;
 use crate::ops::FrozenOpState;
 
 #[derive(Default)]
@@ -14,6 +18,26 @@ pub struct SessionState {
     pub resolved_symbols: SymbolValues,
     pub tensors: HashMap<String, Tensor>,
     pub cached_mmm_scratch_space: Option<Box<dyn tract_linalg::mmm::ScratchSpace>>,
+    /// Generation of the model inputs tagged as parameters (see `TensorRole::Parameter`).
+    pub parameters_generation: usize,
+    /// Cumulative time spent in each fused spec of the matrix multiplication nodes, by node id,
+    /// when the plan profiles them (see `SimplePlan::with_fused_spec_profiling`).
+    pub fused_spec_profile: Option<HashMap<usize, Vec<(String, Duration)>>>,
+    /// Scratch buffer lent to the op states while they run, sized for the largest of their
+    /// declarations (see `OpState::scratch_layout`).
+    pub scratch_arena: Option<Tensor>,
+    /// Check the inputs of the matrix multiplication nodes against their geometry before
+    /// running their kernels, as debug builds always do (see
+    /// `SimplePlan::with_matmul_input_checks`).
+    pub check_matmul_inputs: bool,
+    /// Precision the op states pack the weights of the matrix products in, when the model keeps
+    /// several (see `ops::matmul::weight_variants`).
+    pub weight_precision: WeightPrecision,
+    /// Limits on the runs of the matrix multiplication nodes (see
+    /// `SimplePlan::with_matmul_limits`).
+    pub matmul_limits: MatMulLimits,
+    /// Token aborting the run once cancelled (see `SimplePlan::with_cancellation_token`).
+    pub cancellation: Option<CancellationToken>,
 }
 
 impl Clone for SessionState {
@@ -23,16 +47,116 @@ impl Clone for SessionState {
             resolved_symbols: self.resolved_symbols.clone(),
             tensors: self.tensors.clone(),
             cached_mmm_scratch_space: None,
+            parameters_generation: self.parameters_generation,
+            fused_spec_profile: self.fused_spec_profile.clone(),
+            scratch_arena: None,
+            check_matmul_inputs: self.check_matmul_inputs,
+            weight_precision: self.weight_precision,
+            matmul_limits: self.matmul_limits,
+            cancellation: self.cancellation.clone(),
         }
     }
 }
 
+impl SessionState {
+    /// Evaluate an op state, lending it the scratch arena.
+    pub fn eval_op_state(
+        &mut self,
+        state: &mut dyn OpState,
+        op: &dyn Op,
+        inputs: TVec<TValue>,
+    ) -> TractResult<TVec<TValue>> {
+        let mut arena = self.scratch_arena.take();
+        let scratch = match arena.as_mut() {
+            Some(arena) => unsafe { arena.as_slice_mut_unchecked::<u8>() },
+            None => &mut [],
+        };
+        let outputs = state.eval_with_scratch(self, op, inputs, scratch);
+        self.scratch_arena = arena;
+        outputs
+    }
+}
+
+/// Cancels the runs of a plan from another thread: they check it between nodes, and the matrix
+/// multiplications between kernel invocations, failing once it is cancelled.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<CancellationState>);
+
+#[derive(Debug, Default)]
+struct CancellationState {
+    cancelled: AtomicBool,
+    checks: AtomicUsize,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Relaxed)
+    }
+
+    /// Let the runs go on again, and count the checks from zero.
+    pub fn reset(&self) {
+        self.0.cancelled.store(false, Ordering::Relaxed);
+        self.0.checks.store(0, Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Number of checks of the token since its creation or last reset, successful or not: the
+    /// progress of the runs, in nodes and matrix multiplication kernel invocations.
+    pub fn checks(&self) -> usize {
+        self.0.checks.load(Ordering::Relaxed)
+    }
+
+    /// Fail if the token is cancelled.
+    pub fn check(&self) -> TractResult<()> {
+        self.0.checks.fetch_add(1, Ordering::Relaxed);
+        ensure!(!self.is_cancelled(), "Run cancelled");
+        Ok(())
+    }
+}
+
 impl Debug for SessionState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "SessionState({:?})", self.resolved_symbols)
     }
 }
 
+/// Node property (a scalar i64) naming the input slot whose buffer the node may compute its
+/// output in, when it is the last consumer of this input and both have the same type and shape.
+pub const IN_PLACE_INPUT: &str = "in_place_input";
+
+/// The input slot a node may compute its output in: the one declared by its op (see
+/// `EvalOp::in_place_input`), or else the one named by its `IN_PLACE_INPUT` property (patches
+/// carry properties over to fused nodes, so the op declaration wins). An op declaration naming
+/// an input of another type or shape than the output is an error.
+fn in_place_input<F, O>(model: &Graph<F, O>, node: &Node<F, O>) -> TractResult<Option<usize>>
+where
+    F: Fact + Clone + 'static,
+    O: Debug + Display + AsRef<dyn Op> + AsMut<dyn Op> + Clone + 'static,
+{
+    let Some(declared) = node.op().in_place_input()? else {
+        return Ok(model
+            .node_property(node.id, IN_PLACE_INPUT)
+            .and_then(|slot| slot.cast_to_scalar::<i64>().ok())
+            .and_then(|slot| usize::try_from(slot).ok()));
+    };
+    let input = node.inputs.get(declared).with_context(|| format!("No input #{declared}"))?;
+    ensure!(node.outputs.len() == 1, "In place output of an op with several outputs");
+    let input = model.outlet_fact(*input)?.to_typed_fact()?;
+    let output = node.outputs[0].fact.to_typed_fact()?;
+    ensure!(
+        input.datum_type == output.datum_type && input.shape == output.shape,
+        "Output {output:?} can not be computed in input #{declared} {input:?}"
+    );
+    Ok(Some(declared))
+}
+
 #[derive(Debug, Clone)]
 pub struct SimplePlan<F, O, M>
 where
@@ -45,6 +169,10 @@ where
     order: Vec<usize>,
     flush_lists: Vec<TVec<usize>>,
     has_unresolved_symbols: bool,
+    profile_fused_specs: bool,
+    check_matmul_inputs: bool,
+    matmul_limits: MatMulLimits,
+    cancellation: Option<CancellationToken>,
     _casper: PhantomData<(F, O)>,
 }
 
@@ -80,6 +208,11 @@ where
         let mut order =
             eval_order_for_nodes(model.borrow().nodes(), &inputs, &outputs_nodes, deps)?;
         order.retain(|node| !model.borrow().node(*node).op_is::<Const>());
+        for &n in &order {
+            let node = model.borrow().node(n);
+            in_place_input(model.borrow(), node)
+                .with_context(|| format!("Checking in place output of {node}"))?;
+        }
         let mut values_needed_until_step = vec![0; model.borrow().nodes().len()];
         for (step, node) in order.iter().enumerate() {
             for i in &model.borrow().node(*node).inputs {
@@ -109,10 +242,105 @@ where
             flush_lists,
             outputs: outputs.to_vec(),
             has_unresolved_symbols: !symbols.is_empty(),
+            profile_fused_specs: false,
+            check_matmul_inputs: false,
+            matmul_limits: MatMulLimits::default(),
+            cancellation: None,
             _casper: PhantomData,
         })
     }
 
+    /// Time the fused specs of the matrix multiplications separately (see
+    /// `SimpleState::fused_spec_profile`). The kernels then run once per fused spec, so
+    /// profiling slows the matrix multiplications down. It costs nothing when off.
+    pub fn with_fused_spec_profiling(mut self, profile: bool) -> SimplePlan<F, O, M> {
+        self.profile_fused_specs = profile;
+        self
+    }
+
+    /// Check the inputs of the matrix multiplication nodes against the geometry recorded when
+    /// they were lowered, with the symbols resolved, before running their kernels: a mismatch is
+    /// then an error instead of out of bounds accesses. Debug builds always check them.
+    pub fn with_matmul_input_checks(mut self, check: bool) -> SimplePlan<F, O, M> {
+        self.check_matmul_inputs = check;
+        self
+    }
+
+    /// Check each run of the matrix multiplication nodes against `limits` before allocating its
+    /// output (see `MatMulLimits`).
+    pub fn with_matmul_limits(mut self, limits: MatMulLimits) -> SimplePlan<F, O, M> {
+        self.matmul_limits = limits;
+        self
+    }
+
+    /// Abort the runs of the states of this plan once `token` is cancelled.
+    pub fn with_cancellation_token(mut self, token: CancellationToken) -> SimplePlan<F, O, M> {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Largest total size, in bytes, of the values computed by the plan and alive at the same
+    /// time, according to the plan flush lists. Constants and model inputs are not counted. An
+    /// output computed in place (see `IN_PLACE_INPUT` and `EvalOp::in_place_input`) takes over the
+    /// buffer of its input.
+    pub fn peak_memory(&self, symbols: &SymbolValues) -> TractResult<usize> {
+        let model = self.model.borrow();
+        let layout = |outlet: &OutletId| -> TractResult<(DatumType, TVec<usize>)> {
+            let fact = model.outlet_fact(*outlet)?.to_typed_fact()?;
+            Ok((fact.datum_type, fact.shape.eval_to_usize(symbols)?.into_owned()))
+        };
+        let node_bytes = |node: usize| -> TractResult<usize> {
+            (0..model.node(node).outputs.len())
+                .map(|slot| {
+                    let (dt, shape) = layout(&OutletId::new(node, slot))?;
+                    crate::ops::matmul::checked_byte_size(dt, &shape)
+                        .with_context(|| format!("Computing size of {}", model.node(node)))
+                })
+                .try_fold(0usize, |sum, bytes: TractResult<usize>| {
+                    sum.checked_add(bytes?).context("Node outputs size overflows usize")
+                })
+        };
+        let mut flushed_at = vec![None; model.nodes().len()];
+        for (step, flush) in self.flush_lists.iter().enumerate() {
+            for &node in flush {
+                flushed_at[node] = Some(step);
+            }
+        }
+        let mut counted = vec![false; model.nodes().len()];
+        let (mut live, mut peak) = (0usize, 0usize);
+        for (step, &n) in self.order.iter().enumerate() {
+            let node = model.node(n);
+            if !model.input_outlets()?.iter().any(|i| i.node == n) {
+                let in_place = in_place_input(model, node)?
+                    .and_then(|slot| node.inputs.get(slot))
+                    .filter(|input| {
+                        counted[input.node]
+                            && flushed_at[input.node] == Some(step)
+                            && node.outputs.len() == 1
+                            && model.node(input.node).outputs.len() == 1
+                            && node.inputs.iter().filter(|i| i.node == input.node).count() == 1
+                            && layout(input).ok() == layout(&n.into()).ok()
+                    })
+                    .map(|input| input.node);
+                if let Some(input) = in_place {
+                    counted[input] = false;
+                } else {
+                    live =
+                        live.checked_add(node_bytes(n)?).context("Peak memory overflows usize")?;
+                }
+                counted[n] = true;
+                peak = peak.max(live);
+            }
+            for &flush in &self.flush_lists[step] {
+                if counted[flush] {
+                    live -= node_bytes(flush)?;
+                    counted[flush] = false;
+                }
+            }
+        }
+        Ok(peak)
+    }
+
     pub fn order_without_consts(&self) -> &[usize] {
         &self.order
     }
@@ -139,9 +367,168 @@ where
     pub states: Vec<Option<Box<dyn OpState>>>,
     pub session_state: SessionState,
     pub values: Vec<Option<TVec<TValue>>>,
+    /// Outputs of the subgraphs only depending on stable inputs, kept across runs (see
+    /// `SimpleState::set_input_stable`).
+    invariants: Option<InvariantValues>,
+    /// Statistics of node outputs reported after each evaluation (see
+    /// `SimpleState::set_output_observer`).
+    observer: Option<OutputObserver>,
     _phantom: PhantomData<(M, F, O)>,
 }
 
+/// Statistics of the values of a tensor, computed in a single pass. NaN values are ignored by
+/// the extrema, not by the mean.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutputStats {
+    pub len: usize,
+    /// +∞ for an empty tensor.
+    pub min: f32,
+    /// -∞ for an empty tensor.
+    pub max: f32,
+    /// 0 for an empty tensor.
+    pub mean: f64,
+    pub abs_max: f32,
+}
+
+impl OutputStats {
+    /// The statistics of the values of `tensor`, cast to f32 first unless they are f32.
+    pub fn of(tensor: &Tensor) -> TractResult<OutputStats> {
+        let values = tensor.cast_to::<f32>()?;
+        Ok(OutputStats::of_slice(values.as_slice::<f32>()?))
+    }
+
+    /// Independent accumulators for each lane of a chunk let the compiler vectorize the loop.
+    fn of_slice(values: &[f32]) -> OutputStats {
+        const LANES: usize = 16;
+        let mut min = [f32::INFINITY; LANES];
+        let mut max = [f32::NEG_INFINITY; LANES];
+        let mut abs_max = [0f32; LANES];
+        let mut sum = [0f64; LANES];
+        let chunks = values.chunks_exact(LANES);
+        let rest = chunks.remainder();
+        for chunk in chunks {
+            for lane in 0..LANES {
+                let x = chunk[lane];
+                min[lane] = min[lane].min(x);
+                max[lane] = max[lane].max(x);
+                abs_max[lane] = abs_max[lane].max(x.abs());
+                sum[lane] += x as f64;
+            }
+        }
+        for (lane, &x) in rest.iter().enumerate() {
+            min[lane] = min[lane].min(x);
+            max[lane] = max[lane].max(x);
+            abs_max[lane] = abs_max[lane].max(x.abs());
+            sum[lane] += x as f64;
+        }
+        let sum: f64 = sum.iter().sum();
+        OutputStats {
+            len: values.len(),
+            min: min.iter().fold(f32::INFINITY, |a, &b| a.min(b)),
+            max: max.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b)),
+            mean: if values.is_empty() { 0. } else { sum / values.len() as f64 },
+            abs_max: abs_max.iter().fold(0f32, |a, &b| a.max(b)),
+        }
+    }
+}
+
+/// Callback of an output observer, called with the name of the node and the statistics of its
+/// first output.
+pub type OutputObserverFn = dyn Fn(&str, &OutputStats) + Send + Sync;
+
+/// The nodes whose outputs are observed, by node id, and the callback.
+#[derive(Clone)]
+struct OutputObserver {
+    observed: Vec<bool>,
+    callback: Arc<OutputObserverFn>,
+}
+
+impl Debug for OutputObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let observed = self.observed.iter().filter(|o| **o).count();
+        write!(f, "OutputObserver({observed} nodes)")
+    }
+}
+
+/// The nodes whose outputs do not change across runs as long as the stable inputs do not, and
+/// the cached outputs of the ones the rest of the plan consumes.
+#[derive(Clone, Debug)]
+struct InvariantValues {
+    stable_inputs: Vec<bool>,
+    invariant: Vec<bool>,
+    /// Invariant nodes consumed by a variant node, or model outputs: their outputs are cached.
+    frontier: Vec<bool>,
+    cached: Vec<Option<TVec<TValue>>>,
+}
+
+impl InvariantValues {
+    fn new<F, O>(model: &Graph<F, O>, order: &[usize], stable_inputs: Vec<bool>) -> Self
+    where
+        F: Fact + Clone + 'static,
+        O: Debug + Display + AsRef<dyn Op> + AsMut<dyn Op> + Clone + 'static,
+    {
+        let mut invariant: Vec<bool> =
+            model.nodes().iter().map(|n| n.op_is::<Const>()).collect::<Vec<_>>();
+        for (ix, input) in model.inputs.iter().enumerate() {
+            invariant[input.node] = stable_inputs[ix];
+        }
+        for &n in order {
+            let node = model.node(n);
+            if !node.inputs.is_empty() && node.op().is_stateless() {
+                invariant[n] = node.inputs.iter().all(|i| invariant[i.node]);
+            }
+        }
+        let mut frontier = vec![false; invariant.len()];
+        for node in model.nodes() {
+            for input in &node.inputs {
+                frontier[input.node] |= invariant[input.node] && !invariant[node.id];
+            }
+        }
+        for output in &model.outputs {
+            frontier[output.node] |= invariant[output.node];
+        }
+        for node in model.nodes().iter().filter(|n| n.op_is::<Const>()) {
+            frontier[node.id] = false;
+        }
+        let cached = vec![None; invariant.len()];
+        InvariantValues { stable_inputs, invariant, frontier, cached }
+    }
+
+    /// The invariant nodes to evaluate in this run: the frontier nodes not in cache, and the
+    /// invariant nodes they depend on.
+    fn to_evaluate<F, O>(&self, model: &Graph<F, O>, order: &[usize]) -> Vec<bool>
+    where
+        F: Fact + Clone + 'static,
+        O: Debug + Display + AsRef<dyn Op> + AsMut<dyn Op> + Clone + 'static,
+    {
+        let mut needed = vec![false; self.invariant.len()];
+        for &n in order.iter().rev() {
+            if self.invariant[n] {
+                let successors = model.node(n).outputs.iter().flat_map(|o| &o.successors);
+                let feeds_needed = successors.into_iter().any(|s| needed[s.node]);
+                needed[n] = (self.frontier[n] && self.cached[n].is_none()) || feeds_needed;
+            }
+        }
+        needed
+    }
+
+    /// Drop the cached outputs of the nodes depending on `node`.
+    fn invalidate<F, O>(&mut self, model: &Graph<F, O>, order: &[usize], node: usize)
+    where
+        F: Fact + Clone + 'static,
+        O: Debug + Display + AsRef<dyn Op> + AsMut<dyn Op> + Clone + 'static,
+    {
+        let mut depends = vec![false; self.invariant.len()];
+        depends[node] = true;
+        for &n in order {
+            depends[n] |= model.node(n).inputs.iter().any(|i| depends[i.node]);
+            if depends[n] {
+                self.cached[n] = None;
+            }
+        }
+    }
+}
+
 impl<F, O, M, P> SimpleState<F, O, M, P>
 where
     F: Fact + Clone + 'static,
@@ -150,20 +537,69 @@ where
     P: Borrow<SimplePlan<F, O, M>> + Clone,
 {
     pub fn new(plan: P) -> TractResult<SimpleState<F, O, M, P>> {
+        Self::new_with_weight_precision(plan, WeightPrecision::default())
+    }
+
+    /// A state packing the weights of the matrix products in the precision `precision` picks,
+    /// when the model keeps several (see `ops::matmul::weight_variants`).
+    pub fn new_with_weight_precision(
+        plan: P,
+        precision: WeightPrecision,
+    ) -> TractResult<SimpleState<F, O, M, P>> {
         let values = vec![None; plan.borrow().model.borrow().nodes().len()];
-        let mut session = SessionState::default();
+        let mut session = SessionState { weight_precision: precision, ..SessionState::default() };
+        if plan.borrow().profile_fused_specs {
+            session.fused_spec_profile = Some(HashMap::default());
+        }
+        session.check_matmul_inputs = plan.borrow().check_matmul_inputs;
+        session.matmul_limits = plan.borrow().matmul_limits;
+        session.cancellation = plan.borrow().cancellation.clone();
         let model = plan.borrow().model();
         let states: Vec<Option<Box<dyn OpState>>> = model
             .nodes()
             .iter()
             .map(|n: &Node<F, O>| n.op().state(&mut session, n.id))
             .collect::<TractResult<_>>()?;
-        let mut state =
-            SimpleState { plan, states, session_state: session, values, _phantom: PhantomData };
+        let mut state = SimpleState {
+            plan,
+            states,
+            session_state: session,
+            values,
+            invariants: None,
+            observer: None,
+            _phantom: PhantomData,
+        };
         state.populate_consts();
+        state.prepare_scratch_arena()?;
         Ok(state)
     }
 
+    /// Size the scratch arena for the declarations of the op states with the symbols resolved
+    /// so far, allocating it if it is too small.
+    fn prepare_scratch_arena(&mut self) -> TractResult<()> {
+        let SimpleState { plan, states, session_state, .. } = self;
+        let plan = (*plan).borrow();
+        let (mut size, mut align) = (0, 1);
+        for (node, state) in plan.model().nodes().iter().zip(states.iter()) {
+            let Some(state) = state else { continue };
+            if let Some(layout) =
+                state.scratch_layout(node.op(), &session_state.resolved_symbols)?
+            {
+                size = size.max(layout.size());
+                align = align.max(layout.align());
+            }
+        }
+        let fits = |arena: &Tensor| {
+            arena.len() >= size && unsafe { arena.as_ptr_unchecked::<u8>() } as usize % align == 0
+        };
+        if size > 0 && !session_state.scratch_arena.as_ref().map(fits).unwrap_or(false) {
+            let arena =
+                unsafe { Tensor::uninitialized_aligned_dt(u8::datum_type(), &[size], align)? };
+            session_state.scratch_arena = Some(arena);
+        }
+        Ok(())
+    }
+
     fn populate_consts(&mut self) {
         for node in &self.plan.borrow().model().nodes {
             if let Some(k) = node.op_as::<Const>() {
@@ -172,6 +608,12 @@ where
         }
     }
 
+    /// Bytes of the data the op states derived from the model, like the weights they packed
+    /// (see `OpState::memory_footprint`).
+    pub fn memory_footprint(&self) -> usize {
+        self.states.iter().flatten().map(|s| s.memory_footprint()).sum()
+    }
+
     /// Reset wires state.
     pub fn reset_turn(&mut self) -> TractResult<()> {
         for node in &self.plan.borrow().order {
@@ -197,6 +639,89 @@ where
         self.run_plan_with_eval(inputs, self::eval)
     }
 
+    /// Cumulative time spent in each fused spec of the matrix multiplications since the state
+    /// was created, keyed as `node_name/spec_index:spec_kind`, in evaluation order. None if the
+    /// plan does not profile them.
+    pub fn fused_spec_profile(&self) -> Option<Vec<(String, Duration)>> {
+        let profile = self.session_state.fused_spec_profile.as_ref()?;
+        let plan = self.plan.borrow();
+        let mut entries = vec![];
+        for &node in plan.order_without_consts() {
+            for (ix, (kind, time)) in profile.get(&node).into_iter().flatten().enumerate() {
+                entries.push((format!("{}/{ix}:{kind}", plan.model().node(node).name), *time));
+            }
+        }
+        Some(entries)
+    }
+
+    /// Signal that the values of the inputs tagged as `TensorRole::Parameter` change, discarding
+    /// what ops cached from them.
+    pub fn bump_parameters_generation(&mut self) {
+        self.session_state.parameters_generation += 1;
+    }
+
+    /// Mark the `input`-th model input as stable or not. The caller promises a stable input
+    /// keeps the same value from run to run, until it calls `invalidate_input`: the nodes only
+    /// depending on stable inputs and constants (like the packing or the column sums of a
+    /// weight matrix fed as an input) are then evaluated once, and their outputs cached in the
+    /// state. A new value set for a stable input is ignored until it is invalidated. Frozen
+    /// states do not keep the marks nor the cached outputs.
+    pub fn set_input_stable(&mut self, input: usize, stable: bool) -> TractResult<()> {
+        let inputs = self.model().inputs.len();
+        ensure!(input < inputs, "Invalid input id {input} for model with {inputs} inputs");
+        let mut stable_inputs = self
+            .invariants
+            .take()
+            .map(|inv| inv.stable_inputs)
+            .unwrap_or_else(|| vec![false; inputs]);
+        stable_inputs[input] = stable;
+        if stable_inputs.iter().any(|s| *s) {
+            let plan = self.plan.borrow();
+            self.invariants = Some(InvariantValues::new(plan.model(), &plan.order, stable_inputs));
+        }
+        Ok(())
+    }
+
+    /// Signal that the value of the stable `input`-th model input changes, discarding the
+    /// cached outputs depending on it.
+    pub fn invalidate_input(&mut self, input: usize) -> TractResult<()> {
+        let outlet = *self
+            .model()
+            .input_outlets()?
+            .get(input)
+            .with_context(|| format!("Invalid input id {input}"))?;
+        let SimpleState { plan, invariants, .. } = self;
+        if let Some(invariants) = invariants {
+            let plan = (*plan).borrow();
+            invariants.invalidate(plan.model(), &plan.order, outlet.node);
+        }
+        Ok(())
+    }
+
+    /// Call `callback` with the statistics of the first output of the nodes named in `nodes`
+    /// right after each of their evaluations, replacing the previous observer. Nothing is
+    /// computed for the other nodes, nor at all without an observer. Outputs that can not be
+    /// cast to f32 are skipped with a warning. The observer is kept by `freeze`.
+    pub fn set_output_observer(
+        &mut self,
+        nodes: &[&str],
+        callback: impl Fn(&str, &OutputStats) + Send + Sync + 'static,
+    ) -> TractResult<()> {
+        let model = self.model();
+        let mut observed = vec![false; model.nodes().len()];
+        for name in nodes {
+            let node = model.node_by_name(name)?;
+            ensure!(!node.outputs.is_empty(), "Node {node} has no output to observe");
+            observed[node.id] = true;
+        }
+        self.observer = Some(OutputObserver { observed, callback: Arc::new(callback) });
+        Ok(())
+    }
+
+    pub fn clear_output_observer(&mut self) {
+        self.observer = None;
+    }
+
     pub fn exec(&mut self) -> TractResult<()> {
         self.exec_plan_with_eval(self::eval)
     }
@@ -232,19 +757,38 @@ where
         ) -> Result<TVec<TValue>, E>,
         E: Into<anyhow::Error> + Send + Sync + 'static,
     {
+        if self.plan.borrow().has_unresolved_symbols || self.session_state.scratch_arena.is_none() {
+            self.prepare_scratch_arena()?;
+        }
         {
             let &mut SimpleState {
                 ref plan,
                 ref mut session_state,
                 ref mut states,
                 ref mut values,
+                ref mut invariants,
+                ref observer,
                 ..
             } = self;
             let plan = plan.borrow();
             let model = plan.model().borrow();
+            let to_evaluate = invariants.as_ref().map(|inv| inv.to_evaluate(model, &plan.order));
             for (step, n) in plan.order.iter().enumerate() {
                 let node = model.node(*n);
+                if let (Some(inv), Some(to_evaluate)) = (invariants.as_ref(), &to_evaluate) {
+                    if inv.invariant[*n] && !to_evaluate[*n] {
+                        trace!("Skipping step {}, node {} (invariant)", step, node);
+                        for flush in &plan.flush_lists[step] {
+                            values[*flush] = None;
+                        }
+                        values[*n] = inv.cached[*n].clone();
+                        continue;
+                    }
+                }
                 trace!("Running step {}, node {}", step, node);
+                if let Some(token) = &session_state.cancellation {
+                    token.check().with_context(|| format!("Before running {node}"))?;
+                }
                 let mut inputs: TVec<TValue> = tvec![];
                 for i in &node.inputs {
                     trace!("  use input {:?}", i);
@@ -325,6 +869,16 @@ where
                     }
                 }
 
+                if let Some(observer) = observer.as_ref().filter(|o| o.observed[node.id]) {
+                    // an output without statistics (like strings) is skipped, not a failed run
+                    match OutputStats::of(&vs[0]) {
+                        Ok(stats) => (observer.callback)(&node.name, &stats),
+                        Err(e) => log::warn!("Not observing {node} output: {e}"),
+                    }
+                }
+                if let Some(inv) = invariants.as_mut().filter(|inv| inv.frontier[node.id]) {
+                    inv.cached[node.id] = Some(vs.clone());
+                }
                 values[node.id] = Some(vs);
             }
         }
@@ -454,7 +1008,7 @@ where
         let nodes = plan.model().nodes();
         let node = &nodes[node];
         let vs = match self.states[node.id] {
-            Some(ref mut state) => state.eval(session_state, node.op(), inputs),
+            Some(ref mut state) => session_state.eval_op_state(state.as_mut(), node.op(), inputs),
             None => node.op().eval(inputs),
         }
         .with_context(|| format!("Evaluating {node}"))?;
@@ -482,9 +1036,11 @@ where
             let Self { ref mut states, ref mut session_state, ref plan, .. } = self;
             let plan = plan.borrow();
             match states[node] {
-                Some(ref mut state) => {
-                    state.eval(session_state, plan.borrow().model().nodes()[node].op(), inputs)
-                }
+                Some(ref mut state) => session_state.eval_op_state(
+                    state.as_mut(),
+                    plan.model().nodes()[node].op(),
+                    inputs,
+                ),
                 None => plan.borrow().model().nodes()[node].op().eval(inputs),
             }
             .with_context(|| format!("Evaluating {node:?}"))?
@@ -526,6 +1082,8 @@ where
                 .collect(),
             resolved_symbols: self.session_state.resolved_symbols.clone(),
             tensors: self.session_state.tensors.clone(),
+            parameters_generation: self.session_state.parameters_generation,
+            weight_precision: self.session_state.weight_precision,
             states: self.states.iter().map(|s| s.as_ref().map(|s| s.freeze())).collect(),
             values: self
                 .values
@@ -539,6 +1097,7 @@ where
                     }
                 })
                 .collect(),
+            observer: self.observer.clone(),
             _phantom: PhantomData,
         }
     }
@@ -555,7 +1114,7 @@ where
     O: Debug + Display + AsRef<dyn Op> + AsMut<dyn Op> + Clone + 'static,
 {
     let r = match state {
-        Some(ref mut state) => state.eval(session_state, node.op(), input),
+        Some(ref mut state) => session_state.eval_op_state(*state, node.op(), input),
         None => node.op().eval(input),
     }
     .with_context(|| format!("Evaluating {node}"));
@@ -574,8 +1133,11 @@ where
     pub inputs: HashMap<usize, Tensor>,
     pub resolved_symbols: SymbolValues,
     pub tensors: HashMap<String, Tensor>,
+    pub parameters_generation: usize,
+    pub weight_precision: WeightPrecision,
     pub states: Vec<Option<Box<dyn FrozenOpState>>>,
     pub values: Vec<Option<TVec<Tensor>>>,
+    observer: Option<OutputObserver>,
     _phantom: PhantomData<(M, F, O)>,
 }
 
@@ -594,6 +1156,13 @@ where
                 resolved_symbols: self.resolved_symbols.clone(),
                 tensors: self.tensors.clone(),
                 cached_mmm_scratch_space: None,
+                parameters_generation: self.parameters_generation,
+                fused_spec_profile: self.plan.borrow().profile_fused_specs.then(HashMap::default),
+                scratch_arena: None,
+                check_matmul_inputs: self.plan.borrow().check_matmul_inputs,
+                weight_precision: self.weight_precision,
+                matmul_limits: self.plan.borrow().matmul_limits,
+                cancellation: self.plan.borrow().cancellation.clone(),
             },
             states: self.states.iter().map(|s| s.as_ref().map(|s| s.unfreeze())).collect(),
             values: self
@@ -601,6 +1170,8 @@ where
                 .iter()
                 .map(|t| t.as_ref().map(|t| t.iter().map(|t| t.clone().into_tvalue()).collect()))
                 .collect(),
+            invariants: None,
+            observer: self.observer.clone(),
             _phantom: PhantomData,
         };
         state.populate_consts();
@@ -638,4 +1209,168 @@ mod test {
     fn frozen_type_state_is_send() {
         is_send::<TypedFrozenSimpleState<TypedModel, TypedSimplePlan<TypedModel>>>();
     }
+
+    /// A quantized product of an activation and a weight matrix fed as a model input: with a
+    /// non-zero activation zero point, the weight column sums compensate it.
+    fn streamed_weights_model() -> TractResult<TypedModel> {
+        use crate::ops::einsum::EinSum;
+        let mut model = TypedModel::default();
+        let a = model.add_source("a", i8::fact([4, 6]))?;
+        let b = model.add_source("b", i8::fact([6, 5]))?;
+        let mut inputs = tvec!(a, b);
+        for (name, t) in [
+            ("bias", tensor0(0i32)),
+            ("a0", tensor0(3i8)),
+            ("a_scale", tensor0(0.05f32)),
+            ("b0", tensor0(0i8)),
+            ("b_scale", tensor0(0.02f32)),
+            ("c0", tensor0(1i8)),
+            ("c_scale", tensor0(0.1f32)),
+        ] {
+            inputs.push(model.add_const(name, t)?);
+        }
+        let op = EinSum::newq("mk,kn,,,,,,,->mn".parse()?, i32::datum_type(), i8::datum_type());
+        let c = model.wire_node("einsum", op, &inputs)?;
+        model.set_output_outlets(&c)?;
+        model.into_optimized()
+    }
+
+    #[test]
+    fn stable_input_subgraphs_computed_once() -> TractResult<()> {
+        use crate::ops::matmul::pack::MatMatMulPack;
+        use crate::ops::nn::IntegerSum;
+        let model = streamed_weights_model()?;
+        let tensor = |shape: [usize; 2], seed: usize| -> TractResult<TValue> {
+            let data = (0..shape[0] * shape[1])
+                .map(|i| ((i * 7 + seed) % 41) as i8 - 20)
+                .collect::<Vec<_>>();
+            Ok(Tensor::from_shape(&shape, &data)?.into_tvalue())
+        };
+        let a = tensor([4, 6], 0)?;
+        let plan = SimplePlan::new(model)?;
+        let reference = |b: &TValue| plan.run(tvec!(a.clone(), b.clone()));
+        let mut state = SimpleState::new(&plan)?;
+        state.set_input_stable(1, true)?;
+        // evaluations of the column sums and of the packings
+        let (sums, packs) = (std::cell::Cell::new(0), std::cell::Cell::new(0));
+        let run = |state: &mut SimpleState<_, _, _, _>, b: &TValue| {
+            state.run_plan_with_eval(
+                tvec!(a.clone(), b.clone()),
+                |session, op_state, node, inputs| {
+                    sums.set(sums.get() + node.op_is::<IntegerSum>() as usize);
+                    packs.set(packs.get() + node.op_is::<MatMatMulPack>() as usize);
+                    eval(session, op_state, node, inputs)
+                },
+            )
+        };
+        let b = tensor([6, 5], 3)?;
+        for _ in 0..10 {
+            assert_eq!(run(&mut state, &b)?, reference(&b)?);
+        }
+        // the activation is packed at each run, the weights once
+        assert_eq!((sums.get(), packs.get()), (1, 10 + 1));
+
+        let other = tensor([6, 5], 11)?;
+        state.invalidate_input(1)?;
+        let found = run(&mut state, &other)?;
+        assert_eq!(found, reference(&other)?);
+        assert_ne!(found, reference(&b)?);
+        assert_eq!((sums.get(), packs.get()), (2, 11 + 2));
+        Ok(())
+    }
+
+    #[test]
+    fn observed_matmul_output_stats() -> TractResult<()> {
+        use crate::ops::einsum::EinSum;
+        use crate::ops::matmul::lir_unary::LirMatMulUnary;
+        use std::sync::Mutex;
+        let matrix = |shape: [usize; 2], seed: usize| {
+            let data = (0..shape[0] * shape[1])
+                .map(|i| ((i * 7 + seed) % 23) as f32 / 4. - 3.)
+                .collect::<Vec<_>>();
+            tract_ndarray::Array2::from_shape_vec(shape, data).unwrap()
+        };
+        let (w1, w2) = (matrix([16, 24], 1), matrix([24, 8], 2));
+        let mut model = TypedModel::default();
+        let x = model.add_source("x", f32::fact([6, 16]))?;
+        let mut wire = x;
+        for (name, w) in [("first", &w1), ("second", &w2)] {
+            let w = model.add_const(format!("{name}.w"), w.clone().into_tensor())?;
+            let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+            wire = model.wire_node(name, op, &[wire, w])?[0];
+        }
+        model.set_output_outlets(&[wire])?;
+        let model = model.into_optimized()?;
+        assert!(model.node_by_name("first")?.op_is::<LirMatMulUnary>());
+
+        let plan = SimplePlan::new(model)?;
+        let mut state = SimpleState::new(&plan)?;
+        let reported = Arc::new(Mutex::new(vec![]));
+        let sink = reported.clone();
+        state.set_output_observer(&["first"], move |name, stats| {
+            sink.lock().unwrap().push((name.to_string(), *stats))
+        })?;
+        assert!(state.set_output_observer(&["nope"], |_, _| ()).is_err());
+        let mut expected = vec![];
+        for seed in [3, 11, 5] {
+            if seed == 5 {
+                // the observer is frozen with the state
+                state = state.freeze().unfreeze();
+            }
+            let x = matrix([6, 16], seed);
+            let product = x.dot(&w1);
+            expected.push(product.iter().fold(0f32, |acc, v| acc.max(v.abs())));
+            state.run(tvec!(x.into_tensor().into_tvalue()))?;
+        }
+        let reported = reported.lock().unwrap();
+        assert_eq!(reported.len(), 3);
+        assert_ne!(expected[0], expected[1]);
+        for ((name, stats), abs_max) in reported.iter().zip(expected) {
+            assert_eq!(name, "first");
+            assert_eq!(stats.len, 6 * 24);
+            assert!((stats.abs_max - abs_max).abs() <= abs_max * 1e-5, "{stats:?} {abs_max}");
+            assert!(stats.min <= stats.mean as f32 && stats.mean as f32 <= stats.max);
+            assert_eq!(stats.abs_max, stats.max.abs().max(stats.min.abs()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unobservable_outputs_are_skipped() -> TractResult<()> {
+        use crate::ops::identity::Identity;
+        use std::sync::Mutex;
+        let mut model = TypedModel::default();
+        let text = model.add_source("text", String::fact([2]))?;
+        let x = model.add_source("x", f32::fact([2]))?;
+        let text = model.wire_node("text_id", Identity, &[text])?[0];
+        let x = model.wire_node("x_id", Identity, &[x])?[0];
+        model.set_output_outlets(&[text, x])?;
+        let plan = SimplePlan::new(model)?;
+        let mut state = SimpleState::new(&plan)?;
+        let reported = Arc::new(Mutex::new(vec![]));
+        let sink = reported.clone();
+        state.set_output_observer(&["text_id", "x_id"], move |name, stats| {
+            sink.lock().unwrap().push((name.to_string(), stats.max))
+        })?;
+        let text = tensor1(&["not".to_string(), "a number".to_string()]);
+        let outputs = state.run(tvec!(text.into_tvalue(), tensor1(&[1f32, 2.]).into_tvalue()))?;
+        assert_eq!(*outputs[1], tensor1(&[1f32, 2.]));
+        assert_eq!(*reported.lock().unwrap(), [("x_id".to_string(), 2f32)]);
+        Ok(())
+    }
+
+    #[test]
+    fn output_stats_of_partial_chunks() -> TractResult<()> {
+        let values = (0..37).map(|i| (i as f32 - 20.) / 3.).collect::<Vec<_>>();
+        let stats = OutputStats::of(&tensor1(&values))?;
+        let mean = values.iter().map(|v| *v as f64).sum::<f64>() / 37.;
+        assert_eq!((stats.len, stats.min, stats.max), (37, -20. / 3., 16. / 3.));
+        assert_eq!(stats.abs_max, 20. / 3.);
+        assert!((stats.mean - mean).abs() < 1e-9);
+        let empty = OutputStats::of(&tensor1::<f32>(&[]))?;
+        assert_eq!((empty.len, empty.mean, empty.abs_max), (0, 0., 0.));
+        let ints = OutputStats::of(&tensor1(&[3i32, -7, 2]))?;
+        assert_eq!((ints.min, ints.max, ints.abs_max), (-7., 3., 7.));
+        Ok(())
+    }
 }
@@ -0,0 +1,93 @@
+//! Counts the heap allocations of the matrix multiplication nodes with a global allocator, in a
+//! test binary of its own for the allocator not to sit under the unit tests.
+
+use std::cell::Cell;
+use tract_core::internal::*;
+use tract_core::ops::einsum::EinSum;
+use tract_core::ops::math::{add, max};
+use tract_core::ops::matmul::lir_unary::LirMatMulUnary;
+use tract_core::ops::matmul::tiling::MacroTiling;
+use tract_core::optim::OptimizerOptions;
+
+thread_local! {
+    /// Allocations of the thread, counted while set.
+    static ALLOCATIONS: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+struct CountingAllocator;
+
+unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|c| c.set(c.get().map(|n| n + 1)));
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn matmul_nodes_run_in_the_scratch_arena() -> TractResult<()> {
+    let mut model = TypedModel::default();
+    let b = model.symbol_table.sym("B");
+    let x = model.add_source("x", f32::fact(dims!(b, 40, 72)))?;
+    let y = model.add_source("y", f32::fact(dims!(b, 72, 24)))?;
+    let w = (0..24 * 16).map(|i| (i % 7) as f32 - 3.).collect::<Vec<_>>();
+    let w = model.add_const("w", Tensor::from_shape(&[1, 24, 16], &w)?)?;
+    let bias = model.add_const("bias.value", Tensor::from_shape(&[1, 40, 1], &[0.5f32; 40])?)?;
+    let zero = model.add_const("zero", tensor3(&[[[0f32]]]))?;
+    // macro tiled, then packed with a per row bias and a relu fused
+    let op = EinSum::new("bmk,bkn->bmn".parse()?, f32::datum_type());
+    let c = model.wire_node("tiled", op, &[x, y])?;
+    let op = EinSum::new("bmk,bkn->bmn".parse()?, f32::datum_type());
+    let c = model.wire_node("packed", op, &[c[0], w])?;
+    let c = model.wire_node("bias", add(), &[c[0], bias])?;
+    let c = model.wire_node("relu", max(), &[c[0], zero])?;
+    model.set_output_outlets(&c)?;
+    let options = OptimizerOptions {
+        symbol_bounds: SymbolValues::default().with(&b, 4),
+        macro_tiling: MacroTiling { min_packed_bytes: 0, a_block_bytes: 1024, b_block_bytes: 2048 },
+        ..Default::default()
+    };
+    let plan = model.clone().into_optimized_with_options(&options)?.into_runnable()?;
+    let reference = model.into_runnable()?;
+    let lirs = plan.model().nodes().iter().filter_map(|n| n.op_as::<LirMatMulUnary>());
+    assert_eq!(lirs.clone().filter(|op| op.macro_tiles.is_some()).count(), 1);
+    assert!(lirs.clone().any(|op| op.fused_spec_names().len() >= 4));
+    let mut state = SimpleState::new(&plan)?;
+    let arena = state.session_state.scratch_arena.as_ref().context("Expected an arena")?;
+    for op in lirs {
+        assert!(arena.len() >= op.scratch_layout()?.size());
+    }
+    let input = |shape: [usize; 3], seed: usize| {
+        let data = (0..shape.iter().product::<usize>())
+            .map(|i| ((i * 7 + seed) % 23) as f32 / 8. - 1.)
+            .collect::<Vec<_>>();
+        Tensor::from_shape(&shape, &data).map(|t| t.into_tvalue())
+    };
+    let mut allocations = 0;
+    for run in 0..8 {
+        let batch = run % 4 + 1;
+        let inputs = tvec!(input([batch, 40, 72], run)?, input([batch, 72, 24], 3)?);
+        let found =
+            state.run_plan_with_eval(inputs.clone(), |session, op_state, node, inputs| {
+                let counted = node.op_is::<LirMatMulUnary>();
+                if counted {
+                    ALLOCATIONS.with(|c| c.set(Some(0)));
+                }
+                let outputs = tract_core::plan::eval(session, op_state, node, inputs);
+                if counted {
+                    allocations += ALLOCATIONS.with(|c| c.replace(None)).unwrap();
+                }
+                outputs
+            })?;
+        let expected = reference.run(inputs)?;
+        found[0].close_enough(&expected[0], Approximation::Approximate)?;
+    }
+    assert_eq!(allocations, 0);
+    Ok(())
+}
@@ -8,6 +8,7 @@ use ndarray::prelude::*;
 #[cfg(feature = "complex")]
 use num_complex::Complex;
 use std::alloc;
+use std::any::Any;
 use std::borrow::Cow;
 use std::fmt;
 use std::hash::Hash;
@@ -49,7 +50,6 @@ impl Approximation {
 }
 
 /// Tensor is a concrete tensor in tract.
-#[derive(Eq)]
 pub struct Tensor {
     dt: DatumType,
     shape: TVec<usize>,
@@ -57,8 +57,12 @@ pub struct Tensor {
     len: usize,
     layout: alloc::Layout,
     data: *mut u8,
+    /// Keeps alive the storage of tensors whose data is not owned by the allocator.
+    external: Option<Arc<dyn Any + Send + Sync>>,
 }
 
+impl Eq for Tensor {}
+
 unsafe impl Send for Tensor {}
 unsafe impl Sync for Tensor {}
 
@@ -143,7 +147,7 @@ impl Drop for Tensor {
                     .for_each(|s| std::ptr::drop_in_place(s as *mut TDim));
             }
         }
-        if !self.data.is_null() && self.layout.size() > 0 {
+        if !self.data.is_null() && self.layout.size() > 0 && self.external.is_none() {
             unsafe { alloc::dealloc(self.data, self.layout) }
         }
     }
@@ -191,7 +195,15 @@ impl Tensor {
             assert!(!ptr.is_null());
             ptr
         } as *mut u8;
-        let mut tensor = Tensor { strides: tvec!(), layout, dt, shape: shape.into(), data, len: 0 };
+        let mut tensor = Tensor {
+            strides: tvec!(),
+            layout,
+            dt,
+            shape: shape.into(),
+            data,
+            len: 0,
+            external: None,
+        };
         tensor.update_strides_and_len();
         #[cfg(debug_assertions)]
         if !data.is_null() {
@@ -205,6 +217,44 @@ impl Tensor {
         Ok(tensor)
     }
 
+    /// Create a tensor over an externally managed, uninitialized buffer.
+    ///
+    /// `data` must point to at least `shape.product() * dt.size_of()` bytes, aligned on
+    /// `alignment`, and stay valid as long as `storage` is alive. The buffer is never
+    /// deallocated by the tensor, it only keeps `storage` alive. Only copy types are supported.
+    pub unsafe fn from_external_storage(
+        dt: DatumType,
+        shape: &[usize],
+        alignment: usize,
+        data: *mut u8,
+        storage: Arc<dyn Any + Send + Sync>,
+    ) -> anyhow::Result<Tensor> {
+        anyhow::ensure!(dt.is_copy(), "External storage only supports copy types, got {:?}", dt);
+        anyhow::ensure!(
+            data as usize % alignment == 0,
+            "External storage is not aligned on {} bytes",
+            alignment
+        );
+        let bytes = shape.iter().cloned().product::<usize>() * dt.size_of();
+        let layout = alloc::Layout::from_size_align(bytes, alignment)?;
+        let mut tensor = Tensor {
+            strides: tvec!(),
+            layout,
+            dt,
+            shape: shape.into(),
+            data,
+            len: 0,
+            external: Some(storage),
+        };
+        tensor.update_strides_and_len();
+        Ok(tensor)
+    }
+
+    /// Is the tensor data backed by an external storage (see `from_external_storage`) ?
+    pub fn is_externally_stored(&self) -> bool {
+        self.external.is_some()
+    }
+
     pub fn stack_tensors(
         axis: usize,
         tensors: &[impl std::borrow::Borrow<Tensor>],
@@ -1218,8 +1268,15 @@ impl Tensor {
             let shape = it.shape().into();
             let vec = it.into_raw_vec().into_boxed_slice();
             let data = Box::into_raw(vec) as *mut u8;
-            let mut t =
-                Tensor { dt: T::datum_type(), shape, layout, data, strides: tvec!(), len: 0 };
+            let mut t = Tensor {
+                dt: T::datum_type(),
+                shape,
+                layout,
+                data,
+                strides: tvec!(),
+                len: 0,
+                external: None,
+            };
             t.update_strides_and_len();
             return t;
         }
@@ -1263,6 +1320,7 @@ impl Tensor {
                 data: data as *mut u8,
                 shape: self.shape.clone(),
                 strides: self.strides.clone(),
+                external: None,
                 ..*self
             }
         } else if self.dt == DatumType::TDim {
@@ -1273,6 +1331,7 @@ impl Tensor {
                 data: data as *mut u8,
                 shape: self.shape.clone(),
                 strides: self.strides.clone(),
+                external: None,
                 ..*self
             }
         } else {
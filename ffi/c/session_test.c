@@ -0,0 +1,108 @@
+// Drives the session API from C. Built against libtract and run by the "session_c" test of the
+// "session" feature, on its model: "y" = x [2, 3] by [3, 4], "z" = x transposed by [2, 3].
+#include <math.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <string.h>
+
+// Declarations of the tract.h header generated by cbindgen.
+typedef enum TRACT_RESULT { TRACT_RESULT_OK = 0, TRACT_RESULT_KO = 1 } TRACT_RESULT;
+typedef enum TractDatumType { TRACT_DATUM_TYPE_F32 = 0x34 } TractDatumType;
+typedef struct TractRunnable TractRunnable;
+typedef struct TractSession TractSession;
+typedef struct TractTensorDesc {
+    TractDatumType datum_type;
+    uintptr_t rank;
+    const uintptr_t *shape;
+    const intptr_t *strides;
+    const void *data;
+} TractTensorDesc;
+
+const char *tract_get_last_error(void);
+TRACT_RESULT tract_runnable_for_path(const char *path, TractRunnable **runnable);
+TRACT_RESULT tract_runnable_release(TractRunnable **runnable);
+TRACT_RESULT tract_session_create(const TractRunnable *runnable, TractSession **session);
+TRACT_RESULT tract_session_set_input(TractSession *session, const char *name,
+                                     const TractTensorDesc *tensor);
+TRACT_RESULT tract_session_run(TractSession *session);
+TRACT_RESULT tract_session_output(const TractSession *session, const char *name,
+                                  TractTensorDesc *tensor);
+TRACT_RESULT tract_session_destroy(TractSession **session);
+
+#define check(call)                                                                                \
+    if ((call) != TRACT_RESULT_OK)                                                                 \
+        return __LINE__;
+#define ensure(cond)                                                                               \
+    if (!(cond))                                                                                   \
+        return __LINE__;
+
+static int check_output(const TractSession *session, const char *name, uintptr_t rows,
+                        uintptr_t cols, const float *expected) {
+    TractTensorDesc output;
+    check(tract_session_output(session, name, &output));
+    ensure(output.datum_type == TRACT_DATUM_TYPE_F32 && output.rank == 2);
+    ensure(output.shape[0] == rows && output.shape[1] == cols);
+    const float *data = output.data;
+    for (uintptr_t r = 0; r < rows; r++) {
+        for (uintptr_t c = 0; c < cols; c++) {
+            float found = data[r * output.strides[0] + c * output.strides[1]];
+            ensure(fabsf(found - expected[r * cols + c]) < 1e-5f);
+        }
+    }
+    return 0;
+}
+
+static int run(const char *model_path) {
+    TractRunnable *runnable = NULL;
+    check(tract_runnable_for_path(model_path, &runnable));
+    TractSession *session = NULL;
+    check(tract_session_create(runnable, &session));
+    // the session keeps the model alive
+    check(tract_runnable_release(&runnable));
+    ensure(runnable == NULL);
+
+    // running before setting the inputs is an error
+    ensure(tract_session_run(session) == TRACT_RESULT_KO);
+    ensure(strstr(tract_get_last_error(), "not set") != NULL);
+    uintptr_t shape[] = {2, 3};
+    float x[] = {1, 2, 3, 4, 5, 6};
+    TractTensorDesc input = {TRACT_DATUM_TYPE_F32, 2, shape, NULL, x};
+    ensure(tract_session_set_input(session, "nope", &input) == TRACT_RESULT_KO);
+    ensure(strstr(tract_get_last_error(), "No input named") != NULL);
+
+    const float y[] = {1, 2, 3, 6, 4, 5, 6, 15};
+    const float z[] = {17, 22, 27, 22, 29, 36, 27, 36, 45};
+    // contiguous
+    check(tract_session_set_input(session, "x", &input));
+    check(tract_session_run(session));
+    int error = check_output(session, "y", 2, 4, y) || check_output(session, "z", 3, 3, z);
+    ensure(!error);
+
+    // the same x, stored column major
+    float x_t[] = {1, 4, 2, 5, 3, 6};
+    intptr_t strides[] = {1, 2};
+    TractTensorDesc strided = {TRACT_DATUM_TYPE_F32, 2, shape, strides, x_t};
+    check(tract_session_set_input(session, "x", &strided));
+    check(tract_session_run(session));
+    error = check_output(session, "y", 2, 4, y) || check_output(session, "z", 3, 3, z);
+    ensure(!error);
+
+    check(tract_session_destroy(&session));
+    ensure(session == NULL);
+    return 0;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 2) {
+        fprintf(stderr, "usage: %s model.nnef.tar\n", argv[0]);
+        return 1;
+    }
+    int line = run(argv[1]);
+    if (line) {
+        const char *error = tract_get_last_error();
+        fprintf(stderr, "failed at line %d (%s)\n", line, error ? error : "no tract error");
+        return 1;
+    }
+    return 0;
+}
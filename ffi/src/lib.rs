@@ -1205,3 +1205,6 @@ unsafe fn state_run(
     }
     Ok(())
 }
+
+#[cfg(feature = "session")]
+pub mod session;
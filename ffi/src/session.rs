@@ -0,0 +1,317 @@
+//! Named inputs and outputs, set and read from caller memory.
+//!
+//! A `TractSession` runs an optimized model on tensors described by a `TractTensorDesc`: the
+//! caller memory is used as is when it is contiguous and aligned, copied otherwise. Strided
+//! descriptors let the caller feed a transposed view of its activations without transposing
+//! them first.
+use super::*;
+use tract_nnef::tract_core::ndarray::{indices, Dimension};
+
+/// A tensor in caller memory.
+///
+/// `shape` points to `rank` dimensions. `strides`, in items, points to `rank` strides, or is
+/// null for a contiguous (row major) tensor.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TractTensorDesc {
+    pub datum_type: TractDatumType,
+    pub rank: usize,
+    pub shape: *const usize,
+    pub strides: *const isize,
+    pub data: *const c_void,
+}
+
+pub struct TractSession {
+    state: NativeState,
+    input_names: Vec<String>,
+    inputs: TVec<Option<TValue>>,
+    output_names: Vec<String>,
+    outputs: TVec<TValue>,
+}
+
+/// Load a NNEF model (a directory, a tar or a tar.gz archive, with the tract_core, onnx and
+/// pulse extensions), optimize it and make it runnable.
+///
+/// `path` is a null-terminated utf-8 string pointer. The runnable must be released with
+/// `tract_runnable_release`.
+#[no_mangle]
+pub unsafe extern "C" fn tract_runnable_for_path(
+    path: *const c_char,
+    runnable: *mut *mut TractRunnable,
+) -> TRACT_RESULT {
+    wrap(|| unsafe {
+        check_not_null!(path, runnable);
+        *runnable = std::ptr::null_mut();
+        let path = CStr::from_ptr(path).to_str()?;
+        use tract_onnx::WithOnnx;
+        use tract_pulse::WithPulse;
+        let nnef = tract_nnef::nnef().with_tract_core().with_onnx().with_pulse();
+        let model = nnef.model_for_path(path).with_context(|| format!("opening file {path:?}"))?;
+        let model = model.into_optimized()?.into_runnable()?;
+        *runnable = Box::into_raw(Box::new(TractRunnable(Arc::new(model))));
+        Ok(())
+    })
+}
+
+/// Create a session running the runnable model.
+///
+/// The session keeps the runnable alive. It must be destroyed with `tract_session_destroy`.
+#[no_mangle]
+pub unsafe extern "C" fn tract_session_create(
+    runnable: *const TractRunnable,
+    session: *mut *mut TractSession,
+) -> TRACT_RESULT {
+    wrap(|| unsafe {
+        check_not_null!(runnable, session);
+        *session = std::ptr::null_mut();
+        let state = native::TypedSimpleState::new((*runnable).0.clone())?;
+        let model = state.model();
+        let names = |outlets: &[OutletId]| -> Vec<String> {
+            outlets.iter().map(|o| model.node(o.node).name.clone()).collect()
+        };
+        let input_names = names(model.input_outlets()?);
+        let output_names = names(model.output_outlets()?);
+        let inputs = tvec!(None; input_names.len());
+        let it = TractSession { state, input_names, inputs, output_names, outputs: tvec!() };
+        *session = Box::into_raw(Box::new(it));
+        Ok(())
+    })
+}
+
+/// Set the input named `name` for the next runs.
+///
+/// When the tensor is contiguous and its data aligned for its datum type, the session uses the
+/// caller memory as is: it must stay valid, and unchanged, until the input is set again or the
+/// session is destroyed, and as long as the outputs of the runs are read. Otherwise, the tensor
+/// is copied, and the pointers only need to be valid for the duration of the call.
+#[no_mangle]
+pub unsafe extern "C" fn tract_session_set_input(
+    session: *mut TractSession,
+    name: *const c_char,
+    tensor: *const TractTensorDesc,
+) -> TRACT_RESULT {
+    wrap(|| unsafe {
+        check_not_null!(session, name, tensor);
+        let session = &mut *session;
+        let name = CStr::from_ptr(name).to_str()?;
+        let slot = session
+            .input_names
+            .iter()
+            .position(|n| n == name)
+            .with_context(|| format!("No input named {name:?}"))?;
+        let value = tensor_from_desc(&*tensor)
+            .with_context(|| format!("Setting input {name:?} from {:?}", *tensor))?;
+        session.inputs[slot] = Some(value.into_tvalue());
+        Ok(())
+    })
+}
+
+/// Run the model on the inputs set, all of them must be.
+#[no_mangle]
+pub unsafe extern "C" fn tract_session_run(session: *mut TractSession) -> TRACT_RESULT {
+    wrap(|| unsafe {
+        check_not_null!(session);
+        let session = &mut *session;
+        session.outputs.clear();
+        let inputs = session
+            .inputs
+            .iter()
+            .zip(&session.input_names)
+            .map(|(value, name)| value.clone().with_context(|| format!("Input {name:?} not set")))
+            .collect::<TractResult<TVec<_>>>()?;
+        // the session keeps the inputs: the model never computes in place of caller memory
+        session.outputs = session.state.run(inputs)?;
+        Ok(())
+    })
+}
+
+/// Describe the output named `name` of the last run in `tensor`.
+///
+/// The described memory belongs to the session: it is valid until the next run or the
+/// destruction of the session. The strides are always filled.
+#[no_mangle]
+pub unsafe extern "C" fn tract_session_output(
+    session: *const TractSession,
+    name: *const c_char,
+    tensor: *mut TractTensorDesc,
+) -> TRACT_RESULT {
+    wrap(|| unsafe {
+        check_not_null!(session, name, tensor);
+        let session = &*session;
+        let name = CStr::from_ptr(name).to_str()?;
+        let slot = session
+            .output_names
+            .iter()
+            .position(|n| n == name)
+            .with_context(|| format!("No output named {name:?}"))?;
+        let value = session.outputs.get(slot).context("The session has not run")?;
+        *tensor = TractTensorDesc {
+            datum_type: value.datum_type().try_into()?,
+            rank: value.rank(),
+            shape: value.shape().as_ptr(),
+            strides: value.strides().as_ptr(),
+            data: value.as_ptr_unchecked::<u8>() as _,
+        };
+        Ok(())
+    })
+}
+
+/// Destroy a session, its inputs and outputs.
+#[no_mangle]
+pub unsafe extern "C" fn tract_session_destroy(session: *mut *mut TractSession) -> TRACT_RESULT {
+    release!(session)
+}
+
+/// The tensor of a descriptor: over the caller memory if it is contiguous and aligned, copied
+/// otherwise.
+unsafe fn tensor_from_desc(desc: &TractTensorDesc) -> TractResult<Tensor> {
+    let dt: DatumType = desc.datum_type.into();
+    check_not_null!(desc.data);
+    if desc.rank > 0 {
+        check_not_null!(desc.shape);
+    }
+    let shape = if desc.rank > 0 { std::slice::from_raw_parts(desc.shape, desc.rank) } else { &[] };
+    let mut contiguous: TVec<isize> = tvec!(1; shape.len());
+    for axis in (0..shape.len().saturating_sub(1)).rev() {
+        contiguous[axis] = contiguous[axis + 1] * shape[axis + 1] as isize;
+    }
+    let strides = if desc.strides.is_null() {
+        &contiguous
+    } else {
+        std::slice::from_raw_parts(desc.strides, desc.rank)
+    };
+    // strides along axes of one item are irrelevant
+    let is_contiguous =
+        shape.iter().zip(strides.iter().zip(&contiguous)).all(|(d, (s, c))| *d == 1 || s == c);
+    if is_contiguous && desc.data as usize % dt.alignment() == 0 {
+        let data = desc.data as *mut u8;
+        return Tensor::from_external_storage(dt, shape, dt.alignment(), data, Arc::new(()));
+    }
+    // copied by runs of contiguous items, the data may not be aligned for its datum type
+    let (outer, run) = match (shape.split_last(), strides.last()) {
+        (Some((&len, outer)), Some(1)) => (outer, len),
+        _ => (shape, 1),
+    };
+    let size = dt.size_of();
+    let mut tensor = Tensor::uninitialized_dt(dt, shape)?;
+    let dst = tensor.as_bytes_mut().as_mut_ptr();
+    for (ix, coords) in indices(outer).into_iter().enumerate() {
+        let offset =
+            coords.slice().iter().zip(strides).map(|(c, s)| *c as isize * s).sum::<isize>();
+        let src = (desc.data as *const u8).offset(offset * size as isize);
+        std::ptr::copy_nonoverlapping(src, dst.add(ix * run * size), run * size);
+    }
+    Ok(tensor)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tract_nnef::tract_core::ops::einsum::EinSum;
+
+    /// "y" = x [2, 3] by [3, 4], "z" = x transposed by [2, 3].
+    fn model_path(name: &str) -> TractResult<CString> {
+        let mut model = TypedModel::default();
+        let x = model.add_source("x", f32::fact([2, 3]))?;
+        let w = [1f32, 0., 0., 1., 0., 1., 0., 1., 0., 0., 1., 1.];
+        let w = model.add_const("w", Tensor::from_shape(&[3, 4], &w)?)?;
+        let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let y = model.wire_node("y", einsum, &[x, w])?;
+        let v = model.add_const("v", Tensor::from_shape(&[2, 3], &[1f32, 2., 3., 4., 5., 6.])?)?;
+        let einsum = EinSum::new("km,kn->mn".parse()?, f32::datum_type());
+        let z = model.wire_node("z", einsum, &[x, v])?;
+        model.set_output_outlets(&[y[0], z[0]])?;
+        let path = format!("tract-{name}-{}.nnef.tar", std::process::id());
+        let path = std::env::temp_dir().join(path);
+        tract_nnef::nnef().with_tract_core().write_to_tar(&model, std::fs::File::create(&path)?)?;
+        Ok(CString::new(path.to_str().context("Non utf-8 path")?)?)
+    }
+
+    fn desc(shape: &[usize], strides: Option<&[isize]>, data: &[f32]) -> TractTensorDesc {
+        TractTensorDesc {
+            datum_type: TractDatumType::TRACT_DATUM_TYPE_F32,
+            rank: shape.len(),
+            shape: shape.as_ptr(),
+            strides: strides.map(|s| s.as_ptr()).unwrap_or(std::ptr::null()),
+            data: data.as_ptr() as _,
+        }
+    }
+
+    unsafe fn output(session: *const TractSession, name: &str) -> TractResult<Tensor> {
+        let name = CString::new(name)?;
+        let mut found = desc(&[], None, &[]);
+        assert_eq!(tract_session_output(session, name.as_ptr(), &mut found), TRACT_RESULT_OK);
+        tensor_from_desc(&found).map(|t| t.deep_clone())
+    }
+
+    unsafe fn last_error() -> String {
+        CStr::from_ptr(tract_get_last_error()).to_string_lossy().into_owned()
+    }
+
+    use TRACT_RESULT::*;
+
+    #[test]
+    fn run_through_c_signatures() -> TractResult<()> {
+        unsafe {
+            let path = model_path("session")?;
+            let mut runnable = std::ptr::null_mut();
+            assert_eq!(tract_runnable_for_path(path.as_ptr(), &mut runnable), TRACT_RESULT_OK);
+            let mut session = std::ptr::null_mut();
+            assert_eq!(tract_session_create(runnable, &mut session), TRACT_RESULT_OK);
+            assert_eq!(tract_runnable_release(&mut runnable), TRACT_RESULT_OK);
+            assert_eq!(tract_session_run(session), TRACT_RESULT_KO);
+            assert!(last_error().contains("Input \"x\" not set"), "{}", last_error());
+            let x = CString::new("x")?;
+            let y = tensor2(&[[1f32, 2., 3., 6.], [4., 5., 6., 15.]]);
+            let z = tensor2(&[[17f32, 22., 27.], [22., 29., 36.], [27., 36., 45.]]);
+            // row major, then the same x stored column major
+            let row_major = [1f32, 2., 3., 4., 5., 6.];
+            let col_major = [1f32, 4., 2., 5., 3., 6.];
+            for input in [desc(&[2, 3], None, &row_major), desc(&[2, 3], Some(&[1, 2]), &col_major)]
+            {
+                assert_eq!(tract_session_set_input(session, x.as_ptr(), &input), TRACT_RESULT_OK);
+                assert_eq!(tract_session_run(session), TRACT_RESULT_OK);
+                assert_eq!(output(session, "y")?, y);
+                assert_eq!(output(session, "z")?, z);
+            }
+            let nope = CString::new("nope")?;
+            let input = desc(&[2, 3], None, &row_major);
+            assert_eq!(tract_session_set_input(session, nope.as_ptr(), &input), TRACT_RESULT_KO);
+            assert!(last_error().contains("No input named \"nope\""));
+            assert_eq!(tract_session_destroy(&mut session), TRACT_RESULT_OK);
+            assert!(session.is_null());
+            std::fs::remove_file(path.to_str()?)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn zero_copy_when_contiguous_and_aligned() -> TractResult<()> {
+        unsafe {
+            let data = [0f32, 1., 2., 3., 4., 5., 6.];
+            let expected = tensor2(&[[0f32, 1., 2.], [3., 4., 5.]]);
+            let contiguous = tensor_from_desc(&desc(&[2, 3], None, &data))?;
+            assert!(contiguous.is_externally_stored());
+            assert_eq!(contiguous.as_ptr::<f32>()?, data.as_ptr());
+            assert_eq!(contiguous, expected);
+            // explicit contiguous strides, any stride along an axis of one item
+            let explicit = tensor_from_desc(&desc(&[2, 1, 3], Some(&[3, 17, 1]), &data))?;
+            assert!(explicit.is_externally_stored());
+            // strided: copied
+            let strided = tensor_from_desc(&desc(&[3, 2], Some(&[1, 3]), &data))?;
+            assert!(!strided.is_externally_stored());
+            assert_eq!(strided, expected.permute_axes(&[1, 0])?);
+            // misaligned: copied
+            let bytes = data.iter().flat_map(|f| f.to_ne_bytes()).collect::<Vec<u8>>();
+            let misaligned = bytes.as_ptr().add(1 + (bytes.as_ptr() as usize % 4 == 3) as usize);
+            let mut input = desc(&[2], None, &data);
+            input.data = misaligned as _;
+            let copied = tensor_from_desc(&input)?;
+            assert!(!copied.is_externally_stored());
+            let mut expected = [0u8; 8];
+            std::ptr::copy_nonoverlapping(misaligned, expected.as_mut_ptr(), 8);
+            assert_eq!(copied.as_bytes(), expected);
+        }
+        Ok(())
+    }
+}
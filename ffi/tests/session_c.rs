@@ -0,0 +1,52 @@
+//! Drives the session API from the C program in c/session_test.c, compiled against the shared
+//! library. The program is only built here: it is no part of the library.
+#![cfg(all(feature = "session", unix))]
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tract_nnef::tract_core::internal::*;
+use tract_nnef::tract_core::ops::einsum::EinSum;
+
+/// "y" = x [2, 3] by [3, 4], "z" = x transposed by [2, 3].
+fn write_model(path: &Path) -> TractResult<()> {
+    let mut model = TypedModel::default();
+    let x = model.add_source("x", f32::fact([2, 3]))?;
+    let w = [1f32, 0., 0., 1., 0., 1., 0., 1., 0., 0., 1., 1.];
+    let w = model.add_const("w", Tensor::from_shape(&[3, 4], &w)?)?;
+    let einsum = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+    let y = model.wire_node("y", einsum, &[x, w])?;
+    let v = model.add_const("v", Tensor::from_shape(&[2, 3], &[1f32, 2., 3., 4., 5., 6.])?)?;
+    let einsum = EinSum::new("km,kn->mn".parse()?, f32::datum_type());
+    let z = model.wire_node("z", einsum, &[x, v])?;
+    model.set_output_outlets(&[y[0], z[0]])?;
+    tract_nnef::nnef().with_tract_core().write_to_tar(&model, std::fs::File::create(path)?)?;
+    Ok(())
+}
+
+#[test]
+fn run_from_c() -> TractResult<()> {
+    // libtract is built in the deps directory, with the test binary
+    let exe = std::env::current_exe()?;
+    let lib_dir = exe.parent().context("No deps directory")?;
+    let scratch = std::env::temp_dir().join(format!("tract-session-c-{}", std::process::id()));
+    std::fs::create_dir_all(&scratch)?;
+    let (driver, model) = (scratch.join("session_test"), scratch.join("model.nnef.tar"));
+    write_model(&model)?;
+    let source: PathBuf = [env!("CARGO_MANIFEST_DIR"), "c", "session_test.c"].iter().collect();
+    let compiler = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let status = Command::new(compiler)
+        .arg(&source)
+        .arg("-o")
+        .arg(&driver)
+        .arg("-L")
+        .arg(lib_dir)
+        .args(["-ltract", "-lm"])
+        .status()?;
+    ensure!(status.success(), "Failed to compile {source:?}");
+    let library_path =
+        if cfg!(target_os = "macos") { "DYLD_LIBRARY_PATH" } else { "LD_LIBRARY_PATH" };
+    let output = Command::new(&driver).arg(&model).env(library_path, lib_dir).output()?;
+    std::fs::remove_dir_all(&scratch)?;
+    ensure!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    Ok(())
+}
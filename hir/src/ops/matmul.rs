@@ -53,26 +53,32 @@ impl Expansion for MatMulInference {
         target: &mut TypedModel,
         inputs: &[OutletId],
     ) -> TractResult<TVec<OutletId>> {
+        let mut inputs: TVec<OutletId> = inputs.into();
+        // numpy semantics: a vector a is a single row, a vector b a single column
         let implicit_m = target.outlet_fact(inputs[0])?.rank() < 2;
         let implicit_n = target.outlet_fact(inputs[1])?.rank() < 2;
-        let inputs = crate::ops::binary::wire_rank_broadcast(prefix, target, inputs)?;
+        if implicit_m {
+            let add = AxisOp::Add(self.a_trans as usize);
+            inputs[0] = target.wire_node(format!("{prefix}.implicit_m"), add, &[inputs[0]])?[0];
+        }
+        if implicit_n {
+            let add = AxisOp::Add(!self.b_trans as usize);
+            inputs[1] = target.wire_node(format!("{prefix}.implicit_n"), add, &[inputs[1]])?[0];
+        }
+        let inputs = crate::ops::binary::wire_rank_broadcast(prefix, target, &inputs)?;
         let fact = target.outlet_fact(inputs[0])?;
-        let mut axes = AxesMapping::for_numpy_matmul(fact.rank(), self.a_trans, self.b_trans, self.c_trans)?;
+        let rank = fact.rank();
+        let mut axes =
+            AxesMapping::for_numpy_matmul(rank, self.a_trans, self.b_trans, self.c_trans)?;
         if implicit_m {
-            let a = InOut::In(0);
-            let m_axis = axes.axis((a, axes.rank(a) - 2))?;
+            let m_axis = axes.axis((InOut::In(0), rank - 2 + self.a_trans as usize))?;
             axes = axes.remove_output_axis(0, m_axis.outputs[0][0])?;
         }
         if implicit_n {
-            let b = InOut::In(1);
-            let n_axis = axes.axis((b, axes.rank(b) - 2))?;
+            let n_axis = axes.axis((InOut::In(1), rank - 1 - self.b_trans as usize))?;
             axes = axes.remove_output_axis(0, n_axis.outputs[0][0])?;
         }
-        target.wire_node(
-            prefix,
-            EinSum { axes, operating_dt: fact.datum_type, q_params: None },
-            &inputs,
-        )
+        target.wire_node(prefix, EinSum::new(axes, fact.datum_type), &inputs)
     }
 }
 
@@ -100,12 +106,10 @@ pub fn compute_shapes<D: DimLike>(
     while bshape.len() < ashape.len() {
         bshape.insert(0, D::one());
     }
-    let c_bc_shape_prefix = tract_core::broadcast::multi_broadcast(&[
+    let mut c_bc_shape: TVec<D> = tract_core::broadcast::broadcast_shapes(&[
         &ashape[..(ashape.len() - 2)],
         &bshape[..(bshape.len() - 2)],
-    ])
-    .ok_or_else(|| format_err!("Could not broadcast"))?;
-    let mut c_bc_shape: TVec<D> = c_bc_shape_prefix;
+    ])?;
     let (mut m, mut ka) = (ashape[ashape.len() - 2].clone(), ashape[ashape.len() - 1].clone());
     let (mut kb, mut n) = (bshape[bshape.len() - 2].clone(), bshape[bshape.len() - 1].clone());
     if a_trans {
@@ -146,3 +150,118 @@ pub fn compute_shapes<D: DimLike>(
     }
     Ok((ashape, bshape, c_bc_shape, c_shape_final))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infer::InferenceModel;
+
+    fn konst(shape: &[usize]) -> Tensor {
+        let len = shape.iter().product::<usize>();
+        Tensor::from_shape(shape, &(0..len).map(|i| (i % 7) as f32 - 3.).collect::<Vec<_>>())
+            .unwrap()
+    }
+
+    /// The same product, imported through the inference matmul and written as an einsum.
+    fn both_paths(
+        op: MatMulInference,
+        a_shape: &[usize],
+        b_shape: &[usize],
+        expr: &str,
+    ) -> TractResult<(TypedModel, TypedModel)> {
+        let mut inference = InferenceModel::default();
+        let a = inference.add_source("a", f32::fact(a_shape).into())?;
+        let b = inference.add_const("b", konst(b_shape))?;
+        let c = inference.wire_node("matmul", expand(op), &[a, b])?;
+        inference.set_output_outlets(&c)?;
+
+        let mut typed = TypedModel::default();
+        let a = typed.add_source("a", f32::fact(a_shape))?;
+        let b = typed.add_const("b", konst(b_shape))?;
+        let einsum = EinSum::new(expr.parse()?, f32::datum_type());
+        let c = typed.wire_node("matmul", einsum, &[a, b])?;
+        typed.set_output_outlets(&c)?;
+        Ok((inference.into_typed()?, typed))
+    }
+
+    fn structure(model: &TypedModel) -> TractResult<Vec<String>> {
+        model
+            .eval_order()?
+            .into_iter()
+            .map(|id| {
+                let node = model.node(id);
+                Ok(format!(
+                    "{} {} {:?} {:?}",
+                    node.name,
+                    node.op.name(),
+                    node.op.info()?,
+                    node.outputs[0].fact
+                ))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn inference_and_typed_matmuls_converge() -> TractResult<()> {
+        let default = MatMulInference::default();
+        for (op, a_shape, b_shape, expr) in [
+            (default.clone(), &[2, 3, 4][..], &[4, 5][..], "bmk,kn->bmn"),
+            (default.clone().with_a_trans(true), &[4, 3], &[4, 5], "km,kn->mn"),
+            (default.clone().with_b_trans(true), &[3, 4], &[5, 4], "mk,nk->mn"),
+            (default.clone(), &[4], &[4, 5], "k,kn->n"),
+            (default.clone().with_a_trans(true), &[4], &[4, 5], "k,kn->n"),
+            (default.clone(), &[3, 4], &[4], "mk,k->m"),
+            (default.with_b_trans(true), &[3, 4], &[4], "mk,k->m"),
+        ] {
+            let (inference, typed) = both_paths(op, a_shape, b_shape, expr)?;
+            let input = tvec!(konst(a_shape).into_tvalue());
+            let expected = typed.clone().into_runnable()?.run(input.clone())?;
+            let found = inference.clone().into_runnable()?.run(input.clone())?;
+            assert_eq!(found, expected, "{expr}");
+            let (inference, typed) = (inference.into_optimized()?, typed.into_optimized()?);
+            assert_eq!(structure(&inference)?, structure(&typed)?, "{expr}");
+            assert_eq!(inference.into_runnable()?.run(input.clone())?, expected, "{expr}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn inference_and_einsum_shapes_agree() -> TractResult<()> {
+        let table = SymbolTable::default();
+        let s = table.sym("S").to_dim();
+        let d = |dims: &[i64]| -> TVec<TDim> {
+            dims.iter().map(|d| if *d < 0 { s.clone() } else { d.to_dim() }).collect()
+        };
+        // -1 stands for S
+        for (a, b) in [
+            (&[2, 3, 4][..], &[4, 5][..]),
+            (&[1, 3, 4], &[-1, 4, 5]),
+            (&[-1, 3, 4], &[1, 4, 5]),
+            // used to be rejected by inference, but not by einsum
+            (&[-1, 3, 4], &[2, 4, 5]),
+            (&[2, 1, 3, 4], &[-1, 4, 5]),
+            (&[2, 3, 4], &[3, 4, 5]),
+        ] {
+            let (a, b) = (d(a), d(b));
+            let inferred = compute_shapes(a.clone(), b.clone(), false, false, false);
+            let (a, b) = (f32::fact(&a), f32::fact(&b));
+            let rank = a.rank().max(b.rank());
+            let einsum = EinSum::new(
+                AxesMapping::for_numpy_matmul(rank, false, false, false)?,
+                f32::datum_type(),
+            );
+            let mut model = TypedModel::default();
+            let mut wires = tvec!(model.add_source("a", a)?, model.add_source("b", b)?);
+            wires = tract_core::ops::binary::wire_rank_broadcast("bc", &mut model, &wires)?;
+            let typed = model.wire_node("einsum", einsum, &wires);
+            match (inferred, typed) {
+                (Ok(inferred), Ok(typed)) => {
+                    assert_eq!(inferred.3, model.outlet_fact(typed[0])?.shape.to_tvec())
+                }
+                (Err(_), Err(_)) => (),
+                (inferred, typed) => panic!("{inferred:?} vs {typed:?}"),
+            }
+        }
+        Ok(())
+    }
+}
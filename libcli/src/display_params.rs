@@ -18,12 +18,14 @@ pub struct DisplayParams {
     pub debug_op: bool,
     pub cost: bool,
     pub profile: bool,
+    pub profile_fused_specs: bool,
     pub folded: bool,
     pub node_ids: Option<Vec<TVec<(usize, String)>>>,
     pub op_name: Option<String>,
     pub node_name: Option<String>,
     pub expect_core: bool,
     pub outlet_labels: bool,
+    pub patches: bool,
     pub io: Io,
     pub json: bool,
     pub info: bool,
@@ -1,5 +1,6 @@
 use tract_core::{
     internal::*,
+    ops::matmul::roofline::{self, MachinePeaks},
     ops::{scan::State, submodel::TypedModelOpState},
 };
 
@@ -67,6 +68,79 @@ pub fn profile(
     Ok(())
 }
 
+/// Time the fused steps of the matrix multiplications, adding them to the node sections. This
+/// is a separate run, as the kernels are slower when their steps are timed.
+pub fn profile_fused_specs(
+    model: &TypedModel,
+    bench_limits: &BenchLimits,
+    dg: &mut Annotations,
+    inputs: &TVec<TValue>,
+) -> TractResult<()> {
+    let plan = TypedSimplePlan::new(model.clone())?.with_fused_spec_profiling(true);
+    let mut state = TypedSimpleState::new(Arc::new(plan))?;
+    let mut iters = 0usize;
+    let start = Instant::now();
+    while iters < bench_limits.max_iters && start.elapsed() < bench_limits.max_time {
+        state.run(inputs.clone())?;
+        iters += 1;
+    }
+    let mut sections: HashMap<usize, Vec<String>> = HashMap::default();
+    for (key, time) in state.fused_spec_profile().unwrap_or_default() {
+        let (name, spec) = key.rsplit_once('/').context("Malformed fused spec key")?;
+        let section = sections
+            .entry(model.node_id_by_name(name)?)
+            .or_insert_with(|| vec!["Fused specs:".to_string()]);
+        let time = time.mul_f32((iters as f32).recip());
+        section.push(format!("{spec}: {:.3} ms", time.as_secs_f64() * 1e3));
+    }
+    for (node, section) in sections {
+        dg.node_mut(NodeQId(tvec!(), node)).sections.push(section);
+    }
+    Ok(())
+}
+
+/// Add the roofline estimate of the matrix multiplications to the node sections, comparing it
+/// to their measured throughput if the model was profiled. The nodes running at less than a
+/// quarter of their attainable throughput are labelled.
+pub fn roofline(model: &TypedModel, dg: &mut Annotations, peaks: &MachinePeaks) -> TractResult<()> {
+    let profile = dg
+        .tags
+        .iter()
+        .filter(|(id, _)| id.0.is_empty())
+        .filter_map(|(id, tags)| Some((id.1, tags.profile?)))
+        .collect();
+    for estimate in roofline::roofline(model, peaks, &SymbolValues::default(), &profile)? {
+        let traffic = &estimate.traffic;
+        let mut section = vec![
+            format!("Roofline: {:?} bound", estimate.bound),
+            format!(
+                "{} flops, {} bytes (A: {}, B: {}, C: {}), {:.2} flops/byte",
+                traffic.flops,
+                traffic.bytes(),
+                traffic.a_bytes,
+                traffic.b_bytes,
+                traffic.c_bytes,
+                traffic.arithmetic_intensity()
+            ),
+            format!("Attainable: {:.3} GFLOP/s", estimate.attainable_flops / 1e9),
+        ];
+        if let (Some(measured), Some(efficiency)) = (estimate.measured_flops, estimate.efficiency())
+        {
+            section.push(format!(
+                "Measured: {:.3} GFLOP/s ({:.1}%)",
+                measured / 1e9,
+                efficiency * 100.
+            ));
+        }
+        let tags = dg.node_mut(estimate.node.into());
+        tags.sections.push(section);
+        if estimate.is_far_below(0.25) {
+            tags.labels.push("Far below roofline".to_string());
+        }
+    }
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn rec_profiler(
     state: &mut TypedSimpleState<TypedModel, Arc<TypedSimplePlan<TypedModel>>>,
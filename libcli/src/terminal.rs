@@ -264,6 +264,14 @@ fn render_node_prefixed(
             println!("  * {info}");
         }
     }
+    if options.patches {
+        if let Some(typed) = model.downcast_ref::<TypedModel>() {
+            for patch in typed.node_patches(node_id) {
+                prefix!();
+                println!("  * patched by: {patch}");
+            }
+        }
+    }
     if options.invariants {
         if let Some(typed) = model.downcast_ref::<TypedModel>() {
             let node = typed.node(node_id);
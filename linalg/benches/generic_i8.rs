@@ -0,0 +1,55 @@
+use criterion::*;
+use tract_data::internal::*;
+use tract_linalg::frame::mmm::{FusedSpec, MatMatMul, MatMatMulKer};
+use tract_linalg::generic::{GenericMmm4x4, GenericMmmI8};
+
+/// A 256x512 by 512x256 i8 product, run by the scalar generic kernel and by the vectorizable
+/// i8 one.
+fn generic_i8(c: &mut Criterion) {
+    let (m, k, n) = (256, 512, 256);
+    let a = (0..m * k).map(|i| (i % 251) as i8).collect::<Vec<_>>();
+    let b = (0..k * n).map(|i| (i % 241) as i8).collect::<Vec<_>>();
+    let a = Tensor::from_shape(&[m, k], &a).unwrap();
+    let b = Tensor::from_shape(&[k, n], &b).unwrap();
+    let mut group = c.benchmark_group("generic_i8");
+    group.throughput(Throughput::Elements((m * k * n) as u64));
+    let kernels: [(&str, Box<dyn MatMatMul>); 2] = [
+        ("scalar", GenericMmm4x4::<i8, i8, i32>::mmm()),
+        ("vectorized", GenericMmmI8::<8, 8>::mmm()),
+    ];
+    for (name, mmm) in kernels {
+        unsafe {
+            let (a_pack, b_pack) = (mmm.a_pack(), mmm.b_pack());
+            let mut pa =
+                Tensor::uninitialized_aligned::<i8>(&[a_pack.len(k, m)], a_pack.alignment())
+                    .unwrap();
+            a_pack.pack(pa.view_mut(), a.view(), 1, 0);
+            let mut pb =
+                Tensor::uninitialized_aligned::<i8>(&[b_pack.len(k, n)], b_pack.alignment())
+                    .unwrap();
+            b_pack.pack(pb.view_mut(), b.view(), 0, 1);
+            let mut c = Tensor::zero::<i32>(&[m, n]).unwrap();
+            group.bench_function(name, |be| {
+                be.iter(|| {
+                    mmm.run(
+                        m,
+                        n,
+                        &[
+                            FusedSpec::AddMatMul {
+                                a: mmm.a_packed(1, k).wrap(&pa.view()),
+                                b: mmm.b_packed(1, k).wrap(&pb.view()),
+                                k,
+                            },
+                            FusedSpec::Store(mmm.c_view(0, 1).wrap(&c.view_mut())),
+                        ],
+                    )
+                    .unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, generic_i8);
+criterion_main!(benches);
@@ -7,13 +7,11 @@ use DatumType::F32;
 fn mat_vec_mul(c: &mut Criterion) {
     let mut group = c.benchmark_group("mat_vec_mul");
     unsafe {
-        {
-            let (m, k) = &(768usize, 256usize);
+        for (m, k) in [(768usize, 256usize), (4096, 4096)] {
             group.throughput(Throughput::Elements((m * k) as u64));
-            group.bench_with_input(
-                BenchmarkId::from_parameter(format!("{m}x{k}")),
-                &(m, k),
-                |be, (&m, &k)| {
+            for pack_b in [false, true] {
+                let id = if pack_b { format!("{m}x{k}-pack_b") } else { format!("{m}x{k}") };
+                group.bench_with_input(BenchmarkId::from_parameter(id), &(m, k), |be, &(m, k)| {
                     let mm =
                         tract_linalg::ops().mmm(F32, F32, F32, Some(m), Some(k), Some(1)).unwrap();
                     let pa = Tensor::uninitialized_aligned::<f32>(
@@ -21,24 +19,35 @@ fn mat_vec_mul(c: &mut Criterion) {
                         mm.a_pack().alignment(),
                     )
                     .unwrap();
-                    let b = tensor1(&vec![0.0; k]);
+                    let b = Tensor::zero::<f32>(&[k, 1]).unwrap();
+                    let mut pb = Tensor::uninitialized_aligned::<f32>(
+                        &[mm.b_pack().len(k, 1)],
+                        mm.b_pack().alignment(),
+                    )
+                    .unwrap();
                     let mut c = Tensor::zero::<f32>(&[m]).unwrap();
                     be.iter(move || {
+                        let b = if pack_b {
+                            mm.b_pack().pack(pb.view_mut(), b.view(), 0, 1);
+                            pb.view()
+                        } else {
+                            b.view()
+                        };
                         mm.run(
                             m,
                             1,
                             &[
                                 FusedSpec::AddMatMul {
                                     a: mm.a_packed(F32.size_of(), k).wrap(&pa.view()),
-                                    b: mm.b_packed(b.datum_type().size_of(), k).wrap(&b.view()),
+                                    b: mm.b_packed(F32.size_of(), k).wrap(&b),
                                     k,
                                 },
                                 FusedSpec::Store(mm.c_view(0, 0).wrap(&c.view_mut())),
                             ],
                         )
                     });
-                },
-            );
+                });
+            }
         }
     }
     group.finish();
@@ -0,0 +1,24 @@
+#[macro_use]
+extern crate criterion;
+use criterion::Criterion;
+use tract_data::internal::*;
+use tract_linalg::frame::Packer;
+
+/// Packing a 2048x2048 operand with k inner to mn: strided gather vs blocked transposition.
+fn pack_k_inner(c: &mut Criterion) {
+    let (k, mn, r) = (2048, 2048, 16);
+    let packer = Packer::new(r, 32, 0);
+    let input = Tensor::zero::<f32>(&[mn, k]).unwrap();
+    let mut packed =
+        unsafe { Tensor::uninitialized_aligned::<f32>(&[packer.len(k, mn)], 32).unwrap() };
+    let mut group = c.benchmark_group("pack_k_inner");
+    group.bench_function("gather", |b| {
+        b.iter(|| unsafe { packer.pack(packed.view_mut(), input.view(), 1, 0) })
+    });
+    group.bench_function("blocked", |b| {
+        b.iter(|| unsafe { packer.pack_transposing(packed.view_mut(), input.view(), 1, 0) })
+    });
+}
+
+criterion_group!(benches, pack_k_inner);
+criterion_main!(benches);
@@ -1,6 +1,6 @@
 use std::fmt::Debug;
 
-use super::{InputStore, OutputStore, OutputStoreKer};
+use super::{InputStore, OutputStore, OutputStoreKer, ScratchNeed};
 use tract_data::internal::*;
 
 #[repr(usize)]
@@ -51,6 +51,31 @@ pub enum FusedSpec<'t> {
 }
 
 impl<'t> FusedSpec<'t> {
+    /// True if this is a Store that converts from the kernel accumulator type `ti`.
+    pub fn converting_store(&self, ti: DatumType) -> bool {
+        matches!(self, FusedSpec::Store(store) if store.converts_from(ti))
+    }
+
+    /// What the spec takes in the scratch space.
+    pub fn scratch_need(&self) -> ScratchNeed {
+        match self {
+            FusedSpec::BinPerRow(..) => ScratchNeed::PerRow,
+            FusedSpec::BinPerCol(..) => ScratchNeed::PerCol,
+            FusedSpec::AddRowColProducts(..) => ScratchNeed::RowColProducts,
+            FusedSpec::AddUnicast(_) | FusedSpec::Store(_) => ScratchNeed::Tile,
+            FusedSpec::AddMatMul { a, b, .. } => unsafe {
+                ScratchNeed::MatMul(
+                    a.scratch_panel_buffer_layout(),
+                    b.scratch_panel_buffer_layout(),
+                )
+            },
+            FusedSpec::BinScalar(..)
+            | FusedSpec::QScale(..)
+            | FusedSpec::RoundingShiftRight(..)
+            | FusedSpec::ShiftLeft(_) => ScratchNeed::Nothing,
+        }
+    }
+
     pub fn prefer_col_outer(&self) -> bool {
         if let FusedSpec::AddMatMul { b, .. } = self {
             match b {
@@ -1,3 +1,4 @@
+use downcast_rs::{impl_downcast, Downcast};
 use std::alloc::Layout;
 use std::fmt;
 use std::ops::Range;
@@ -5,10 +6,16 @@ use tract_data::internal::*;
 
 use crate::frame::Packer;
 
-pub trait VirtualInputSpec: dyn_clone::DynClone + std::fmt::Debug + Sync + Send {
+pub trait VirtualInputSpec: dyn_clone::DynClone + std::fmt::Debug + Sync + Send + Downcast {
     fn wrap(&self, view: &TensorView) -> Box<dyn VirtualInput>;
+
+    /// Type of the items the input is packed to, from an input tensor of type `input`.
+    fn packed_datum_type(&self, input: DatumType) -> DatumType {
+        input
+    }
 }
 dyn_clone::clone_trait_object!(VirtualInputSpec);
+impl_downcast!(VirtualInputSpec);
 
 pub trait VirtualInput: dyn_clone::DynClone + std::fmt::Debug + Sync + Send {
     fn input(&self, packer: &Packer, packed_output: *mut u8, k: Range<usize>, mn: Range<usize>);
@@ -34,10 +41,22 @@ impl InputStoreSpec {
                 packer: packer.clone(),
                 input: func.wrap(tensor),
                 k: *k,
-                dt: tensor.datum_type(),
+                dt: func.packed_datum_type(tensor.datum_type()),
             },
         }
     }
+
+    /// Layout of the single panel buffer the operand is packed in at run time, if it is, for
+    /// items of at most `item_size` bytes.
+    pub fn scratch_panel_buffer_layout(&self, item_size: usize) -> Option<Layout> {
+        match self {
+            InputStoreSpec::Prepacked { .. } => None,
+            InputStoreSpec::VirtualPacking { packer, k, .. } => {
+                let size = packer.single_panel_len(*k) * item_size;
+                Layout::from_size_align(size, packer.alignment()).ok()
+            }
+        }
+    }
 }
 
 impl fmt::Display for InputStoreSpec {
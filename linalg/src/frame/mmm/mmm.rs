@@ -6,6 +6,7 @@ use anyhow::Context;
 use std::fmt;
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::ops::Range;
 use tract_data::anyhow;
 use tract_data::internal::*;
 
@@ -21,9 +22,18 @@ pub trait MatMatMul:
 
     fn internal_type(&self) -> DatumType;
 
+    /// Can the Store write the accumulator directly to an output of type `dt`.
+    fn can_store(&self, dt: DatumType) -> bool {
+        let ti = self.internal_type();
+        dt == ti
+            || (ti.is_float() && dt.is_float())
+            || (ti == DatumType::I32 && (dt == DatumType::I8 || dt == DatumType::U8))
+    }
+
     unsafe fn a_packed(&self, item_size: usize, k: usize) -> InputStoreSpec;
 
     unsafe fn b_packed(&self, item_size: usize, k: usize) -> InputStoreSpec;
+    unsafe fn a_virtual_input(&self, func: Box<dyn VirtualInputSpec>, k: usize) -> InputStoreSpec;
     unsafe fn b_virtual_input(&self, func: Box<dyn VirtualInputSpec>, k: usize) -> InputStoreSpec;
 
     unsafe fn c_view(&self, m_axis: usize, n_axis: usize) -> OutputStoreSpec;
@@ -42,6 +52,11 @@ pub trait MatMatMul:
     }
 
     unsafe fn allocate_scratch_space(&self) -> Box<dyn ScratchSpace>;
+    /// A scratch space with room for `specs` fused specs, running them without allocating when
+    /// it is lent a large enough buffer (see `scratch_space_layout`).
+    unsafe fn allocate_scratch_space_for(&self, specs: usize) -> Box<dyn ScratchSpace>;
+    /// Layout of the buffer to lend to a scratch space running fused specs of `needs`.
+    fn scratch_space_layout(&self, needs: &[ScratchNeed]) -> std::alloc::Layout;
     unsafe fn can_use_scratch_space(&self, scratch: &dyn ScratchSpace) -> bool;
     unsafe fn run_with_scratch_space(
         &self,
@@ -65,6 +80,19 @@ pub trait MatMatMul:
         scratch: &mut dyn ScratchSpace,
         non_linear: &[FusedSpec],
     ) -> anyhow::Result<()>;
+
+    /// Run the kernel over the tiles of the panel rows `rows` (of mr) and panel columns `cols`
+    /// (of nr) of a m by n product. The packed AddMatMul operands hold the panels of these
+    /// ranges only: panel `rows.start` of A is the first one.
+    unsafe fn run_tiles(
+        &self,
+        m: usize,
+        n: usize,
+        rows: Range<usize>,
+        cols: Range<usize>,
+        scratch: &mut dyn ScratchSpace,
+        non_linear: &[FusedSpec],
+    ) -> anyhow::Result<()>;
 }
 
 dyn_clone::clone_trait_object!(MatMatMul);
@@ -114,6 +142,40 @@ where
     }
 }
 
+impl<K, TI> MatMatMulImpl<K, TI>
+where
+    TI: LADatum,
+    K: MatMatMulKer<TI> + 'static,
+{
+    // converting stores go through the border tile path, every tile is written to the scratch
+    // tile then converted to the output
+    unsafe fn run_with_scratch_space_converting(
+        &self,
+        m: usize,
+        n: usize,
+        scratch: &mut dyn ScratchSpace,
+        non_linear: &[FusedSpec],
+    ) -> anyhow::Result<()> {
+        let mr = K::mr();
+        let nr = K::nr();
+        let scratch = scratch
+            .downcast_mut::<ScratchSpaceFusedNonLinear<TI>>()
+            .context("Wrong scratch space type")?;
+        scratch.prepare::<K>(non_linear)?;
+        for ib in 0..n.divceil(nr) {
+            for ia in 0..m.divceil(mr) {
+                scratch.for_border_tile::<K>(non_linear, ia, ib);
+                let err = K::kernel(scratch.uspecs());
+                debug_assert_eq!(err, 0, "Kernel return error {err}");
+                let height = mr.min(m - ia * mr);
+                let width = nr.min(n - ib * nr);
+                scratch.postprocess_tile::<K>(non_linear, ia, ib, height, width);
+            }
+        }
+        Ok(())
+    }
+}
+
 impl<K, TI> fmt::Debug for MatMatMulImpl<K, TI>
 where
     TI: LADatum,
@@ -163,6 +225,10 @@ where
         InputStoreSpec::Prepacked { panel_bytes }
     }
 
+    unsafe fn a_virtual_input(&self, func: Box<dyn VirtualInputSpec>, k: usize) -> InputStoreSpec {
+        InputStoreSpec::VirtualPacking { packer: self.a_pack(), func, k }
+    }
+
     unsafe fn b_virtual_input(&self, func: Box<dyn VirtualInputSpec>, k: usize) -> InputStoreSpec {
         InputStoreSpec::VirtualPacking { packer: self.b_pack(), func, k }
     }
@@ -193,6 +259,14 @@ where
         Box::<ScratchSpaceFusedNonLinear<TI>>::default()
     }
 
+    unsafe fn allocate_scratch_space_for(&self, specs: usize) -> Box<dyn ScratchSpace> {
+        Box::new(ScratchSpaceFusedNonLinear::<TI>::with_capacity(specs))
+    }
+
+    fn scratch_space_layout(&self, needs: &[ScratchNeed]) -> std::alloc::Layout {
+        ScratchSpaceFusedNonLinear::<TI>::layout::<K>(needs.iter().copied())
+    }
+
     unsafe fn can_use_scratch_space(&self, scratch: &dyn ScratchSpace) -> bool {
         scratch.downcast_ref::<ScratchSpaceFusedNonLinear<TI>>().is_some()
     }
@@ -203,6 +277,9 @@ where
         scratch: &mut dyn ScratchSpace,
         non_linear: &[FusedSpec],
     ) -> anyhow::Result<()> {
+        if non_linear.iter().any(|f| f.converting_store(TI::datum_type())) {
+            return self.run_with_scratch_space_converting(m, 1, scratch, non_linear);
+        }
         let mr = K::mr();
         let scratch = scratch
             .downcast_mut::<ScratchSpaceFusedNonLinear<TI>>()
@@ -229,6 +306,9 @@ where
         scratch: &mut dyn ScratchSpace,
         non_linear: &[FusedSpec],
     ) -> anyhow::Result<()> {
+        if non_linear.iter().any(|f| f.converting_store(TI::datum_type())) {
+            return self.run_with_scratch_space_converting(m, n, scratch, non_linear);
+        }
         let mr = K::mr();
         let nr = K::nr();
         let scratch = scratch
@@ -265,6 +345,42 @@ where
         Ok(())
     }
 
+    unsafe fn run_tiles(
+        &self,
+        m: usize,
+        n: usize,
+        rows: Range<usize>,
+        cols: Range<usize>,
+        scratch: &mut dyn ScratchSpace,
+        non_linear: &[FusedSpec],
+    ) -> anyhow::Result<()> {
+        let mr = K::mr();
+        let nr = K::nr();
+        let converting = non_linear.iter().any(|f| f.converting_store(TI::datum_type()));
+        let scratch = scratch
+            .downcast_mut::<ScratchSpaceFusedNonLinear<TI>>()
+            .context("Wrong scratch space type")?;
+        scratch.prepare::<K>(non_linear)?;
+        scratch.set_panel_origin(rows.start, cols.start);
+        for ib in cols {
+            let width = nr.min(n - ib * nr);
+            for ia in rows.clone() {
+                let height = mr.min(m - ia * mr);
+                if converting || height < mr || width < nr {
+                    scratch.for_border_tile::<K>(non_linear, ia, ib);
+                    let err = K::kernel(scratch.uspecs());
+                    debug_assert_eq!(err, 0, "Kernel return error {err}");
+                    scratch.postprocess_tile::<K>(non_linear, ia, ib, height, width);
+                } else {
+                    scratch.for_valid_tile::<K>(non_linear, ia, ib);
+                    let err = K::kernel(scratch.uspecs());
+                    debug_assert_eq!(err, 0, "Kernel return error {err}");
+                }
+            }
+        }
+        Ok(())
+    }
+
     unsafe fn run_with_scratch_space(
         &self,
         m: usize,
@@ -274,6 +390,9 @@ where
     ) -> anyhow::Result<()> {
         let mr = K::mr();
         let nr = K::nr();
+        if non_linear.iter().any(|f| f.converting_store(TI::datum_type())) {
+            return self.run_with_scratch_space_converting(m, n, scratch, non_linear);
+        }
         if n == 1 && K::nr() == 1 {
             return self.run_with_scratch_space_vec(m, scratch, non_linear);
         }
@@ -8,15 +8,43 @@ use super::{BinOp, FusedKerSpec, FusedSpec, MatMatMulKer, OutputStoreKer};
 use downcast_rs::{impl_downcast, Downcast};
 use tract_data::internal::num_integer::Integer;
 
-pub trait ScratchSpace: Downcast + Send {}
+pub trait ScratchSpace: Downcast + Send {
+    /// Run in the `len` bytes at `buffer` instead of an owned allocation, when they are large
+    /// and aligned enough for the fused specs, until the next call. The caller keeps them alive
+    /// and untouched while the scratch space runs. A null buffer goes back to owned allocations.
+    unsafe fn lend(&mut self, buffer: *mut u8, len: usize);
+}
 impl_downcast!(ScratchSpace);
 
+/// What a fused spec takes in the scratch space, to size it before the run: see
+/// `MatMatMul::scratch_space_layout`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScratchNeed {
+    /// Scalars, shifts and scalings take nothing.
+    Nothing,
+    /// The border tile copy of a per-row vector.
+    PerRow,
+    /// The border tile copy of a per-column vector.
+    PerCol,
+    /// The border tile copies of a row and a column vector.
+    RowColProducts,
+    /// The border tile copy of a matrix.
+    Tile,
+    /// A matrix product, with the single panel buffers of its operands packed at run time.
+    MatMul(Option<Layout>, Option<Layout>),
+}
+
 #[derive(Debug)]
 pub struct ScratchSpaceFusedNonLinear<TI: LADatum> {
     uspecs: Vec<FusedKerSpec<TI>>,
     layout: Layout,
     buffer: *const u8,
     loc_dependant: TVec<LocDependant>,
+    /// Buffer lent by the caller, and its length in bytes (see `ScratchSpace::lend`).
+    lent: (*mut u8, usize),
+    /// Row and column panels the packed AddMatMul operands start at: a macro tile run (see
+    /// `MatMatMul::run_tiles`) is fed the panels of its tile only.
+    panel_origin: (usize, usize),
 }
 
 impl<TI: LADatum> Default for ScratchSpaceFusedNonLinear<TI> {
@@ -26,6 +54,8 @@ impl<TI: LADatum> Default for ScratchSpaceFusedNonLinear<TI> {
             layout: unsafe { Layout::from_size_align_unchecked(0, 1) },
             buffer: std::ptr::null(),
             loc_dependant: tvec!(),
+            lent: (std::ptr::null_mut(), 0),
+            panel_origin: (0, 0),
         }
     }
 }
@@ -38,7 +68,11 @@ struct LocDependant {
     buffer: Option<*const u8>,
 }
 
-impl<TI: LADatum> ScratchSpace for ScratchSpaceFusedNonLinear<TI> {}
+impl<TI: LADatum> ScratchSpace for ScratchSpaceFusedNonLinear<TI> {
+    unsafe fn lend(&mut self, buffer: *mut u8, len: usize) {
+        self.lent = (buffer, len);
+    }
+}
 unsafe impl<TI: LADatum> Send for ScratchSpaceFusedNonLinear<TI> {}
 
 impl<TI: LADatum> Drop for ScratchSpaceFusedNonLinear<TI> {
@@ -59,18 +93,70 @@ struct AddMatMulTemp {
 }
 
 impl<TI: LADatum> ScratchSpaceFusedNonLinear<TI> {
+    /// A scratch space with room for the kernel specs of `specs` fused specs: preparing it for
+    /// as many does not allocate them.
+    pub fn with_capacity(specs: usize) -> Self {
+        let mut it = Self::default();
+        it.uspecs.reserve(specs + 2);
+        it.loc_dependant.reserve(2 * specs);
+        it
+    }
+
+    /// Layout of the buffer the fused specs of `needs` take.
+    pub fn layout<K: MatMatMulKer<TI>>(needs: impl IntoIterator<Item = ScratchNeed>) -> Layout {
+        let (mut offset, mut align) = (0, std::mem::size_of::<*const ()>());
+        for need in needs {
+            Self::reserve::<K>(need, &mut offset, &mut align, |_, _| ());
+        }
+        unsafe { Layout::from_size_align_unchecked(offset, align) }
+    }
+
+    /// Reserve the room of `need` from `offset`, calling `loc` with the offset of each location
+    /// it depends on, and of its panel buffer.
+    fn reserve<K: MatMatMulKer<TI>>(
+        need: ScratchNeed,
+        offset: &mut usize,
+        align: &mut usize,
+        mut loc: impl FnMut(usize, Option<usize>),
+    ) {
+        let ti = TI::datum_type().size_of();
+        let mut vector = |len: usize| {
+            loc(*offset, None);
+            *offset += ti * len;
+        };
+        match need {
+            ScratchNeed::Nothing => (),
+            ScratchNeed::PerRow => vector(K::mr()),
+            ScratchNeed::PerCol => vector(K::nr()),
+            ScratchNeed::RowColProducts => vector(K::mr() + K::nr()),
+            ScratchNeed::Tile => vector(K::mr() * K::nr()),
+            ScratchNeed::MatMul(a, b) => {
+                for panel in [a, b] {
+                    let at = *offset;
+                    *offset += std::mem::size_of::<AddMatMulTemp>();
+                    let buffer = panel.map(|tmp| {
+                        *align = tmp.align().lcm(align);
+                        *offset = Integer::next_multiple_of(offset, &tmp.align());
+                        let buffer = *offset;
+                        *offset += tmp.size();
+                        buffer
+                    });
+                    loc(at, buffer);
+                }
+            }
+        }
+    }
+
     pub unsafe fn prepare<K: MatMatMulKer<TI>>(&mut self, specs: &[FusedSpec]) -> TractResult<()> {
         use FusedKerSpec as FKS;
         use FusedSpec as FS;
         self.uspecs.clear();
         self.loc_dependant.clear();
+        self.panel_origin = (0, 0);
         self.uspecs.reserve(specs.len() + 2);
         self.uspecs.push(FusedKerSpec::Clear);
         let mut offset = 0;
         let mut align = std::mem::size_of::<*const ()>();
-        fn ld(spec: usize, uspec: usize, loc: *const u8) -> LocDependant {
-            LocDependant { spec, uspec, loc, buffer: None }
-        }
         // we're cheating here, storing offset as the buf pointer first
         for (ix, spec) in specs.iter().enumerate() {
             let uspec = match spec {
@@ -85,57 +171,35 @@ impl<TI: LADatum> ScratchSpaceFusedNonLinear<TI> {
                 FS::ShiftLeft(s) => FKS::ShiftLeft(*s),
                 FS::RoundingShiftRight(s, rp) => FKS::RoundingShiftRight(*s, *rp),
                 FS::QScale(s, rp, m) => FKS::QScale(*s, *rp, *m),
-                FS::BinPerRow(_, _) => {
-                    self.loc_dependant.push(ld(ix, self.uspecs.len(), offset as _));
-                    offset += TI::datum_type().size_of() * K::mr();
-                    FusedKerSpec::Done
-                }
-                FS::BinPerCol(_, _) => {
-                    self.loc_dependant.push(ld(ix, self.uspecs.len(), offset as _));
-                    offset += TI::datum_type().size_of() * K::nr();
-                    FusedKerSpec::Done
-                }
-                FS::AddRowColProducts(_, _) => {
-                    self.loc_dependant.push(ld(ix, self.uspecs.len(), offset as _));
-                    offset += TI::datum_type().size_of() * (K::mr() + K::nr());
-                    FusedKerSpec::Done
-                }
-                FS::Store(_) | FS::AddUnicast(_) => {
-                    self.loc_dependant.push(ld(ix, self.uspecs.len(), offset as _));
-                    offset += TI::datum_type().size_of() * K::mr() * K::nr();
-                    FusedKerSpec::Done
-                }
-                FS::AddMatMul { a, b, .. } => {
-                    for input in [a, b] {
-                        let mut ld = ld(ix, self.uspecs.len(), offset as _);
-                        offset += std::mem::size_of::<AddMatMulTemp>();
-                        if let Some(tmp) = input.scratch_panel_buffer_layout() {
-                            align = tmp.align().lcm(&align);
-                            offset = Integer::next_multiple_of(&offset, &tmp.align());
-                            ld.buffer = Some(offset as _);
-                            offset += tmp.size();
-                        }
-                        self.loc_dependant.push(ld);
-                    }
-                    FusedKerSpec::Done
-                }
+                _ => FKS::Done,
             };
+            let (uspec_ix, loc_dependant) = (self.uspecs.len(), &mut self.loc_dependant);
+            Self::reserve::<K>(spec.scratch_need(), &mut offset, &mut align, |loc, buffer| {
+                let buffer = buffer.map(|b| b as *const u8);
+                loc_dependant.push(LocDependant::new(ix, uspec_ix, loc as _, buffer))
+            });
             self.uspecs.push(uspec);
         }
         self.uspecs.push(FKS::Done);
-        if offset > self.layout.size() || align > self.layout.align() {
-            if !self.buffer.is_null() {
-                std::alloc::dealloc(self.buffer as _, self.layout);
+        let (lent, lent_len) = self.lent;
+        let buffer = if !lent.is_null() && offset <= lent_len && lent as usize % align == 0 {
+            lent as *const u8
+        } else {
+            if offset > self.layout.size() || align > self.layout.align() {
+                if !self.buffer.is_null() {
+                    std::alloc::dealloc(self.buffer as _, self.layout);
+                }
+                self.layout = Layout::from_size_align_unchecked(offset, align);
+                self.buffer = std::alloc::alloc(self.layout);
+                assert!(!self.buffer.is_null());
             }
-            self.layout = Layout::from_size_align_unchecked(offset, align);
-            self.buffer = std::alloc::alloc(self.layout);
-            assert!(!self.buffer.is_null());
-        }
+            self.buffer
+        };
         let mut mat_mul_half_done = false;
-        for LocDependant { loc, buffer, spec, .. } in &mut self.loc_dependant {
-            *loc = self.buffer.offset(*loc as _);
-            if let Some(b) = buffer {
-                *b = self.buffer.offset(*b as _);
+        for LocDependant { loc, buffer: panel, spec, .. } in &mut self.loc_dependant {
+            *loc = buffer.offset(*loc as _);
+            if let Some(b) = panel {
+                *b = buffer.offset(*b as _);
             }
             let spec = specs.get_unchecked(*spec);
             #[allow(clippy::single_match)]
@@ -161,7 +225,7 @@ impl<TI: LADatum> ScratchSpaceFusedNonLinear<TI> {
     ) {
         use FusedKerSpec as FKS;
         use FusedSpec as FS;
-        let ScratchSpaceFusedNonLinear { uspecs, loc_dependant, .. } = self;
+        let ScratchSpaceFusedNonLinear { uspecs, loc_dependant, panel_origin, .. } = self;
         debug_assert!(specs.len() + 2 == uspecs.len());
         let mut adhoc_pa: *const u8 = std::ptr::null();
         for LocDependant { spec, uspec, loc, buffer } in loc_dependant.iter_mut() {
@@ -200,7 +264,7 @@ impl<TI: LADatum> ScratchSpaceFusedNonLinear<TI> {
                     let scratch = &mut *(*loc as *mut AddMatMulTemp);
                     if !scratch.is_b {
                         if scratch.panel_id != down {
-                            scratch.ptr = a.panel(down, *buffer);
+                            scratch.ptr = a.panel(down - panel_origin.0, *buffer);
                             scratch.panel_id = down;
                         }
                         adhoc_pa = scratch.ptr;
@@ -208,7 +272,7 @@ impl<TI: LADatum> ScratchSpaceFusedNonLinear<TI> {
                                   // done.
                     } else {
                         if scratch.panel_id != right {
-                            scratch.ptr = b.panel(right, *buffer);
+                            scratch.ptr = b.panel(right - panel_origin.1, *buffer);
                             scratch.panel_id = right;
                         }
                         FKS::AddMatMul { k: *k, pa: adhoc_pa, pb: scratch.ptr, cpu_variant: 0 }
@@ -228,7 +292,7 @@ impl<TI: LADatum> ScratchSpaceFusedNonLinear<TI> {
     ) {
         use FusedKerSpec as FKS;
         use FusedSpec as FS;
-        let ScratchSpaceFusedNonLinear { uspecs, loc_dependant, .. } = self;
+        let ScratchSpaceFusedNonLinear { uspecs, loc_dependant, panel_origin, .. } = self;
         debug_assert!(specs.len() + 2 == uspecs.len());
         let mut adhoc_pa: *const u8 = std::ptr::null();
         for LocDependant { spec, uspec, loc, buffer } in loc_dependant.iter_mut() {
@@ -357,11 +421,16 @@ impl<TI: LADatum> ScratchSpaceFusedNonLinear<TI> {
                     })
                 }
                 FS::Store(c_store) => {
+                    let item_size = if c_store.converts_from(TI::datum_type()) {
+                        std::mem::size_of::<TI>()
+                    } else {
+                        c_store.item_size
+                    };
                     let tmpc = OutputStoreKer {
                         ptr: *loc as _,
-                        item_size: c_store.item_size,
-                        row_byte_stride: c_store.item_size as isize,
-                        col_byte_stride: (c_store.item_size * K::mr()) as isize,
+                        item_size,
+                        row_byte_stride: item_size as isize,
+                        col_byte_stride: (item_size * K::mr()) as isize,
                     };
                     FKS::Store(tmpc)
                 }
@@ -369,7 +438,7 @@ impl<TI: LADatum> ScratchSpaceFusedNonLinear<TI> {
                     let scratch = &mut *(*loc as *mut AddMatMulTemp);
                     if !scratch.is_b {
                         if scratch.panel_id != down {
-                            scratch.ptr = a.panel(down, *buffer);
+                            scratch.ptr = a.panel(down - panel_origin.0, *buffer);
                             scratch.panel_id = down;
                         }
                         adhoc_pa = scratch.ptr;
@@ -377,7 +446,7 @@ impl<TI: LADatum> ScratchSpaceFusedNonLinear<TI> {
                                   // done.
                     } else {
                         if scratch.panel_id != right {
-                            scratch.ptr = b.panel(right, *buffer);
+                            scratch.ptr = b.panel(right - panel_origin.1, *buffer);
                             scratch.panel_id = right;
                         }
                         FKS::AddMatMul { k: *k, pa: adhoc_pa, pb: scratch.ptr, cpu_variant: 0 }
@@ -388,6 +457,11 @@ impl<TI: LADatum> ScratchSpaceFusedNonLinear<TI> {
         }
     }
 
+    /// Set the first row and column panels of the packed operands, after `prepare`.
+    pub fn set_panel_origin(&mut self, down: usize, right: usize) {
+        self.panel_origin = (down, right);
+    }
+
     #[inline]
     pub fn uspecs(&self) -> &[FusedKerSpec<TI>] {
         &self.uspecs
@@ -407,7 +481,18 @@ impl<TI: LADatum> ScratchSpaceFusedNonLinear<TI> {
             let spec = specs.get_unchecked(*spec);
             let ker_spec = self.uspecs.get_unchecked(*uspec);
             if let (FusedSpec::Store(c_store), FusedKerSpec::Store(tmp)) = (spec, ker_spec) {
-                c_store.set_from_tile(down, right, m_remnant, n_remnant, tmp)
+                if c_store.converts_from(TI::datum_type()) {
+                    c_store.set_from_converted_tile(
+                        TI::datum_type(),
+                        down,
+                        right,
+                        m_remnant,
+                        n_remnant,
+                        tmp,
+                    )
+                } else {
+                    c_store.set_from_tile(down, right, m_remnant, n_remnant, tmp)
+                }
             }
         }
     }
@@ -28,6 +28,7 @@ pub struct OutputStore {
     pub(crate) panel_col_byte_stride: isize,
     pub(crate) item_size: usize,
     pub(crate) item_count: usize,
+    pub(crate) dt: DatumType,
     pub(crate) mr: usize,
     pub(crate) m: usize,
     pub(crate) n: usize,
@@ -52,6 +53,7 @@ impl OutputStoreSpec {
             item_size: tensor.datum_type().size_of(),
             mr,
             item_count: tensor.len(),
+            dt: tensor.datum_type(),
             m,
             n,
         }
@@ -96,6 +98,17 @@ impl OutputStore {
         self.item_size
     }
 
+    #[inline]
+    pub fn datum_type(&self) -> DatumType {
+        self.dt
+    }
+
+    /// True if the kernel accumulator type `ti` must be converted when stored to this output.
+    #[inline]
+    pub fn converts_from(&self, ti: DatumType) -> bool {
+        self.dt != ti && self.dt.is_float() && ti.is_float()
+    }
+
     #[inline]
     pub(super) unsafe fn set_from_tile(
         &self,
@@ -138,6 +151,41 @@ impl OutputStore {
             }
         }
     }
+
+    /// Same as set_from_tile, but the tile holds `ti` values that are converted to the output
+    /// float type on the fly.
+    pub(super) unsafe fn set_from_converted_tile(
+        &self,
+        ti: DatumType,
+        down: usize,
+        right: usize,
+        height: usize,
+        width: usize,
+        tile: &OutputStoreKer,
+    ) {
+        let dst = self.ptr.add(
+            self.panel_row_byte_stride as usize * down
+                + self.panel_col_byte_stride as usize * right,
+        );
+        for y in 0..height as isize {
+            for x in 0..width as isize {
+                let value = tile.ptr.offset((y + x * self.mr as isize) * tile.item_size as isize);
+                let value = match ti {
+                    DatumType::F16 => (*(value as *const f16)).to_f64(),
+                    DatumType::F32 => *(value as *const f32) as f64,
+                    DatumType::F64 => *(value as *const f64),
+                    _ => unreachable!(),
+                };
+                let dst = dst.offset(y * self.row_byte_stride + x * self.col_byte_stride);
+                match self.dt {
+                    DatumType::F16 => *(dst as *mut f16) = f16::from_f64(value),
+                    DatumType::F32 => *(dst as *mut f32) = value as f32,
+                    DatumType::F64 => *(dst as *mut f64) = value,
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
 }
 
 #[repr(C)]
@@ -23,6 +23,10 @@ impl Packer {
         self.r
     }
 
+    pub fn end_padding_record(&self) -> usize {
+        self.end_padding_record
+    }
+
     pub fn len<D: DimLike>(&self, k: D, n: D) -> D {
         (n.divceil(self.r) * (k + self.end_padding_record)) * self.r
     }
@@ -130,6 +134,102 @@ impl Packer {
         self.pack_segment(pb, b, k_axis, mn_axis, 0..k, 0..mn);
     }
 
+    /// Pack a whole operand whose k axis is inner to its mn axis in memory (k-contiguous rows
+    /// of mn, a transposed layout relative to the k-outer panels).
+    ///
+    /// The naive gather writes each input row across all k records of a panel. This tiles the
+    /// copy instead: a block of `K_BLOCK` records of one panel is filled from the `r` input
+    /// rows before moving on, so both the read rows and the written block stay in L1.
+    pub unsafe fn pack_transposing<'a, 'b>(
+        &self,
+        mut pb: impl std::borrow::BorrowMut<TensorView<'a>>,
+        b: impl std::borrow::Borrow<TensorView<'b>>,
+        k_axis: usize,
+        mn_axis: usize,
+    ) {
+        let pb = pb.borrow_mut();
+        let b = b.borrow();
+        let (k, mn) = (b.shape()[k_axis], b.shape()[mn_axis]);
+        debug_assert!(pb.len() >= self.len(k, mn));
+        let dt = pb.datum_type();
+        dispatch_copy!(Self::pack_transposing_t(dt)(
+            self,
+            pb.as_ptr_mut_unchecked(),
+            b.as_ptr_unchecked(),
+            k,
+            mn,
+            b.strides()[k_axis],
+            b.strides()[mn_axis]
+        ));
+    }
+
+    /// `pack` to the raw buffer `pb`, of items of the datum type of `b`.
+    pub unsafe fn pack_to(&self, pb: *mut u8, b: &TensorView, k_axis: usize, mn_axis: usize) {
+        let (k, mn) = (b.shape()[k_axis], b.shape()[mn_axis]);
+        dispatch_copy!(Self::pack_t(b.datum_type())(
+            self,
+            pb as _,
+            b.as_ptr_unchecked(),
+            mn,
+            b.strides()[k_axis],
+            b.strides()[mn_axis],
+            0..k,
+            0..mn
+        ));
+    }
+
+    /// `pack_transposing` to the raw buffer `pb`, of items of the datum type of `b`.
+    pub unsafe fn pack_transposing_to(
+        &self,
+        pb: *mut u8,
+        b: &TensorView,
+        k_axis: usize,
+        mn_axis: usize,
+    ) {
+        let (k, mn) = (b.shape()[k_axis], b.shape()[mn_axis]);
+        dispatch_copy!(Self::pack_transposing_t(b.datum_type())(
+            self,
+            pb as _,
+            b.as_ptr_unchecked(),
+            k,
+            mn,
+            b.strides()[k_axis],
+            b.strides()[mn_axis]
+        ));
+    }
+
+    unsafe fn pack_transposing_t<T: Datum + Copy>(
+        &self,
+        pb: *mut T,
+        b: *const T,
+        k: usize,
+        mn: usize,
+        k_stride: isize,
+        mn_stride: isize,
+    ) {
+        const K_BLOCK: usize = 64;
+        let r = self.r;
+        let panel_len = self.single_panel_len(k);
+        for panel in 0..mn.divceil(r) {
+            let packed = pb.add(panel * panel_len);
+            let lanes = r.min(mn - panel * r);
+            for k_start in (0..k).step_by(K_BLOCK) {
+                let k_end = (k_start + K_BLOCK).min(k);
+                for lane in 0..lanes {
+                    let row = b.offset((panel * r + lane) as isize * mn_stride);
+                    for x in k_start..k_end {
+                        *packed.add(x * r + lane) = *row.offset(x as isize * k_stride);
+                    }
+                }
+                for lane in lanes..r {
+                    for x in k_start..k_end {
+                        *packed.add(x * r + lane) = T::default();
+                    }
+                }
+            }
+        }
+    }
+
     pub fn write_with_k_outer<'p, T: Copy + Debug>(
         &self,
         pb: *mut T,
@@ -461,6 +561,25 @@ mod test {
 
     }
 
+    fn check_transposing(r: usize, k: usize, mn: usize) {
+        let packer = super::Packer::new(r, 1, 0);
+        let input = Array2::from_shape_fn((mn, k), |(x, k)| (x * 1000 + k) as u32).into_tensor();
+        let mut expected = Tensor::zero::<u32>(&[packer.len(k, mn)]).unwrap();
+        let mut found = Tensor::zero::<u32>(&[packer.len(k, mn)]).unwrap();
+        unsafe {
+            packer.pack(expected.view_mut(), input.view(), 1, 0);
+            packer.pack_transposing(found.view_mut(), input.view(), 1, 0);
+        }
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn transposing_matches_gather() {
+        for (r, k, mn) in [(1, 1, 1), (4, 3, 9), (8, 130, 17), (6, 64, 12), (16, 200, 5)] {
+            check_transposing(r, k, mn);
+        }
+    }
+
     #[test]
     fn simple_b_1() {
         PackProblem { k: 2, mn: 1, is_a: false, r: 1, k_range: 0..2, mn_range: 0..1 }.check();
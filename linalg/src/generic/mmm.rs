@@ -345,6 +345,126 @@ where
     }
 }
 
+/// Portable i8 by i8 kernel, accumulating in i32, written for LLVM to vectorize it on any
+/// target: at each k, the B row is widened once, then multiplied by each A item and added to the
+/// matching accumulator row. It is the quantized kernel of the platforms linalg has
+/// no assembly kernel for (see the `generic_i8` bench against the scalar generic kernel).
+#[derive(Copy, Clone, Debug)]
+pub struct GenericMmmI8<const MR: usize, const NR: usize>;
+
+impl<const MR: usize, const NR: usize> MatMatMulKer<i32> for GenericMmmI8<MR, NR> {
+    #[inline(always)]
+    fn name() -> &'static str {
+        match (MR, NR) {
+            (8, 8) => "generic_i8_8x8",
+            (16, 1) => "generic_i8_16x1",
+            _ => "generic_i8",
+        }
+    }
+    #[inline(always)]
+    fn mr() -> usize {
+        MR
+    }
+    #[inline(always)]
+    fn nr() -> usize {
+        NR
+    }
+    fn end_padding_packed_a() -> usize {
+        0
+    }
+    fn end_padding_packed_b() -> usize {
+        0
+    }
+    #[inline(always)]
+    fn alignment_bytes_packed_a() -> usize {
+        1
+    }
+    #[inline(always)]
+    fn alignment_bytes_packed_b() -> usize {
+        1
+    }
+    #[inline(never)]
+    fn kernel(spec: &[FusedKerSpec<i32>]) -> isize {
+        unsafe {
+            let mut ab = [[0i32; NR]; MR];
+            let mut pnl = spec.as_ptr();
+            loop {
+                if pnl.is_null() {
+                    break;
+                }
+                match *pnl {
+                    FusedKerSpec::Done => break,
+                    FusedKerSpec::Clear => ab = [[0i32; NR]; MR],
+                    FusedKerSpec::ScalarAdd(a) => scalar!(ab, a, |a, b| a + b),
+                    FusedKerSpec::ScalarMul(a) => scalar!(ab, a, |a, b| a * b),
+                    FusedKerSpec::ScalarMin(m) => scalar!(ab, m, |a: i32, b| a.min(b)),
+                    FusedKerSpec::ScalarMax(m) => scalar!(ab, m, |a: i32, b| a.max(b)),
+                    FusedKerSpec::ScalarSub(m) => scalar!(ab, m, |a, b| a - b),
+                    FusedKerSpec::ScalarSubF(m) => scalar!(ab, m, |a, b| b - a),
+                    FusedKerSpec::PerRowMin(m) => per_row!(ab, m, |a: i32, b| a.min(b)),
+                    FusedKerSpec::PerRowMax(m) => per_row!(ab, m, |a: i32, b| a.max(b)),
+                    FusedKerSpec::PerRowAdd(m) => per_row!(ab, m, |a, b| a + b),
+                    FusedKerSpec::PerRowMul(m) => per_row!(ab, m, |a, b| a * b),
+                    FusedKerSpec::PerRowSub(m) => per_row!(ab, m, |a, b| a - b),
+                    FusedKerSpec::PerRowSubF(m) => per_row!(ab, m, |a, b| b - a),
+                    FusedKerSpec::PerColMin(m) => per_col!(ab, m, |a: i32, b| a.min(b)),
+                    FusedKerSpec::PerColMax(m) => per_col!(ab, m, |a: i32, b| a.max(b)),
+                    FusedKerSpec::PerColAdd(m) => per_col!(ab, m, |a, b| a + b),
+                    FusedKerSpec::PerColMul(m) => per_col!(ab, m, |a, b| a * b),
+                    FusedKerSpec::PerColSub(m) => per_col!(ab, m, |a, b| a - b),
+                    FusedKerSpec::PerColSubF(m) => per_col!(ab, m, |a, b| b - a),
+                    FusedKerSpec::AddRowColProducts(rows, cols) => {
+                        for i in 0..MR {
+                            for j in 0..NR {
+                                ab[i][j] += *rows.add(i) * *cols.add(j);
+                            }
+                        }
+                    }
+                    FusedKerSpec::AddUnicast(tile) => add_unicast::<i32, _>(&tile, &mut ab),
+                    FusedKerSpec::ShiftLeft(shift) => scalar!(ab, shift, |s, a: i32| a.q_shl(s)),
+                    FusedKerSpec::RoundingShiftRight(shift, rp) => {
+                        scalar!(ab, shift, |s, a: i32| a.q_shr(s, rp))
+                    }
+                    FusedKerSpec::QScale(shift, rp, mult) => {
+                        let scaler = Scaler::from_fuse_params(shift, rp, mult);
+                        scalar!(ab, scaler, |s, a: i32| a.q_scale(s))
+                    }
+                    // the packed panels may be null when they are empty
+                    FusedKerSpec::AddMatMul { k: 0, .. } => {}
+                    FusedKerSpec::AddMatMul { k, pa, pb, .. } => {
+                        let a = std::slice::from_raw_parts(pa as *const i8, MR * k);
+                        let b = std::slice::from_raw_parts(pb as *const i8, NR * k);
+                        add_mat_mul_i8(&mut ab, a, b);
+                    }
+                    FusedKerSpec::Store(tile) => store(&tile, &ab),
+                }
+                pnl = pnl.add(1);
+            }
+        }
+        0
+    }
+}
+
+/// The k loop of `GenericMmmI8`, over packed panels of MR (and NR) items by k.
+///
+/// The product of two i8 fits in i16: it is computed in i16 lanes, which all targets multiply
+/// (unlike i32 ones), then widened to be accumulated.
+#[inline(always)]
+fn add_mat_mul_i8<const MR: usize, const NR: usize>(ab: &mut [[i32; NR]; MR], a: &[i8], b: &[i8]) {
+    for (a, b) in a.chunks_exact(MR).zip(b.chunks_exact(NR)) {
+        let mut wide_b = [0i16; NR];
+        for j in 0..NR {
+            wide_b[j] = b[j] as i16;
+        }
+        for i in 0..MR {
+            let a = a[i] as i16;
+            for j in 0..NR {
+                ab[i][j] += (a * wide_b[j]) as i32;
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 #[derive(Copy, Clone, Debug)]
 pub struct GenericMmmTest3x2<TA, TB, TI>(PhantomData<(TA, TB, TI)>)
@@ -571,6 +691,14 @@ test_mmm_kernel_f64!(generic_f64_4x4, true);
 pub type generic_i32_4x4 = GenericMmm4x4<i8, i8, i32>;
 test_mmm_kernel_i32!(generic_i32_4x4, true);
 
+#[allow(non_camel_case_types)]
+pub type generic_i8_8x8 = GenericMmmI8<8, 8>;
+test_mmm_kernel_i32!(generic_i8_8x8, true);
+
+#[allow(non_camel_case_types)]
+pub type generic_i8_16x1 = GenericMmmI8<16, 1>;
+test_mmm_kernel_i32!(generic_i8_16x1, true);
+
 #[allow(non_camel_case_types)]
 pub type generic_f32_4x1 = GenericMmm4x1<f32, f32, f32>;
 test_mmm_kernel_f32!(generic_f32_4x1, true);
@@ -592,3 +720,73 @@ test_mmm_kernel_f32!(generic_f32_3x2, true);
 #[allow(non_camel_case_types)]
 type generic_i32_3x2 = GenericMmmTest3x2<i8, i8, i32>;
 test_mmm_kernel_i32!(generic_i32_3x2, true);
+
+#[cfg(test)]
+mod test_generic_i8 {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    /// Product of row-major `m x k` by `k x n` matrices, run by `mmm`.
+    fn run(mmm: &dyn MatMatMul, (m, k, n): (usize, usize, usize), a: &[i8], b: &[i8]) -> Vec<i32> {
+        unsafe {
+            let a = Tensor::from_shape(&[m, k], a).unwrap();
+            let b = Tensor::from_shape(&[k, n], b).unwrap();
+            let (a_pack, b_pack) = (mmm.a_pack(), mmm.b_pack());
+            let mut pa =
+                Tensor::uninitialized_aligned::<i8>(&[a_pack.len(k, m)], a_pack.alignment())
+                    .unwrap();
+            a_pack.pack(pa.view_mut(), a.view(), 1, 0);
+            let mut pb =
+                Tensor::uninitialized_aligned::<i8>(&[b_pack.len(k, n)], b_pack.alignment())
+                    .unwrap();
+            b_pack.pack(pb.view_mut(), b.view(), 0, 1);
+            let mut c = Tensor::zero::<i32>(&[m, n]).unwrap();
+            mmm.run(
+                m,
+                n,
+                &[
+                    FusedSpec::AddMatMul {
+                        a: mmm.a_packed(1, k).wrap(&pa.view()),
+                        b: mmm.b_packed(1, k).wrap(&pb.view()),
+                        k,
+                    },
+                    FusedSpec::Store(mmm.c_view(0, 1).wrap(&c.view_mut())),
+                ],
+            )
+            .unwrap();
+            c.as_slice::<i32>().unwrap().to_vec()
+        }
+    }
+
+    fn reference((m, k, n): (usize, usize, usize), a: &[i8], b: &[i8]) -> Vec<i64> {
+        let mut c = vec![0i64; m * n];
+        for row in 0..m {
+            for col in 0..n {
+                for i in 0..k {
+                    c[row * n + col] += a[row * k + i] as i64 * b[i * n + col] as i64;
+                }
+            }
+        }
+        c
+    }
+
+    fn problem() -> BoxedStrategy<((usize, usize, usize), Vec<i8>, Vec<i8>)> {
+        (1usize..40, 1usize..300, 1usize..40)
+            .prop_flat_map(|(m, k, n)| {
+                (Just((m, k, n)), vec(any::<i8>(), m * k), vec(any::<i8>(), k * n))
+            })
+            .boxed()
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn i8_kernels_match_i64_reference((shape, a, b) in problem()) {
+            let expected = reference(shape, &a, &b);
+            for mmm in [GenericMmmI8::<8, 8>::mmm(), GenericMmmI8::<16, 1>::mmm()] {
+                let found = run(&*mmm, shape, &a, &b);
+                prop_assert!(found.iter().zip(&expected).all(|(f, e)| *f as i64 == *e));
+            }
+        }
+    }
+}
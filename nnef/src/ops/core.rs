@@ -10,6 +10,7 @@ mod einsum;
 mod fft;
 mod force_eval;
 mod gather;
+mod lir_matmul;
 mod load;
 mod matmul;
 mod one_hot;
@@ -48,6 +49,7 @@ pub fn register(registry: &mut Registry) {
     fft::register(registry);
     force_eval::register(registry);
     gather::register(registry);
+    lir_matmul::register(registry);
     load::register(registry);
     matmul::register(registry);
     one_hot::register(registry);
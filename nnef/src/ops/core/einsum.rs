@@ -1,6 +1,6 @@
 use crate::internal::*;
 use crate::ser::*;
-use tract_core::ops::einsum::EinSum;
+use tract_core::ops::einsum::{EinSum, QActivation, QOverflow};
 use tract_core::tract_data::itertools::Itertools;
 
 pub fn register(registry: &mut Registry) {
@@ -41,6 +41,9 @@ pub fn parameters_q() -> Vec<Parameter> {
         TypeName::Scalar.tensor().named("b_scale"),
         TypeName::Integer.tensor().named("c0"),
         TypeName::Scalar.tensor().named("c_scale"),
+        TypeName::String.named("activation").default(""),
+        TypeName::Scalar.named("alpha").default(0.0f32),
+        TypeName::String.named("overflow").default("saturate"),
     ]
 }
 
@@ -70,21 +73,36 @@ pub fn ser_einsum(ast: &mut IntoAst, node: &TypedNode) -> TractResult<Option<Arc
 pub fn ser_einsum_q(ast: &mut IntoAst, node: &TypedNode) -> TractResult<Option<Arc<RValue>>> {
     let einsum = node.op_as::<EinSum>().unwrap();
     let inputs = node.inputs.iter().map(|i| (*ast.mapping[i]).clone()).collect_vec();
+    let mut named = vec![
+        ("expr", string(einsum.axes.to_string())),
+        ("acc", datum_type(einsum.operating_dt)),
+        ("output", einsum.q_params.map(datum_type).unwrap_or_else(|| string(""))),
+        ("bias", inputs[2].clone()),
+        ("a0", inputs[3].clone()),
+        ("a_scale", inputs[4].clone()),
+        ("b0", inputs[5].clone()),
+        ("b_scale", inputs[6].clone()),
+        ("c0", inputs[7].clone()),
+        ("c_scale", inputs[8].clone()),
+    ];
+    match einsum.q_activation {
+        Some(QActivation::Relu) => named.push(("activation", string("relu"))),
+        Some(QActivation::LeakyRelu(alpha)) => {
+            named.push(("activation", string("leaky_relu")));
+            named.push(("alpha", numeric(alpha)));
+        }
+        Some(QActivation::GeluApproximate) => {
+            named.push(("activation", string("gelu_approximate")))
+        }
+        None => (),
+    }
+    if einsum.q_overflow == QOverflow::Wrap {
+        named.push(("overflow", string("wrap")));
+    }
     Ok(Some(invocation(
         "tract_core_einsum_q",
         &[Arc::new(RValue::Array(vec![inputs[0].clone(), inputs[1].clone()]))],
-        &[
-            ("expr", string(einsum.axes.to_string())),
-            ("acc", datum_type(einsum.operating_dt)),
-            ("output", einsum.q_params.map(datum_type).unwrap_or_else(|| string(""))),
-            ("bias", inputs[2].clone()),
-            ("a0", inputs[3].clone()),
-            ("a_scale", inputs[4].clone()),
-            ("b0", inputs[5].clone()),
-            ("b_scale", inputs[6].clone()),
-            ("c0", inputs[7].clone()),
-            ("c_scale", inputs[8].clone()),
-        ],
+        &named,
     )))
 }
 
@@ -96,7 +114,7 @@ pub fn de_einsum(
     let inputs: TVec<OutletId> = invocation.named_arg_as(builder, "inputs")?;
     let operating_dt = invocation.named_arg_as::<String>(builder, "acc")?;
     let operating_dt = operating_dt.parse()?;
-    let einsum = EinSum::new(expr, operating_dt);
+    let einsum = EinSum::try_new(expr, operating_dt, inputs.len())?;
     builder.wire(einsum, &inputs)
 }
 
@@ -106,7 +124,7 @@ pub fn de_einsum_q(
 ) -> TractResult<Value> {
     let expr = invocation.named_arg_as::<String>(builder, "expr")?.parse::<AxesMapping>()?;
     let mut inputs: TVec<OutletId> = invocation.named_arg_as(builder, "inputs")?;
-    for qp in parameters_q().iter().skip(4) {
+    for qp in parameters_q().iter().skip(4).take(7) {
         inputs.push(invocation.named_arg_as(builder, &qp.id.0)?);
     }
     let operating_dt = invocation.named_arg_as::<String>(builder, "acc")?;
@@ -118,6 +136,54 @@ pub fn de_einsum_q(
     } else {
         bail!("Expected an output type for tract_core_einsum_q")
     };
-    let einsum = EinSum::newq(expr, operating_dt, output_dt);
+    let mut einsum = EinSum::try_newq(expr, operating_dt, output_dt)?;
+    einsum.q_activation = match &*invocation.named_arg_as::<String>(builder, "activation")? {
+        "" => None,
+        "relu" => Some(QActivation::Relu),
+        "leaky_relu" => {
+            Some(QActivation::LeakyRelu(invocation.named_arg_as::<f32>(builder, "alpha")?))
+        }
+        "gelu_approximate" => Some(QActivation::GeluApproximate),
+        other => bail!("Unsupported fused activation {other}"),
+    };
+    einsum.q_overflow = match &*invocation.named_arg_as::<String>(builder, "overflow")? {
+        "saturate" => QOverflow::Saturate,
+        "wrap" => QOverflow::Wrap,
+        other => bail!("Unsupported output overflow {other}"),
+    };
     builder.wire(einsum, &inputs)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ast::ProtoModel;
+
+    fn load(expr: &str) -> TractResult<TypedModel> {
+        let graph = format!(
+            "version 1.0;
+            extension tract_registry tract_core;
+            graph network(a, b) -> (c) {{
+                a = external<scalar>(shape = [2, 3]);
+                b = external<scalar>(shape = [3, 4]);
+                c = tract_core_einsum([a, b], expr = \"{expr}\", acc = \"f32\");
+            }}"
+        );
+        let doc = crate::ast::parse::parse_document(&graph)?;
+        let proto = ProtoModel {
+            doc,
+            tensors: Default::default(),
+            quantization: None,
+            resources: Default::default(),
+        };
+        crate::nnef().with_tract_core().model_for_proto_model(&proto)
+    }
+
+    #[test]
+    fn inconsistent_input_count_is_an_error() -> TractResult<()> {
+        load("mk,kn->mn")?;
+        let err = load("mk,kn,k->mn").unwrap_err();
+        assert!(format!("{err:?}").contains("mk,kn,k->mn expects 3 inputs, got 2"), "{err:?}");
+        Ok(())
+    }
+}
@@ -0,0 +1,648 @@
+use crate::ast::Literal;
+use crate::internal::*;
+use crate::ser::*;
+use tract_core::ops::matmul::lir_unary::{
+    AddMatMulGeometry, LirMatMulUnary, MapOutputAxisToInput, ProtoFusedSpec,
+};
+use tract_core::ops::matmul::pack::MatMatMulPack;
+use tract_core::ops::matmul::strided::StridedInputSpec;
+use tract_core::tract_data::itertools::Itertools;
+use tract_core::tract_linalg::frame::Packer;
+use tract_core::tract_linalg::mmm::{
+    BinOp, InputStoreSpec, MatMatMul, OutputStoreSpec, RoundingPolicy,
+};
+use tract_core::tract_linalg::Scaler;
+
+pub fn register(registry: &mut Registry) {
+    registry.register_dumper(TypeId::of::<MatMatMulPack>(), ser_pack);
+    registry.register_primitive(
+        "tract_core_matmul_pack",
+        &[
+            TypeName::Scalar.tensor().array().named("inputs"),
+            TypeName::Integer.array().named("packing"),
+            TypeName::Integer.named("k_axis"),
+            TypeName::Integer.named("mn_axis"),
+            TypeName::Logical.named("parameter").default(false),
+            TypeName::Integer.named("mask_axis").default(-1),
+            TypeName::Integer.array().named("slice").default(Literal::Array(vec![])),
+            TypeName::Integer.named("k_padded").default(-1),
+        ],
+        &[("output", TypeName::Scalar.tensor())],
+        de_pack,
+    );
+    registry.register_dumper(TypeId::of::<LirMatMulUnary>(), ser_lir);
+    registry.register_primitive(
+        "tract_core_lir_matmul",
+        &[
+            TypeName::Scalar.tensor().array().named("inputs"),
+            TypeName::String.named("kernel"),
+            TypeName::String.named("acc"),
+            TypeName::Integer.array().named("a_packing"),
+            TypeName::Integer.array().named("b_packing"),
+            TypeName::String.named("c_dt"),
+            TypeName::Integer.array().named("c_shape"),
+            TypeName::Integer.named("c_m_axis"),
+            TypeName::Integer.named("c_n_axis"),
+            TypeName::Logical.named("operands_swapped").default(false),
+            TypeName::Integer.named("in_place_input").default(-1),
+            TypeName::String.array().named("ops"),
+            TypeName::Integer.array().named("k"),
+        ],
+        &[("output", TypeName::Scalar.tensor())],
+        de_lir,
+    );
+}
+
+/// Packer parameters: panel width, alignment and end padding records.
+fn packing(packer: &Packer) -> RValue {
+    ints(&[packer.r, packer.alignment(), packer.end_padding_record()])
+}
+
+fn packer(
+    builder: &mut ModelBuilder,
+    invocation: &ResolvedInvocation,
+    name: &str,
+) -> TractResult<Packer> {
+    let packing: TVec<usize> = invocation.named_arg_as(builder, name)?;
+    let &[r, alignment, end_padding_record] = &*packing else {
+        bail!("Expected a packing as [r, alignment, end_padding_record], got {packing:?}")
+    };
+    Ok(Packer::new(r, alignment, end_padding_record))
+}
+
+// Allocation hints derived from the optimizer symbol bounds (bounded outputs) are not
+// serialized: the loaded ops allocate their outputs on each run.
+fn ser_pack(ast: &mut IntoAst, node: &TypedNode) -> TractResult<Option<Arc<RValue>>> {
+    let op = node.op_as::<MatMatMulPack>().unwrap();
+    let inputs = node.inputs.iter().map(|i| (*ast.mapping[i]).clone()).collect();
+    let mut named = vec![
+        ("packing", packing(&op.packer)),
+        ("k_axis", numeric(op.k_axis)),
+        ("mn_axis", numeric(op.mn_axis)),
+    ];
+    if op.parameter {
+        named.push(("parameter", logical(true)));
+    }
+    if let Some(axis) = op.mask_axis {
+        named.push(("mask_axis", numeric(axis)));
+    }
+    if let Some((axis, range)) = &op.input_slice {
+        named.push(("slice", ints(&[*axis, range.start, range.end])));
+    }
+    if let Some(k) = op.k_padded {
+        named.push(("k_padded", numeric(k)));
+    }
+    Ok(Some(invocation("tract_core_matmul_pack", &[Arc::new(RValue::Array(inputs))], &named)))
+}
+
+fn de_pack(builder: &mut ModelBuilder, invocation: &ResolvedInvocation) -> TractResult<Value> {
+    let inputs: TVec<OutletId> = invocation.named_arg_as(builder, "inputs")?;
+    let mask_axis: i64 = invocation.named_arg_as(builder, "mask_axis")?;
+    let slice: TVec<usize> = invocation.named_arg_as(builder, "slice")?;
+    let k_padded: i64 = invocation.named_arg_as(builder, "k_padded")?;
+    let input_slice = match &*slice {
+        [] => None,
+        &[axis, start, end] => Some((axis, start..end)),
+        _ => bail!("Expected a slice as [axis, start, end], got {slice:?}"),
+    };
+    let op = MatMatMulPack {
+        packer: packer(builder, invocation, "packing")?,
+        k_axis: invocation.named_arg_as(builder, "k_axis")?,
+        mn_axis: invocation.named_arg_as(builder, "mn_axis")?,
+        parameter: invocation.named_arg_as(builder, "parameter")?,
+        mask_axis: if mask_axis < 0 { None } else { Some(mask_axis as usize) },
+        bounded_output: None,
+        input_slice,
+        k_padded: if k_padded < 0 { None } else { Some(k_padded as usize) },
+    };
+    builder.wire(op, &inputs)
+}
+
+fn ser_lir(ast: &mut IntoAst, node: &TypedNode) -> TractResult<Option<Arc<RValue>>> {
+    let op = node.op_as::<LirMatMulUnary>().unwrap();
+    ensure!(op.macro_tiles.is_none(), "Macro tiled products can not be serialized");
+    ensure!(
+        !op.c_fact.datum_type.is_quantized(),
+        "Products to a quantized type can not be serialized"
+    );
+    let inputs = node.inputs.iter().map(|i| (*ast.mapping[i]).clone()).collect();
+    let mut ops = vec![];
+    let mut k = vec![];
+    for micro_op in &op.micro_ops {
+        if let ProtoFusedSpec::AddMatMul(geo, _, _) = micro_op {
+            k.push(geo.k.clone());
+        }
+        ops.push(string(micro_op_to_string(micro_op)?));
+    }
+    let mut named = vec![
+        ("kernel", string(op.mmm.kernel_name())),
+        ("acc", datum_type(op.mmm.internal_type())),
+        ("a_packing", packing(&op.mmm.a_pack())),
+        ("b_packing", packing(&op.mmm.b_pack())),
+        ("c_dt", datum_type(op.c_fact.datum_type)),
+        ("c_shape", tdims(&op.c_fact.shape)),
+        ("c_m_axis", numeric(op.c_m_axis)),
+        ("c_n_axis", numeric(op.c_n_axis)),
+        ("operands_swapped", logical(op.operands_swapped)),
+        ("ops", array(ops)),
+        ("k", tdims(&k)),
+    ];
+    if let Some(slot) = op.in_place_input {
+        named.push(("in_place_input", numeric(slot)));
+    }
+    Ok(Some(invocation("tract_core_lir_matmul", &[Arc::new(RValue::Array(inputs))], &named)))
+}
+
+fn de_lir(builder: &mut ModelBuilder, invocation: &ResolvedInvocation) -> TractResult<Value> {
+    let mut inputs: TVec<OutletId> = invocation.named_arg_as(builder, "inputs")?;
+    let kernel: String = invocation.named_arg_as(builder, "kernel")?;
+    let acc: DatumType = invocation.named_arg_as::<String>(builder, "acc")?.parse()?;
+    let c_dt: DatumType = invocation.named_arg_as::<String>(builder, "c_dt")?.parse()?;
+    let c_shape: TVec<TDim> = invocation.named_arg_as(builder, "c_shape")?;
+    let c_m_axis: usize = invocation.named_arg_as(builder, "c_m_axis")?;
+    let c_n_axis: usize = invocation.named_arg_as(builder, "c_n_axis")?;
+    let ops: TVec<String> = invocation.named_arg_as(builder, "ops")?;
+    let mut k: TVec<TDim> = invocation.named_arg_as(builder, "k")?;
+    ensure!(c_m_axis < c_shape.len() && c_n_axis < c_shape.len());
+    let (m, n) = (c_shape[c_m_axis].clone(), c_shape[c_n_axis].clone());
+
+    let matmuls = ops.iter().filter(|op| op.starts_with("matmul ")).count();
+    ensure!(matmuls == k.len(), "Expected {matmuls} k values, got {}", k.len());
+    let operand = |ix: usize| -> TractResult<DatumType> {
+        let outlet = inputs.get(ix).with_context(|| format!("No input #{ix}"))?;
+        Ok(builder.model.outlet_fact(*outlet)?.datum_type)
+    };
+    let (a_dt, b_dt) = match ops.iter().find_map(|op| parse_matmul_operands(op)) {
+        Some((a, b)) => (operand(a?)?, operand(b?)?),
+        None => bail!("No matrix product in {ops:?}"),
+    };
+    let dims = [&m, &k[0], &n].map(|d| d.to_usize().ok());
+    let (mmm, same_kernel) = kernel_for(&kernel, (a_dt, b_dt, acc), dims)?;
+
+    let mut k_values = k.drain(..);
+    let micro_ops = ops
+        .iter()
+        .map(|op| micro_op_from_str(op, &*mmm, &mut k_values))
+        .collect::<TractResult<Vec<_>>>()?;
+    let lir =
+        LirMatMulUnary::new(mmm.clone(), c_dt.fact(&*c_shape), c_m_axis, c_n_axis, micro_ops)?;
+    let in_place_input: i64 = invocation.named_arg_as(builder, "in_place_input")?;
+    let lir = LirMatMulUnary {
+        operands_swapped: invocation.named_arg_as(builder, "operands_swapped")?,
+        in_place_input: if in_place_input < 0 { None } else { Some(in_place_input as usize) },
+        ..lir
+    };
+
+    let packings = [
+        (packer(builder, invocation, "a_packing")?, mmm.a_pack(), m),
+        (packer(builder, invocation, "b_packing")?, mmm.b_pack(), n),
+    ];
+    for op in &lir.micro_ops {
+        let ProtoFusedSpec::AddMatMul(geo, a, b) = op else { continue };
+        // a B read strided is not packed
+        let slots = if geo.b_storage.is_some() { vec![*a] } else { vec![*a, *b] };
+        for ((recorded, packer, mn), slot) in packings.iter().zip(slots) {
+            let outlet = inputs[slot];
+            inputs[slot] = if same_kernel {
+                aligned_operand(builder, outlet, packer)?
+            } else {
+                repacked_operand(builder, outlet, recorded, packer, (&geo.k, mn))
+                    .with_context(|| format!("Re-packing operand for {}", mmm.kernel_name()))?
+            };
+        }
+    }
+    if !same_kernel {
+        warn!("Kernel {kernel} is not available, running {} instead", mmm.kernel_name());
+    }
+    builder.wire(lir, &inputs)
+}
+
+/// The kernel named `name`, or the one this CPU would pick for the same types and dimensions,
+/// with false if it is not the named one.
+fn kernel_for(
+    name: &str,
+    (a, b, acc): (DatumType, DatumType, DatumType),
+    [m, k, n]: [Option<usize>; 3],
+) -> TractResult<(Box<dyn MatMatMul>, bool)> {
+    let ops = tract_core::tract_linalg::ops();
+    let generic = tract_core::tract_linalg::generic();
+    let picked = ops
+        .mmm(a, b, acc, m, k, n)
+        .with_context(|| format!("No kernel for {a:?} by {b:?} in {acc:?}"))?;
+    let candidates = [ops.mmm(a, b, acc, m, k, Some(1)), ops.mmm(a, b, acc, None, None, None)]
+        .into_iter()
+        .flatten()
+        .chain(
+            [generic.mmm(a, b, acc, m, k, n), generic.mmm(a, b, acc, m, k, Some(1))]
+                .into_iter()
+                .flatten(),
+        )
+        .chain(ops.mmm_f32_impls().iter().filter(|_| acc == f32::datum_type()).cloned());
+    if picked.kernel_name() == name {
+        return Ok((picked, true));
+    }
+    for candidate in candidates {
+        if candidate.kernel_name() == name {
+            return Ok((candidate, true));
+        }
+    }
+    Ok((picked, false))
+}
+
+/// Packed constants are loaded with their type alignment: copy them if the kernel needs more.
+fn aligned_operand(
+    builder: &mut ModelBuilder,
+    outlet: OutletId,
+    packer: &Packer,
+) -> TractResult<OutletId> {
+    // the kernel reads the tensor of the Const op, the fact may hold a copy of it
+    let konst = match builder.model.node(outlet.node).op_as::<tract_core::ops::konst::Const>() {
+        Some(konst) => Some(konst.0.clone()),
+        None => builder.model.outlet_fact(outlet)?.konst.clone(),
+    };
+    let Some(konst) = konst else { return Ok(outlet) };
+    if unsafe { konst.as_ptr_unchecked::<u8>() } as usize % packer.alignment() == 0 {
+        return Ok(outlet);
+    }
+    let mut aligned = unsafe {
+        Tensor::uninitialized_aligned_dt(konst.datum_type(), konst.shape(), packer.alignment())?
+    };
+    unsafe { aligned.as_bytes_mut().copy_from_slice(konst.as_bytes()) };
+    builder.add_const(aligned)
+}
+
+/// The operand packed by `recorded`, packed for `packer` instead.
+///
+/// Packing nodes are rewired with the new packer, and packed constants unpacked and packed
+/// again. Other operands are only accepted if both packings agree.
+fn repacked_operand(
+    builder: &mut ModelBuilder,
+    outlet: OutletId,
+    recorded: &Packer,
+    packer: &Packer,
+    (k, mn): (&TDim, &TDim),
+) -> TractResult<OutletId> {
+    if recorded == packer {
+        return aligned_operand(builder, outlet, packer);
+    }
+    let node = builder.model.node(outlet.node);
+    if let Some(pack) = node.op_as::<MatMatMulPack>() {
+        ensure!(
+            &pack.packer == recorded,
+            "Operand packed by {:?}, expected {recorded:?}",
+            pack.packer
+        );
+        let op = MatMatMulPack { packer: packer.clone(), ..pack.clone() };
+        let inputs = node.inputs.clone();
+        return Ok(builder.wire_as_outlets(op, &inputs)?[0]);
+    }
+    let Some(konst) = builder.model.outlet_fact(outlet)?.konst.clone() else {
+        bail!("Can only re-pack constants and packing nodes outputs")
+    };
+    let (k, mn) = (k.to_usize()?, mn.to_usize()?);
+    let unpack = MatMatMulPack {
+        packer: recorded.clone(),
+        k_axis: konst.rank() - 1,
+        mn_axis: konst.rank(),
+        parameter: false,
+        mask_axis: None,
+        bounded_output: None,
+        input_slice: None,
+        k_padded: None,
+    };
+    let unpacked = unpack.unpack(&konst, k, mn)?;
+    let pack = MatMatMulPack { packer: packer.clone(), ..unpack };
+    let packed = pack.pack(&unpacked, &Default::default())?;
+    builder.add_const(packed)
+}
+
+fn bin_op_name(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Min => "min",
+        BinOp::Max => "max",
+        BinOp::Add => "add",
+        BinOp::Mul => "mul",
+        BinOp::Sub => "sub",
+        BinOp::SubF => "subf",
+    }
+}
+
+fn bin_op(name: &str) -> TractResult<BinOp> {
+    Ok(match name {
+        "min" => BinOp::Min,
+        "max" => BinOp::Max,
+        "add" => BinOp::Add,
+        "mul" => BinOp::Mul,
+        "sub" => BinOp::Sub,
+        "subf" => BinOp::SubF,
+        _ => bail!("Unknown fused binary op {name}"),
+    })
+}
+
+fn rounding_policy_name(policy: RoundingPolicy) -> &'static str {
+    match policy {
+        RoundingPolicy::Native => "native",
+        RoundingPolicy::Zero => "zero",
+        RoundingPolicy::Away => "away",
+        RoundingPolicy::MinusInf => "minus_inf",
+        RoundingPolicy::PlusInf => "plus_inf",
+        RoundingPolicy::Even => "even",
+        RoundingPolicy::Odd => "odd",
+    }
+}
+
+fn rounding_policy(name: &str) -> TractResult<RoundingPolicy> {
+    Ok(match name {
+        "native" => RoundingPolicy::Native,
+        "zero" => RoundingPolicy::Zero,
+        "away" => RoundingPolicy::Away,
+        "minus_inf" => RoundingPolicy::MinusInf,
+        "plus_inf" => RoundingPolicy::PlusInf,
+        "even" => RoundingPolicy::Even,
+        "odd" => RoundingPolicy::Odd,
+        _ => bail!("Unknown rounding policy {name}"),
+    })
+}
+
+/// Output to input axis pairs, as "c:i" comma separated ("-" if empty).
+fn mapping_to_string(mapping: &MapOutputAxisToInput) -> String {
+    if mapping.0.is_empty() {
+        "-".to_string()
+    } else {
+        mapping.0.iter().map(|(c, i)| format!("{c}:{i}")).join(",")
+    }
+}
+
+fn mapping_from_str(s: &str) -> TractResult<MapOutputAxisToInput> {
+    if s == "-" {
+        return Ok(MapOutputAxisToInput(tvec!()));
+    }
+    let pairs = s
+        .split(',')
+        .map(|pair| {
+            let (c, i) =
+                pair.split_once(':').with_context(|| format!("Invalid axis pair {pair}"))?;
+            Ok((c.parse()?, i.parse()?))
+        })
+        .collect::<TractResult<_>>()?;
+    Ok(MapOutputAxisToInput(pairs))
+}
+
+fn view_axes(oss: &OutputStoreSpec) -> TractResult<(usize, usize)> {
+    match oss {
+        OutputStoreSpec::View { m_axis, n_axis, .. } => Ok((*m_axis, *n_axis)),
+        OutputStoreSpec::Strides { .. } => {
+            bail!("Products stored with strides can not be serialized")
+        }
+    }
+}
+
+/// One fused op as space separated words: its kind, then its inputs and parameters. The k of
+/// the matrix products are serialized apart, as they may be symbolic.
+fn micro_op_to_string(op: &ProtoFusedSpec) -> TractResult<String> {
+    use ProtoFusedSpec::*;
+    Ok(match op {
+        AddMatMul(geo, a, b) => {
+            // B read strided is the only virtual input a product is lowered to
+            let strided_b = match &geo.b_storage {
+                Some(InputStoreSpec::VirtualPacking { func, .. }) => {
+                    func.downcast_ref::<StridedInputSpec>()
+                }
+                _ => None,
+            };
+            ensure!(
+                !matches!(geo.a_storage, Some(InputStoreSpec::VirtualPacking { .. }))
+                    && (geo.b_storage.is_none() || strided_b.is_some()),
+                "Products of virtual inputs can not be serialized"
+            );
+            let mut s = format!(
+                "matmul {a} {b} {} {}",
+                mapping_to_string(&geo.c_to_a_axis_mapping),
+                mapping_to_string(&geo.c_to_b_axis_mapping)
+            );
+            if let Some(spec) = strided_b {
+                s.push_str(&format!(" strided_b {} {}", spec.k_axis, spec.mn_axis));
+            }
+            s
+        }
+        BinScalar(ix, op) => format!("scalar {ix} {}", bin_op_name(*op)),
+        BinPerRow(ix, op, map) => {
+            format!("per_row {ix} {} {}", bin_op_name(*op), mapping_to_string(map))
+        }
+        BinPerCol(ix, op, map) => {
+            format!("per_col {ix} {} {}", bin_op_name(*op), mapping_to_string(map))
+        }
+        AddRowColProducts(row, col) => format!("row_col_products {row} {col}"),
+        AddUnicast(oss, ix) => {
+            let (m_axis, n_axis) = view_axes(oss)?;
+            format!("add_unicast {ix} {m_axis} {n_axis}")
+        }
+        Scaler(scaler) => format!(
+            "scaler {:?} {} {} {}",
+            scaler.scale,
+            scaler.mult.map(|m| m.to_string()).unwrap_or_else(|| "-".to_string()),
+            scaler.shift,
+            rounding_policy_name(scaler.policy)
+        ),
+        Store(oss) => {
+            let (m_axis, n_axis) = view_axes(oss)?;
+            format!("store {m_axis} {n_axis}")
+        }
+    })
+}
+
+/// Operand indices of a serialized matrix product.
+fn parse_matmul_operands(op: &str) -> Option<(TractResult<usize>, TractResult<usize>)> {
+    let mut words = op.strip_prefix("matmul ")?.split_whitespace();
+    let mut operand =
+        || -> TractResult<usize> { Ok(words.next().context("Missing operand")?.parse()?) };
+    Some((operand(), operand()))
+}
+
+fn micro_op_from_str(
+    op: &str,
+    mmm: &dyn MatMatMul,
+    k: &mut impl Iterator<Item = TDim>,
+) -> TractResult<ProtoFusedSpec> {
+    let words = op.split_whitespace().collect_vec();
+    let arg = |ix: usize| -> TractResult<&str> {
+        words.get(ix).copied().with_context(|| format!("Missing argument #{ix} in {op}"))
+    };
+    let int = |ix: usize| -> TractResult<usize> { Ok(arg(ix)?.parse()?) };
+    Ok(match arg(0)? {
+        "matmul" => {
+            let k = k.next().context("Missing k")?;
+            let b_storage = if words.get(5) == Some(&"strided_b") {
+                let spec = StridedInputSpec { k_axis: int(6)?, mn_axis: int(7)? };
+                Some(unsafe { mmm.b_virtual_input(Box::new(spec), k.to_usize()?) })
+            } else {
+                None
+            };
+            ProtoFusedSpec::AddMatMul(
+                AddMatMulGeometry {
+                    k,
+                    a_storage: None,
+                    b_storage,
+                    mmm: tract_core::dyn_clone::clone_box(mmm),
+                    c_to_a_axis_mapping: mapping_from_str(arg(3)?)?,
+                    c_to_b_axis_mapping: mapping_from_str(arg(4)?)?,
+                },
+                int(1)?,
+                int(2)?,
+            )
+        }
+        "scalar" => ProtoFusedSpec::BinScalar(int(1)?, bin_op(arg(2)?)?),
+        "per_row" => {
+            ProtoFusedSpec::BinPerRow(int(1)?, bin_op(arg(2)?)?, mapping_from_str(arg(3)?)?)
+        }
+        "per_col" => {
+            ProtoFusedSpec::BinPerCol(int(1)?, bin_op(arg(2)?)?, mapping_from_str(arg(3)?)?)
+        }
+        "row_col_products" => ProtoFusedSpec::AddRowColProducts(int(1)?, int(2)?),
+        "add_unicast" => {
+            ProtoFusedSpec::AddUnicast(unsafe { mmm.c_view(int(2)?, int(3)?) }, int(1)?)
+        }
+        "scaler" => ProtoFusedSpec::Scaler(Scaler {
+            scale: arg(1)?.parse()?,
+            mult: if arg(2)? == "-" { None } else { Some(arg(2)?.parse()?) },
+            shift: arg(3)?.parse()?,
+            policy: rounding_policy(arg(4)?)?,
+        }),
+        "store" => ProtoFusedSpec::Store(unsafe { mmm.c_view(int(1)?, int(2)?) }),
+        other => bail!("Unknown fused op {other}"),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tract_core::ops::einsum::EinSum;
+    use tract_core::tract_linalg;
+
+    fn b() -> Tensor {
+        Tensor::from_shape(&[5, 6], &(0..30).map(|x| x as f32 / 4. - 3.).collect_vec()).unwrap()
+    }
+
+    fn a() -> Tensor {
+        Tensor::from_shape(&[7, 5], &(0..35).map(|x| (x % 11) as f32 - 5.).collect_vec()).unwrap()
+    }
+
+    fn run(model: &TypedModel) -> TractResult<Tensor> {
+        let mut outputs = model.clone().into_runnable()?.run(tvec!(b().into_tvalue()))?;
+        Ok(outputs.remove(0).into_tensor())
+    }
+
+    fn kernel(model: &TypedModel) -> &'static str {
+        let lir = model.nodes().iter().find_map(|n| n.op_as::<LirMatMulUnary>()).unwrap();
+        lir.mmm.kernel_name()
+    }
+
+    /// A product of a constant by an input, with a bias, lowered on the generic 4x4 kernel.
+    fn generic_lowering() -> TractResult<TypedModel> {
+        let f32 = f32::datum_type();
+        let mmm = tract_linalg::generic().mmm(f32, f32, f32, Some(7), Some(5), Some(6)).unwrap();
+        let mut model = TypedModel::default();
+        let b = model.add_source("b", f32.fact([5, 6]))?;
+        let pack = |packer: Packer, k_axis: usize, mn_axis: usize| MatMatMulPack {
+            packer,
+            k_axis,
+            mn_axis,
+            parameter: false,
+            mask_axis: None,
+            bounded_output: None,
+            input_slice: None,
+            k_padded: None,
+        };
+        let a = pack(mmm.a_pack(), 1, 0).pack(&a(), &Default::default())?;
+        let a = model.add_const("a", a)?;
+        let b = model.wire_node("pack_b", pack(mmm.b_pack(), 0, 1), &[b])?[0];
+        let bias = model.add_const("bias", tensor1(&[1f32, 2., 3., 4., 5., 6., 7.]))?;
+        let geo = AddMatMulGeometry {
+            k: 5.to_dim(),
+            a_storage: None,
+            b_storage: None,
+            mmm: mmm.clone(),
+            c_to_a_axis_mapping: MapOutputAxisToInput(tvec!()),
+            c_to_b_axis_mapping: MapOutputAxisToInput(tvec!()),
+        };
+        let micro_ops = vec![
+            ProtoFusedSpec::AddMatMul(geo, 0, 1),
+            ProtoFusedSpec::BinPerRow(2, BinOp::Add, MapOutputAxisToInput(tvec!())),
+            ProtoFusedSpec::Store(unsafe { mmm.c_view(0, 1) }),
+        ];
+        let lir = LirMatMulUnary::new(mmm, f32.fact([7, 6]), 0, 1, micro_ops)?;
+        let c = model.wire_node("c", lir, &[a, b, bias])?;
+        model.set_output_outlets(&c)?;
+        Ok(model)
+    }
+
+    #[test]
+    fn optimized_product_round_trip() -> TractResult<()> {
+        let mut model = TypedModel::default();
+        let a = model.add_const("a", a())?;
+        let b = model.add_source("b", f32::fact([5, 6]))?;
+        let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let c = model.wire_node("c", op, &[a, b])?;
+        model.set_output_outlets(&c)?;
+        let optimized = model.into_optimized()?;
+
+        let nnef = crate::nnef().with_tract_core();
+        let buffer = nnef.write_to_tar(&optimized, vec![])?;
+        let loaded = nnef.model_for_read(&mut &*buffer)?;
+        assert_eq!(kernel(&loaded), kernel(&optimized));
+        assert_eq!(run(&loaded)?, run(&optimized)?);
+        Ok(())
+    }
+
+    #[test]
+    fn k_padded_packing_round_trip() -> TractResult<()> {
+        use tract_core::ops::matmul::pack::KPadding;
+        let mut model = TypedModel::default();
+        let a = model.add_const("a", a())?;
+        let b = model.add_source("b", f32::fact([5, 6]))?;
+        let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let c = model.wire_node("c", op, &[a, b])?;
+        model.set_output_outlets(&c)?;
+        let reference = run(&model)?;
+        let options = tract_core::optim::OptimizerOptions {
+            k_padding: Some(KPadding { max_k: 8, multiple: 4 }),
+            ..Default::default()
+        };
+        let optimized = model.into_optimized_with_options(&options)?;
+
+        let nnef = crate::nnef().with_tract_core();
+        let buffer = nnef.write_to_tar(&optimized, vec![])?;
+        let loaded = nnef.model_for_read(&mut &*buffer)?;
+        let pack = loaded.nodes().iter().find_map(|n| n.op_as::<MatMatMulPack>()).unwrap();
+        assert_eq!(pack.k_padded, Some(8));
+        run(&loaded)?.close_enough(&reference, Approximation::Approximate)?;
+        Ok(())
+    }
+
+    #[test]
+    fn foreign_kernel_is_repacked() -> TractResult<()> {
+        let model = generic_lowering()?;
+        let expected = run(&model)?;
+        let nnef = crate::nnef().with_tract_core();
+        let mut proto = crate::ser::to_proto_model(&nnef, &model)?;
+        let loaded = nnef.model_for_proto_model(&proto)?;
+        assert_eq!(kernel(&loaded), kernel(&model));
+        assert_eq!(run(&loaded)?, expected);
+
+        // as if the model was optimized on a CPU with another kernel
+        for assignment in &mut proto.doc.graph_def.body {
+            let RValue::Invocation(invocation) = &mut assignment.right else { continue };
+            for arg in &mut invocation.arguments {
+                if arg.id.as_ref().map(|id| &*id.0) == Some("kernel") {
+                    arg.rvalue = string("foreign_mmm_f32_4x4");
+                }
+            }
+        }
+        let loaded = nnef.model_for_proto_model(&proto)?;
+        let f32 = f32::datum_type();
+        let native = tract_linalg::ops().mmm(f32, f32, f32, Some(7), Some(5), Some(6)).unwrap();
+        assert_eq!(kernel(&loaded), native.kernel_name());
+        run(&loaded)?.close_enough(&expected, Approximation::Approximate)?;
+        Ok(())
+    }
+}
@@ -581,11 +581,11 @@ pub fn matmul(builder: &mut ModelBuilder, invocation: &ResolvedInvocation) -> Tr
         let c_scale = builder.model.add_const(format!("{name}.c_scale"), rctensor0(c_qp.1))?;
 
         builder.wire(
-            ops::einsum::EinSum { axes, operating_dt: i32::datum_type(), q_params: Some(c_dt) },
+            ops::einsum::EinSum::newq(axes, i32::datum_type(), c_dt),
             &[a, b, bias, a0, a_scale, b0, b_scale, c0, c_scale],
         )
     } else {
-        builder.wire(ops::einsum::EinSum { axes, operating_dt: a_dt, q_params: None }, &[a, b])
+        builder.wire(ops::einsum::EinSum::new(axes, a_dt), &[a, b])
     }
 }
 
@@ -1,6 +1,7 @@
 use crate::model::ParsingContext;
 use crate::pb::*;
 use tract_hir::internal::*;
+use tract_hir::tract_core::broadcast::broadcast_dim;
 
 pub fn einsum(
     _ctx: &ParsingContext,
@@ -33,11 +34,7 @@ impl Expansion for EinSum {
             .collect::<TractResult<TVec<_>>>()?;
         let expr = resolve_ellipsis(&self.expr, &ranks)?;
         let operating_dt = model.outlet_fact(inputs[0])?.datum_type;
-        model.wire_node(
-            prefix,
-            tract_core::ops::einsum::EinSum { axes: expr, operating_dt, q_params: None },
-            inputs,
-        )
+        model.wire_node(prefix, tract_core::ops::einsum::EinSum::new(expr, operating_dt), inputs)
     }
 
     fn rules<'r, 'p: 'r, 's: 'r>(
@@ -67,16 +64,29 @@ impl Expansion for EinSum {
             let expr = resolve_ellipsis(&self.expr, &ranks)?;
             s.equals(&outputs[0].rank, expr.rank(InOut::Out(0)) as i64)?;
             for axis in expr.iter_all_axes() {
-                let mut axes = vec![];
-                if let Some(result) = axis.outputs[0].first() {
-                    axes.push(outputs[0].shape[*result].bex())
-                }
+                let mut dims = vec![];
                 for (input_id, input_axis_positions) in axis.inputs.iter().enumerate() {
                     for position in input_axis_positions {
-                        axes.push(inputs[input_id].shape[*position].bex());
+                        dims.push(inputs[input_id].shape[*position].bex());
                     }
                 }
-                s.equals_all(axes)?;
+                let (repr, result) = (axis.repr, axis.outputs[0].first().copied());
+                if dims.len() == 1 {
+                    if let Some(result) = result {
+                        dims.push(outputs[0].shape[result].bex());
+                    }
+                    s.equals_all(dims)?;
+                    continue;
+                }
+                // dims of 1 broadcast: the output dim is only known once all the inputs ones are
+                s.given_all(dims, move |s, dims| {
+                    let dim = broadcast_dim(result.unwrap_or(0), &dims)
+                        .with_context(|| format!("Inconsistent dimensions for axis {repr}"))?;
+                    if let Some(result) = result {
+                        s.equals(&outputs[0].shape[result], dim)?;
+                    }
+                    Ok(())
+                })?;
             }
             Ok(())
         })
@@ -153,4 +163,21 @@ mod test {
         assert_eq!(resolved, "abgi,gih->abgh".parse().unwrap());
         Ok(())
     }
+
+    #[test]
+    fn symbol_survives_broadcast_one() -> TractResult<()> {
+        let mut model = InferenceModel::default();
+        let s = model.symbol_table.sym("S");
+        let a = model.add_source("a", f32::fact(dims![s, 4, 3].as_ref()).into())?;
+        let b = model.add_source("b", f32::fact(dims![s, 3, 3].as_ref()).into())?;
+        let c = model.add_source("c", f32::fact([1, 3, 3]).into())?;
+        let einsum = expand(EinSum { expr: "bmk,bkn,bkn->bmn".parse()? });
+        let product = model.wire_node("einsum", einsum, &[a, c, b])?[0];
+        let sum = model.wire_node("add", tract_hir::ops::math::Add.into_hir(), &[product, a])?;
+        model.set_output_outlets(&sum)?;
+        let typed = model.into_typed()?;
+        let expected: TVec<TDim> = tvec!(s.to_dim(), 4.into(), 3.into());
+        assert_eq!(typed.output_fact(0)?.shape.to_tvec(), expected);
+        Ok(())
+    }
 }
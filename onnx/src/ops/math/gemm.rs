@@ -2,6 +2,8 @@ use crate::model::ParsingContext;
 use crate::pb::*;
 use tract_hir::internal::*;
 use tract_hir::ops;
+use tract_hir::ops::logic::wire_with_rank_broadcast;
+use tract_hir::tract_core::ops::cast::cast;
 use tract_hir::tract_core::ops::einsum::EinSum;
 
 pub fn gemm(
@@ -12,15 +14,62 @@ pub fn gemm(
     let beta = node.get_attr_opt("beta")?.unwrap_or(1.);
     let trans_a = node.get_attr_opt("transA")?.unwrap_or(false);
     let trans_b = node.get_attr_opt("transB")?.unwrap_or(false);
-    Ok((expand(Gemm::new(alpha, beta, trans_a, trans_b)), vec![]))
+    let has_c = crate::model::optional_inputs(node).nth(2).unwrap().is_some();
+    Ok((expand(Gemm::new(alpha, beta, trans_a, trans_b, has_c)), vec![]))
 }
 
+/// alpha × A·B + beta × C, C being optional (since opset 11).
+///
+/// The scalars are applied to the operands: alpha scales A, and ends up folded in a constant B,
+/// or applied to the product by the kernel (see `EinSum::declutter_operand_scalar`). beta scales
+/// C, folded with it if it is a constant, and the sum is fused in the kernel. A zero beta drops
+/// C altogether, dynamic or not. On integers, a fractional alpha scales the product and a
+/// fractional beta scales C, both in f32 truncated back to the integer type.
 #[derive(Debug, Clone, new)]
 pub struct Gemm {
     alpha: f32,
     beta: f32,
     trans_a: bool,
     trans_b: bool,
+    has_c: bool,
+}
+
+impl Gemm {
+    /// Can `factor` scale a `dt` operand without changing the result ? A fractional factor
+    /// would truncate an integer operand.
+    fn exact_scale(factor: f32, dt: DatumType) -> bool {
+        dt.is_float() || factor.fract() == 0.0
+    }
+
+    /// Scale `wire` by `factor`. A fractional factor of an integer wire is applied in f32, the
+    /// result being cast back to the integer type.
+    fn scaled(
+        model: &mut TypedModel,
+        name: String,
+        wire: OutletId,
+        factor: f32,
+    ) -> TractResult<OutletId> {
+        if factor == 1.0 {
+            return Ok(wire);
+        }
+        let dt = model.outlet_fact(wire)?.datum_type;
+        if Self::exact_scale(factor, dt) {
+            let factor = tensor0(factor).cast_to_dt(dt)?.into_owned();
+            let factor = model.add_const(format!("{name}.cst"), factor)?;
+            return Ok(
+                wire_with_rank_broadcast(&name, model, ops::math::mul(), &[wire, factor])?[0]
+            );
+        }
+        let float = model.wire_node(format!("{name}.as_f32"), cast(f32::datum_type()), &[wire])?;
+        let factor = model.add_const(format!("{name}.cst"), tensor0(factor))?;
+        let scaled = wire_with_rank_broadcast(
+            &format!("{name}.scaled"),
+            model,
+            ops::math::mul(),
+            &[float[0], factor],
+        )?;
+        Ok(model.wire_node(name, cast(dt), &scaled)?[0])
+    }
 }
 
 impl Expansion for Gemm {
@@ -34,8 +83,10 @@ impl Expansion for Gemm {
         inputs: &'p [TensorProxy],
         outputs: &'p [TensorProxy],
     ) -> InferenceResult {
-        check_input_arity(inputs, 3)?;
-        s.equals(&inputs[2].datum_type, &outputs[0].datum_type)?;
+        check_input_arity(inputs, 2 + self.has_c as usize)?;
+        if self.has_c {
+            s.equals(&inputs[2].datum_type, &outputs[0].datum_type)?;
+        }
         s.equals(&inputs[0].rank, 2)?;
         s.equals(&inputs[1].rank, 2)?;
         check_output_arity(outputs, 1)?;
@@ -56,36 +107,195 @@ impl Expansion for Gemm {
         model: &mut TypedModel,
         inputs: &[OutletId],
     ) -> TractResult<TVec<OutletId>> {
-        let (a, b, mut c) = (inputs[0], inputs[1], inputs[2]);
+        // a fractional alpha of an integer Gemm scales the product, not A
+        let dt = model.outlet_fact(inputs[0])?.datum_type;
+        let alpha_on_a = Self::exact_scale(self.alpha, dt);
+        let a = if alpha_on_a {
+            Self::scaled(model, format!("{name}.alpha_a"), inputs[0], self.alpha)?
+        } else {
+            inputs[0]
+        };
         let axes = AxesMapping::for_numpy_matmul(2, self.trans_a, self.trans_b, false)?;
         let mut wire = model.wire_node(
             format!("{name}.ab"),
-            EinSum::new(axes, model.outlet_fact(a)?.datum_type),
-            [a, b].as_ref(),
+            EinSum::new(axes, dt),
+            [a, inputs[1]].as_ref(),
         )?[0];
-        if self.alpha != 1.0 {
-            let alpha = tensor0(self.alpha).broadcast_into_rank(model.outlet_fact(wire)?.rank())?;
-            let alpha = model.add_const(name.to_string() + ".alpha_ab.cst", alpha)?;
-            wire = model.wire_node(
-                name.to_string() + ".alpha_ab",
-                ops::math::mul(),
-                &[alpha, wire],
-            )?[0];
+        if !alpha_on_a {
+            wire = Self::scaled(model, format!("{name}.alpha_ab"), wire, self.alpha)?;
         }
-        if self.beta != 0.0f32 {
-            while model.outlet_fact(wire)?.rank() > model.outlet_fact(c)?.rank() {
-                c = model.wire_node(
-                    format!("{}.c_add_axis_{}", name, model.outlet_fact(c)?.rank()),
-                    tract_hir::tract_core::ops::change_axes::AxisOp::Add(0),
-                    &[c],
-                )?[0];
-            }
-            let beta = tensor0(self.beta).broadcast_into_rank(model.outlet_fact(wire)?.rank())?;
-            let beta = model.add_const(name.to_string() + ".beta_c.cst", beta)?;
-            let beta_c =
-                model.wire_node(name.to_string() + ".beta_c", ops::math::mul(), &[beta, c])?[0];
-            wire = model.wire_node(name, ops::math::add(), &[wire, beta_c])?[0];
+        if self.has_c && self.beta != 0.0 {
+            let c = Self::scaled(model, format!("{name}.beta_c"), inputs[2], self.beta)?;
+            wire = wire_with_rank_broadcast(name, model, ops::math::add(), &[wire, c])?[0];
         }
         Ok(tvec!(wire))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tract_hir::tract_core::ops::binary::TypedBinOp;
+    use tract_ndarray::{Array2, Ix2};
+
+    fn operand(shape: &[usize], seed: usize) -> Tensor {
+        let len = shape.iter().product::<usize>();
+        let values = (0..len).map(|i| ((i * 7 + seed) % 13) as f32 / 4. - 1.5).collect::<Vec<_>>();
+        Tensor::from_shape(shape, &values).unwrap()
+    }
+
+    /// alpha × op(A)·op(B) + beta × C, in f64, like the ONNX reference implementation.
+    fn reference(gemm: &Gemm, a: &Tensor, b: &Tensor, c: Option<&Tensor>) -> TractResult<Tensor> {
+        let matrix = |t: &Tensor, trans: bool| -> TractResult<Array2<f64>> {
+            let m = t
+                .cast_to::<f64>()?
+                .to_array_view::<f64>()?
+                .into_dimensionality::<Ix2>()?
+                .to_owned();
+            Ok(if trans { m.reversed_axes() } else { m })
+        };
+        let mut result =
+            matrix(a, gemm.trans_a)?.dot(&matrix(b, gemm.trans_b)?) * gemm.alpha as f64;
+        if let Some(c) = c {
+            let c = c.cast_to::<f64>()?.into_owned().into_array::<f64>()?;
+            let c = c.broadcast(result.raw_dim()).unwrap().mapv(|x| x * gemm.beta as f64);
+            result = result + c;
+        }
+        Ok(result.into_tensor().cast_to::<f32>()?.into_owned())
+    }
+
+    /// A Gemm of a dynamic A by a constant B, with a dynamic C of `c_shape`.
+    fn model(
+        gemm: &Gemm,
+        a_shape: &[usize],
+        b: &Tensor,
+        c_shape: &[usize],
+    ) -> TractResult<InferenceModel> {
+        let mut model = InferenceModel::default();
+        let mut inputs = tvec!(
+            model.add_source("a", f32::fact(a_shape).into())?,
+            model.add_const("b", b.clone())?
+        );
+        if gemm.has_c {
+            inputs.push(model.add_source("c", f32::fact(c_shape).into())?);
+        }
+        let output = model.wire_node("gemm", expand(gemm.clone()), &inputs)?;
+        model.set_output_outlets(&output)?;
+        Ok(model)
+    }
+
+    fn check(
+        gemm: Gemm,
+        a_shape: &[usize],
+        b_shape: &[usize],
+        c_shape: &[usize],
+    ) -> TractResult<TypedModel> {
+        let (a, b, c) = (operand(a_shape, 0), operand(b_shape, 1), operand(c_shape, 2));
+        let expected = reference(&gemm, &a, &b, gemm.has_c.then_some(&c))?;
+        let model = model(&gemm, a_shape, &b, c_shape)?.into_optimized()?;
+        let mut inputs = tvec!(a.into_tvalue());
+        if gemm.has_c {
+            inputs.push(c.into_tvalue());
+        }
+        let found = model.clone().into_runnable()?.run(inputs)?;
+        found[0].close_enough(&expected, Approximation::Approximate)?;
+        Ok(model)
+    }
+
+    fn muls(model: &TypedModel) -> Vec<&TypedNode> {
+        let is_mul = |n: &&TypedNode| {
+            n.op_as::<TypedBinOp>().map_or(false, |bin| bin.0.is::<ops::math::Mul>())
+        };
+        model.nodes().iter().filter(is_mul).collect()
+    }
+
+    #[test]
+    fn alpha_and_beta() -> TractResult<()> {
+        let optimized = check(Gemm::new(2., 0.5, false, false, true), &[3, 6], &[6, 4], &[4])?;
+        // alpha went in the constant B, beta scales C alone
+        let muls = muls(&optimized);
+        assert_eq!(muls.len(), 1, "{optimized}");
+        let c = optimized.input_outlets()?[1];
+        assert!(muls[0].inputs.contains(&c));
+        Ok(())
+    }
+
+    #[test]
+    fn zero_beta_prunes_dynamic_bias() -> TractResult<()> {
+        let gemm = Gemm::new(1., 0., false, false, true);
+        let b = operand(&[6, 4], 1);
+        let mut model = model(&gemm, &[3, 6], &b, &[3, 4])?;
+        // C is computed from the c input
+        let c = model.input_outlets()?[1];
+        let doubled =
+            model.wire_node("c.double", tract_hir::ops::math::Add.into_hir(), &[c, c])?[0];
+        let gemm_node = model.node_id_by_name("gemm")?;
+        model.add_edge(doubled, InletId::new(gemm_node, 2))?;
+        let decluttered = model.into_typed()?.into_decluttered()?;
+        assert!(decluttered.node_by_name("c.double").is_err());
+        assert!(decluttered.outlet_successors(c).is_empty());
+        assert!(decluttered.nodes().iter().all(|n| n.op_as::<TypedBinOp>().is_none()));
+        Ok(())
+    }
+
+    /// An i32 Gemm of a dynamic A by a constant B, with a dynamic C, compared to the ONNX
+    /// reference semantics: the scaled terms are truncated to i32 before the sum.
+    fn check_i32(gemm: Gemm) -> TractResult<()> {
+        let int_operand = |shape: &[usize], seed: usize| -> TractResult<Tensor> {
+            let len = shape.iter().product::<usize>();
+            let values = (0..len).map(|i| ((i * 7 + seed) % 13) as i32 - 6).collect::<Vec<_>>();
+            Tensor::from_shape(shape, &values)
+        };
+        let (a, b, c) = (int_operand(&[3, 5], 0)?, int_operand(&[5, 4], 1)?, int_operand(&[4], 2)?);
+        let ab = a
+            .to_array_view::<i32>()?
+            .into_dimensionality::<Ix2>()?
+            .dot(&b.to_array_view::<i32>()?.into_dimensionality::<Ix2>()?);
+        let c_view = c.to_array_view::<i32>()?;
+        let expected = ab.mapv(|x| (x as f32 * gemm.alpha) as i32)
+            + c_view.broadcast(ab.raw_dim()).unwrap().mapv(|x| (x as f32 * gemm.beta) as i32);
+        let mut model = InferenceModel::default();
+        let inputs = tvec!(
+            model.add_source("a", i32::fact([3, 5]).into())?,
+            model.add_const("b", b)?,
+            model.add_source("c", i32::fact([4]).into())?,
+        );
+        let output = model.wire_node("gemm", expand(gemm), &inputs)?;
+        model.set_output_outlets(&output)?;
+        let found = model
+            .into_optimized()?
+            .into_runnable()?
+            .run(tvec!(a.into_tvalue(), c.into_tvalue()))?;
+        assert_eq!(*found[0], expected.into_tensor());
+        Ok(())
+    }
+
+    #[test]
+    fn integer_alpha_and_beta() -> TractResult<()> {
+        check_i32(Gemm::new(2., 3., false, false, true))
+    }
+
+    #[test]
+    fn fractional_alpha_and_beta_on_integers() -> TractResult<()> {
+        check_i32(Gemm::new(0.5, 1.5, false, false, true))?;
+        check_i32(Gemm::new(-0.25, 0.5, false, false, true))
+    }
+
+    // onnxruntime is not available offline to compare with: the ONNX backend node tests
+    // (test_gemm_*, run by the harness) cover the float cases against its reference outputs.
+    #[test]
+    fn attribute_combinations() -> TractResult<()> {
+        // the cases of the ONNX node tests
+        check(Gemm::new(0.25, 0.35, true, true, true), &[4, 3], &[5, 4], &[1, 5])?;
+        check(Gemm::new(0.5, 1., false, false, true), &[3, 5], &[5, 4], &[1, 4])?;
+        check(Gemm::new(1., 0.5, false, false, true), &[2, 7], &[7, 4], &[1, 4])?;
+        check(Gemm::new(1., 1., false, false, true), &[3, 5], &[5, 4], &[3, 4])?;
+        check(Gemm::new(1., 1., false, false, true), &[2, 3], &[3, 4], &[])?;
+        check(Gemm::new(1., 1., false, false, true), &[2, 3], &[3, 4], &[1])?;
+        check(Gemm::new(1., 1., true, false, true), &[6, 3], &[6, 4], &[1, 4])?;
+        check(Gemm::new(1., 1., false, true, true), &[3, 6], &[4, 6], &[1, 4])?;
+        check(Gemm::new(1., 1., false, false, false), &[3, 6], &[6, 4], &[])?;
+        check(Gemm::new(-2., 0., true, false, true), &[6, 3], &[6, 4], &[3, 4])?;
+        Ok(())
+    }
+}
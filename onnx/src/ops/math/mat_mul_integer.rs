@@ -178,10 +178,6 @@ fn wire_as_einsum(
     if ranks[8] == 1 {
         expr = expr.linking('m', (InOut::In(8), 0))?;
     }
-    let op = tract_core::ops::einsum::EinSum {
-        axes: expr,
-        operating_dt: i32::datum_type(),
-        q_params: Some(output),
-    };
+    let op = tract_core::ops::einsum::EinSum::newq(expr, i32::datum_type(), output);
     target.wire_node(prefix, op, inputs)
 }
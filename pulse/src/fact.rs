@@ -33,8 +33,6 @@ pub struct PulsedFact {
     pub stream: Option<StreamInfo>,
 }
 
-
-
 impl PulsedFact {
     pub fn from_tensor_fact_pulse(
         tf: &TypedFact,
@@ -63,6 +61,34 @@ impl PulsedFact {
         }
     }
 
+    /// Check the consistency of the stream: its axis is in the rank, its pulse is positive, and
+    /// its full dim is not negative. A full dim statically smaller than the pulse is only valid
+    /// for a stream lying in a single window, the final and partial one.
+    pub fn check_stream(&self) -> TractResult<()> {
+        let Some(stream) = &self.stream else { return Ok(()) };
+        ensure!(
+            stream.axis < self.shape.rank(),
+            "Inconsistent pulsed fact {self:?}: streaming axis {} is out of rank {}",
+            stream.axis,
+            self.shape.rank()
+        );
+        let pulse = self.shape[stream.axis].to_i64().ok();
+        if let Some(pulse) = pulse {
+            ensure!(pulse > 0, "Inconsistent pulsed fact {self:?}: pulse must be positive");
+        }
+        if let Ok(dim) = stream.dim.to_i64() {
+            ensure!(dim >= 0, "Inconsistent pulsed fact {self:?}: stream dim must not be negative");
+            if let Some(pulse) = pulse {
+                ensure!(
+                    dim >= pulse || (stream.delay as i64 % pulse) + dim <= pulse,
+                    "Inconsistent pulsed fact {self:?}: stream dim is smaller than the pulse, \
+                    but spans more than a single window"
+                );
+            }
+        }
+        Ok(())
+    }
+
     pub fn to_pulse_fact(&self) -> TypedFact {
         self.datum_type.fact(self.shape.clone())
     }
@@ -138,3 +164,52 @@ impl<'a> From<&'a PulsedFact> for TypedFact {
         fact.datum_type.fact(fact.shape.clone())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fact(shape: &[i64], axis: usize, dim: TDim, delay: usize) -> PulsedFact {
+        PulsedFact {
+            datum_type: f32::datum_type(),
+            shape: shape.iter().map(|d| d.to_dim()).collect::<TVec<_>>().into(),
+            stream: Some(StreamInfo { axis, dim, delay }),
+        }
+    }
+
+    fn check_err(fact: PulsedFact, invariant: &str) {
+        let err = fact.check_stream().unwrap_err();
+        assert!(format!("{err:?}").contains(invariant), "{err:?}");
+    }
+
+    #[test]
+    fn consistent_streams() -> TractResult<()> {
+        let s = SymbolTable::default().sym("S");
+        fact(&[4, 3], 0, s.to_dim(), 0).check_stream()?;
+        fact(&[4, 3], 0, s.to_dim() - 10, 7).check_stream()?;
+        fact(&[4, 3], 0, 6.to_dim(), 7).check_stream()?;
+        // a short stream, in the final window
+        fact(&[4, 3], 0, 2.to_dim(), 5).check_stream()?;
+        Ok(())
+    }
+
+    #[test]
+    fn axis_out_of_rank() {
+        check_err(fact(&[4, 3], 2, 6.to_dim(), 0), "out of rank");
+    }
+
+    #[test]
+    fn pulse_not_positive() {
+        check_err(fact(&[0, 3], 0, 6.to_dim(), 0), "pulse must be positive");
+    }
+
+    #[test]
+    fn negative_dim() {
+        check_err(fact(&[4, 3], 0, (-2).to_dim(), 0), "must not be negative");
+    }
+
+    #[test]
+    fn dim_smaller_than_pulse() {
+        check_err(fact(&[4, 3], 0, 2.to_dim(), 3), "smaller than the pulse");
+    }
+}
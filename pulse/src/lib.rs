@@ -81,6 +81,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_einsum_contracting_stream_axis() {
+        let mut model = TypedModel::default();
+        let s = model.symbol_table.sym("S");
+        let a = model.add_source("a", f32::fact(dims![2, s].as_ref())).unwrap();
+        let b = model.add_source("b", f32::fact(dims![s, 3].as_ref())).unwrap();
+        let op =
+            tract_core::ops::einsum::EinSum::new("mk,kn->mn".parse().unwrap(), f32::datum_type());
+        let c = model.wire_node("einsum", op, &[a, b]).unwrap();
+        model.set_output_outlets(&c).unwrap();
+        let err = PulsedModel::new(&model, s, &4.to_dim()).unwrap_err();
+        assert!(format!("{err:?}").contains("contracted axis k"), "{err:?}");
+    }
+
     #[test]
     fn test_immediate() {
         let mut model = TypedModel::default();
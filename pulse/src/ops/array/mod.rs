@@ -1,6 +1,6 @@
 use crate::internal::*;
 
-mod concat;
+pub(crate) mod concat;
 mod pad;
 mod slice;
 
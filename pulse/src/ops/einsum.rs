@@ -0,0 +1,527 @@
+use crate::fact::StreamInfo;
+use crate::internal::*;
+use crate::ops::array::concat::overwrite_part_of_pulse;
+use crate::ops::sync_inputs;
+use tract_core::axes::Axis;
+use tract_core::ops::einsum::EinSum;
+use tract_pulse_opl::ops::Delay;
+use tract_pulse_opl::tract_core::trivial_op_state_freeeze;
+
+register_all!(EinSum: pulsify);
+
+/// EinSum pulsifies through its axes mapping when the streaming axis reaches the output. This
+/// catches the cases where it does not: streaming along a contracted (k) axis is only valid over a
+/// finite window (see `pulsify_window`), and constant inputs with a full (non-broadcast)
+/// dimension on the streaming axis, like a stack of per-expert weights indexed by the streaming
+/// batch axis, are fed pulse by pulse.
+fn pulsify(
+    op: &EinSum,
+    source: &TypedModel,
+    node: &TypedNode,
+    target: &mut PulsedModel,
+    mapping: &HashMap<OutletId, OutletId>,
+    _symbol: &Symbol,
+    pulse: &TDim,
+) -> TractResult<Option<TVec<OutletId>>> {
+    let mut streaming = None;
+    for (ix, input) in node.inputs.iter().enumerate() {
+        let fact = target.outlet_fact(mapping[input])?;
+        fact.check_stream().with_context(|| format!("Pulsifying {node}, input #{ix}"))?;
+        let Some(stream) = &fact.stream else { continue };
+        let axis = op.axes.axis((InOut::In(ix), stream.axis))?;
+        if axis.outputs[0].is_empty() {
+            if let Some(window) = window_length(source, node, axis, ix)? {
+                return pulsify_window(op, node, target, mapping, ix, window, pulse);
+            }
+            bail!(
+                "Can not pulsify {} along its contracted axis {} (axis {} of input #{ix}, {:?}): \
+                no other input bounds it to a finite window, so each output depends on the whole \
+                stream. If the model contracts over a window of the stream, the window axis \
+                should be a distinct, finite axis, with the streaming axis moved to a batch or \
+                m/n position.",
+                node,
+                axis.repr,
+                stream.axis,
+                source.outlet_fact(*input)?,
+            )
+        }
+        streaming.get_or_insert((ix, axis.repr));
+    }
+    let Some((stream_ix, repr)) = streaming else { return Ok(None) };
+    let axis = op.axes.axis(repr)?;
+    let mut inputs = sync_inputs(node, target, mapping)?;
+    let stream = target.outlet_fact(inputs[stream_ix])?.stream.clone().unwrap();
+    let mut sliced = false;
+    for (ix, input) in node.inputs.iter().enumerate() {
+        let (Some(konst), &[pos]) = (&source.outlet_fact(*input)?.konst, &*axis.inputs[ix]) else {
+            continue;
+        };
+        if konst.shape()[pos] == 1 {
+            continue;
+        }
+        let slices = PulsedConstSlices {
+            konst: konst.clone(),
+            axis: pos,
+            stream_axis: stream.axis,
+            delay: stream.delay,
+        };
+        let name = format!("{}.slices-{ix}", node.name);
+        inputs[ix] = target.wire_node(name, slices, &[inputs[stream_ix]])?[0];
+        sliced = true;
+    }
+    if !sliced {
+        return Ok(None);
+    }
+    let pulse_op = crate::model::PulseWrappingOp(Box::new(op.clone()));
+    Ok(Some(target.wire_node(&*node.name, pulse_op, &inputs)?))
+}
+
+/// The length of the window an einsum contracts its streaming input `stream_ix` over, along
+/// `axis`: the dimension the other inputs agree on, if it is known and not a broadcast 1.
+fn window_length(
+    source: &TypedModel,
+    node: &TypedNode,
+    axis: &Axis,
+    stream_ix: usize,
+) -> TractResult<Option<usize>> {
+    let mut window = None;
+    for (ix, input) in node.inputs.iter().enumerate() {
+        if ix == stream_ix {
+            continue;
+        }
+        let fact = source.outlet_fact(*input)?;
+        for dim in axis.inputs[ix].iter().map(|pos| &fact.shape[*pos]) {
+            match dim.to_usize() {
+                Ok(1) => (),
+                Ok(dim) if window.is_none() || window == Some(dim) => window = Some(dim),
+                _ => return Ok(None),
+            }
+        }
+    }
+    Ok(window)
+}
+
+/// Pulsify an einsum contracting its streaming input over a finite window, like a learned
+/// projection of the last `window` frames. The hop between windows is the pulse: the streaming
+/// input is buffered to the window length by a delay overlapping the previous pulses, and each
+/// pulse computes the einsum once, for the window ending with the pulse. The output streams
+/// windows along its first axis of length 1, like a batch of one window.
+fn pulsify_window(
+    op: &EinSum,
+    node: &TypedNode,
+    target: &mut PulsedModel,
+    mapping: &HashMap<OutletId, OutletId>,
+    stream_ix: usize,
+    window: usize,
+    pulse: &TDim,
+) -> TractResult<Option<TVec<OutletId>>> {
+    let hop = pulse.to_usize().with_context(|| {
+        format!("Pulsifying {node} over a window of {window}: the pulse must be known")
+    })?;
+    ensure!(
+        window >= hop,
+        "Can not pulsify {node} over a window of {window} with a pulse of {hop}: the hop \
+        between windows is the pulse, and can not be longer than the window"
+    );
+    let mut inputs: TVec<OutletId> = node.inputs.iter().map(|i| mapping[i]).collect();
+    for (ix, input) in inputs.iter().enumerate() {
+        ensure!(
+            ix == stream_ix || target.outlet_fact(*input)?.stream.is_none(),
+            "Can not pulsify {node} over a window: input #{ix} streams too"
+        );
+    }
+    let fact = target.outlet_fact(inputs[stream_ix])?.clone();
+    let stream = fact.stream.clone().unwrap();
+    // windows start on a hop boundary of the stream
+    let overlap = window - hop;
+    let misalignment = (stream.delay + overlap) % hop;
+    let delay = if misalignment > 0 { hop - misalignment } else { 0 };
+    inputs[stream_ix] = target.wire_node(
+        format!("{}.window", node.name),
+        Delay::new_typed(&(&fact).into(), stream.axis, delay, overlap),
+        &[inputs[stream_ix]],
+    )?[0];
+    let facts = inputs
+        .iter()
+        .map(|i| target.outlet_fact(*i)?.to_typed_fact().map(|f| f.into_owned()))
+        .collect::<TractResult<TVec<_>>>()?;
+    let output = op.output_facts(&facts.iter().collect::<TVec<_>>())?.remove(0);
+    let axis = output.shape.iter().position(|d| d.is_one()).with_context(|| {
+        format!(
+            "Can not pulsify {node} over a window: its output {output:?} has no axis of length \
+            1 to stream the windows along"
+        )
+    })?;
+    let op = PulsedWindowEinSum { einsum: op.clone(), axis, window, hop };
+    Ok(Some(target.wire_node(&*node.name, op, &inputs)?))
+}
+
+/// An einsum contracting a window of its streaming input, buffered to the window length, and
+/// computed once per pulse. Each pulse is a window, along the output axis `axis`.
+#[derive(Debug, Clone)]
+pub struct PulsedWindowEinSum {
+    pub einsum: EinSum,
+    pub axis: usize,
+    pub window: usize,
+    pub hop: usize,
+}
+
+impl Op for PulsedWindowEinSum {
+    fn name(&self) -> Cow<str> {
+        "PulsedWindowEinSum".into()
+    }
+
+    fn info(&self) -> TractResult<Vec<String>> {
+        let mut info = self.einsum.info()?;
+        info.push(format!("window: {} hop: {} axis: {}", self.window, self.hop, self.axis));
+        Ok(info)
+    }
+
+    op_as_typed_op!();
+}
+
+impl EvalOp for PulsedWindowEinSum {
+    fn is_stateless(&self) -> bool {
+        true
+    }
+
+    fn eval(&self, inputs: TVec<TValue>) -> TractResult<TVec<TValue>> {
+        self.einsum.eval(inputs)
+    }
+}
+
+impl TypedOp for PulsedWindowEinSum {
+    as_op!();
+
+    fn output_facts(&self, inputs: &[&TypedFact]) -> TractResult<TVec<TypedFact>> {
+        self.einsum.output_facts(inputs)
+    }
+}
+
+impl PulsedOp for PulsedWindowEinSum {
+    fn pulsed_output_facts(&self, inputs: &[&PulsedFact]) -> TractResult<TVec<PulsedFact>> {
+        let stream =
+            inputs.iter().find_map(|f| f.stream.as_ref()).context("Expected a streaming input")?;
+        let facts = inputs.iter().map(|f| f.to_typed_fact()).collect::<TractResult<TVec<_>>>()?;
+        let output =
+            self.einsum.output_facts(&facts.iter().map(|f| f.as_ref()).collect::<TVec<_>>())?;
+        // the buffer of each pulse starts stream.delay before the pulse, on a hop boundary
+        let windows = (stream.dim.clone() - (self.window - 1).to_dim()).divceil(self.hop);
+        Ok(tvec!(PulsedFact {
+            datum_type: output[0].datum_type,
+            shape: output[0].shape.clone(),
+            stream: Some(StreamInfo {
+                axis: self.axis,
+                dim: windows,
+                delay: stream.delay / self.hop
+            }),
+        }))
+    }
+
+    as_op!();
+
+    fn to_typed(&self) -> Box<dyn TypedOp> {
+        Box::new(self.einsum.clone())
+    }
+}
+
+/// Feed a constant tensor along the streaming axis: each pulse gets the entries of the constant
+/// at the positions the pulse covers in the stream, zeros before and after.
+///
+/// Its input is the stream the constant is aligned with, setting the pulse length.
+#[derive(Debug, Clone, Hash)]
+pub struct PulsedConstSlices {
+    pub konst: Arc<Tensor>,
+    pub axis: usize,
+    pub stream_axis: usize,
+    pub delay: usize,
+}
+
+impl Op for PulsedConstSlices {
+    fn name(&self) -> Cow<str> {
+        "PulsedConstSlices".into()
+    }
+
+    fn info(&self) -> TractResult<Vec<String>> {
+        Ok(vec![format!("axis: {} delay: {}", self.axis, self.delay)])
+    }
+
+    op_as_typed_op!();
+}
+
+impl EvalOp for PulsedConstSlices {
+    fn is_stateless(&self) -> bool {
+        false
+    }
+
+    fn state(
+        &self,
+        _session: &mut SessionState,
+        _node_id: usize,
+    ) -> TractResult<Option<Box<dyn OpState>>> {
+        Ok(Some(Box::<PulsedConstSlicesState>::default()))
+    }
+}
+
+impl PulsedConstSlices {
+    fn output_shape<D: DimLike>(&self, pulse: D) -> TVec<D> {
+        let mut shape: TVec<D> = self.konst.shape().iter().map(|d| D::from(*d)).collect();
+        shape[self.axis] = pulse;
+        shape
+    }
+}
+
+impl TypedOp for PulsedConstSlices {
+    as_op!();
+
+    fn output_facts(&self, inputs: &[&TypedFact]) -> TractResult<TVec<TypedFact>> {
+        let pulse = inputs[0].shape[self.stream_axis].clone();
+        Ok(tvec!(self.konst.datum_type().fact(self.output_shape(pulse))))
+    }
+}
+
+impl PulsedOp for PulsedConstSlices {
+    fn pulsed_output_facts(&self, inputs: &[&PulsedFact]) -> TractResult<TVec<PulsedFact>> {
+        let stream = inputs[0].stream.as_ref().context("Expected a streaming input")?;
+        Ok(tvec!(PulsedFact {
+            datum_type: self.konst.datum_type(),
+            shape: self.output_shape(inputs[0].shape[self.stream_axis].clone()).into(),
+            stream: Some(StreamInfo {
+                axis: self.axis,
+                dim: stream.dim.clone(),
+                delay: self.delay
+            }),
+        }))
+    }
+
+    as_op!();
+
+    fn to_typed(&self) -> Box<dyn TypedOp> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PulsedConstSlicesState {
+    current_pos: usize,
+}
+trivial_op_state_freeeze!(PulsedConstSlicesState);
+
+impl OpState for PulsedConstSlicesState {
+    fn eval(
+        &mut self,
+        _session: &mut SessionState,
+        op: &dyn Op,
+        inputs: TVec<TValue>,
+    ) -> TractResult<TVec<TValue>> {
+        let op =
+            op.downcast_ref::<PulsedConstSlices>().ok_or_else(|| format_err!("Wrong Op type"))?;
+        let pulse = inputs[0].shape()[op.stream_axis];
+        let mut data = Tensor::zero_dt(op.konst.datum_type(), &op.output_shape(pulse))?;
+        overwrite_part_of_pulse(op.axis, &mut data, self.current_pos, &op.konst, op.delay)?;
+        self.current_pos += pulse;
+        Ok(tvec!(data.into_tvalue()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tract_core::ndarray::*;
+    use tract_core::ops::array::Slice;
+
+    fn experts_model() -> TractResult<(TypedModel, Symbol)> {
+        let mut model = TypedModel::default();
+        let s = model.symbol_table.sym("S");
+        let a = model.add_source("a", f32::fact(dims![s, 2, 3].as_ref()))?;
+        let b =
+            Tensor::from_shape(&[4, 3, 5], &(0..60).map(|i| i as f32 - 30.).collect::<Vec<_>>())?;
+        let b = model.add_const("experts", b)?;
+        let op = EinSum::new("emk,ekn->emn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", op, &[a, b])?;
+        model.set_output_outlets(&c)?;
+        Ok((model, s))
+    }
+
+    #[test]
+    fn stream_over_experts() -> TractResult<()> {
+        let (model, s) = experts_model()?;
+        let a = Array3::from_shape_fn((4, 2, 3), |(e, m, k)| (e * 6 + m * 3 + k) as f32 / 8.);
+        let reference =
+            model.clone().into_runnable()?.run(tvec!(a.clone().into_tensor().into()))?;
+        for pulse in [1, 2] {
+            let pulsed = PulsedModel::new(&model, s.clone(), &pulse.to_dim())?;
+            assert_eq!(pulsed.output_fact(0)?.stream.as_ref().unwrap().delay, 0);
+            let plan = SimplePlan::new(pulsed.into_typed()?.into_optimized()?)?;
+            let mut state = SimpleState::new(plan)?;
+            let mut outputs = vec![];
+            for chunk in a.axis_chunks_iter(Axis(0), pulse) {
+                let output = state.run(tvec!(chunk.to_owned().into_tensor().into()))?;
+                outputs.push(output[0].clone().into_tensor());
+            }
+            let found = Tensor::stack_tensors(0, &outputs)?;
+            found.close_enough(&reference[0], Approximation::Close)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn experts_are_packed_once() -> TractResult<()> {
+        use tract_core::ops::matmul::pack::MatMatMulPack;
+        let optimized = experts_model()?.0.into_optimized()?;
+        // only the streaming operand is packed at runtime, the expert stack is packed as a const
+        let packs = optimized.nodes().iter().filter(|n| n.op_is::<MatMatMulPack>()).count();
+        assert_eq!(packs, 1);
+        Ok(())
+    }
+
+    /// Run `model`, streaming along axis `axis` of its single input, pulse by pulse. The last
+    /// pulse is padded with zeros, the output is cropped to the input length.
+    fn run_pulsed(
+        model: &TypedModel,
+        s: &Symbol,
+        pulse: usize,
+        input: &Tensor,
+        axis: usize,
+    ) -> TractResult<Tensor> {
+        let pulsed = PulsedModel::new(model, s.clone(), &pulse.to_dim())?;
+        let output_stream = pulsed.output_fact(0)?.stream.clone().unwrap();
+        let plan = SimplePlan::new(pulsed.into_typed()?.into_optimized()?)?;
+        let mut state = SimpleState::new(plan)?;
+        let len = input.shape()[axis];
+        let mut outputs = vec![];
+        for start in (0..len + output_stream.delay).step_by(pulse) {
+            let mut chunk_shape = input.shape().to_vec();
+            chunk_shape[axis] = pulse;
+            let mut chunk = Tensor::zero::<f32>(&chunk_shape)?;
+            if start < len {
+                let end = (start + pulse).min(len);
+                let slice = input.slice(axis, start, end)?;
+                chunk.assign_slice(0..end - start, &slice, 0..end - start, axis)?;
+            }
+            outputs.push(state.run(tvec!(chunk.into_tvalue()))?.remove(0).into_tensor());
+        }
+        let output = Tensor::stack_tensors(output_stream.axis, &outputs)?;
+        let delay = output_stream.delay;
+        output.slice(output_stream.axis, delay, delay + len)
+    }
+
+    #[test]
+    fn stream_over_right_operand() -> TractResult<()> {
+        // the streaming axis is n, then a batch axis the constant operand does not have
+        for (expr, b_shape, axis) in [("mk,kn->mn", &[4, 7][..], 1), ("mk,bkn->bmn", &[7, 4, 2], 0)]
+        {
+            let mut model = TypedModel::default();
+            let s = model.symbol_table.sym("S");
+            let mut fact_shape: TVec<TDim> = b_shape.iter().map(|d| d.to_dim()).collect();
+            fact_shape[axis] = s.to_dim();
+            let b = model.add_source("b", f32::fact(&fact_shape))?;
+            let a =
+                Tensor::from_shape(&[3, 4], &(0..12).map(|i| i as f32 - 6.).collect::<Vec<_>>())?;
+            let a = model.add_const("a", a)?;
+            let c = model.wire_node(
+                "einsum",
+                EinSum::new(expr.parse()?, f32::datum_type()),
+                &[a, b],
+            )?;
+            model.set_output_outlets(&c)?;
+            let len = b_shape.iter().product();
+            let input = Tensor::from_shape(
+                b_shape,
+                &(0..len).map(|i| (i % 11) as f32 / 4.).collect::<Vec<_>>(),
+            )?;
+            let reference = model.clone().into_runnable()?.run(tvec!(input.clone().into()))?;
+            for pulse in [1, 2, 3, 7] {
+                let found = run_pulsed(&model, &s, pulse, &input, axis)?;
+                found.close_enough(&reference[0], Approximation::Close)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A projection of the last `window` frames every `hop` frames, after skipping the first
+    /// `skip` frames of the stream, against the offline model run on each window.
+    fn check_k_window(window: usize, hop: usize, skip: usize) -> TractResult<()> {
+        let len = 40;
+        let mut model = TypedModel::default();
+        let s = model.symbol_table.sym("S");
+        let a = model.add_source("a", f32::fact(dims![1, s].as_ref()))?;
+        let a = model.wire_node("skip", Slice::new(1, skip, s.to_dim()), &[a])?;
+        let w = (0..window * 3).map(|i| (i % 7) as f32 - 3.).collect::<Vec<_>>();
+        let w = model.add_const("w", Tensor::from_shape(&[window, 3], &w)?)?;
+        let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", op, &[a[0], w])?;
+        model.set_output_outlets(&c)?;
+        let input = Array2::from_shape_fn((1, len), |(_, k)| (k % 11) as f32 / 4. - 1.);
+
+        let offline = model.clone().into_runnable()?;
+        let windows = (len - skip - window) / hop + 1;
+        let reference = (0..windows)
+            .map(|t| {
+                let frames = input.slice(s![.., t * hop..t * hop + skip + window]).to_owned();
+                Ok(offline.run(tvec!(frames.into_tvalue()))?.remove(0).into_tensor())
+            })
+            .collect::<TractResult<Vec<_>>>()?;
+        let reference = Tensor::stack_tensors(0, &reference)?;
+
+        let pulsed = PulsedModel::new(&model, s, &hop.to_dim())?;
+        let stream = pulsed.output_fact(0)?.stream.clone().unwrap();
+        // the first window ends in the pulse holding its last frame
+        assert_eq!((stream.axis, stream.delay), (0, (skip + window - 1) / hop));
+        let plan = SimplePlan::new(pulsed.into_typed()?.into_optimized()?)?;
+        let mut state = SimpleState::new(plan)?;
+        let mut outputs = vec![];
+        for chunk in input.axis_chunks_iter(Axis(1), hop) {
+            outputs.push(state.run(tvec!(chunk.to_owned().into_tvalue()))?.remove(0).into_tensor());
+        }
+        let found = Tensor::stack_tensors(0, &outputs)?;
+        let found = found.slice(0, stream.delay, stream.delay + windows)?;
+        found.close_enough(&reference, Approximation::Close)
+    }
+
+    #[test]
+    fn stream_over_k_window() -> TractResult<()> {
+        check_k_window(16, 4, 0)
+    }
+
+    #[test]
+    fn stream_over_k_window_off_the_hop() -> TractResult<()> {
+        check_k_window(16, 4, 2)
+    }
+
+    #[test]
+    fn stream_over_right_operand_k_is_an_error() -> TractResult<()> {
+        let mut model = TypedModel::default();
+        let s = model.symbol_table.sym("S");
+        let b = model.add_source("b", f32::fact(dims![s, 5].as_ref()))?;
+        let a = model.add_const("a", Tensor::zero::<f32>(&[3, 1])?)?;
+        let op = EinSum::new("mk,kn->mn".parse()?, f32::datum_type());
+        let c = model.wire_node("einsum", op, &[a, b])?;
+        model.set_output_outlets(&c)?;
+        let err = PulsedModel::new(&model, s, &2.to_dim()).unwrap_err();
+        assert!(format!("{err:?}").contains("contracted axis"), "{err:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn inconsistent_input_fact_is_an_error() -> TractResult<()> {
+        let (model, s) = experts_model()?;
+        let mut target = PulsedModel::default();
+        let fact = PulsedFact {
+            datum_type: f32::datum_type(),
+            shape: tvec!(4.to_dim(), 2.to_dim(), 3.to_dim()).into(),
+            stream: Some(StreamInfo { axis: 0, dim: 2.to_dim(), delay: 3 }),
+        };
+        let a = target.add_source("a", fact.clone())?;
+        let experts = PulsedFact { stream: None, ..fact };
+        let experts = target.add_source("experts", experts)?;
+        let mapping =
+            tvec!((OutletId::new(0, 0), a), (OutletId::new(1, 0), experts)).into_iter().collect();
+        let node = model.node(2);
+        let op = node.op_as::<EinSum>().unwrap();
+        let err = pulsify(op, &model, node, &mut target, &mapping, &s, &4.to_dim()).unwrap_err();
+        let err = format!("{err:?}");
+        assert!(err.contains("einsum") && err.contains("smaller than the pulse"), "{err}");
+        Ok(())
+    }
+}